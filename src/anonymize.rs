@@ -0,0 +1,332 @@
+//! Pseudonymized exports of the source data
+//!
+//! `--anonymize-export` reads the configured source like a sync and
+//! writes its users to a state file for a staging organization, with
+//! their personal data replaced by fake values, so that issues seen at
+//! a customer's scale can be reproduced without handling their data.
+//! The export is loaded into the staging organization with
+//! `--apply-state`.
+//!
+//! Fake values are derived from the original ones with a keyed hash,
+//! so that the same value always gets the same fake value, within and
+//! across exports with the same secret. This keeps the structure of
+//! the data, e.g. users sharing a last name or department, while the
+//! original values can't be recovered without the secret.
+use std::{collections::VecDeque, path::Path};
+
+use anyhow::{Context, Result};
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+
+use crate::{desired_state::DesiredState, get_source, prepare_source_users, user::User, Config};
+
+/// The first names fake first names are picked from
+const FIRST_NAMES: &[&str] = &[
+	"Alex", "Anna", "Ben", "Clara", "David", "Emma", "Felix", "Greta", "Hannah", "Jonas", "Julia",
+	"Leon", "Lina", "Lukas", "Marie", "Max", "Mia", "Noah", "Paul", "Sophie",
+];
+
+/// The last names fake last names are picked from
+const LAST_NAMES: &[&str] = &[
+	"Bauer",
+	"Becker",
+	"Fischer",
+	"Hoffmann",
+	"Koch",
+	"Meyer",
+	"Müller",
+	"Neumann",
+	"Richter",
+	"Schäfer",
+	"Schmidt",
+	"Schneider",
+	"Schulz",
+	"Schwarz",
+	"Wagner",
+	"Weber",
+	"Wolf",
+	"Zimmermann",
+];
+
+/// Configuration of pseudonymized exports of the source data
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct AnonymizationConfig {
+	/// The secret the fake values are derived with, which must be kept
+	/// secret, since the original values can be guessed with it
+	pub secret: String,
+	/// The Zitadel organization the export is meant for, e.g. a staging
+	/// organization
+	pub organization_id: String,
+	/// Metadata keys whose values aren't personal data, e.g.
+	/// `department`, and are therefore kept as they are
+	#[serde(default)]
+	pub keep_metadata: Vec<String>,
+	/// Whether to replace external user IDs and localparts as well, for
+	/// sources deriving them from personal data, e.g. the email
+	/// addresses of the CSV source. This changes the order of the users.
+	#[serde(default)]
+	pub pseudonymize_ids: bool,
+}
+
+/// Export the users of the source with their personal data replaced
+/// by fake values to a state file
+pub async fn anonymize_export(config: &Config, path: &Path) -> Result<()> {
+	let anonymization = config
+		.anonymization
+		.as_ref()
+		.context("`--anonymize-export` requires `anonymization` to be set")?;
+	let anonymizer = Anonymizer::new(anonymization)?;
+
+	let source = get_source(config)?;
+	let mut users: VecDeque<User> = source
+		.get_sorted_users()
+		.await
+		.context(format!("Failed to query users from {}", source.get_name()))?
+		.into();
+	let (_, persona_conflicts) = prepare_source_users(config, &mut users)?;
+	for conflict in &persona_conflicts {
+		tracing::warn!("{}", conflict.message(config.language));
+	}
+
+	// Users which couldn't be read in full lack the data to export
+	let count = users.len();
+	users.retain(|user| user.unreadable.is_none());
+	if users.len() < count {
+		tracing::warn!("Leaving out {} users which couldn't be read in full", count - users.len());
+	}
+
+	let mut users: Vec<User> = users.iter().map(|user| anonymizer.anonymize(user)).collect();
+	users.sort_by(|a, b| a.external_user_id.cmp(&b.external_user_id));
+
+	let state = DesiredState { organization_id: anonymization.organization_id.clone(), users };
+	state.save(path)?;
+
+	tracing::info!("Exported {} pseudonymized users to {}", state.users.len(), path.display());
+
+	Ok(())
+}
+
+/// Replaces the personal data of users by fake values
+struct Anonymizer<'a> {
+	/// The configuration of the export
+	config: &'a AnonymizationConfig,
+	/// The keyed hash fake values are derived with
+	mac: Hmac<Sha256>,
+}
+
+impl<'a> Anonymizer<'a> {
+	/// Create an anonymizer for the given configuration
+	fn new(config: &'a AnonymizationConfig) -> Result<Self> {
+		let mac = Hmac::<Sha256>::new_from_slice(config.secret.as_bytes())
+			.context("Invalid anonymization secret")?;
+		Ok(Self { config, mac })
+	}
+
+	/// The keyed hash of a value of the given kind, so that equal
+	/// values of different kinds get unrelated fake values
+	fn digest(&self, kind: &str, value: &str) -> [u8; 32] {
+		let mut mac = self.mac.clone();
+		mac.update(kind.as_bytes());
+		mac.update(b"\0");
+		mac.update(value.as_bytes());
+		let mut digest = [0; 32];
+		digest.copy_from_slice(&mac.finalize().into_bytes());
+		digest
+	}
+
+	/// A hex pseudonym of the given length for a value
+	fn hex(&self, kind: &str, value: &str, length: usize) -> String {
+		let mut pseudonym = hex::encode(self.digest(kind, value));
+		pseudonym.truncate(length);
+		pseudonym
+	}
+
+	/// A number below the given bound for a value
+	fn number(&self, kind: &str, value: &str, bound: u64) -> u64 {
+		let digest = self.digest(kind, value);
+		let mut bytes = [0; 8];
+		bytes.copy_from_slice(&digest[..8]);
+		u64::from_be_bytes(bytes) % bound
+	}
+
+	/// Pick a fake name for a value, keeping empty values empty
+	fn pick(&self, kind: &str, value: &str, names: &[&str]) -> String {
+		if value.is_empty() {
+			return String::new();
+		}
+		let bound = u64::try_from(names.len()).unwrap_or(u64::MAX);
+		let index = usize::try_from(self.number(kind, value, bound)).unwrap_or_default();
+		names.get(index).copied().unwrap_or_default().to_owned()
+	}
+
+	/// A fake email address in the domain of the original one, unique
+	/// by the hash of the original address
+	fn email(&self, email: &str, first_name: &str, last_name: &str) -> String {
+		if email.is_empty() {
+			return String::new();
+		}
+		let domain = email.rsplit_once('@').map_or("example.com", |(_, domain)| domain);
+		let name: String = format!("{first_name}.{last_name}")
+			.to_lowercase()
+			.chars()
+			.map(|character| match character {
+				'ä' => 'a',
+				'ö' => 'o',
+				'ü' => 'u',
+				character => character,
+			})
+			.collect();
+		format!("{}.{}@{}", name.trim_matches('.'), self.hex("email", email, 12), domain)
+	}
+
+	/// A copy of the user with its personal data replaced
+	fn anonymize(&self, user: &User) -> User {
+		let first_name = self.pick("first_name", &user.first_name, FIRST_NAMES);
+		let last_name = self.pick("last_name", &user.last_name, LAST_NAMES);
+		let email = self.email(&user.email, &first_name, &last_name);
+
+		let (external_user_id, localpart) = if self.config.pseudonymize_ids {
+			(
+				self.hex("external_user_id", &user.external_user_id, 32),
+				user.localpart.as_ref().map(|localpart| self.hex("localpart", localpart, 16)),
+			)
+		} else {
+			(user.external_user_id.clone(), user.localpart.clone())
+		};
+
+		// Preferred usernames are usually the email address or
+		// localpart, which is kept recognizable
+		let preferred_username =
+			user.preferred_username.as_ref().map(|preferred_username| match preferred_username {
+				username if username.is_empty() => String::new(),
+				username if *username == user.email => email.clone(),
+				username if Some(username) == user.localpart.as_ref() => {
+					localpart.clone().unwrap_or_default()
+				}
+				username => format!("user-{}", self.hex("preferred_username", username, 12)),
+			});
+
+		let phone = user.phone.as_ref().map(|phone| match phone.as_str() {
+			"" => String::new(),
+			phone => format!("+4915550{:07}", self.number("phone", phone, 10_000_000)),
+		});
+
+		let metadata = user
+			.metadata
+			.iter()
+			.map(|(key, value)| {
+				let value = if value.is_empty() || self.config.keep_metadata.contains(key) {
+					value.clone()
+				} else {
+					format!("{key}-{}", self.hex(key, value, 12))
+				};
+				(key.clone(), value)
+			})
+			.collect();
+
+		let mut anonymized = User::new(
+			first_name,
+			last_name,
+			email,
+			phone,
+			user.enabled,
+			preferred_username,
+			external_user_id,
+			localpart,
+		);
+		anonymized.metadata = metadata;
+		anonymized.roles = user.roles.clone();
+		anonymized
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn config(secret: &str, pseudonymize_ids: bool) -> AnonymizationConfig {
+		AnonymizationConfig {
+			secret: secret.to_owned(),
+			organization_id: "staging".to_owned(),
+			keep_metadata: vec!["department".to_owned()],
+			pseudonymize_ids,
+		}
+	}
+
+	fn user(external_user_id: &str, first_name: &str, last_name: &str) -> User {
+		let mut user = User::new(
+			first_name.to_owned(),
+			last_name.to_owned(),
+			format!("{}.{}@hospital.example", first_name, last_name).to_lowercase(),
+			Some("+491701234567".to_owned()),
+			true,
+			None,
+			external_user_id.to_owned(),
+			Some(format!("{first_name}{last_name}").to_lowercase()),
+		);
+		user.preferred_username = user.localpart.clone();
+		user.metadata.insert("department".to_owned(), "Radiology".to_owned());
+		user.metadata.insert("employee_number".to_owned(), "1234".to_owned());
+		user.roles.insert("Physician".to_owned());
+		user
+	}
+
+	#[test]
+	fn test_anonymize() {
+		let config = config("secret", false);
+		let anonymizer = Anonymizer::new(&config).expect("invalid config");
+
+		let original = user("0a", "Jane", "Roe");
+		let anonymized = anonymizer.anonymize(&original);
+
+		// Personal data is replaced
+		assert!(FIRST_NAMES.contains(&anonymized.first_name.as_str()));
+		assert!(LAST_NAMES.contains(&anonymized.last_name.as_str()));
+		assert_ne!(anonymized.email, original.email);
+		assert!(anonymized.email.ends_with("@hospital.example"));
+		assert_ne!(anonymized.phone, original.phone);
+		assert_eq!(anonymized.phone.as_ref().map(String::len), Some(15));
+		assert_ne!(anonymized.metadata.get("employee_number"), Some(&"1234".to_owned()));
+
+		// The structure of the user is kept
+		assert_eq!(anonymized.external_user_id, "0a");
+		assert_eq!(anonymized.localpart, original.localpart);
+		assert_eq!(anonymized.preferred_username, anonymized.localpart);
+		assert_eq!(anonymized.metadata.get("department"), Some(&"Radiology".to_owned()));
+		assert_eq!(anonymized.roles, original.roles);
+		assert!(anonymized.enabled);
+
+		// Fake values are consistent, and differ by user
+		assert_eq!(anonymizer.anonymize(&original), anonymized);
+		let relative = anonymizer.anonymize(&user("0b", "John", "Roe"));
+		assert_eq!(relative.last_name, anonymized.last_name);
+		assert_eq!(
+			relative.metadata.get("employee_number"),
+			anonymized.metadata.get("employee_number")
+		);
+		assert_ne!(relative.email, anonymized.email);
+
+		// Without the secret, the fake values differ
+		let other_config = config("other", false);
+		let other = Anonymizer::new(&other_config).expect("invalid config");
+		assert_ne!(other.anonymize(&original).email, anonymized.email);
+	}
+
+	#[test]
+	fn test_anonymize_ids() {
+		let config = config("secret", true);
+		let anonymizer = Anonymizer::new(&config).expect("invalid config");
+
+		let mut original = user("6a616e65", "Jane", "Roe");
+		original.preferred_username = Some(original.email.clone());
+		original.phone = None;
+		let anonymized = anonymizer.anonymize(&original);
+
+		assert_ne!(anonymized.external_user_id, original.external_user_id);
+		assert!(anonymized.get_external_id_bytes().is_ok());
+		assert_ne!(anonymized.localpart, original.localpart);
+		assert_eq!(anonymized.preferred_username.as_ref(), Some(&anonymized.email));
+		assert_eq!(anonymized.phone, None);
+	}
+}
@@ -0,0 +1,179 @@
+//! Per-run artifact directories
+//!
+//! With `artifacts` configured, every sync gets its own directory below
+//! the configured path, named after the time the sync started. The
+//! report, the audit log, the deletion archive and the resource metrics
+//! of the run are written into it, unless configured elsewhere, along
+//! with a copy of the state file once the run is over. Only the
+//! directories of the last runs are kept, so that the artifacts of a
+//! run can be handed over as a single bundle.
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use chrono::{NaiveDateTime, Utc};
+use serde::Deserialize;
+
+use crate::Config;
+
+/// The format of the names of the run directories, which sort in the
+/// order of the runs
+const RUN_DIRECTORY_FORMAT: &str = "%Y-%m-%dT%H-%M-%S%.3fZ";
+
+/// The default number of runs to keep the artifacts of
+const DEFAULT_KEEP_RUNS: usize = 10;
+
+/// Configuration of the per-run artifact directories
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct ArtifactsConfig {
+	/// The directory to create the run directories in
+	pub path: PathBuf,
+	/// The number of runs to keep the artifacts of, including the
+	/// current one. Older run directories are removed.
+	#[serde(default = "default_keep_runs")]
+	pub keep_runs: usize,
+}
+
+/// Default for [`ArtifactsConfig::keep_runs`]
+fn default_keep_runs() -> usize {
+	DEFAULT_KEEP_RUNS
+}
+
+/// The artifact directory of a run
+#[derive(Debug)]
+pub(crate) struct RunArtifacts {
+	/// The directory of the run
+	directory: PathBuf,
+}
+
+impl RunArtifacts {
+	/// Create the directory of a new run, removing those of old runs,
+	/// and direct the artifacts which aren't configured elsewhere into
+	/// it
+	pub(crate) fn start(artifacts: &ArtifactsConfig, config: &mut Config) -> Result<Self> {
+		let directory = artifacts.path.join(Utc::now().format(RUN_DIRECTORY_FORMAT).to_string());
+		std::fs::create_dir_all(&directory)
+			.context(format!("Failed to create artifact directory {}", directory.display()))?;
+		tracing::info!("Writing the artifacts of this run to {}", directory.display());
+
+		remove_old_runs(&artifacts.path, artifacts.keep_runs)?;
+
+		let reporting = &mut config.reporting;
+		reporting.report_path.get_or_insert_with(|| directory.join("report.json"));
+		reporting.html_report_path.get_or_insert_with(|| directory.join("report.html"));
+		reporting.audit_log_path.get_or_insert_with(|| directory.join("audit.jsonl"));
+		reporting
+			.deletion_archive_path
+			.get_or_insert_with(|| directory.join("deletion-archive.jsonl"));
+		if let Some(resource_monitoring) = &mut config.resource_monitoring {
+			resource_monitoring.metrics_path.get_or_insert_with(|| directory.join("metrics.jsonl"));
+		}
+
+		Ok(Self { directory })
+	}
+
+	/// Copy the state file into the directory of the run, once the
+	/// run is over
+	pub(crate) fn finish(self, config: &Config) {
+		let Some(state_path) = &config.state_path else {
+			return;
+		};
+		// The state file doesn't exist before the first sync
+		if !state_path.exists() {
+			return;
+		}
+
+		let file_name = state_path.file_name().map_or_else(|| "state".into(), ToOwned::to_owned);
+		if let Err(error) = std::fs::copy(state_path, self.directory.join(file_name)) {
+			tracing::warn!("Failed to copy the state file to the artifacts: {:?}", error);
+		}
+	}
+}
+
+/// The run directories below the given path, oldest first. Other
+/// entries of the directory are ignored.
+pub(crate) fn list_runs(path: &Path) -> Result<Vec<PathBuf>> {
+	let mut runs = Vec::new();
+	for entry in std::fs::read_dir(path)
+		.context(format!("Failed to read artifact directory {}", path.display()))?
+	{
+		let entry = entry?;
+		let is_run = entry
+			.file_name()
+			.to_str()
+			.is_some_and(|name| NaiveDateTime::parse_from_str(name, RUN_DIRECTORY_FORMAT).is_ok());
+		if is_run && entry.file_type()?.is_dir() {
+			runs.push(entry.path());
+		}
+	}
+
+	runs.sort();
+	Ok(runs)
+}
+
+/// Remove the run directories beyond the given number of most recent
+/// ones
+fn remove_old_runs(path: &Path, keep_runs: usize) -> Result<()> {
+	let runs = list_runs(path)?;
+	let old_run_count = runs.len().saturating_sub(keep_runs);
+	for run in runs.into_iter().take(old_run_count) {
+		tracing::debug!("Removing the artifacts of run {}", run.display());
+		std::fs::remove_dir_all(&run)
+			.context(format!("Failed to remove artifact directory {}", run.display()))?;
+	}
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use tempfile::TempDir;
+
+	use super::*;
+
+	#[test]
+	fn test_remove_old_runs() {
+		let dir = TempDir::new().expect("failed to create tempdir");
+		let runs =
+			["2026-01-01T00-00-00.000Z", "2026-01-02T00-00-00.000Z", "2026-01-03T00-00-00.000Z"];
+		for run in runs {
+			std::fs::create_dir_all(dir.path().join(run)).expect("failed to create run directory");
+		}
+		std::fs::create_dir_all(dir.path().join("other")).expect("failed to create directory");
+
+		remove_old_runs(dir.path(), 2).expect("failed to remove old runs");
+
+		assert!(!dir.path().join(runs[0]).exists());
+		assert!(dir.path().join(runs[1]).exists());
+		assert!(dir.path().join(runs[2]).exists());
+		assert!(dir.path().join("other").exists());
+	}
+
+	#[test]
+	fn test_start() {
+		let dir = TempDir::new().expect("failed to create tempdir");
+		let artifacts = ArtifactsConfig { path: dir.path().to_path_buf(), keep_runs: 1 };
+		let mut config: Config = serde_yaml::from_str(indoc::indoc! {r#"
+			zitadel:
+			  url: http://localhost:8080
+			  key_file: tests/environment/zitadel/service-user.json
+			  organization_id: 1
+			  project_id: 1
+			  idp_id: 1
+
+			sources:
+			  csv:
+			    file_path: users.csv
+
+			reporting:
+			  audit_log_path: audit.jsonl
+		"#})
+		.expect("invalid config");
+
+		let run = RunArtifacts::start(&artifacts, &mut config).expect("failed to start run");
+
+		assert!(run.directory.is_dir());
+		assert_eq!(config.reporting.report_path, Some(run.directory.join("report.json")));
+		assert_eq!(config.reporting.html_report_path, Some(run.directory.join("report.html")));
+		assert_eq!(config.reporting.audit_log_path, Some(PathBuf::from("audit.jsonl")));
+	}
+}
@@ -0,0 +1,71 @@
+//! Budgeting of the changes a single sync applies
+//!
+//! The first sync after a long outage may have to apply a huge number
+//! of changes, taking hours in which a problem affects ever more users.
+//! With `max_changes_per_run` configured, a sync only applies that many
+//! changes and defers the rest. Deferred changes are listed in the
+//! sync report, and since they still differ between the source and
+//! Zitadel, the next sync finds them again.
+
+/// Counts the changes of a sync against the configured maximum
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ChangeBudget {
+	/// The maximum number of changes, if any
+	limit: Option<usize>,
+	/// The number of changes made so far
+	spent: usize,
+}
+
+impl ChangeBudget {
+	/// Create a budget allowing the given number of changes
+	pub(crate) fn new(limit: Option<usize>) -> Self {
+		Self { limit, spent: 0 }
+	}
+
+	/// Whether no more changes may be made
+	pub(crate) fn exhausted(&self) -> bool {
+		self.limit.is_some_and(|limit| self.spent >= limit)
+	}
+
+	/// Count a change against the budget
+	pub(crate) fn spend(&mut self) {
+		self.spent += 1;
+	}
+
+	/// Count a change against the budget, returning `false` if it
+	/// should be deferred instead
+	pub(crate) fn admit(&mut self) -> bool {
+		if self.exhausted() {
+			return false;
+		}
+
+		self.spend();
+		true
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_change_budget() {
+		let mut budget = ChangeBudget::new(Some(2));
+
+		assert!(budget.admit());
+		assert!(!budget.exhausted());
+		assert!(budget.admit());
+		assert!(budget.exhausted());
+		assert!(!budget.admit());
+	}
+
+	#[test]
+	fn test_unlimited_change_budget() {
+		let mut budget = ChangeBudget::default();
+
+		for _ in 0..1000 {
+			assert!(budget.admit());
+		}
+		assert!(!budget.exhausted());
+	}
+}
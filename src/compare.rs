@@ -0,0 +1,130 @@
+//! Comparison of the users in the production and shadow organizations
+//!
+//! After syncing to the shadow organization with the `shadow_run`
+//! feature flag, e.g. to try out a configuration change on a staging
+//! instance, the users there can be compared with the production
+//! organization, to see the effect of the change before it is applied
+//! to production.
+use std::collections::BTreeMap;
+
+use anyhow::{Context, Result};
+
+use crate::{get_next_zitadel_user, output::Table, user::User, zitadel::Zitadel, Config};
+
+/// Compare the users in the production and shadow organizations,
+/// returning the differences by external user ID
+pub async fn compare_shadow(config: &Config) -> Result<Table> {
+	let shadow_zitadel = config.shadow_zitadel.clone().context(
+		"Comparing requires `shadow_zitadel` to be set, and the `shadow_run` feature flag to be \
+		 disabled",
+	)?;
+
+	let production = list_users(config).await.context("Failed to list production users")?;
+
+	let mut shadow_config = config.clone();
+	shadow_config.zitadel = shadow_zitadel;
+	let shadow = list_users(&shadow_config).await.context("Failed to list shadow users")?;
+
+	let mut differences = Table::new(vec!["external_user_id", "difference"]);
+	for (external_user_id, difference) in compare(&production, &shadow) {
+		differences.push(vec![external_user_id, difference]);
+	}
+
+	if differences.is_empty() {
+		tracing::info!("Production and shadow organizations match ({} users)", production.len());
+	}
+
+	Ok(differences)
+}
+
+/// List the users of the configured organization, by external ID
+async fn list_users(config: &Config) -> Result<BTreeMap<String, User>> {
+	let mut zitadel = Zitadel::new(config).await?;
+	let mut stream = zitadel.list_users()?;
+	let mut users = BTreeMap::new();
+	while let Some((user, _)) = get_next_zitadel_user(&mut stream, &mut zitadel).await? {
+		users.insert(user.external_user_id.clone(), user);
+	}
+
+	Ok(users)
+}
+
+/// Describe the differences between the production and shadow users,
+/// by external user ID. Only the names of differing attributes are
+/// listed, not their values.
+fn compare(
+	production: &BTreeMap<String, User>,
+	shadow: &BTreeMap<String, User>,
+) -> Vec<(String, String)> {
+	let mut differences = Vec::new();
+
+	for (external_user_id, production_user) in production {
+		match shadow.get(external_user_id) {
+			None => differences.push((external_user_id.clone(), "only in production".to_owned())),
+			Some(shadow_user) => {
+				let attributes: Vec<_> = production_user
+					.diff(shadow_user)
+					.into_iter()
+					.map(|(attribute, _, _)| attribute)
+					.collect();
+				if !attributes.is_empty() {
+					differences.push((
+						external_user_id.clone(),
+						format!("differs in {}", attributes.join(", ")),
+					));
+				}
+			}
+		}
+	}
+
+	for external_user_id in shadow.keys().filter(|id| !production.contains_key(*id)) {
+		differences.push((external_user_id.clone(), "only in shadow".to_owned()));
+	}
+
+	differences
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn user(external_user_id: &str, email: &str) -> User {
+		User::new(
+			"John".to_owned(),
+			"Doe".to_owned(),
+			email.to_owned(),
+			None,
+			true,
+			None,
+			external_user_id.to_owned(),
+			None,
+		)
+	}
+
+	#[test]
+	fn test_compare() {
+		let production = BTreeMap::from([
+			("a".to_owned(), user("a", "a@example.com")),
+			("b".to_owned(), user("b", "b@example.com")),
+			("c".to_owned(), user("c", "c@example.com")),
+		]);
+		let mut shadow = BTreeMap::from([
+			("a".to_owned(), user("a", "a@example.com")),
+			("c".to_owned(), user("c", "c@example.org")),
+			("d".to_owned(), user("d", "d@example.com")),
+		]);
+		if let Some(user) = shadow.get_mut("c") {
+			user.roles.insert("Admin".to_owned());
+		}
+
+		assert_eq!(
+			compare(&production, &shadow),
+			vec![
+				("b".to_owned(), "only in production".to_owned()),
+				("c".to_owned(), "differs in email, roles".to_owned()),
+				("d".to_owned(), "only in shadow".to_owned()),
+			]
+		);
+		assert!(compare(&production, &production).is_empty());
+	}
+}
@@ -1,15 +1,55 @@
 //! All sync client configuration structs and logic
 use std::{
+	collections::BTreeMap,
 	ops::{Deref, DerefMut},
-	path::Path,
+	path::{Path, PathBuf},
 };
 
 use anyhow::{bail, Result};
 use serde::Deserialize;
+use sha2::{Digest, Sha256};
 use url::Url;
 
-pub use crate::sources::{csv::CsvSourceConfig, ldap::LdapSourceConfig, ukt::UktSourceConfig};
-use crate::zitadel::ZitadelConfig;
+pub use crate::sources::{
+	csv::{CsvPreferredUsername, CsvSourceConfig},
+	entra::EntraSourceConfig,
+	fhir::FhirSourceConfig,
+	keycloak::KeycloakSourceConfig,
+	ldap::{ActiveDirectorySourceConfig, FreeIpaSourceConfig, LdapSourceConfig, UcsSourceConfig},
+	merged::{SourceKind, SourceMergeConfig},
+	ukt::UktSourceConfig,
+};
+use crate::{
+	anonymize::AnonymizationConfig,
+	artifacts::ArtifactsConfig,
+	daemon::Schedule,
+	drift::DriftConfig,
+	guests::{GuestConfig, GUEST_EXPIRES_AT_KEY},
+	id_mapping::IdMappingConfig,
+	import_throttle::ImportRampUpConfig,
+	messages::{Language, Message},
+	metrics::MetricsConfig,
+	normalization::{self, FieldComparison},
+	personas::PersonaMergeConfig,
+	pilot::PilotConfig,
+	profile::SyncProfile,
+	rename::RenameDetectionConfig,
+	report::ReportingConfig,
+	resources::ResourceMonitoringConfig,
+	rules::{self, Rule},
+	scim::ScimConfig,
+	second_factors::SecondFactorProtectionConfig,
+	self_service::SelfServiceConfig,
+	source_guard::SourceGuardConfig,
+	support_bundle::{self, SupportBundleConfig},
+	user::NameFallbackConfig,
+	user_cache::UserCacheConfig,
+	version_check::VersionCheckConfig,
+	watchdog::WatchdogConfig,
+	zitadel::{
+		DeprovisioningPolicy, ZitadelConfig, DEACTIVATED_AT_KEY, PENDING_DEPROVISIONING_KEY,
+	},
+};
 
 /// App prefix for env var configuration
 const ENV_VAR_CONFIG_PREFIX: &str = "FAMEDLY_SYNC";
@@ -21,13 +61,158 @@ const ENV_VAR_LIST_SEP: &str = " ";
 pub struct Config {
 	/// Configuration related to Zitadel provided by Famedly
 	pub zitadel: ZitadelConfig,
+	/// Optional Zitadel configuration used instead of `zitadel` with
+	/// the `shadow_run` feature flag, e.g. of a staging instance
+	pub shadow_zitadel: Option<ZitadelConfig>,
 	/// Sources configuration
 	pub sources: SourcesConfig,
+	/// How the users of several sources are merged, required if more
+	/// than one of the LDAP, CSV, FHIR, Entra ID and Keycloak sources is
+	/// configured
+	pub source_merge: Option<SourceMergeConfig>,
+	/// Optional timeouts and circuit breakers for reads of the sources
+	pub source_guard: Option<SourceGuardConfig>,
 	/// Optional sync tool log level
 	pub log_level: Option<String>,
+	/// Optional profile providing defaults for the safety and policy
+	/// options not set explicitly: `strict`, `balanced` or `legacy`
+	pub profile: Option<SyncProfile>,
 	/// Opt-in features
 	#[serde(default)]
 	pub feature_flags: FeatureFlags,
+	/// Optional check of the Zitadel user count after a sync
+	pub user_count_check: Option<UserCountCheckConfig>,
+	/// Optional check that the source returned a plausible number of
+	/// users, run before any users are changed
+	pub source_user_count_check: Option<SourceUserCountCheckConfig>,
+	/// Optional limit of the users a sync may delete, checked before
+	/// any user is deleted
+	pub deletion_guard: Option<DeletionGuardConfig>,
+	/// Reporting and audit log configuration
+	#[serde(default)]
+	pub reporting: ReportingConfig,
+	/// Optional per-run directories bundling the report, audit log and
+	/// other artifacts of each sync
+	pub artifacts: Option<ArtifactsConfig>,
+	/// What to include in support bundles
+	#[serde(default)]
+	pub support_bundle: SupportBundleConfig,
+	/// Rules deciding which users to sync, their project roles and
+	/// derived attributes
+	#[serde(default)]
+	pub rules: Vec<Rule>,
+	/// How to normalize the values of fields before comparing them,
+	/// by field
+	#[serde(default)]
+	pub comparison: BTreeMap<String, FieldComparison>,
+	/// Configuration for the removal of data left behind by earlier
+	/// syncs
+	#[serde(default)]
+	pub gc: GcConfig,
+	/// Optional watchdog aborting syncs which stop making progress
+	pub watchdog: Option<WatchdogConfig>,
+	/// Optional periodic reporting of the memory usage and open
+	/// connections of the process during syncs
+	pub resource_monitoring: Option<ResourceMonitoringConfig>,
+	/// Where to export the metrics of each sync to, with the `metrics`
+	/// feature flag
+	pub metrics: Option<MetricsConfig>,
+	/// Optional cron schedule of the syncs run with `--daemon`, e.g.
+	/// `0 */2 * * *`
+	pub schedule: Option<Schedule>,
+	/// Path to a file storing state between syncs. Without it, every
+	/// sync is treated as if it were not the first one.
+	pub state_path: Option<PathBuf>,
+	/// How to handle the first sync against an organization
+	#[serde(default)]
+	pub initial_sync: InitialSyncPolicy,
+	/// Optional pause after the first imports of the first sync
+	/// against an organization
+	pub import_ramp_up: Option<ImportRampUpConfig>,
+	/// Optional maximum number of changes a sync applies, leaving the
+	/// rest to later syncs
+	pub max_changes_per_run: Option<usize>,
+	/// Optional maximum age in minutes of the source data a plan was
+	/// computed from, beyond which `--apply-plan` refuses to apply it
+	pub max_plan_age_minutes: Option<u64>,
+	/// Protection of users with second factors against deletion
+	#[serde(default)]
+	pub second_factor_protection: SecondFactorProtectionConfig,
+	/// Optional SCIM server, run with `--scim-server`
+	pub scim: Option<ScimConfig>,
+	/// Optional endpoint receiving changes users make to their own
+	/// Zitadel accounts, run with `--self-service-events`
+	pub self_service: Option<SelfServiceConfig>,
+	/// Optional local cache of the Zitadel user listing for read-only
+	/// commands
+	pub zitadel_cache: Option<UserCacheConfig>,
+	/// Optional persistent mapping of external user IDs to localparts,
+	/// for sources which recycle external IDs
+	pub id_mapping: Option<IdMappingConfig>,
+	/// Optional detection of users whose external ID changed, which
+	/// are then renamed in place instead of being re-created
+	pub rename_detection: Option<RenameDetectionConfig>,
+	/// Whether users may be identified by other attributes than their
+	/// external ID
+	#[serde(default)]
+	pub identity: IdentityPolicy,
+	/// Optional merging of source users sharing an attribute, e.g. the
+	/// clinical and administrative personas of a person, into one user
+	pub personas: Option<PersonaMergeConfig>,
+	/// Optional handling of guest users, e.g. external clinicians, whose
+	/// access expires
+	pub guests: Option<GuestConfig>,
+	/// Optional pseudonymized exports of the source data, e.g. for
+	/// staging environments
+	pub anonymization: Option<AnonymizationConfig>,
+	/// Optional fallbacks for users lacking a first or last name
+	pub name_fallback: Option<NameFallbackConfig>,
+	/// Optional pilot mode, in which only users of the pilot group
+	/// are written to
+	pub pilot: Option<PilotConfig>,
+	/// Optional classification of differences into changes of the
+	/// source and changes in Zitadel, with a policy for each
+	pub drift: Option<DriftConfig>,
+	/// The language of error messages asking the operator to act and
+	/// of the texts in the sync report
+	#[serde(default)]
+	pub language: Language,
+	/// Optional check whether a newer version of the sync is available
+	pub version_check: Option<VersionCheckConfig>,
+	/// The fingerprint of the effective configuration, with secrets
+	/// redacted, set when loading it from file and env vars
+	#[serde(skip)]
+	pub fingerprint: String,
+}
+
+/// How to handle the first sync against an organization, which is
+/// where misconfigurations cause the most damage
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum InitialSyncPolicy {
+	/// Refuse to sync unless confirmed with `--confirm-initial-sync`
+	#[default]
+	RequireConfirmation,
+	/// Perform a dry run unless confirmed with
+	/// `--confirm-initial-sync`
+	DryRun,
+}
+
+/// Whether users may be identified by other attributes than their
+/// external ID
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum IdentityPolicy {
+	/// Identify users by external ID, falling back to their email
+	/// address where configured, i.e. for rename detection and UKT
+	/// deletions
+	#[default]
+	ExternalIdWithFallbacks,
+	/// Identify users strictly by external ID, e.g. an employee
+	/// number, for organizations reusing the email addresses of
+	/// departed staff. New users whose email address is still bound
+	/// to another Zitadel user aren't imported, but reported.
+	ExternalIdOnly,
 }
 
 /// Configuration for sources
@@ -35,36 +220,516 @@ pub struct Config {
 pub struct SourcesConfig {
 	/// Optional LDAP configuration
 	pub ldap: Option<LdapSourceConfig>,
+	/// Optional Active Directory configuration, which is turned into
+	/// an LDAP configuration with presets for AD
+	pub active_directory: Option<ActiveDirectorySourceConfig>,
+	/// Optional FreeIPA configuration, which is turned into an LDAP
+	/// configuration with presets for FreeIPA
+	pub freeipa: Option<FreeIpaSourceConfig>,
+	/// Optional Univention Corporate Server configuration, which is
+	/// turned into an LDAP configuration with presets for UCS
+	pub ucs: Option<UcsSourceConfig>,
 	/// Optional UKT configuration
 	pub ukt: Option<UktSourceConfig>,
 	/// Optional CSV configuration
 	pub csv: Option<CsvSourceConfig>,
+	/// Optional FHIR configuration
+	pub fhir: Option<FhirSourceConfig>,
+	/// Optional Microsoft Entra ID configuration
+	pub entra: Option<EntraSourceConfig>,
+	/// Optional Keycloak configuration
+	pub keycloak: Option<KeycloakSourceConfig>,
+}
+
+impl SourcesConfig {
+	/// The configured sources which can be merged with others
+	fn mergeable(&self) -> Vec<SourceKind> {
+		[
+			(SourceKind::Ldap, self.ldap.is_some()),
+			(SourceKind::Csv, self.csv.is_some()),
+			(SourceKind::Fhir, self.fhir.is_some()),
+			(SourceKind::Entra, self.entra.is_some()),
+			(SourceKind::Keycloak, self.keycloak.is_some()),
+		]
+		.into_iter()
+		.filter_map(|(kind, configured)| configured.then_some(kind))
+		.collect()
+	}
+}
+
+/// Configuration for the removal of data left behind by earlier
+/// syncs, e.g. after partial failures or configuration changes
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+pub struct GcConfig {
+	/// Metadata keys previously managed by the sync, which are
+	/// removed from all users unless they are still managed
+	#[serde(default)]
+	pub metadata_keys: Vec<String>,
+	/// Whether to remove project roles beyond the default role if no
+	/// rules grant roles
+	#[serde(default)]
+	pub roles: bool,
+	/// How to handle links to the configured IDP whose provided user
+	/// ID doesn't belong to any source user
+	#[serde(default)]
+	pub idp_links: IdpLinkGcMode,
+}
+
+/// How to handle stale IDP links
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum IdpLinkGcMode {
+	/// Leave IDP links untouched
+	Ignore,
+	/// Only include stale IDP links in the report
+	#[default]
+	Report,
+	/// Remove stale IDP links
+	Remove,
+}
+
+/// Configuration for the post-sync user count check
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct UserCountCheckConfig {
+	/// The maximum allowed difference between the number of enabled
+	/// source users and the number of users in Zitadel
+	#[serde(default)]
+	pub tolerance: usize,
+}
+
+/// Configuration for the plausibility check of the number of users
+/// returned by the source, which guards against mass deletions due to
+/// e.g. an empty CSV file or a broken LDAP filter
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct SourceUserCountCheckConfig {
+	/// The minimum number of enabled source users
+	#[serde(default)]
+	pub min_expected_users: usize,
+	/// The minimum number of enabled source users, as a percentage of
+	/// their number at the last sync. Requires `state_path`.
+	pub min_percent_of_last_sync: Option<u8>,
+}
+
+impl SourceUserCountCheckConfig {
+	/// Check the number of enabled source users against the limits,
+	/// given their number at the last sync, if known
+	pub(crate) fn check(
+		&self,
+		user_count: usize,
+		last_user_count: Option<usize>,
+	) -> Option<Message<'static>> {
+		if user_count < self.min_expected_users {
+			return Some(Message::TooFewSourceUsers {
+				user_count,
+				min_expected_users: self.min_expected_users,
+			});
+		}
+
+		if let (Some(min_percent), Some(last_user_count)) =
+			(self.min_percent_of_last_sync, last_user_count)
+		{
+			if user_count * 100 < last_user_count * usize::from(min_percent) {
+				return Some(Message::SourceUserCountDropped {
+					user_count,
+					last_user_count,
+					min_percent,
+				});
+			}
+		}
+
+		None
+	}
+}
+
+/// Configuration for the limit of the users a sync may delete, which
+/// guards against wiping the organization due to e.g. a transient empty
+/// LDAP result
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct DeletionGuardConfig {
+	/// The maximum number of users a sync may delete
+	pub max_deletions_absolute: Option<usize>,
+	/// The maximum number of users a sync may delete, as a percentage
+	/// of the managed Zitadel users
+	pub max_deletions_percent: Option<u8>,
+}
+
+impl DeletionGuardConfig {
+	/// Check the number of users to delete against the limits, given
+	/// the number of managed Zitadel users
+	pub(crate) fn check(
+		&self,
+		deletions: usize,
+		zitadel_user_count: usize,
+	) -> Option<Message<'static>> {
+		if let Some(max_deletions) = self.max_deletions_absolute {
+			if deletions > max_deletions {
+				return Some(Message::TooManyDeletions { deletions, max_deletions });
+			}
+		}
+
+		if let Some(max_percent) = self.max_deletions_percent {
+			if deletions * 100 > zitadel_user_count * usize::from(max_percent) {
+				return Some(Message::TooManyDeletionsPercent {
+					deletions,
+					zitadel_user_count,
+					max_percent,
+				});
+			}
+		}
+
+		None
+	}
 }
 
 impl Config {
 	/// Create new config from file and env var
 	pub fn new(path: &Path) -> Result<Self> {
-		let config_builder = config::Config::builder()
-			.add_source(config::File::from(path).required(false))
-			.add_source(
-				config::Environment::with_prefix(ENV_VAR_CONFIG_PREFIX)
-					.separator("__")
-					.list_separator(ENV_VAR_LIST_SEP)
-					.with_list_parse_key("sources.ldap.attributes.disable_bitmasks")
-					.with_list_parse_key("feature_flags")
-					.try_parsing(true),
-			);
+		Self::load(path).map(|(config, _)| config)
+	}
+
+	/// Create new config from file and env var, along with the paths of
+	/// all keys which aren't part of the configuration, e.g. due to
+	/// typos or wrong nesting. With the `strict_config` feature flag,
+	/// unknown keys are an error instead.
+	pub fn load(path: &Path) -> Result<(Self, Vec<String>)> {
+		let config_builder = Self::build(path)?;
+		let fingerprint = fingerprint(&config_builder)?;
+
+		let mut unknown_keys = Vec::new();
+		let mut config: Config = serde_ignored::deserialize(config_builder, |path| {
+			unknown_keys.push(format_key_path(&path));
+		})?;
+		unknown_keys.sort();
+		config.fingerprint = fingerprint;
+
+		if config.feature_flags.is_enabled(FeatureFlag::StrictConfig) && !unknown_keys.is_empty() {
+			bail!("Unknown configuration keys: {}", unknown_keys.join(", "));
+		}
+
+		Ok((config.validate()?, unknown_keys))
+	}
+
+	/// The configuration as read from file and env vars, including the
+	/// defaults of its profile, before validation, e.g. to inspect it as
+	/// given
+	pub(crate) fn load_raw(path: &Path) -> Result<serde_json::Value> {
+		Ok(Self::build(path)?.try_deserialize()?)
+	}
 
-		let config_builder = config_builder.build()?;
+	/// Merge the configuration file and env vars over the defaults of
+	/// the profile they select, if any
+	fn build(path: &Path) -> Result<config::Config> {
+		let config = Self::builder(path, None).build()?;
+		let profile = match config.get::<SyncProfile>("profile") {
+			Ok(profile) => profile,
+			Err(config::ConfigError::NotFound(_)) => return Ok(config),
+			Err(error) => return Err(error.into()),
+		};
 
-		let config: Config = config_builder.try_deserialize()?;
+		Ok(Self::builder(path, Some(profile)).build()?)
+	}
 
-		config.validate()
+	/// The sources of the configuration, in increasing precedence
+	fn builder(
+		path: &Path,
+		profile: Option<SyncProfile>,
+	) -> config::builder::ConfigBuilder<config::builder::DefaultState> {
+		let mut builder = config::Config::builder();
+		if let Some(profile) = profile {
+			builder = builder
+				.add_source(config::File::from_str(profile.defaults(), config::FileFormat::Yaml));
+		}
+
+		builder.add_source(config::File::from(path).required(false)).add_source(
+			config::Environment::with_prefix(ENV_VAR_CONFIG_PREFIX)
+				.separator("__")
+				.list_separator(ENV_VAR_LIST_SEP)
+				.with_list_parse_key("sources.ldap.attributes.disable_bitmasks")
+				.with_list_parse_key("feature_flags")
+				.try_parsing(true),
+		)
+	}
+
+	/// The Zitadel metadata keys managed by the sync in addition to
+	/// the built-in ones
+	#[must_use]
+	pub fn additional_metadata_keys(&self) -> Vec<String> {
+		let mut keys: Vec<String> = self
+			.sources
+			.ldap
+			.as_ref()
+			.map(|ldap| ldap.attributes.metadata.keys().cloned().collect())
+			.unwrap_or_default();
+
+		for key in rules::metadata_keys(&self.rules) {
+			if !keys.contains(key) {
+				keys.push(key.clone());
+			}
+		}
+
+		// Reading the marker lets syncs skip users already marked, and
+		// removes it from users who reappear in the source
+		match self.zitadel.deprovisioning {
+			DeprovisioningPolicy::Delete => {}
+			DeprovisioningPolicy::MarkPending => keys.push(PENDING_DEPROVISIONING_KEY.to_owned()),
+			DeprovisioningPolicy::Deactivate => keys.push(DEACTIVATED_AT_KEY.to_owned()),
+		}
+		if self.guests.is_some() {
+			keys.push(GUEST_EXPIRES_AT_KEY.to_owned());
+		}
+
+		keys
+	}
+
+	/// Whether the configured source or the rules provide the users'
+	/// preferred usernames. Otherwise, the preferred usernames in
+	/// Zitadel are left as they are.
+	#[must_use]
+	pub fn syncs_preferred_username(&self) -> bool {
+		self.rules.iter().any(|rule| rule.set.contains_key("preferred_username"))
+			|| self
+				.sources
+				.csv
+				.as_ref()
+				.is_some_and(|csv| csv.preferred_username != CsvPreferredUsername::Disabled)
+			|| self
+				.sources
+				.ldap
+				.as_ref()
+				.is_some_and(|ldap| ldap.attributes.preferred_username.is_some())
+			|| self.sources.fhir.as_ref().is_some_and(|fhir| fhir.username_system.is_some())
+			|| self
+				.sources
+				.entra
+				.as_ref()
+				.is_some_and(|entra| entra.attributes.preferred_username.is_some())
+			|| self
+				.sources
+				.keycloak
+				.as_ref()
+				.is_some_and(|keycloak| keycloak.attributes.preferred_username.is_some())
 	}
 
 	/// Validate the config and return a valid configuration
 	fn validate(mut self) -> Result<Self> {
+		if self.feature_flags.is_enabled(FeatureFlag::ShadowRun) {
+			let Some(shadow_zitadel) = self.shadow_zitadel.take() else {
+				bail!("The `shadow_run` feature flag requires `shadow_zitadel` to be set");
+			};
+			self.zitadel = shadow_zitadel;
+		}
 		self.zitadel.url = validate_zitadel_url(self.zitadel.url)?;
+		if self.zitadel.max_requests_per_second == Some(0) {
+			bail!("`zitadel.max_requests_per_second` must be greater than 0");
+		}
+		if self.zitadel.page_size == 0 {
+			bail!("`zitadel.page_size` must be greater than 0");
+		}
+		if self.zitadel.request_burst.is_some() {
+			if self.zitadel.max_requests_per_second.is_none() {
+				bail!(
+					"`zitadel.request_burst` requires `zitadel.max_requests_per_second` to be set"
+				);
+			}
+			if self.zitadel.request_burst == Some(0) {
+				bail!("`zitadel.request_burst` must be greater than 0");
+			}
+		}
+
+		if self.feature_flags.is_enabled(FeatureFlag::Metrics) {
+			let Some(metrics) = &self.metrics else {
+				bail!("The `metrics` feature flag requires `metrics` to be set");
+			};
+			if metrics.textfile_path.is_none() && metrics.pushgateway_url.is_none() {
+				bail!("`metrics` requires `textfile_path` or `pushgateway_url` to be set");
+			}
+		}
+
+		if let Some(active_directory) = self.sources.active_directory.take() {
+			if self.sources.ldap.is_some() {
+				bail!("Only one of the LDAP and Active Directory sources may be defined");
+			}
+			self.sources.ldap = Some(active_directory.into());
+		}
+		if let Some(freeipa) = self.sources.freeipa.take() {
+			if self.sources.ldap.is_some() {
+				bail!("Only one of the LDAP, Active Directory and FreeIPA sources may be defined");
+			}
+			self.sources.ldap = Some(freeipa.into());
+		}
+		if let Some(ucs) = self.sources.ucs.take() {
+			if self.sources.ldap.is_some() {
+				bail!("Only one of the LDAP, Active Directory, FreeIPA and UCS sources may be defined");
+			}
+			self.sources.ldap = Some(ucs.into());
+		}
+		if let Some(csv) = &self.sources.csv {
+			csv.validate()?;
+		}
+		if let Some(ukt) = &self.sources.ukt {
+			ukt.validate()?;
+		}
+		rules::validate_rules(&self.rules)?;
+		normalization::validate(&self.comparison)?;
+
+		if let Some(rename_detection) = &self.rename_detection {
+			rename_detection.validate()?;
+		}
+		if self.identity == IdentityPolicy::ExternalIdOnly {
+			if self.rename_detection.as_ref().is_some_and(|rename_detection| {
+				rename_detection.match_keys.iter().any(|key| key == "email")
+			}) {
+				bail!(
+					"Rename detection can't match users by `email` with `identity: \
+					 external_id_only`, since email addresses are reused"
+				);
+			}
+			if self.sources.ukt.is_some() {
+				bail!(
+					"The UKT source deletes users by email address, which can't be used with \
+					 `identity: external_id_only`"
+				);
+			}
+		}
+
+		// Merging personas requires all of them, while changes of the
+		// source may only include some
+		if self.personas.is_some() {
+			if self.sources.ldap.as_ref().is_some_and(|ldap| {
+				ldap.dirsync.is_some() || ldap.incremental.is_some() || ldap.watch.is_some()
+			}) {
+				bail!("`personas` can't be used with LDAP DirSync, incremental sync or watching");
+			}
+			if self.sources.entra.as_ref().is_some_and(|entra| entra.delta) {
+				bail!("`personas` can't be used with Entra ID delta queries");
+			}
+		}
+
+		// Guests only expire when they are read from the source, which
+		// changes of the source don't do for unchanged guests
+		if let Some(guests) = &self.guests {
+			if self.sources.ldap.as_ref().is_some_and(|ldap| {
+				ldap.dirsync.is_some() || ldap.incremental.is_some() || ldap.watch.is_some()
+			}) {
+				bail!("`guests` can't be used with LDAP DirSync, incremental sync or watching");
+			}
+			if self.sources.entra.as_ref().is_some_and(|entra| entra.delta) {
+				bail!("`guests` can't be used with Entra ID delta queries");
+			}
+			if guests.deactivation_grace_days.is_some()
+				&& self.zitadel.deprovisioning != DeprovisioningPolicy::Deactivate
+			{
+				bail!("`guests.deactivation_grace_days` requires `deprovisioning: deactivate`");
+			}
+		}
+
+		if let Some(min_percent) =
+			self.source_user_count_check.as_ref().and_then(|check| check.min_percent_of_last_sync)
+		{
+			if min_percent > 100 {
+				bail!("`min_percent_of_last_sync` must be at most 100");
+			}
+			if self.state_path.is_none() {
+				bail!(
+					"`min_percent_of_last_sync` requires `state_path` to be set, to store the \
+					 number of users at the last sync"
+				);
+			}
+		}
+
+		if let Some(deletion_guard) = &self.deletion_guard {
+			if deletion_guard.max_deletions_absolute.is_none()
+				&& deletion_guard.max_deletions_percent.is_none()
+			{
+				bail!(
+					"`deletion_guard` requires `max_deletions_absolute` or \
+					 `max_deletions_percent` to be set"
+				);
+			}
+			if deletion_guard.max_deletions_percent.is_some_and(|percent| percent > 100) {
+				bail!("`max_deletions_percent` must be at most 100");
+			}
+		}
+
+		if self.zitadel.deactivation_grace_days.is_some()
+			&& self.zitadel.deprovisioning != DeprovisioningPolicy::Deactivate
+		{
+			bail!("`deactivation_grace_days` requires `deprovisioning: deactivate`");
+		}
+
+		if self.sources.ldap.as_ref().is_some_and(|ldap| ldap.dirsync.is_some())
+			&& self.state_path.is_none()
+		{
+			bail!("LDAP DirSync requires `state_path` to be set, to store the DirSync cookie");
+		}
+		if let Some(ldap) = self.sources.ldap.as_ref().filter(|ldap| ldap.incremental.is_some()) {
+			if ldap.dirsync.is_some() {
+				bail!("Only one of LDAP DirSync and incremental sync may be configured");
+			}
+			if ldap.attributes.last_modified.is_none() {
+				bail!("Incremental LDAP sync requires `attributes.last_modified` to be set");
+			}
+			if self.state_path.is_none() {
+				bail!(
+					"Incremental LDAP sync requires `state_path` to be set, to store when the \
+					 last sync happened"
+				);
+			}
+		}
+
+		if let Some(entra) = self.sources.entra.as_ref().filter(|entra| entra.delta) {
+			if self.state_path.is_none() {
+				bail!(
+					"Entra ID delta queries require `state_path` to be set, to store the delta link"
+				);
+			}
+			if entra.attributes.external_user_id != "id" {
+				bail!(
+					"Entra ID delta queries require `attributes.external_user_id` to be `id`, \
+					 since removed users are only identified by it"
+				);
+			}
+			if entra.filter.is_some() {
+				bail!("Entra ID delta queries can't be used with `filter`");
+			}
+		}
+
+		if let Some(anonymization) = &self.anonymization {
+			if anonymization.secret.is_empty() {
+				bail!("`anonymization.secret` must not be empty");
+			}
+			// Loading fake users into the organization of the real ones
+			// would replace them
+			if anonymization.organization_id == self.zitadel.organization_id {
+				bail!("`anonymization.organization_id` must not be the synced organization");
+			}
+		}
+
+		if let Some(source_guard) = &self.source_guard {
+			source_guard.validate(self.state_path.is_some())?;
+		}
+
+		let merged_sources = self.sources.mergeable();
+		match &self.source_merge {
+			Some(source_merge) => {
+				source_merge.validate(&merged_sources)?;
+				if self
+					.sources
+					.ldap
+					.as_ref()
+					.is_some_and(|ldap| ldap.dirsync.is_some() || ldap.incremental.is_some())
+				{
+					bail!("LDAP DirSync and incremental sync can't be used with `source_merge`");
+				}
+				if self.sources.entra.as_ref().is_some_and(|entra| entra.delta) {
+					bail!("Entra ID delta queries can't be used with `source_merge`");
+				}
+			}
+			None if merged_sources.len() > 1 => {
+				bail!("Syncing from more than one source requires `source_merge` to be set");
+			}
+			None => {}
+		}
 
 		Ok(self)
 	}
@@ -87,6 +752,18 @@ pub enum FeatureFlag {
 	DeactivateOnly,
 	/// Use plain localpart
 	PlainLocalpart,
+	/// Import and update users, but only log deletions instead of
+	/// deleting users
+	DryRunDeletions,
+	/// Sync to the Zitadel instance configured as `shadow_zitadel`,
+	/// e.g. a staging instance, instead of the production one
+	ShadowRun,
+	/// Refuse to start if the configuration contains unknown keys,
+	/// instead of only warning about them
+	StrictConfig,
+	/// Export the metrics of each sync to the targets configured in
+	/// `metrics`
+	Metrics,
 }
 
 #[derive(Debug, Clone, Deserialize, PartialEq, Default)]
@@ -114,6 +791,34 @@ impl FeatureFlags {
 }
 
 /// Validate the Zitadel URL provided by Famedly
+/// The fingerprint of an effective configuration, i.e. the hash of the
+/// file, env vars and profile defaults merged, with secrets redacted so
+/// that rotating them doesn't change it
+fn fingerprint(config: &config::Config) -> Result<String> {
+	let mut raw_config: serde_json::Value = config.clone().try_deserialize()?;
+	support_bundle::redact(&mut raw_config);
+
+	Ok(hex::encode(Sha256::digest(serde_json::to_vec(&raw_config)?)))
+}
+
+/// Format the path of a configuration key as in YAML, e.g.
+/// `sources.ldap.attributes.name`
+fn format_key_path(path: &serde_ignored::Path<'_>) -> String {
+	match path {
+		serde_ignored::Path::Root => String::new(),
+		serde_ignored::Path::Seq { parent, index } => {
+			format!("{}[{index}]", format_key_path(parent))
+		}
+		serde_ignored::Path::Map { parent, key } => match format_key_path(parent) {
+			parent if parent.is_empty() => key.clone(),
+			parent => format!("{parent}.{key}"),
+		},
+		serde_ignored::Path::Some { parent }
+		| serde_ignored::Path::NewtypeStruct { parent }
+		| serde_ignored::Path::NewtypeVariant { parent } => format_key_path(parent),
+	}
+}
+
 fn validate_zitadel_url(url: Url) -> Result<Url> {
 	// If a URL contains a port, the domain name may appear as a
 	// scheme and pass through URL parsing despite lacking a scheme
@@ -134,6 +839,7 @@ mod tests {
 	use tempfile::TempDir;
 
 	use super::*;
+	use crate::{guests, user::User, zitadel::MissingEmailPolicy};
 
 	const EXAMPLE_CONFIG: &str = indoc! {r#"
         zitadel:
@@ -235,6 +941,48 @@ mod tests {
 		assert!(validate_zitadel_url(url).is_err());
 	}
 
+	#[test]
+	fn test_source_user_count_check() {
+		let check = SourceUserCountCheckConfig {
+			min_expected_users: 10,
+			min_percent_of_last_sync: Some(80),
+		};
+
+		assert!(check.check(100, None).is_none());
+		assert!(check.check(80, Some(100)).is_none());
+		assert!(matches!(
+			check.check(79, Some(100)),
+			Some(Message::SourceUserCountDropped { user_count: 79, last_user_count: 100, .. })
+		));
+		assert!(matches!(
+			check.check(0, None),
+			Some(Message::TooFewSourceUsers { user_count: 0, min_expected_users: 10 })
+		));
+	}
+
+	#[test]
+	fn test_deletion_guard() {
+		let guard = DeletionGuardConfig {
+			max_deletions_absolute: Some(50),
+			max_deletions_percent: Some(10),
+		};
+
+		assert!(guard.check(10, 100).is_none());
+		assert!(matches!(
+			guard.check(11, 100),
+			Some(Message::TooManyDeletionsPercent { deletions: 11, zitadel_user_count: 100, .. })
+		));
+		assert!(matches!(
+			guard.check(51, 1000),
+			Some(Message::TooManyDeletions { deletions: 51, max_deletions: 50 })
+		));
+
+		let mut config = load_config();
+		config.deletion_guard =
+			Some(DeletionGuardConfig { max_deletions_absolute: None, max_deletions_percent: None });
+		assert!(config.validate().is_err());
+	}
+
 	#[tokio::test]
 	async fn test_sample_config() {
 		let config = Config::new(Path::new("./sample-configs/csv-config.sample.yaml"));
@@ -243,17 +991,54 @@ mod tests {
 		assert!(config.is_ok(), "Invalid config: {:?}", config);
 		let config = Config::new(Path::new("./sample-configs/ukt-config.sample.yaml"));
 		assert!(config.is_ok(), "Invalid config: {:?}", config);
+		let config = Config::new(Path::new("./sample-configs/ad-config.sample.yaml"));
+		assert!(config.is_ok(), "Invalid config: {:?}", config);
+		let config = Config::new(Path::new("./sample-configs/freeipa-config.sample.yaml"));
+		assert!(config.is_ok(), "Invalid config: {:?}", config);
+		let config = Config::new(Path::new("./sample-configs/ucs-config.sample.yaml"));
+		assert!(config.is_ok(), "Invalid config: {:?}", config);
+		let config = Config::new(Path::new("./sample-configs/fhir-config.sample.yaml"));
+		assert!(config.is_ok(), "Invalid config: {:?}", config);
+		let config = Config::new(Path::new("./sample-configs/entra-config.sample.yaml"));
+		assert!(config.is_ok(), "Invalid config: {:?}", config);
+		let config = Config::new(Path::new("./sample-configs/keycloak-config.sample.yaml"));
+		assert!(config.is_ok(), "Invalid config: {:?}", config);
 	}
 
 	#[test]
 	fn test_config_from_file() {
 		let tempdir = TempDir::new().expect("failed to initialize tempdir");
 		let file_path = create_config_file(tempdir.path());
-		let config = Config::new(file_path.as_path()).expect("Failed to create config object");
+		let mut config = Config::new(file_path.as_path()).expect("Failed to create config object");
 
+		// The fingerprint is only set when loading from file
+		assert_eq!(config.fingerprint.len(), 64);
+		config.fingerprint = String::new();
 		assert_eq!(load_config(), config);
 	}
 
+	#[test]
+	fn test_config_fingerprint() {
+		let tempdir = TempDir::new().expect("failed to initialize tempdir");
+		let file_path = tempdir.path().join("config.yaml");
+		let fingerprint = |config: &str| {
+			std::fs::write(&file_path, format!("{EXAMPLE_CONFIG}{config}"))
+				.expect("failed to write config");
+			Config::new(&file_path).expect("Failed to create config object").fingerprint
+		};
+
+		let scim = |bearer_token: &str| {
+			format!("scim:\n  listen_address: 127.0.0.1:8080\n  bearer_token: {bearer_token}\n")
+		};
+		assert_eq!(fingerprint(&scim("secret")), fingerprint(&scim("secret")));
+		// Secrets don't change the fingerprint, other options do
+		assert_eq!(fingerprint(&scim("secret")), fingerprint(&scim("rotated")));
+		assert_ne!(
+			fingerprint(&scim("secret")),
+			fingerprint(&format!("{}max_changes_per_run: 10\n", scim("secret")))
+		);
+	}
+
 	#[test]
 	fn test_config_env_var_override() {
 		let tempdir = TempDir::new().expect("failed to initialize tempdir");
@@ -262,9 +1047,10 @@ mod tests {
 		let env_var_name = format!("{ENV_VAR_CONFIG_PREFIX}__FEATURE_FLAGS");
 		env::set_var(&env_var_name, "dry_run");
 
-		let loaded_config =
+		let mut loaded_config =
 			Config::new(file_path.as_path()).expect("Failed to create config object");
 		env::remove_var(env_var_name);
+		loaded_config.fingerprint = String::new();
 
 		let mut sample_config = load_config();
 		sample_config.feature_flags.push(FeatureFlag::DryRun);
@@ -272,6 +1058,43 @@ mod tests {
 		assert_eq!(sample_config, loaded_config);
 	}
 
+	#[test]
+	fn test_profile() {
+		let tempdir = TempDir::new().expect("failed to initialize tempdir");
+		let file_path = tempdir.path().join("config.yaml");
+		let config = indoc! {r#"
+			profile: strict
+			deletion_guard:
+			  max_deletions_absolute: 50
+			second_factor_protection:
+			  max_deletions: 2
+		"#};
+		std::fs::write(&file_path, format!("{EXAMPLE_CONFIG}{config}"))
+			.expect("failed to write config");
+
+		let config = Config::new(&file_path).expect("Failed to create config object");
+		assert_eq!(config.profile, Some(SyncProfile::Strict));
+		assert_eq!(config.initial_sync, InitialSyncPolicy::RequireConfirmation);
+		assert_eq!(config.watchdog.map(|watchdog| watchdog.stall_timeout_minutes), Some(30));
+		// Options set explicitly take precedence, also within sections
+		// the profile sets
+		let deletion_guard = config.deletion_guard.expect("deletion guard must be configured");
+		assert_eq!(deletion_guard.max_deletions_absolute, Some(50));
+		assert_eq!(deletion_guard.max_deletions_percent, Some(5));
+		assert!(config.second_factor_protection.check);
+		assert_eq!(config.second_factor_protection.max_deletions, 2);
+
+		std::fs::write(&file_path, format!("{EXAMPLE_CONFIG}profile: legacy\n"))
+			.expect("failed to write config");
+		let config = Config::new(&file_path).expect("Failed to create config object");
+		assert!(!config.second_factor_protection.check);
+		assert!(config.deletion_guard.is_none());
+
+		std::fs::write(&file_path, format!("{EXAMPLE_CONFIG}profile: reckless\n"))
+			.expect("failed to write config");
+		assert!(Config::new(&file_path).is_err());
+	}
+
 	#[test]
 	fn test_no_config_file() {
 		let env_vars = example_env_vars();
@@ -291,6 +1114,279 @@ mod tests {
 		assert_eq!(load_config(), config);
 	}
 
+	#[test]
+	fn test_shadow_run() {
+		let mut config = load_config();
+		config.feature_flags.push(FeatureFlag::ShadowRun);
+		assert!(config.clone().validate().is_err());
+
+		let mut shadow_zitadel = config.zitadel.clone();
+		shadow_zitadel.organization_id = "2".to_owned();
+		config.shadow_zitadel = Some(shadow_zitadel);
+		let config = config.validate().expect("invalid config");
+		assert_eq!(config.zitadel.organization_id, "2");
+	}
+
+	#[test]
+	fn test_metrics() {
+		let mut config = load_config();
+		config.feature_flags.push(FeatureFlag::Metrics);
+		assert!(config.clone().validate().is_err());
+
+		config.metrics = Some(MetricsConfig {
+			textfile_path: None,
+			pushgateway_url: None,
+			job: "sync".to_owned(),
+		});
+		assert!(config.clone().validate().is_err());
+
+		config.metrics.as_mut().expect("metrics must be configured").textfile_path =
+			Some(PathBuf::from("famedly_sync.prom"));
+		assert!(config.validate().is_ok());
+	}
+
+	#[test]
+	fn test_pending_deprovisioning_metadata() {
+		let mut config = load_config();
+		assert!(!config
+			.additional_metadata_keys()
+			.contains(&PENDING_DEPROVISIONING_KEY.to_owned()));
+
+		config.zitadel.deprovisioning = DeprovisioningPolicy::MarkPending;
+		assert!(config.additional_metadata_keys().contains(&PENDING_DEPROVISIONING_KEY.to_owned()));
+
+		config.zitadel.deprovisioning = DeprovisioningPolicy::Deactivate;
+		assert!(config.additional_metadata_keys().contains(&DEACTIVATED_AT_KEY.to_owned()));
+		assert!(!config
+			.additional_metadata_keys()
+			.contains(&PENDING_DEPROVISIONING_KEY.to_owned()));
+	}
+
+	#[test]
+	fn test_deactivation_grace_period() {
+		let mut config = load_config();
+		let now = chrono::Utc::now();
+		let deactivated_at = (now - chrono::Duration::days(10)).to_rfc3339();
+		assert!(!config.zitadel.deactivation_expired(&deactivated_at, now));
+
+		config.zitadel.deactivation_grace_days = Some(30);
+		assert!(!config.zitadel.deactivation_expired(&deactivated_at, now));
+		config.zitadel.deactivation_grace_days = Some(10);
+		assert!(config.zitadel.deactivation_expired(&deactivated_at, now));
+		assert!(!config.zitadel.deactivation_expired("yesterday", now));
+
+		assert!(config.clone().validate().is_err());
+		config.zitadel.deprovisioning = DeprovisioningPolicy::Deactivate;
+		assert!(config.validate().is_ok());
+	}
+
+	#[test]
+	fn test_guest_grace_period() {
+		let mut config = load_config();
+		config.zitadel.deactivation_grace_days = Some(30);
+		config.guests = Some(GuestConfig {
+			when: serde_yaml::from_str("'employee_type == \"external\"'")
+				.expect("invalid expression"),
+			expires_attribute: "contract_end".to_owned(),
+			role: None,
+			deactivation_grace_days: Some(7),
+		});
+		assert!(config.additional_metadata_keys().contains(&GUEST_EXPIRES_AT_KEY.to_owned()));
+
+		let now = chrono::Utc::now();
+		let deactivated_at = (now - chrono::Duration::days(10)).to_rfc3339();
+		let mut user = User::new(
+			"John".to_owned(),
+			"Doe".to_owned(),
+			"john.doe@example.com".to_owned(),
+			None,
+			false,
+			None,
+			"jdoe".to_owned(),
+			None,
+		);
+		assert!(!guests::deactivation_expired(&config, &user, &deactivated_at, now));
+		user.metadata.insert(GUEST_EXPIRES_AT_KEY.to_owned(), now.to_rfc3339());
+		assert!(guests::deactivation_expired(&config, &user, &deactivated_at, now));
+
+		assert!(config.clone().validate().is_err());
+		config.zitadel.deprovisioning = DeprovisioningPolicy::Deactivate;
+		assert!(config.validate().is_ok());
+	}
+
+	#[test]
+	fn test_anonymization_config() {
+		let mut config = load_config();
+		config.anonymization = Some(AnonymizationConfig {
+			secret: "secret".to_owned(),
+			organization_id: config.zitadel.organization_id.clone(),
+			keep_metadata: Vec::new(),
+			pseudonymize_ids: false,
+		});
+		assert!(config.clone().validate().is_err());
+
+		let anonymization = config.anonymization.as_mut().expect("anonymization must be set");
+		anonymization.organization_id = "staging".to_owned();
+		assert!(config.clone().validate().is_ok());
+
+		config.anonymization.as_mut().expect("anonymization must be set").secret = String::new();
+		assert!(config.validate().is_err());
+	}
+
+	#[test]
+	fn test_identity_policy() {
+		let mut config = load_config();
+		config.rename_detection =
+			Some(RenameDetectionConfig { match_keys: vec!["email".to_owned()] });
+		assert!(config.clone().validate().is_ok());
+
+		config.identity = IdentityPolicy::ExternalIdOnly;
+		assert!(config.clone().validate().is_err());
+
+		config.rename_detection =
+			Some(RenameDetectionConfig { match_keys: vec!["personnel_number".to_owned()] });
+		assert!(config.validate().is_ok());
+	}
+
+	#[test]
+	fn test_missing_email_policy() {
+		// Users without an email address are left untouched by default
+		let config = load_config();
+		assert_eq!(config.zitadel.missing_email, MissingEmailPolicy::Report);
+		assert!(!config.zitadel.missing_email.keeps_user("1"));
+
+		for (value, keeps_user) in [("skip", false), ("report", false), ("match", true)] {
+			let policy: MissingEmailPolicy =
+				serde_yaml::from_str(value).expect("invalid missing email policy");
+			assert_eq!(policy.keeps_user("1"), keeps_user, "{value}");
+		}
+		assert!(serde_yaml::from_str::<MissingEmailPolicy>("abort").is_err());
+	}
+
+	#[test]
+	fn test_rate_limit() {
+		let mut config = load_config();
+		config.zitadel.request_burst = Some(20);
+		assert!(config.clone().validate().is_err());
+
+		config.zitadel.max_requests_per_second = Some(10);
+		assert!(config.clone().validate().is_ok());
+
+		config.zitadel.request_burst = Some(0);
+		assert!(config.clone().validate().is_err());
+
+		config.zitadel.request_burst = None;
+		config.zitadel.max_requests_per_second = Some(0);
+		assert!(config.validate().is_err());
+	}
+
+	#[test]
+	fn test_page_size() {
+		let config = load_config();
+		assert_eq!(config.zitadel.page_size, 100);
+
+		let mut config: Config = serde_yaml::from_str(
+			&EXAMPLE_CONFIG.replace("idp_id: 1", "idp_id: 1\n  page_size: 500"),
+		)
+		.expect("invalid config");
+		assert_eq!(config.zitadel.page_size, 500);
+		assert!(config.clone().validate().is_ok());
+
+		config.zitadel.page_size = 0;
+		assert!(config.validate().is_err());
+	}
+
+	#[test]
+	fn test_syncs_preferred_username() {
+		let mut config = load_config();
+		assert!(!config.syncs_preferred_username());
+
+		config.sources.csv = Some(CsvSourceConfig {
+			file_path: PathBuf::from("users.csv"),
+			preferred_username: CsvPreferredUsername::default(),
+			verification: None,
+			download: None,
+		});
+		assert!(config.syncs_preferred_username());
+
+		config.sources.csv.as_mut().expect("csv must be configured").preferred_username =
+			CsvPreferredUsername::Disabled;
+		assert!(!config.syncs_preferred_username());
+	}
+
+	#[test]
+	fn test_source_merge() {
+		let mut config = Config::new(Path::new("./sample-configs/ldap-config.sample.yaml"))
+			.expect("invalid config");
+		config.sources.csv = Some(CsvSourceConfig {
+			file_path: PathBuf::from("contractors.csv"),
+			preferred_username: CsvPreferredUsername::default(),
+			verification: None,
+			download: None,
+		});
+		assert!(config.clone().validate().is_err());
+
+		config.source_merge = Some(SourceMergeConfig {
+			priority: vec![SourceKind::Ldap, SourceKind::Csv],
+			strategy: crate::sources::merged::MergeStrategy::FieldMerge,
+		});
+		assert!(config.clone().validate().is_ok());
+
+		config.sources.csv = None;
+		assert!(config.validate().is_err());
+	}
+
+	#[test]
+	fn test_entra_delta() {
+		let mut config = Config::new(Path::new("./sample-configs/entra-config.sample.yaml"))
+			.expect("invalid config");
+		let entra = config.sources.entra.as_mut().expect("entra must be configured");
+		entra.delta = true;
+		assert!(config.clone().validate().is_err());
+
+		config.state_path = Some(PathBuf::from("state.json"));
+		assert!(config.clone().validate().is_ok());
+
+		let entra = config.sources.entra.as_mut().expect("entra must be configured");
+		entra.attributes.external_user_id = "employeeId".to_owned();
+		assert!(config.clone().validate().is_err());
+	}
+
+	#[test]
+	fn test_unknown_keys() {
+		let tempdir = TempDir::new().expect("failed to initialize tempdir");
+		let file_path = tempdir.path().join("config.yaml");
+		let config = indoc! {r#"
+			zitadel:
+			  url: http://localhost:8080
+			  key_file: tests/environment/zitadel/service-user.json
+			  organization_id: 1
+			  project_id: 1
+			  idp_id: 1
+			  organisation_id: 1
+
+			sources:
+			  csv:
+			    file_path: users.csv
+			    delimiter: ";"
+
+			featureflags: [dry_run]
+		"#};
+		std::fs::write(&file_path, config).expect("failed to write config");
+
+		let (_, unknown_keys) = Config::load(&file_path).expect("failed to load config");
+		assert_eq!(
+			unknown_keys,
+			vec!["featureflags", "sources.csv.delimiter", "zitadel.organisation_id"]
+		);
+
+		let strict_config = format!("{config}feature_flags: [strict_config]\n");
+		std::fs::write(&file_path, strict_config).expect("failed to write config");
+
+		let error = Config::load(&file_path).expect_err("unknown keys were accepted");
+		assert!(error.to_string().contains("featureflags, sources.csv.delimiter"));
+	}
+
 	#[test]
 	fn test_config_env_var_feature_flag() {
 		let tempdir = TempDir::new().expect("failed to initialize tempdir");
@@ -0,0 +1,328 @@
+//! Long-running mode running syncs on a cron schedule
+//!
+//! Deployments would otherwise need an external cron job or systemd
+//! timer to start each sync. Syncs run one after the other: a sync
+//! taking longer than the schedule allows is never overlapped by the
+//! next one, whose run is skipped instead. SIGTERM and SIGINT stop the
+//! daemon, after the running sync, if any, finished.
+use std::{
+	future::Future,
+	pin::{pin, Pin},
+	str::FromStr,
+};
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveTime, Timelike, Utc};
+use serde::Deserialize;
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::{perform_sync, report::SyncReport, Config};
+
+/// The number of years after which a schedule which didn't match yet
+/// is considered to never match, which covers leap days
+const MAX_SCHEDULE_YEARS: i32 = 8;
+
+/// A cron schedule of the form `minute hour day-of-month month
+/// day-of-week`, in UTC
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(try_from = "String")]
+pub struct Schedule {
+	/// The expression the schedule was parsed from
+	expression: String,
+	/// The matching minutes, as bits
+	minutes: u64,
+	/// The matching hours, as bits
+	hours: u64,
+	/// The matching days of the month, as bits
+	days_of_month: u64,
+	/// The matching months, as bits
+	months: u64,
+	/// The matching days of the week, as bits, starting with Sunday
+	days_of_week: u64,
+	/// Whether both day fields are restricted, in which case days
+	/// matching either of them match, as in cron
+	either_day: bool,
+}
+
+impl FromStr for Schedule {
+	type Err = anyhow::Error;
+
+	fn from_str(expression: &str) -> Result<Self> {
+		let fields: Vec<&str> = expression.split_whitespace().collect();
+		let [minutes, hours, days_of_month, months, days_of_week] = fields[..] else {
+			bail!(
+				"Invalid schedule `{}`, expected the five fields `minute hour day-of-month \
+				 month day-of-week`",
+				expression
+			);
+		};
+
+		let mut schedule = Self {
+			expression: fields.join(" "),
+			minutes: parse_field(minutes, "minute", 0, 59)?,
+			hours: parse_field(hours, "hour", 0, 23)?,
+			days_of_month: parse_field(days_of_month, "day-of-month", 1, 31)?,
+			months: parse_field(months, "month", 1, 12)?,
+			days_of_week: parse_field(days_of_week, "day-of-week", 0, 7)?,
+			either_day: !days_of_month.starts_with('*') && !days_of_week.starts_with('*'),
+		};
+		// Both 0 and 7 are Sunday
+		if schedule.days_of_week & (1 << 7) != 0 {
+			schedule.days_of_week = schedule.days_of_week & !(1 << 7) | 1;
+		}
+
+		if schedule.next_after(DateTime::UNIX_EPOCH).is_none() {
+			bail!("The schedule `{}` never matches", expression);
+		}
+
+		Ok(schedule)
+	}
+}
+
+impl TryFrom<String> for Schedule {
+	type Error = anyhow::Error;
+
+	fn try_from(expression: String) -> Result<Self> {
+		expression.parse()
+	}
+}
+
+impl std::fmt::Display for Schedule {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str(&self.expression)
+	}
+}
+
+impl Schedule {
+	/// The first time matching the schedule after the given time
+	#[must_use]
+	pub fn next_after(&self, time: DateTime<Utc>) -> Option<DateTime<Utc>> {
+		let mut time = time.naive_utc().with_second(0)?.with_nanosecond(0)? + Duration::minutes(1);
+		let last_year = time.year() + MAX_SCHEDULE_YEARS;
+
+		// Skip whole months, days and hours which don't match before
+		// looking for a matching minute
+		while time.year() <= last_year {
+			if !matches(self.months, time.month()) {
+				let (year, month) = if time.month() == 12 {
+					(time.year() + 1, 1)
+				} else {
+					(time.year(), time.month() + 1)
+				};
+				time = NaiveDate::from_ymd_opt(year, month, 1)?.and_time(NaiveTime::MIN);
+			} else if !self.matches_day(time.date()) {
+				time = time.date().succ_opt()?.and_time(NaiveTime::MIN);
+			} else if !matches(self.hours, time.hour()) {
+				time = time.with_minute(0)? + Duration::hours(1);
+			} else if !matches(self.minutes, time.minute()) {
+				time += Duration::minutes(1);
+			} else {
+				return Some(time.and_utc());
+			}
+		}
+
+		None
+	}
+
+	/// Whether the given day matches the day fields of the schedule
+	fn matches_day(&self, date: NaiveDate) -> bool {
+		let day_of_month = matches(self.days_of_month, date.day());
+		let day_of_week = matches(self.days_of_week, date.weekday().num_days_from_sunday());
+
+		if self.either_day {
+			day_of_month || day_of_week
+		} else {
+			day_of_month && day_of_week
+		}
+	}
+}
+
+/// Whether the given value is set in the bits of a field
+fn matches(bits: u64, value: u32) -> bool {
+	bits & (1 << value) != 0
+}
+
+/// Parse a field of a cron expression, i.e. a list of values, ranges
+/// or `*`, each optionally followed by a step, into bits
+fn parse_field(field: &str, name: &str, min: u32, max: u32) -> Result<u64> {
+	let parse_value = |value: &str| {
+		value.parse::<u32>().ok().filter(|value| (min..=max).contains(value)).context(format!(
+			"Invalid value `{}` in the {} field of the schedule, expected {}-{}",
+			value, name, min, max
+		))
+	};
+
+	let mut bits = 0;
+	for item in field.split(',') {
+		let (range, step) = match item.split_once('/') {
+			Some((range, step)) => (
+				range,
+				step.parse::<usize>().ok().filter(|step| *step > 0).context(format!(
+					"Invalid step `{}` in the {} field of the schedule",
+					step, name
+				))?,
+			),
+			None => (item, 1),
+		};
+
+		let (start, end) = match range.split_once('-') {
+			_ if range == "*" => (min, max),
+			Some((start, end)) => (parse_value(start)?, parse_value(end)?),
+			// A single value with a step starts a range
+			None if item.contains('/') => (parse_value(range)?, max),
+			None => {
+				let value = parse_value(range)?;
+				(value, value)
+			}
+		};
+		if start > end {
+			bail!("Invalid range `{}` in the {} field of the schedule", range, name);
+		}
+
+		for value in (start..=end).step_by(step) {
+			bits |= 1 << value;
+		}
+	}
+
+	Ok(bits)
+}
+
+/// Run syncs on the configured schedule until the process receives
+/// SIGTERM or SIGINT
+pub async fn run_daemon(config: &Config) -> Result<()> {
+	let schedule = config.schedule.as_ref().context("The daemon requires `schedule` to be set")?;
+	let mut shutdown = pin!(shutdown_signal()?);
+
+	tracing::info!("Running syncs on the schedule `{}`", schedule);
+	let mut next_run = run_after(schedule, Utc::now())?;
+
+	loop {
+		tracing::info!("Next sync at {}", next_run.to_rfc3339());
+		let wait = (next_run - Utc::now()).to_std().unwrap_or_default();
+		tokio::select! {
+			() = tokio::time::sleep(wait) => {}
+			() = &mut shutdown => {
+				tracing::info!("Shutting down");
+				return Ok(());
+			}
+		}
+
+		if run_until_shutdown(perform_sync(config), &mut shutdown).await.is_none() {
+			return Ok(());
+		}
+
+		let due_run = run_after(schedule, next_run)?;
+		next_run = run_after(schedule, Utc::now())?;
+		if due_run < next_run {
+			tracing::warn!(
+				"The sync took longer than the schedule allows, skipping the syncs due since {}",
+				due_run.to_rfc3339()
+			);
+		}
+	}
+}
+
+/// The first run of the schedule after the given time
+fn run_after(schedule: &Schedule, after: DateTime<Utc>) -> Result<DateTime<Utc>> {
+	schedule.next_after(after).context(format!("The schedule `{}` doesn't match again", schedule))
+}
+
+/// Run a sync, logging its failure. If a shutdown is requested
+/// meanwhile, the sync is finished first and `None` is returned.
+pub(crate) async fn run_until_shutdown(
+	sync: impl Future<Output = Result<SyncReport>>,
+	shutdown: &mut Pin<&mut impl Future<Output = ()>>,
+) -> Option<Result<SyncReport>> {
+	let mut sync = pin!(sync);
+	let (result, shutting_down) = tokio::select! {
+		result = &mut sync => (result, false),
+		() = shutdown => {
+			tracing::info!("Finishing the running sync before shutting down");
+			(sync.await, true)
+		}
+	};
+
+	if let Err(error) = &result {
+		tracing::error!("Sync failed: {:?}", error);
+	}
+
+	(!shutting_down).then_some(result)
+}
+
+/// Listen for SIGTERM and SIGINT, returning a future which completes
+/// once either is received
+pub(crate) fn shutdown_signal() -> Result<impl Future<Output = ()>> {
+	let mut terminate = signal(SignalKind::terminate()).context("Failed to listen for SIGTERM")?;
+
+	Ok(async move {
+		tokio::select! {
+			_ = terminate.recv() => {}
+			_ = tokio::signal::ctrl_c() => {}
+		}
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Parse a UTC timestamp
+	fn time(time: &str) -> DateTime<Utc> {
+		DateTime::parse_from_rfc3339(time).expect("invalid timestamp").with_timezone(&Utc)
+	}
+
+	#[test]
+	fn test_parse_schedule() {
+		let schedule: Schedule = "0 */2 * * *".parse().expect("failed to parse schedule");
+		assert_eq!(schedule.minutes, 1);
+		assert_eq!(schedule.hours, 0b0101_0101_0101_0101_0101_0101);
+		assert!(!schedule.either_day);
+
+		let schedule: Schedule = "5,10-12 3 1 1-6/2 7".parse().expect("failed to parse schedule");
+		assert_eq!(schedule.minutes, 1 << 5 | 1 << 10 | 1 << 11 | 1 << 12);
+		assert_eq!(schedule.months, 1 << 1 | 1 << 3 | 1 << 5);
+		assert_eq!(schedule.days_of_week, 1);
+		assert!(schedule.either_day);
+
+		for invalid in ["0 */2 * *", "60 * * * *", "* * 0 * *", "5-1 * * * *", "*/0 * * * *"] {
+			assert!(invalid.parse::<Schedule>().is_err(), "`{invalid}` was accepted");
+		}
+		assert!("0 0 30 2 *".parse::<Schedule>().is_err());
+	}
+
+	#[test]
+	fn test_next_after() {
+		let schedule: Schedule = "0 */2 * * *".parse().expect("failed to parse schedule");
+		assert_eq!(
+			schedule.next_after(time("2024-03-01T13:59:30Z")),
+			Some(time("2024-03-01T14:00:00Z"))
+		);
+		assert_eq!(
+			schedule.next_after(time("2024-03-01T14:00:00Z")),
+			Some(time("2024-03-01T16:00:00Z"))
+		);
+		assert_eq!(
+			schedule.next_after(time("2024-12-31T23:00:00Z")),
+			Some(time("2025-01-01T00:00:00Z"))
+		);
+
+		// Leap days
+		let schedule: Schedule = "30 4 29 2 *".parse().expect("failed to parse schedule");
+		assert_eq!(
+			schedule.next_after(time("2024-03-01T00:00:00Z")),
+			Some(time("2028-02-29T04:30:00Z"))
+		);
+
+		// With both day fields restricted, either matches: the 13th or
+		// any Friday
+		let schedule: Schedule = "0 0 13 * 5".parse().expect("failed to parse schedule");
+		assert_eq!(
+			schedule.next_after(time("2024-03-01T00:00:00Z")),
+			Some(time("2024-03-08T00:00:00Z"))
+		);
+		assert_eq!(
+			schedule.next_after(time("2024-03-12T00:00:00Z")),
+			Some(time("2024-03-13T00:00:00Z"))
+		);
+	}
+}
@@ -0,0 +1,214 @@
+//! Declarative desired state of the Zitadel users
+//!
+//! Instead of syncing, `--render-state` writes the users the sync
+//! would maintain in Zitadel, along with their metadata and roles, to
+//! a state file, e.g. to review changes in git before they are
+//! applied. `--apply-state` reconciles Zitadel to a state file, which
+//! may also be produced by other tools.
+use std::{
+	collections::{BTreeMap, VecDeque},
+	path::Path,
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tracing::Instrument;
+
+use crate::{
+	get_source, import_throttle::ImportThrottle, prepare_source_users, report::Reporter, resources,
+	spans, sync_users, user::User, user_cache, watchdog, zitadel::Zitadel, Config, FeatureFlag,
+};
+
+/// The desired state of the Zitadel users of an organization
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct DesiredState {
+	/// The Zitadel organization the state is meant for
+	pub organization_id: String,
+	/// The users which should exist in Zitadel. Users within the user
+	/// scope which aren't listed are deleted.
+	pub users: Vec<User>,
+}
+
+impl DesiredState {
+	/// Load a state file
+	fn load(path: &Path) -> Result<Self> {
+		let state =
+			std::fs::read(path).context(format!("Failed to read state file {}", path.display()))?;
+		serde_json::from_slice(&state).context(format!("Invalid state file {}", path.display()))
+	}
+
+	/// Write the state to a file
+	pub(crate) fn save(&self, path: &Path) -> Result<()> {
+		// Write to a temporary file first, so that a crash doesn't
+		// leave a partial state behind, which would delete users when
+		// applied
+		let temporary_path = path.with_extension("tmp");
+		std::fs::write(&temporary_path, serde_json::to_vec_pretty(self)?)
+			.context(format!("Failed to write state file {}", temporary_path.display()))?;
+		std::fs::rename(&temporary_path, path)
+			.context(format!("Failed to write state file {}", path.display()))
+	}
+
+	/// The users of the state, sorted by external user ID as the sync
+	/// expects them
+	fn into_sorted_users(self) -> Result<VecDeque<User>> {
+		let mut users = self.users;
+		users.sort_by(|a, b| a.external_user_id.cmp(&b.external_user_id));
+
+		if let Some(duplicate) =
+			users.windows(2).find(|pair| pair[0].external_user_id == pair[1].external_user_id)
+		{
+			anyhow::bail!(
+				"The external user ID `{}` is listed more than once",
+				duplicate[0].external_user_id
+			);
+		}
+
+		Ok(users.into())
+	}
+}
+
+/// Render the desired state of the Zitadel users, as derived from the
+/// source, to a state file
+pub async fn render_state(config: &Config, path: &Path) -> Result<()> {
+	let source = get_source(config)?;
+	let mut users: VecDeque<User> = source
+		.get_sorted_users()
+		.await
+		.context(format!("Failed to query users from {}", source.get_name()))?
+		.into();
+
+	let (_, persona_conflicts) = prepare_source_users(config, &mut users)?;
+	for conflict in &persona_conflicts {
+		tracing::warn!("{}", conflict.message(config.language));
+	}
+	// Disabled users are treated as deleted
+	users.retain(|user| user.enabled);
+
+	// Applying the state would delete users missing from it, so users
+	// which couldn't be read in full can't be left out
+	if let Some(user) = users.iter().find(|user| user.unreadable.is_some()) {
+		anyhow::bail!(
+			"Can't render the desired state, since user `{}` {}",
+			user.external_user_id,
+			user.unreadable.as_ref().map(ToString::to_string).unwrap_or_default()
+		);
+	}
+
+	let state = DesiredState {
+		organization_id: config.zitadel.organization_id.clone(),
+		users: users.into(),
+	};
+	state.save(path)?;
+
+	tracing::info!(
+		"Rendered the desired state of {} users to {}",
+		state.users.len(),
+		path.display()
+	);
+
+	Ok(())
+}
+
+/// Reconcile the Zitadel users to a state file
+pub async fn apply_state(config: &Config, path: &Path) -> Result<()> {
+	let state = DesiredState::load(path)?;
+	if state.organization_id != config.zitadel.organization_id {
+		anyhow::bail!(
+			"The state file {} is meant for organization `{}`, not `{}`",
+			path.display(),
+			state.organization_id,
+			config.zitadel.organization_id
+		);
+	}
+	let mut users = state.into_sorted_users()?;
+
+	let dry_run = config.feature_flags.is_enabled(FeatureFlag::DryRun);
+	if !dry_run {
+		user_cache::invalidate(config)?;
+	}
+
+	let mut reporter = Reporter::new(&config.reporting, dry_run)
+		.with_deletions_dry_run(config.feature_flags.is_enabled(FeatureFlag::DryRunDeletions))
+		.with_language(config.language)
+		.with_config_fingerprint(&config.fingerprint);
+
+	let result = resources::run_with_monitoring(
+		config.resource_monitoring.as_ref(),
+		watchdog::run_with_watchdog(
+			config.watchdog.as_ref(),
+			apply_users(config, &mut reporter, &mut users).instrument(spans::run_span("state")),
+		),
+	)
+	.await;
+
+	// Always finish the report, so that aborted runs are documented as
+	// well
+	if let Err(error) = reporter.finish() {
+		if result.is_ok() {
+			return Err(error);
+		}
+		tracing::error!("Failed to write sync report: {:?}", error);
+	}
+
+	result
+}
+
+/// Sync the users of a state file to Zitadel
+async fn apply_users(
+	config: &Config,
+	reporter: &mut Reporter,
+	users: &mut VecDeque<User>,
+) -> Result<()> {
+	watchdog::set_phase("checking Zitadel configuration");
+	Zitadel::new(config).await?.preflight().await?;
+
+	watchdog::set_phase("syncing users");
+	sync_users(config, users, BTreeMap::new(), None, reporter, &mut ImportThrottle::default()).await
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn user(external_user_id: &str) -> User {
+		User::new(
+			"John".to_owned(),
+			"Doe".to_owned(),
+			format!("{external_user_id}@example.com"),
+			None,
+			true,
+			None,
+			external_user_id.to_owned(),
+			None,
+		)
+	}
+
+	#[test]
+	fn test_state_round_trip() {
+		let dir = tempfile::TempDir::new().expect("failed to create tempdir");
+		let path = dir.path().join("state.json");
+
+		let state =
+			DesiredState { organization_id: "1".to_owned(), users: vec![user("b"), user("a")] };
+		state.save(&path).expect("failed to save state");
+
+		let users = DesiredState::load(&path)
+			.expect("failed to load state")
+			.into_sorted_users()
+			.expect("invalid state");
+		let ids: Vec<_> = users.iter().map(|user| user.external_user_id.as_str()).collect();
+		assert_eq!(ids, vec!["a", "b"]);
+	}
+
+	#[test]
+	fn test_duplicate_users() {
+		let state = DesiredState {
+			organization_id: "1".to_owned(),
+			users: vec![user("a"), user("b"), user("a")],
+		};
+
+		let error = state.into_sorted_users().expect_err("duplicate users were accepted");
+		assert!(error.to_string().contains("`a`"));
+	}
+}
@@ -0,0 +1,286 @@
+//! Classification of differences between source and Zitadel users
+//!
+//! Without further information, every difference between a source
+//! user and its Zitadel user looks like an update of the source, so
+//! that manual fixes in Zitadel are reverted by the next sync. With
+//! drift classification, the values of each user are remembered in
+//! the state file after every sync. A difference is then classified as
+//! a change of the source if the source value changed since, and as a
+//! change in Zitadel otherwise, and each class is treated according to
+//! its own policy. Values are only stored as truncated hashes, so that
+//! the state file doesn't accumulate personal data.
+use std::collections::{BTreeMap, BTreeSet};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{report::Reporter, state::SyncState, user::User, Config, FeatureFlag};
+
+/// Fields which are always taken from the source, since they identify
+/// the user
+const SOURCE_ONLY_FIELDS: &[&str] = &["external_user_id", "enabled", "localpart"];
+
+/// The number of hex digits of the stored value hashes
+const HASH_LENGTH: usize = 16;
+
+/// Configuration of the drift classification
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+pub struct DriftConfig {
+	/// How to treat values changed in the source since the last sync
+	#[serde(default)]
+	pub source_changes: DriftPolicy,
+	/// How to treat values changed in Zitadel since the last sync,
+	/// e.g. manual fixes
+	#[serde(default)]
+	pub zitadel_changes: DriftPolicy,
+}
+
+/// How to treat a class of differences
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DriftPolicy {
+	/// Write the source value to Zitadel
+	#[default]
+	Overwrite,
+	/// Keep the Zitadel value
+	Keep,
+	/// Keep the Zitadel value and list the difference in the sync
+	/// report
+	Report,
+}
+
+/// Where a difference between a source and a Zitadel user originates
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DriftClass {
+	/// The value changed in the source since the last sync, or the
+	/// user wasn't synced before
+	Source,
+	/// The value changed in Zitadel since the last sync
+	Zitadel,
+}
+
+impl DriftConfig {
+	/// The policy for the given class of differences
+	fn policy(&self, class: DriftClass) -> DriftPolicy {
+		match class {
+			DriftClass::Source => self.source_changes,
+			DriftClass::Zitadel => self.zitadel_changes,
+		}
+	}
+}
+
+/// Tracks the values of the users during a sync, classifying the
+/// differences against the values of the last sync
+#[derive(Debug)]
+pub(crate) struct DriftTracker {
+	/// The drift configuration
+	config: DriftConfig,
+	/// The hashed source values of the last sync, by external user ID
+	/// and field
+	last_sync: BTreeMap<String, BTreeMap<String, String>>,
+	/// The hashed source values of this sync, by external user ID and
+	/// field
+	this_sync: BTreeMap<String, BTreeMap<String, String>>,
+	/// The external IDs of the users compared during this sync
+	compared: BTreeSet<String>,
+}
+
+impl DriftTracker {
+	/// Load the values of the last sync, if drift classification is
+	/// configured
+	pub(crate) fn load(config: &Config) -> Result<Option<Self>> {
+		let (Some(drift), Some(state_path)) = (&config.drift, &config.state_path) else {
+			return Ok(None);
+		};
+
+		Ok(Some(Self {
+			config: drift.clone(),
+			last_sync: SyncState::load_synced_values(state_path, &config.zitadel.organization_id)?,
+			this_sync: BTreeMap::new(),
+			compared: BTreeSet::new(),
+		}))
+	}
+
+	/// Apply the configured policies to the differences between a
+	/// source user and its Zitadel user, taking the Zitadel values of
+	/// differences which shouldn't be overwritten over into the source
+	/// user
+	///
+	/// Returns the source values to remember for the user, once it was
+	/// synced.
+	pub(crate) fn apply(
+		&mut self,
+		source_user: &mut User,
+		zitadel_user: &User,
+		zitadel_id: &str,
+		reporter: &mut Reporter,
+	) -> BTreeMap<String, String> {
+		let source_values = hashed_values(source_user);
+		self.compared.insert(source_user.external_user_id.clone());
+		let last_sync = self.last_sync.get(&source_user.external_user_id);
+
+		for (field, _, source_value) in zitadel_user.diff(source_user) {
+			if SOURCE_ONLY_FIELDS.contains(&field.as_str()) {
+				continue;
+			}
+
+			let class = classify(last_sync, &field, &hash(&source_value));
+			match self.config.policy(class) {
+				DriftPolicy::Overwrite => {}
+				DriftPolicy::Keep => {
+					tracing::debug!("Keeping the {} of `{}` in Zitadel", field, zitadel_id);
+					take_value(source_user, zitadel_user, &field);
+				}
+				DriftPolicy::Report => {
+					take_value(source_user, zitadel_user, &field);
+					reporter.record_drift(&source_user.external_user_id, zitadel_id, &field, class);
+				}
+			}
+		}
+
+		source_values
+	}
+
+	/// Keep the values of the last sync for a user skipped since it
+	/// didn't change in the source
+	pub(crate) fn keep(&mut self, external_user_id: &str) {
+		self.compared.insert(external_user_id.to_owned());
+	}
+
+	/// Remember the source values returned by [`Self::apply`] for a
+	/// successfully synced user
+	pub(crate) fn record(
+		&mut self,
+		external_user_id: &str,
+		source_values: BTreeMap<String, String>,
+	) {
+		self.this_sync.insert(external_user_id.to_owned(), source_values);
+	}
+
+	/// Store the values of this sync in the state file. Users which
+	/// failed to sync keep the values of the last sync, and users which
+	/// no longer exist are forgotten.
+	pub(crate) fn save(mut self, config: &Config) -> Result<()> {
+		let Some(state_path) = &config.state_path else {
+			return Ok(());
+		};
+		if config.feature_flags.is_enabled(FeatureFlag::DryRun) {
+			tracing::info!("Not storing the synced values due to dry run");
+			return Ok(());
+		}
+
+		for external_user_id in self.compared {
+			if self.this_sync.contains_key(&external_user_id) {
+				continue;
+			}
+			if let Some(values) = self.last_sync.remove(&external_user_id) {
+				self.this_sync.insert(external_user_id, values);
+			}
+		}
+
+		SyncState::record_synced_values(state_path, &config.zitadel.organization_id, self.this_sync)
+	}
+}
+
+/// Classify a difference of a field, given the hashed source values
+/// of the user at the last sync and the current hashed source value
+///
+/// If the source value is unchanged, the difference can only come from
+/// Zitadel, either since the last sync or kept from before.
+fn classify(last_sync: Option<&BTreeMap<String, String>>, field: &str, source: &str) -> DriftClass {
+	let Some(last_sync) = last_sync else {
+		return DriftClass::Source;
+	};
+
+	// Empty values aren't stored
+	let unchanged = match last_sync.get(field) {
+		Some(last_source) => last_source == source,
+		None => source == hash(""),
+	};
+	if unchanged {
+		DriftClass::Zitadel
+	} else {
+		DriftClass::Source
+	}
+}
+
+/// Take the Zitadel value of a field over into the source user
+pub(crate) fn take_value(source_user: &mut User, zitadel_user: &User, field: &str) {
+	match field {
+		"email" => source_user.email = zitadel_user.email.clone(),
+		"roles" => source_user.roles = zitadel_user.roles.clone(),
+		_ => {
+			source_user.set_attribute(field, zitadel_user.get_attribute(field).unwrap_or_default())
+		}
+	}
+}
+
+/// The hashed values of the classified fields of a user
+fn hashed_values(user: &User) -> BTreeMap<String, String> {
+	user.diff(&empty_user())
+		.into_iter()
+		.filter(|(field, _, _)| !SOURCE_ONLY_FIELDS.contains(&field.as_str()))
+		.map(|(field, value, _)| (field, hash(&value)))
+		.collect()
+}
+
+/// A user without any values, to list the values of other users
+fn empty_user() -> User {
+	User::new(String::new(), String::new(), String::new(), None, false, None, String::new(), None)
+}
+
+/// A truncated hash of a value
+fn hash(value: &str) -> String {
+	let mut hash = hex::encode(Sha256::digest(value.as_bytes()));
+	hash.truncate(HASH_LENGTH);
+	hash
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_classify() {
+		// Users synced for the first time
+		assert_eq!(classify(None, "phone", &hash("new")), DriftClass::Source);
+
+		let last_sync = BTreeMap::from([("phone".to_owned(), hash("old"))]);
+
+		// Changed in the source, possibly in Zitadel as well, in which
+		// case the source wins
+		assert_eq!(classify(Some(&last_sync), "phone", &hash("new")), DriftClass::Source);
+
+		// Changed in Zitadel, since the last sync or before
+		assert_eq!(classify(Some(&last_sync), "phone", &hash("old")), DriftClass::Zitadel);
+
+		// Values which were empty at the last sync
+		assert_eq!(classify(Some(&last_sync), "department", &hash("")), DriftClass::Zitadel);
+		assert_eq!(classify(Some(&last_sync), "department", &hash("IT")), DriftClass::Source);
+	}
+
+	#[test]
+	fn test_hashed_values() {
+		let mut user = User::new(
+			"John".to_owned(),
+			"Doe".to_owned(),
+			"john.doe@example.com".to_owned(),
+			None,
+			true,
+			Some("jdoe".to_owned()),
+			"john.doe".to_owned(),
+			None,
+		);
+		user.metadata.insert("department".to_owned(), "IT".to_owned());
+
+		let values = hashed_values(&user);
+		let fields: Vec<_> = values.keys().map(String::as_str).collect();
+		assert_eq!(
+			fields,
+			vec!["department", "email", "first_name", "last_name", "preferred_username"]
+		);
+		assert_eq!(values.get("first_name"), Some(&hash("John")));
+	}
+}
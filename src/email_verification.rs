@@ -0,0 +1,125 @@
+//! Re-triggering email verification for synced users
+//!
+//! If users were imported with the `verify_email` feature flag
+//! disabled by mistake, their email addresses are marked as verified
+//! in Zitadel without the users ever having confirmed them. This marks
+//! the email addresses of selected users as unverified again, which
+//! makes Zitadel send them verification emails.
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use tracing::Instrument;
+
+use crate::{
+	explain::matches_identifier,
+	get_next_zitadel_user,
+	report::{Operation, Reporter},
+	spans, user_cache,
+	zitadel::Zitadel,
+	Config, FeatureFlag,
+};
+
+/// Re-send verification emails to the users listed in the given file,
+/// one identifier per line
+pub async fn reverify_emails(config: &Config, path: &Path) -> Result<()> {
+	let identifiers = parse_identifiers(
+		&std::fs::read_to_string(path)
+			.context(format!("Failed to read user identifiers from {}", path.display()))?,
+	);
+	if identifiers.is_empty() {
+		anyhow::bail!("No user identifiers given in {}", path.display());
+	}
+
+	request_verification(config, &identifiers)
+		.instrument(spans::run_span(spans::source_name(config)))
+		.await
+}
+
+/// Mark the email addresses of the users with the given identifiers
+/// as unverified
+async fn request_verification(config: &Config, identifiers: &[String]) -> Result<()> {
+	let dry_run = config.feature_flags.is_enabled(FeatureFlag::DryRun);
+	if !dry_run {
+		user_cache::invalidate(config)?;
+	}
+
+	let mut reporter = Reporter::new(&config.reporting, dry_run)
+		.with_language(config.language)
+		.with_config_fingerprint(&config.fingerprint);
+	let mut unmatched: Vec<&String> = identifiers.iter().collect();
+
+	let mut zitadel = Zitadel::new(config).await?;
+	zitadel.preflight().await?;
+	let mut stream = zitadel.list_users()?;
+
+	while let Some((user, zitadel_id)) = get_next_zitadel_user(&mut stream, &mut zitadel).await? {
+		let matched = identifiers.iter().any(|identifier| matches_identifier(&user, identifier));
+		if !matched {
+			continue;
+		}
+		unmatched.retain(|identifier| !matches_identifier(&user, identifier));
+
+		let span =
+			spans::user_span(Operation::Update, Some(&user.external_user_id), Some(&zitadel_id));
+		let res = zitadel.reverify_email(&zitadel_id, &user.email).instrument(span.clone()).await;
+		if let Err(error) = &res {
+			span.in_scope(|| tracing::error!("Failed to request email verification: {:?}", error));
+		}
+
+		reporter.record_update(
+			&user.external_user_id,
+			&zitadel_id,
+			vec!["email_verified".to_owned()],
+			&res,
+		);
+	}
+
+	let report = reporter.finish()?;
+	if !report.failures.is_empty() {
+		anyhow::bail!("Failed to request email verification for {} users", report.failures.len());
+	}
+
+	if !unmatched.is_empty() {
+		anyhow::bail!(
+			"No synced users found for {} identifiers: {}",
+			unmatched.len(),
+			unmatched
+				.iter()
+				.map(|identifier| format!("`{identifier}`"))
+				.collect::<Vec<_>>()
+				.join(", ")
+		);
+	}
+
+	Ok(())
+}
+
+/// Parse a list of user identifiers, one per line. Blank lines and
+/// lines starting with `#` are ignored.
+fn parse_identifiers(list: &str) -> Vec<String> {
+	list.lines()
+		.map(str::trim)
+		.filter(|line| !line.is_empty() && !line.starts_with('#'))
+		.map(ToOwned::to_owned)
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use indoc::indoc;
+
+	use super::*;
+
+	#[test]
+	fn test_parse_identifiers() {
+		let list = indoc! {"
+			# Imported on 2024-05-02 without verification
+			john.doe@example.com
+
+			  jane.doe
+			Y2ZlNjA=
+		"};
+
+		assert_eq!(parse_identifiers(list), vec!["john.doe@example.com", "jane.doe", "Y2ZlNjA="]);
+	}
+}
@@ -0,0 +1,343 @@
+//! Explanations of how the sync treats individual users
+//!
+//! This reconstructs the decisions the sync would make for a single
+//! user, without writing anything, so that support engineers don't
+//! have to reconstruct them from logs and code. Note that the
+//! explanation contains the user's personal data.
+use std::fmt::Write;
+
+use anyhow::Result;
+use chrono::Utc;
+
+use crate::{
+	get_next_zitadel_user, get_source,
+	guests::GUEST_EXPIRES_AT_KEY,
+	hooks, pilot, rules,
+	sources::Source,
+	user::{User, USER_FIELDS},
+	user_cache,
+	zitadel::Zitadel,
+	Config, FeatureFlag,
+};
+
+/// Whether a user matches an identifier given by a support engineer
+///
+/// Users can be identified by their external user ID, either encoded
+/// or as it appears in the source, their email address, preferred
+/// username or localpart.
+pub(crate) fn matches_identifier(user: &User, identifier: &str) -> bool {
+	user.external_user_id == identifier
+		|| user.external_user_id == hex::encode(identifier)
+		|| user.email == identifier
+		|| user.preferred_username.as_deref() == Some(identifier)
+		|| user.localpart.as_deref() == Some(identifier)
+}
+
+/// Write all attributes of a user
+fn write_user(out: &mut String, config: &Config, user: &User) -> Result<()> {
+	for field in USER_FIELDS {
+		writeln!(out, "  {}: {}", field, user.get_attribute(field).unwrap_or_default())?;
+	}
+	for (key, value) in &user.metadata {
+		writeln!(out, "  metadata.{key}: {value}")?;
+	}
+	writeln!(out, "  roles: {}", format_roles(config, user))?;
+
+	Ok(())
+}
+
+/// Format the project roles of a user, including the default role or
+/// the guest role
+fn format_roles(config: &Config, user: &User) -> String {
+	let user_role = config
+		.guests
+		.as_ref()
+		.and_then(|guests| guests.role_of(user))
+		.unwrap_or(&config.zitadel.user_role);
+	crate::zitadel::get_role_keys(user_role, &user.roles).join(", ")
+}
+
+/// Explain how the sync treats the user with the given identifier
+pub async fn explain_user(config: &Config, identifier: &str) -> Result<String> {
+	let source = get_source(config)?;
+
+	let mut out = String::new();
+	writeln!(out, "Explanation for `{identifier}`")?;
+	writeln!(out)?;
+
+	let source_user = explain_source(&mut out, config, source.as_ref(), identifier).await?;
+	explain_validation(&mut out, source_user.as_ref())?;
+	let source_user = explain_rules(&mut out, config, source_user)?;
+	explain_feature_flags(&mut out, config)?;
+	let zitadel_user = explain_zitadel(&mut out, config, source_user.as_ref(), identifier).await?;
+	explain_outcome(&mut out, config, source_user.as_ref(), zitadel_user.as_ref())?;
+
+	Ok(out)
+}
+
+/// Find the user in the source and explain how it was parsed
+async fn explain_source(
+	out: &mut String,
+	config: &Config,
+	source: &(dyn Source + Send + Sync),
+	identifier: &str,
+) -> Result<Option<User>> {
+	writeln!(out, "Source ({}):", source.get_name())?;
+
+	let source_user = source
+		.get_sorted_users()
+		.await?
+		.into_iter()
+		.find(|user| matches_identifier(user, identifier));
+
+	match &source_user {
+		Some(user) => {
+			match source.get_raw_attributes(&user.external_user_id).await? {
+				Some(attributes) => {
+					writeln!(out, "  Raw attributes:")?;
+					for (name, values) in attributes {
+						writeln!(out, "    {}: {}", name, values.join(" | "))?;
+					}
+				}
+				None => writeln!(out, "  Raw attributes: unavailable")?,
+			}
+			writeln!(out, "  Parsed user:")?;
+			write_user(out, config, user)?;
+		}
+		None => writeln!(out, "  No matching user found")?,
+	}
+	writeln!(out)?;
+
+	Ok(source_user)
+}
+
+/// Explain problems with the source data of a user
+fn explain_validation(out: &mut String, source_user: Option<&User>) -> Result<()> {
+	writeln!(out, "Validation:")?;
+
+	match source_user {
+		Some(user) => {
+			let mut problems = Vec::new();
+			if user.external_user_id.is_empty() {
+				problems.push("The external user ID is empty");
+			}
+			if user.email.is_empty() {
+				problems.push("The email address is empty");
+			}
+			if user.first_name.is_empty() || user.last_name.is_empty() {
+				problems.push(
+					"The first or last name is empty, which Zitadel requires unless filled in by \
+					 `name_fallback`",
+				);
+			}
+			if !user.enabled {
+				problems.push("The user is disabled, and therefore treated as deleted");
+			}
+
+			if problems.is_empty() {
+				writeln!(out, "  No problems found")?;
+			}
+			for problem in problems {
+				writeln!(out, "  - {problem}")?;
+			}
+		}
+		None => writeln!(out, "  Skipped, since the user is not in the source")?,
+	}
+	writeln!(out)?;
+
+	Ok(())
+}
+
+/// Run the user hooks and apply the configured rules to a user,
+/// explain which rules applied and return the resulting user unless it
+/// was excluded
+fn explain_rules(
+	out: &mut String,
+	config: &Config,
+	source_user: Option<User>,
+) -> Result<Option<User>> {
+	writeln!(out, "Rules:")?;
+
+	let source_user = match source_user {
+		Some(mut user) => {
+			let hooked = hooks::has_user_hooks();
+			if hooked {
+				hooks::run_user_hooks(&mut user)?;
+				writeln!(out, "  User hooks: applied")?;
+			}
+			user.fill_missing_names(config.name_fallback.as_ref());
+			let trace = rules::apply_rules(&config.rules, &mut user);
+
+			if config.rules.is_empty() {
+				writeln!(out, "  No rules configured")?;
+			}
+			for rule in &config.rules {
+				let fired = if trace.fired.contains(&rule.name) { "applied" } else { "-" };
+				writeln!(out, "  {}: {}", rule.name, fired)?;
+			}
+
+			if trace.excluded {
+				writeln!(out, "  The user is excluded from the sync")?;
+				None
+			} else {
+				let mut guest = false;
+				if let Some(guests) = &config.guests {
+					let enabled = user.enabled;
+					guests.apply(&mut user, Utc::now());
+					if let Some(expires_at) = user.metadata.get(GUEST_EXPIRES_AT_KEY) {
+						writeln!(out, "  The user is a guest until {expires_at}")?;
+						guest = true;
+					}
+					if enabled && !user.enabled {
+						writeln!(
+							out,
+							"  The guest's access expired, so the user is treated as deleted"
+						)?;
+						guest = true;
+					}
+					if user.is_unparsable() {
+						writeln!(out, "  The guest has no valid expiry date")?;
+						guest = true;
+					}
+				}
+				if hooked || guest || !trace.fired.is_empty() {
+					writeln!(out, "  Resulting user:")?;
+					write_user(out, config, &user)?;
+				}
+				Some(user)
+			}
+		}
+		None => {
+			writeln!(out, "  Skipped, since the user is not in the source")?;
+			None
+		}
+	};
+	writeln!(out)?;
+
+	Ok(source_user)
+}
+
+/// List the enabled feature flags
+fn explain_feature_flags(out: &mut String, config: &Config) -> Result<()> {
+	writeln!(out, "Feature flags:")?;
+
+	if config.feature_flags.is_empty() {
+		writeln!(out, "  None enabled")?;
+	}
+	for flag in config.feature_flags.iter() {
+		writeln!(out, "  {flag:?}")?;
+	}
+	writeln!(out)?;
+
+	Ok(())
+}
+
+/// Find the Zitadel account matching the source user, or the
+/// identifier if the user is not synced
+async fn explain_zitadel(
+	out: &mut String,
+	config: &Config,
+	source_user: Option<&User>,
+	identifier: &str,
+) -> Result<Option<(User, String)>> {
+	writeln!(out, "Zitadel:")?;
+
+	let matches = |user: &User| match source_user {
+		Some(source_user) => user.external_user_id == source_user.external_user_id,
+		None => matches_identifier(user, identifier),
+	};
+
+	let zitadel_user = match &config.zitadel_cache {
+		Some(cache_config) => user_cache::list_users(config, cache_config)
+			.await?
+			.into_iter()
+			.find(|(user, _)| matches(user)),
+		None => {
+			let mut zitadel = Zitadel::new(config).await?;
+			let mut stream = zitadel.list_users()?;
+			let mut zitadel_user = None;
+
+			while let Some((user, zitadel_id)) =
+				get_next_zitadel_user(&mut stream, &mut zitadel).await?
+			{
+				if matches(&user) {
+					zitadel_user = Some((user, zitadel_id));
+					break;
+				}
+			}
+
+			zitadel_user
+		}
+	};
+
+	match &zitadel_user {
+		Some((user, zitadel_id)) => {
+			writeln!(out, "  Matched account `{zitadel_id}`:")?;
+			write_user(out, config, user)?;
+		}
+		None => writeln!(out, "  No matching account found within the user scope")?,
+	}
+	writeln!(out)?;
+
+	Ok(zitadel_user)
+}
+
+/// Explain what the sync does with the user
+fn explain_outcome(
+	out: &mut String,
+	config: &Config,
+	source_user: Option<&User>,
+	zitadel_user: Option<&(User, String)>,
+) -> Result<()> {
+	writeln!(out, "Outcome:")?;
+
+	let deactivate_only = config.feature_flags.is_enabled(FeatureFlag::DeactivateOnly);
+
+	match (source_user, zitadel_user) {
+		(Some(User { unreadable: Some(unreadable), .. }), _) => {
+			writeln!(out, "  Nothing happens, since the user {unreadable}")?;
+		}
+		(Some(new_user), Some(_)) if deactivate_only && !new_user.enabled => {
+			writeln!(out, "  The account is deleted, since the user is disabled")?;
+		}
+		_ if deactivate_only => {
+			writeln!(out, "  Nothing happens, since only disabled users are synced")?;
+		}
+		(Some(new_user), Some(_)) if !new_user.enabled => {
+			writeln!(out, "  The account is deleted, since the user is disabled")?;
+		}
+		(None, Some(_)) => {
+			writeln!(out, "  The account is deleted, since the user is not synced")?;
+		}
+		(Some(new_user), None) if new_user.enabled => writeln!(out, "  The user is imported")?,
+		(_, None) => writeln!(out, "  Nothing happens")?,
+		(Some(new_user), Some((old_user, _))) if new_user == old_user => {
+			writeln!(out, "  Nothing happens, since the account is up to date")?;
+		}
+		(Some(new_user), Some((old_user, _))) => {
+			writeln!(out, "  The account is updated:")?;
+			for (attribute, old_value, new_value) in old_user.diff(new_user) {
+				writeln!(out, "    {attribute}: {old_value:?} -> {new_value:?}")?;
+			}
+			if old_user.localpart != new_user.localpart {
+				writeln!(out, "  Note: The localpart of existing accounts cannot be changed")?;
+			}
+		}
+	}
+
+	if config.feature_flags.is_enabled(FeatureFlag::DryRun) {
+		writeln!(out, "  No changes are written, since dry run is enabled")?;
+	}
+
+	// Deletions are checked against the Zitadel user, all other
+	// changes against the source user
+	let pilot_user = match source_user.filter(|user| user.enabled) {
+		Some(user) => Some(user),
+		None => zitadel_user.map(|(user, _)| user),
+	};
+	if pilot_user.is_some_and(|user| !pilot::includes(config.pilot.as_ref(), user)) {
+		writeln!(out, "  No changes are written, since the user is outside the pilot group")?;
+	}
+
+	Ok(())
+}
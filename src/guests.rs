@@ -0,0 +1,211 @@
+//! Lifecycle of guest users
+//!
+//! External staff, e.g. visiting clinicians, are only granted access
+//! for a limited time. Source users matching the configured condition
+//! are synced as guests: their expiry date is written to the
+//! [`GUEST_EXPIRES_AT_KEY`] metadata entry, they may be granted another
+//! project role than other users, and may be deleted sooner after their
+//! deactivation. Expired guests are treated as disabled in the source.
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::Deserialize;
+
+use crate::{
+	rules::Expression,
+	user::{Unreadable, User},
+	zitadel::grace_period_over,
+	Config,
+};
+
+/// The metadata key recording until when a guest may access the
+/// messenger, in RFC 3339 format
+pub const GUEST_EXPIRES_AT_KEY: &str = "guest_expires_at";
+
+/// Configuration of guest users
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct GuestConfig {
+	/// The condition guests match, e.g. `employee_type == "external"`
+	pub when: Expression,
+	/// The attribute holding the expiry date of a guest, either an RFC
+	/// 3339 time or a date, which is the guest's last day of access
+	pub expires_attribute: String,
+	/// The project role granted to guests instead of
+	/// `zitadel.user_role`
+	pub role: Option<String>,
+	/// The number of days deactivated guests are kept before they are
+	/// deleted, instead of `zitadel.deactivation_grace_days`
+	pub deactivation_grace_days: Option<u32>,
+}
+
+impl GuestConfig {
+	/// Mark a source user as guest if it matches the condition,
+	/// disabling it if it expired
+	///
+	/// Guests without a valid expiry date are marked as unparsable, so
+	/// that their Zitadel users are left untouched and reported rather
+	/// than deleted over a typo in the source.
+	pub(crate) fn apply(&self, user: &mut User, now: DateTime<Utc>) {
+		if !self.when.evaluate(|name| user.get_attribute(name)) {
+			return;
+		}
+
+		let value = user.get_attribute(&self.expires_attribute).unwrap_or_default();
+		let Some(expires_at) = parse_expiry(&value) else {
+			tracing::warn!(
+				"Guest `{}` has no valid expiry date in `{}`, leaving it untouched",
+				user.external_user_id,
+				self.expires_attribute
+			);
+			user.unreadable = Some(Unreadable::ParseFailure(format!(
+				"invalid guest expiry date `{}` in `{}`",
+				value, self.expires_attribute
+			)));
+			return;
+		};
+
+		user.metadata.insert(GUEST_EXPIRES_AT_KEY.to_owned(), expires_at.to_rfc3339());
+		if expires_at <= now {
+			tracing::debug!("Guest `{}` expired at {}", user.external_user_id, expires_at);
+			user.enabled = false;
+		}
+	}
+
+	/// The project role granted to the given user instead of the
+	/// default role, if it is a guest
+	pub(crate) fn role_of(&self, user: &User) -> Option<&str> {
+		self.role.as_deref().filter(|_| is_guest(user))
+	}
+}
+
+/// Whether a user was synced as guest
+pub(crate) fn is_guest(user: &User) -> bool {
+	user.metadata.contains_key(GUEST_EXPIRES_AT_KEY)
+}
+
+/// Whether a user is a guest whose access expired
+pub(crate) fn is_expired(user: &User, now: DateTime<Utc>) -> bool {
+	user.metadata
+		.get(GUEST_EXPIRES_AT_KEY)
+		.and_then(|expires_at| parse_expiry(expires_at))
+		.is_some_and(|expires_at| expires_at <= now)
+}
+
+/// Whether the grace period of a user deactivated at the given time is
+/// over, taking the grace period of guests into account
+pub(crate) fn deactivation_expired(
+	config: &Config,
+	user: &User,
+	deactivated_at: &str,
+	now: DateTime<Utc>,
+) -> bool {
+	match config
+		.guests
+		.as_ref()
+		.and_then(|guests| guests.deactivation_grace_days)
+		.filter(|_| is_guest(user))
+	{
+		Some(grace_days) => grace_period_over(deactivated_at, grace_days, now),
+		None => config.zitadel.deactivation_expired(deactivated_at, now),
+	}
+}
+
+/// Parse an expiry date, where a date without time expires at the end
+/// of that day in UTC
+fn parse_expiry(value: &str) -> Option<DateTime<Utc>> {
+	let value = value.trim();
+	if let Ok(expires_at) = DateTime::parse_from_rfc3339(value) {
+		return Some(expires_at.with_timezone(&Utc));
+	}
+
+	NaiveDate::parse_from_str(value, "%Y-%m-%d")
+		.ok()
+		.and_then(|date| date.succ_opt())
+		.and_then(|date| date.and_hms_opt(0, 0, 0))
+		.map(|expires_at| expires_at.and_utc())
+}
+
+#[cfg(test)]
+mod tests {
+	use indoc::indoc;
+
+	use super::*;
+
+	fn guest_config() -> GuestConfig {
+		serde_yaml::from_str(indoc! {r#"
+			when: 'employee_type == "external"'
+			expires_attribute: contract_end
+			role: Guest
+			deactivation_grace_days: 7
+		"#})
+		.expect("invalid guest config")
+	}
+
+	fn user(employee_type: &str, contract_end: Option<&str>) -> User {
+		let mut user = User::new(
+			"John".to_owned(),
+			"Doe".to_owned(),
+			"john.doe@example.com".to_owned(),
+			None,
+			true,
+			None,
+			"jdoe".to_owned(),
+			None,
+		);
+		user.metadata.insert("employee_type".to_owned(), employee_type.to_owned());
+		if let Some(contract_end) = contract_end {
+			user.metadata.insert("contract_end".to_owned(), contract_end.to_owned());
+		}
+		user
+	}
+
+	fn time(value: &str) -> DateTime<Utc> {
+		DateTime::parse_from_rfc3339(value).expect("invalid time").with_timezone(&Utc)
+	}
+
+	#[test]
+	fn test_apply() {
+		let config = guest_config();
+		let now = time("2026-10-15T12:00:00Z");
+
+		// Staff aren't touched
+		let mut staff = user("internal", Some("2026-01-01"));
+		config.apply(&mut staff, now);
+		assert!(staff.enabled);
+		assert!(!is_guest(&staff));
+		assert_eq!(config.role_of(&staff), None);
+
+		// Guests expire at the end of their last day
+		let mut guest = user("external", Some("2026-10-15"));
+		config.apply(&mut guest, now);
+		assert!(guest.enabled);
+		assert_eq!(
+			guest.metadata.get(GUEST_EXPIRES_AT_KEY).map(String::as_str),
+			Some("2026-10-16T00:00:00+00:00")
+		);
+		assert_eq!(config.role_of(&guest), Some("Guest"));
+		assert!(!is_expired(&guest, now));
+
+		let mut expired = user("external", Some("2026-10-15T08:00:00+02:00"));
+		config.apply(&mut expired, now);
+		assert!(!expired.enabled);
+		assert!(is_expired(&expired, now));
+
+		// Guests without a valid expiry date are left untouched
+		let mut unknown = user("external", None);
+		config.apply(&mut unknown, now);
+		assert!(unknown.enabled);
+		assert!(unknown.is_unparsable());
+		assert!(!is_guest(&unknown));
+		let mut invalid = user("external", Some("next week"));
+		config.apply(&mut invalid, now);
+		assert!(invalid.enabled);
+		assert!(invalid.is_unparsable());
+	}
+
+	#[test]
+	fn test_parse_expiry() {
+		assert_eq!(parse_expiry("2026-12-31"), Some(time("2027-01-01T00:00:00Z")));
+		assert_eq!(parse_expiry(" 2026-12-31T10:00:00+01:00 "), Some(time("2026-12-31T09:00:00Z")));
+		assert_eq!(parse_expiry("31.12.2026"), None);
+		assert_eq!(parse_expiry(""), None);
+	}
+}
@@ -0,0 +1,103 @@
+//! Holding the names of users, so that syncs don't revert them
+//!
+//! When support fixes the names of a user in Zitadel, e.g. their
+//! casing, the next sync reverts the fix unless the source is fixed as
+//! well, which may take a while. `--hold-user <identifier> <days>`
+//! sets the `sync_hold_until` metadata entry of the user, and updates
+//! keep the names of the user as they are in Zitadel until then. Other
+//! changes of the user are still synced. A hold of 0 days releases the
+//! hold.
+use anyhow::{Context, Result};
+use chrono::{DateTime, Duration, Utc};
+use tracing::Instrument;
+
+use crate::{
+	explain::matches_identifier,
+	get_next_zitadel_user,
+	report::{Operation, Reporter},
+	spans,
+	zitadel::{Zitadel, SYNC_HOLD_UNTIL_KEY},
+	Config, FeatureFlag,
+};
+
+/// Hold the names of the user with the given identifier for the given
+/// number of days, or release the hold if it is 0
+pub async fn hold_user(config: &Config, identifier: &str, days: u32) -> Result<()> {
+	let hold_until = (days > 0).then(|| Utc::now() + Duration::days(i64::from(days)));
+
+	set_hold(config, identifier, hold_until)
+		.instrument(spans::run_span(spans::source_name(config)))
+		.await
+}
+
+/// Set or release the hold of the user with the given identifier
+async fn set_hold(
+	config: &Config,
+	identifier: &str,
+	hold_until: Option<DateTime<Utc>>,
+) -> Result<()> {
+	let dry_run = config.feature_flags.is_enabled(FeatureFlag::DryRun);
+	let mut reporter = Reporter::new(&config.reporting, dry_run)
+		.with_language(config.language)
+		.with_config_fingerprint(&config.fingerprint);
+
+	let mut zitadel = Zitadel::new(config).await?;
+	zitadel.preflight().await?;
+	let mut stream = zitadel.list_users()?;
+
+	let mut found = None;
+	while let Some((user, zitadel_id)) = get_next_zitadel_user(&mut stream, &mut zitadel).await? {
+		if matches_identifier(&user, identifier) {
+			found = Some((user, zitadel_id));
+			break;
+		}
+	}
+	let (user, zitadel_id) =
+		found.context(format!("No synced user found for the identifier `{identifier}`"))?;
+
+	match hold_until {
+		Some(hold_until) => tracing::info!(
+			"Holding the names of user `{}` until {}",
+			user.external_user_id,
+			hold_until.to_rfc3339()
+		),
+		None => tracing::info!("Releasing the hold of user `{}`", user.external_user_id),
+	}
+
+	let span = spans::user_span(Operation::Update, Some(&user.external_user_id), Some(&zitadel_id));
+	let res = zitadel.set_profile_hold(&zitadel_id, hold_until).instrument(span).await;
+	reporter.record_update(
+		&user.external_user_id,
+		&zitadel_id,
+		vec![SYNC_HOLD_UNTIL_KEY.to_owned()],
+		&res,
+	);
+
+	reporter.finish()?;
+	res
+}
+
+/// Whether a hold until the given time, as stored in the metadata of a
+/// user, is still active at the given time. Invalid times are ignored.
+pub(crate) fn is_hold_active(hold_until: &str, now: DateTime<Utc>) -> bool {
+	match DateTime::parse_from_rfc3339(hold_until) {
+		Ok(hold_until) => now < hold_until.with_timezone(&Utc),
+		Err(_) => {
+			tracing::warn!("Ignoring invalid hold time `{}`", hold_until);
+			false
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_is_hold_active() {
+		let now = Utc::now();
+		assert!(is_hold_active(&(now + Duration::days(1)).to_rfc3339(), now));
+		assert!(!is_hold_active(&(now - Duration::minutes(1)).to_rfc3339(), now));
+		assert!(!is_hold_active("next week", now));
+	}
+}
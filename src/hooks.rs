@@ -0,0 +1,106 @@
+//! Hooks for programs embedding the sync as a library
+//!
+//! Embedders can register hooks which run on every source user before
+//! the configured rules are applied and the user is compared with
+//! Zitadel, e.g. to derive external IDs in a custom way or to enrich
+//! users with data from another system, without changing the sources.
+//! Hooks are registered for the whole process, and apply to syncs as
+//! well as to `render_state`, `explain_user` and the SCIM server.
+//!
+//! With LDAP DirSync, unchanged users are taken from Zitadel, where
+//! they were stored after being processed by the hooks, so hooks
+//! should give the same result when run on their own output.
+use std::sync::{Arc, PoisonError, RwLock};
+
+use anyhow::{Context, Result};
+
+use crate::user::User;
+
+/// The registered user hooks, in the order of their registration
+static USER_HOOKS: RwLock<Vec<Arc<dyn UserHook>>> = RwLock::new(Vec::new());
+
+/// A hook processing every source user before it is synced
+pub trait UserHook: Send + Sync {
+	/// Process a source user
+	///
+	/// An error aborts the sync, so that users aren't deleted from
+	/// Zitadel because their processing failed.
+	fn process(&self, user: &mut User) -> Result<()>;
+}
+
+impl<F> UserHook for F
+where
+	F: Fn(&mut User) -> Result<()> + Send + Sync,
+{
+	fn process(&self, user: &mut User) -> Result<()> {
+		self(user)
+	}
+}
+
+/// Register a hook to run on every source user, after the hooks
+/// registered before
+pub fn register_user_hook(hook: impl UserHook + 'static) {
+	USER_HOOKS.write().unwrap_or_else(PoisonError::into_inner).push(Arc::new(hook));
+}
+
+/// Remove all registered user hooks
+pub fn clear_user_hooks() {
+	USER_HOOKS.write().unwrap_or_else(PoisonError::into_inner).clear();
+}
+
+/// Whether any user hooks are registered
+pub(crate) fn has_user_hooks() -> bool {
+	!USER_HOOKS.read().unwrap_or_else(PoisonError::into_inner).is_empty()
+}
+
+/// Run the registered hooks on a source user
+pub(crate) fn run_user_hooks(user: &mut User) -> Result<()> {
+	let hooks = USER_HOOKS.read().unwrap_or_else(PoisonError::into_inner).clone();
+	for hook in hooks {
+		let external_user_id = user.external_user_id.clone();
+		hook.process(user).context(format!("User hook failed for user `{external_user_id}`"))?;
+	}
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_user_hooks() {
+		let mut user = User::new(
+			"John".to_owned(),
+			"Doe".to_owned(),
+			"john.doe@example.com".to_owned(),
+			None,
+			true,
+			None,
+			"JDOE".to_owned(),
+			None,
+		);
+
+		register_user_hook(|user: &mut User| -> Result<()> {
+			user.set_external_user_id(user.get_external_id().to_lowercase());
+			Ok(())
+		});
+		register_user_hook(|user: &mut User| -> Result<()> {
+			user.set_attribute("department", format!("{}-dept", user.get_external_id()));
+			Ok(())
+		});
+		assert!(has_user_hooks());
+
+		run_user_hooks(&mut user).expect("hooks failed");
+		assert_eq!(user.get_external_id(), "jdoe");
+		assert_eq!(user.get_attribute("department"), Some("jdoe-dept".to_owned()));
+
+		register_user_hook(|_: &mut User| -> Result<()> {
+			anyhow::bail!("enrichment unavailable")
+		});
+		assert!(run_user_hooks(&mut user).is_err());
+
+		clear_user_hooks();
+		assert!(!has_user_hooks());
+	}
+}
@@ -0,0 +1,363 @@
+//! Persistent mapping between external user IDs, localparts and
+//! Zitadel IDs
+//!
+//! Localparts are normally derived from the external user ID, which
+//! breaks down if external IDs are recycled, e.g. sequential employee
+//! numbers: the new owner of an ID would get the localpart of its
+//! previous owner. With the mapping, localparts are allocated once
+//! per external ID and retired when the user is deleted, so that a
+//! recycled external ID is given a fresh localpart.
+use std::{
+	collections::BTreeMap,
+	fmt,
+	fs::File,
+	io::{BufRead, BufReader},
+	path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+use crate::{report::append_json_lines, Config};
+
+/// Configuration of the ID mapping
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct IdMappingConfig {
+	/// Path to the JSON lines file storing the mapping
+	pub path: PathBuf,
+}
+
+/// The mapping of a single user
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct IdMapping {
+	/// The hex-encoded external user ID
+	pub external_user_id: String,
+	/// The localpart allocated to the user
+	pub localpart: String,
+	/// The Zitadel ID of the user
+	pub zitadel_id: String,
+	/// When the mapping was created
+	pub created_at: String,
+	/// When the user was deleted, after which the localpart is never
+	/// allocated again
+	pub retired_at: Option<String>,
+}
+
+/// Storage of the ID mapping
+pub trait IdMappingBackend: fmt::Debug + Send {
+	/// Load all changes to the mapping, in order
+	fn load(&self) -> Result<Vec<IdMapping>>;
+	/// Persist a changed mapping
+	fn append(&self, mapping: &IdMapping) -> Result<()>;
+	/// Replace all stored changes with the given mappings
+	fn replace(&self, mappings: &[IdMapping]) -> Result<()>;
+}
+
+/// Storage of the ID mapping in a JSON lines file, to which every
+/// change is appended
+#[derive(Debug)]
+pub struct JsonLinesBackend {
+	/// Path to the file
+	path: PathBuf,
+}
+
+impl IdMappingBackend for JsonLinesBackend {
+	fn load(&self) -> Result<Vec<IdMapping>> {
+		if !self.path.exists() {
+			return Ok(Vec::new());
+		}
+
+		let file = File::open(&self.path)
+			.context(format!("Failed to open ID mapping {}", self.path.display()))?;
+		BufReader::new(file)
+			.lines()
+			.enumerate()
+			.filter(|(_, line)| line.as_ref().map_or(true, |line| !line.trim().is_empty()))
+			.map(|(number, line)| {
+				serde_json::from_str(&line?).context(format!(
+					"Invalid ID mapping in line {} of {}",
+					number + 1,
+					self.path.display()
+				))
+			})
+			.collect()
+	}
+
+	fn append(&self, mapping: &IdMapping) -> Result<()> {
+		append_json_lines(&self.path, &[mapping])
+	}
+
+	fn replace(&self, mappings: &[IdMapping]) -> Result<()> {
+		// Write to a temporary file first, so that a crash doesn't
+		// lose the mapping
+		let temporary_path = self.path.with_extension("tmp");
+		if temporary_path.exists() {
+			std::fs::remove_file(&temporary_path)?;
+		}
+		append_json_lines(&temporary_path, mappings)?;
+		std::fs::rename(&temporary_path, &self.path)
+			.context(format!("Failed to write ID mapping {}", self.path.display()))
+	}
+}
+
+/// The ID mapping, keyed by localpart
+#[derive(Debug)]
+pub struct IdMappingStore {
+	/// The storage of the mapping
+	backend: Box<dyn IdMappingBackend>,
+	/// All mappings ever created, keyed by localpart
+	mappings: BTreeMap<String, IdMapping>,
+}
+
+impl IdMappingStore {
+	/// Open the ID mapping configured by the user
+	pub fn open(config: &IdMappingConfig) -> Result<Self> {
+		Self::with_backend(Box::new(JsonLinesBackend { path: config.path.clone() }))
+	}
+
+	/// Open the ID mapping stored in the given backend
+	pub fn with_backend(backend: Box<dyn IdMappingBackend>) -> Result<Self> {
+		let changes = backend.load()?;
+		let change_count = changes.len();
+		let mappings: BTreeMap<_, _> =
+			changes.into_iter().map(|mapping| (mapping.localpart.clone(), mapping)).collect();
+
+		// Compact the log of changes once it is dominated by
+		// superseded entries
+		if change_count > 2 * mappings.len() + 100 {
+			backend.replace(&mappings.values().cloned().collect::<Vec<_>>())?;
+		}
+
+		Ok(Self { backend, mappings })
+	}
+
+	/// Get the active mapping of an external user ID
+	#[must_use]
+	pub fn get(&self, external_user_id: &str) -> Option<&IdMapping> {
+		self.mappings.values().find(|mapping| {
+			mapping.external_user_id == external_user_id && mapping.retired_at.is_none()
+		})
+	}
+
+	/// Get the localpart of an external user ID, allocating one if it
+	/// has none. The derived localpart is used unless it was allocated
+	/// before, in which case a numeric suffix is added.
+	///
+	/// New users get their localpart as Zitadel ID.
+	pub fn allocate_localpart(&mut self, external_user_id: &str, derived: &str) -> Result<String> {
+		if let Some(mapping) = self.get(external_user_id) {
+			return Ok(mapping.localpart.clone());
+		}
+
+		let localpart = (1..)
+			.map(|generation| match generation {
+				1 => derived.to_owned(),
+				generation => format!("{derived}.{generation}"),
+			})
+			.find(|localpart| !self.mappings.contains_key(localpart))
+			.context("No free localpart")?;
+
+		if localpart != derived {
+			tracing::info!(
+				"Allocating localpart `{}` to recycled external ID `{}`",
+				localpart,
+				external_user_id
+			);
+		}
+
+		self.insert(IdMapping {
+			external_user_id: external_user_id.to_owned(),
+			localpart: localpart.clone(),
+			zitadel_id: localpart.clone(),
+			created_at: Utc::now().to_rfc3339(),
+			retired_at: None,
+		})?;
+
+		Ok(localpart)
+	}
+
+	/// Record an existing user, e.g. one imported before the mapping
+	/// was configured, unless it is already known
+	pub fn observe(
+		&mut self,
+		external_user_id: &str,
+		localpart: &str,
+		zitadel_id: &str,
+	) -> Result<()> {
+		match self.mappings.get(localpart) {
+			Some(mapping) if mapping.external_user_id != external_user_id => {
+				tracing::warn!(
+					"Localpart `{}` of user `{}` is mapped to external ID `{}`",
+					localpart,
+					zitadel_id,
+					mapping.external_user_id
+				);
+				Ok(())
+			}
+			Some(_) => Ok(()),
+			None => self.insert(IdMapping {
+				external_user_id: external_user_id.to_owned(),
+				localpart: localpart.to_owned(),
+				zitadel_id: zitadel_id.to_owned(),
+				created_at: Utc::now().to_rfc3339(),
+				retired_at: None,
+			}),
+		}
+	}
+
+	/// Retire the mapping of a deleted user
+	pub fn retire(&mut self, zitadel_id: &str) -> Result<()> {
+		let Some(mapping) = self
+			.mappings
+			.values()
+			.find(|mapping| mapping.zitadel_id == zitadel_id && mapping.retired_at.is_none())
+			.cloned()
+		else {
+			return Ok(());
+		};
+
+		self.insert(IdMapping { retired_at: Some(Utc::now().to_rfc3339()), ..mapping })
+	}
+
+	/// Move the active mapping of a user to its new external ID
+	pub fn rename(&mut self, zitadel_id: &str, external_user_id: &str) -> Result<()> {
+		let Some(mapping) = self
+			.mappings
+			.values()
+			.find(|mapping| mapping.zitadel_id == zitadel_id && mapping.retired_at.is_none())
+			.cloned()
+		else {
+			return Ok(());
+		};
+
+		self.insert(IdMapping { external_user_id: external_user_id.to_owned(), ..mapping })
+	}
+
+	/// Write all mappings to a CSV file
+	pub fn export(&self, path: &Path) -> Result<()> {
+		let mut writer =
+			csv::Writer::from_path(path).context(format!("Failed to create {}", path.display()))?;
+		for mapping in self.mappings.values() {
+			writer.serialize(mapping)?;
+		}
+		writer.flush()?;
+
+		Ok(())
+	}
+
+	/// Read mappings from a CSV file written by
+	/// [`export`](Self::export), replacing existing mappings of the
+	/// same localparts. Returns the number of imported mappings.
+	pub fn import(&mut self, path: &Path) -> Result<usize> {
+		let mut reader =
+			csv::Reader::from_path(path).context(format!("Failed to open {}", path.display()))?;
+		let imported = reader
+			.deserialize()
+			.collect::<Result<Vec<IdMapping>, _>>()
+			.context(format!("Invalid ID mapping in {}", path.display()))?;
+
+		let mut active = BTreeMap::new();
+		for mapping in imported.iter().filter(|mapping| mapping.retired_at.is_none()) {
+			if let Some(localpart) = active.insert(&mapping.external_user_id, &mapping.localpart) {
+				bail!(
+					"External ID `{}` is mapped to both `{}` and `{}`",
+					mapping.external_user_id,
+					localpart,
+					mapping.localpart
+				);
+			}
+		}
+
+		for mapping in &imported {
+			self.mappings.insert(mapping.localpart.clone(), mapping.clone());
+		}
+		self.backend.replace(&self.mappings.values().cloned().collect::<Vec<_>>())?;
+
+		Ok(imported.len())
+	}
+
+	/// Store a new or changed mapping
+	fn insert(&mut self, mapping: IdMapping) -> Result<()> {
+		self.backend.append(&mapping)?;
+		self.mappings.insert(mapping.localpart.clone(), mapping);
+		Ok(())
+	}
+}
+
+/// Get the ID mapping configured by the user
+fn open_configured(config: &Config) -> Result<IdMappingStore> {
+	match &config.id_mapping {
+		Some(id_mapping) => IdMappingStore::open(id_mapping),
+		None => bail!("No ID mapping is configured"),
+	}
+}
+
+/// Export the configured ID mapping to a CSV file, e.g. for backups
+pub fn export_id_mapping(config: &Config, path: &Path) -> Result<()> {
+	open_configured(config)?.export(path)?;
+	tracing::info!("Exported ID mapping to {}", path.display());
+	Ok(())
+}
+
+/// Import an ID mapping exported with [`export_id_mapping`] into the
+/// configured ID mapping
+pub fn import_id_mapping(config: &Config, path: &Path) -> Result<()> {
+	let imported = open_configured(config)?.import(path)?;
+	tracing::info!("Imported {} ID mappings from {}", imported, path.display());
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use tempfile::TempDir;
+
+	use super::*;
+
+	fn open(dir: &TempDir) -> IdMappingStore {
+		IdMappingStore::open(&IdMappingConfig { path: dir.path().join("id-mapping.jsonl") })
+			.expect("failed to open ID mapping")
+	}
+
+	#[test]
+	fn test_recycled_external_id() {
+		let dir = TempDir::new().expect("failed to create tempdir");
+		let mut store = open(&dir);
+
+		let localpart = store.allocate_localpart("3031", "1001").expect("failed to allocate");
+		assert_eq!(localpart, "1001");
+		assert_eq!(store.allocate_localpart("3031", "1001").expect("failed to allocate"), "1001");
+
+		store.retire("1001").expect("failed to retire");
+		assert!(store.get("3031").is_none());
+
+		// Mappings survive reopening the store
+		let mut store = open(&dir);
+		assert_eq!(store.allocate_localpart("3031", "1001").expect("failed to allocate"), "1001.2");
+		assert_eq!(store.get("3031").map(|mapping| mapping.zitadel_id.as_str()), Some("1001.2"));
+	}
+
+	#[test]
+	fn test_export_import() {
+		let dir = TempDir::new().expect("failed to create tempdir");
+		let export_path = dir.path().join("export.csv");
+
+		let mut store = open(&dir);
+		store.observe("3031", "alice", "123").expect("failed to observe");
+		store.allocate_localpart("3032", "bob").expect("failed to allocate");
+		store.retire("bob").expect("failed to retire");
+		store.export(&export_path).expect("failed to export");
+
+		let restore_dir = TempDir::new().expect("failed to create tempdir");
+		let mut restored = open(&restore_dir);
+		assert_eq!(restored.import(&export_path).expect("failed to import"), 2);
+		assert_eq!(restored.get("3031").map(|mapping| mapping.localpart.as_str()), Some("alice"));
+		assert!(restored.get("3032").is_none());
+
+		let mut restored = open(&restore_dir);
+		assert_eq!(
+			restored.allocate_localpart("3032", "bob").expect("failed to allocate"),
+			"bob.2"
+		);
+	}
+}
@@ -0,0 +1,137 @@
+//! Throttling of imports during the onboarding of an organization
+//!
+//! The first import of a large organization provisions many users in
+//! downstream systems, e.g. Matrix accounts and licenses, at once.
+//! With a ramp-up configured, the initial sync pauses after a first
+//! batch of imports, giving operators the chance to check these
+//! systems and abort the sync before the rest is imported. The number
+//! of imports of a single run can be limited as well, in which case
+//! the remaining users are imported by later syncs.
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::watchdog;
+
+/// Configuration of the import ramp-up of the initial sync
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct ImportRampUpConfig {
+	/// The number of users imported before pausing
+	#[serde(default = "default_initial_batch")]
+	pub initial_batch: usize,
+	/// How long to pause after the first batch, in seconds
+	#[serde(default = "default_pause_seconds")]
+	pub pause_seconds: u64,
+}
+
+/// Default for [`ImportRampUpConfig::initial_batch`]
+fn default_initial_batch() -> usize {
+	100
+}
+
+/// Default for [`ImportRampUpConfig::pause_seconds`]
+fn default_pause_seconds() -> u64 {
+	300
+}
+
+/// Decides whether and when the users of a sync are imported
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ImportThrottle {
+	/// The ramp-up to apply, if any
+	ramp_up: Option<ImportRampUpConfig>,
+	/// The maximum number of imports
+	limit: Option<usize>,
+	/// The number of imports admitted so far
+	admitted: usize,
+	/// The number of imports skipped due to the limit
+	skipped: usize,
+}
+
+impl ImportThrottle {
+	/// Create a throttle applying the given ramp-up and limit
+	pub(crate) fn new(ramp_up: Option<ImportRampUpConfig>, limit: Option<usize>) -> Self {
+		Self { ramp_up, limit, admitted: 0, skipped: 0 }
+	}
+
+	/// Wait until the next import may proceed, returning `false` if it
+	/// should be skipped since the limit is reached
+	pub(crate) async fn admit(&mut self, external_user_id: &str) -> bool {
+		if self.limit.is_some_and(|limit| self.admitted >= limit) {
+			tracing::debug!("Skipping import of `{}` beyond the import limit", external_user_id);
+			self.skipped += 1;
+			return false;
+		}
+
+		if let Some(ramp_up) = &self.ramp_up {
+			if self.admitted > 0 && self.admitted == ramp_up.initial_batch {
+				tracing::warn!(
+					"Imported the first {} users, pausing for {} seconds before importing the \
+					 rest; abort the sync now if downstream systems misbehave",
+					self.admitted,
+					ramp_up.pause_seconds
+				);
+				pause(Duration::from_secs(ramp_up.pause_seconds)).await;
+				tracing::info!("Resuming imports");
+			}
+		}
+
+		self.admitted += 1;
+		true
+	}
+
+	/// The number of imports skipped due to the limit
+	pub(crate) fn skipped(&self) -> usize {
+		self.skipped
+	}
+}
+
+/// Sleep for the given time, keeping the watchdog from aborting the
+/// sync meanwhile
+async fn pause(duration: Duration) {
+	let step = Duration::from_secs(10);
+	let mut remaining = duration;
+
+	while !remaining.is_zero() {
+		watchdog::set_phase("pausing imports");
+		let sleep = remaining.min(step);
+		tokio::time::sleep(sleep).await;
+		remaining -= sleep;
+	}
+
+	watchdog::set_phase("syncing users");
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[tokio::test]
+	async fn test_import_limit() {
+		let mut throttle = ImportThrottle::new(None, Some(2));
+
+		assert!(throttle.admit("1").await);
+		assert!(throttle.admit("2").await);
+		assert!(!throttle.admit("3").await);
+		assert!(!throttle.admit("4").await);
+		assert_eq!(throttle.skipped(), 2);
+	}
+
+	#[tokio::test]
+	async fn test_ramp_up() {
+		let ramp_up = ImportRampUpConfig { initial_batch: 2, pause_seconds: 1 };
+		let mut throttle = ImportThrottle::new(Some(ramp_up), None);
+
+		let start = std::time::Instant::now();
+		assert!(throttle.admit("1").await);
+		assert!(throttle.admit("2").await);
+		assert!(start.elapsed() < Duration::from_secs(1));
+
+		assert!(throttle.admit("3").await);
+		assert!(start.elapsed() >= Duration::from_secs(1));
+
+		let resumed = std::time::Instant::now();
+		assert!(throttle.admit("4").await);
+		assert!(resumed.elapsed() < Duration::from_secs(1));
+		assert_eq!(throttle.skipped(), 0);
+	}
+}
@@ -0,0 +1,209 @@
+//! Write-ahead log of the writes to Zitadel
+//!
+//! Before each write, an intent record naming the operation, its target
+//! and a hash of the written data is appended to the log and synced to
+//! disk. Once the write is over, a record of its outcome follows. After
+//! a crash, intents without an outcome name exactly the writes which
+//! may have been applied partially.
+use std::{
+	collections::BTreeMap,
+	fs::OpenOptions,
+	io::{BufRead, BufReader, Write},
+	path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::report::Operation;
+
+/// The stage of a write a record of the intent log marks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum IntentStatus {
+	/// The write is about to start
+	Intent,
+	/// The write succeeded
+	Completed,
+	/// The write failed
+	Failed,
+}
+
+/// A record of the intent log
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct IntentRecord {
+	/// When the record was written
+	pub(crate) timestamp: String,
+	/// The ID of the write, shared by its intent and outcome
+	pub(crate) intent_id: String,
+	/// The stage of the write
+	pub(crate) status: IntentStatus,
+	/// The write operation
+	pub(crate) operation: Operation,
+	/// The external or Zitadel ID of the user written
+	pub(crate) target: String,
+	/// The SHA-256 hash of the data written, as JSON
+	pub(crate) payload_hash: String,
+	/// The error the write failed with
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub(crate) error: Option<String>,
+}
+
+/// A write whose intent was logged
+#[derive(Debug)]
+pub(crate) struct Intent {
+	/// The ID of the write
+	intent_id: String,
+	/// The write operation
+	operation: Operation,
+	/// The user written
+	target: String,
+	/// The hash of the data written
+	payload_hash: String,
+}
+
+/// The intent log of a sync
+#[derive(Debug)]
+pub(crate) struct IntentLog {
+	/// The path of the log file
+	path: PathBuf,
+	/// The ID of the sync, prefixing the IDs of its writes
+	run_id: String,
+	/// The number of writes logged so far
+	logged: u64,
+}
+
+impl IntentLog {
+	/// Open the intent log at the given path, warning about writes of
+	/// earlier syncs which never finished
+	pub(crate) fn open(path: &Path) -> Result<Self> {
+		for intent in unfinished_intents(path)? {
+			tracing::warn!(
+				"A {} of `{}` started at {} never finished and may have been applied partially (intent `{}`)",
+				intent.operation.name(),
+				intent.target,
+				intent.timestamp,
+				intent.intent_id
+			);
+		}
+
+		Ok(Self { path: path.to_owned(), run_id: uuid::Uuid::new_v4().to_string(), logged: 0 })
+	}
+
+	/// Log the intent to write the given data, before writing it
+	pub(crate) fn begin(
+		&mut self,
+		operation: Operation,
+		target: &str,
+		payload: &impl Serialize,
+	) -> Result<Intent> {
+		self.logged += 1;
+		let intent = Intent {
+			intent_id: format!("{}-{}", self.run_id, self.logged),
+			operation,
+			target: target.to_owned(),
+			payload_hash: hex::encode(Sha256::digest(serde_json::to_vec(payload)?)),
+		};
+		self.append(&intent, IntentStatus::Intent, None)?;
+
+		Ok(intent)
+	}
+
+	/// Log the outcome of a write
+	pub(crate) fn finish(&self, intent: &Intent, result: &Result<()>) -> Result<()> {
+		match result {
+			Ok(()) => self.append(intent, IntentStatus::Completed, None),
+			Err(error) => self.append(intent, IntentStatus::Failed, Some(format!("{error:#}"))),
+		}
+	}
+
+	/// Append a record to the log, and sync it to disk
+	fn append(&self, intent: &Intent, status: IntentStatus, error: Option<String>) -> Result<()> {
+		let record = IntentRecord {
+			timestamp: Utc::now().to_rfc3339(),
+			intent_id: intent.intent_id.clone(),
+			status,
+			operation: intent.operation,
+			target: intent.target.clone(),
+			payload_hash: intent.payload_hash.clone(),
+			error,
+		};
+
+		let mut line = serde_json::to_vec(&record)?;
+		line.push(b'\n');
+
+		let mut file = OpenOptions::new()
+			.create(true)
+			.append(true)
+			.open(&self.path)
+			.context(format!("Failed to open {}", self.path.display()))?;
+		file.write_all(&line).context(format!("Failed to write to {}", self.path.display()))?;
+		file.sync_data().context(format!("Failed to sync {}", self.path.display()))?;
+
+		Ok(())
+	}
+}
+
+/// The intents of the log at the given path which have no outcome
+pub(crate) fn unfinished_intents(path: &Path) -> Result<Vec<IntentRecord>> {
+	if !path.exists() {
+		return Ok(Vec::new());
+	}
+
+	let file = std::fs::File::open(path).context(format!("Failed to open {}", path.display()))?;
+	let mut unfinished = BTreeMap::new();
+	for line in BufReader::new(file).lines() {
+		let line = line.context(format!("Failed to read {}", path.display()))?;
+		// A crash while appending may leave a truncated last line
+		let Ok(record) = serde_json::from_str::<IntentRecord>(&line) else {
+			continue;
+		};
+
+		if record.status == IntentStatus::Intent {
+			unfinished.insert(record.intent_id.clone(), record);
+		} else {
+			unfinished.remove(&record.intent_id);
+		}
+	}
+
+	let mut unfinished: Vec<_> = unfinished.into_values().collect();
+	unfinished.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+	Ok(unfinished)
+}
+
+#[cfg(test)]
+mod tests {
+	use anyhow::anyhow;
+	use tempfile::TempDir;
+
+	use super::*;
+
+	#[test]
+	fn test_unfinished_intents() {
+		let dir = TempDir::new().expect("failed to create tempdir");
+		let path = dir.path().join("intents.jsonl");
+
+		let mut log = IntentLog::open(&path).expect("failed to open intent log");
+		let created = log.begin(Operation::Create, "jdoe", &"jdoe").expect("failed to log intent");
+		log.finish(&created, &Ok(())).expect("failed to log outcome");
+		let updated = log.begin(Operation::Update, "mmuster", &"mmuster").expect("failed to log");
+		log.finish(&updated, &Err(anyhow!("Zitadel is down"))).expect("failed to log outcome");
+		let deleted = log.begin(Operation::Delete, "123", &"123").expect("failed to log intent");
+
+		let unfinished = unfinished_intents(&path).expect("failed to read intent log");
+		assert_eq!(unfinished.len(), 1);
+		assert_eq!(unfinished[0].intent_id, deleted.intent_id);
+		assert_eq!(unfinished[0].operation, Operation::Delete);
+		assert_eq!(unfinished[0].target, "123");
+		assert_eq!(unfinished[0].payload_hash, hex::encode(Sha256::digest(b"\"123\"")));
+
+		// Intents of earlier syncs stay unfinished, while those of later
+		// syncs get IDs of their own
+		let mut log = IntentLog::open(&path).expect("failed to open intent log");
+		let created = log.begin(Operation::Create, "jdoe", &"jdoe").expect("failed to log intent");
+		log.finish(&created, &Ok(())).expect("failed to log outcome");
+		assert_eq!(unfinished_intents(&path).expect("failed to read intent log").len(), 1);
+	}
+}
@@ -0,0 +1,163 @@
+//! Tracking of the time spent reconciling each user
+//!
+//! Zitadel API calls made while a sync is running are timed, and the
+//! time is attributed to the user the calls were made for once the
+//! user is processed. This reveals users which stall the sync, and
+//! which call is responsible.
+use std::{
+	cmp::Reverse,
+	collections::BTreeMap,
+	future::Future,
+	sync::{Mutex, MutexGuard, PoisonError},
+	time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use crate::metrics;
+
+/// The API call timings of the running sync
+static STATE: Mutex<LatencyState> =
+	Mutex::new(LatencyState { enabled: false, pending: BTreeMap::new(), users: BTreeMap::new() });
+
+/// The API call timings of the running sync
+#[derive(Debug)]
+struct LatencyState {
+	/// Whether calls are currently timed
+	enabled: bool,
+	/// Time spent in calls not yet attributed to a user, by call
+	pending: BTreeMap<&'static str, Duration>,
+	/// Time spent in calls for each user, by call
+	users: BTreeMap<String, BTreeMap<&'static str, Duration>>,
+}
+
+/// The time spent reconciling a user
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct UserLatency {
+	/// The external ID of the user
+	pub external_user_id: String,
+	/// The total time spent in API calls for the user, in
+	/// milliseconds
+	pub total_ms: u128,
+	/// The API call the most time was spent in
+	pub dominant_call: String,
+	/// The time spent in the dominant call, in milliseconds
+	pub dominant_call_ms: u128,
+}
+
+/// Lock the timings, ignoring poisoning, since they are only used for
+/// diagnostics
+fn state() -> MutexGuard<'static, LatencyState> {
+	STATE.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Start timing API calls, discarding earlier timings
+pub(crate) fn start() {
+	let mut state = state();
+	state.enabled = true;
+	state.pending.clear();
+	state.users.clear();
+}
+
+/// Time an API call, if timing is enabled, and count it for the
+/// metrics
+pub(crate) async fn timed<T>(call: &'static str, future: impl Future<Output = T>) -> T {
+	metrics::count_api_call(call);
+	let started_at = Instant::now();
+	let output = future.await;
+
+	let mut state = state();
+	if state.enabled {
+		*state.pending.entry(call).or_default() += started_at.elapsed();
+	}
+
+	output
+}
+
+/// Attribute the calls timed since the last attribution to a user
+pub(crate) fn attribute(external_user_id: &str) {
+	let mut state = state();
+	if !state.enabled || state.pending.is_empty() {
+		return;
+	}
+
+	let pending = std::mem::take(&mut state.pending);
+	let user = state.users.entry(external_user_id.to_owned()).or_default();
+	for (call, duration) in pending {
+		*user.entry(call).or_default() += duration;
+	}
+}
+
+/// Stop timing API calls, and return the time spent on each user,
+/// slowest first
+pub(crate) fn finish() -> Vec<UserLatency> {
+	let mut state = state();
+	state.enabled = false;
+	state.pending.clear();
+	summarize(std::mem::take(&mut state.users))
+}
+
+/// Summarize the time spent in calls for each user, slowest first
+fn summarize(users: BTreeMap<String, BTreeMap<&'static str, Duration>>) -> Vec<UserLatency> {
+	let mut latencies: Vec<UserLatency> = users
+		.into_iter()
+		.filter_map(|(external_user_id, calls)| {
+			let total: Duration = calls.values().sum();
+			let (dominant_call, dominant_duration) =
+				calls.into_iter().max_by_key(|(_, duration)| *duration)?;
+
+			Some(UserLatency {
+				external_user_id,
+				total_ms: total.as_millis(),
+				dominant_call: dominant_call.to_owned(),
+				dominant_call_ms: dominant_duration.as_millis(),
+			})
+		})
+		.collect();
+
+	latencies.sort_by_key(|latency| Reverse(latency.total_ms));
+	latencies
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_summarize() {
+		let users = BTreeMap::from([
+			(
+				"aa".to_owned(),
+				BTreeMap::from([
+					("get metadata", Duration::from_millis(2500)),
+					("list grants", Duration::from_millis(100)),
+				]),
+			),
+			("bb".to_owned(), BTreeMap::from([("update user", Duration::from_millis(50))])),
+			(
+				"cc".to_owned(),
+				BTreeMap::from([
+					("create user", Duration::from_millis(300)),
+					("add grant", Duration::from_millis(400)),
+				]),
+			),
+		]);
+
+		let latencies = summarize(users);
+
+		assert_eq!(
+			latencies.iter().map(|latency| latency.external_user_id.as_str()).collect::<Vec<_>>(),
+			vec!["aa", "cc", "bb"]
+		);
+		assert_eq!(
+			latencies[0],
+			UserLatency {
+				external_user_id: "aa".to_owned(),
+				total_ms: 2600,
+				dominant_call: "get metadata".to_owned(),
+				dominant_call_ms: 2500,
+			}
+		);
+		assert_eq!(latencies[1].dominant_call, "add grant");
+	}
+}
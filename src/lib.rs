@@ -1,180 +1,1960 @@
 //! Sync tool between other sources and our infrastructure based on Zitadel.
 use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
 use futures::{Stream, StreamExt};
+use tracing::Instrument;
 use user::User;
-use zitadel::Zitadel;
+use zitadel::{
+	get_zitadel_encoded_id, DeprovisioningPolicy, UniqueAttributeTaken, UnmanagedUserPolicy,
+	Zitadel, DEACTIVATED_AT_KEY, PENDING_DEPROVISIONING_KEY,
+};
 
+mod anonymize;
+mod artifacts;
+mod change_budget;
+mod compare;
 mod config;
+mod daemon;
+mod desired_state;
+mod drift;
+mod email_verification;
+mod explain;
+mod guests;
+mod hold;
+pub mod hooks;
+pub mod id_mapping;
+mod import_throttle;
+mod intent_log;
+mod latency;
+mod messages;
+mod metrics;
+mod normalization;
+pub mod output;
+mod page_prefetch;
+mod personas;
+mod pilot;
+pub mod plan;
+mod profile;
+mod rate_limit;
+mod remap_roles;
+mod rename;
+pub mod report;
+pub mod resources;
+pub mod rules;
+mod scim;
+mod second_factors;
+mod self_service;
+pub mod source_guard;
+mod source_versions;
 mod sources;
+mod spans;
+pub mod state;
+mod support_bundle;
 pub mod user;
+mod user_cache;
+mod version_check;
+mod watch;
+pub mod watchdog;
+mod write_batches;
 pub mod zitadel;
 
-use std::collections::VecDeque;
+use std::{
+	borrow::Cow,
+	collections::{BTreeMap, HashSet, VecDeque},
+	path::Path,
+};
 
+pub use anonymize::anonymize_export;
+use artifacts::RunArtifacts;
+use change_budget::ChangeBudget;
+pub use compare::compare_shadow;
 pub use config::{Config, FeatureFlag, LdapSourceConfig};
+use config::{IdentityPolicy, IdpLinkGcMode, InitialSyncPolicy};
+pub use daemon::run_daemon;
+pub use desired_state::{apply_state, render_state};
+use drift::DriftTracker;
+pub use email_verification::reverify_emails;
+pub use explain::explain_user;
+pub use hold::hold_user;
+use import_throttle::ImportThrottle;
+use messages::Message;
+use metrics::MetricsRun;
+use personas::MergeConflict;
+use pilot::PilotConfig;
+pub use plan::{apply_plan, plan_sync};
+pub use remap_roles::remap_roles;
+use rename::Rename;
+use report::{DeletionReason, Operation, Reporter, ReportingConfig, SyncReport};
+pub use scim::serve_scim;
+pub use self_service::serve_self_service_events;
+use source_guard::SourceGuard;
+use source_versions::SourceVersions;
 pub use sources::{
 	csv::test_helpers as csv_test_helpers, ldap::AttributeMapping,
 	ukt::test_helpers as ukt_test_helpers,
 };
-use sources::{csv::CsvSource, ldap::LdapSource, ukt::UktSource, Source};
+use sources::{
+	csv::CsvSource,
+	entra::EntraSource,
+	fhir::FhirSource,
+	keycloak::KeycloakSource,
+	ldap::{IncrementalSyncConfig, LdapSource},
+	merged::MergedSource,
+	ukt::UktSource,
+	Source,
+};
+use state::{IncrementalSyncMarks, SyncState};
+pub use support_bundle::create_support_bundle;
+pub use watch::watch_ldap;
 
 /// Helper function to add metadata to streamed zitadel users
+///
+/// Users outside of the configured user scope are skipped.
 // TODO: If async closures become a reality, this should be factored
 // into the `zitadel::search_result_to_user` function
 pub async fn get_next_zitadel_user(
 	stream: &mut (impl Stream<Item = Result<(User, String)>> + Send + Unpin),
 	zitadel: &mut Zitadel,
 ) -> Result<Option<(User, String)>> {
-	match stream.next().await.transpose()? {
-		Some(mut zitadel_user) => {
-			let preferred_username = zitadel
-				.zitadel_client
-				.get_user_metadata(&zitadel_user.1, "preferred_username")
-				.await
-				.ok()
-				.and_then(|metadata| metadata.metadata().value());
+	get_next_compared_zitadel_user(stream, zitadel, None).await
+}
+
+/// Get the next Zitadel user to compare with the source users
+///
+/// Users unchanged in the source since the last sync are returned as
+/// listed, without reading their metadata and roles, since they aren't
+/// compared.
+async fn get_next_compared_zitadel_user(
+	stream: &mut (impl Stream<Item = Result<(User, String)>> + Send + Unpin),
+	zitadel: &mut Zitadel,
+	source_versions: Option<&SourceVersions>,
+) -> Result<Option<(User, String)>> {
+	while let Some((user, zitadel_id)) = stream.next().await.transpose()? {
+		watchdog::record_progress(&user.external_user_id);
+
+		if source_versions.is_some_and(|versions| versions.is_unchanged(&user.external_user_id)) {
+			return Ok(Some((user, zitadel_id)));
+		}
+
+		let external_user_id = user.external_user_id.clone();
+		let user = complete_zitadel_user(zitadel, user, &zitadel_id).await?;
+		latency::attribute(&external_user_id);
+
+		if let Some(user) = user {
+			return Ok(Some((user, zitadel_id)));
+		}
+	}
+
+	Ok(None)
+}
+
+/// Add metadata and roles to a Zitadel user, or return `None` if the
+/// user is not managed by the sync
+pub(crate) async fn complete_zitadel_user(
+	zitadel: &mut Zitadel,
+	mut user: User,
+	zitadel_id: &str,
+) -> Result<Option<User>> {
+	if !latency::timed("check user scope", zitadel.is_user_in_scope(zitadel_id)).await {
+		tracing::debug!("Skipping Zitadel user `{}` outside of the user scope", zitadel_id);
+		zitadel.record_unmanaged_user(&user.external_user_id, zitadel_id);
+		return Ok(None);
+	}
+
+	if user.email.is_empty() && !zitadel.keep_user_without_email(zitadel_id) {
+		zitadel.record_unmanaged_user(&user.external_user_id, zitadel_id);
+		return Ok(None);
+	}
+
+	// A preferred username which failed to be read is left as it is,
+	// rather than taken for missing, which would delete it
+	match latency::timed(
+		"get metadata",
+		zitadel.try_get_managed_metadata_value(zitadel_id, "preferred_username"),
+	)
+	.await
+	{
+		Ok(preferred_username) => user.preferred_username = preferred_username,
+		Err(error) => {
+			tracing::warn!(
+				"Leaving the preferred username of user `{}` as it is: {:?}",
+				zitadel_id,
+				error
+			);
+			user.preferred_username_unknown = true;
+		}
+	}
+	// Users whose metadata can't be read are skipped like unmanaged
+	// ones, since missing values would be set, or lead to deletions
+	let metadata = async {
+		let localpart = zitadel.try_get_managed_metadata_value(zitadel_id, "localpart").await?;
+		let metadata = zitadel.get_additional_metadata(zitadel_id).await?;
+		anyhow::Ok((localpart, metadata))
+	};
+	match latency::timed("get metadata", metadata).await {
+		Ok((localpart, metadata)) => {
+			user.localpart = localpart;
+			user.metadata = metadata;
+		}
+		Err(error) => {
+			tracing::warn!(
+				"Skipping Zitadel user `{}`, since its metadata can't be read: {:?}",
+				zitadel_id,
+				error
+			);
+			zitadel.record_unmanaged_user(&user.external_user_id, zitadel_id);
+			return Ok(None);
+		}
+	}
+	zitadel.record_id_mapping(&user, zitadel_id)?;
+	user.roles = latency::timed("list grants", zitadel.get_additional_roles(zitadel_id)).await?;
+
+	Ok(Some(user))
+}
+
+/// Options for a single sync run, as opposed to the configuration
+/// shared by all runs
+#[derive(Debug, Clone, Default)]
+pub struct SyncOptions {
+	/// Whether the first sync against an organization was confirmed
+	pub confirm_initial_sync: bool,
+	/// The maximum number of users to import, leaving the rest to
+	/// later syncs
+	pub import_limit: Option<usize>,
+	/// Whether to delete users with second factors, even if there are
+	/// more than `second_factor_protection.max_deletions`
+	pub allow_second_factor_deletions: bool,
+	/// Whether to delete more users than `deletion_guard` allows
+	pub allow_mass_deletions: bool,
+}
+
+/// Perform a sync operation, returning the report of the sync
+pub async fn perform_sync(config: &Config) -> Result<SyncReport> {
+	perform_sync_with_options(config, &SyncOptions::default()).await
+}
+
+/// Perform a sync operation with the given options, returning the
+/// report of the sync
+pub async fn perform_sync_with_options(
+	config: &Config,
+	options: &SyncOptions,
+) -> Result<SyncReport> {
+	run_sync(config, options, None).await
+}
+
+/// Sync the given changes of the source, e.g. observed while watching
+/// it, instead of reading all users from the source, returning the
+/// report of the sync
+pub(crate) async fn perform_sync_of_changes(
+	config: &Config,
+	changes: SourceChanges,
+) -> Result<SyncReport> {
+	run_sync(config, &SyncOptions::default(), Some(changes)).await
+}
+
+/// Perform a sync operation with the given options, of only the given
+/// changes of the source if any, returning the report of the sync
+async fn run_sync(
+	config: &Config,
+	options: &SyncOptions,
+	changes: Option<SourceChanges>,
+) -> Result<SyncReport> {
+	let initial_sync = match &config.state_path {
+		Some(state_path) => !SyncState::has_synced(state_path, &config.zitadel.organization_id)?,
+		None => false,
+	};
+
+	let mut config = Cow::Borrowed(config);
+	if initial_sync && !options.confirm_initial_sync {
+		match config.initial_sync {
+			InitialSyncPolicy::RequireConfirmation => {
+				anyhow::bail!(Message::InitialSyncRequiresConfirmation {
+					organization_id: &config.zitadel.organization_id
+				}
+				.render(config.language))
+			}
+			InitialSyncPolicy::DryRun => {
+				tracing::warn!(
+					"{}",
+					Message::InitialSyncDryRun { organization_id: &config.zitadel.organization_id }
+						.render(config.language)
+				);
+				config.to_mut().feature_flags.push(FeatureFlag::DryRun);
+			}
+		}
+	}
+	if options.allow_second_factor_deletions {
+		config.to_mut().second_factor_protection.max_deletions = usize::MAX;
+	}
+	if options.allow_mass_deletions {
+		config.to_mut().deletion_guard = None;
+	}
+	let artifacts = match config.artifacts.clone() {
+		Some(artifacts) => Some(RunArtifacts::start(&artifacts, config.to_mut())?),
+		None => None,
+	};
+	let dry_run = config.feature_flags.is_enabled(FeatureFlag::DryRun);
+	if !dry_run {
+		user_cache::invalidate(&config)?;
+	}
+
+	let mut reporter = Reporter::new(&config.reporting, dry_run)
+		.with_deletions_dry_run(config.feature_flags.is_enabled(FeatureFlag::DryRunDeletions))
+		.with_language(config.language)
+		.with_config_fingerprint(&config.fingerprint);
+	if let Some(version_check) = &config.version_check {
+		if let Some(notice) = version_check::check_version(version_check, config.language).await {
+			reporter.record_version_notice(notice);
+		}
+	}
+
+	// Pausing only makes sense if the imports are real
+	let ramp_up = config.import_ramp_up.clone().filter(|_| initial_sync && !dry_run);
+	let mut import_throttle = ImportThrottle::new(ramp_up, options.import_limit);
+	let metrics = config
+		.metrics
+		.clone()
+		.filter(|_| config.feature_flags.is_enabled(FeatureFlag::Metrics))
+		.map(|metrics_config| (metrics_config, MetricsRun::start()));
+
+	let result = resources::run_with_monitoring(
+		config.resource_monitoring.as_ref(),
+		watchdog::run_with_watchdog(
+			config.watchdog.as_ref(),
+			sync_from_sources(&config, &mut reporter, &mut import_throttle, changes, true)
+				.instrument(spans::run_span(spans::source_name(&config))),
+		),
+	)
+	.await;
+
+	// Always finish the report, so that aborted syncs are documented
+	// as well
+	let report = reporter.finish();
+	if let (Err(error), Err(_)) = (&report, &result) {
+		tracing::error!("Failed to write sync report: {:?}", error);
+	}
+
+	// Failing to export metrics doesn't make the sync fail, but the
+	// missing metrics should trigger an alert
+	if let Some((metrics_config, metrics)) = metrics {
+		let organization_id = &config.zitadel.organization_id;
+		if let Err(error) = metrics
+			.finish(
+				&metrics_config,
+				organization_id,
+				report.as_ref().ok(),
+				result.is_ok() && report.is_ok(),
+			)
+			.await
+		{
+			tracing::error!("Failed to export metrics: {:?}", error);
+		}
+	}
+	let result = result.and(report);
+
+	// Dry runs don't count as syncs, so that the first real sync
+	// still needs to be confirmed
+	if let (Ok(_), Some(state_path), false) = (&result, &config.state_path, dry_run) {
+		SyncState::record_sync(state_path, &config.zitadel.organization_id)?;
+	}
+
+	if let Some(artifacts) = artifacts {
+		artifacts.finish(&config);
+	}
+
+	result
+}
+
+/// Sync twice, failing if the second pass would write anything
+///
+/// The second pass runs as a dry run, so that it only reports the
+/// writes it would make. Any such writes indicate that the sync
+/// doesn't converge, e.g. because attributes are normalized
+/// differently by the sources and Zitadel. The second pass compares
+/// all users, including those unchanged in the source since the first.
+pub async fn verify_idempotent(config: &Config) -> Result<()> {
+	perform_sync(config).await.context("First sync pass failed")?;
+
+	let mut dry_run_config = config.clone();
+	dry_run_config.feature_flags.push(FeatureFlag::DryRun);
+
+	// Don't overwrite the report of the first pass
+	let mut reporter = Reporter::new(&ReportingConfig::default(), true);
+	sync_from_sources(&dry_run_config, &mut reporter, &mut ImportThrottle::default(), None, false)
+		.instrument(spans::run_span(spans::source_name(config)))
+		.await
+		.context("Second sync pass failed")?;
+	let report = reporter.finish()?;
+
+	let mut writes = Vec::new();
+	writes.extend(report.created.iter().map(|id| format!("create of `{id}`")));
+	writes.extend(report.updated.iter().map(|id| {
+		let fields = report.changed_fields.get(id).map(|fields| fields.join(", "));
+		format!("update of `{}` (changed: {})", id, fields.unwrap_or_default())
+	}));
+	writes.extend(report.renamed.iter().map(|rename| {
+		format!("rename of `{}` to `{}`", rename.old_external_user_id, rename.new_external_user_id)
+	}));
+	writes.extend(report.deleted.iter().map(|id| format!("deletion of Zitadel user `{id}`")));
+
+	if !writes.is_empty() {
+		anyhow::bail!(
+			"Sync is not idempotent, the second pass would perform {} writes:\n{}",
+			writes.len(),
+			writes.join("\n")
+		);
+	}
+
+	tracing::info!("Sync is idempotent");
+
+	Ok(())
+}
+
+/// Get the configured CSV, LDAP, FHIR, Entra ID or Keycloak source, or
+/// the merged source of several
+fn get_source(config: &Config) -> Result<Box<dyn Source + Send + Sync>> {
+	if config.source_merge.is_some() {
+		return Ok(Box::new(MergedSource::new(config)?));
+	}
+
+	let sources = &config.sources;
+	match (&sources.csv, &sources.ldap, &sources.fhir, &sources.entra, &sources.keycloak) {
+		(Some(csv), None, None, None, None) => Ok(Box::new(CsvSource::new(csv.clone()))),
+		(None, Some(ldap), None, None, None) => Ok(Box::new(LdapSource::new(ldap.clone()))),
+		(None, None, Some(fhir), None, None) => Ok(Box::new(FhirSource::new(fhir.clone())?)),
+		(None, None, None, Some(entra), None) => Ok(Box::new(EntraSource::new(entra.clone())?)),
+		(None, None, None, None, Some(keycloak)) => {
+			Ok(Box::new(KeycloakSource::new(keycloak.clone())?))
+		}
+		_ => anyhow::bail!(
+			"Exactly one CSV, LDAP, FHIR, Entra ID or Keycloak source must be defined"
+		),
+	}
+}
+
+/// Remove data left behind by earlier syncs from all Zitadel users,
+/// as configured in [`GcConfig`](config::GcConfig)
+pub async fn perform_gc(config: &Config) -> Result<()> {
+	collect_garbage(config).instrument(spans::run_span(spans::source_name(config))).await
+}
+
+/// Remove data left behind by earlier syncs from all Zitadel users
+async fn collect_garbage(config: &Config) -> Result<()> {
+	let dry_run = config.feature_flags.is_enabled(FeatureFlag::DryRun);
+	if !dry_run {
+		user_cache::invalidate(config)?;
+	}
+
+	let mut reporter = Reporter::new(&config.reporting, dry_run)
+		.with_language(config.language)
+		.with_config_fingerprint(&config.fingerprint);
+
+	// IDP links are checked against the IDs of the source users
+	let valid_provided_user_ids: Option<HashSet<String>> = match config.gc.idp_links {
+		IdpLinkGcMode::Ignore => None,
+		IdpLinkGcMode::Report | IdpLinkGcMode::Remove => {
+			let source = get_source(config)?;
+			let read = source.get_sorted_users();
+			let users = if config.source_merge.is_some() {
+				// The merged source guards the reads of each of its sources
+				read.await
+			} else {
+				SourceGuard::new(config).read(source.get_name(), read).await
+			}
+			.context(format!("Failed to query users from {}", source.get_name()))?;
+
+			// A source read returning too few users would mark the links
+			// of all other users as stale, so it is checked like in a sync
+			let enabled_user_count = users.iter().filter(|user| user.enabled).count();
+			if enabled_user_count == 0 {
+				anyhow::bail!(Message::TooFewSourceUsers { user_count: 0, min_expected_users: 1 }
+					.render(config.language));
+			}
+			check_source_user_count(config, enabled_user_count)?;
+
+			// Skipping a user here would remove its links, so a single
+			// invalid ID fails the run
+			Some(
+				users
+					.iter()
+					.map(|user| {
+						user.get_external_id_bytes().map(get_zitadel_encoded_id).context(format!(
+							"Failed to encode the ID of user `{}`",
+							user.get_external_id()
+						))
+					})
+					.collect::<Result<_>>()?,
+			)
+		}
+	};
+	let remove_idp_links = config.gc.idp_links == IdpLinkGcMode::Remove;
+
+	let mut zitadel = Zitadel::new(config).await?;
+	zitadel.preflight().await?;
+	let mut stream = zitadel.list_users()?;
+
+	while let Some((user, zitadel_id)) = get_next_zitadel_user(&mut stream, &mut zitadel).await? {
+		let span =
+			spans::user_span(Operation::Update, Some(&user.external_user_id), Some(&zitadel_id));
+		let mut res =
+			zitadel.collect_garbage(&zitadel_id, &config.gc).instrument(span.clone()).await;
 
-			let localpart = zitadel
-				.zitadel_client
-				.get_user_metadata(&zitadel_user.1, "localpart")
+		if let Some(valid_provided_user_ids) = &valid_provided_user_ids {
+			match zitadel
+				.collect_stale_idp_links(&zitadel_id, valid_provided_user_ids, remove_idp_links)
+				.instrument(span)
 				.await
-				.ok()
-				.and_then(|metadata| metadata.metadata().value());
+			{
+				Ok(stale_links) => {
+					if remove_idp_links && !stale_links.is_empty() {
+						res = res.map(|_| true);
+					}
+					reporter.record_stale_idp_links(&zitadel_id, stale_links);
+				}
+				Err(error) => res = res.and(Err(error)),
+			}
+		}
+
+		// Only record users which had something to remove
+		if !matches!(res, Ok(false)) {
+			reporter.record(
+				Operation::Update,
+				Some(&user.external_user_id),
+				Some(&zitadel_id),
+				&res.map(|_| ()),
+			);
+		}
+	}
+
+	reporter.finish()?;
+
+	Ok(())
+}
+
+/// Move the metadata managed by the sync of all Zitadel users to the
+/// configured metadata namespace
+pub async fn migrate_metadata_namespace(config: &Config) -> Result<()> {
+	move_metadata_to_namespace(config).instrument(spans::run_span(spans::source_name(config))).await
+}
+
+/// Move the metadata managed by the sync of all Zitadel users to the
+/// configured metadata namespace
+async fn move_metadata_to_namespace(config: &Config) -> Result<()> {
+	if config.zitadel.metadata_namespace.is_none() {
+		anyhow::bail!("Migrating metadata requires `zitadel.metadata_namespace` to be set");
+	}
+
+	let dry_run = config.feature_flags.is_enabled(FeatureFlag::DryRun);
+	if !dry_run {
+		user_cache::invalidate(config)?;
+	}
+
+	let mut reporter = Reporter::new(&config.reporting, dry_run)
+		.with_language(config.language)
+		.with_config_fingerprint(&config.fingerprint);
+
+	let mut zitadel = Zitadel::new(config).await?;
+	zitadel.preflight().await?;
+	let mut stream = zitadel.list_users()?;
+
+	while let Some((user, zitadel_id)) = get_next_zitadel_user(&mut stream, &mut zitadel).await? {
+		let span =
+			spans::user_span(Operation::Update, Some(&user.external_user_id), Some(&zitadel_id));
+		let res = zitadel.migrate_metadata_namespace(&zitadel_id).instrument(span).await;
+
+		// Only record users which had something to move
+		if !matches!(res, Ok(false)) {
+			reporter.record(
+				Operation::Update,
+				Some(&user.external_user_id),
+				Some(&zitadel_id),
+				&res.map(|_| ()),
+			);
+		}
+	}
+
+	reporter.finish()?;
+
+	Ok(())
+}
+
+/// Sync the configured sources to Zitadel, or only the given changes
+/// of the source. Users unchanged in the source since the last sync
+/// are only skipped if `skip_unchanged` is set.
+async fn sync_from_sources(
+	config: &Config,
+	reporter: &mut Reporter,
+	import_throttle: &mut ImportThrottle,
+	changes: Option<SourceChanges>,
+	skip_unchanged: bool,
+) -> Result<()> {
+	if config.feature_flags.is_enabled(FeatureFlag::ShadowRun) {
+		tracing::info!(
+			"Shadow run, syncing to organization `{}` at {}",
+			config.zitadel.organization_id,
+			config.zitadel.url
+		);
+	}
+
+	watchdog::set_phase("checking Zitadel configuration");
+	Zitadel::new(config).await?.preflight().await?;
+
+	watchdog::set_phase("querying source");
+
+	let ukt = config.sources.ukt.clone().map(UktSource::new);
+
+	// The ukt source is handled specially, since it doesn't behave as
+	// the others
+	if let Some(ukt) = ukt {
+		let users = SourceGuard::new(config)
+			.read("UKT", ukt.get_removed_user_emails())
+			.await
+			.context("Failed to query users from ukt")?;
+		watchdog::set_phase("deleting users");
+		delete_users_by_email(config, users, reporter).await?;
+
+		return Ok(());
+	}
 
-			zitadel_user.0.preferred_username = preferred_username;
-			zitadel_user.0.localpart = localpart;
+	let (mut users, source_position) = read_source_users(config, changes).await?;
 
-			Ok(Some(zitadel_user))
+	let (excluded_users, persona_conflicts) = prepare_source_users(config, &mut users)?;
+	for conflict in &persona_conflicts {
+		reporter.record_persona_conflict(conflict);
+	}
+
+	let expected_user_count = users.iter().filter(|user| user.enabled).count();
+	check_source_user_count(config, expected_user_count)?;
+
+	let source_versions = SourceVersions::load(config, &users, skip_unchanged)?;
+
+	if config.feature_flags.is_enabled(FeatureFlag::DeactivateOnly) {
+		watchdog::set_phase("disabling users");
+		disable_users(config, &mut users, reporter).await?;
+	} else {
+		watchdog::set_phase("syncing users");
+		sync_users(
+			config,
+			&mut users,
+			excluded_users,
+			source_versions.as_ref(),
+			reporter,
+			import_throttle,
+		)
+		.await?;
+
+		if import_throttle.skipped() > 0 {
+			tracing::warn!(
+				"Skipped the import of {} users due to the import limit; they are imported by \
+				 later syncs",
+				import_throttle.skipped()
+			);
+		}
+		if reporter.held_back_deletions() > 0 {
+			tracing::warn!(
+				"Held back the deletion of {} users with second factors; confirm it with \
+				 `--allow-second-factor-deletions`",
+				reporter.held_back_deletions()
+			);
+		}
+		if reporter.deferred() > 0 {
+			tracing::warn!(
+				"Deferred {} changes beyond `max_changes_per_run`; they are applied by later syncs",
+				reporter.deferred()
+			);
+		}
+
+		// Zitadel only matches the sources once all changes were applied
+		let incomplete = import_throttle.skipped() > 0
+			|| reporter.held_back_deletions() > 0
+			|| reporter.deferred() > 0;
+		if incomplete {
+			tracing::info!("Skipping the user count check, since not all changes were applied");
+		} else if config.pilot.is_some() {
+			tracing::info!("Skipping the user count check in pilot mode");
+		} else if let Some(user_count_check) = &config.user_count_check {
+			watchdog::set_phase("checking user count");
+			check_user_count(config, user_count_check.tolerance, expected_user_count).await?;
 		}
-		None => Ok(None),
 	}
+
+	if let (Some(state_path), false) =
+		(&config.state_path, config.feature_flags.is_enabled(FeatureFlag::DryRun))
+	{
+		SyncState::record_source_user_count(
+			state_path,
+			&config.zitadel.organization_id,
+			expected_user_count,
+		)?;
+	}
+
+	// Only move past the changes once they were all applied, so that
+	// failed writes are retried by the next sync
+	if let (Some(position), Some(state_path)) = (source_position, &config.state_path) {
+		let organization_id = &config.zitadel.organization_id;
+		match position {
+			SourcePosition::DirSyncCookie(cookie) => {
+				if all_changes_applied(config, reporter, import_throttle, "DirSync cookie") {
+					SyncState::record_dirsync_cookie(state_path, organization_id, &cookie)?;
+				}
+			}
+			SourcePosition::IncrementalSync(marks) => {
+				if all_changes_applied(config, reporter, import_throttle, "incremental sync marks")
+				{
+					SyncState::record_incremental_sync(state_path, organization_id, marks)?;
+				}
+			}
+			SourcePosition::EntraDeltaLink(delta_link) => {
+				if all_changes_applied(config, reporter, import_throttle, "Entra ID delta link") {
+					SyncState::record_entra_delta_link(state_path, organization_id, &delta_link)?;
+				}
+			}
+		}
+	}
+
+	if let Some(source_versions) = source_versions {
+		if all_changes_applied(config, reporter, import_throttle, "source versions") {
+			source_versions.save(config)?;
+		}
+	}
+
+	Ok(())
+}
+
+/// The state of a source at the start of a sync, which the next sync
+/// reads the changes since
+enum SourcePosition {
+	/// The DirSync cookie returned by Active Directory
+	DirSyncCookie(Vec<u8>),
+	/// When the incremental and full syncs from LDAP started
+	IncrementalSync(IncrementalSyncMarks),
+	/// The delta link returned by Microsoft Graph
+	EntraDeltaLink(String),
 }
 
-/// Perform a sync operation
-pub async fn perform_sync(config: &Config) -> Result<()> {
+/// Users changed in the source, which a sync applies to the users as
+/// they currently are in Zitadel instead of reading all source users
+#[derive(Debug, Default)]
+pub(crate) struct SourceChanges {
+	/// Users which were created or changed
+	pub(crate) changed: Vec<User>,
+	/// External IDs of users which were removed
+	pub(crate) removed: Vec<String>,
+}
+
+/// Read the users of the configured CSV, LDAP, FHIR, Entra ID or
+/// Keycloak source, or of several merged, along with the state of the
+/// source to store for incremental syncs. Given changes of the source,
+/// these are applied to the Zitadel users instead.
+async fn read_source_users(
+	config: &Config,
+	changes: Option<SourceChanges>,
+) -> Result<(VecDeque<User>, Option<SourcePosition>)> {
 	/// Get users from a source
-	async fn get_users_from_source(source: impl Source + Send) -> Result<VecDeque<User>> {
-		source
-			.get_sorted_users()
+	async fn get_users_from_source(
+		guard: &SourceGuard,
+		source: impl Source + Send,
+	) -> Result<VecDeque<User>> {
+		guard
+			.read(source.get_name(), source.get_sorted_users())
 			.await
 			.map(VecDeque::from)
 			.context(format!("Failed to query users from {}", source.get_name()))
 	}
 
+	if let Some(changes) = changes {
+		let users = apply_source_changes(config, changes.changed, changes.removed).await?;
+		return Ok((users, None));
+	}
+
+	if config.source_merge.is_some() {
+		// The merged source guards the reads of each of its sources
+		let users = MergedSource::new(config)?
+			.get_sorted_users()
+			.await
+			.context("Failed to query users from merged sources")?;
+		return Ok((VecDeque::from(users), None));
+	}
+
+	let guard = SourceGuard::new(config);
+
 	let csv = config.sources.csv.clone().map(CsvSource::new);
 	let ldap = config.sources.ldap.clone().map(LdapSource::new);
-	let ukt = config.sources.ukt.clone().map(UktSource::new);
+	let fhir = config.sources.fhir.clone().map(FhirSource::new).transpose()?;
+	let entra = config.sources.entra.clone().map(EntraSource::new).transpose()?;
+	let keycloak = config.sources.keycloak.clone().map(KeycloakSource::new).transpose()?;
 
-	// The ukt source is handled specially, since it doesn't behave as
-	// the others
-	if let Some(ukt) = ukt {
-		match ukt.get_removed_user_emails().await {
-			Ok(users) => delete_users_by_email(config, users).await?,
-			Err(err) => {
-				anyhow::bail!("Failed to query users from ukt: {:?}", err);
+	match (csv, ldap, fhir, entra, keycloak) {
+		(Some(csv), None, None, None, None) => {
+			Ok((get_users_from_source(&guard, csv).await?, None))
+		}
+		(None, Some(ldap), None, None, None) => {
+			let ldap_config = config.sources.ldap.as_ref();
+			let dirsync = ldap_config.and_then(|ldap| ldap.dirsync.as_ref());
+			let incremental = ldap_config.and_then(|ldap| ldap.incremental.as_ref());
+			match (&config.state_path, dirsync, incremental) {
+				(Some(state_path), Some(_), _) => {
+					let (users, cookie) =
+						get_users_from_dirsync(config, &guard, &ldap, state_path).await?;
+					Ok((users, Some(SourcePosition::DirSyncCookie(cookie))))
+				}
+				(Some(state_path), None, Some(incremental)) => {
+					let (users, marks) = get_users_modified_since_last_sync(
+						config,
+						&guard,
+						&ldap,
+						incremental,
+						state_path,
+					)
+					.await?;
+					Ok((users, Some(SourcePosition::IncrementalSync(marks))))
+				}
+				_ => Ok((get_users_from_source(&guard, ldap).await?, None)),
 			}
 		}
+		(None, None, Some(fhir), None, None) => {
+			Ok((get_users_from_source(&guard, fhir).await?, None))
+		}
+		(None, None, None, Some(entra), None) => {
+			let delta = config.sources.entra.as_ref().is_some_and(|entra| entra.delta);
+			match (&config.state_path, delta) {
+				(Some(state_path), true) => {
+					let (users, delta_link) =
+						get_users_from_entra_delta(config, &guard, &entra, state_path).await?;
+					Ok((users, Some(SourcePosition::EntraDeltaLink(delta_link))))
+				}
+				_ => Ok((get_users_from_source(&guard, entra).await?, None)),
+			}
+		}
+		(None, None, None, None, Some(keycloak)) => {
+			Ok((get_users_from_source(&guard, keycloak).await?, None))
+		}
+		_ => anyhow::bail!("Exactly one source must be defined"),
+	}
+}
 
-		return Ok(());
+/// Whether all changes of the sync were applied, so that the state
+/// describing the source at the last sync, e.g. the DirSync cookie, may
+/// move past them. Otherwise, logs why it may not.
+fn all_changes_applied(
+	config: &Config,
+	reporter: &Reporter,
+	import_throttle: &ImportThrottle,
+	source_state: &str,
+) -> bool {
+	if config.feature_flags.is_enabled(FeatureFlag::DryRun) {
+		tracing::info!("Not storing the {} due to dry run", source_state);
+	} else if reporter.has_failures() {
+		tracing::warn!("Not storing the {}, since some changes failed to sync", source_state);
+	} else if import_throttle.skipped() > 0 {
+		tracing::info!("Not storing the {}, since some imports were skipped", source_state);
+	} else if reporter.deferred() > 0 || reporter.held_back_deletions() > 0 {
+		tracing::info!("Not storing the {}, since some changes were deferred", source_state);
+	} else {
+		return true;
+	}
+
+	false
+}
+
+/// Run the registered hooks on source users, merge their personas,
+/// fill in missing names, apply the configured rules and mark guests,
+/// dropping the excluded users and disabling expired guests
+///
+/// Returns the names of the rules excluding the dropped users, by
+/// external ID, along with the conflicts of the merged personas.
+fn prepare_source_users(
+	config: &Config,
+	users: &mut VecDeque<User>,
+) -> Result<(BTreeMap<String, String>, Vec<MergeConflict>)> {
+	// Entries which failed to parse only carry their external ID, so
+	// hooks and rules can't judge them. They must reach the sync as they
+	// are, since dropping them would delete their Zitadel counterparts.
+	if hooks::has_user_hooks() {
+		for user in users.iter_mut().filter(|user| !user.is_unparsable()) {
+			hooks::run_user_hooks(user)?;
+		}
+		// Hooks may change external IDs, which the users are sorted by
+		users.make_contiguous().sort_by(|a, b| a.external_user_id.cmp(&b.external_user_id));
 	}
 
-	let mut users = match (csv, ldap, ukt) {
-		(Some(csv), None, None) => get_users_from_source(csv).await?,
-		(None, Some(ldap), None) => get_users_from_source(ldap).await?,
-		(None, None, Some(_)) => VecDeque::new(),
-		_ => {
-			anyhow::bail!("Exactly one source must be defined");
+	let persona_conflicts = config
+		.personas
+		.as_ref()
+		.map(|personas| personas::merge_personas(personas, users))
+		.unwrap_or_default();
+
+	let now = Utc::now();
+	let mut excluded_users = BTreeMap::new();
+	users.retain_mut(|user| {
+		if user.is_unparsable() {
+			return true;
+		}
+		user.fill_missing_names(config.name_fallback.as_ref());
+		let trace = rules::apply_rules(&config.rules, user);
+		if trace.excluded {
+			let rule = trace.fired.last().cloned().unwrap_or_default();
+			tracing::debug!("Excluding user `{}` by rule `{}`", user.external_user_id, rule);
+			excluded_users.insert(user.external_user_id.clone(), rule);
+		} else if let Some(guests) = &config.guests {
+			// Rules may derive the attributes marking guests
+			guests.apply(user, now);
 		}
+		!trace.excluded
+	});
+	Ok((excluded_users, persona_conflicts))
+}
+
+/// Get the users of an LDAP source using DirSync, along with the
+/// DirSync cookie describing the state of the source
+///
+/// Only the users changed since the last sync are read from LDAP, and
+/// applied to the users as they currently are in Zitadel. Unchanged
+/// users therefore compare equal to their Zitadel counterparts and
+/// aren't touched. Without a cookie from an earlier sync, all users
+/// are read from LDAP.
+async fn get_users_from_dirsync(
+	config: &Config,
+	guard: &SourceGuard,
+	ldap: &LdapSource,
+	state_path: &Path,
+) -> Result<(VecDeque<User>, Vec<u8>)> {
+	let Some(cookie) = SyncState::load_dirsync_cookie(state_path, &config.zitadel.organization_id)?
+	else {
+		tracing::info!("No DirSync cookie stored yet, reading all users from LDAP");
+		// Get the cookie first, so that changes made while reading the
+		// users are picked up by the next sync
+		let cookie = guard
+			.read(ldap.get_name(), ldap.get_dirsync_cookie())
+			.await
+			.context("Failed to get DirSync cookie")?;
+		let users = guard
+			.read(ldap.get_name(), ldap.get_sorted_users())
+			.await
+			.context("Failed to query users from LDAP")?;
+		return Ok((VecDeque::from(users), cookie));
 	};
 
-	if config.feature_flags.is_enabled(FeatureFlag::DeactivateOnly) {
-		disable_users(config, &mut users).await?;
-	} else {
-		sync_users(config, &mut users).await?;
+	let (changes, cookie) = guard
+		.read(ldap.get_name(), ldap.get_dirsync_changes(cookie))
+		.await
+		.context("Failed to query changes from LDAP")?;
+	let users = apply_source_changes(config, changes.changed, changes.removed).await?;
+
+	Ok((users, cookie))
+}
+
+/// Get the users of an Entra ID source using delta queries, along with
+/// the delta link to read the next changes from
+///
+/// As with DirSync, only the users changed since the last sync are read
+/// and applied to the users as they currently are in Zitadel. Without a
+/// delta link from an earlier sync, all users are read, starting a new
+/// round of delta queries.
+async fn get_users_from_entra_delta(
+	config: &Config,
+	guard: &SourceGuard,
+	entra: &EntraSource,
+	state_path: &Path,
+) -> Result<(VecDeque<User>, String)> {
+	let Some(delta_link) =
+		SyncState::load_entra_delta_link(state_path, &config.zitadel.organization_id)?
+	else {
+		tracing::info!("No Entra ID delta link stored yet, reading all users from Entra ID");
+		let (users, delta_link) = guard
+			.read(entra.get_name(), entra.get_users_with_delta_link())
+			.await
+			.context("Failed to query users from Entra ID")?;
+		return Ok((VecDeque::from(users), delta_link));
+	};
+
+	let (changes, delta_link) = guard
+		.read(entra.get_name(), entra.get_delta_changes(&delta_link))
+		.await
+		.context("Failed to query changes from Entra ID")?;
+	let users = apply_source_changes(config, changes.changed, changes.removed).await?;
+
+	Ok((users, delta_link))
+}
+
+/// Get the users of an LDAP source modified since the last sync, along
+/// with the incremental sync marks to store once they were synced
+///
+/// As with DirSync, the modified users are applied to the users as they
+/// currently are in Zitadel. Users deleted from LDAP leave no
+/// modification behind, so all users are read from LDAP if no sync
+/// happened yet or the last full sync is older than configured.
+async fn get_users_modified_since_last_sync(
+	config: &Config,
+	guard: &SourceGuard,
+	ldap: &LdapSource,
+	incremental: &IncrementalSyncConfig,
+	state_path: &Path,
+) -> Result<(VecDeque<User>, IncrementalSyncMarks)> {
+	// Take the time before reading, so that changes made while reading
+	// the users are picked up by the next sync
+	let now = Utc::now();
+	let marks = SyncState::load_incremental_sync(state_path, &config.zitadel.organization_id)?;
+
+	let modified_since = marks
+		.as_ref()
+		.map(|marks| {
+			let synced_at = DateTime::parse_from_rfc3339(&marks.synced_at)
+				.context("Invalid incremental sync timestamp")?;
+			let full_synced_at = DateTime::parse_from_rfc3339(&marks.full_synced_at)
+				.context("Invalid incremental sync timestamp")?;
+			anyhow::Ok(incremental.modified_since(
+				synced_at.with_timezone(&Utc),
+				full_synced_at.with_timezone(&Utc),
+				now,
+			))
+		})
+		.transpose()?
+		.flatten();
+
+	let Some((since, marks)) = modified_since.zip(marks) else {
+		tracing::info!("Running a full sync, reading all users from LDAP");
+		let users = guard
+			.read(ldap.get_name(), ldap.get_sorted_users())
+			.await
+			.context("Failed to query users from LDAP")?;
+		let marks =
+			IncrementalSyncMarks { synced_at: now.to_rfc3339(), full_synced_at: now.to_rfc3339() };
+		return Ok((VecDeque::from(users), marks));
+	};
+
+	let changed = guard
+		.read(ldap.get_name(), ldap.get_users_modified_since(since))
+		.await
+		.context("Failed to query modified users from LDAP")?;
+	let users = apply_source_changes(config, changed, Vec::new()).await?;
+
+	Ok((users, IncrementalSyncMarks { synced_at: now.to_rfc3339(), ..marks }))
+}
+
+/// Apply the users changed and removed in the source since the last
+/// sync to the users as they currently are in Zitadel, so that
+/// unchanged users compare equal to their Zitadel counterparts
+async fn apply_source_changes(
+	config: &Config,
+	changed: Vec<User>,
+	removed: Vec<String>,
+) -> Result<VecDeque<User>> {
+	let mut zitadel = Zitadel::new(config).await?;
+	let mut stream = zitadel.list_users()?;
+	let mut users = BTreeMap::new();
+	while let Some((user, _)) = get_next_zitadel_user(&mut stream, &mut zitadel).await? {
+		users.insert(user.external_user_id.clone(), user);
+	}
+
+	for external_user_id in removed {
+		users.remove(&external_user_id);
+	}
+	for user in changed {
+		users.insert(user.external_user_id.clone(), user);
+	}
+
+	Ok(users.into_values().collect())
+}
+
+/// Abort if the source returned implausibly few enabled users, which
+/// would otherwise lead to mass deletions
+fn check_source_user_count(config: &Config, user_count: usize) -> Result<()> {
+	let Some(check) = &config.source_user_count_check else {
+		return Ok(());
+	};
+
+	let last_user_count = match &config.state_path {
+		Some(state_path) => {
+			SyncState::load_source_user_count(state_path, &config.zitadel.organization_id)?
+		}
+		None => None,
+	};
+
+	if let Some(message) = check.check(user_count, last_user_count) {
+		anyhow::bail!(message.render(config.language));
+	}
+
+	tracing::info!("Source user count check passed: {} enabled users", user_count);
+
+	Ok(())
+}
+
+/// Abort before any users are deleted if a sync would delete more users
+/// than `deletion_guard` allows, given the number of managed Zitadel
+/// users
+fn check_deletions(
+	config: &Config,
+	deletions: &[(User, String)],
+	zitadel_user_count: usize,
+) -> Result<()> {
+	let Some(guard) = &config.deletion_guard else {
+		return Ok(());
+	};
+
+	// Users already being deprovisioned aren't deleted again
+	let deletions =
+		deletions.iter().filter(|(user, _)| !is_being_deprovisioned(config, user)).count();
+
+	if let Some(message) = guard.check(deletions, zitadel_user_count) {
+		anyhow::bail!(message.render(config.language));
+	}
+
+	Ok(())
+}
+
+/// Assert that the number of users in Zitadel matches the number of
+/// enabled source users after a sync, within the given tolerance
+async fn check_user_count(
+	config: &Config,
+	tolerance: usize,
+	expected_user_count: usize,
+) -> Result<()> {
+	if config.feature_flags.is_enabled(FeatureFlag::DryRun) {
+		tracing::info!("Skipping user count check due to dry run");
+		return Ok(());
+	}
+
+	// Users which should have been deleted are still in Zitadel
+	if config.feature_flags.is_enabled(FeatureFlag::DryRunDeletions) {
+		tracing::info!("Skipping user count check due to deletion dry run");
+		return Ok(());
+	}
+
+	let mut zitadel = Zitadel::new(config).await?;
+	let actual_user_count = zitadel.count_users().await?;
+	compare_user_counts(expected_user_count, actual_user_count, tolerance)
+		.map_err(|message| anyhow::anyhow!(message.render(config.language)))?;
+
+	tracing::info!(
+		"User count check passed: expected {} users, found {}",
+		expected_user_count,
+		actual_user_count
+	);
+
+	Ok(())
+}
+
+/// Compare the number of users in Zitadel with the expected number
+fn compare_user_counts(
+	expected_user_count: usize,
+	actual_user_count: usize,
+	tolerance: usize,
+) -> Result<(), Message> {
+	if expected_user_count.abs_diff(actual_user_count) > tolerance {
+		return Err(Message::UserCountMismatch {
+			expected: expected_user_count,
+			actual: actual_user_count,
+			tolerance,
+		});
 	}
 
 	Ok(())
 }
 
 /// Delete a list of users given their email addresses
-async fn delete_users_by_email(config: &Config, emails: Vec<String>) -> Result<()> {
+async fn delete_users_by_email(
+	config: &Config,
+	emails: Vec<String>,
+	reporter: &mut Reporter,
+) -> Result<()> {
 	let mut zitadel = Zitadel::new(config).await?;
+	let mut deletions = Vec::new();
 	let mut stream = zitadel.get_users_by_email(emails)?;
+	while let Some(user) = get_next_zitadel_user(&mut stream, &mut zitadel).await? {
+		deletions.push(user);
+	}
 
-	while let Some(zitadel_user) = get_next_zitadel_user(&mut stream, &mut zitadel).await? {
-		zitadel.delete_user(&zitadel_user.1).await?;
+	if config.deletion_guard.is_some() {
+		let zitadel_user_count = zitadel.count_users().await?;
+		check_deletions(config, &deletions, zitadel_user_count)?;
 	}
 
+	for (user, zitadel_id) in deletions {
+		let res = zitadel.delete_user(&zitadel_id).await;
+		reporter.record_deletion(
+			Some(&user.external_user_id),
+			&zitadel_id,
+			DeletionReason::RemovedInUkt,
+			&res,
+		);
+		if res.is_ok() {
+			reporter.record_listed_deletion(&user, &zitadel_id);
+		}
+		res?;
+	}
+
+	zitadel.wait_for_projections().await;
+	reporter.record_users_without_email(zitadel.take_users_without_email());
+
 	Ok(())
 }
 
 /// Only disable users
-async fn disable_users(config: &Config, users: &mut VecDeque<User>) -> Result<()> {
+async fn disable_users(
+	config: &Config,
+	users: &mut VecDeque<User>,
+	reporter: &mut Reporter,
+) -> Result<()> {
 	// We only care about disabled users for this flow
 	users.retain(|user| !user.enabled);
 
 	let mut zitadel = Zitadel::new(config).await?;
+	let mut deletions = Vec::new();
+	let mut zitadel_user_count = 0;
 	let mut stream = zitadel.list_users()?;
 
 	while let Some(zitadel_user) = get_next_zitadel_user(&mut stream, &mut zitadel).await? {
+		zitadel_user_count += 1;
 		if users.front().map(|user| user.external_user_id.clone())
-			== Some(zitadel_user.0.external_user_id)
+			== Some(zitadel_user.0.external_user_id.clone())
 		{
-			zitadel.delete_user(&zitadel_user.1).await?;
+			deletions.push(zitadel_user);
 			users.pop_front();
 		}
 	}
 
+	check_deletions(config, &deletions, zitadel_user_count)?;
+
+	for (user, zitadel_id) in deletions {
+		let res = zitadel.delete_user(&zitadel_id).await;
+		reporter.record_deletion(
+			Some(&user.external_user_id),
+			&zitadel_id,
+			DeletionReason::DisabledInSource,
+			&res,
+		);
+		if res.is_ok() {
+			reporter.record_listed_deletion(&user, &zitadel_id);
+		}
+		res?;
+	}
+
+	zitadel.wait_for_projections().await;
+	reporter.record_users_without_email(zitadel.take_users_without_email());
+
 	Ok(())
 }
 
-/// Fully sync users
-async fn sync_users(config: &Config, sync_users: &mut VecDeque<User>) -> Result<()> {
+/// Imports and deletions found while comparing the users, which are
+/// only executed once all users were compared
+#[derive(Default)]
+struct PendingChanges {
+	/// Zitadel users whose external ID changed, detected among the
+	/// imports and deletions
+	renames: Vec<Rename>,
+	/// Source users not found in Zitadel
+	imports: Vec<User>,
+	/// Zitadel users not found in the source, along with their
+	/// Zitadel IDs
+	deletions: Vec<(User, String)>,
+}
+
+impl PendingChanges {
+	/// Move the imports and deletions of users whose external ID
+	/// changed to the renames, if rename detection is configured
+	fn detect_renames(self, config: &Config) -> Self {
+		let Some(rename_detection) = &config.rename_detection else {
+			return self;
+		};
+
+		let (renames, imports, deletions) =
+			rename::detect_renames(rename_detection, self.imports, self.deletions);
+		let mut all_renames = self.renames;
+		all_renames.extend(renames);
+		Self { renames: all_renames, imports, deletions }
+	}
+}
+
+/// An update found while comparing the users, which is sent to Zitadel
+/// along with the next ones
+struct PendingUpdate {
+	/// The Zitadel ID of the user
+	zitadel_id: String,
+	/// The user as it is in Zitadel
+	existing_user: User,
+	/// The user as it is in the source
+	new_user: User,
+	/// The source values to remember for drift classification once the
+	/// user is updated
+	drift_values: Option<BTreeMap<String, String>>,
+}
+
+/// What the source says about the users it doesn't provide, which
+/// explains their deletion
+#[derive(Default)]
+struct DeletionEvidence {
+	/// External IDs of the users disabled in the source
+	disabled: HashSet<String>,
+	/// External IDs of the guests whose access expired
+	expired_guests: HashSet<String>,
+	/// The names of the rules excluding users, by external ID
+	excluded: BTreeMap<String, String>,
+}
+
+impl DeletionEvidence {
+	/// Why the Zitadel user with the given external ID is deleted
+	fn reason(&self, external_user_id: &str) -> DeletionReason {
+		if let Some(rule) = self.excluded.get(external_user_id) {
+			DeletionReason::ExcludedByRule { rule: rule.clone() }
+		} else if self.expired_guests.contains(external_user_id) {
+			DeletionReason::GuestExpired
+		} else if self.disabled.contains(external_user_id) {
+			DeletionReason::DisabledInSource
+		} else {
+			DeletionReason::NotInSource
+		}
+	}
+}
+
+/// Import users into Zitadel, up to the given number at once,
+/// recording the outcomes
+///
+/// Users are admitted by the import throttle one at a time, so that its
+/// pauses still separate the imports before and after them.
+async fn import_users(
+	config: &Config,
+	zitadel: &Zitadel,
+	reporter: &mut Reporter,
+	import_throttle: &mut ImportThrottle,
+	change_budget: &mut ChangeBudget,
+	imports: Vec<User>,
+) {
+	let pilot = config.pilot.as_ref();
+	let concurrency = config.zitadel.write_concurrency.imports;
+
+	let imports: Vec<_> = imports
+		.into_iter()
+		.filter(|new_user| {
+			let included = pilot::includes(pilot, new_user);
+			if !included {
+				reporter.record_pilot_drift(
+					Operation::Create,
+					Some(&new_user.external_user_id),
+					None,
+					Vec::new(),
+				);
+			}
+			included
+		})
+		.collect();
+	let mut imports = imports.into_iter().peekable();
+
+	let mut chunk: Vec<User> = Vec::new();
+	loop {
+		chunk.clear();
+		while chunk.len() < concurrency.max(1) {
+			// Imports of the same user go to separate chunks, so that they
+			// never run at once
+			let Some(new_user) = imports.next_if(|new_user| {
+				chunk.iter().all(|user| user.external_user_id != new_user.external_user_id)
+			}) else {
+				break;
+			};
+			if change_budget.exhausted() {
+				reporter.record_deferred(Operation::Create, Some(&new_user.external_user_id), None);
+				continue;
+			}
+			if import_throttle.admit(&new_user.external_user_id).await {
+				change_budget.spend();
+				chunk.push(new_user);
+			}
+		}
+		if chunk.is_empty() {
+			break;
+		}
+
+		let results = futures::future::join_all(chunk.iter().map(|new_user| {
+			let mut zitadel = zitadel.clone();
+			let span = spans::user_span(Operation::Create, Some(&new_user.external_user_id), None);
+			async move {
+				let (skipped, res) =
+					import_user(config, &mut zitadel, new_user).instrument(span.clone()).await;
+				if let Err(error) = &res {
+					span.in_scope(|| {
+						tracing::error!(
+							"Failed to import user `{}`: {}",
+							new_user.external_user_id,
+							error
+						);
+					});
+				}
+				(skipped, res)
+			}
+		}))
+		.await;
+
+		for (new_user, (skipped, res)) in chunk.iter().zip(results) {
+			match skipped {
+				Some(SkippedImport::Unmanaged(zitadel_id)) => {
+					reporter.record_unmanaged_user(&new_user.external_user_id, &zitadel_id);
+				}
+				Some(SkippedImport::EmailConflict { zitadel_id, external_user_id }) => {
+					reporter.record_email_conflict(
+						&new_user.external_user_id,
+						&zitadel_id,
+						&external_user_id,
+					);
+				}
+				Some(SkippedImport::CrossOrgConflict { zitadel_id, organization_id }) => {
+					reporter.record_cross_org_conflict(
+						&new_user.external_user_id,
+						&zitadel_id,
+						&organization_id,
+					);
+				}
+				Some(SkippedImport::Collision(collision)) => {
+					reporter.record_import_collision(
+						&new_user.external_user_id,
+						collision.key,
+						&collision.zitadel_id,
+						collision.external_user_id.as_deref(),
+					);
+				}
+				None => {
+					reporter.record(
+						Operation::Create,
+						Some(&new_user.external_user_id),
+						None,
+						&res,
+					);
+				}
+			}
+		}
+	}
+}
+
+/// Update users in Zitadel at once, recording the outcomes
+async fn update_users(
+	zitadel: &Zitadel,
+	reporter: &mut Reporter,
+	mut drift_tracker: Option<&mut DriftTracker>,
+	updates: Vec<PendingUpdate>,
+) {
+	// Updates of the same user never run at once
+	let size = updates.len();
+	for batch in write_batches::partition(updates, size, |update| update.zitadel_id.clone()) {
+		let results = futures::future::join_all(batch.iter().map(|update| {
+			let mut zitadel = zitadel.clone();
+			let span = spans::user_span(
+				Operation::Update,
+				Some(&update.new_user.external_user_id),
+				Some(&update.zitadel_id),
+			);
+			async move {
+				let res = zitadel
+					.update_user(&update.zitadel_id, &update.existing_user, &update.new_user)
+					.instrument(span.clone())
+					.await;
+				if let Err(error) = &res {
+					span.in_scope(|| {
+						tracing::error!(
+							"Failed to update user `{}`: {}",
+							update.new_user.external_user_id,
+							error
+						);
+					});
+				}
+				res
+			}
+		}))
+		.await;
+
+		for (update, res) in batch.into_iter().zip(results) {
+			reporter.record_update(
+				&update.new_user.external_user_id,
+				&update.zitadel_id,
+				update
+					.existing_user
+					.diff(&update.new_user)
+					.into_iter()
+					.map(|(field, _, _)| field)
+					.collect(),
+				&res,
+			);
+			if let (Ok(()), Some(tracker), Some(values)) =
+				(&res, &mut drift_tracker, update.drift_values)
+			{
+				tracker.record(&update.new_user.external_user_id, values);
+			}
+		}
+	}
+}
+
+/// Why the import of a user was skipped
+#[derive(Debug)]
+enum SkippedImport {
+	/// The user already exists in Zitadel with the given Zitadel ID,
+	/// but isn't managed by the sync
+	Unmanaged(String),
+	/// The email address of the user is still bound to another Zitadel
+	/// user
+	EmailConflict {
+		/// The Zitadel ID of the user holding the email address
+		zitadel_id: String,
+		/// The external ID of the user holding the email address
+		external_user_id: String,
+	},
+	/// The email address of the user belongs to a user of another
+	/// Zitadel organization
+	CrossOrgConflict {
+		/// The Zitadel ID of the user holding the email address
+		zitadel_id: String,
+		/// The organization of the user holding the email address
+		organization_id: String,
+	},
+	/// A unique attribute of the user is already taken by another
+	/// Zitadel user
+	Collision(UniqueAttributeTaken),
+}
+
+/// Import a user, unless it already exists in Zitadel without being
+/// managed by the sync, its email address is bound to another user
+/// while users are identified strictly by external ID, it belongs to a
+/// user of another organization, or Zitadel rejects it since one of its
+/// unique attributes is already taken
+///
+/// Returns why the import is skipped, if it is, along with the outcome
+/// of the import.
+async fn import_user(
+	config: &Config,
+	zitadel: &mut Zitadel,
+	new_user: &User,
+) -> (Option<SkippedImport>, Result<()>) {
+	let policy = config.zitadel.unmanaged_users;
+	if policy != UnmanagedUserPolicy::Import {
+		match zitadel.find_unmanaged_user(new_user).await {
+			Ok(Some(zitadel_id)) if policy == UnmanagedUserPolicy::Report => {
+				return (Some(SkippedImport::Unmanaged(zitadel_id)), Ok(()));
+			}
+			Ok(Some(zitadel_id)) => {
+				return (
+					None,
+					Err(anyhow::anyhow!(
+						"User already exists in Zitadel as `{}`, but isn't managed by the sync",
+						zitadel_id
+					)),
+				);
+			}
+			Ok(None) => {}
+			Err(error) => return (None, Err(error)),
+		}
+	}
+
+	// The email address of a departed user may have been reused for the
+	// new user, whose import must not take over the old account
+	if config.identity == IdentityPolicy::ExternalIdOnly {
+		match zitadel.find_email_holder(new_user).await {
+			Ok(Some((zitadel_id, external_user_id))) => {
+				return (
+					Some(SkippedImport::EmailConflict { zitadel_id, external_user_id }),
+					Ok(()),
+				);
+			}
+			Ok(None) => {}
+			Err(error) => return (None, Err(error)),
+		}
+	}
+
+	// Logins break if the email address has an account in another
+	// organization as well
+	if config.zitadel.cross_org_email_check {
+		match zitadel.find_cross_org_email_holder(new_user).await {
+			Ok(Some((zitadel_id, organization_id))) => {
+				return (
+					Some(SkippedImport::CrossOrgConflict { zitadel_id, organization_id }),
+					Ok(()),
+				);
+			}
+			Ok(None) => {}
+			Err(error) => return (None, Err(error)),
+		}
+	}
+
+	match zitadel.import_user(new_user).await {
+		Err(error) => match error.downcast::<UniqueAttributeTaken>() {
+			Ok(collision) => (Some(SkippedImport::Collision(collision)), Ok(())),
+			Err(error) => (None, Err(error)),
+		},
+		Ok(()) => (None, Ok(())),
+	}
+}
+
+/// Delete users from Zitadel, up to the given number at once,
+/// recording the outcomes
+async fn delete_users(
+	config: &Config,
+	zitadel: &Zitadel,
+	reporter: &mut Reporter,
+	change_budget: &mut ChangeBudget,
+	deletions: Vec<(User, String)>,
+	evidence: &DeletionEvidence,
+) {
+	let pilot = config.pilot.as_ref();
+	let concurrency = config.zitadel.write_concurrency.deletions;
+
+	let mut deletions: Vec<_> = deletions
+		.into_iter()
+		.filter(|(existing_user, zitadel_id)| {
+			if is_being_deprovisioned(config, existing_user) {
+				tracing::debug!("User `{}` is already being deprovisioned", zitadel_id);
+				return false;
+			}
+
+			let included = pilot::includes(pilot, existing_user);
+			if !included {
+				reporter.record_pilot_drift(
+					Operation::Delete,
+					Some(&existing_user.external_user_id),
+					Some(zitadel_id),
+					Vec::new(),
+				);
+			}
+			included
+		})
+		.collect();
+
+	// Users pending deprovisioning keep their second factors
+	if config.zitadel.deprovisioning == DeprovisioningPolicy::Delete {
+		deletions = second_factors::protect_deletions(
+			&config.second_factor_protection,
+			zitadel,
+			reporter,
+			concurrency,
+			deletions,
+		)
+		.await;
+	}
+
+	let deletions: Vec<_> = deletions
+		.into_iter()
+		.filter(|(existing_user, zitadel_id)| {
+			if !change_budget.admit() {
+				reporter.record_deferred(
+					Operation::Delete,
+					Some(&existing_user.external_user_id),
+					Some(zitadel_id),
+				);
+				return false;
+			}
+
+			true
+		})
+		.collect();
+
+	// Deletions of the same user never run at once
+	for chunk in
+		write_batches::partition(deletions, concurrency, |(_, zitadel_id)| zitadel_id.clone())
+	{
+		let results = futures::future::join_all(chunk.iter().map(|(existing_user, zitadel_id)| {
+			let mut zitadel = zitadel.clone();
+			let span = spans::user_span(
+				Operation::Delete,
+				Some(&existing_user.external_user_id),
+				Some(zitadel_id),
+			);
+			async move {
+				let res = zitadel.delete_user(zitadel_id).instrument(span.clone()).await;
+				if let Err(error) = &res {
+					span.in_scope(|| {
+						tracing::error!(
+							"Failed to delete user with Zitadel ID `{}`: {}",
+							zitadel_id,
+							error
+						);
+					});
+				}
+				res
+			}
+		}))
+		.await;
+
+		for ((existing_user, zitadel_id), res) in chunk.iter().zip(results) {
+			reporter.record_deletion(
+				Some(&existing_user.external_user_id),
+				zitadel_id,
+				evidence.reason(&existing_user.external_user_id),
+				&res,
+			);
+			if res.is_ok() {
+				reporter.record_listed_deletion(existing_user, zitadel_id);
+			}
+		}
+	}
+}
+
+/// Whether a Zitadel user missing from the source is kept for now,
+/// since it is pending deprovisioning, or deactivated and within its
+/// grace period, which may be shorter for guests
+fn is_being_deprovisioned(config: &Config, user: &User) -> bool {
+	user.metadata.contains_key(PENDING_DEPROVISIONING_KEY)
+		|| user.metadata.get(DEACTIVATED_AT_KEY).is_some_and(|deactivated_at| {
+			!guests::deactivation_expired(config, user, deactivated_at, Utc::now())
+		})
+}
+
+/// Rename the users whose external ID changed instead of re-creating
+/// them
+async fn rename_users(
+	zitadel: &mut Zitadel,
+	reporter: &mut Reporter,
+	change_budget: &mut ChangeBudget,
+	pilot: Option<&PilotConfig>,
+	renames: Vec<Rename>,
+) {
+	for rename in renames {
+		if !pilot::includes(pilot, &rename.new_user) {
+			reporter.record_pilot_drift(
+				Operation::Rename,
+				Some(&rename.new_user.external_user_id),
+				Some(&rename.zitadel_id),
+				vec!["external_user_id".to_owned()],
+			);
+			continue;
+		}
+
+		if !change_budget.admit() {
+			reporter.record_deferred(
+				Operation::Rename,
+				Some(&rename.new_user.external_user_id),
+				Some(&rename.zitadel_id),
+			);
+			continue;
+		}
+
+		let span = spans::user_span(
+			Operation::Rename,
+			Some(&rename.new_user.external_user_id),
+			Some(&rename.zitadel_id),
+		);
+		let res = zitadel
+			.rename_user(&rename.zitadel_id, &rename.old_user, &rename.new_user)
+			.instrument(span.clone())
+			.await;
+		reporter.record_rename(
+			&rename.zitadel_id,
+			&rename.old_user.external_user_id,
+			&rename.new_user.external_user_id,
+			&res,
+		);
+		if let Err(error) = res {
+			span.in_scope(|| {
+				tracing::error!("Failed to rename user `{}`: {}", rename.zitadel_id, error);
+			});
+		}
+	}
+}
+
+/// Execute the renames, imports and deletions found while comparing the
+/// users
+async fn apply_pending_changes(
+	config: &Config,
+	zitadel: &mut Zitadel,
+	reporter: &mut Reporter,
+	import_throttle: &mut ImportThrottle,
+	change_budget: &mut ChangeBudget,
+	pending: PendingChanges,
+	evidence: &DeletionEvidence,
+) {
+	let pilot = config.pilot.as_ref();
+	let PendingChanges { renames, imports, deletions } = pending;
+
+	rename_users(zitadel, reporter, change_budget, pilot, renames).await;
+
+	// Delete first, so that the email addresses of deleted users are
+	// free to be used by imported ones
+	delete_users(config, zitadel, reporter, change_budget, deletions, evidence).await;
+	import_users(config, zitadel, reporter, import_throttle, change_budget, imports).await;
+}
+
+/// Fully sync users, given the rules excluding source users which were
+/// dropped, by external ID
+async fn sync_users(
+	config: &Config,
+	sync_users: &mut VecDeque<User>,
+	excluded_users: BTreeMap<String, String>,
+	source_versions: Option<&SourceVersions>,
+	reporter: &mut Reporter,
+	import_throttle: &mut ImportThrottle,
+) -> Result<()> {
+	let now = Utc::now();
+	let evidence = DeletionEvidence {
+		disabled: sync_users
+			.iter()
+			.filter(|user| !user.enabled)
+			.map(|user| user.external_user_id.clone())
+			.collect(),
+		expired_guests: sync_users
+			.iter()
+			.filter(|user| !user.enabled && guests::is_expired(user, now))
+			.map(|user| user.external_user_id.clone())
+			.collect(),
+		excluded: excluded_users,
+	};
+
 	// Treat any disabled users as deleted, so we simply pretend they
 	// are not in the list
 	sync_users.retain(|user| user.enabled);
+	let source_user_count = sync_users.len();
+	let pilot = config.pilot.as_ref();
+	let self_service_changes = match &config.state_path {
+		Some(state_path) => {
+			SyncState::load_self_service_changes(state_path, &config.zitadel.organization_id)?
+		}
+		None => BTreeMap::new(),
+	};
+
+	let mut drift_tracker = DriftTracker::load(config)?;
 
 	let mut zitadel = Zitadel::new(config).await?;
 	let mut stream = zitadel.list_users()?;
+	latency::start();
+
+	// Imports and deletions are only executed once all users were
+	// compared, so that they can be sent concurrently and renamed users
+	// can be detected. Updates are independent of each other, so they
+	// are sent in batches while comparing.
+	let mut pending = PendingChanges::default();
+	let mut pending_updates = Vec::new();
+	let update_concurrency = config.zitadel.write_concurrency.updates.max(1);
+	let mut change_budget = ChangeBudget::new(config.max_changes_per_run);
 
 	let mut source_user = sync_users.pop_front();
-	let mut zitadel_user = get_next_zitadel_user(&mut stream, &mut zitadel).await?;
+	let mut zitadel_user =
+		get_next_compared_zitadel_user(&mut stream, &mut zitadel, source_versions).await?;
 
 	loop {
+		// The source values of the current user, to remember for drift
+		// classification once the user is synced
+		let mut drift_values = None;
+		// Users unchanged in the source since the last sync count as
+		// synced, without comparing them
+		if let (Some(new_user), Some((existing_user, _))) = (&source_user, &zitadel_user) {
+			if new_user.external_user_id == existing_user.external_user_id
+				&& source_versions
+					.is_some_and(|versions| versions.is_unchanged(&new_user.external_user_id))
+			{
+				if let Some(tracker) = &mut drift_tracker {
+					tracker.keep(&new_user.external_user_id);
+				}
+
+				zitadel_user =
+					get_next_compared_zitadel_user(&mut stream, &mut zitadel, source_versions)
+						.await?;
+				source_user = sync_users.pop_front();
+				continue;
+			}
+		}
+
+		if let (Some(new_user), Some((existing_user, zitadel_id))) =
+			(&mut source_user, &zitadel_user)
+		{
+			if new_user.external_user_id == existing_user.external_user_id
+				&& new_user.unreadable.is_none()
+			{
+				if existing_user.preferred_username_unknown
+					|| (new_user.preferred_username.is_none() && !config.syncs_preferred_username())
+				{
+					new_user.preferred_username.clone_from(&existing_user.preferred_username);
+				}
+				normalization::reconcile(&config.comparison, new_user, existing_user);
+				self_service::reconcile(
+					config,
+					&self_service_changes,
+					new_user,
+					existing_user,
+					zitadel_id,
+					reporter,
+				);
+				drift_values = drift_tracker
+					.as_mut()
+					.map(|tracker| tracker.apply(new_user, existing_user, zitadel_id, reporter));
+			}
+		}
+
 		tracing::debug!("Comparing users {:?} and {:?}", source_user, zitadel_user);
 
 		match (source_user.clone(), zitadel_user.clone()) {
 			(None, None) => {
+				update_users(
+					&zitadel,
+					reporter,
+					drift_tracker.as_mut(),
+					std::mem::take(&mut pending_updates),
+				)
+				.await;
+				// Renamed users are neither deleted nor imported
+				let pending = std::mem::take(&mut pending).detect_renames(config);
+				// Every source user is either found in Zitadel or
+				// imported, and every other Zitadel user is deleted
+				let zitadel_user_count = source_user_count.saturating_sub(pending.imports.len())
+					+ pending.deletions.len();
+				check_deletions(config, &pending.deletions, zitadel_user_count)?;
+				apply_pending_changes(
+					config,
+					&mut zitadel,
+					reporter,
+					import_throttle,
+					&mut change_budget,
+					pending,
+					&evidence,
+				)
+				.await;
+
+				if let Some(tracker) = drift_tracker.take() {
+					tracker.save(config)?;
+				}
+
+				zitadel.wait_for_projections().await;
 				tracing::info!("Sync completed successfully");
+				reporter.record_users_without_email(zitadel.take_users_without_email());
+				reporter.record_latencies(latency::finish());
 				break;
 			}
 
 			// Excess Zitadel users are not present in the sync
 			// source, so we delete them
-			(None, Some((_, zitadel_id))) => {
-				let res = zitadel.delete_user(&zitadel_id).await;
-				if let Err(error) = res {
-					tracing::error!(
-						"Failed to delete user with Zitadel ID `{}`: {}",
-						zitadel_id,
-						error
-					);
+			(None, Some((existing_user, zitadel_id))) => {
+				pending.deletions.push((existing_user, zitadel_id));
+
+				zitadel_user =
+					get_next_compared_zitadel_user(&mut stream, &mut zitadel, source_versions)
+						.await?;
+			}
+
+			// Users lacking a required attribute or whose entries failed
+			// to parse are skipped, and their Zitadel counterparts are
+			// kept as they are rather than deleted
+			(Some(new_user), Some((existing_user, zitadel_id)))
+				if new_user.unreadable.is_some()
+					&& new_user.external_user_id == existing_user.external_user_id =>
+			{
+				reporter.record_unreadable(&new_user, Some(&zitadel_id));
+				if let Some(tracker) = &mut drift_tracker {
+					tracker.keep(&new_user.external_user_id);
 				}
 
-				zitadel_user = get_next_zitadel_user(&mut stream, &mut zitadel).await?;
+				zitadel_user =
+					get_next_compared_zitadel_user(&mut stream, &mut zitadel, source_versions)
+						.await?;
+				source_user = sync_users.pop_front();
+			}
+			(Some(new_user), next_zitadel_user)
+				if new_user.unreadable.is_some()
+					&& next_zitadel_user.as_ref().map_or(true, |(existing_user, _)| {
+						new_user.external_user_id < existing_user.external_user_id
+					}) =>
+			{
+				reporter.record_unreadable(&new_user, None);
+
+				source_user = sync_users.pop_front();
+				// Don't fetch the next zitadel user yet
 			}
 
 			// Excess sync source users are not yet in Zitadel, so
 			// we import them
 			(Some(new_user), None) => {
-				let res = zitadel.import_user(&new_user).await;
-				if let Err(error) = res {
-					tracing::error!(
-						"Failed to import user `{}`: {}",
-						new_user.external_user_id,
-						error
-					);
-				}
+				pending.imports.push(new_user);
 
 				source_user = sync_users.pop_front();
 			}
@@ -182,7 +1962,13 @@ async fn sync_users(config: &Config, sync_users: &mut VecDeque<User>) -> Result<
 			// If the sync source user matches the Zitadel user, the
 			// user is already synced and we can move on
 			(Some(new_user), Some((existing_user, _))) if new_user == existing_user => {
-				zitadel_user = get_next_zitadel_user(&mut stream, &mut zitadel).await?;
+				if let (Some(tracker), Some(values)) = (&mut drift_tracker, drift_values) {
+					tracker.record(&new_user.external_user_id, values);
+				}
+
+				zitadel_user =
+					get_next_compared_zitadel_user(&mut stream, &mut zitadel, source_versions)
+						.await?;
 				source_user = sync_users.pop_front();
 			}
 
@@ -192,14 +1978,7 @@ async fn sync_users(config: &Config, sync_users: &mut VecDeque<User>) -> Result<
 			(Some(new_user), Some((existing_user, _)))
 				if new_user.external_user_id < existing_user.external_user_id =>
 			{
-				let res = zitadel.import_user(&new_user).await;
-				if let Err(error) = res {
-					tracing::error!(
-						"Failed to import user `{}`: {}",
-						new_user.external_user_id,
-						error
-					);
-				}
+				pending.imports.push(new_user);
 
 				source_user = sync_users.pop_front();
 				// Don't fetch the next zitadel user yet
@@ -211,35 +1990,76 @@ async fn sync_users(config: &Config, sync_users: &mut VecDeque<User>) -> Result<
 			(Some(new_user), Some((existing_user, zitadel_id)))
 				if new_user.external_user_id > existing_user.external_user_id =>
 			{
-				let res = zitadel.delete_user(&zitadel_id).await;
-				if let Err(error) = res {
-					tracing::error!(
-						"Failed to delete user with Zitadel ID `{}`: {}",
-						zitadel_id,
-						error
-					);
-				}
+				pending.deletions.push((existing_user, zitadel_id));
 
-				zitadel_user = get_next_zitadel_user(&mut stream, &mut zitadel).await?;
+				zitadel_user =
+					get_next_compared_zitadel_user(&mut stream, &mut zitadel, source_versions)
+						.await?;
 				// Don't move to the next source user yet
 			}
 
+			// Updates of users outside the pilot group are only
+			// reported
+			(Some(new_user), Some((existing_user, zitadel_id)))
+				if new_user.external_user_id == existing_user.external_user_id
+					&& !pilot::includes(pilot, &new_user) =>
+			{
+				reporter.record_pilot_drift(
+					Operation::Update,
+					Some(&new_user.external_user_id),
+					Some(&zitadel_id),
+					existing_user.diff(&new_user).into_iter().map(|(field, _, _)| field).collect(),
+				);
+
+				zitadel_user =
+					get_next_compared_zitadel_user(&mut stream, &mut zitadel, source_versions)
+						.await?;
+				source_user = sync_users.pop_front();
+			}
+
+			// Updates beyond the change budget are left to later syncs
+			(Some(new_user), Some((existing_user, zitadel_id)))
+				if new_user.external_user_id == existing_user.external_user_id
+					&& change_budget.exhausted() =>
+			{
+				reporter.record_deferred(
+					Operation::Update,
+					Some(&new_user.external_user_id),
+					Some(&zitadel_id),
+				);
+
+				zitadel_user =
+					get_next_compared_zitadel_user(&mut stream, &mut zitadel, source_versions)
+						.await?;
+				source_user = sync_users.pop_front();
+			}
+
 			// If the users don't match (since we've failed the former
 			// checks), but the user IDs are the same, the user has
 			// been updated
 			(Some(new_user), Some((existing_user, zitadel_id)))
 				if new_user.external_user_id == existing_user.external_user_id =>
 			{
-				let res = zitadel.update_user(&zitadel_id, &existing_user, &new_user).await;
-				if let Err(error) = res {
-					tracing::error!(
-						"Failed to update user `{}`: {}",
-						new_user.external_user_id,
-						error
-					);
+				change_budget.spend();
+				pending_updates.push(PendingUpdate {
+					zitadel_id,
+					existing_user,
+					new_user,
+					drift_values,
+				});
+				if pending_updates.len() >= update_concurrency {
+					update_users(
+						&zitadel,
+						reporter,
+						drift_tracker.as_mut(),
+						std::mem::take(&mut pending_updates),
+					)
+					.await;
 				}
 
-				zitadel_user = get_next_zitadel_user(&mut stream, &mut zitadel).await?;
+				zitadel_user =
+					get_next_compared_zitadel_user(&mut stream, &mut zitadel, source_versions)
+						.await?;
 				source_user = sync_users.pop_front();
 			}
 
@@ -260,3 +2080,53 @@ async fn sync_users(config: &Config, sync_users: &mut VecDeque<User>) -> Result<
 
 	Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+	use indoc::indoc;
+
+	use super::*;
+
+	const EXAMPLE_CONFIG: &str = indoc! {r#"
+        zitadel:
+          url: http://localhost:8080
+          key_file: tests/environment/zitadel/service-user.json
+          organization_id: 1
+          project_id: 1
+          idp_id: 1
+
+        sources:
+          csv:
+            file_path: ./tests/environment/files/test-users.csv
+
+        user_count_check:
+          tolerance: 2
+
+        feature_flags: [dry_run]
+    "#};
+
+	#[test]
+	fn test_compare_user_counts() {
+		assert!(compare_user_counts(10, 10, 0).is_ok());
+		assert!(compare_user_counts(10, 12, 2).is_ok());
+		assert!(compare_user_counts(10, 8, 2).is_ok());
+
+		assert!(matches!(
+			compare_user_counts(10, 13, 2),
+			Err(Message::UserCountMismatch { expected: 10, actual: 13, tolerance: 2 })
+		));
+		assert!(matches!(
+			compare_user_counts(10, 7, 2),
+			Err(Message::UserCountMismatch { expected: 10, actual: 7, tolerance: 2 })
+		));
+	}
+
+	#[tokio::test]
+	async fn test_check_user_count_dry_run() {
+		let config: Config = serde_yaml::from_str(EXAMPLE_CONFIG).expect("invalid config");
+		let tolerance = config.user_count_check.as_ref().expect("no user count check").tolerance;
+
+		// Zitadel isn't reachable, so this only passes if the check is skipped
+		check_user_count(&config, tolerance, 1000).await.expect("dry run wasn't skipped");
+	}
+}
@@ -1,14 +1,210 @@
 //! Tool for syncing different sources to Famedly's Zitadel
-use std::{path::Path, process::ExitCode, str::FromStr};
+use std::{io::IsTerminal, path::PathBuf, process::ExitCode, str::FromStr};
 
 use anyhow::{Context, Result};
-use famedly_sync::{perform_sync, Config};
+use famedly_sync::{
+	anonymize_export, apply_plan, apply_state, compare_shadow, create_support_bundle, explain_user,
+	hold_user,
+	id_mapping::{export_id_mapping, import_id_mapping},
+	migrate_metadata_namespace,
+	output::{OutputFormat, Table},
+	perform_gc, perform_sync_with_options, plan_sync, remap_roles, render_state, reverify_emails,
+	run_daemon, serve_scim, serve_self_service_events,
+	source_guard::{SourceUnavailable, SOURCE_UNAVAILABLE_EXIT_CODE},
+	verify_idempotent, watch_ldap,
+	watchdog::{WatchdogTimeout, WATCHDOG_EXIT_CODE},
+	Config, SyncOptions,
+};
 use tracing::level_filters::LevelFilter;
 
+/// Usage information for the command line
+const USAGE: &str = "Usage: famedly-sync [--confirm-initial-sync | --limit <n> | --allow-second-factor-deletions | --allow-mass-deletions | --explain-user <identifier> | --hold-user <identifier> <days> | --gc | --migrate-metadata-namespace | --verify-idempotent | --compare-shadow | --remap-roles <from> <to> | --reverify-emails <path> | --render-state <path> | --apply-state <path> | --anonymize-export <path> | --plan <path> | --apply-plan <path> | --scim-server | --self-service-events | --daemon | --watch | --export-id-mapping <path> | --import-id-mapping <path> | --support-bundle <path>] [--output table|json|csv]";
+
+/// The command to run, as given on the command line
+enum Command {
+	/// Sync users, the default
+	Sync(SyncOptions),
+	/// Sync users on the configured schedule until stopped
+	Daemon,
+	/// Sync changes as they are made in LDAP until stopped
+	Watch,
+	/// Explain how the sync treats the user with the given identifier
+	ExplainUser(String),
+	/// Keep the names of the user with the given identifier as they are
+	/// in Zitadel for the given number of days
+	HoldUser(String, u32),
+	/// Remove data left behind by earlier syncs
+	Gc,
+	/// Move the metadata managed by the sync to the configured
+	/// namespace
+	MigrateMetadataNamespace,
+	/// Sync twice, failing if the second pass would write anything
+	VerifyIdempotent,
+	/// Compare the users in the production and shadow organizations
+	CompareShadow,
+	/// Replace the first project role with the second one for all
+	/// managed users
+	RemapRoles(String, String),
+	/// Re-send verification emails to the users listed in the given
+	/// file
+	ReverifyEmails(PathBuf),
+	/// Write the desired state of the Zitadel users to the given file
+	RenderState(PathBuf),
+	/// Reconcile the Zitadel users to the given state file
+	ApplyState(PathBuf),
+	/// Write the source users with their personal data replaced by
+	/// fake values to the given state file
+	AnonymizeExport(PathBuf),
+	/// Write the changes a sync would make to the given plan file
+	Plan(PathBuf),
+	/// Execute the changes of the given plan file
+	ApplyPlan(PathBuf),
+	/// Serve the SCIM API
+	ScimServer,
+	/// Receive changes users make to their own Zitadel accounts
+	SelfServiceEvents,
+	/// Export the ID mapping to the given CSV file
+	ExportIdMapping(PathBuf),
+	/// Import the ID mapping from the given CSV file
+	ImportIdMapping(PathBuf),
+	/// Write a support bundle to the given file
+	SupportBundle(PathBuf),
+}
+
+impl Command {
+	/// Parse the command and the format of its output from the command
+	/// line arguments
+	fn from_args(mut args: impl Iterator<Item = String>) -> Result<(Self, OutputFormat)> {
+		let mut options = SyncOptions::default();
+		let mut command = None;
+		// The last option given that only applies to syncs
+		let mut sync_option = None;
+		let mut output = None;
+
+		while let Some(arg) = args.next() {
+			let next_command = match arg.as_str() {
+				"--output" => {
+					let format = args.next().context("`--output` requires a format")?;
+					output = Some(format.parse()?);
+					continue;
+				}
+				"--confirm-initial-sync" => {
+					options.confirm_initial_sync = true;
+					sync_option = Some("--confirm-initial-sync");
+					continue;
+				}
+				"--allow-second-factor-deletions" => {
+					options.allow_second_factor_deletions = true;
+					sync_option = Some("--allow-second-factor-deletions");
+					continue;
+				}
+				"--allow-mass-deletions" => {
+					options.allow_mass_deletions = true;
+					sync_option = Some("--allow-mass-deletions");
+					continue;
+				}
+				"--limit" => {
+					let limit = args.next().context("`--limit` requires a number of users")?;
+					options.import_limit =
+						Some(limit.parse().context(format!("Invalid import limit `{limit}`"))?);
+					sync_option = Some("--limit");
+					continue;
+				}
+				"--daemon" => Self::Daemon,
+				"--watch" => Self::Watch,
+				"--gc" => Self::Gc,
+				"--migrate-metadata-namespace" => Self::MigrateMetadataNamespace,
+				"--verify-idempotent" => Self::VerifyIdempotent,
+				"--compare-shadow" => Self::CompareShadow,
+				"--scim-server" => Self::ScimServer,
+				"--self-service-events" => Self::SelfServiceEvents,
+				"--explain-user" => Self::ExplainUser(
+					args.next().context("`--explain-user` requires a user identifier")?,
+				),
+				"--hold-user" => {
+					let identifier =
+						args.next().context("`--hold-user` requires a user identifier")?;
+					let days = args.next().context("`--hold-user` requires a number of days")?;
+					Self::HoldUser(
+						identifier,
+						days.parse().context(format!("Invalid number of days `{days}`"))?,
+					)
+				}
+				"--remap-roles" => Self::RemapRoles(
+					args.next().context("`--remap-roles` requires the role to replace")?,
+					args.next().context("`--remap-roles` requires the new role")?,
+				),
+				"--reverify-emails" => Self::ReverifyEmails(
+					args.next().context("`--reverify-emails` requires a path")?.into(),
+				),
+				"--render-state" => Self::RenderState(
+					args.next().context("`--render-state` requires a path")?.into(),
+				),
+				"--apply-state" => {
+					Self::ApplyState(args.next().context("`--apply-state` requires a path")?.into())
+				}
+				"--anonymize-export" => Self::AnonymizeExport(
+					args.next().context("`--anonymize-export` requires a path")?.into(),
+				),
+				"--plan" => Self::Plan(args.next().context("`--plan` requires a path")?.into()),
+				"--apply-plan" => {
+					Self::ApplyPlan(args.next().context("`--apply-plan` requires a path")?.into())
+				}
+				"--export-id-mapping" => Self::ExportIdMapping(
+					args.next().context("`--export-id-mapping` requires a path")?.into(),
+				),
+				"--import-id-mapping" => Self::ImportIdMapping(
+					args.next().context("`--import-id-mapping` requires a path")?.into(),
+				),
+				"--support-bundle" => Self::SupportBundle(
+					args.next().context("`--support-bundle` requires a path")?.into(),
+				),
+				arg => anyhow::bail!("Unknown argument `{}`", arg),
+			};
+
+			if command.replace(next_command).is_some() {
+				anyhow::bail!("Only one command may be given");
+			}
+		}
+
+		let command = match (command, sync_option) {
+			(Some(_), Some(sync_option)) => {
+				anyhow::bail!(
+					"`{}` only applies to syncs and can't be given with a command",
+					sync_option
+				)
+			}
+			(Some(command), None) => command,
+			(None, _) => Self::Sync(options),
+		};
+
+		match output {
+			Some(_) if !command.prints_results() => anyhow::bail!(
+				"`--output` only applies to `--explain-user`, `--plan` and `--compare-shadow`"
+			),
+			output => Ok((command, output.unwrap_or_default())),
+		}
+	}
+
+	/// Whether the command prints its results, in the format given
+	/// with `--output`
+	fn prints_results(&self) -> bool {
+		matches!(self, Self::ExplainUser(_) | Self::Plan(_) | Self::CompareShadow)
+	}
+}
+
 #[tokio::main]
 async fn main() -> ExitCode {
 	match run_sync().await {
 		Ok(_) => ExitCode::SUCCESS,
+		Err(e) if e.is::<WatchdogTimeout>() => {
+			tracing::error!("{:?}", e);
+			ExitCode::from(WATCHDOG_EXIT_CODE)
+		}
+		Err(e) if e.is::<SourceUnavailable>() => {
+			tracing::error!("{:?}", e);
+			ExitCode::from(SOURCE_UNAVAILABLE_EXIT_CODE)
+		}
 		Err(e) => {
 			tracing::error!("{:?}", e);
 			ExitCode::FAILURE
@@ -17,12 +213,22 @@ async fn main() -> ExitCode {
 }
 
 /// Simple entrypoint without any bells or whistles
-#[allow(clippy::print_stderr)]
+#[allow(clippy::print_stderr, clippy::print_stdout)]
 async fn run_sync() -> Result<()> {
-	let config = {
-		let config_path = std::env::var("FAMEDLY_SYNC_CONFIG").unwrap_or("config.yaml".into());
-		let config_path = Path::new(&config_path);
-		match Config::new(config_path) {
+	let (command, output) = match Command::from_args(std::env::args().skip(1)) {
+		Ok(arguments) => arguments,
+		Err(error) => {
+			eprintln!("{}", error);
+			eprintln!("{}", USAGE);
+			anyhow::bail!(error);
+		}
+	};
+
+	let config_path =
+		PathBuf::from(std::env::var("FAMEDLY_SYNC_CONFIG").unwrap_or("config.yaml".into()));
+	let (config, unknown_config_keys) = {
+		let config_path = config_path.as_path();
+		match Config::load(config_path) {
 			Ok(config) => config,
 			Err(error) => {
 				// Tracing subscriber is not yet configured, so we
@@ -44,5 +250,59 @@ async fn run_sync() -> Result<()> {
 	tracing::subscriber::set_global_default(subscriber)
 		.context("Setting default tracing subscriber failed")?;
 
-	perform_sync(&config).await
+	for key in unknown_config_keys {
+		tracing::warn!("Ignoring unknown configuration key `{}`", key);
+	}
+
+	match command {
+		Command::Sync(options) => perform_sync_with_options(&config, &options).await.map(|_| ()),
+		Command::Daemon => run_daemon(&config).await,
+		Command::Watch => watch_ldap(&config).await,
+		Command::Gc => perform_gc(&config).await,
+		Command::MigrateMetadataNamespace => migrate_metadata_namespace(&config).await,
+		Command::VerifyIdempotent => verify_idempotent(&config).await,
+		Command::CompareShadow => {
+			let differences = compare_shadow(&config).await?;
+			if !differences.is_empty() || output != OutputFormat::Table {
+				print_results(&differences, output)?;
+			}
+			if !differences.is_empty() {
+				anyhow::bail!(
+					"Production and shadow organizations differ in {} users",
+					differences.len()
+				);
+			}
+			Ok(())
+		}
+		Command::HoldUser(identifier, days) => hold_user(&config, &identifier, days).await,
+		Command::RemapRoles(from, to) => remap_roles(&config, &from, &to).await,
+		Command::ReverifyEmails(path) => reverify_emails(&config, &path).await,
+		Command::RenderState(path) => render_state(&config, &path).await,
+		Command::ApplyState(path) => apply_state(&config, &path).await,
+		Command::AnonymizeExport(path) => anonymize_export(&config, &path).await,
+		Command::Plan(path) => print_results(&plan_sync(&config, &path).await?, output),
+		Command::ApplyPlan(path) => apply_plan(&config, &path).await,
+		Command::ScimServer => serve_scim(&config).await,
+		Command::SelfServiceEvents => serve_self_service_events(&config).await,
+		Command::ExportIdMapping(path) => export_id_mapping(&config, &path),
+		Command::ImportIdMapping(path) => import_id_mapping(&config, &path),
+		Command::SupportBundle(path) => create_support_bundle(&config, &config_path, &path).await,
+		Command::ExplainUser(identifier) => {
+			let explanation = explain_user(&config, &identifier).await?;
+			match output {
+				OutputFormat::Table => println!("{}", explanation),
+				output => print_results(&Table::from_sections(&explanation), output)?,
+			}
+			Ok(())
+		}
+	}
+}
+
+/// Print the results of a command in the given format. Tables are
+/// styled when printed to a terminal, unless `NO_COLOR` is set.
+#[allow(clippy::print_stdout)]
+fn print_results(results: &Table, output: OutputFormat) -> Result<()> {
+	let styled = std::io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none();
+	print!("{}", results.render(output, styled)?);
+	Ok(())
 }
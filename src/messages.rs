@@ -0,0 +1,828 @@
+//! Operator-facing messages in the configured language
+//!
+//! Errors asking the operator to act, and the texts of the sync
+//! report, are rendered from this catalog, so that they can be
+//! forwarded to administrators who don't read English. Debug logging
+//! and errors passed through from Zitadel or the sources stay in
+//! English.
+use serde::Deserialize;
+
+use crate::{
+	report::{CollisionKey, Operation},
+	zitadel::Capability,
+};
+
+/// The language of operator-facing messages
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+pub enum Language {
+	/// English
+	#[default]
+	#[serde(rename = "en")]
+	English,
+	/// German
+	#[serde(rename = "de")]
+	German,
+}
+
+/// A Zitadel object referenced by the configuration
+#[derive(Debug, Clone, Copy)]
+pub enum ConfiguredObject<'a> {
+	/// The organization users are synced to
+	Organization {
+		/// The organization ID
+		organization_id: &'a str,
+	},
+	/// The project roles are granted in
+	Project {
+		/// The project ID
+		project_id: &'a str,
+		/// The organization ID
+		organization_id: &'a str,
+	},
+	/// The IDP users are linked to
+	Idp {
+		/// The IDP ID
+		idp_id: &'a str,
+		/// The organization ID
+		organization_id: &'a str,
+	},
+}
+
+/// An operator-facing message
+#[derive(Debug, Clone)]
+pub enum Message<'a> {
+	/// A configured Zitadel object doesn't exist
+	ObjectNotFound(ConfiguredObject<'a>),
+	/// The service user may not access a configured Zitadel object
+	ObjectAccessDenied(ConfiguredObject<'a>),
+	/// Zitadel rejected the key of the service user
+	ObjectUnauthenticated(ConfiguredObject<'a>),
+	/// Looking up a configured Zitadel object failed for another
+	/// reason
+	ObjectLookupFailed(ConfiguredObject<'a>),
+	/// The service user lacks permissions for the sync
+	MissingPermissions {
+		/// The operations the service user can't perform, along with
+		/// the permissions it lacks for them
+		missing: &'a [(Capability, Vec<&'static str>)],
+		/// The roles to grant the service user
+		roles: &'a [&'static str],
+		/// The organization ID
+		organization_id: &'a str,
+	},
+	/// The first sync against an organization wasn't confirmed
+	InitialSyncRequiresConfirmation {
+		/// The organization ID
+		organization_id: &'a str,
+	},
+	/// The first sync against an organization is run as a dry run
+	InitialSyncDryRun {
+		/// The organization ID
+		organization_id: &'a str,
+	},
+	/// The number of users in Zitadel is off after a sync
+	UserCountMismatch {
+		/// The expected number of users
+		expected: usize,
+		/// The number of users found in Zitadel
+		actual: usize,
+		/// The tolerated difference
+		tolerance: usize,
+	},
+	/// The source returned fewer enabled users than configured
+	TooFewSourceUsers {
+		/// The number of enabled source users
+		user_count: usize,
+		/// The minimum number of enabled source users
+		min_expected_users: usize,
+	},
+	/// The number of enabled source users dropped too much since the
+	/// last sync
+	SourceUserCountDropped {
+		/// The number of enabled source users
+		user_count: usize,
+		/// The number of enabled source users at the last sync
+		last_user_count: usize,
+		/// The minimum percentage of the users at the last sync
+		min_percent: u8,
+	},
+	/// A sync would delete more users than `deletion_guard` allows
+	TooManyDeletions {
+		/// The number of users to delete
+		deletions: usize,
+		/// The maximum number of users to delete
+		max_deletions: usize,
+	},
+	/// A sync would delete a larger share of the users than
+	/// `deletion_guard` allows
+	TooManyDeletionsPercent {
+		/// The number of users to delete
+		deletions: usize,
+		/// The number of managed Zitadel users
+		zitadel_user_count: usize,
+		/// The maximum percentage of the users to delete
+		max_percent: u8,
+	},
+	/// The outcome of a sync
+	SyncSummary {
+		/// The number of imported users
+		created: usize,
+		/// The number of updated users
+		updated: usize,
+		/// The number of renamed users
+		renamed: usize,
+		/// The number of deleted users
+		deleted: usize,
+		/// The number of failed operations
+		failed: usize,
+	},
+	/// The email address of a new user is still bound to another
+	/// Zitadel user
+	EmailConflict {
+		/// The external ID of the new user
+		external_user_id: &'a str,
+		/// The Zitadel ID of the user holding the email address
+		zitadel_id: &'a str,
+		/// The external ID of the user holding the email address
+		holder_external_user_id: &'a str,
+	},
+	/// The email address of a new user belongs to a user of another
+	/// Zitadel organization
+	CrossOrgConflict {
+		/// The external ID of the new user
+		external_user_id: &'a str,
+		/// The Zitadel ID of the user holding the email address
+		zitadel_id: &'a str,
+		/// The organization of the user holding the email address
+		organization_id: &'a str,
+	},
+	/// A unique attribute of a new user is already taken by another
+	/// Zitadel user
+	ImportCollision {
+		/// The external ID of the new user
+		external_user_id: &'a str,
+		/// The attribute which collided
+		key: CollisionKey,
+		/// The Zitadel ID of the user holding the attribute
+		zitadel_id: &'a str,
+		/// The external ID of the user holding the attribute, if any
+		holder_external_user_id: Option<&'a str>,
+	},
+	/// A user was merged from personas which disagree on some
+	/// attributes
+	PersonaConflict {
+		/// The external ID of the merged user
+		external_user_id: &'a str,
+		/// The external IDs of the personas
+		personas: &'a [String],
+		/// The attributes the personas disagree on
+		attributes: &'a [String],
+	},
+	/// A newer version of the sync is available
+	OutdatedVersion {
+		/// The installed version
+		installed: &'a str,
+		/// The latest version
+		latest: &'a str,
+	},
+	/// The installed version of the sync lacks a critical fix
+	MissingCriticalFix {
+		/// The first version containing the fix
+		fixed_in: &'a str,
+		/// A description of the bug
+		description: &'a str,
+	},
+	/// An operation on a user failed
+	OperationFailed {
+		/// The failed operation
+		operation: Operation,
+		/// The external or Zitadel ID of the user
+		user: &'a str,
+		/// The error the operation failed with
+		error: &'a str,
+	},
+}
+
+impl Message<'_> {
+	/// Render the message in the given language
+	#[must_use]
+	pub fn render(&self, language: Language) -> String {
+		match language {
+			Language::English => self.render_english(),
+			Language::German => self.render_german(),
+		}
+	}
+
+	/// Render the message in English
+	fn render_english(&self) -> String {
+		let language = Language::English;
+		match self {
+			Message::ObjectNotFound(object) => {
+				format!("{} not found, check the Zitadel configuration", object.render(language))
+			}
+			Message::ObjectAccessDenied(object) => {
+				format!("The service user is not allowed to access {}", object.render(language))
+			}
+			Message::ObjectUnauthenticated(object) => format!(
+				"Zitadel rejected the service user key while accessing {}",
+				object.render(language)
+			),
+			Message::ObjectLookupFailed(object) => {
+				format!("Failed to look up {}", object.render(language))
+			}
+			Message::MissingPermissions { missing, roles, organization_id } => format!(
+				"The service user lacks permissions to {}; grant it the {} role(s) in \
+				 organization `{organization_id}`",
+				render_capabilities(missing, "requires", language),
+				roles.join(", ")
+			),
+			Message::InitialSyncRequiresConfirmation { organization_id } => format!(
+				"This is the first sync against organization `{organization_id}`. Verify the \
+				 configuration, e.g. with a dry run, and confirm the sync with \
+				 `--confirm-initial-sync`"
+			),
+			Message::InitialSyncDryRun { organization_id } => format!(
+				"This is the first sync against organization `{organization_id}`, performing a \
+				 dry run. Confirm the sync with `--confirm-initial-sync`"
+			),
+			Message::UserCountMismatch { expected, actual, tolerance } => format!(
+				"User count mismatch after sync: expected {expected} users in Zitadel, found \
+				 {actual} (tolerance: {tolerance})"
+			),
+			Message::TooFewSourceUsers { user_count, min_expected_users } => format!(
+				"The source returned only {user_count} enabled users, fewer than the expected \
+				 {min_expected_users}; aborting before any users are changed. Check the source, \
+				 e.g. for an empty CSV file or a broken user filter, or lower \
+				 `min_expected_users`"
+			),
+			Message::SourceUserCountDropped { user_count, last_user_count, min_percent } => {
+				format!(
+					"The source returned only {user_count} enabled users, less than \
+					 {min_percent}% of the {last_user_count} users at the last sync; aborting \
+					 before any users are changed. Check the source, e.g. for an empty CSV file \
+					 or a broken user filter, or lower `min_percent_of_last_sync` if the users \
+					 were removed intentionally"
+				)
+			}
+			Message::TooManyDeletions { deletions, max_deletions } => format!(
+				"The sync would delete {deletions} users, more than the allowed {max_deletions}; \
+				 aborting before any users are deleted. Check the source, or run with \
+				 `--allow-mass-deletions` if the users were removed intentionally"
+			),
+			Message::TooManyDeletionsPercent { deletions, zitadel_user_count, max_percent } => {
+				format!(
+					"The sync would delete {deletions} of {zitadel_user_count} users, more than \
+					 the allowed {max_percent}%; aborting before any users are deleted. Check the \
+					 source, or run with `--allow-mass-deletions` if the users were removed \
+					 intentionally"
+				)
+			}
+			Message::SyncSummary { created, updated, renamed, deleted, failed } => format!(
+				"Sync finished: {created} created, {updated} updated, {renamed} renamed, \
+				 {deleted} deleted, {failed} failed"
+			),
+			Message::EmailConflict { external_user_id, zitadel_id, holder_external_user_id } => {
+				format!(
+					"The email address of the new user `{external_user_id}` is still bound to \
+					 Zitadel user `{zitadel_id}` with the external ID \
+					 `{holder_external_user_id}`, so the new user wasn't imported. Delete the \
+					 old user or change its email address, and the next sync imports the new user"
+				)
+			}
+			Message::CrossOrgConflict { external_user_id, zitadel_id, organization_id } => {
+				format!(
+					"The email address of the new user `{external_user_id}` belongs to Zitadel \
+					 user `{zitadel_id}` of the organization `{organization_id}`, so the new user \
+					 wasn't imported, since a second account with the same email address breaks \
+					 logins. Remove the user from the other organization or change its email \
+					 address, and the next sync imports the new user"
+				)
+			}
+			Message::ImportCollision {
+				external_user_id,
+				key,
+				zitadel_id,
+				holder_external_user_id,
+			} => {
+				let key = match key {
+					CollisionKey::UserId => "user ID (localpart)",
+					CollisionKey::Username => "username",
+					CollisionKey::Email => "email address",
+				};
+				let holder = holder_external_user_id
+					.map(|holder| format!(" with the external ID `{holder}`"))
+					.unwrap_or_default();
+				format!(
+					"The {key} of the new user `{external_user_id}` is already taken by Zitadel \
+					 user `{zitadel_id}`{holder}, so the new user wasn't imported. Delete the \
+					 other user or resolve the collision otherwise, and the next sync imports the \
+					 new user"
+				)
+			}
+			Message::PersonaConflict { external_user_id, personas, attributes } => format!(
+				"User `{external_user_id}` was merged from the personas {}, which differ in {}; \
+				 the values of `{external_user_id}` were kept",
+				render_list(personas),
+				render_list(attributes)
+			),
+			Message::OutdatedVersion { installed, latest } => format!(
+				"famedly-sync {installed} is outdated, please update to the latest version \
+				 {latest}"
+			),
+			Message::MissingCriticalFix { fixed_in, description } => format!(
+				"The installed famedly-sync lacks a critical fix of version {fixed_in}: \
+				 {description}"
+			),
+			Message::OperationFailed { operation, user, error } => {
+				let operation = match operation {
+					Operation::Create => "Import",
+					Operation::Update => "Update",
+					Operation::Delete => "Deletion",
+					Operation::Rename => "Rename",
+				};
+				format!("{operation} of user `{user}` failed: {error}")
+			}
+		}
+	}
+
+	/// Render the message in German
+	fn render_german(&self) -> String {
+		let language = Language::German;
+		match self {
+			Message::ObjectNotFound(object) => format!(
+				"{} wurde nicht gefunden, bitte die Zitadel-Konfiguration prüfen",
+				object.render(language)
+			),
+			Message::ObjectAccessDenied(object) => format!(
+				"Der Service-User hat keinen Zugriff auf {}",
+				object.render_accusative_german()
+			),
+			Message::ObjectUnauthenticated(object) => format!(
+				"Zitadel hat den Schlüssel des Service-Users beim Zugriff auf {} abgelehnt",
+				object.render_accusative_german()
+			),
+			Message::ObjectLookupFailed(object) => {
+				format!("{} konnte nicht abgefragt werden", object.render(language))
+			}
+			Message::MissingPermissions { missing, roles, organization_id } => format!(
+				"Dem Service-User fehlen Berechtigungen, um {}; bitte ihm in der Organisation \
+				 `{organization_id}` die Rolle(n) {} zuweisen",
+				render_capabilities(missing, "benötigt", language),
+				roles.join(", ")
+			),
+			Message::InitialSyncRequiresConfirmation { organization_id } => format!(
+				"Dies ist der erste Sync in die Organisation `{organization_id}`. Bitte die \
+				 Konfiguration prüfen, z. B. mit einem Probelauf, und den Sync mit \
+				 `--confirm-initial-sync` bestätigen"
+			),
+			Message::InitialSyncDryRun { organization_id } => format!(
+				"Dies ist der erste Sync in die Organisation `{organization_id}`, es wird ein \
+				 Probelauf durchgeführt. Den Sync mit `--confirm-initial-sync` bestätigen"
+			),
+			Message::UserCountMismatch { expected, actual, tolerance } => format!(
+				"Abweichende Anzahl an Benutzern nach dem Sync: {expected} Benutzer in Zitadel \
+				 erwartet, {actual} gefunden (Toleranz: {tolerance})"
+			),
+			Message::TooFewSourceUsers { user_count, min_expected_users } => format!(
+				"Die Quelle hat nur {user_count} aktive Benutzer geliefert, weniger als die \
+				 erwarteten {min_expected_users}; der Sync wird abgebrochen, bevor Benutzer \
+				 geändert werden. Bitte die Quelle prüfen, z. B. auf eine leere CSV-Datei oder \
+				 einen fehlerhaften Benutzerfilter, oder `min_expected_users` verringern"
+			),
+			Message::SourceUserCountDropped { user_count, last_user_count, min_percent } => {
+				format!(
+					"Die Quelle hat nur {user_count} aktive Benutzer geliefert, weniger als \
+					 {min_percent} % der {last_user_count} Benutzer beim letzten Sync; der Sync \
+					 wird abgebrochen, bevor Benutzer geändert werden. Bitte die Quelle prüfen, \
+					 z. B. auf eine leere CSV-Datei oder einen fehlerhaften Benutzerfilter, oder \
+					 `min_percent_of_last_sync` verringern, falls die Benutzer absichtlich \
+					 entfernt wurden"
+				)
+			}
+			Message::TooManyDeletions { deletions, max_deletions } => format!(
+				"Der Sync würde {deletions} Benutzer löschen, mehr als die erlaubten \
+				 {max_deletions}; der Sync wird abgebrochen, bevor Benutzer gelöscht werden. \
+				 Bitte die Quelle prüfen, oder mit `--allow-mass-deletions` ausführen, falls die \
+				 Benutzer absichtlich entfernt wurden"
+			),
+			Message::TooManyDeletionsPercent { deletions, zitadel_user_count, max_percent } => {
+				format!(
+					"Der Sync würde {deletions} von {zitadel_user_count} Benutzern löschen, mehr \
+					 als die erlaubten {max_percent} %; der Sync wird abgebrochen, bevor Benutzer \
+					 gelöscht werden. Bitte die Quelle prüfen, oder mit `--allow-mass-deletions` \
+					 ausführen, falls die Benutzer absichtlich entfernt wurden"
+				)
+			}
+			Message::SyncSummary { created, updated, renamed, deleted, failed } => format!(
+				"Sync abgeschlossen: {created} angelegt, {updated} aktualisiert, {renamed} \
+				 umbenannt, {deleted} gelöscht, {failed} fehlgeschlagen"
+			),
+			Message::EmailConflict { external_user_id, zitadel_id, holder_external_user_id } => {
+				format!(
+					"Die E-Mail-Adresse des neuen Benutzers `{external_user_id}` ist noch an den \
+					 Zitadel-Benutzer `{zitadel_id}` mit der externen ID \
+					 `{holder_external_user_id}` vergeben, daher wurde der neue Benutzer nicht \
+					 importiert. Bitte den alten Benutzer löschen oder seine E-Mail-Adresse \
+					 ändern, dann importiert der nächste Sync den neuen Benutzer"
+				)
+			}
+			Message::CrossOrgConflict { external_user_id, zitadel_id, organization_id } => {
+				format!(
+					"Die E-Mail-Adresse des neuen Benutzers `{external_user_id}` gehört dem \
+					 Zitadel-Benutzer `{zitadel_id}` der Organisation `{organization_id}`, daher \
+					 wurde der neue Benutzer nicht importiert, da ein zweites Konto mit derselben \
+					 E-Mail-Adresse die Anmeldung verhindert. Bitte den Benutzer aus der anderen \
+					 Organisation entfernen oder seine E-Mail-Adresse ändern, dann importiert der \
+					 nächste Sync den neuen Benutzer"
+				)
+			}
+			Message::ImportCollision {
+				external_user_id,
+				key,
+				zitadel_id,
+				holder_external_user_id,
+			} => {
+				let key = match key {
+					CollisionKey::UserId => "Die Benutzer-ID (Localpart)",
+					CollisionKey::Username => "Der Benutzername",
+					CollisionKey::Email => "Die E-Mail-Adresse",
+				};
+				let holder = holder_external_user_id
+					.map(|holder| format!(" mit der externen ID `{holder}`"))
+					.unwrap_or_default();
+				format!(
+					"{key} des neuen Benutzers `{external_user_id}` ist bereits an den \
+					 Zitadel-Benutzer `{zitadel_id}`{holder} vergeben, daher wurde der neue \
+					 Benutzer nicht importiert. Bitte den anderen Benutzer löschen oder die \
+					 Kollision anderweitig auflösen, dann importiert der nächste Sync den neuen \
+					 Benutzer"
+				)
+			}
+			Message::PersonaConflict { external_user_id, personas, attributes } => format!(
+				"Der Benutzer `{external_user_id}` wurde aus den Personas {} zusammengeführt, die \
+				 sich in {} unterscheiden; die Werte von `{external_user_id}` wurden beibehalten",
+				render_list(personas),
+				render_list(attributes)
+			),
+			Message::OutdatedVersion { installed, latest } => format!(
+				"famedly-sync {installed} ist veraltet, bitte auf die aktuelle Version {latest} \
+				 aktualisieren"
+			),
+			Message::MissingCriticalFix { fixed_in, description } => format!(
+				"Dem installierten famedly-sync fehlt eine kritische Korrektur der Version \
+				 {fixed_in}: {description}"
+			),
+			Message::OperationFailed { operation, user, error } => {
+				let operation = match operation {
+					Operation::Create => "Der Import",
+					Operation::Update => "Die Aktualisierung",
+					Operation::Delete => "Die Löschung",
+					Operation::Rename => "Die Umbenennung",
+				};
+				format!("{operation} des Benutzers `{user}` ist fehlgeschlagen: {error}")
+			}
+		}
+	}
+}
+
+impl ConfiguredObject<'_> {
+	/// Render the object as the subject of a sentence
+	fn render(&self, language: Language) -> String {
+		match (language, self) {
+			(Language::English, ConfiguredObject::Organization { organization_id }) => {
+				format!("Organization `{organization_id}`")
+			}
+			(Language::English, ConfiguredObject::Project { project_id, organization_id }) => {
+				format!("Project `{project_id}` in organization `{organization_id}`")
+			}
+			(Language::English, ConfiguredObject::Idp { idp_id, organization_id }) => {
+				format!("IDP `{idp_id}` in organization `{organization_id}`")
+			}
+			(Language::German, ConfiguredObject::Organization { organization_id }) => {
+				format!("Die Organisation `{organization_id}`")
+			}
+			(Language::German, ConfiguredObject::Project { project_id, organization_id }) => {
+				format!("Das Projekt `{project_id}` in der Organisation `{organization_id}`")
+			}
+			(Language::German, ConfiguredObject::Idp { idp_id, organization_id }) => {
+				format!("Der IDP `{idp_id}` in der Organisation `{organization_id}`")
+			}
+		}
+	}
+
+	/// Render the object as the object of a German preposition
+	/// requiring the accusative
+	fn render_accusative_german(&self) -> String {
+		match self {
+			ConfiguredObject::Organization { organization_id } => {
+				format!("die Organisation `{organization_id}`")
+			}
+			ConfiguredObject::Project { project_id, organization_id } => {
+				format!("das Projekt `{project_id}` in der Organisation `{organization_id}`")
+			}
+			ConfiguredObject::Idp { idp_id, organization_id } => {
+				format!("den IDP `{idp_id}` in der Organisation `{organization_id}`")
+			}
+		}
+	}
+}
+
+/// A label of the HTML rendering of the sync report
+#[derive(Debug, Clone, Copy)]
+pub enum ReportLabel<'a> {
+	/// The title of the report
+	Title,
+	/// When the sync started
+	StartedAt,
+	/// When the sync finished
+	FinishedAt,
+	/// The version of the sync
+	Version,
+	/// The fingerprint of the configuration
+	ConfigFingerprint,
+	/// The notice on reports of dry runs
+	DryRun,
+	/// The notice on reports of syncs only logging deletions
+	DeletionsDryRun,
+	/// An operation, as the heading of the users it was applied to
+	Operation(Operation),
+	/// The heading of users whose changes were skipped
+	Skipped,
+	/// The heading of failed operations
+	Failed,
+	/// The heading of the details of each user
+	Users,
+	/// The column of external user IDs
+	User,
+	/// The column of Zitadel IDs
+	ZitadelId,
+	/// The column of changed attributes
+	ChangedFields,
+	/// The column of reasons
+	Reason,
+	/// The column of external IDs before a rename
+	OldExternalId,
+	/// The column of external IDs after a rename
+	NewExternalId,
+	/// The column of kinds of changes
+	Change,
+	/// A kind of change
+	ChangeKind(Operation),
+	/// The column of errors
+	Error,
+	/// Data left out of the report
+	Redacted,
+	/// A change left to a later sync
+	Deferred,
+	/// A change to a user outside the pilot group
+	OutsidePilot,
+	/// A source user existing in Zitadel without being managed
+	Unmanaged,
+	/// A Zitadel user without an email address
+	NoEmail,
+	/// A deletion held back due to registered second factors
+	SecondFactors,
+	/// A source user lacking a required attribute
+	MissingAttribute,
+	/// A source user whose entry failed to parse
+	ParseFailure,
+	/// A new user whose email address is bound to another user
+	EmailConflict,
+	/// A new user whose email address belongs to a user of another
+	/// organization
+	CrossOrgConflict,
+	/// A new user whose user ID, username or email address is taken by
+	/// another user
+	ImportCollision,
+	/// The user is no longer in the source
+	NotInSource,
+	/// The user is disabled in the source
+	DisabledInSource,
+	/// The user is a guest whose access expired
+	GuestExpired,
+	/// A rule excludes the user
+	ExcludedByRule(&'a str),
+	/// The UKT source lists the user as removed
+	RemovedInUkt,
+	/// A SCIM client deleted the user
+	DeletedViaScim,
+}
+
+impl ReportLabel<'_> {
+	/// Render the label in the given language
+	#[must_use]
+	pub fn render(&self, language: Language) -> String {
+		match language {
+			Language::English => match self {
+				ReportLabel::Title => "Sync report".to_owned(),
+				ReportLabel::StartedAt => "Started".to_owned(),
+				ReportLabel::FinishedAt => "Finished".to_owned(),
+				ReportLabel::Version => "Version".to_owned(),
+				ReportLabel::ConfigFingerprint => "Configuration".to_owned(),
+				ReportLabel::DryRun => "Dry run: nothing was changed in Zitadel".to_owned(),
+				ReportLabel::DeletionsDryRun => {
+					"Deletions were only logged, the users still exist".to_owned()
+				}
+				ReportLabel::Operation(operation) => match operation {
+					Operation::Create => "Created",
+					Operation::Update => "Updated",
+					Operation::Delete => "Deleted",
+					Operation::Rename => "Renamed",
+				}
+				.to_owned(),
+				ReportLabel::Skipped => "Skipped".to_owned(),
+				ReportLabel::Failed => "Failed".to_owned(),
+				ReportLabel::Users => "Users".to_owned(),
+				ReportLabel::User => "User".to_owned(),
+				ReportLabel::ZitadelId => "Zitadel ID".to_owned(),
+				ReportLabel::ChangedFields => "Changed attributes".to_owned(),
+				ReportLabel::Reason => "Reason".to_owned(),
+				ReportLabel::OldExternalId => "Old ID".to_owned(),
+				ReportLabel::NewExternalId => "New ID".to_owned(),
+				ReportLabel::Change => "Change".to_owned(),
+				ReportLabel::ChangeKind(operation) => match operation {
+					Operation::Create => "Import",
+					Operation::Update => "Update",
+					Operation::Delete => "Deletion",
+					Operation::Rename => "Rename",
+				}
+				.to_owned(),
+				ReportLabel::Error => "Error".to_owned(),
+				ReportLabel::Redacted => "(redacted)".to_owned(),
+				ReportLabel::Deferred => "Left to a later sync".to_owned(),
+				ReportLabel::OutsidePilot => "Outside the pilot group".to_owned(),
+				ReportLabel::Unmanaged => {
+					"Exists in Zitadel, but isn't managed by the sync".to_owned()
+				}
+				ReportLabel::NoEmail => "No email address".to_owned(),
+				ReportLabel::SecondFactors => "Deletion held back due to second factors".to_owned(),
+				ReportLabel::MissingAttribute => "Lacks a required attribute".to_owned(),
+				ReportLabel::ParseFailure => "Entry failed to parse".to_owned(),
+				ReportLabel::EmailConflict => "Email address bound to another user".to_owned(),
+				ReportLabel::CrossOrgConflict => {
+					"Email address used in another organization".to_owned()
+				}
+				ReportLabel::ImportCollision => "Unique attribute taken by another user".to_owned(),
+				ReportLabel::NotInSource => "No longer in the source".to_owned(),
+				ReportLabel::DisabledInSource => "Disabled in the source".to_owned(),
+				ReportLabel::GuestExpired => "Guest access expired".to_owned(),
+				ReportLabel::ExcludedByRule(rule) => format!("Excluded by rule `{rule}`"),
+				ReportLabel::RemovedInUkt => "Removed in the UKT export".to_owned(),
+				ReportLabel::DeletedViaScim => "Deleted via SCIM".to_owned(),
+			},
+			Language::German => match self {
+				ReportLabel::Title => "Sync-Bericht".to_owned(),
+				ReportLabel::StartedAt => "Gestartet".to_owned(),
+				ReportLabel::FinishedAt => "Beendet".to_owned(),
+				ReportLabel::Version => "Version".to_owned(),
+				ReportLabel::ConfigFingerprint => "Konfiguration".to_owned(),
+				ReportLabel::DryRun => "Testlauf: In Zitadel wurde nichts geändert".to_owned(),
+				ReportLabel::DeletionsDryRun => {
+					"Löschungen wurden nur protokolliert, die Benutzer existieren weiterhin"
+						.to_owned()
+				}
+				ReportLabel::Operation(operation) => match operation {
+					Operation::Create => "Angelegt",
+					Operation::Update => "Aktualisiert",
+					Operation::Delete => "Gelöscht",
+					Operation::Rename => "Umbenannt",
+				}
+				.to_owned(),
+				ReportLabel::Skipped => "Übersprungen".to_owned(),
+				ReportLabel::Failed => "Fehlgeschlagen".to_owned(),
+				ReportLabel::Users => "Benutzer".to_owned(),
+				ReportLabel::User => "Benutzer".to_owned(),
+				ReportLabel::ZitadelId => "Zitadel-ID".to_owned(),
+				ReportLabel::ChangedFields => "Geänderte Attribute".to_owned(),
+				ReportLabel::Reason => "Grund".to_owned(),
+				ReportLabel::OldExternalId => "Alte ID".to_owned(),
+				ReportLabel::NewExternalId => "Neue ID".to_owned(),
+				ReportLabel::Change => "Änderung".to_owned(),
+				ReportLabel::ChangeKind(operation) => match operation {
+					Operation::Create => "Import",
+					Operation::Update => "Aktualisierung",
+					Operation::Delete => "Löschung",
+					Operation::Rename => "Umbenennung",
+				}
+				.to_owned(),
+				ReportLabel::Error => "Fehler".to_owned(),
+				ReportLabel::Redacted => "(entfernt)".to_owned(),
+				ReportLabel::Deferred => "Auf einen späteren Sync verschoben".to_owned(),
+				ReportLabel::OutsidePilot => "Außerhalb der Pilotgruppe".to_owned(),
+				ReportLabel::Unmanaged => {
+					"Existiert in Zitadel, wird aber nicht vom Sync verwaltet".to_owned()
+				}
+				ReportLabel::NoEmail => "Keine E-Mail-Adresse".to_owned(),
+				ReportLabel::SecondFactors => {
+					"Löschung wegen zweiter Faktoren zurückgehalten".to_owned()
+				}
+				ReportLabel::MissingAttribute => "Pflichtattribut fehlt".to_owned(),
+				ReportLabel::ParseFailure => "Eintrag nicht lesbar".to_owned(),
+				ReportLabel::EmailConflict => {
+					"E-Mail-Adresse an anderen Benutzer vergeben".to_owned()
+				}
+				ReportLabel::CrossOrgConflict => {
+					"E-Mail-Adresse in anderer Organisation vergeben".to_owned()
+				}
+				ReportLabel::ImportCollision => {
+					"Eindeutiges Attribut an anderen Benutzer vergeben".to_owned()
+				}
+				ReportLabel::NotInSource => "Nicht mehr in der Quelle".to_owned(),
+				ReportLabel::DisabledInSource => "In der Quelle deaktiviert".to_owned(),
+				ReportLabel::GuestExpired => "Gastzugang abgelaufen".to_owned(),
+				ReportLabel::ExcludedByRule(rule) => {
+					format!("Durch die Regel `{rule}` ausgeschlossen")
+				}
+				ReportLabel::RemovedInUkt => "Im UKT-Export entfernt".to_owned(),
+				ReportLabel::DeletedViaScim => "Über SCIM gelöscht".to_owned(),
+			},
+		}
+	}
+}
+
+/// Render operations the service user can't perform, along with the
+/// permissions it lacks for them
+fn render_capabilities(
+	missing: &[(Capability, Vec<&'static str>)],
+	requires: &str,
+	language: Language,
+) -> String {
+	missing
+		.iter()
+		.map(|(capability, permissions)| {
+			format!(
+				"{} ({requires} {})",
+				render_capability(*capability, language),
+				permissions.join(", ")
+			)
+		})
+		.collect::<Vec<_>>()
+		.join(", ")
+}
+
+/// Render a list of names, each quoted as code
+fn render_list(names: &[String]) -> String {
+	names.iter().map(|name| format!("`{name}`")).collect::<Vec<_>>().join(", ")
+}
+
+/// Render an operation of the sync as an infinitive clause
+fn render_capability(capability: Capability, language: Language) -> String {
+	match language {
+		Language::English => capability.to_string(),
+		Language::German => match capability {
+			Capability::ListUsers => "Benutzer aufzulisten",
+			Capability::CreateUsers => "Benutzer anzulegen",
+			Capability::UpdateUsers => "Benutzer zu aktualisieren",
+			Capability::DeleteUsers => "Benutzer zu löschen",
+			Capability::Metadata => "Metadaten von Benutzern zu verwalten",
+			Capability::Grants => "Rollen von Benutzern zu verwalten",
+		}
+		.to_owned(),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_render() {
+		let message = Message::ObjectNotFound(ConfiguredObject::Project {
+			project_id: "123",
+			organization_id: "456",
+		});
+		assert_eq!(
+			message.render(Language::English),
+			"Project `123` in organization `456` not found, check the Zitadel configuration"
+		);
+		assert_eq!(
+			message.render(Language::German),
+			"Das Projekt `123` in der Organisation `456` wurde nicht gefunden, bitte die \
+			 Zitadel-Konfiguration prüfen"
+		);
+
+		let missing = [(Capability::DeleteUsers, vec!["user.delete"])];
+		let message = Message::MissingPermissions {
+			missing: &missing,
+			roles: &["ORG_USER_MANAGER"],
+			organization_id: "456",
+		};
+		assert_eq!(
+			message.render(Language::English),
+			"The service user lacks permissions to delete users (requires user.delete); grant it \
+			 the ORG_USER_MANAGER role(s) in organization `456`"
+		);
+		assert_eq!(
+			message.render(Language::German),
+			"Dem Service-User fehlen Berechtigungen, um Benutzer zu löschen (benötigt \
+			 user.delete); bitte ihm in der Organisation `456` die Rolle(n) ORG_USER_MANAGER \
+			 zuweisen"
+		);
+	}
+
+	#[test]
+	fn test_deserialize_language() {
+		let language: Language = serde_yaml::from_str("de").expect("invalid language");
+		assert_eq!(language, Language::German);
+	}
+}
@@ -0,0 +1,298 @@
+//! Prometheus metrics of the sync
+//!
+//! With the `metrics` feature flag, the outcome of each sync, i.e. its
+//! duration, the users processed, the changes made, failures, the
+//! Zitadel API calls made and the time spent waiting for the Zitadel
+//! user listing, is exported in the Prometheus text format,
+//! either to a file picked up by the textfile collector of the node
+//! exporter, or to a Pushgateway. This allows alerting on degrading
+//! syncs, which only run briefly and can't be scraped themselves.
+use std::{
+	collections::BTreeMap,
+	fmt::Write,
+	path::PathBuf,
+	sync::{Mutex, MutexGuard, PoisonError},
+	time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Context, Result};
+use chrono::Utc;
+use serde::Deserialize;
+use url::Url;
+
+use crate::{
+	report::{Operation, SyncReport},
+	watchdog,
+};
+
+/// The default job name of pushed metrics
+const DEFAULT_JOB: &str = "famedly_sync";
+
+/// The Zitadel API calls of the running sync, by call
+static API_CALLS: Mutex<BTreeMap<&'static str, u64>> = Mutex::new(BTreeMap::new());
+
+/// The time the running sync spent waiting for the Zitadel user
+/// listing
+static LISTING_WAIT: Mutex<Duration> = Mutex::new(Duration::ZERO);
+
+/// Configuration of where metrics are exported to
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct MetricsConfig {
+	/// Optional file to write the metrics to, for the textfile
+	/// collector of the node exporter. Its name must end in `.prom`.
+	pub textfile_path: Option<PathBuf>,
+	/// Optional URL of a Pushgateway to push the metrics to
+	pub pushgateway_url: Option<Url>,
+	/// The job name of pushed metrics
+	#[serde(default = "default_job")]
+	pub job: String,
+}
+
+/// Default for [`MetricsConfig::job`]
+fn default_job() -> String {
+	DEFAULT_JOB.to_owned()
+}
+
+/// Lock the API call counts, ignoring poisoning, since they are only
+/// used for metrics
+fn api_calls() -> MutexGuard<'static, BTreeMap<&'static str, u64>> {
+	API_CALLS.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Count a Zitadel API call
+pub(crate) fn count_api_call(call: &'static str) {
+	*api_calls().entry(call).or_default() += 1;
+}
+
+/// Lock the time spent waiting for the user listing, ignoring
+/// poisoning, since it is only used for metrics
+fn listing_wait() -> MutexGuard<'static, Duration> {
+	LISTING_WAIT.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Count time spent waiting for the Zitadel user listing
+pub(crate) fn record_listing_wait(wait: Duration) {
+	*listing_wait() += wait;
+}
+
+/// The metrics of a running sync
+#[derive(Debug)]
+pub(crate) struct MetricsRun {
+	/// When the sync started
+	started_at: Instant,
+	/// The number of users processed before the sync started
+	processed_before: u64,
+}
+
+impl MetricsRun {
+	/// Start collecting the metrics of a sync, discarding the API calls
+	/// and listing waits counted so far
+	pub(crate) fn start() -> Self {
+		api_calls().clear();
+		*listing_wait() = Duration::ZERO;
+		Self { started_at: Instant::now(), processed_before: watchdog::progress().1 }
+	}
+
+	/// Export the metrics of the finished sync
+	pub(crate) async fn finish(
+		self,
+		config: &MetricsConfig,
+		organization_id: &str,
+		report: Option<&SyncReport>,
+		success: bool,
+	) -> Result<()> {
+		let metrics = SyncMetrics {
+			finished_at: Utc::now().timestamp(),
+			success,
+			duration_seconds: self.started_at.elapsed().as_secs_f64(),
+			users_processed: watchdog::progress().1.saturating_sub(self.processed_before),
+			report,
+			api_calls: std::mem::take(&mut *api_calls()),
+			listing_wait_seconds: std::mem::take(&mut *listing_wait()).as_secs_f64(),
+		};
+		let text = metrics.render()?;
+
+		if let Some(path) = &config.textfile_path {
+			// Write to a temporary file first, so that the collector
+			// never reads a partially written file
+			let temporary_path = path.with_extension("tmp");
+			std::fs::write(&temporary_path, &text)
+				.context(format!("Failed to write metrics to {}", temporary_path.display()))?;
+			std::fs::rename(&temporary_path, path)
+				.context(format!("Failed to write metrics to {}", path.display()))?;
+		}
+
+		if let Some(pushgateway_url) = &config.pushgateway_url {
+			let mut url = pushgateway_url.clone();
+			url.path_segments_mut()
+				.map_err(|()| anyhow!("Invalid Pushgateway URL {}", pushgateway_url))?
+				.pop_if_empty()
+				.extend(["metrics", "job", config.job.as_str(), "organization", organization_id]);
+			reqwest::Client::new()
+				.put(url)
+				.body(text)
+				.send()
+				.await
+				.and_then(reqwest::Response::error_for_status)
+				.context("Failed to push metrics to the Pushgateway")?;
+		}
+
+		Ok(())
+	}
+}
+
+/// The metrics of a finished sync
+#[derive(Debug)]
+struct SyncMetrics<'a> {
+	/// When the sync finished, as a Unix timestamp
+	finished_at: i64,
+	/// Whether the sync succeeded
+	success: bool,
+	/// How long the sync took
+	duration_seconds: f64,
+	/// The number of users processed
+	users_processed: u64,
+	/// The report of the sync, unless it couldn't be finished
+	report: Option<&'a SyncReport>,
+	/// The Zitadel API calls made, by call
+	api_calls: BTreeMap<&'static str, u64>,
+	/// How long the sync waited for the Zitadel user listing
+	listing_wait_seconds: f64,
+}
+
+impl SyncMetrics<'_> {
+	/// Render the metrics in the Prometheus text format
+	fn render(&self) -> Result<String> {
+		let mut text = String::new();
+		let mut gauge = |name: &str, help: &str, samples: &[(Option<(&str, &str)>, String)]| {
+			writeln!(text, "# HELP famedly_sync_{name} {help}")?;
+			writeln!(text, "# TYPE famedly_sync_{name} gauge")?;
+			for (label, value) in samples {
+				match label {
+					Some((label, label_value)) => writeln!(
+						text,
+						"famedly_sync_{name}{{{label}=\"{}\"}} {value}",
+						escape(label_value)
+					)?,
+					None => writeln!(text, "famedly_sync_{name} {value}")?,
+				}
+			}
+			anyhow::Ok(())
+		};
+
+		gauge(
+			"last_run_timestamp_seconds",
+			"When the last sync finished, as a Unix timestamp",
+			&[(None, self.finished_at.to_string())],
+		)?;
+		gauge(
+			"last_run_success",
+			"Whether the last sync succeeded",
+			&[(None, u8::from(self.success).to_string())],
+		)?;
+		gauge(
+			"duration_seconds",
+			"How long the last sync took",
+			&[(None, format!("{:.3}", self.duration_seconds))],
+		)?;
+		gauge(
+			"users_processed",
+			"The number of users processed by the last sync",
+			&[(None, self.users_processed.to_string())],
+		)?;
+
+		if let Some(report) = self.report {
+			let counts = report.counts();
+			let operations = [
+				(Operation::Create, counts.created),
+				(Operation::Update, counts.updated),
+				(Operation::Rename, counts.renamed),
+				(Operation::Delete, counts.deleted),
+			];
+			gauge(
+				"operations",
+				"The number of users changed by the last sync, by operation",
+				&operations.map(|(operation, count)| {
+					(Some(("operation", operation.name())), count.to_string())
+				}),
+			)?;
+			gauge(
+				"failed_operations",
+				"The number of failed operations of the last sync, by operation",
+				&operations.map(|(operation, _)| {
+					let failed = report
+						.failures
+						.iter()
+						.filter(|failure| failure.operation == operation)
+						.count();
+					(Some(("operation", operation.name())), failed.to_string())
+				}),
+			)?;
+			gauge(
+				"skipped_users",
+				"The number of users whose changes the last sync skipped",
+				&[(None, counts.skipped.to_string())],
+			)?;
+		}
+
+		let api_calls: Vec<_> = self
+			.api_calls
+			.iter()
+			.map(|(call, count)| (Some(("call", *call)), count.to_string()))
+			.collect();
+		gauge("zitadel_api_calls", "The Zitadel API calls of the last sync, by call", &api_calls)?;
+		gauge(
+			"zitadel_listing_wait_seconds",
+			"How long the last sync waited for the Zitadel user listing",
+			&[(None, format!("{:.3}", self.listing_wait_seconds))],
+		)?;
+
+		Ok(text)
+	}
+}
+
+/// Escape a label value
+fn escape(value: &str) -> String {
+	value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_render_metrics() {
+		let report = SyncReport {
+			created: vec!["aa".to_owned(), "bb".to_owned()],
+			updated: vec!["cc".to_owned()],
+			..Default::default()
+		};
+		let metrics = SyncMetrics {
+			finished_at: 1_700_000_000,
+			success: true,
+			duration_seconds: 1.5,
+			users_processed: 3,
+			report: Some(&report),
+			api_calls: BTreeMap::from([("create user", 2), ("update \"user\"", 1)]),
+			listing_wait_seconds: 0.25,
+		};
+
+		let text = metrics.render().expect("failed to render metrics");
+		assert!(text.contains("# TYPE famedly_sync_duration_seconds gauge\n"));
+		assert!(text.contains("famedly_sync_last_run_success 1\n"));
+		assert!(text.contains("famedly_sync_duration_seconds 1.500\n"));
+		assert!(text.contains("famedly_sync_users_processed 3\n"));
+		assert!(text.contains("famedly_sync_operations{operation=\"create\"} 2\n"));
+		assert!(text.contains("famedly_sync_operations{operation=\"delete\"} 0\n"));
+		assert!(text.contains("famedly_sync_failed_operations{operation=\"update\"} 0\n"));
+		assert!(text.contains("famedly_sync_zitadel_api_calls{call=\"create user\"} 2\n"));
+		assert!(text.contains("famedly_sync_zitadel_api_calls{call=\"update \\\"user\\\"\"} 1\n"));
+		assert!(text.contains("famedly_sync_zitadel_listing_wait_seconds 0.250\n"));
+
+		// Without a report, only the outcome and API calls are known
+		let metrics = SyncMetrics { success: false, report: None, ..metrics };
+		let text = metrics.render().expect("failed to render metrics");
+		assert!(text.contains("famedly_sync_last_run_success 0\n"));
+		assert!(!text.contains("famedly_sync_operations"));
+	}
+}
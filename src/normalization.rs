@@ -0,0 +1,169 @@
+//! Normalized comparison of user attributes
+//!
+//! Sources and Zitadel often hold trivially different values, e.g.
+//! `JOHN.DOE@example.com` and `john.doe@example.com`, or names with
+//! trailing spaces from a CSV file. Compared verbatim, these would be
+//! written to Zitadel again on every sync. With comparison options
+//! configured for a field, values of the field which are equal after
+//! normalization count as unchanged, and the Zitadel value is kept.
+use std::collections::BTreeMap;
+
+use anyhow::{bail, Result};
+use serde::Deserialize;
+
+use crate::{drift, user::User};
+
+/// Attributes which can't be compared in normalized form, since they
+/// identify the user or aren't plain text
+const UNSUPPORTED_FIELDS: &[&str] = &["external_user_id", "enabled", "roles"];
+
+/// How to normalize the values of a field before comparing them
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+pub struct FieldComparison {
+	/// Whether to ignore differences in case
+	#[serde(default)]
+	pub ignore_case: bool,
+	/// Whether to ignore leading and trailing whitespace
+	#[serde(default)]
+	pub trim_whitespace: bool,
+	/// Whether to treat runs of whitespace within values as a single
+	/// space
+	#[serde(default)]
+	pub collapse_whitespace: bool,
+}
+
+impl FieldComparison {
+	/// Normalize a value of the field
+	fn normalize(&self, value: &str) -> String {
+		let mut value = value;
+		if self.trim_whitespace {
+			value = value.trim();
+		}
+
+		let value =
+			if self.collapse_whitespace { collapse_whitespace(value) } else { value.to_owned() };
+
+		if self.ignore_case {
+			value.to_lowercase()
+		} else {
+			value
+		}
+	}
+}
+
+/// Replace each run of whitespace in a value with a single space
+fn collapse_whitespace(value: &str) -> String {
+	let mut collapsed = String::with_capacity(value.len());
+	let mut in_whitespace = false;
+	for character in value.chars() {
+		if !character.is_whitespace() {
+			collapsed.push(character);
+		} else if !in_whitespace {
+			collapsed.push(' ');
+		}
+		in_whitespace = character.is_whitespace();
+	}
+
+	collapsed
+}
+
+/// Check the comparison options of the fields
+pub(crate) fn validate(comparison: &BTreeMap<String, FieldComparison>) -> Result<()> {
+	for field in comparison.keys() {
+		if UNSUPPORTED_FIELDS.contains(&field.as_str()) {
+			bail!("`{field}` can't be compared in normalized form");
+		}
+	}
+
+	Ok(())
+}
+
+/// Take the Zitadel values of the fields whose values only differ
+/// before normalization over into the source user, so that they
+/// aren't written to Zitadel
+pub(crate) fn reconcile(
+	comparison: &BTreeMap<String, FieldComparison>,
+	source_user: &mut User,
+	zitadel_user: &User,
+) {
+	for (field, options) in comparison {
+		let (Some(source_value), Some(zitadel_value)) =
+			(source_user.get_attribute(field), zitadel_user.get_attribute(field))
+		else {
+			continue;
+		};
+
+		if source_value != zitadel_value
+			&& options.normalize(&source_value) == options.normalize(&zitadel_value)
+		{
+			tracing::debug!(
+				"Keeping the {} of `{}`, which only differs before normalization",
+				field,
+				source_user.external_user_id
+			);
+			drift::take_value(source_user, zitadel_user, field);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn user(email: &str, first_name: &str) -> User {
+		User::new(
+			first_name.to_owned(),
+			"Doe".to_owned(),
+			email.to_owned(),
+			None,
+			true,
+			None,
+			"john.doe".to_owned(),
+			None,
+		)
+	}
+
+	#[test]
+	fn test_normalize() {
+		let options =
+			FieldComparison { ignore_case: true, trim_whitespace: true, collapse_whitespace: true };
+		assert_eq!(options.normalize("  Mary   Ann "), "mary ann");
+
+		let collapse_only = FieldComparison { collapse_whitespace: true, ..Default::default() };
+		assert_eq!(collapse_only.normalize(" Mary   Ann "), " Mary Ann ");
+
+		let trim_only = FieldComparison { trim_whitespace: true, ..Default::default() };
+		assert_eq!(trim_only.normalize(" Mary   Ann "), "Mary   Ann");
+	}
+
+	#[test]
+	fn test_reconcile() {
+		let comparison = BTreeMap::from([
+			("email".to_owned(), FieldComparison { ignore_case: true, ..Default::default() }),
+			(
+				"first_name".to_owned(),
+				FieldComparison { trim_whitespace: true, ..Default::default() },
+			),
+		]);
+		let zitadel_user = user("john.doe@example.com", "John");
+
+		let mut source_user = user("JOHN.DOE@example.com", "John ");
+		reconcile(&comparison, &mut source_user, &zitadel_user);
+		assert_eq!(source_user, zitadel_user);
+
+		let mut changed_user = user("JANE.DOE@example.com", "Jane");
+		reconcile(&comparison, &mut changed_user, &zitadel_user);
+		assert_eq!(changed_user.email, "JANE.DOE@example.com");
+		assert_eq!(changed_user.first_name, "Jane");
+	}
+
+	#[test]
+	fn test_validate() {
+		let comparison =
+			BTreeMap::from([("external_user_id".to_owned(), FieldComparison::default())]);
+		assert!(validate(&comparison).is_err());
+
+		let comparison = BTreeMap::from([("department".to_owned(), FieldComparison::default())]);
+		assert!(validate(&comparison).is_ok());
+	}
+}
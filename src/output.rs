@@ -0,0 +1,250 @@
+//! Output of the commands printing their results, such as
+//! `--explain-user`, `--plan` and `--compare-shadow`
+//!
+//! Results are printed as an aligned table for operators by default,
+//! or as JSON or CSV for further processing. On a terminal, the
+//! table's header is bold and rows are colored by the kind of change
+//! they describe.
+use std::str::FromStr;
+
+use anyhow::{bail, Result};
+
+/// The ANSI escape sequence starting bold text
+const BOLD: &str = "\x1b[1m";
+/// The ANSI escape sequence resetting the text style
+const RESET: &str = "\x1b[0m";
+
+/// The color of a row printed to a terminal
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Color {
+	/// For additions, such as users to be created
+	Green,
+	/// For modifications, such as users to be updated
+	Yellow,
+	/// For removals, such as users to be deleted
+	Red,
+}
+
+impl Color {
+	/// The ANSI escape sequence starting text in this color
+	fn escape(self) -> &'static str {
+		match self {
+			Self::Green => "\x1b[32m",
+			Self::Yellow => "\x1b[33m",
+			Self::Red => "\x1b[31m",
+		}
+	}
+}
+
+/// The format results are printed in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+	/// A table with aligned columns, for reading in a terminal
+	#[default]
+	Table,
+	/// A JSON array with an object per row
+	Json,
+	/// CSV with a header row
+	Csv,
+}
+
+impl FromStr for OutputFormat {
+	type Err = anyhow::Error;
+
+	fn from_str(format: &str) -> Result<Self> {
+		match format {
+			"table" => Ok(Self::Table),
+			"json" => Ok(Self::Json),
+			"csv" => Ok(Self::Csv),
+			format => bail!("Unknown output format `{format}`, expected `table`, `json` or `csv`"),
+		}
+	}
+}
+
+/// Rows of results with named columns
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Table {
+	/// The names of the columns
+	headers: Vec<&'static str>,
+	/// The rows, with a value per column
+	rows: Vec<Vec<String>>,
+	/// The colors of the rows on a terminal, by row
+	colors: Vec<Option<Color>>,
+}
+
+impl Table {
+	/// Create a table without rows
+	#[must_use]
+	pub fn new(headers: Vec<&'static str>) -> Self {
+		Self { headers, rows: Vec::new(), colors: Vec::new() }
+	}
+
+	/// Split a text of sections into a table of the lines of each
+	/// section. Sections start with an unindented line ending in a
+	/// colon, and are followed by their indented lines.
+	#[must_use]
+	pub fn from_sections(text: &str) -> Self {
+		let mut table = Self::new(vec!["section", "detail"]);
+		let mut section = "";
+		for line in text.lines().filter(|line| !line.trim().is_empty()) {
+			match line.strip_suffix(':') {
+				Some(title) if !line.starts_with(' ') => section = title,
+				_ => table.push(vec![section.to_owned(), line.trim().to_owned()]),
+			}
+		}
+		table
+	}
+
+	/// Add a row, with a value per column
+	pub fn push(&mut self, row: Vec<String>) {
+		self.rows.push(row);
+		self.colors.push(None);
+	}
+
+	/// Add a row, printed in the given color on a terminal
+	pub fn push_colored(&mut self, row: Vec<String>, color: Color) {
+		self.rows.push(row);
+		self.colors.push(Some(color));
+	}
+
+	/// The number of rows
+	#[must_use]
+	pub fn len(&self) -> usize {
+		self.rows.len()
+	}
+
+	/// Whether the table has no rows
+	#[must_use]
+	pub fn is_empty(&self) -> bool {
+		self.rows.is_empty()
+	}
+
+	/// Render the table in the given format. Tables for terminals are
+	/// rendered with a bold header and colored rows.
+	pub fn render(&self, format: OutputFormat, terminal: bool) -> Result<String> {
+		match format {
+			OutputFormat::Table => Ok(self.render_table(terminal)),
+			OutputFormat::Json => {
+				let rows: Vec<serde_json::Map<String, serde_json::Value>> = self
+					.rows
+					.iter()
+					.map(|row| {
+						self.headers
+							.iter()
+							.zip(row)
+							.map(|(header, value)| ((*header).to_owned(), value.clone().into()))
+							.collect()
+					})
+					.collect();
+				Ok(format!("{}\n", serde_json::to_string_pretty(&rows)?))
+			}
+			OutputFormat::Csv => {
+				let mut writer = csv::Writer::from_writer(Vec::new());
+				writer.write_record(&self.headers)?;
+				for row in &self.rows {
+					writer.write_record(row)?;
+				}
+				Ok(String::from_utf8(writer.into_inner()?)?)
+			}
+		}
+	}
+
+	/// Render the table with its columns aligned
+	fn render_table(&self, terminal: bool) -> String {
+		let mut widths: Vec<usize> =
+			self.headers.iter().map(|header| header.chars().count()).collect();
+		for row in &self.rows {
+			for (width, value) in widths.iter_mut().zip(row) {
+				*width = (*width).max(value.chars().count());
+			}
+		}
+
+		let line = |values: Vec<&str>| {
+			let cells: Vec<String> = values
+				.iter()
+				.zip(&widths)
+				.map(|(value, width)| format!("{value:<width$}"))
+				.collect();
+			cells.join("  ").trim_end().to_owned()
+		};
+
+		let header = line(self.headers.clone());
+		let mut out =
+			if terminal { format!("{BOLD}{header}{RESET}\n") } else { format!("{header}\n") };
+		for (row, color) in self.rows.iter().zip(&self.colors) {
+			let row = line(row.iter().map(String::as_str).collect());
+			match color {
+				Some(color) if terminal => {
+					out.push_str(&format!("{}{row}{RESET}\n", color.escape()));
+				}
+				_ => out.push_str(&format!("{row}\n")),
+			}
+		}
+		out
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn table() -> Table {
+		let mut table = Table::new(vec!["operation", "external_user_id"]);
+		table.push_colored(vec!["create".to_owned(), "jdoe".to_owned()], Color::Green);
+		table.push(vec!["delete".to_owned(), "max, mustermann".to_owned()]);
+		table
+	}
+
+	#[test]
+	fn test_render() {
+		let table = table();
+
+		assert_eq!(
+			table.render(OutputFormat::Table, false).expect("failed to render table"),
+			"operation  external_user_id\ncreate     jdoe\ndelete     max, mustermann\n"
+		);
+		assert_eq!(
+			table.render(OutputFormat::Table, true).expect("failed to render table"),
+			"\x1b[1moperation  external_user_id\x1b[0m\n\x1b[32mcreate     jdoe\x1b[0m\ndelete     \
+			 max, mustermann\n"
+		);
+		assert_eq!(
+			table.render(OutputFormat::Csv, false).expect("failed to render CSV"),
+			"operation,external_user_id\ncreate,jdoe\ndelete,\"max, mustermann\"\n"
+		);
+
+		let json: serde_json::Value = serde_json::from_str(
+			&table.render(OutputFormat::Json, false).expect("failed to render JSON"),
+		)
+		.expect("invalid JSON");
+		assert_eq!(
+			json,
+			serde_json::json!([
+				{ "operation": "create", "external_user_id": "jdoe" },
+				{ "operation": "delete", "external_user_id": "max, mustermann" },
+			])
+		);
+	}
+
+	#[test]
+	fn test_from_sections() {
+		let table = Table::from_sections(
+			"Explanation\n\nSource (CSV):\n  Found user\n\nOutcome:\n  Nothing happens\n",
+		);
+
+		assert_eq!(
+			table.rows,
+			vec![
+				vec![String::new(), "Explanation".to_owned()],
+				vec!["Source (CSV)".to_owned(), "Found user".to_owned()],
+				vec!["Outcome".to_owned(), "Nothing happens".to_owned()],
+			]
+		);
+	}
+
+	#[test]
+	fn test_output_format() {
+		assert_eq!("json".parse::<OutputFormat>().expect("invalid format"), OutputFormat::Json);
+		assert!("yaml".parse::<OutputFormat>().is_err());
+	}
+}
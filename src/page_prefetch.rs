@@ -0,0 +1,133 @@
+//! Prefetching of the pages of the Zitadel user listing
+//!
+//! Listing all Zitadel users takes one request per page, which the
+//! Zitadel client sends one after the other as the listing is consumed.
+//! Over high-latency links to hosted Zitadel, most of a listing's time
+//! is spent waiting for these requests. With prefetching, the pages
+//! following the one being consumed are requested concurrently, and
+//! their users are yielded in the original order.
+use std::{future::Future, time::Instant};
+
+use anyhow::Result;
+use futures::{future, stream, Stream, StreamExt};
+
+use crate::metrics;
+
+/// Stream the items of a paginated listing, requesting up to the
+/// given number of pages ahead of the one being consumed
+///
+/// Pages are numbered from 0. The listing ends with the first page
+/// holding fewer than `page_size` items, or with the first error.
+pub(crate) fn prefetch_pages<T, F, Fut>(
+	page_size: usize,
+	pages_ahead: usize,
+	fetch: F,
+) -> impl Stream<Item = Result<T>> + Unpin
+where
+	F: FnMut(usize) -> Fut,
+	Fut: Future<Output = Result<Vec<T>>>,
+{
+	let page_size = page_size.max(1);
+
+	let pages =
+		stream::iter(0..).map(fetch).buffered(pages_ahead + 1).scan(false, move |done, page| {
+			if *done {
+				return future::ready(None);
+			}
+			// Pages beyond the end may already be requested, but are
+			// never consumed
+			*done = page.as_ref().map_or(true, |items| items.len() < page_size);
+			future::ready(Some(page))
+		});
+
+	Box::pin(pages.flat_map(|page| {
+		let items: Vec<Result<T>> = match page {
+			Ok(items) => items.into_iter().map(Ok).collect(),
+			Err(error) => vec![Err(error)],
+		};
+		stream::iter(items)
+	}))
+}
+
+/// Record the time spent waiting for the items of a listing in the
+/// metrics, which shows how much prefetching shortens the listing
+pub(crate) fn time_waits<S>(stream: S) -> impl Stream<Item = S::Item> + Unpin
+where
+	S: Stream + Unpin,
+{
+	Box::pin(stream::unfold(stream, |mut stream| async move {
+		let started_at = Instant::now();
+		let item = stream.next().await;
+		metrics::record_listing_wait(started_at.elapsed());
+		item.map(|item| (item, stream))
+	}))
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::{
+		atomic::{AtomicUsize, Ordering},
+		Mutex,
+	};
+
+	use super::*;
+
+	/// Fetch a page of a listing of the given number of items, taking
+	/// longer for earlier pages, so that later pages finish first
+	async fn fetch(
+		page: usize,
+		page_size: usize,
+		total: usize,
+		in_flight: &AtomicUsize,
+		fetched: &Mutex<Vec<usize>>,
+	) -> Result<Vec<usize>> {
+		let concurrent = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+		for _ in 0..(10_usize.saturating_sub(page)) {
+			tokio::task::yield_now().await;
+		}
+		in_flight.fetch_sub(1, Ordering::SeqCst);
+		fetched.lock().expect("poisoned lock").push(concurrent);
+
+		Ok((page * page_size..total.min((page + 1) * page_size)).collect())
+	}
+
+	#[tokio::test]
+	async fn test_prefetch_pages() {
+		for (page_size, pages_ahead, total) in [(3, 0, 10), (3, 2, 10), (5, 4, 20), (4, 8, 3)] {
+			let in_flight = AtomicUsize::new(0);
+			let fetched = Mutex::new(Vec::new());
+
+			let items: Vec<usize> = prefetch_pages(page_size, pages_ahead, |page| {
+				fetch(page, page_size, total, &in_flight, &fetched)
+			})
+			.map(|item| item.expect("failed to fetch page"))
+			.collect()
+			.await;
+
+			// Items keep their order
+			assert_eq!(items, (0..total).collect::<Vec<_>>());
+
+			// No more than the requested number of pages run at once
+			let fetched = fetched.into_inner().expect("poisoned lock");
+			assert!(fetched.iter().all(|concurrent| *concurrent <= pages_ahead + 1));
+			assert!(fetched.len() >= total.div_ceil(page_size));
+		}
+	}
+
+	#[tokio::test]
+	async fn test_prefetch_pages_error() {
+		let items: Vec<Result<usize>> = prefetch_pages(2, 3, |page| async move {
+			match page {
+				0 => Ok(vec![0, 1]),
+				1 => anyhow::bail!("page {page} failed"),
+				_ => Ok(vec![page * 2, page * 2 + 1]),
+			}
+		})
+		.collect()
+		.await;
+
+		// The listing ends with the first error
+		assert_eq!(items.len(), 3);
+		assert!(items[2].is_err());
+	}
+}
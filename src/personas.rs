@@ -0,0 +1,250 @@
+//! Merging of the personas of a person
+//!
+//! Some staff have several entries in the source, e.g. a clinical and
+//! an administrative persona, which should map to a single Zitadel
+//! account. Entries sharing the value of the configured key attribute,
+//! e.g. the employee number, are merged into one user, which is granted
+//! the roles of all personas. The merged user takes the external ID and
+//! fields of its primary persona, and the others fill in what it lacks.
+//! Attributes the personas disagree on are reported.
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+use serde::Deserialize;
+
+use crate::{
+	messages::{Language, Message},
+	rules::Expression,
+	sources::merged::merge_fields,
+	user::User,
+};
+
+/// The user fields compared between personas, besides their metadata
+const COMPARED_FIELDS: &[&str] =
+	&["first_name", "last_name", "email", "phone", "preferred_username", "localpart"];
+
+/// Configuration of the merging of personas
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct PersonaMergeConfig {
+	/// The attribute identifying the person behind the personas, e.g.
+	/// `employee_number`
+	pub key: String,
+	/// The condition the primary persona matches, e.g.
+	/// `department != "Administration"`. Defaults to the persona with
+	/// the lowest external ID.
+	pub primary_when: Option<Expression>,
+}
+
+/// A user merged from personas which disagree on some attributes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MergeConflict {
+	/// The external ID of the merged user, i.e. of its primary persona
+	pub external_user_id: String,
+	/// The external IDs of the merged personas, the one the fields are
+	/// taken from first
+	pub personas: Vec<String>,
+	/// The attributes the personas disagree on
+	pub attributes: Vec<String>,
+}
+
+impl MergeConflict {
+	/// Describe the conflict for the operator
+	pub(crate) fn message(&self, language: Language) -> String {
+		Message::PersonaConflict {
+			external_user_id: &self.external_user_id,
+			personas: &self.personas,
+			attributes: &self.attributes,
+		}
+		.render(language)
+	}
+}
+
+/// Merge the source users sharing the value of the key attribute,
+/// keeping the users sorted by external ID. Returns the conflicts of
+/// the merged users.
+pub(crate) fn merge_personas(
+	config: &PersonaMergeConfig,
+	users: &mut VecDeque<User>,
+) -> Vec<MergeConflict> {
+	let mut persons: BTreeMap<String, Vec<User>> = BTreeMap::new();
+	let mut merged = VecDeque::with_capacity(users.len());
+
+	// Entries which failed to parse only carry their external ID, so
+	// they can't be told to belong to a person
+	for user in users.drain(..) {
+		match user.get_attribute(&config.key).filter(|key| !key.is_empty() && !user.is_unparsable())
+		{
+			Some(key) => persons.entry(key).or_default().push(user),
+			None => merged.push_back(user),
+		}
+	}
+
+	let mut conflicts = Vec::new();
+	for personas in persons.into_values() {
+		if personas.len() == 1 {
+			merged.extend(personas);
+			continue;
+		}
+
+		let (user, conflict) = merge(config, personas);
+		merged.push_back(user);
+		conflicts.extend(conflict);
+	}
+
+	merged.make_contiguous().sort_by(|a, b| a.external_user_id.cmp(&b.external_user_id));
+	*users = merged;
+	conflicts
+}
+
+/// Merge the personas of a person into one user
+fn merge(config: &PersonaMergeConfig, mut personas: Vec<User>) -> (User, Option<MergeConflict>) {
+	let is_primary = |persona: &User| {
+		config
+			.primary_when
+			.as_ref()
+			.is_some_and(|when| when.evaluate(|name| persona.get_attribute(name)))
+	};
+
+	// The primary persona is chosen among all personas, so that the
+	// external ID of the merged user doesn't change when a persona is
+	// disabled or enabled
+	let external_user_id =
+		personas[personas.iter().position(is_primary).unwrap_or(0)].external_user_id.clone();
+	// The merged user changes whenever any of its personas does
+	let versions: Option<Vec<_>> =
+		personas.iter().map(|persona| persona.source_version.clone()).collect();
+
+	// Disabled personas only count if the person has no enabled one.
+	// If the primary persona doesn't count, the fields are taken from
+	// the first one which would be primary among the others.
+	if personas.iter().any(|persona| persona.enabled) {
+		personas.retain(|persona| persona.enabled);
+	}
+	let first = personas
+		.iter()
+		.position(|persona| persona.external_user_id == external_user_id)
+		.or_else(|| personas.iter().position(is_primary))
+		.unwrap_or(0);
+	let mut user = personas.remove(first);
+
+	let mut external_user_ids = vec![user.external_user_id.clone()];
+	user.external_user_id = external_user_id;
+	let mut attributes = BTreeSet::new();
+	for persona in personas {
+		let names = COMPARED_FIELDS.iter().map(|name| (*name).to_owned());
+		for name in names.chain(persona.metadata.keys().cloned()) {
+			if let (Some(value), Some(other)) = (
+				user.get_attribute(&name).filter(|value| !value.is_empty()),
+				persona.get_attribute(&name).filter(|value| !value.is_empty()),
+			) {
+				if value != other {
+					attributes.insert(name);
+				}
+			}
+		}
+
+		external_user_ids.push(persona.external_user_id.clone());
+		merge_fields(&mut user, persona);
+	}
+
+	user.source_version = versions.map(|versions| versions.join(","));
+
+	tracing::debug!(
+		"Merged the personas {} into user `{}`",
+		external_user_ids.join(", "),
+		user.external_user_id
+	);
+	let conflict = (!attributes.is_empty()).then(|| MergeConflict {
+		external_user_id: user.external_user_id.clone(),
+		personas: external_user_ids,
+		attributes: attributes.into_iter().collect(),
+	});
+
+	(user, conflict)
+}
+
+#[cfg(test)]
+mod tests {
+	use indoc::indoc;
+
+	use super::*;
+
+	fn persona(external_user_id: &str, department: &str, roles: &[&str]) -> User {
+		let mut user = User::new(
+			"John".to_owned(),
+			"Doe".to_owned(),
+			"john.doe@example.com".to_owned(),
+			None,
+			true,
+			None,
+			external_user_id.to_owned(),
+			None,
+		);
+		user.metadata.insert("employee_number".to_owned(), "1234".to_owned());
+		user.metadata.insert("department".to_owned(), department.to_owned());
+		user.roles = roles.iter().map(|role| (*role).to_owned()).collect();
+		user
+	}
+
+	#[test]
+	fn test_merge_personas() {
+		let config: PersonaMergeConfig = serde_yaml::from_str(indoc! {r#"
+			key: employee_number
+			primary_when: 'department == "Radiology"'
+		"#})
+		.expect("invalid persona config");
+
+		let mut admin = persona("a-jdoe", "Administration", &["Admin"]);
+		admin.phone = Some("+1111111111".to_owned());
+		let clinical = persona("c-jdoe", "Radiology", &["Physician"]);
+		let mut other = persona("b-mmuster", "IT", &[]);
+		other.metadata.insert("employee_number".to_owned(), "5678".to_owned());
+		let mut users = VecDeque::from(vec![admin, other, clinical]);
+
+		let conflicts = merge_personas(&config, &mut users);
+
+		assert_eq!(users.len(), 2);
+		assert_eq!(users[0].external_user_id, "b-mmuster");
+		let merged = &users[1];
+		assert_eq!(merged.external_user_id, "c-jdoe");
+		assert_eq!(merged.get_attribute("department").as_deref(), Some("Radiology"));
+		assert_eq!(merged.phone.as_deref(), Some("+1111111111"));
+		assert_eq!(merged.roles, BTreeSet::from(["Admin".to_owned(), "Physician".to_owned()]));
+		assert_eq!(
+			conflicts,
+			vec![MergeConflict {
+				external_user_id: "c-jdoe".to_owned(),
+				personas: vec!["c-jdoe".to_owned(), "a-jdoe".to_owned()],
+				attributes: vec!["department".to_owned()],
+			}]
+		);
+	}
+
+	#[test]
+	fn test_merge_disabled_personas() {
+		let config = PersonaMergeConfig { key: "employee_number".to_owned(), primary_when: None };
+
+		let mut disabled = persona("a-jdoe", "Administration", &["Admin"]);
+		disabled.enabled = false;
+		disabled.source_version = Some("1".to_owned());
+		let mut enabled = persona("c-jdoe", "Administration", &["Physician"]);
+		enabled.source_version = Some("2".to_owned());
+		let mut users = VecDeque::from(vec![disabled.clone(), enabled.clone()]);
+
+		// Only the enabled persona counts, but the merged user keeps the
+		// external ID of the primary persona
+		assert!(merge_personas(&config, &mut users).is_empty());
+		assert_eq!(users.len(), 1);
+		assert!(users[0].enabled);
+		assert_eq!(users[0].external_user_id, "a-jdoe");
+		assert_eq!(users[0].roles, BTreeSet::from(["Physician".to_owned()]));
+		assert_eq!(users[0].source_version.as_deref(), Some("1,2"));
+
+		// Without a primary condition, the persona with the lowest
+		// external ID is primary, and both versions are tracked
+		disabled.enabled = true;
+		let mut users = VecDeque::from(vec![disabled, enabled]);
+		assert!(merge_personas(&config, &mut users).is_empty());
+		assert_eq!(users[0].external_user_id, "a-jdoe");
+		assert_eq!(users[0].source_version.as_deref(), Some("1,2"));
+	}
+}
@@ -0,0 +1,58 @@
+//! Staged rollouts to pilot groups
+//!
+//! Messengers are often rolled out group by group, e.g. ward by ward.
+//! In pilot mode, the sync only writes to users matching the pilot
+//! condition. Changes to all other users are reported as drift, but
+//! not applied, so that the sync can be validated against the full
+//! source while only the pilot group is onboarded.
+use serde::Deserialize;
+
+use crate::{rules::Expression, user::User};
+
+/// Configuration of the pilot mode
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct PilotConfig {
+	/// The condition users in the pilot group match, e.g.
+	/// `department in ["Radiology", "IT"]`
+	pub when: Expression,
+}
+
+/// Whether changes to the given user are applied, which is the case
+/// for all users outside of pilot mode
+pub(crate) fn includes(pilot: Option<&PilotConfig>, user: &User) -> bool {
+	pilot.map_or(true, |pilot| pilot.when.evaluate(|name| user.get_attribute(name)))
+}
+
+#[cfg(test)]
+mod tests {
+	use indoc::indoc;
+
+	use super::*;
+
+	#[test]
+	fn test_includes() {
+		let pilot: PilotConfig = serde_yaml::from_str(indoc! {r#"
+			when: 'department in ["Radiology", "IT"]'
+		"#})
+		.expect("invalid pilot config");
+
+		let mut user = User::new(
+			"John".to_owned(),
+			"Doe".to_owned(),
+			"john.doe@example.com".to_owned(),
+			None,
+			true,
+			None,
+			"john.doe".to_owned(),
+			None,
+		);
+		assert!(includes(None, &user));
+		assert!(!includes(Some(&pilot), &user));
+
+		user.metadata.insert("department".to_owned(), "Radiology".to_owned());
+		assert!(includes(Some(&pilot), &user));
+
+		user.metadata.insert("department".to_owned(), "Cardiology".to_owned());
+		assert!(!includes(Some(&pilot), &user));
+	}
+}
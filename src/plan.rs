@@ -0,0 +1,573 @@
+//! Plans of the changes a sync would make
+//!
+//! `--plan` computes the users a sync would create, update and delete,
+//! without changing anything, and writes them to a plan file, e.g. for
+//! a change-approval workflow. Plans are written as JSON, or as YAML if
+//! the file name ends in `.yaml` or `.yml`. `--apply-plan` executes a
+//! plan, skipping the changes to users which changed in Zitadel since
+//! the plan was made. Plans record when the source data they were
+//! computed from was read, and with `max_plan_age_minutes`, plans of
+//! older data are refused, since the source may have changed since.
+use std::{
+	collections::{BTreeMap, VecDeque},
+	path::Path,
+};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::Instrument;
+
+use crate::{
+	apply_pending_changes,
+	change_budget::ChangeBudget,
+	complete_zitadel_user, get_next_zitadel_user, get_source,
+	import_throttle::ImportThrottle,
+	normalization,
+	output::{Color, Table},
+	pilot, prepare_source_users,
+	report::{DeletionListing, Operation, Reporter},
+	resources, spans,
+	user::User,
+	user_cache, watchdog,
+	zitadel::Zitadel,
+	Config, DeletionEvidence, FeatureFlag, PendingChanges,
+};
+
+/// The changes a sync would make to the Zitadel users of an
+/// organization
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Plan {
+	/// The Zitadel organization the plan is meant for
+	pub organization_id: String,
+	/// When the plan was made, in RFC 3339 format
+	pub planned_at: String,
+	/// When the source data the plan was computed from was last
+	/// changed, if the source knows it, or else read, in RFC 3339
+	/// format. Unset in plans of earlier versions.
+	#[serde(default)]
+	pub source_snapshot_at: Option<String>,
+	/// The fingerprint of the configuration the plan was computed with
+	#[serde(default)]
+	pub config_fingerprint: String,
+	/// The planned changes
+	pub changes: Vec<PlannedChange>,
+}
+
+/// A change to a single Zitadel user
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case", tag = "operation")]
+pub enum PlannedChange {
+	/// Import a source user
+	Create {
+		/// The user to import
+		user: User,
+	},
+	/// Update a Zitadel user to match its source user
+	Update {
+		/// The Zitadel ID of the user
+		zitadel_id: String,
+		/// The attributes changed by the update
+		changed_fields: Vec<String>,
+		/// The user as it was in Zitadel when the plan was made
+		current: User,
+		/// The user after the update
+		user: User,
+	},
+	/// Delete a Zitadel user missing from the source
+	Delete {
+		/// The Zitadel ID of the user
+		zitadel_id: String,
+		/// The user as it was in Zitadel when the plan was made
+		current: User,
+	},
+}
+
+impl Plan {
+	/// Load a plan file
+	fn load(path: &Path) -> Result<Self> {
+		let plan =
+			std::fs::read(path).context(format!("Failed to read plan file {}", path.display()))?;
+		let plan = if is_yaml(path) {
+			serde_yaml::from_slice(&plan).map_err(anyhow::Error::from)
+		} else {
+			serde_json::from_slice(&plan).map_err(anyhow::Error::from)
+		};
+		plan.context(format!("Invalid plan file {}", path.display()))
+	}
+
+	/// Check that the source data the plan was computed from is at
+	/// most the given number of minutes old
+	fn check_age(&self, max_age_minutes: u64, now: DateTime<Utc>) -> Result<()> {
+		let snapshot_at = self
+			.source_snapshot_at
+			.as_deref()
+			.context("The plan doesn't record when its source data was read")?;
+		let snapshot_at = DateTime::parse_from_rfc3339(snapshot_at)
+			.context(format!("Invalid source snapshot time `{snapshot_at}` in the plan"))?
+			.with_timezone(&Utc);
+
+		let age = now - snapshot_at;
+		let max_age = Duration::minutes(i64::try_from(max_age_minutes).unwrap_or(i64::MAX));
+		if age > max_age {
+			anyhow::bail!(
+				"The plan was computed from source data of {}, which is {} minutes old, more \
+				 than the `max_plan_age_minutes` of {}; compute a new plan",
+				snapshot_at.to_rfc3339(),
+				age.num_minutes(),
+				max_age_minutes
+			);
+		}
+
+		Ok(())
+	}
+
+	/// The users the plan deletes, up to the given number
+	fn deletion_listing(&self, limit: usize) -> DeletionListing {
+		let mut listing = DeletionListing::default();
+		for change in &self.changes {
+			if let PlannedChange::Delete { zitadel_id, current } = change {
+				listing.push(limit, current, zitadel_id);
+			}
+		}
+		listing
+	}
+
+	/// List the planned changes, without the values of the users.
+	/// Rows are colored by operation on a terminal.
+	fn summary(&self) -> Table {
+		let mut summary =
+			Table::new(vec!["operation", "external_user_id", "zitadel_id", "changed_fields"]);
+		for change in &self.changes {
+			let (row, color) = match change {
+				PlannedChange::Create { user } => (
+					vec![
+						"create".to_owned(),
+						user.external_user_id.clone(),
+						String::new(),
+						String::new(),
+					],
+					Color::Green,
+				),
+				PlannedChange::Update { zitadel_id, changed_fields, user, .. } => (
+					vec![
+						"update".to_owned(),
+						user.external_user_id.clone(),
+						zitadel_id.clone(),
+						changed_fields.join(", "),
+					],
+					Color::Yellow,
+				),
+				PlannedChange::Delete { zitadel_id, current } => (
+					vec![
+						"delete".to_owned(),
+						current.external_user_id.clone(),
+						zitadel_id.clone(),
+						String::new(),
+					],
+					Color::Red,
+				),
+			};
+			summary.push_colored(row, color);
+		}
+		summary
+	}
+
+	/// Write the plan to a file
+	fn save(&self, path: &Path) -> Result<()> {
+		let plan = if is_yaml(path) {
+			serde_yaml::to_string(self)?
+		} else {
+			serde_json::to_string_pretty(self)?
+		};
+		std::fs::write(path, plan).context(format!("Failed to write plan file {}", path.display()))
+	}
+}
+
+/// Whether a plan file is written in YAML, rather than JSON
+fn is_yaml(path: &Path) -> bool {
+	path.extension().is_some_and(|extension| extension == "yaml" || extension == "yml")
+}
+
+/// Compute the changes a sync would make, without making them
+pub async fn compute_plan(config: &Config) -> Result<Plan> {
+	let source = get_source(config)?;
+	let queried_at = Utc::now();
+	let mut users: VecDeque<User> = source
+		.get_sorted_users()
+		.await
+		.context(format!("Failed to query users from {}", source.get_name()))?
+		.into();
+	let source_snapshot_at = source
+		.snapshot_time()
+		.await
+		.context(format!("Failed to read the snapshot time of {}", source.get_name()))?
+		.unwrap_or(queried_at);
+
+	let (_, persona_conflicts) = prepare_source_users(config, &mut users)?;
+	for conflict in &persona_conflicts {
+		tracing::warn!("{}", conflict.message(config.language));
+	}
+	// Disabled users are treated as deleted
+	users.retain(|user| user.enabled);
+
+	let mut zitadel = Zitadel::new(config).await?;
+	let mut stream = zitadel.list_users()?;
+	let mut existing = BTreeMap::new();
+	while let Some((user, zitadel_id)) = get_next_zitadel_user(&mut stream, &mut zitadel).await? {
+		existing.insert(user.external_user_id.clone(), (user, zitadel_id));
+	}
+
+	Ok(Plan {
+		organization_id: config.zitadel.organization_id.clone(),
+		planned_at: Utc::now().to_rfc3339(),
+		source_snapshot_at: Some(source_snapshot_at.to_rfc3339()),
+		config_fingerprint: config.fingerprint.clone(),
+		changes: plan_changes(config, users, existing),
+	})
+}
+
+/// Compute the changes a sync would make and write them to a plan
+/// file, returning a summary of the changes
+pub async fn plan_sync(config: &Config, path: &Path) -> Result<Table> {
+	let plan = compute_plan(config).await?;
+	plan.save(path)?;
+
+	tracing::info!("Planned {} changes to {}", plan.changes.len(), path.display());
+	plan.deletion_listing(config.reporting.deletion_listing_limit).log();
+
+	Ok(plan.summary())
+}
+
+/// Compare the source users with the Zitadel users, given by external
+/// ID along with their Zitadel IDs
+///
+/// Differences are normalized as by a sync, but the drift and
+/// self-service policies aren't applied, since they depend on the
+/// state of the sync at the time the plan is applied.
+fn plan_changes(
+	config: &Config,
+	users: VecDeque<User>,
+	mut existing: BTreeMap<String, (User, String)>,
+) -> Vec<PlannedChange> {
+	let mut changes = Vec::new();
+
+	for mut user in users {
+		// Users lacking a required attribute or whose entries failed to
+		// parse are skipped, and their Zitadel counterparts are kept as
+		// they are
+		if user.unreadable.is_some() {
+			existing.remove(&user.external_user_id);
+			continue;
+		}
+
+		let Some((current, zitadel_id)) = existing.remove(&user.external_user_id) else {
+			changes.push(PlannedChange::Create { user });
+			continue;
+		};
+
+		if current.preferred_username_unknown
+			|| (user.preferred_username.is_none() && !config.syncs_preferred_username())
+		{
+			user.preferred_username.clone_from(&current.preferred_username);
+		}
+		normalization::reconcile(&config.comparison, &mut user, &current);
+
+		if user != current {
+			let changed_fields =
+				current.diff(&user).into_iter().map(|(field, _, _)| field).collect();
+			changes.push(PlannedChange::Update { zitadel_id, changed_fields, current, user });
+		}
+	}
+
+	changes.extend(
+		existing
+			.into_values()
+			.map(|(current, zitadel_id)| PlannedChange::Delete { zitadel_id, current }),
+	);
+
+	changes
+}
+
+/// Execute the changes of a plan file
+pub async fn apply_plan(config: &Config, path: &Path) -> Result<()> {
+	let plan = Plan::load(path)?;
+	if plan.organization_id != config.zitadel.organization_id {
+		anyhow::bail!(
+			"The plan file {} is meant for organization `{}`, not `{}`",
+			path.display(),
+			plan.organization_id,
+			config.zitadel.organization_id
+		);
+	}
+	if let Some(max_age_minutes) = config.max_plan_age_minutes {
+		plan.check_age(max_age_minutes, Utc::now())
+			.context(format!("Refusing to apply the plan file {}", path.display()))?;
+	}
+	if !plan.config_fingerprint.is_empty() && plan.config_fingerprint != config.fingerprint {
+		tracing::warn!(
+			"The plan file {} was computed with a different configuration",
+			path.display()
+		);
+	}
+	tracing::info!("Applying {} changes planned at {}", plan.changes.len(), plan.planned_at);
+
+	let dry_run = config.feature_flags.is_enabled(FeatureFlag::DryRun);
+	if !dry_run {
+		user_cache::invalidate(config)?;
+	}
+
+	let mut reporter = Reporter::new(&config.reporting, dry_run)
+		.with_deletions_dry_run(config.feature_flags.is_enabled(FeatureFlag::DryRunDeletions))
+		.with_language(config.language)
+		.with_config_fingerprint(&config.fingerprint);
+
+	let result = resources::run_with_monitoring(
+		config.resource_monitoring.as_ref(),
+		watchdog::run_with_watchdog(
+			config.watchdog.as_ref(),
+			apply_changes(config, &mut reporter, plan.changes).instrument(spans::run_span("plan")),
+		),
+	)
+	.await;
+
+	// Always finish the report, so that aborted runs are documented as
+	// well
+	if let Err(error) = reporter.finish() {
+		if result.is_ok() {
+			return Err(error);
+		}
+		tracing::error!("Failed to write sync report: {:?}", error);
+	}
+
+	result
+}
+
+/// Execute planned changes, skipping those of users which changed in
+/// Zitadel since the plan was made
+async fn apply_changes(
+	config: &Config,
+	reporter: &mut Reporter,
+	changes: Vec<PlannedChange>,
+) -> Result<()> {
+	watchdog::set_phase("checking Zitadel configuration");
+	let mut zitadel = Zitadel::new(config).await?;
+	zitadel.preflight().await?;
+
+	watchdog::set_phase("applying plan");
+	let pilot = config.pilot.as_ref();
+	let mut change_budget = ChangeBudget::new(config.max_changes_per_run);
+	let mut pending = PendingChanges::default();
+
+	for change in changes {
+		match change {
+			PlannedChange::Create { user } => pending.imports.push(user),
+
+			PlannedChange::Update { zitadel_id, changed_fields, current, user } => {
+				let external_user_id = user.external_user_id.clone();
+				if !is_unchanged(&mut zitadel, &zitadel_id, &current).await? {
+					reporter.record(
+						Operation::Update,
+						Some(&external_user_id),
+						Some(&zitadel_id),
+						&Err(anyhow::anyhow!("User changed in Zitadel since the plan was made")),
+					);
+				} else if !pilot::includes(pilot, &user) {
+					reporter.record_pilot_drift(
+						Operation::Update,
+						Some(&external_user_id),
+						Some(&zitadel_id),
+						changed_fields,
+					);
+				} else if change_budget.exhausted() {
+					reporter.record_deferred(
+						Operation::Update,
+						Some(&external_user_id),
+						Some(&zitadel_id),
+					);
+				} else {
+					change_budget.spend();
+					let span = spans::user_span(
+						Operation::Update,
+						Some(&external_user_id),
+						Some(&zitadel_id),
+					);
+					let res =
+						zitadel.update_user(&zitadel_id, &current, &user).instrument(span).await;
+					reporter.record_update(&external_user_id, &zitadel_id, changed_fields, &res);
+				}
+			}
+
+			PlannedChange::Delete { zitadel_id, current } => {
+				if is_unchanged(&mut zitadel, &zitadel_id, &current).await? {
+					pending.deletions.push((current, zitadel_id));
+				} else {
+					reporter.record(
+						Operation::Delete,
+						Some(&current.external_user_id),
+						Some(&zitadel_id),
+						&Err(anyhow::anyhow!("User changed in Zitadel since the plan was made")),
+					);
+				}
+			}
+		}
+	}
+
+	apply_pending_changes(
+		config,
+		&mut zitadel,
+		reporter,
+		&mut ImportThrottle::default(),
+		&mut change_budget,
+		pending.detect_renames(config),
+		&DeletionEvidence::default(),
+	)
+	.await;
+
+	zitadel.wait_for_projections().await;
+
+	Ok(())
+}
+
+/// Whether a Zitadel user is still as it was when the plan was made
+async fn is_unchanged(zitadel: &mut Zitadel, zitadel_id: &str, planned: &User) -> Result<bool> {
+	let Some(user) = zitadel.get_user(zitadel_id).await? else {
+		return Ok(false);
+	};
+
+	Ok(complete_zitadel_user(zitadel, user, zitadel_id).await?.is_some_and(|user| user == *planned))
+}
+
+#[cfg(test)]
+mod tests {
+	use indoc::indoc;
+
+	use super::*;
+	use crate::user::Unreadable;
+
+	const EXAMPLE_CONFIG: &str = indoc! {r#"
+        zitadel:
+          url: http://localhost:8080
+          key_file: tests/environment/zitadel/service-user.json
+          organization_id: 1
+          project_id: 1
+          idp_id: 1
+
+        sources:
+          csv:
+            file_path: ./test_users.csv
+    "#};
+
+	fn user(external_user_id: &str, email: &str) -> User {
+		User::new(
+			"John".to_owned(),
+			"Doe".to_owned(),
+			email.to_owned(),
+			None,
+			true,
+			None,
+			external_user_id.to_owned(),
+			None,
+		)
+	}
+
+	#[test]
+	fn test_plan_changes() {
+		let config: Config = serde_yaml::from_str(EXAMPLE_CONFIG).expect("invalid config");
+		// Users lacking a required attribute are neither updated nor
+		// deleted
+		let mut skipped = user("e", "");
+		skipped.unreadable = Some(Unreadable::MissingAttribute("email".to_owned()));
+		let users = VecDeque::from([
+			user("a", "a@example.com"),
+			user("b", "b@example.com"),
+			user("c", "c@example.org"),
+			skipped,
+		]);
+		let existing = BTreeMap::from([
+			("b".to_owned(), (user("b", "b@example.com"), "2".to_owned())),
+			("c".to_owned(), (user("c", "c@example.com"), "3".to_owned())),
+			("d".to_owned(), (user("d", "d@example.com"), "4".to_owned())),
+			("e".to_owned(), (user("e", "e@example.com"), "5".to_owned())),
+		]);
+
+		let changes = plan_changes(&config, users, existing);
+		assert_eq!(changes.len(), 3);
+		assert!(
+			matches!(&changes[0], PlannedChange::Create { user } if user.external_user_id == "a")
+		);
+		assert!(matches!(
+			&changes[1],
+			PlannedChange::Update { zitadel_id, changed_fields, .. }
+				if zitadel_id == "3" && changed_fields == &vec!["email".to_owned()]
+		));
+		assert!(
+			matches!(&changes[2], PlannedChange::Delete { zitadel_id, .. } if zitadel_id == "4")
+		);
+	}
+
+	#[test]
+	fn test_plan_unknown_preferred_username() {
+		let config: Config = serde_yaml::from_str(EXAMPLE_CONFIG).expect("invalid config");
+		let mut new = user("a", "a@example.com");
+		new.preferred_username = Some("a@example.com".to_owned());
+		// The preferred username of the Zitadel user failed to be read
+		let mut current = user("a", "a@example.com");
+		current.preferred_username_unknown = true;
+		let existing = BTreeMap::from([("a".to_owned(), (current, "1".to_owned()))]);
+
+		let changes = plan_changes(&config, VecDeque::from([new]), existing);
+		assert!(changes.is_empty());
+	}
+
+	#[test]
+	fn test_plan_round_trip() {
+		let dir = tempfile::TempDir::new().expect("failed to create tempdir");
+		let plan = Plan {
+			organization_id: "1".to_owned(),
+			planned_at: Utc::now().to_rfc3339(),
+			source_snapshot_at: Some(Utc::now().to_rfc3339()),
+			config_fingerprint: String::new(),
+			changes: vec![
+				PlannedChange::Create { user: user("a", "a@example.com") },
+				PlannedChange::Delete {
+					zitadel_id: "4".to_owned(),
+					current: user("d", "d@example.com"),
+				},
+			],
+		};
+
+		for file_name in ["plan.json", "plan.yaml"] {
+			let path = dir.path().join(file_name);
+			plan.save(&path).expect("failed to save plan");
+
+			let loaded = Plan::load(&path).expect("failed to load plan");
+			assert_eq!(loaded.organization_id, "1");
+			assert_eq!(loaded.changes.len(), 2);
+			assert_eq!(loaded.deletion_listing(10).users[0].zitadel_id, "4");
+			assert!(matches!(
+				&loaded.changes[0],
+				PlannedChange::Create { user } if user.email == "a@example.com"
+			));
+		}
+	}
+
+	#[test]
+	fn test_plan_age() {
+		let now = Utc::now();
+		let mut plan = Plan {
+			organization_id: "1".to_owned(),
+			planned_at: now.to_rfc3339(),
+			source_snapshot_at: Some((now - Duration::minutes(90)).to_rfc3339()),
+			config_fingerprint: String::new(),
+			changes: Vec::new(),
+		};
+
+		assert!(plan.check_age(120, now).is_ok());
+		assert!(plan.check_age(60, now).is_err());
+
+		// Plans of earlier versions don't record the age of their data
+		plan.source_snapshot_at = None;
+		assert!(plan.check_age(120, now).is_err());
+	}
+}
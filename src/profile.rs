@@ -0,0 +1,71 @@
+//! Named profiles bundling the safety and policy options
+//!
+//! Most deployments don't need to weigh each of the options guarding
+//! against mass deletions, implausible source data and manual changes
+//! in Zitadel. A profile provides defaults for all of them, which the
+//! configuration file and env vars override option by option, e.g. to
+//! raise a single limit of an otherwise strict profile.
+use serde::Deserialize;
+
+/// The defaults of the `strict` profile
+const STRICT_DEFAULTS: &str = "
+initial_sync: require_confirmation
+source_user_count_check:
+  min_expected_users: 1
+deletion_guard:
+  max_deletions_percent: 5
+user_count_check:
+  tolerance: 0
+second_factor_protection:
+  check: true
+  max_deletions: 0
+drift:
+  zitadel_changes: report
+watchdog:
+  stall_timeout_minutes: 30
+";
+
+/// The defaults of the `balanced` profile
+const BALANCED_DEFAULTS: &str = "
+initial_sync: dry_run
+source_user_count_check:
+  min_expected_users: 1
+deletion_guard:
+  max_deletions_percent: 20
+second_factor_protection:
+  check: true
+watchdog:
+  stall_timeout_minutes: 60
+";
+
+/// The defaults of the `legacy` profile
+const LEGACY_DEFAULTS: &str = "
+second_factor_protection:
+  check: false
+";
+
+/// A named set of defaults for the safety and policy options
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncProfile {
+	/// Refuse the first sync unless confirmed, abort syncs deleting
+	/// more than 5% of the users or any user with second factors, and
+	/// keep manual changes in Zitadel, listing them in the report
+	Strict,
+	/// Dry-run the first sync unless confirmed, and abort syncs
+	/// deleting more than 20% of the users or an empty source
+	Balanced,
+	/// Behave as syncs did before the safety options were introduced
+	Legacy,
+}
+
+impl SyncProfile {
+	/// The options the profile sets, as YAML
+	pub(crate) fn defaults(self) -> &'static str {
+		match self {
+			Self::Strict => STRICT_DEFAULTS,
+			Self::Balanced => BALANCED_DEFAULTS,
+			Self::Legacy => LEGACY_DEFAULTS,
+		}
+	}
+}
@@ -0,0 +1,131 @@
+//! Rate limiting of Zitadel requests
+//!
+//! Zitadel rejects clients exceeding its rate limits, which large
+//! imports and the listing of large organizations easily do. With
+//! `zitadel.max_requests_per_second` set, requests wait for a token of
+//! a token bucket, which holds up to `zitadel.request_burst` tokens and
+//! is refilled at the configured rate. Clones of the Zitadel client
+//! share the bucket, so concurrent writes are limited together.
+use std::{
+	pin::Pin,
+	sync::{Arc, Mutex, PoisonError},
+	time::{Duration, Instant},
+};
+
+use futures::{stream, Stream, StreamExt};
+
+/// A token bucket limiting the rate of requests
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+	/// The number of tokens added per second
+	rate: f64,
+	/// The maximum number of tokens
+	burst: f64,
+	/// The state of the bucket
+	bucket: Mutex<Bucket>,
+}
+
+/// The state of a token bucket
+#[derive(Debug)]
+struct Bucket {
+	/// The number of available tokens. It is negative if tokens were
+	/// reserved by requests which are still waiting.
+	tokens: f64,
+	/// When the tokens were last refilled
+	refilled_at: Instant,
+}
+
+impl RateLimiter {
+	/// Create a rate limiter allowing the given number of requests per
+	/// second, and bursts of up to `burst` requests, starting full
+	pub(crate) fn new(requests_per_second: u32, burst: u32) -> Self {
+		Self {
+			rate: f64::from(requests_per_second),
+			burst: f64::from(burst),
+			bucket: Mutex::new(Bucket { tokens: f64::from(burst), refilled_at: Instant::now() }),
+		}
+	}
+
+	/// Wait until a request may be sent
+	pub(crate) async fn acquire(&self) {
+		let wait = self.reserve(Instant::now());
+		if !wait.is_zero() {
+			tracing::debug!("Waiting {}ms for the Zitadel rate limit", wait.as_millis());
+			tokio::time::sleep(wait).await;
+		}
+	}
+
+	/// Take a token at the given time, returning how long to wait until
+	/// it is available
+	fn reserve(&self, now: Instant) -> Duration {
+		let mut bucket = self.bucket.lock().unwrap_or_else(PoisonError::into_inner);
+		let elapsed = now.saturating_duration_since(bucket.refilled_at).as_secs_f64();
+		bucket.tokens = elapsed.mul_add(self.rate, bucket.tokens).min(self.burst);
+		bucket.refilled_at = bucket.refilled_at.max(now);
+		bucket.tokens -= 1.0;
+
+		if bucket.tokens < 0.0 {
+			Duration::from_secs_f64(-bucket.tokens / self.rate)
+		} else {
+			Duration::ZERO
+		}
+	}
+}
+
+/// Limit the requests of a paginated listing, which are only sent
+/// while it is consumed, by waiting for a token before each page
+pub(crate) fn throttle_pages<S>(
+	rate_limiter: Option<Arc<RateLimiter>>,
+	page_size: usize,
+	stream: S,
+) -> impl Stream<Item = S::Item> + Unpin
+where
+	S: Stream,
+{
+	let page_size = page_size.max(1);
+	let stream: Pin<Box<S>> = Box::pin(stream);
+
+	Box::pin(stream::unfold((stream, 0_usize), move |(mut stream, consumed)| {
+		let rate_limiter = rate_limiter.clone();
+		async move {
+			if consumed % page_size == 0 {
+				if let Some(rate_limiter) = rate_limiter {
+					rate_limiter.acquire().await;
+				}
+			}
+			let item = stream.next().await?;
+			Some((item, (stream, consumed + 1)))
+		}
+	}))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_reserve() {
+		let rate_limiter = RateLimiter::new(10, 3);
+		let start = rate_limiter.bucket.lock().expect("poisoned bucket").refilled_at;
+
+		// The burst is available immediately
+		for _ in 0..3 {
+			assert_eq!(rate_limiter.reserve(start), Duration::ZERO);
+		}
+		// Further requests queue up, 100ms apart
+		assert_eq!(rate_limiter.reserve(start), Duration::from_millis(100));
+		assert_eq!(rate_limiter.reserve(start), Duration::from_millis(200));
+
+		// The reserved tokens are used up after 200ms, so half a token
+		// is available 50ms later
+		let later = start + Duration::from_millis(250);
+		assert_eq!(rate_limiter.reserve(later), Duration::from_millis(50));
+
+		// Idle time refills the bucket up to the burst only
+		let idle = later + Duration::from_secs(60);
+		for _ in 0..3 {
+			assert_eq!(rate_limiter.reserve(idle), Duration::ZERO);
+		}
+		assert_eq!(rate_limiter.reserve(idle), Duration::from_millis(100));
+	}
+}
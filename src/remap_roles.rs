@@ -0,0 +1,153 @@
+//! Bulk replacement of project roles
+//!
+//! When a project role is renamed, e.g. when the product renames the
+//! role granted to all users from `User` to `MessengerUser`, the grants
+//! of all managed users need to be updated. This replaces the old role
+//! key with the new one in the project grants of all users within the
+//! user scope.
+use anyhow::Result;
+use tracing::Instrument;
+
+use crate::{
+	get_next_zitadel_user,
+	report::{Operation, Reporter},
+	resources, spans, user_cache, watchdog,
+	zitadel::Zitadel,
+	Config, FeatureFlag,
+};
+
+/// How often to log progress, in users
+const PROGRESS_INTERVAL: usize = 100;
+
+/// Replace the project role `from` with `to` for all managed users
+pub async fn remap_roles(config: &Config, from: &str, to: &str) -> Result<()> {
+	if from == to {
+		anyhow::bail!("The role `{from}` can't be remapped to itself");
+	}
+
+	if from == config.zitadel.user_role {
+		tracing::warn!(
+			"The sync grants `{}` to all users; set `zitadel.user_role` to `{}`, or the next sync \
+			 grants it again",
+			from,
+			to
+		);
+	}
+
+	let dry_run = config.feature_flags.is_enabled(FeatureFlag::DryRun);
+	if !dry_run {
+		user_cache::invalidate(config)?;
+	}
+
+	let mut reporter = Reporter::new(&config.reporting, dry_run)
+		.with_language(config.language)
+		.with_config_fingerprint(&config.fingerprint);
+
+	let result = resources::run_with_monitoring(
+		config.resource_monitoring.as_ref(),
+		watchdog::run_with_watchdog(
+			config.watchdog.as_ref(),
+			remap_all(config, &mut reporter, from, to)
+				.instrument(spans::run_span(spans::source_name(config))),
+		),
+	)
+	.await;
+
+	// Always finish the report, so that aborted runs are documented as
+	// well
+	let report = reporter.finish();
+	result?;
+
+	let report = report?;
+	if !report.failures.is_empty() {
+		anyhow::bail!("Failed to remap the roles of {} users", report.failures.len());
+	}
+
+	Ok(())
+}
+
+/// Replace the role for all users within the user scope
+async fn remap_all(config: &Config, reporter: &mut Reporter, from: &str, to: &str) -> Result<()> {
+	let mut zitadel = Zitadel::new(config).await?;
+	zitadel.preflight().await?;
+
+	watchdog::set_phase("remapping roles");
+	let mut stream = zitadel.list_users()?;
+	let mut checked = 0;
+	let mut remapped = 0;
+
+	while let Some((user, zitadel_id)) = get_next_zitadel_user(&mut stream, &mut zitadel).await? {
+		watchdog::record_progress(&user.external_user_id);
+
+		let span =
+			spans::user_span(Operation::Update, Some(&user.external_user_id), Some(&zitadel_id));
+		match zitadel.remap_role(&zitadel_id, from, to).instrument(span.clone()).await {
+			Ok(false) => {}
+			Ok(true) => {
+				remapped += 1;
+				reporter.record_update(
+					&user.external_user_id,
+					&zitadel_id,
+					vec!["roles".to_owned()],
+					&Ok(()),
+				);
+			}
+			Err(error) => {
+				span.in_scope(|| tracing::error!("Failed to remap roles: {:?}", error));
+				reporter.record_update(
+					&user.external_user_id,
+					&zitadel_id,
+					vec!["roles".to_owned()],
+					&Err(error),
+				);
+			}
+		}
+
+		checked += 1;
+		if checked % PROGRESS_INTERVAL == 0 {
+			tracing::info!("Checked {} users, remapped the roles of {}", checked, remapped);
+		}
+	}
+
+	tracing::info!("Checked {} users, remapped the roles of {}", checked, remapped);
+
+	Ok(())
+}
+
+/// Replace the role `from` with `to` in the role keys of a grant,
+/// returning `None` if the grant doesn't contain `from`
+pub(crate) fn remap_role_keys(role_keys: &[String], from: &str, to: &str) -> Option<Vec<String>> {
+	if !role_keys.iter().any(|role| role == from) {
+		return None;
+	}
+
+	let mut remapped: Vec<String> =
+		role_keys.iter().filter(|role| *role != from).cloned().collect();
+	if !remapped.iter().any(|role| role == to) {
+		remapped.push(to.to_owned());
+	}
+
+	Some(remapped)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn roles(roles: &[&str]) -> Vec<String> {
+		roles.iter().map(|role| (*role).to_owned()).collect()
+	}
+
+	#[test]
+	fn test_remap_role_keys() {
+		assert_eq!(
+			remap_role_keys(&roles(&["User", "Admin"]), "User", "MessengerUser"),
+			Some(roles(&["Admin", "MessengerUser"]))
+		);
+		assert_eq!(
+			remap_role_keys(&roles(&["User", "MessengerUser"]), "User", "MessengerUser"),
+			Some(roles(&["MessengerUser"]))
+		);
+		assert_eq!(remap_role_keys(&roles(&["Admin"]), "User", "MessengerUser"), None);
+	}
+}
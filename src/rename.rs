@@ -0,0 +1,191 @@
+//! Detection of users whose external ID changed
+//!
+//! The sync matches users by external ID, so a user whose external ID
+//! changed in the source looks like a deleted user and an unrelated
+//! new one. Deleting and re-creating the user would lose its grants,
+//! metadata and Zitadel ID. Instead, imports and deletions are paired
+//! up by other attributes identifying the user, and such pairs are
+//! renamed in place.
+use anyhow::{bail, Result};
+use serde::Deserialize;
+
+use crate::user::User;
+
+/// Configuration of the detection of renamed external IDs
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct RenameDetectionConfig {
+	/// The attributes which must match for a deleted and a new user to
+	/// be considered the same user
+	#[serde(default = "default_match_keys")]
+	pub match_keys: Vec<String>,
+}
+
+/// Default for [`RenameDetectionConfig::match_keys`]
+fn default_match_keys() -> Vec<String> {
+	vec!["email".to_owned()]
+}
+
+impl RenameDetectionConfig {
+	/// Check that the users can be matched by the configured keys
+	pub fn validate(&self) -> Result<()> {
+		if self.match_keys.is_empty() {
+			bail!("Rename detection requires at least one match key");
+		}
+
+		if self.match_keys.iter().any(|key| key == "external_user_id") {
+			bail!("Rename detection can't match users by `external_user_id`");
+		}
+
+		Ok(())
+	}
+}
+
+/// A user whose external ID changed
+#[derive(Debug, Clone)]
+pub struct Rename {
+	/// The user as it is in Zitadel
+	pub old_user: User,
+	/// The user as it is in the source
+	pub new_user: User,
+	/// The Zitadel ID of the user
+	pub zitadel_id: String,
+}
+
+/// Pair up users to import with users to delete whose configured
+/// match keys are all set and equal. Users matching more than one
+/// counterpart are ambiguous and left alone. Returns the renames, and
+/// the remaining imports and deletions.
+#[must_use]
+pub fn detect_renames(
+	config: &RenameDetectionConfig,
+	imports: Vec<User>,
+	deletions: Vec<(User, String)>,
+) -> (Vec<Rename>, Vec<User>, Vec<(User, String)>) {
+	let matches = |new_user: &User, old_user: &User| {
+		config.match_keys.iter().all(|key| {
+			match (new_user.get_attribute(key), old_user.get_attribute(key)) {
+				(Some(new_value), Some(old_value)) => {
+					!new_value.is_empty() && new_value == old_value
+				}
+				_ => false,
+			}
+		})
+	};
+
+	let counterparts: Vec<Vec<usize>> = imports
+		.iter()
+		.map(|new_user| {
+			deletions
+				.iter()
+				.enumerate()
+				.filter(|(_, (old_user, _))| matches(new_user, old_user))
+				.map(|(index, _)| index)
+				.collect()
+		})
+		.collect();
+
+	let mut pairs = Vec::new();
+	for (import_index, candidates) in counterparts.iter().enumerate() {
+		let [deletion_index] = candidates.as_slice() else {
+			if candidates.len() > 1 {
+				tracing::warn!(
+					"Not renaming user `{}`, since it matches {} deleted users",
+					imports[import_index].external_user_id,
+					candidates.len()
+				);
+			}
+			continue;
+		};
+
+		let competing = counterparts.iter().filter(|other| other.contains(deletion_index)).count();
+		if competing > 1 {
+			tracing::warn!(
+				"Not renaming user `{}`, since {} new users match it",
+				deletions[*deletion_index].0.external_user_id,
+				competing
+			);
+			continue;
+		}
+
+		pairs.push((import_index, *deletion_index));
+	}
+
+	let mut imports: Vec<Option<User>> = imports.into_iter().map(Some).collect();
+	let mut deletions: Vec<Option<(User, String)>> = deletions.into_iter().map(Some).collect();
+
+	let renames = pairs
+		.into_iter()
+		.filter_map(|(import_index, deletion_index)| {
+			let new_user = imports[import_index].take()?;
+			let (old_user, zitadel_id) = deletions[deletion_index].take()?;
+			Some(Rename { old_user, new_user, zitadel_id })
+		})
+		.collect();
+
+	(renames, imports.into_iter().flatten().collect(), deletions.into_iter().flatten().collect())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn user(external_user_id: &str, email: &str) -> User {
+		User::new(
+			"Jane".to_owned(),
+			"Doe".to_owned(),
+			email.to_owned(),
+			None,
+			true,
+			None,
+			external_user_id.to_owned(),
+			None,
+		)
+	}
+
+	#[test]
+	fn test_detect_renames() {
+		let config = RenameDetectionConfig { match_keys: default_match_keys() };
+
+		let imports = vec![
+			user("aa", "renamed@example.com"),
+			user("bb", "new@example.com"),
+			user("cc", "shared@example.com"),
+			user("dd", "shared@example.com"),
+		];
+		let deletions = vec![
+			(user("ff", "gone@example.com"), "1".to_owned()),
+			(user("ee", "renamed@example.com"), "2".to_owned()),
+			(user("99", "shared@example.com"), "3".to_owned()),
+		];
+
+		let (renames, imports, deletions) = detect_renames(&config, imports, deletions);
+
+		assert_eq!(renames.len(), 1);
+		assert_eq!(renames[0].old_user.external_user_id, "ee");
+		assert_eq!(renames[0].new_user.external_user_id, "aa");
+		assert_eq!(renames[0].zitadel_id, "2");
+
+		// Ambiguous matches are imported and deleted as usual
+		assert_eq!(
+			imports.iter().map(|user| user.external_user_id.as_str()).collect::<Vec<_>>(),
+			vec!["bb", "cc", "dd"]
+		);
+		assert_eq!(
+			deletions.iter().map(|(_, zitadel_id)| zitadel_id.as_str()).collect::<Vec<_>>(),
+			vec!["1", "3"]
+		);
+	}
+
+	#[test]
+	fn test_validate() {
+		assert!(RenameDetectionConfig { match_keys: Vec::new() }.validate().is_err());
+		assert!(RenameDetectionConfig { match_keys: vec!["external_user_id".to_owned()] }
+			.validate()
+			.is_err());
+		assert!(RenameDetectionConfig {
+			match_keys: vec!["email".to_owned(), "localpart".to_owned()]
+		}
+		.validate()
+		.is_ok());
+	}
+}
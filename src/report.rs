@@ -0,0 +1,1647 @@
+//! Reporting and auditing of sync operations
+use std::{
+	collections::BTreeMap,
+	fs::OpenOptions,
+	io::Write,
+	path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+mod html;
+
+pub use html::{HtmlRedaction, HtmlRenderer};
+
+use crate::{
+	drift::DriftClass,
+	latency::{self, UserLatency},
+	messages::{Language, Message},
+	personas::MergeConflict,
+	user::{Unreadable, User},
+	version_check::VersionNotice,
+	watchdog,
+};
+
+/// The default number of operations after which report data is
+/// flushed to disk
+const DEFAULT_FLUSH_INTERVAL: usize = 100;
+
+/// The default number of slowest users to list in the report
+const DEFAULT_SLOW_USER_COUNT: usize = 10;
+
+/// The default number of users a dry run would delete to list
+const DEFAULT_DELETION_LISTING_LIMIT: usize = 100;
+
+/// Configuration for sync reports and audit logs
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct ReportingConfig {
+	/// Path to write a JSON report of the sync to
+	pub report_path: Option<PathBuf>,
+	/// Path to write an HTML summary of the sync to, once it finished
+	pub html_report_path: Option<PathBuf>,
+	/// Personal data to leave out of the HTML summary
+	#[serde(default)]
+	pub html_redaction: HtmlRedaction,
+	/// Path to a file to append a JSON line to for each write
+	/// operation
+	pub audit_log_path: Option<PathBuf>,
+	/// Path to a file to append a JSON line with the metadata and
+	/// project roles of each user to before deleting it
+	pub deletion_archive_path: Option<PathBuf>,
+	/// Path to a file to append a JSON line to before and after each
+	/// write, naming the writes a crash may have applied partially
+	pub intent_log_path: Option<PathBuf>,
+	/// The number of operations after which the report and audit log
+	/// are flushed to disk
+	#[serde(default = "default_flush_interval")]
+	pub flush_interval: usize,
+	/// The number of users taking the longest to reconcile to list in
+	/// the report
+	#[serde(default = "default_slow_user_count")]
+	pub slow_user_count: usize,
+	/// The time in milliseconds reconciling a single user should take
+	/// at most. Users taking longer are logged.
+	pub latency_budget_ms: Option<u64>,
+	/// The number of users a dry run or plan would delete to list in a
+	/// separate section of the report and the log
+	#[serde(default = "default_deletion_listing_limit")]
+	pub deletion_listing_limit: usize,
+}
+
+impl Default for ReportingConfig {
+	fn default() -> Self {
+		Self {
+			report_path: None,
+			html_report_path: None,
+			html_redaction: HtmlRedaction::default(),
+			audit_log_path: None,
+			deletion_archive_path: None,
+			intent_log_path: None,
+			flush_interval: DEFAULT_FLUSH_INTERVAL,
+			slow_user_count: DEFAULT_SLOW_USER_COUNT,
+			latency_budget_ms: None,
+			deletion_listing_limit: DEFAULT_DELETION_LISTING_LIMIT,
+		}
+	}
+}
+
+/// Default for [`ReportingConfig::flush_interval`]
+fn default_flush_interval() -> usize {
+	DEFAULT_FLUSH_INTERVAL
+}
+
+/// Default for [`ReportingConfig::slow_user_count`]
+fn default_slow_user_count() -> usize {
+	DEFAULT_SLOW_USER_COUNT
+}
+
+/// Default for [`ReportingConfig::deletion_listing_limit`]
+fn default_deletion_listing_limit() -> usize {
+	DEFAULT_DELETION_LISTING_LIMIT
+}
+
+/// A write operation against Zitadel
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Operation {
+	/// A user was imported
+	Create,
+	/// A user was updated
+	Update,
+	/// A user was deleted
+	Delete,
+	/// The external ID of a user was changed
+	Rename,
+}
+
+impl Operation {
+	/// The name of the operation, as it appears in reports and logs
+	#[must_use]
+	pub fn name(self) -> &'static str {
+		match self {
+			Operation::Create => "create",
+			Operation::Update => "update",
+			Operation::Delete => "delete",
+			Operation::Rename => "rename",
+		}
+	}
+}
+
+/// The build of the sync and the configuration it ran with
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct BuildInfo {
+	/// The version of the sync
+	pub version: String,
+	/// The git commit the sync was built from
+	pub git_sha: String,
+	/// The fingerprint of the configuration, with secrets redacted
+	pub config_fingerprint: String,
+}
+
+impl BuildInfo {
+	/// The build of the running sync, with the given configuration
+	/// fingerprint
+	fn new(config_fingerprint: &str) -> Self {
+		Self {
+			version: env!("CARGO_PKG_VERSION").to_owned(),
+			git_sha: env!("VERGEN_GIT_SHA").to_owned(),
+			config_fingerprint: config_fingerprint.to_owned(),
+		}
+	}
+}
+
+/// A record of a single write operation
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditRecord {
+	/// When the operation finished
+	pub timestamp: String,
+	/// The build and configuration which made the change
+	#[serde(flatten)]
+	pub build: BuildInfo,
+	/// The kind of operation
+	pub operation: Operation,
+	/// The external ID of the affected user, if known
+	pub external_user_id: Option<String>,
+	/// The Zitadel ID of the affected user, if known
+	pub zitadel_id: Option<String>,
+	/// The attributes changed by an update
+	#[serde(skip_serializing_if = "Vec::is_empty")]
+	pub changed_fields: Vec<String>,
+	/// Why the user was deleted
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub deletion_reason: Option<DeletionReason>,
+	/// The error the operation failed with, if any
+	pub error: Option<String>,
+	/// A description of the failure in the configured language
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub message: Option<String>,
+}
+
+/// Why a user was deleted
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case", tag = "reason")]
+pub enum DeletionReason {
+	/// The source doesn't contain the user
+	NotInSource,
+	/// The user is disabled in the source
+	DisabledInSource,
+	/// The user is a guest whose access expired
+	GuestExpired,
+	/// The user is excluded from the sync by a rule
+	ExcludedByRule {
+		/// The name of the excluding rule
+		rule: String,
+	},
+	/// The UKT source lists the user as removed
+	RemovedInUkt,
+	/// A SCIM client deleted the user
+	DeletedViaScim,
+}
+
+/// Summary of a sync run
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SyncReport {
+	/// When the sync started
+	pub started_at: String,
+	/// The build and configuration of the sync
+	#[serde(flatten)]
+	pub build: BuildInfo,
+	/// When the sync finished, unset while the sync is in progress
+	pub finished_at: Option<String>,
+	/// How long the sync took in milliseconds, unset while the sync is
+	/// in progress
+	pub duration_ms: Option<u64>,
+	/// A summary of the sync in the configured language, unset while
+	/// the sync is in progress
+	pub summary: Option<String>,
+	/// Whether the sync ran without writing to Zitadel
+	pub dry_run: bool,
+	/// Whether the sync only logged deletions instead of deleting
+	/// users, in which case the deleted users still exist
+	pub deletions_dry_run: bool,
+	/// Set if the installed sync is outdated, according to the version
+	/// check
+	pub version_notice: Option<VersionNotice>,
+	/// External IDs of imported users
+	pub created: Vec<String>,
+	/// External IDs of updated users
+	pub updated: Vec<String>,
+	/// The attributes changed by updates, by external user ID
+	pub changed_fields: BTreeMap<String, Vec<String>>,
+	/// The number of updates changing each attribute
+	pub field_change_counts: BTreeMap<String, usize>,
+	/// Zitadel IDs of deleted users
+	pub deleted: Vec<String>,
+	/// Why each user was deleted, by Zitadel ID
+	pub deletion_reasons: BTreeMap<String, DeletionReason>,
+	/// The users a dry run would have deleted, for review
+	pub deletion_listing: DeletionListing,
+	/// Users whose external ID was changed
+	pub renamed: Vec<RenamedUser>,
+	/// Operations which failed
+	pub failures: Vec<AuditRecord>,
+	/// Zitadel IDs of users without an email address
+	pub users_without_email: Vec<String>,
+	/// Source users which already exist in Zitadel, but aren't managed
+	/// by the sync, so they weren't imported
+	pub unmanaged: Vec<UnmanagedUser>,
+	/// Links to the configured IDP not matching any source user
+	pub stale_idp_links: Vec<StaleIdpLink>,
+	/// Changes to users outside the pilot group, which weren't applied
+	pub pilot_drift: Vec<PilotDrift>,
+	/// Changes beyond `max_changes_per_run`, which are left to later
+	/// syncs
+	pub deferred: Vec<DeferredChange>,
+	/// Users slated for deletion despite registered second factors
+	pub second_factor_users: Vec<SecondFactorUser>,
+	/// Source users skipped since they lack a required attribute
+	pub users_missing_attributes: Vec<UserMissingAttribute>,
+	/// Source users whose entries failed to parse, which were left
+	/// untouched
+	pub unparsable_users: Vec<UnparsableUser>,
+	/// New users whose email address is still bound to another Zitadel
+	/// user, so they weren't imported
+	pub email_conflicts: Vec<EmailConflict>,
+	/// New users whose email address belongs to a user of another
+	/// Zitadel organization, so they weren't imported
+	pub cross_org_conflicts: Vec<CrossOrgConflict>,
+	/// New users whose user ID, username or email address is already
+	/// taken by another Zitadel user, so they weren't imported
+	pub import_collisions: Vec<ImportCollision>,
+	/// Users merged from personas which disagree on some attributes
+	pub persona_conflicts: Vec<PersonaConflict>,
+	/// Changes users made to their own accounts without approval,
+	/// which were overwritten
+	pub self_service_drift: Vec<SelfServiceDrift>,
+	/// Differences kept in Zitadel due to the drift policies
+	pub drift: Vec<Drift>,
+	/// The users taking the longest to reconcile, slowest first
+	pub slowest_users: Vec<UserLatency>,
+	/// The number of users taking longer to reconcile than the
+	/// latency budget
+	pub users_over_latency_budget: usize,
+}
+
+impl SyncReport {
+	/// The number of users in each outcome of the sync
+	#[must_use]
+	pub fn counts(&self) -> SyncCounts {
+		SyncCounts {
+			created: self.created.len(),
+			updated: self.updated.len(),
+			renamed: self.renamed.len(),
+			deleted: self.deleted.len(),
+			skipped: self.skipped().len(),
+			failed: self.failures.len(),
+		}
+	}
+
+	/// The users whose changes were skipped, and why
+	#[must_use]
+	pub fn skipped(&self) -> Vec<SkippedUser> {
+		let deferred = self.deferred.iter().map(|change| SkippedUser {
+			external_user_id: change.external_user_id.clone(),
+			zitadel_id: change.zitadel_id.clone(),
+			reason: SkipReason::Deferred,
+		});
+		let pilot_drift = self.pilot_drift.iter().map(|drift| SkippedUser {
+			external_user_id: drift.external_user_id.clone(),
+			zitadel_id: drift.zitadel_id.clone(),
+			reason: SkipReason::OutsidePilot,
+		});
+		let unmanaged = self.unmanaged.iter().map(|user| SkippedUser {
+			external_user_id: Some(user.external_user_id.clone()),
+			zitadel_id: Some(user.zitadel_id.clone()),
+			reason: SkipReason::Unmanaged,
+		});
+		let without_email = self.users_without_email.iter().map(|zitadel_id| SkippedUser {
+			external_user_id: None,
+			zitadel_id: Some(zitadel_id.clone()),
+			reason: SkipReason::NoEmail,
+		});
+		let second_factors =
+			self.second_factor_users.iter().filter(|user| user.held_back).map(|user| SkippedUser {
+				external_user_id: Some(user.external_user_id.clone()),
+				zitadel_id: Some(user.zitadel_id.clone()),
+				reason: SkipReason::SecondFactors,
+			});
+
+		let missing_attributes = self.users_missing_attributes.iter().map(|user| SkippedUser {
+			external_user_id: Some(user.external_user_id.clone()),
+			zitadel_id: user.zitadel_id.clone(),
+			reason: SkipReason::MissingAttribute,
+		});
+		let unparsable = self.unparsable_users.iter().map(|user| SkippedUser {
+			external_user_id: Some(user.external_user_id.clone()),
+			zitadel_id: user.zitadel_id.clone(),
+			reason: SkipReason::ParseFailure,
+		});
+		let email_conflicts = self.email_conflicts.iter().map(|conflict| SkippedUser {
+			external_user_id: Some(conflict.external_user_id.clone()),
+			zitadel_id: None,
+			reason: SkipReason::EmailConflict,
+		});
+		let cross_org_conflicts = self.cross_org_conflicts.iter().map(|conflict| SkippedUser {
+			external_user_id: Some(conflict.external_user_id.clone()),
+			zitadel_id: None,
+			reason: SkipReason::CrossOrgConflict,
+		});
+		let import_collisions = self.import_collisions.iter().map(|collision| SkippedUser {
+			external_user_id: Some(collision.external_user_id.clone()),
+			zitadel_id: None,
+			reason: SkipReason::ImportCollision,
+		});
+
+		deferred
+			.chain(pilot_drift)
+			.chain(unmanaged)
+			.chain(without_email)
+			.chain(second_factors)
+			.chain(missing_attributes)
+			.chain(unparsable)
+			.chain(email_conflicts)
+			.chain(cross_org_conflicts)
+			.chain(import_collisions)
+			.collect()
+	}
+}
+
+/// The number of users in each outcome of a sync
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct SyncCounts {
+	/// The number of imported users
+	pub created: usize,
+	/// The number of updated users
+	pub updated: usize,
+	/// The number of renamed users
+	pub renamed: usize,
+	/// The number of deleted users
+	pub deleted: usize,
+	/// The number of users whose changes were skipped
+	pub skipped: usize,
+	/// The number of failed operations
+	pub failed: usize,
+}
+
+/// A user whose changes were skipped
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SkippedUser {
+	/// The external ID of the user, unless it is only known in Zitadel
+	pub external_user_id: Option<String>,
+	/// The Zitadel ID of the user, unless it would have been created
+	pub zitadel_id: Option<String>,
+	/// Why the changes were skipped
+	pub reason: SkipReason,
+}
+
+/// Why the changes to a user were skipped
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipReason {
+	/// The changes are beyond `max_changes_per_run`
+	Deferred,
+	/// The user is outside the pilot group
+	OutsidePilot,
+	/// The user exists in Zitadel, but isn't managed by the sync
+	Unmanaged,
+	/// The user has no email address
+	NoEmail,
+	/// The deletion of the user was held back due to its second factors
+	SecondFactors,
+	/// The user lacks a required attribute in the source
+	MissingAttribute,
+	/// The entry of the user failed to parse in the source
+	ParseFailure,
+	/// The email address of the new user is still bound to another
+	/// Zitadel user
+	EmailConflict,
+	/// The email address of the new user belongs to a user of another
+	/// Zitadel organization
+	CrossOrgConflict,
+	/// A unique attribute of the new user is already taken by another
+	/// Zitadel user
+	ImportCollision,
+}
+
+/// The unique attribute of a new user already taken by another Zitadel
+/// user
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CollisionKey {
+	/// The Zitadel user ID, which is the localpart of the user
+	UserId,
+	/// The Zitadel username, which is the email address of the user
+	Username,
+	/// The email address
+	Email,
+}
+
+/// A user whose external ID was changed
+#[derive(Debug, Clone, Serialize)]
+pub struct RenamedUser {
+	/// The Zitadel ID of the user
+	pub zitadel_id: String,
+	/// The external ID before the rename
+	pub old_external_user_id: String,
+	/// The external ID after the rename
+	pub new_external_user_id: String,
+}
+
+/// A source user which already exists in Zitadel, but isn't managed by
+/// the sync
+#[derive(Debug, Clone, Serialize)]
+pub struct UnmanagedUser {
+	/// The external ID of the user
+	pub external_user_id: String,
+	/// The Zitadel ID of the existing user
+	pub zitadel_id: String,
+}
+
+/// A change to a user outside the pilot group, which wasn't applied
+#[derive(Debug, Clone, Serialize)]
+pub struct PilotDrift {
+	/// The change which would have been made
+	pub operation: Operation,
+	/// The external ID of the user
+	pub external_user_id: Option<String>,
+	/// The Zitadel ID of the user, unless it would have been created
+	pub zitadel_id: Option<String>,
+	/// The attributes which would have been changed by an update
+	#[serde(skip_serializing_if = "Vec::is_empty")]
+	pub changed_fields: Vec<String>,
+}
+
+/// A change beyond `max_changes_per_run`, which is left to later
+/// syncs
+#[derive(Debug, Clone, Serialize)]
+pub struct DeferredChange {
+	/// The deferred change
+	pub operation: Operation,
+	/// The external ID of the user
+	pub external_user_id: Option<String>,
+	/// The Zitadel ID of the user, unless it is to be created
+	pub zitadel_id: Option<String>,
+}
+
+/// A user slated for deletion despite registered second factors
+#[derive(Debug, Clone, Serialize)]
+pub struct SecondFactorUser {
+	/// The external ID of the user
+	pub external_user_id: String,
+	/// The Zitadel ID of the user
+	pub zitadel_id: String,
+	/// The registered second factors, e.g. `totp` or `passkey`
+	pub factors: Vec<String>,
+	/// Whether the deletion was held back, since more users with
+	/// second factors would have been deleted than allowed
+	pub held_back: bool,
+}
+
+/// A source user skipped since it lacks a required attribute
+#[derive(Debug, Clone, Serialize)]
+pub struct UserMissingAttribute {
+	/// The external ID of the user
+	pub external_user_id: String,
+	/// The Zitadel ID of the user, which is kept as it is, if the user
+	/// exists in Zitadel
+	pub zitadel_id: Option<String>,
+	/// The missing attribute, e.g. `email`
+	pub attribute: String,
+}
+
+/// A source user whose entry failed to parse
+#[derive(Debug, Clone, Serialize)]
+pub struct UnparsableUser {
+	/// The external ID of the user
+	pub external_user_id: String,
+	/// The Zitadel ID of the user, which is kept as it is, if the user
+	/// exists in Zitadel
+	pub zitadel_id: Option<String>,
+	/// Why the entry failed to parse
+	pub error: String,
+}
+
+/// A new user whose email address is still bound to another Zitadel
+/// user, which must be resolved manually
+#[derive(Debug, Clone, Serialize)]
+pub struct EmailConflict {
+	/// The external ID of the new user
+	pub external_user_id: String,
+	/// The Zitadel ID of the user holding the email address
+	pub zitadel_id: String,
+	/// The external ID of the user holding the email address
+	pub holder_external_user_id: String,
+	/// What the operator needs to do
+	pub message: String,
+}
+
+/// A new user whose email address belongs to a user of another Zitadel
+/// organization, which must be resolved manually
+#[derive(Debug, Clone, Serialize)]
+pub struct CrossOrgConflict {
+	/// The external ID of the new user
+	pub external_user_id: String,
+	/// The Zitadel ID of the user holding the email address
+	pub zitadel_id: String,
+	/// The organization of the user holding the email address
+	pub organization_id: String,
+	/// What the operator needs to do
+	pub message: String,
+}
+
+/// A new user whose user ID, username or email address is already
+/// taken by another Zitadel user, which must be resolved manually
+#[derive(Debug, Clone, Serialize)]
+pub struct ImportCollision {
+	/// The external ID of the new user
+	pub external_user_id: String,
+	/// The attribute which collided
+	pub key: CollisionKey,
+	/// The Zitadel ID of the user holding the attribute
+	pub zitadel_id: String,
+	/// The external ID of the user holding the attribute, unless it
+	/// has none
+	pub holder_external_user_id: Option<String>,
+	/// What the operator needs to do
+	pub message: String,
+}
+
+/// A user merged from personas which disagree on some attributes, of
+/// which the values of the primary persona were kept
+#[derive(Debug, Clone, Serialize)]
+pub struct PersonaConflict {
+	/// The external ID of the merged user, i.e. of its primary persona
+	pub external_user_id: String,
+	/// The external IDs of the personas, the primary one first
+	pub personas: Vec<String>,
+	/// The attributes the personas disagree on
+	pub attributes: Vec<String>,
+	/// What happened
+	pub message: String,
+}
+
+/// A change a user made to their own account without approval, which
+/// was overwritten
+#[derive(Debug, Clone, Serialize)]
+pub struct SelfServiceDrift {
+	/// The external ID of the user
+	pub external_user_id: String,
+	/// The Zitadel ID of the user
+	pub zitadel_id: String,
+	/// The changed field
+	pub field: String,
+	/// When the user made the change
+	pub changed_at: String,
+}
+
+/// A difference between a source and a Zitadel user, which was kept
+/// in Zitadel due to the drift policies
+#[derive(Debug, Clone, Serialize)]
+pub struct Drift {
+	/// The external ID of the user
+	pub external_user_id: String,
+	/// The Zitadel ID of the user
+	pub zitadel_id: String,
+	/// The differing field
+	pub field: String,
+	/// Where the difference originates
+	pub class: DriftClass,
+}
+
+/// The users a dry run or plan would delete, up to
+/// `deletion_listing_limit`
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DeletionListing {
+	/// The listed users
+	pub users: Vec<ListedDeletion>,
+	/// The number of users beyond the limit, which aren't listed
+	pub unlisted: usize,
+}
+
+/// A user a dry run or plan would delete
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ListedDeletion {
+	/// The external ID of the user
+	pub external_user_id: String,
+	/// The Zitadel ID of the user
+	pub zitadel_id: String,
+	/// The email address of the user, with most of its localpart
+	/// redacted
+	pub email: String,
+}
+
+impl DeletionListing {
+	/// Add a user, unless the given number of users is listed already
+	pub(crate) fn push(&mut self, limit: usize, user: &User, zitadel_id: &str) {
+		if self.users.len() < limit {
+			self.users.push(ListedDeletion {
+				external_user_id: user.external_user_id.clone(),
+				zitadel_id: zitadel_id.to_owned(),
+				email: redact_email(&user.email),
+			});
+		} else {
+			self.unlisted += 1;
+		}
+	}
+
+	/// Log the listed users as a single block, unless there are none
+	pub(crate) fn log(&self) {
+		if self.users.is_empty() {
+			return;
+		}
+
+		let mut lines =
+			vec![format!("Users which would be deleted ({}):", self.users.len() + self.unlisted)];
+		lines.extend(self.users.iter().map(|user| {
+			format!("  {} <{}> (Zitadel ID {})", user.external_user_id, user.email, user.zitadel_id)
+		}));
+		if self.unlisted > 0 {
+			lines.push(format!("  ... and {} more", self.unlisted));
+		}
+		tracing::info!("{}", lines.join("\n"));
+	}
+}
+
+/// Redact an email address, keeping the first character of its
+/// localpart and its domain, e.g. `j***@example.com`
+fn redact_email(email: &str) -> String {
+	match email.split_once('@') {
+		Some((localpart, domain)) => {
+			let initial = localpart.chars().next().map(String::from).unwrap_or_default();
+			format!("{initial}***@{domain}")
+		}
+		None if email.is_empty() => String::new(),
+		None => "***".to_owned(),
+	}
+}
+
+/// A link to the configured IDP not matching any source user
+#[derive(Debug, Clone, Serialize)]
+pub struct StaleIdpLink {
+	/// The Zitadel ID of the linked user
+	pub zitadel_id: String,
+	/// The user ID provided by the IDP
+	pub provided_user_id: String,
+}
+
+/// A format to render sync reports in
+pub trait ReportRenderer {
+	/// Render the report
+	fn render(&self, report: &SyncReport) -> Result<String>;
+}
+
+/// Renders reports as pretty-printed JSON
+#[derive(Debug, Clone, Copy)]
+pub struct JsonRenderer;
+
+impl ReportRenderer for JsonRenderer {
+	fn render(&self, report: &SyncReport) -> Result<String> {
+		Ok(serde_json::to_string_pretty(report)?)
+	}
+}
+
+/// Collects the outcome of sync operations and periodically flushes
+/// it to disk, so that a crash doesn't lose all evidence of what was
+/// changed
+#[derive(Debug)]
+pub struct Reporter {
+	/// Reporting configuration
+	config: ReportingConfig,
+	/// The report collected so far
+	report: SyncReport,
+	/// Audit records not yet written to the audit log
+	pending_audit_records: Vec<AuditRecord>,
+	/// The number of operations recorded since the last flush
+	operations_since_flush: usize,
+	/// The language of the texts in the report
+	language: Language,
+}
+
+impl Reporter {
+	/// Create a new reporter for a sync run
+	#[must_use]
+	pub fn new(config: &ReportingConfig, dry_run: bool) -> Self {
+		Self {
+			config: config.clone(),
+			report: SyncReport {
+				started_at: Utc::now().to_rfc3339(),
+				build: BuildInfo::new(""),
+				dry_run,
+				..Default::default()
+			},
+			pending_audit_records: Vec::new(),
+			operations_since_flush: 0,
+			language: Language::default(),
+		}
+	}
+
+	/// Mark the report as being of a sync which only logs deletions
+	#[must_use]
+	pub fn with_deletions_dry_run(mut self, deletions_dry_run: bool) -> Self {
+		self.report.deletions_dry_run = deletions_dry_run;
+		self
+	}
+
+	/// Write the texts in the report in the given language
+	#[must_use]
+	pub fn with_language(mut self, language: Language) -> Self {
+		self.language = language;
+		self
+	}
+
+	/// Mark the report and audit records with the fingerprint of the
+	/// configuration of the sync
+	#[must_use]
+	pub fn with_config_fingerprint(mut self, config_fingerprint: &str) -> Self {
+		self.report.build = BuildInfo::new(config_fingerprint);
+		self
+	}
+
+	/// Record the outcome of an operation
+	pub fn record(
+		&mut self,
+		operation: Operation,
+		external_user_id: Option<&str>,
+		zitadel_id: Option<&str>,
+		result: &Result<()>,
+	) {
+		self.record_with_changes(operation, external_user_id, zitadel_id, Vec::new(), None, result);
+	}
+
+	/// Record the outcome of a deletion, along with why the user was
+	/// deleted
+	pub fn record_deletion(
+		&mut self,
+		external_user_id: Option<&str>,
+		zitadel_id: &str,
+		reason: DeletionReason,
+		result: &Result<()>,
+	) {
+		self.record_with_changes(
+			Operation::Delete,
+			external_user_id,
+			Some(zitadel_id),
+			Vec::new(),
+			Some(reason),
+			result,
+		);
+	}
+
+	/// Record the outcome of an update, along with the changed
+	/// attributes
+	pub fn record_update(
+		&mut self,
+		external_user_id: &str,
+		zitadel_id: &str,
+		changed_fields: Vec<String>,
+		result: &Result<()>,
+	) {
+		self.record_with_changes(
+			Operation::Update,
+			Some(external_user_id),
+			Some(zitadel_id),
+			changed_fields,
+			None,
+			result,
+		);
+	}
+
+	/// Record the outcome of a change of a user's external ID
+	pub fn record_rename(
+		&mut self,
+		zitadel_id: &str,
+		old_external_user_id: &str,
+		new_external_user_id: &str,
+		result: &Result<()>,
+	) {
+		self.record_with_changes(
+			Operation::Rename,
+			Some(new_external_user_id),
+			Some(zitadel_id),
+			vec!["external_user_id".to_owned()],
+			None,
+			result,
+		);
+
+		if result.is_ok() {
+			self.report.renamed.push(RenamedUser {
+				zitadel_id: zitadel_id.to_owned(),
+				old_external_user_id: old_external_user_id.to_owned(),
+				new_external_user_id: new_external_user_id.to_owned(),
+			});
+		}
+	}
+
+	/// Record the outcome of an operation, along with the changed
+	/// attributes or the reason of a deletion
+	fn record_with_changes(
+		&mut self,
+		operation: Operation,
+		external_user_id: Option<&str>,
+		zitadel_id: Option<&str>,
+		changed_fields: Vec<String>,
+		deletion_reason: Option<DeletionReason>,
+		result: &Result<()>,
+	) {
+		if let Some(id) = external_user_id.or(zitadel_id) {
+			watchdog::record_progress(id);
+			latency::attribute(id);
+		}
+
+		let error = result.as_ref().err().map(|error| format!("{error:#}"));
+		let message = error.as_deref().map(|error| {
+			Message::OperationFailed {
+				operation,
+				user: external_user_id.or(zitadel_id).unwrap_or_default(),
+				error,
+			}
+			.render(self.language)
+		});
+
+		let record = AuditRecord {
+			timestamp: Utc::now().to_rfc3339(),
+			build: self.report.build.clone(),
+			operation,
+			external_user_id: external_user_id.map(ToOwned::to_owned),
+			zitadel_id: zitadel_id.map(ToOwned::to_owned),
+			changed_fields,
+			deletion_reason,
+			error,
+			message,
+		};
+
+		if record.error.is_some() {
+			self.report.failures.push(record.clone());
+		} else {
+			let list = match operation {
+				Operation::Create => Some((&mut self.report.created, external_user_id)),
+				Operation::Update => Some((&mut self.report.updated, external_user_id)),
+				Operation::Delete => Some((&mut self.report.deleted, zitadel_id)),
+				// Renames are recorded by `record_rename`
+				Operation::Rename => None,
+			};
+			if let Some((list, id)) = list {
+				list.push(id.unwrap_or_default().to_owned());
+			}
+
+			for field in &record.changed_fields {
+				*self.report.field_change_counts.entry(field.clone()).or_default() += 1;
+			}
+
+			if let (Some(zitadel_id), Some(reason)) = (zitadel_id, &record.deletion_reason) {
+				self.report.deletion_reasons.insert(zitadel_id.to_owned(), reason.clone());
+			}
+
+			if !record.changed_fields.is_empty() {
+				self.report.changed_fields.insert(
+					external_user_id.unwrap_or_default().to_owned(),
+					record.changed_fields.clone(),
+				);
+			}
+		}
+
+		if self.config.audit_log_path.is_some() {
+			self.pending_audit_records.push(record);
+		}
+
+		self.operations_since_flush += 1;
+		if self.operations_since_flush >= self.config.flush_interval {
+			if let Err(error) = self.flush() {
+				tracing::error!("Failed to flush sync report: {:?}", error);
+			}
+		}
+	}
+
+	/// Whether any operation recorded so far failed
+	#[must_use]
+	pub fn has_failures(&self) -> bool {
+		!self.report.failures.is_empty()
+	}
+
+	/// Record a user the sync deleted, listing it for review if this is
+	/// a dry run
+	pub(crate) fn record_listed_deletion(&mut self, user: &User, zitadel_id: &str) {
+		if self.report.dry_run || self.report.deletions_dry_run {
+			self.report.deletion_listing.push(self.config.deletion_listing_limit, user, zitadel_id);
+		}
+	}
+
+	/// Record Zitadel users without an email address
+	pub fn record_users_without_email(&mut self, zitadel_ids: Vec<String>) {
+		self.report.users_without_email.extend(zitadel_ids);
+	}
+
+	/// Record a source user which already exists in Zitadel, but isn't
+	/// managed by the sync, so it wasn't imported
+	pub(crate) fn record_unmanaged_user(&mut self, external_user_id: &str, zitadel_id: &str) {
+		watchdog::record_progress(external_user_id);
+		tracing::warn!(
+			"Not importing user `{}`, which exists in Zitadel as `{}`, but isn't managed by the \
+			 sync",
+			external_user_id,
+			zitadel_id
+		);
+
+		self.report.unmanaged.push(UnmanagedUser {
+			external_user_id: external_user_id.to_owned(),
+			zitadel_id: zitadel_id.to_owned(),
+		});
+	}
+
+	/// Record a source user which couldn't be read in full, and whose
+	/// Zitadel counterpart, if any, was left untouched
+	pub(crate) fn record_unreadable(&mut self, user: &User, zitadel_id: Option<&str>) {
+		watchdog::record_progress(&user.external_user_id);
+		let external_user_id = user.external_user_id.clone();
+		let zitadel_id = zitadel_id.map(ToOwned::to_owned);
+
+		match &user.unreadable {
+			Some(Unreadable::MissingAttribute(attribute)) => {
+				self.report.users_missing_attributes.push(UserMissingAttribute {
+					external_user_id,
+					zitadel_id,
+					attribute: attribute.clone(),
+				});
+			}
+			Some(Unreadable::ParseFailure(error)) => {
+				tracing::warn!(
+					"Leaving user `{}` untouched, since its source entry failed to parse",
+					external_user_id
+				);
+				self.report.unparsable_users.push(UnparsableUser {
+					external_user_id,
+					zitadel_id,
+					error: error.clone(),
+				});
+			}
+			None => {}
+		}
+	}
+
+	/// Record a new user which wasn't imported, since its email address
+	/// is still bound to another Zitadel user
+	pub(crate) fn record_email_conflict(
+		&mut self,
+		external_user_id: &str,
+		zitadel_id: &str,
+		holder_external_user_id: &str,
+	) {
+		watchdog::record_progress(external_user_id);
+		let message =
+			Message::EmailConflict { external_user_id, zitadel_id, holder_external_user_id }
+				.render(self.language);
+		tracing::warn!("{}", message);
+
+		self.report.email_conflicts.push(EmailConflict {
+			external_user_id: external_user_id.to_owned(),
+			zitadel_id: zitadel_id.to_owned(),
+			holder_external_user_id: holder_external_user_id.to_owned(),
+			message,
+		});
+	}
+
+	/// Record a new user which wasn't imported, since its email address
+	/// belongs to a user of another Zitadel organization
+	pub(crate) fn record_cross_org_conflict(
+		&mut self,
+		external_user_id: &str,
+		zitadel_id: &str,
+		organization_id: &str,
+	) {
+		watchdog::record_progress(external_user_id);
+		let message = Message::CrossOrgConflict { external_user_id, zitadel_id, organization_id }
+			.render(self.language);
+		tracing::warn!("{}", message);
+
+		self.report.cross_org_conflicts.push(CrossOrgConflict {
+			external_user_id: external_user_id.to_owned(),
+			zitadel_id: zitadel_id.to_owned(),
+			organization_id: organization_id.to_owned(),
+			message,
+		});
+	}
+
+	/// Record a new user which wasn't imported, since one of its unique
+	/// attributes is already taken by another Zitadel user
+	pub(crate) fn record_import_collision(
+		&mut self,
+		external_user_id: &str,
+		key: CollisionKey,
+		zitadel_id: &str,
+		holder_external_user_id: Option<&str>,
+	) {
+		watchdog::record_progress(external_user_id);
+		let message =
+			Message::ImportCollision { external_user_id, key, zitadel_id, holder_external_user_id }
+				.render(self.language);
+		tracing::warn!("{}", message);
+
+		self.report.import_collisions.push(ImportCollision {
+			external_user_id: external_user_id.to_owned(),
+			key,
+			zitadel_id: zitadel_id.to_owned(),
+			holder_external_user_id: holder_external_user_id.map(str::to_owned),
+			message,
+		});
+	}
+
+	/// Record a user merged from personas which disagree on some
+	/// attributes
+	pub(crate) fn record_persona_conflict(&mut self, conflict: &MergeConflict) {
+		let message = conflict.message(self.language);
+		tracing::warn!("{}", message);
+
+		self.report.persona_conflicts.push(PersonaConflict {
+			external_user_id: conflict.external_user_id.clone(),
+			personas: conflict.personas.clone(),
+			attributes: conflict.attributes.clone(),
+			message,
+		});
+	}
+
+	/// Record that the installed sync is outdated
+	pub(crate) fn record_version_notice(&mut self, notice: VersionNotice) {
+		self.report.version_notice = Some(notice);
+	}
+
+	/// Record the time spent reconciling each user, slowest first
+	pub(crate) fn record_latencies(&mut self, latencies: Vec<UserLatency>) {
+		if let Some(latency_budget_ms) = self.config.latency_budget_ms {
+			let over_budget: Vec<_> = latencies
+				.iter()
+				.filter(|latency| latency.total_ms > u128::from(latency_budget_ms))
+				.collect();
+
+			for latency in &over_budget {
+				tracing::warn!(
+					"Reconciling user `{}` took {} ms, of which {} ms were spent in `{}`",
+					latency.external_user_id,
+					latency.total_ms,
+					latency.dominant_call_ms,
+					latency.dominant_call
+				);
+			}
+
+			self.report.users_over_latency_budget = over_budget.len();
+		}
+
+		self.report.slowest_users =
+			latencies.into_iter().take(self.config.slow_user_count).collect();
+	}
+
+	/// Record a change to a user outside the pilot group, which
+	/// wasn't applied
+	pub(crate) fn record_pilot_drift(
+		&mut self,
+		operation: Operation,
+		external_user_id: Option<&str>,
+		zitadel_id: Option<&str>,
+		changed_fields: Vec<String>,
+	) {
+		if let Some(id) = external_user_id.or(zitadel_id) {
+			watchdog::record_progress(id);
+			tracing::info!("Not applying {} of `{}` outside the pilot group", operation.name(), id);
+		}
+
+		self.report.pilot_drift.push(PilotDrift {
+			operation,
+			external_user_id: external_user_id.map(ToOwned::to_owned),
+			zitadel_id: zitadel_id.map(ToOwned::to_owned),
+			changed_fields,
+		});
+	}
+
+	/// Record a change beyond `max_changes_per_run`, which is left to
+	/// later syncs
+	pub(crate) fn record_deferred(
+		&mut self,
+		operation: Operation,
+		external_user_id: Option<&str>,
+		zitadel_id: Option<&str>,
+	) {
+		if let Some(id) = external_user_id.or(zitadel_id) {
+			watchdog::record_progress(id);
+			tracing::debug!("Deferring {} of `{}` beyond the change budget", operation.name(), id);
+		}
+
+		self.report.deferred.push(DeferredChange {
+			operation,
+			external_user_id: external_user_id.map(ToOwned::to_owned),
+			zitadel_id: zitadel_id.map(ToOwned::to_owned),
+		});
+	}
+
+	/// The number of changes left to later syncs
+	pub(crate) fn deferred(&self) -> usize {
+		self.report.deferred.len()
+	}
+
+	/// Record a user slated for deletion despite registered second
+	/// factors
+	pub(crate) fn record_second_factor_user(
+		&mut self,
+		external_user_id: &str,
+		zitadel_id: &str,
+		factors: Vec<String>,
+		held_back: bool,
+	) {
+		if held_back {
+			tracing::warn!(
+				"Holding back the deletion of `{}`, which has second factors: {}",
+				zitadel_id,
+				factors.join(", ")
+			);
+		} else {
+			tracing::warn!(
+				"Deleting `{}` despite second factors: {}",
+				zitadel_id,
+				factors.join(", ")
+			);
+		}
+
+		self.report.second_factor_users.push(SecondFactorUser {
+			external_user_id: external_user_id.to_owned(),
+			zitadel_id: zitadel_id.to_owned(),
+			factors,
+			held_back,
+		});
+	}
+
+	/// The number of deletions held back due to second factors
+	pub(crate) fn held_back_deletions(&self) -> usize {
+		self.report.second_factor_users.iter().filter(|user| user.held_back).count()
+	}
+
+	/// Record a change a user made to their own account without
+	/// approval, which is overwritten
+	pub(crate) fn record_self_service_drift(
+		&mut self,
+		external_user_id: &str,
+		zitadel_id: &str,
+		field: &str,
+		changed_at: &str,
+	) {
+		tracing::info!(
+			"Overwriting the {} user `{}` changed themselves, since it isn't approved for \
+			 self-service",
+			field,
+			zitadel_id
+		);
+
+		self.report.self_service_drift.push(SelfServiceDrift {
+			external_user_id: external_user_id.to_owned(),
+			zitadel_id: zitadel_id.to_owned(),
+			field: field.to_owned(),
+			changed_at: changed_at.to_owned(),
+		});
+	}
+
+	/// Record a difference kept in Zitadel due to the drift policies
+	pub(crate) fn record_drift(
+		&mut self,
+		external_user_id: &str,
+		zitadel_id: &str,
+		field: &str,
+		class: DriftClass,
+	) {
+		tracing::info!("Keeping the differing {} of `{}` in Zitadel", field, zitadel_id);
+
+		self.report.drift.push(Drift {
+			external_user_id: external_user_id.to_owned(),
+			zitadel_id: zitadel_id.to_owned(),
+			field: field.to_owned(),
+			class,
+		});
+	}
+
+	/// Record stale IDP links of a Zitadel user
+	pub fn record_stale_idp_links(&mut self, zitadel_id: &str, provided_user_ids: Vec<String>) {
+		self.report.stale_idp_links.extend(provided_user_ids.into_iter().map(|provided_user_id| {
+			StaleIdpLink { zitadel_id: zitadel_id.to_owned(), provided_user_id }
+		}));
+	}
+
+	/// Write the report and any pending audit records to disk
+	pub(crate) fn flush(&mut self) -> Result<()> {
+		self.operations_since_flush = 0;
+
+		if let Some(audit_log_path) = &self.config.audit_log_path {
+			append_json_lines(audit_log_path, &self.pending_audit_records)
+				.context("Failed to write audit log")?;
+			self.pending_audit_records.clear();
+		}
+
+		if let Some(report_path) = &self.config.report_path {
+			write_report(report_path, &JsonRenderer, &self.report)?;
+		}
+
+		Ok(())
+	}
+
+	/// Finish the report, flushing it to disk a final time
+	pub fn finish(mut self) -> Result<SyncReport> {
+		let counts = self.report.counts();
+		let summary = Message::SyncSummary {
+			created: counts.created,
+			updated: counts.updated,
+			renamed: counts.renamed,
+			deleted: counts.deleted,
+			failed: counts.failed,
+		}
+		.render(self.language);
+		tracing::info!("{}", summary);
+
+		let finished_at = Utc::now();
+		self.report.finished_at = Some(finished_at.to_rfc3339());
+		self.report.duration_ms =
+			DateTime::parse_from_rfc3339(&self.report.started_at).ok().and_then(|started_at| {
+				(finished_at - started_at.with_timezone(&Utc)).num_milliseconds().try_into().ok()
+			});
+		self.report.summary = Some(summary);
+		self.flush()?;
+
+		if let Some(html_report_path) = &self.config.html_report_path {
+			let renderer =
+				HtmlRenderer { language: self.language, redaction: self.config.html_redaction };
+			write_report(html_report_path, &renderer, &self.report)?;
+		}
+
+		if !self.report.field_change_counts.is_empty() {
+			tracing::info!("Changed attributes: {:?}", self.report.field_change_counts);
+		}
+		self.report.deletion_listing.log();
+
+		Ok(self.report)
+	}
+}
+
+/// Write a report to a file in the format of the given renderer
+fn write_report(path: &Path, renderer: &impl ReportRenderer, report: &SyncReport) -> Result<()> {
+	std::fs::write(path, renderer.render(report)?)
+		.context(format!("Failed to write sync report {}", path.display()))
+}
+
+/// Append records as JSON lines to a file
+pub(crate) fn append_json_lines(path: &Path, records: &[impl Serialize]) -> Result<()> {
+	let mut file = OpenOptions::new()
+		.create(true)
+		.append(true)
+		.open(path)
+		.context(format!("Failed to open {}", path.display()))?;
+
+	for record in records {
+		let mut line = serde_json::to_vec(record)?;
+		line.push(b'\n');
+		file.write_all(&line).context(format!("Failed to write to {}", path.display()))?;
+	}
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use anyhow::anyhow;
+	use tempfile::TempDir;
+
+	use super::*;
+
+	fn reporting_config(dir: &TempDir, flush_interval: usize) -> ReportingConfig {
+		ReportingConfig {
+			report_path: Some(dir.path().join("report.json")),
+			html_report_path: Some(dir.path().join("report.html")),
+			html_redaction: HtmlRedaction::default(),
+			audit_log_path: Some(dir.path().join("audit.jsonl")),
+			deletion_archive_path: None,
+			intent_log_path: None,
+			flush_interval,
+			slow_user_count: 1,
+			latency_budget_ms: Some(1000),
+			deletion_listing_limit: 100,
+		}
+	}
+
+	#[test]
+	fn test_record_operations() {
+		let mut reporter = Reporter::new(&ReportingConfig::default(), false);
+
+		reporter.record(Operation::Create, Some("aa"), None, &Ok(()));
+		reporter.record_update("bb", "1", vec!["phone".to_owned()], &Ok(()));
+		reporter.record(Operation::Delete, Some("cc"), Some("2"), &Ok(()));
+		reporter.record(Operation::Delete, Some("dd"), Some("3"), &Err(anyhow!("failed")));
+		reporter.record_rename("4", "ee", "ff", &Ok(()));
+
+		let report = reporter.finish().expect("failed to finish report");
+		assert_eq!(report.created, vec!["aa"]);
+		assert_eq!(report.updated, vec!["bb"]);
+		assert_eq!(report.changed_fields.get("bb"), Some(&vec!["phone".to_owned()]));
+		assert_eq!(report.deleted, vec!["2"]);
+		assert_eq!(report.renamed.len(), 1);
+		assert_eq!(report.renamed[0].old_external_user_id, "ee");
+		assert_eq!(report.changed_fields.get("ff"), Some(&vec!["external_user_id".to_owned()]));
+		assert_eq!(report.failures.len(), 1);
+		assert_eq!(report.failures[0].error.as_deref(), Some("failed"));
+		assert!(report.finished_at.is_some());
+		assert!(report.duration_ms.is_some());
+		assert_eq!(
+			report.counts(),
+			SyncCounts { created: 1, updated: 1, renamed: 1, deleted: 1, skipped: 0, failed: 1 }
+		);
+	}
+
+	#[test]
+	fn test_record_deletion() {
+		let mut reporter = Reporter::new(&ReportingConfig::default(), false);
+
+		reporter.record_deletion(Some("aa"), "1", DeletionReason::NotInSource, &Ok(()));
+		reporter.record_deletion(
+			Some("bb"),
+			"2",
+			DeletionReason::ExcludedByRule { rule: "service-accounts".to_owned() },
+			&Ok(()),
+		);
+		reporter.record_deletion(
+			Some("cc"),
+			"3",
+			DeletionReason::DisabledInSource,
+			&Err(anyhow!("failed")),
+		);
+
+		let report = reporter.finish().expect("failed to finish report");
+		assert_eq!(report.deleted, vec!["1", "2"]);
+		assert_eq!(report.deletion_reasons.get("1"), Some(&DeletionReason::NotInSource));
+		assert_eq!(
+			serde_json::to_value(&report.deletion_reasons["2"]).expect("failed to serialize"),
+			serde_json::json!({ "reason": "excluded_by_rule", "rule": "service-accounts" })
+		);
+		assert!(!report.deletion_reasons.contains_key("3"));
+		assert_eq!(report.failures[0].deletion_reason, Some(DeletionReason::DisabledInSource));
+	}
+
+	#[test]
+	fn test_deletion_listing() {
+		let config = ReportingConfig { deletion_listing_limit: 2, ..Default::default() };
+		let user = |external_user_id: &str, email: &str| {
+			User::new(
+				"John".to_owned(),
+				"Doe".to_owned(),
+				email.to_owned(),
+				None,
+				true,
+				None,
+				external_user_id.to_owned(),
+				None,
+			)
+		};
+
+		// Deletions are only listed by dry runs
+		let mut reporter = Reporter::new(&config, false);
+		reporter.record_listed_deletion(&user("aa", "jdoe@example.com"), "1");
+		let report = reporter.finish().expect("failed to finish report");
+		assert!(report.deletion_listing.users.is_empty());
+
+		let mut reporter = Reporter::new(&config, false).with_deletions_dry_run(true);
+		reporter.record_listed_deletion(&user("aa", "jdoe@example.com"), "1");
+		reporter.record_listed_deletion(&user("bb", ""), "2");
+		reporter.record_listed_deletion(&user("cc", "cc@example.com"), "3");
+
+		let report = reporter.finish().expect("failed to finish report");
+		assert_eq!(
+			report.deletion_listing.users,
+			vec![
+				ListedDeletion {
+					external_user_id: "aa".to_owned(),
+					zitadel_id: "1".to_owned(),
+					email: "j***@example.com".to_owned(),
+				},
+				ListedDeletion {
+					external_user_id: "bb".to_owned(),
+					zitadel_id: "2".to_owned(),
+					email: String::new(),
+				},
+			]
+		);
+		assert_eq!(report.deletion_listing.unlisted, 1);
+	}
+
+	#[test]
+	fn test_record_unreadable() {
+		let mut reporter = Reporter::new(&ReportingConfig::default(), false);
+		let user = |external_user_id: &str, unreadable| {
+			let mut user = User::new(
+				"John".to_owned(),
+				"Doe".to_owned(),
+				String::new(),
+				None,
+				true,
+				None,
+				external_user_id.to_owned(),
+				None,
+			);
+			user.unreadable = Some(unreadable);
+			user
+		};
+
+		reporter.record_unreadable(
+			&user("aa", Unreadable::MissingAttribute("email".to_owned())),
+			Some("1"),
+		);
+		reporter.record_unreadable(
+			&user("bb", Unreadable::MissingAttribute("preferred_username".to_owned())),
+			None,
+		);
+		reporter
+			.record_unreadable(&User::unparsable("cc".to_owned(), "invalid".to_owned()), Some("3"));
+
+		let report = reporter.finish().expect("failed to finish report");
+		assert_eq!(report.users_missing_attributes[1].attribute, "preferred_username");
+		assert_eq!(report.unparsable_users[0].error, "invalid");
+		assert_eq!(report.counts().skipped, 3);
+		assert_eq!(report.skipped()[0].zitadel_id.as_deref(), Some("1"));
+		assert_eq!(report.skipped()[0].reason, SkipReason::MissingAttribute);
+		assert_eq!(report.skipped()[2].zitadel_id.as_deref(), Some("3"));
+		assert_eq!(report.skipped()[2].reason, SkipReason::ParseFailure);
+	}
+
+	#[test]
+	fn test_record_email_conflict() {
+		let mut reporter = Reporter::new(&ReportingConfig::default(), false);
+
+		reporter.record_email_conflict("aa", "1", "bb");
+
+		let report = reporter.finish().expect("failed to finish report");
+		assert_eq!(report.email_conflicts[0].holder_external_user_id, "bb");
+		assert!(report.email_conflicts[0].message.contains("Zitadel user `1`"));
+		assert_eq!(report.skipped()[0].external_user_id.as_deref(), Some("aa"));
+		assert_eq!(report.skipped()[0].reason, SkipReason::EmailConflict);
+	}
+
+	#[test]
+	fn test_record_cross_org_conflict() {
+		let mut reporter = Reporter::new(&ReportingConfig::default(), false);
+
+		reporter.record_cross_org_conflict("aa", "1", "other-org");
+
+		let report = reporter.finish().expect("failed to finish report");
+		assert_eq!(report.cross_org_conflicts[0].organization_id, "other-org");
+		assert!(report.cross_org_conflicts[0].message.contains("organization `other-org`"));
+		assert_eq!(report.skipped()[0].external_user_id.as_deref(), Some("aa"));
+		assert_eq!(report.skipped()[0].reason, SkipReason::CrossOrgConflict);
+	}
+
+	#[test]
+	fn test_record_import_collision() {
+		let mut reporter = Reporter::new(&ReportingConfig::default(), false);
+
+		reporter.record_import_collision("aa", CollisionKey::Username, "1", Some("bb"));
+		reporter.record_import_collision("cc", CollisionKey::UserId, "2", None);
+
+		let report = reporter.finish().expect("failed to finish report");
+		assert_eq!(report.import_collisions[0].key, CollisionKey::Username);
+		assert!(report.import_collisions[0].message.contains("The username of the new user `aa`"));
+		assert!(report.import_collisions[0].message.contains("external ID `bb`"));
+		assert!(report.import_collisions[1].message.contains("user ID (localpart)"));
+		assert!(!report.import_collisions[1].message.contains("external ID"));
+		assert_eq!(report.skipped()[1].external_user_id.as_deref(), Some("cc"));
+		assert_eq!(report.skipped()[1].reason, SkipReason::ImportCollision);
+	}
+
+	#[test]
+	fn test_record_persona_conflict() {
+		let mut reporter = Reporter::new(&ReportingConfig::default(), false);
+
+		reporter.record_persona_conflict(&MergeConflict {
+			external_user_id: "aa".to_owned(),
+			personas: vec!["aa".to_owned(), "bb".to_owned()],
+			attributes: vec!["department".to_owned(), "phone".to_owned()],
+		});
+
+		let report = reporter.finish().expect("failed to finish report");
+		assert_eq!(report.persona_conflicts[0].personas, vec!["aa", "bb"]);
+		assert_eq!(
+			report.persona_conflicts[0].message,
+			"User `aa` was merged from the personas `aa`, `bb`, which differ in `department`, \
+			 `phone`; the values of `aa` were kept"
+		);
+		assert!(report.skipped().is_empty());
+	}
+
+	#[test]
+	fn test_record_deferred() {
+		let mut reporter = Reporter::new(&ReportingConfig::default(), false);
+
+		reporter.record_deferred(Operation::Create, Some("aa"), None);
+		reporter.record_deferred(Operation::Delete, Some("bb"), Some("1"));
+		assert_eq!(reporter.deferred(), 2);
+
+		let report = reporter.finish().expect("failed to finish report");
+		assert_eq!(report.deferred[0].external_user_id.as_deref(), Some("aa"));
+		assert_eq!(report.deferred[1].zitadel_id.as_deref(), Some("1"));
+		assert!(report.created.is_empty());
+		assert!(report.deleted.is_empty());
+		assert_eq!(report.counts().skipped, 2);
+		assert_eq!(report.skipped()[1].reason, SkipReason::Deferred);
+	}
+
+	#[test]
+	fn test_field_change_counts() {
+		let mut reporter = Reporter::new(&ReportingConfig::default(), false);
+
+		reporter.record_update(
+			"aa",
+			"1",
+			vec!["first_name".to_owned(), "phone".to_owned()],
+			&Ok(()),
+		);
+		reporter.record_update("bb", "2", vec!["phone".to_owned()], &Ok(()));
+		reporter.record_update("cc", "3", vec!["phone".to_owned()], &Err(anyhow!("failed")));
+
+		let report = reporter.finish().expect("failed to finish report");
+		assert_eq!(
+			report.field_change_counts,
+			BTreeMap::from([("first_name".to_owned(), 1), ("phone".to_owned(), 2)])
+		);
+	}
+
+	#[test]
+	fn test_periodic_flush() {
+		let dir = TempDir::new().expect("failed to create tempdir");
+		let config = reporting_config(&dir, 2);
+		let mut reporter = Reporter::new(&config, false).with_config_fingerprint("abc");
+
+		reporter.record(Operation::Create, Some("aa"), None, &Ok(()));
+		assert!(!dir.path().join("report.json").exists(), "Report flushed too early");
+		assert!(!dir.path().join("report.html").exists(), "HTML report written too early");
+
+		reporter.record(Operation::Create, Some("bb"), None, &Ok(()));
+		reporter.record(Operation::Create, Some("cc"), None, &Ok(()));
+
+		let audit_log = std::fs::read_to_string(dir.path().join("audit.jsonl"))
+			.expect("audit log was not flushed");
+		assert_eq!(audit_log.lines().count(), 2);
+		let record: serde_json::Value =
+			serde_json::from_str(audit_log.lines().next().unwrap_or_default())
+				.expect("invalid audit record");
+		assert_eq!(record["config_fingerprint"], "abc");
+		assert_eq!(record["version"], env!("CARGO_PKG_VERSION"));
+
+		let report: serde_json::Value = serde_json::from_slice(
+			&std::fs::read(dir.path().join("report.json")).expect("report was not flushed"),
+		)
+		.expect("invalid report");
+		assert_eq!(report["created"], serde_json::json!(["aa", "bb"]));
+		assert_eq!(report["finished_at"], serde_json::Value::Null);
+		assert_eq!(report["config_fingerprint"], "abc");
+
+		reporter.finish().expect("failed to finish report");
+
+		let audit_log = std::fs::read_to_string(dir.path().join("audit.jsonl"))
+			.expect("audit log was not flushed");
+		assert_eq!(audit_log.lines().count(), 3);
+		assert!(dir.path().join("report.html").exists(), "HTML report was not written");
+	}
+
+	#[test]
+	fn test_record_latencies() {
+		let dir = TempDir::new().expect("failed to create tempdir");
+		let mut reporter = Reporter::new(&reporting_config(&dir, 100), false);
+
+		let latency = |external_user_id: &str, total_ms| UserLatency {
+			external_user_id: external_user_id.to_owned(),
+			total_ms,
+			dominant_call: "get metadata".to_owned(),
+			dominant_call_ms: total_ms,
+		};
+		reporter.record_latencies(vec![
+			latency("aa", 3000),
+			latency("bb", 1500),
+			latency("cc", 20),
+		]);
+
+		let report = reporter.finish().expect("failed to finish report");
+		assert_eq!(report.slowest_users, vec![latency("aa", 3000)]);
+		assert_eq!(report.users_over_latency_budget, 2);
+	}
+
+	#[test]
+	fn test_localized_report() {
+		let mut reporter =
+			Reporter::new(&ReportingConfig::default(), false).with_language(Language::German);
+
+		reporter.record(Operation::Delete, Some("aa"), Some("1"), &Err(anyhow!("failed")));
+
+		let report = reporter.finish().expect("failed to finish report");
+		assert_eq!(
+			report.failures[0].message.as_deref(),
+			Some("Die Löschung des Benutzers `aa` ist fehlgeschlagen: failed")
+		);
+		assert_eq!(
+			report.summary.as_deref(),
+			Some(
+				"Sync abgeschlossen: 0 angelegt, 0 aktualisiert, 0 umbenannt, 0 gelöscht, 1 \
+				 fehlgeschlagen"
+			)
+		);
+	}
+}
@@ -0,0 +1,463 @@
+//! HTML rendering of sync reports
+//!
+//! The HTML report is a single page without external resources, meant
+//! for administrators who don't read JSON. It lists the users of each
+//! kind of change in tables sortable by clicking their headers, and the
+//! changes of each user below them.
+use std::{collections::BTreeMap, fmt::Write};
+
+use anyhow::Result;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use super::{DeletionReason, Operation, ReportRenderer, SkipReason, SyncReport};
+use crate::messages::{Language, ReportLabel};
+
+/// Sorts a table by the clicked column, alternating between ascending
+/// and descending order
+const SORT_SCRIPT: &str = r#"
+document.querySelectorAll("th").forEach((th) => th.addEventListener("click", () => {
+	const body = th.closest("table").tBodies[0];
+	const ascending = th.dataset.order !== "asc";
+	th.dataset.order = ascending ? "asc" : "desc";
+	[...body.rows]
+		.sort((a, b) => a.cells[th.cellIndex].textContent.localeCompare(
+			b.cells[th.cellIndex].textContent, undefined, { numeric: true }) * (ascending ? 1 : -1))
+		.forEach((row) => body.appendChild(row));
+}));
+"#;
+
+/// The style of the page
+const STYLE: &str = r"
+body { font-family: sans-serif; margin: 2em; }
+table { border-collapse: collapse; margin-bottom: 1em; }
+th, td { border: 1px solid #ccc; padding: 0.3em 0.6em; text-align: left; }
+th { background: #eee; cursor: pointer; }
+.notice { color: #a60; }
+";
+
+/// Personal data to leave out of the HTML report
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HtmlRedaction {
+	/// Leave nothing out
+	#[default]
+	None,
+	/// Leave out error messages, which may quote user attributes
+	Errors,
+	/// Leave out error messages, and replace user IDs by pseudonyms,
+	/// which stay the same within a report
+	Users,
+}
+
+/// Renders reports as a standalone HTML page
+#[derive(Debug, Clone, Copy)]
+pub struct HtmlRenderer {
+	/// The language of the labels
+	pub language: Language,
+	/// The personal data to leave out
+	pub redaction: HtmlRedaction,
+}
+
+/// A cell of a table
+enum Cell {
+	/// Plain text
+	Text(String),
+	/// A user ID, linking to the changes of the user
+	User(String),
+}
+
+/// The page being rendered
+struct Page<'a> {
+	/// The renderer
+	renderer: &'a HtmlRenderer,
+	/// The rendered tables
+	tables: String,
+	/// The changes of each user, by displayed user ID
+	users: BTreeMap<String, Vec<String>>,
+}
+
+impl ReportRenderer for HtmlRenderer {
+	fn render(&self, report: &SyncReport) -> Result<String> {
+		let mut page = Page { renderer: self, tables: String::new(), users: BTreeMap::new() };
+
+		page.table(
+			ReportLabel::Operation(Operation::Create),
+			&[ReportLabel::User],
+			report.created.iter().map(|user| vec![user_cell(user)]).collect(),
+		)?;
+		page.table(
+			ReportLabel::Operation(Operation::Update),
+			&[ReportLabel::User, ReportLabel::ChangedFields],
+			report
+				.updated
+				.iter()
+				.map(|user| {
+					let fields = report.changed_fields.get(user).map(|fields| fields.join(", "));
+					vec![user_cell(user), Cell::Text(fields.unwrap_or_default())]
+				})
+				.collect(),
+		)?;
+		page.table(
+			ReportLabel::Operation(Operation::Delete),
+			&[ReportLabel::ZitadelId, ReportLabel::Reason],
+			report
+				.deleted
+				.iter()
+				.map(|zitadel_id| {
+					let reason = report
+						.deletion_reasons
+						.get(zitadel_id)
+						.map(|reason| deletion_reason_label(reason).render(self.language));
+					vec![user_cell(zitadel_id), Cell::Text(reason.unwrap_or_default())]
+				})
+				.collect(),
+		)?;
+		page.table(
+			ReportLabel::Operation(Operation::Rename),
+			&[ReportLabel::ZitadelId, ReportLabel::OldExternalId, ReportLabel::NewExternalId],
+			report
+				.renamed
+				.iter()
+				.map(|renamed| {
+					vec![
+						user_cell(&renamed.zitadel_id),
+						Cell::Text(self.user_id(&renamed.old_external_user_id)),
+						Cell::Text(self.user_id(&renamed.new_external_user_id)),
+					]
+				})
+				.collect(),
+		)?;
+		page.table(
+			ReportLabel::Skipped,
+			&[ReportLabel::User, ReportLabel::ZitadelId, ReportLabel::Reason],
+			report
+				.skipped()
+				.into_iter()
+				.map(|skipped| {
+					vec![
+						skipped
+							.external_user_id
+							.map_or_else(|| Cell::Text(String::new()), |id| user_cell(&id)),
+						Cell::Text(
+							skipped.zitadel_id.map(|id| self.user_id(&id)).unwrap_or_default(),
+						),
+						Cell::Text(skip_reason_label(skipped.reason).render(self.language)),
+					]
+				})
+				.collect(),
+		)?;
+		page.table(
+			ReportLabel::Failed,
+			&[ReportLabel::User, ReportLabel::Change, ReportLabel::Error],
+			report
+				.failures
+				.iter()
+				.map(|failure| {
+					let user = failure.external_user_id.as_ref().or(failure.zitadel_id.as_ref());
+					let error = if self.redaction == HtmlRedaction::None {
+						failure
+							.message
+							.clone()
+							.or_else(|| failure.error.clone())
+							.unwrap_or_default()
+					} else {
+						ReportLabel::Redacted.render(self.language)
+					};
+					vec![
+						user.map_or_else(|| Cell::Text(String::new()), |user| user_cell(user)),
+						Cell::Text(
+							ReportLabel::ChangeKind(failure.operation).render(self.language),
+						),
+						Cell::Text(error),
+					]
+				})
+				.collect(),
+		)?;
+
+		page.finish(report)
+	}
+}
+
+/// A cell with a user ID
+fn user_cell(user: &str) -> Cell {
+	Cell::User(user.to_owned())
+}
+
+/// The label of a skip reason
+fn skip_reason_label(reason: SkipReason) -> ReportLabel<'static> {
+	match reason {
+		SkipReason::Deferred => ReportLabel::Deferred,
+		SkipReason::OutsidePilot => ReportLabel::OutsidePilot,
+		SkipReason::Unmanaged => ReportLabel::Unmanaged,
+		SkipReason::NoEmail => ReportLabel::NoEmail,
+		SkipReason::SecondFactors => ReportLabel::SecondFactors,
+		SkipReason::MissingAttribute => ReportLabel::MissingAttribute,
+		SkipReason::ParseFailure => ReportLabel::ParseFailure,
+		SkipReason::EmailConflict => ReportLabel::EmailConflict,
+		SkipReason::CrossOrgConflict => ReportLabel::CrossOrgConflict,
+		SkipReason::ImportCollision => ReportLabel::ImportCollision,
+	}
+}
+
+/// The label of a deletion reason
+fn deletion_reason_label(reason: &DeletionReason) -> ReportLabel<'_> {
+	match reason {
+		DeletionReason::NotInSource => ReportLabel::NotInSource,
+		DeletionReason::DisabledInSource => ReportLabel::DisabledInSource,
+		DeletionReason::GuestExpired => ReportLabel::GuestExpired,
+		DeletionReason::ExcludedByRule { rule } => ReportLabel::ExcludedByRule(rule),
+		DeletionReason::RemovedInUkt => ReportLabel::RemovedInUkt,
+		DeletionReason::DeletedViaScim => ReportLabel::DeletedViaScim,
+	}
+}
+
+impl HtmlRenderer {
+	/// The displayed ID of a user, which is a pseudonym if user IDs are
+	/// redacted
+	fn user_id(&self, id: &str) -> String {
+		if self.redaction == HtmlRedaction::Users {
+			format!("user-{}", anchor(id))
+		} else {
+			id.to_owned()
+		}
+	}
+}
+
+impl Page<'_> {
+	/// Render a table, unless it has no rows, and add its rows to the
+	/// changes of the users they name
+	fn table(
+		&mut self,
+		heading: ReportLabel<'_>,
+		columns: &[ReportLabel<'_>],
+		rows: Vec<Vec<Cell>>,
+	) -> Result<()> {
+		if rows.is_empty() {
+			return Ok(());
+		}
+
+		let language = self.renderer.language;
+		let heading = heading.render(language);
+		writeln!(self.tables, "<h2>{} ({})</h2>", escape(&heading), rows.len())?;
+		writeln!(self.tables, "<table><thead><tr>")?;
+		for column in columns {
+			writeln!(self.tables, "<th>{}</th>", escape(&column.render(language)))?;
+		}
+		writeln!(self.tables, "</tr></thead><tbody>")?;
+
+		for row in rows {
+			let mut user = None;
+			let mut details = Vec::new();
+			write!(self.tables, "<tr>")?;
+			for cell in row {
+				match cell {
+					Cell::Text(text) => {
+						write!(self.tables, "<td>{}</td>", escape(&text))?;
+						if !text.is_empty() {
+							details.push(text);
+						}
+					}
+					Cell::User(id) => {
+						let id = self.renderer.user_id(&id);
+						write!(
+							self.tables,
+							"<td><a href=\"#user-{}\">{}</a></td>",
+							anchor(&id),
+							escape(&id)
+						)?;
+						user = Some(id);
+					}
+				}
+			}
+			writeln!(self.tables, "</tr>")?;
+
+			if let Some(user) = user {
+				let change = if details.is_empty() {
+					heading.clone()
+				} else {
+					format!("{heading}: {}", details.join(", "))
+				};
+				self.users.entry(user).or_default().push(change);
+			}
+		}
+
+		writeln!(self.tables, "</tbody></table>")?;
+		Ok(())
+	}
+
+	/// Render the page around the tables
+	fn finish(self, report: &SyncReport) -> Result<String> {
+		let language = self.renderer.language;
+		let title = escape(&ReportLabel::Title.render(language));
+		let lang = match language {
+			Language::English => "en",
+			Language::German => "de",
+		};
+
+		let mut html = String::new();
+		writeln!(
+			html,
+			"<!DOCTYPE html>\n<html lang=\"{lang}\">\n<head>\n<meta charset=\"utf-8\">"
+		)?;
+		writeln!(html, "<title>{title}</title>\n<style>{STYLE}</style>\n</head>\n<body>")?;
+		writeln!(html, "<h1>{title}</h1>")?;
+		if let Some(summary) = &report.summary {
+			writeln!(html, "<p>{}</p>", escape(summary))?;
+		}
+		writeln!(
+			html,
+			"<p>{}: {}<br>{}: {}<br>{}: {} ({})<br>{}: {}</p>",
+			escape(&ReportLabel::StartedAt.render(language)),
+			escape(&report.started_at),
+			escape(&ReportLabel::FinishedAt.render(language)),
+			escape(report.finished_at.as_deref().unwrap_or("-")),
+			escape(&ReportLabel::Version.render(language)),
+			escape(&report.build.version),
+			escape(&report.build.git_sha),
+			escape(&ReportLabel::ConfigFingerprint.render(language)),
+			escape(&report.build.config_fingerprint)
+		)?;
+		if report.dry_run {
+			writeln!(
+				html,
+				"<p class=\"notice\">{}</p>",
+				escape(&ReportLabel::DryRun.render(language))
+			)?;
+		} else if report.deletions_dry_run {
+			writeln!(
+				html,
+				"<p class=\"notice\">{}</p>",
+				escape(&ReportLabel::DeletionsDryRun.render(language))
+			)?;
+		}
+		if let Some(notice) = &report.version_notice {
+			for message in notice.messages() {
+				writeln!(html, "<p class=\"notice\">{}</p>", escape(&message.render(language)))?;
+			}
+		}
+
+		html.push_str(&self.tables);
+
+		if !self.users.is_empty() {
+			writeln!(html, "<h2>{}</h2>", escape(&ReportLabel::Users.render(language)))?;
+			for (user, changes) in &self.users {
+				writeln!(
+					html,
+					"<details id=\"user-{}\"><summary>{}</summary><ul>",
+					anchor(user),
+					escape(user)
+				)?;
+				for change in changes {
+					writeln!(html, "<li>{}</li>", escape(change))?;
+				}
+				writeln!(html, "</ul></details>")?;
+			}
+		}
+
+		writeln!(html, "<script>{SORT_SCRIPT}</script>\n</body>\n</html>")?;
+		Ok(html)
+	}
+}
+
+/// An ID for the given user usable in HTML, which also serves as its
+/// pseudonym
+fn anchor(user: &str) -> String {
+	hex::encode(&Sha256::digest(user)[..6])
+}
+
+/// Escape text for HTML
+fn escape(text: &str) -> String {
+	let mut escaped = String::with_capacity(text.len());
+	for character in text.chars() {
+		match character {
+			'&' => escaped.push_str("&amp;"),
+			'<' => escaped.push_str("&lt;"),
+			'>' => escaped.push_str("&gt;"),
+			'"' => escaped.push_str("&quot;"),
+			'\'' => escaped.push_str("&#39;"),
+			_ => escaped.push(character),
+		}
+	}
+	escaped
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		report::{AuditRecord, BuildInfo},
+		version_check::VersionNotice,
+	};
+
+	fn report() -> SyncReport {
+		SyncReport {
+			started_at: "2026-10-15T02:00:00+00:00".to_owned(),
+			created: vec!["<script>".to_owned()],
+			updated: vec!["jdoe".to_owned()],
+			changed_fields: BTreeMap::from([(
+				"jdoe".to_owned(),
+				vec!["email".to_owned(), "phone".to_owned()],
+			)]),
+			deleted: vec!["123".to_owned()],
+			deletion_reasons: BTreeMap::from([(
+				"123".to_owned(),
+				DeletionReason::ExcludedByRule { rule: "contractors".to_owned() },
+			)]),
+			failures: vec![AuditRecord {
+				timestamp: "2026-10-15T02:00:01+00:00".to_owned(),
+				build: BuildInfo::default(),
+				operation: Operation::Update,
+				external_user_id: Some("mmuster".to_owned()),
+				zitadel_id: None,
+				changed_fields: Vec::new(),
+				deletion_reason: None,
+				error: Some("invalid email mmuster@example.com".to_owned()),
+				message: None,
+			}],
+			build: BuildInfo {
+				version: "0.9.0".to_owned(),
+				git_sha: "0123abc".to_owned(),
+				config_fingerprint: "fedcba".to_owned(),
+			},
+			version_notice: Some(VersionNotice {
+				installed: "0.9.0".to_owned(),
+				latest: "0.10.2".to_owned(),
+				missing_critical_fixes: Vec::new(),
+			}),
+			..Default::default()
+		}
+	}
+
+	#[test]
+	fn test_render_html() {
+		let renderer = HtmlRenderer { language: Language::German, redaction: HtmlRedaction::None };
+		let html = renderer.render(&report()).expect("failed to render report");
+
+		assert!(html.contains("<h2>Angelegt (1)</h2>"));
+		assert!(
+			html.contains(&format!("<a href=\"#user-{}\">&lt;script&gt;</a>", anchor("<script>")))
+		);
+		assert!(html.contains("<td>email, phone</td>"));
+		assert!(html.contains("Durch die Regel `contractors` ausgeschlossen"));
+		assert!(html.contains("invalid email mmuster@example.com"));
+		assert!(html
+			.contains(&format!("<details id=\"user-{}\"><summary>jdoe</summary>", anchor("jdoe"))));
+		assert!(html.contains("<li>Aktualisiert: email, phone</li>"));
+		assert!(html.contains("famedly-sync 0.9.0 ist veraltet"));
+		assert!(html.contains("Version: 0.9.0 (0123abc)<br>Konfiguration: fedcba"));
+	}
+
+	#[test]
+	fn test_redact_html() {
+		let renderer =
+			HtmlRenderer { language: Language::English, redaction: HtmlRedaction::Users };
+		let html = renderer.render(&report()).expect("failed to render report");
+
+		assert!(!html.contains("jdoe"));
+		assert!(!html.contains("mmuster"));
+		assert!(html.contains(&format!("user-{}", anchor("jdoe"))));
+		assert!(html.contains("<td>(redacted)</td>"));
+		assert!(html.contains("<td>email, phone</td>"));
+	}
+}
@@ -0,0 +1,242 @@
+//! Periodic reporting of the resources used by the sync
+//!
+//! While a sync runs, the memory usage and open connections of the
+//! process are sampled along with the progress of the sync, logged,
+//! and optionally appended to a JSON lines file. This shows operators
+//! on constrained hosts whether a sync is about to run out of memory
+//! before it is killed.
+use std::{
+	fs::{File, OpenOptions},
+	future::Future,
+	io::Write,
+	path::{Path, PathBuf},
+	time::Duration,
+};
+
+use anyhow::{Context, Result};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+use crate::watchdog;
+
+/// The share of the memory limit above which a warning is logged, in
+/// percent
+const MEMORY_WARNING_PERCENT: u64 = 90;
+
+/// Configuration for resource reporting
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct ResourceMonitoringConfig {
+	/// The interval between samples, in seconds
+	#[serde(default = "default_interval_seconds")]
+	pub interval_seconds: u64,
+	/// Optional JSON lines file to append the samples to
+	pub metrics_path: Option<PathBuf>,
+}
+
+/// Default for [`ResourceMonitoringConfig::interval_seconds`]
+fn default_interval_seconds() -> u64 {
+	60
+}
+
+/// The resources used by the process at a point of the sync
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ResourceSample {
+	/// When the sample was taken
+	pub timestamp: String,
+	/// The current phase of the sync
+	pub phase: &'static str,
+	/// The number of users processed so far
+	pub processed_users: u64,
+	/// The number of Zitadel writes in flight
+	pub in_flight_operations: usize,
+	/// The resident set size of the process, in bytes
+	pub rss_bytes: Option<u64>,
+	/// The peak resident set size of the process, in bytes
+	pub peak_rss_bytes: Option<u64>,
+	/// The memory limit of the container the process runs in, in
+	/// bytes
+	pub memory_limit_bytes: Option<u64>,
+	/// The number of open sockets, i.e. connections to Zitadel and
+	/// the source
+	pub open_sockets: Option<usize>,
+}
+
+impl ResourceSample {
+	/// Sample the resources used by the process. Memory and socket
+	/// usage are only available on Linux.
+	fn take() -> Self {
+		let (phase, processed_users, in_flight_operations) = watchdog::progress();
+		let status = std::fs::read_to_string("/proc/self/status").unwrap_or_default();
+
+		Self {
+			timestamp: Utc::now().to_rfc3339(),
+			phase,
+			processed_users,
+			in_flight_operations,
+			rss_bytes: parse_status_bytes(&status, "VmRSS"),
+			peak_rss_bytes: parse_status_bytes(&status, "VmHWM"),
+			memory_limit_bytes: memory_limit(),
+			open_sockets: count_open_sockets(),
+		}
+	}
+
+	/// Whether the process uses most of its memory limit
+	fn is_near_memory_limit(&self) -> bool {
+		match (self.rss_bytes, self.memory_limit_bytes) {
+			(Some(rss), Some(limit)) => {
+				rss.saturating_mul(100) >= limit.saturating_mul(MEMORY_WARNING_PERCENT)
+			}
+			_ => false,
+		}
+	}
+
+	/// Log the sample
+	fn log(&self) {
+		let message = format!(
+			"Resource usage in phase `{}`: {} users processed, {} operations in flight, RSS {} \
+			 (peak {}, limit {}), {} open sockets",
+			self.phase,
+			self.processed_users,
+			self.in_flight_operations,
+			format_bytes(self.rss_bytes),
+			format_bytes(self.peak_rss_bytes),
+			format_bytes(self.memory_limit_bytes),
+			self.open_sockets.map_or_else(|| "unknown".to_owned(), |count| count.to_string()),
+		);
+
+		if self.is_near_memory_limit() {
+			tracing::warn!("{message}; the process is close to its memory limit");
+		} else {
+			tracing::info!("{message}");
+		}
+	}
+}
+
+/// Run a future, sampling the resources used by the process in the
+/// configured interval
+pub async fn run_with_monitoring<T>(
+	config: Option<&ResourceMonitoringConfig>,
+	future: impl Future<Output = Result<T>> + Send,
+) -> Result<T> {
+	let Some(config) = config else {
+		return future.await;
+	};
+	let interval = Duration::from_secs(config.interval_seconds.max(1));
+
+	let mut metrics_file = match &config.metrics_path {
+		Some(path) => Some(open_metrics_file(path)?),
+		None => None,
+	};
+
+	let monitor = async {
+		loop {
+			tokio::time::sleep(interval).await;
+			record_sample(metrics_file.as_mut());
+		}
+	};
+
+	let result = tokio::select! {
+		result = future => result,
+		() = monitor => unreachable!("resource monitoring never finishes"),
+	};
+
+	// Record the final state as well, which includes the peak memory
+	// usage of the whole sync
+	record_sample(metrics_file.as_mut());
+
+	result
+}
+
+/// Take, log and export a sample
+fn record_sample(metrics_file: Option<&mut File>) {
+	let sample = ResourceSample::take();
+	sample.log();
+
+	if let Some(file) = metrics_file {
+		let written =
+			serde_json::to_vec(&sample).map_err(anyhow::Error::from).and_then(|mut line| {
+				line.push(b'\n');
+				file.write_all(&line).and_then(|()| file.flush()).map_err(Into::into)
+			});
+		if let Err(error) = written {
+			tracing::warn!("Failed to write resource metrics: {:?}", error);
+		}
+	}
+}
+
+/// Open the metrics file for appending
+fn open_metrics_file(path: &Path) -> Result<File> {
+	OpenOptions::new()
+		.create(true)
+		.append(true)
+		.open(path)
+		.context(format!("Failed to open metrics file {}", path.display()))
+}
+
+/// Read a size in kB from the contents of `/proc/self/status`, in
+/// bytes
+fn parse_status_bytes(status: &str, key: &str) -> Option<u64> {
+	status.lines().find_map(|line| {
+		let value = line.strip_prefix(key)?.strip_prefix(':')?;
+		let kib = value.trim().strip_suffix("kB")?.trim().parse::<u64>().ok()?;
+		Some(kib * 1024)
+	})
+}
+
+/// Read the memory limit of the cgroup the process runs in, if any
+fn memory_limit() -> Option<u64> {
+	["/sys/fs/cgroup/memory.max", "/sys/fs/cgroup/memory/memory.limit_in_bytes"]
+		.iter()
+		.find_map(|path| std::fs::read_to_string(path).ok())
+		.and_then(|limit| parse_memory_limit(&limit))
+}
+
+/// Parse a cgroup memory limit, which is `max` if unlimited. cgroup v1
+/// reports a number close to 2^63 instead.
+fn parse_memory_limit(limit: &str) -> Option<u64> {
+	limit.trim().parse::<u64>().ok().filter(|limit| *limit < 1 << 62)
+}
+
+/// Count the sockets among the open file descriptors of the process
+fn count_open_sockets() -> Option<usize> {
+	let descriptors = std::fs::read_dir("/proc/self/fd").ok()?;
+	Some(
+		descriptors
+			.filter_map(|entry| std::fs::read_link(entry.ok()?.path()).ok())
+			.filter(|target| target.to_string_lossy().starts_with("socket:"))
+			.count(),
+	)
+}
+
+/// Format a size in bytes in MiB
+fn format_bytes(bytes: Option<u64>) -> String {
+	bytes.map_or_else(|| "unknown".to_owned(), |bytes| format!("{} MiB", bytes / (1024 * 1024)))
+}
+
+#[cfg(test)]
+mod tests {
+	use indoc::indoc;
+
+	use super::*;
+
+	#[test]
+	fn test_parse_status_bytes() {
+		let status = indoc! {"
+			Name:	famedly-sync
+			VmHWM:	  204800 kB
+			VmRSS:	  102400 kB
+			Threads:	4
+		"};
+
+		assert_eq!(parse_status_bytes(status, "VmRSS"), Some(100 * 1024 * 1024));
+		assert_eq!(parse_status_bytes(status, "VmHWM"), Some(200 * 1024 * 1024));
+		assert_eq!(parse_status_bytes(status, "VmSwap"), None);
+	}
+
+	#[test]
+	fn test_parse_memory_limit() {
+		assert_eq!(parse_memory_limit("536870912\n"), Some(536_870_912));
+		assert_eq!(parse_memory_limit("max\n"), None);
+		assert_eq!(parse_memory_limit("9223372036854771712\n"), None);
+	}
+}
@@ -0,0 +1,300 @@
+//! Rules deriving sync behavior from user attributes
+//!
+//! Rules are evaluated in order for every source user. A rule whose
+//! condition holds can exclude the user from the sync, grant
+//! additional project roles, and set attributes from templates.
+//! Attributes set by a rule are visible to the conditions of all
+//! following rules.
+use std::collections::{BTreeMap, BTreeSet};
+
+use anyhow::{bail, Result};
+use serde::Deserialize;
+
+use crate::user::{User, USER_FIELDS};
+
+pub mod expression;
+
+pub use expression::{Expression, ExpressionError, Template};
+
+/// User fields which identify a user, and may therefore not be set
+/// by rules
+const IMMUTABLE_FIELDS: &[&str] = &["email", "external_user_id", "enabled"];
+
+/// A rule evaluated against each source user
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct Rule {
+	/// The name of the rule, used in logs and explanations
+	pub name: String,
+	/// The condition under which the rule applies; the rule applies
+	/// to all users if unset
+	pub when: Option<Expression>,
+	/// The DN of a source group, e.g. from LDAP's `memberOf`, the rule
+	/// only applies to members of. DNs are compared case-insensitively.
+	pub member_of: Option<String>,
+	/// Whether to exclude matching users from the sync. Excluded
+	/// users are treated as if they were not in the source.
+	#[serde(default)]
+	pub exclude: bool,
+	/// Project roles granted in addition to the default role
+	#[serde(default)]
+	pub add_roles: Vec<String>,
+	/// Attributes to set, as templates over the user's attributes.
+	/// Attributes which aren't user fields are synced as metadata.
+	#[serde(default)]
+	pub set: BTreeMap<String, Template>,
+}
+
+impl Rule {
+	/// Whether the rule applies to the given user
+	fn matches(&self, user: &User) -> bool {
+		self.member_of.as_ref().map_or(true, |group| {
+			user.groups.iter().any(|member_of| member_of.eq_ignore_ascii_case(group))
+		}) && self.when.as_ref().map_or(true, |when| when.evaluate(|name| user.get_attribute(name)))
+	}
+}
+
+/// The outcome of evaluating rules for a user
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RuleTrace {
+	/// Names of the rules which applied, in order
+	pub fired: Vec<String>,
+	/// Whether the user was excluded from the sync
+	pub excluded: bool,
+}
+
+/// Validate that rules only set attributes which may be set
+pub fn validate_rules(rules: &[Rule]) -> Result<()> {
+	for rule in rules {
+		if let Some(field) = rule.set.keys().find(|key| IMMUTABLE_FIELDS.contains(&key.as_str())) {
+			bail!("Rule `{}` must not set `{}`", rule.name, field);
+		}
+	}
+
+	Ok(())
+}
+
+/// The metadata keys set by rules, i.e. all set attributes which
+/// aren't user fields
+pub fn metadata_keys(rules: &[Rule]) -> impl Iterator<Item = &String> {
+	rules.iter().flat_map(|rule| rule.set.keys()).filter(|key| !USER_FIELDS.contains(&key.as_str()))
+}
+
+/// Apply rules to a user, setting its roles and derived attributes.
+/// Evaluation stops at the first rule excluding the user.
+pub fn apply_rules(rules: &[Rule], user: &mut User) -> RuleTrace {
+	let mut trace = RuleTrace::default();
+	user.roles.clear();
+
+	for rule in rules {
+		if !rule.matches(user) {
+			continue;
+		}
+
+		trace.fired.push(rule.name.clone());
+
+		if rule.exclude {
+			trace.excluded = true;
+			break;
+		}
+
+		user.roles.extend(rule.add_roles.iter().cloned());
+
+		let values: Vec<_> = rule
+			.set
+			.iter()
+			.map(|(name, template)| (name, template.render(|name| user.get_attribute(name))))
+			.collect();
+		for (name, value) in values {
+			user.set_attribute(name, value);
+		}
+	}
+
+	trace
+}
+
+/// The project roles the rules grant, which are the only roles beyond
+/// the default role the sync adds to or removes from users
+pub(crate) fn managed_roles(rules: &[Rule]) -> BTreeSet<String> {
+	rules.iter().flat_map(|rule| rule.add_roles.iter().cloned()).collect()
+}
+
+/// Get the project role keys to grant to a user, given the keys already
+/// granted, the role granted to all users and the user's roles
+///
+/// Only managed roles are added or removed, so that roles granted by
+/// hand are kept.
+pub(crate) fn merge_role_keys(
+	granted: &[String],
+	user_role: &str,
+	roles: &BTreeSet<String>,
+	managed_roles: &BTreeSet<String>,
+) -> Vec<String> {
+	let mut role_keys: Vec<String> = granted
+		.iter()
+		.filter(|role| !managed_roles.contains(*role) || roles.contains(*role))
+		.cloned()
+		.collect();
+	for role in std::iter::once(user_role).chain(roles.iter().map(String::as_str)) {
+		if !role_keys.iter().any(|key| key == role) {
+			role_keys.push(role.to_owned());
+		}
+	}
+	role_keys
+}
+
+#[cfg(test)]
+mod tests {
+	use indoc::indoc;
+
+	use super::*;
+
+	const EXAMPLE_RULES: &str = indoc! {r#"
+        - name: exclude-service-accounts
+          when: 'title == "Service Account"'
+          exclude: true
+        - name: admins
+          when: 'department == "IT"'
+          add_roles: [Admin]
+        - name: clinicians
+          when: 'title in ["Physician", "Nurse"] && department != "IT"'
+          add_roles: [Clinician]
+        - name: ward
+          set:
+            ward: "{department}-{title}"
+            preferred_username: "{first_name}.{last_name}"
+        - name: radiology-ward
+          when: 'ward starts_with "Radiology-"'
+          add_roles: [Staff]
+	"#};
+
+	fn load_rules() -> Vec<Rule> {
+		serde_yaml::from_str(EXAMPLE_RULES).expect("invalid rules")
+	}
+
+	fn user(metadata: &[(&str, &str)]) -> User {
+		let mut user = User::new(
+			"Jane".to_owned(),
+			"Doe".to_owned(),
+			"jane.doe@example.com".to_owned(),
+			None,
+			true,
+			None,
+			"jane.doe".to_owned(),
+			None,
+		);
+		user.metadata =
+			metadata.iter().map(|(key, value)| ((*key).to_owned(), (*value).to_owned())).collect();
+		user
+	}
+
+	#[test]
+	fn test_matching_rules() {
+		let mut user = user(&[("department", "Radiology"), ("title", "Nurse")]);
+		let trace = apply_rules(&load_rules(), &mut user);
+
+		assert_eq!(trace.fired, vec!["clinicians", "ward", "radiology-ward"]);
+		assert!(!trace.excluded);
+		assert_eq!(user.roles, BTreeSet::from(["Clinician".to_owned(), "Staff".to_owned()]));
+		assert_eq!(user.metadata.get("ward").map(String::as_str), Some("Radiology-Nurse"));
+		assert_eq!(user.preferred_username.as_deref(), Some("Jane.Doe"));
+	}
+
+	#[test]
+	fn test_excluding_rule() {
+		let mut user = user(&[("department", "IT"), ("title", "Service Account")]);
+		let trace = apply_rules(&load_rules(), &mut user);
+
+		assert_eq!(trace.fired, vec!["exclude-service-accounts"]);
+		assert!(trace.excluded);
+		assert!(user.roles.is_empty());
+	}
+
+	#[test]
+	fn test_group_rules() {
+		let rules: Vec<Rule> = serde_yaml::from_str(indoc! {r#"
+            - name: admins
+              member_of: cn=admins,ou=groups,dc=example,dc=org
+              add_roles: [Admin]
+            - name: clinicians
+              member_of: cn=clinicians,ou=groups,dc=example,dc=org
+              when: 'title != "Intern"'
+              add_roles: [Clinician]
+        "#})
+		.expect("invalid rules");
+
+		let mut user = user(&[("title", "Physician")]);
+		user.groups = BTreeSet::from([
+			"CN=Admins,OU=Groups,DC=example,DC=org".to_owned(),
+			"cn=clinicians,ou=groups,dc=example,dc=org".to_owned(),
+		]);
+		let trace = apply_rules(&rules, &mut user);
+		assert_eq!(trace.fired, vec!["admins", "clinicians"]);
+		assert_eq!(user.roles, BTreeSet::from(["Admin".to_owned(), "Clinician".to_owned()]));
+
+		user.metadata.insert("title".to_owned(), "Intern".to_owned());
+		user.groups.remove("CN=Admins,OU=Groups,DC=example,DC=org");
+		let trace = apply_rules(&rules, &mut user);
+		assert!(trace.fired.is_empty());
+		assert!(user.roles.is_empty());
+	}
+
+	#[test]
+	fn test_roles_are_recomputed() {
+		let mut user = user(&[]);
+		user.roles.insert("Admin".to_owned());
+
+		let trace = apply_rules(&load_rules(), &mut user);
+
+		assert_eq!(trace.fired, vec!["ward"]);
+		assert!(user.roles.is_empty());
+		assert_eq!(user.metadata.get("ward").map(String::as_str), Some("-"));
+	}
+
+	#[test]
+	fn test_merge_role_keys() {
+		let managed_roles = managed_roles(&load_rules());
+		assert_eq!(
+			managed_roles,
+			BTreeSet::from(["Admin".to_owned(), "Clinician".to_owned(), "Staff".to_owned()])
+		);
+
+		let granted: Vec<String> =
+			["User", "Admin", "Auditor"].into_iter().map(ToOwned::to_owned).collect();
+		let roles = BTreeSet::from(["Clinician".to_owned()]);
+
+		// Roles granted by hand are kept, while managed roles follow the
+		// rules
+		assert_eq!(
+			merge_role_keys(&granted, "User", &roles, &managed_roles),
+			vec!["User", "Auditor", "Clinician"]
+		);
+		assert_eq!(merge_role_keys(&[], "User", &BTreeSet::new(), &managed_roles), vec!["User"]);
+	}
+
+	#[test]
+	fn test_invalid_rules() {
+		let rules: Vec<Rule> = serde_yaml::from_str(indoc! {r#"
+            - name: identity
+              set:
+                email: "{first_name}@example.com"
+		"#})
+		.expect("invalid rules");
+		assert_eq!(
+			validate_rules(&rules).expect_err("accepted invalid rules").to_string(),
+			"Rule `identity` must not set `email`"
+		);
+
+		let error = serde_yaml::from_str::<Vec<Rule>>(indoc! {r#"
+            - name: broken
+              when: 'department = "IT"'
+		"#})
+		.expect_err("accepted invalid expression");
+		assert!(error.to_string().contains("Unexpected character `=` at position 11"));
+	}
+
+	#[test]
+	fn test_metadata_keys() {
+		let rules = load_rules();
+		assert_eq!(metadata_keys(&rules).collect::<Vec<_>>(), vec!["ward"]);
+	}
+}
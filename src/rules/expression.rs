@@ -0,0 +1,684 @@
+//! A small, safe expression language for rule conditions and
+//! attribute templates.
+//!
+//! Conditions compare user attributes with string literals, e.g.
+//! `department in ["IT", "Radiology"] && title != "Intern"`. The
+//! supported comparisons are `==`, `!=`, `in`, `starts_with`,
+//! `ends_with` and `contains`, which can be combined with `&&`, `||`,
+//! `!` and parentheses. A bare attribute is true if it is set, not
+//! empty and not `"false"`. Comparisons with unset attributes are
+//! false, except for `!=`.
+//!
+//! Templates are strings with `{attribute}` placeholders, e.g.
+//! `"{first_name}.{last_name}"`. `{{` and `}}` produce literal braces.
+use std::fmt;
+
+use serde::Deserialize;
+
+/// An error encountered while parsing an expression or template
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExpressionError {
+	/// Description of the problem
+	message: String,
+	/// The character offset at which the problem was found
+	position: usize,
+	/// The expression or template which failed to parse
+	source: String,
+}
+
+impl fmt::Display for ExpressionError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{} at position {} of `{}`", self.message, self.position, self.source)
+	}
+}
+
+impl std::error::Error for ExpressionError {}
+
+/// A token of the expression language
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+	/// An attribute name or keyword
+	Identifier(String),
+	/// A quoted string literal
+	String(String),
+	/// `==`
+	Equals,
+	/// `!=`
+	NotEquals,
+	/// `!`
+	Not,
+	/// `&&`
+	And,
+	/// `||`
+	Or,
+	/// `(`
+	OpenParen,
+	/// `)`
+	CloseParen,
+	/// `[`
+	OpenBracket,
+	/// `]`
+	CloseBracket,
+	/// `,`
+	Comma,
+}
+
+impl fmt::Display for Token {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Identifier(name) => write!(f, "`{name}`"),
+			Self::String(value) => write!(f, "{value:?}"),
+			Self::Equals => write!(f, "`==`"),
+			Self::NotEquals => write!(f, "`!=`"),
+			Self::Not => write!(f, "`!`"),
+			Self::And => write!(f, "`&&`"),
+			Self::Or => write!(f, "`||`"),
+			Self::OpenParen => write!(f, "`(`"),
+			Self::CloseParen => write!(f, "`)`"),
+			Self::OpenBracket => write!(f, "`[`"),
+			Self::CloseBracket => write!(f, "`]`"),
+			Self::Comma => write!(f, "`,`"),
+		}
+	}
+}
+
+/// Whether a character may appear in an attribute name
+fn is_identifier_char(c: char) -> bool {
+	c.is_alphanumeric() || matches!(c, '_' | '-' | '.' | ':')
+}
+
+/// Split an expression into tokens and their positions
+fn tokenize(source: &str) -> Result<Vec<(usize, Token)>, ExpressionError> {
+	let error = |message: &str, position: usize| ExpressionError {
+		message: message.to_owned(),
+		position,
+		source: source.to_owned(),
+	};
+
+	let mut tokens = Vec::new();
+	let mut chars = source.chars().enumerate().peekable();
+
+	while let Some((position, c)) = chars.next() {
+		let token = match c {
+			c if c.is_whitespace() => continue,
+			'(' => Token::OpenParen,
+			')' => Token::CloseParen,
+			'[' => Token::OpenBracket,
+			']' => Token::CloseBracket,
+			',' => Token::Comma,
+			'=' if chars.next_if(|(_, c)| *c == '=').is_some() => Token::Equals,
+			'!' if chars.next_if(|(_, c)| *c == '=').is_some() => Token::NotEquals,
+			'!' => Token::Not,
+			'&' if chars.next_if(|(_, c)| *c == '&').is_some() => Token::And,
+			'|' if chars.next_if(|(_, c)| *c == '|').is_some() => Token::Or,
+			'"' | '\'' => {
+				let quote = c;
+				let mut value = String::new();
+				loop {
+					match chars.next() {
+						Some((_, c)) if c == quote => break,
+						Some((_, '\\')) => match chars.next() {
+							Some((_, c)) => value.push(c),
+							None => return Err(error("Unterminated string literal", position)),
+						},
+						Some((_, c)) => value.push(c),
+						None => return Err(error("Unterminated string literal", position)),
+					}
+				}
+				Token::String(value)
+			}
+			c if is_identifier_char(c) => {
+				let mut name = c.to_string();
+				while let Some((_, c)) = chars.next_if(|(_, c)| is_identifier_char(*c)) {
+					name.push(c);
+				}
+				Token::Identifier(name)
+			}
+			c => return Err(error(&format!("Unexpected character `{c}`"), position)),
+		};
+
+		tokens.push((position, token));
+	}
+
+	Ok(tokens)
+}
+
+/// A comparison operator
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Comparison {
+	/// `==`
+	Equals,
+	/// `!=`
+	NotEquals,
+	/// `starts_with`
+	StartsWith,
+	/// `ends_with`
+	EndsWith,
+	/// `contains`
+	Contains,
+}
+
+/// A side of a comparison
+#[derive(Debug, Clone, PartialEq)]
+enum Operand {
+	/// The value of an attribute
+	Attribute(String),
+	/// A string literal
+	Literal(String),
+}
+
+impl Operand {
+	/// Resolve the operand to its value
+	fn resolve(&self, lookup: &impl Fn(&str) -> Option<String>) -> Option<String> {
+		match self {
+			Self::Attribute(name) => lookup(name),
+			Self::Literal(value) => Some(value.clone()),
+		}
+	}
+}
+
+/// A node of a parsed expression
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+	/// `true` or `false`
+	Bool(bool),
+	/// A bare attribute
+	Attribute(String),
+	/// A comparison of two operands
+	Compare(Operand, Comparison, Operand),
+	/// An `in` test against a list of literals
+	In(Operand, Vec<String>),
+	/// A negation
+	Not(Box<Node>),
+	/// A conjunction
+	And(Box<Node>, Box<Node>),
+	/// A disjunction
+	Or(Box<Node>, Box<Node>),
+}
+
+impl Node {
+	/// Evaluate the node
+	fn evaluate(&self, lookup: &impl Fn(&str) -> Option<String>) -> bool {
+		match self {
+			Self::Bool(value) => *value,
+			Self::Attribute(name) => {
+				lookup(name).is_some_and(|value| !value.is_empty() && value != "false")
+			}
+			Self::Compare(left, comparison, right) => {
+				match (left.resolve(lookup), right.resolve(lookup)) {
+					(Some(left), Some(right)) => match comparison {
+						Comparison::Equals => left == right,
+						Comparison::NotEquals => left != right,
+						Comparison::StartsWith => left.starts_with(&right),
+						Comparison::EndsWith => left.ends_with(&right),
+						Comparison::Contains => left.contains(&right),
+					},
+					_ => *comparison == Comparison::NotEquals,
+				}
+			}
+			Self::In(operand, values) => {
+				operand.resolve(lookup).is_some_and(|value| values.contains(&value))
+			}
+			Self::Not(node) => !node.evaluate(lookup),
+			Self::And(left, right) => left.evaluate(lookup) && right.evaluate(lookup),
+			Self::Or(left, right) => left.evaluate(lookup) || right.evaluate(lookup),
+		}
+	}
+}
+
+/// Recursive descent parser for expressions
+struct Parser<'a> {
+	/// The expression being parsed
+	source: &'a str,
+	/// The tokens of the expression
+	tokens: Vec<(usize, Token)>,
+	/// The index of the next token
+	index: usize,
+}
+
+impl Parser<'_> {
+	/// Construct an error at the current token
+	fn error(&self, message: String) -> ExpressionError {
+		let position = self
+			.tokens
+			.get(self.index)
+			.map_or_else(|| self.source.chars().count(), |(position, _)| *position);
+		ExpressionError { message, position, source: self.source.to_owned() }
+	}
+
+	/// Look at the next token
+	fn peek(&self) -> Option<&Token> {
+		self.tokens.get(self.index).map(|(_, token)| token)
+	}
+
+	/// Consume the next token
+	fn next(&mut self) -> Option<Token> {
+		let token = self.peek().cloned();
+		self.index += 1;
+		token
+	}
+
+	/// Consume the next token if it is the given one
+	fn next_if(&mut self, token: &Token) -> bool {
+		if self.peek() == Some(token) {
+			self.index += 1;
+			true
+		} else {
+			false
+		}
+	}
+
+	/// Describe the next token for error messages
+	fn describe_next(&self) -> String {
+		self.peek().map_or_else(|| "end of expression".to_owned(), ToString::to_string)
+	}
+
+	/// `or := and ("||" and)*`
+	fn parse_or(&mut self) -> Result<Node, ExpressionError> {
+		let mut node = self.parse_and()?;
+		while self.next_if(&Token::Or) {
+			node = Node::Or(Box::new(node), Box::new(self.parse_and()?));
+		}
+		Ok(node)
+	}
+
+	/// `and := unary ("&&" unary)*`
+	fn parse_and(&mut self) -> Result<Node, ExpressionError> {
+		let mut node = self.parse_unary()?;
+		while self.next_if(&Token::And) {
+			node = Node::And(Box::new(node), Box::new(self.parse_unary()?));
+		}
+		Ok(node)
+	}
+
+	/// `unary := "!" unary | primary`
+	fn parse_unary(&mut self) -> Result<Node, ExpressionError> {
+		if self.next_if(&Token::Not) {
+			Ok(Node::Not(Box::new(self.parse_unary()?)))
+		} else {
+			self.parse_primary()
+		}
+	}
+
+	/// `primary := "(" or ")" | "true" | "false" | operand [comparison]`
+	fn parse_primary(&mut self) -> Result<Node, ExpressionError> {
+		if self.next_if(&Token::OpenParen) {
+			let node = self.parse_or()?;
+			if !self.next_if(&Token::CloseParen) {
+				return Err(self.error(format!("Expected `)`, found {}", self.describe_next())));
+			}
+			return Ok(node);
+		}
+
+		let left = match self.peek() {
+			Some(Token::Identifier(name)) if name == "true" => {
+				self.index += 1;
+				return Ok(Node::Bool(true));
+			}
+			Some(Token::Identifier(name)) if name == "false" => {
+				self.index += 1;
+				return Ok(Node::Bool(false));
+			}
+			Some(Token::Identifier(name)) => Operand::Attribute(name.clone()),
+			Some(Token::String(value)) => Operand::Literal(value.clone()),
+			_ => {
+				return Err(self.error(format!(
+					"Expected an attribute, string or `(`, found {}",
+					self.describe_next()
+				)))
+			}
+		};
+		self.index += 1;
+
+		let comparison = match self.peek() {
+			Some(Token::Equals) => Comparison::Equals,
+			Some(Token::NotEquals) => Comparison::NotEquals,
+			Some(Token::Identifier(name)) if name == "starts_with" => Comparison::StartsWith,
+			Some(Token::Identifier(name)) if name == "ends_with" => Comparison::EndsWith,
+			Some(Token::Identifier(name)) if name == "contains" => Comparison::Contains,
+			Some(Token::Identifier(name)) if name == "in" => {
+				self.index += 1;
+				return Ok(Node::In(left, self.parse_list()?));
+			}
+			_ => {
+				return match left {
+					Operand::Attribute(name) => Ok(Node::Attribute(name)),
+					Operand::Literal(_) => Err(self.error(format!(
+						"Expected a comparison after string literal, found {}",
+						self.describe_next()
+					))),
+				};
+			}
+		};
+		self.index += 1;
+
+		let right = match self.next() {
+			Some(Token::Identifier(name)) => Operand::Attribute(name),
+			Some(Token::String(value)) => Operand::Literal(value),
+			_ => {
+				self.index -= 1;
+				return Err(self.error(format!(
+					"Expected an attribute or string to compare with, found {}",
+					self.describe_next()
+				)));
+			}
+		};
+
+		Ok(Node::Compare(left, comparison, right))
+	}
+
+	/// `list := "[" [string ("," string)*] "]"`
+	fn parse_list(&mut self) -> Result<Vec<String>, ExpressionError> {
+		if !self.next_if(&Token::OpenBracket) {
+			return Err(
+				self.error(format!("Expected a list after `in`, found {}", self.describe_next()))
+			);
+		}
+
+		let mut values = Vec::new();
+		if self.next_if(&Token::CloseBracket) {
+			return Ok(values);
+		}
+
+		loop {
+			match self.next() {
+				Some(Token::String(value)) => values.push(value),
+				_ => {
+					self.index -= 1;
+					return Err(self.error(format!(
+						"Expected a string in list, found {}",
+						self.describe_next()
+					)));
+				}
+			}
+
+			if self.next_if(&Token::CloseBracket) {
+				return Ok(values);
+			}
+			if !self.next_if(&Token::Comma) {
+				return Err(
+					self.error(format!("Expected `,` or `]`, found {}", self.describe_next()))
+				);
+			}
+		}
+	}
+}
+
+/// A parsed rule condition
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Expression {
+	/// The source of the expression
+	source: String,
+	/// The parsed expression
+	root: Node,
+}
+
+impl Expression {
+	/// Parse an expression
+	pub fn parse(source: &str) -> Result<Self, ExpressionError> {
+		let mut parser = Parser { source, tokens: tokenize(source)?, index: 0 };
+		let root = parser.parse_or()?;
+
+		if parser.peek().is_some() {
+			return Err(parser.error(format!("Unexpected {}", parser.describe_next())));
+		}
+
+		Ok(Self { source: source.to_owned(), root })
+	}
+
+	/// Evaluate the expression, looking up attribute values with the
+	/// given function
+	pub fn evaluate(&self, lookup: impl Fn(&str) -> Option<String>) -> bool {
+		self.root.evaluate(&lookup)
+	}
+}
+
+impl TryFrom<String> for Expression {
+	type Error = ExpressionError;
+
+	fn try_from(source: String) -> Result<Self, Self::Error> {
+		Self::parse(&source)
+	}
+}
+
+impl PartialEq for Expression {
+	fn eq(&self, other: &Self) -> bool {
+		self.source == other.source
+	}
+}
+
+impl fmt::Display for Expression {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}", self.source)
+	}
+}
+
+/// A part of a template
+#[derive(Debug, Clone, PartialEq)]
+enum TemplatePart {
+	/// Literal text
+	Text(String),
+	/// An attribute placeholder
+	Attribute(String),
+}
+
+/// A parsed attribute template
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Template {
+	/// The source of the template
+	source: String,
+	/// The parsed template
+	parts: Vec<TemplatePart>,
+}
+
+impl Template {
+	/// Parse a template
+	pub fn parse(source: &str) -> Result<Self, ExpressionError> {
+		let error = |message: &str, position: usize| ExpressionError {
+			message: message.to_owned(),
+			position,
+			source: source.to_owned(),
+		};
+
+		let mut parts = Vec::new();
+		let mut text = String::new();
+		let mut chars = source.chars().enumerate().peekable();
+
+		while let Some((position, c)) = chars.next() {
+			match c {
+				'{' if chars.next_if(|(_, c)| *c == '{').is_some() => text.push('{'),
+				'}' if chars.next_if(|(_, c)| *c == '}').is_some() => text.push('}'),
+				'{' => {
+					let mut name = String::new();
+					loop {
+						match chars.next() {
+							Some((_, '}')) => break,
+							Some((_, c)) if is_identifier_char(c) => name.push(c),
+							Some((position, c)) => {
+								return Err(error(
+									&format!("Unexpected character `{c}` in placeholder"),
+									position,
+								))
+							}
+							None => return Err(error("Unterminated placeholder", position)),
+						}
+					}
+					if name.is_empty() {
+						return Err(error("Empty placeholder", position));
+					}
+					if !text.is_empty() {
+						parts.push(TemplatePart::Text(std::mem::take(&mut text)));
+					}
+					parts.push(TemplatePart::Attribute(name));
+				}
+				'}' => return Err(error("Unmatched `}`", position)),
+				c => text.push(c),
+			}
+		}
+
+		if !text.is_empty() {
+			parts.push(TemplatePart::Text(text));
+		}
+
+		Ok(Self { source: source.to_owned(), parts })
+	}
+
+	/// Render the template, looking up attribute values with the
+	/// given function. Unset attributes render as empty strings.
+	pub fn render(&self, lookup: impl Fn(&str) -> Option<String>) -> String {
+		self.parts
+			.iter()
+			.map(|part| match part {
+				TemplatePart::Text(text) => text.clone(),
+				TemplatePart::Attribute(name) => lookup(name).unwrap_or_default(),
+			})
+			.collect()
+	}
+}
+
+impl TryFrom<String> for Template {
+	type Error = ExpressionError;
+
+	fn try_from(source: String) -> Result<Self, Self::Error> {
+		Self::parse(&source)
+	}
+}
+
+impl PartialEq for Template {
+	fn eq(&self, other: &Self) -> bool {
+		self.source == other.source
+	}
+}
+
+impl fmt::Display for Template {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}", self.source)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::HashMap;
+
+	use super::*;
+
+	fn attributes() -> HashMap<&'static str, &'static str> {
+		HashMap::from([
+			("department", "IT"),
+			("title", "Senior Nurse"),
+			("email", "jane.doe@example.com"),
+			("enabled", "true"),
+			("disabled", "false"),
+			("empty", ""),
+		])
+	}
+
+	fn evaluate(source: &str) -> bool {
+		let attributes = attributes();
+		Expression::parse(source)
+			.unwrap_or_else(|error| panic!("Failed to parse `{source}`: {error}"))
+			.evaluate(|name| attributes.get(name).map(|value| (*value).to_owned()))
+	}
+
+	#[test]
+	fn test_comparisons() {
+		assert!(evaluate(r#"department == "IT""#));
+		assert!(evaluate(r#"department == 'IT'"#));
+		assert!(!evaluate(r#"department != "IT""#));
+		assert!(evaluate(r#""IT" == department"#));
+		assert!(evaluate(r#"title starts_with "Senior""#));
+		assert!(evaluate(r#"email ends_with "@example.com""#));
+		assert!(evaluate(r#"title contains "Nurse""#));
+		assert!(evaluate(r#"department in ["Radiology", "IT"]"#));
+		assert!(!evaluate(r#"department in []"#));
+		assert!(evaluate(r#"department == department"#));
+	}
+
+	#[test]
+	fn test_unset_attributes() {
+		assert!(!evaluate(r#"missing == "IT""#));
+		assert!(evaluate(r#"missing != "IT""#));
+		assert!(!evaluate(r#"missing in ["IT"]"#));
+		assert!(!evaluate(r#"missing contains "IT""#));
+	}
+
+	#[test]
+	fn test_bare_attributes() {
+		assert!(evaluate("department"));
+		assert!(evaluate("enabled"));
+		assert!(!evaluate("disabled"));
+		assert!(!evaluate("empty"));
+		assert!(!evaluate("missing"));
+		assert!(evaluate("!missing"));
+		assert!(evaluate("true"));
+		assert!(!evaluate("false"));
+	}
+
+	#[test]
+	fn test_boolean_operators() {
+		assert!(evaluate(r#"department == "IT" && title contains "Nurse""#));
+		assert!(!evaluate(r#"department == "IT" && title == "Intern""#));
+		assert!(evaluate(r#"department == "HR" || title contains "Nurse""#));
+		assert!(evaluate(r#"!(department == "HR")"#));
+		// `&&` binds stronger than `||`
+		assert!(evaluate(r#"true || false && false"#));
+		assert!(!evaluate(r#"(true || false) && false"#));
+		assert!(evaluate(r#"!!department"#));
+	}
+
+	#[test]
+	fn test_escapes() {
+		let expression = Expression::parse(r#"title == "a \"quoted\" \\ value""#)
+			.expect("failed to parse expression");
+		assert!(expression.evaluate(|_| Some(r#"a "quoted" \ value"#.to_owned())));
+	}
+
+	#[test]
+	fn test_parse_errors() {
+		let cases = [
+			(r#"department == "#, "Expected an attribute or string to compare with, found end of expression at position 14"),
+			(r#"department = "IT""#, "Unexpected character `=` at position 11"),
+			(r#"department == "IT"#, "Unterminated string literal at position 14"),
+			(r#"department in "IT""#, "Expected a list after `in`, found \"IT\" at position 14"),
+			(r#"department in ["IT" "HR"]"#, "Expected `,` or `]`, found \"HR\" at position 20"),
+			(r#"department in [IT]"#, "Expected a string in list, found `IT` at position 15"),
+			(r#"(department"#, "Expected `)`, found end of expression at position 11"),
+			(r#""IT""#, "Expected a comparison after string literal, found end of expression at position 4"),
+			(r#"department "IT""#, "Unexpected \"IT\" at position 11"),
+			(r#"&& department"#, "Expected an attribute, string or `(`, found `&&` at position 0"),
+			("", "Expected an attribute, string or `(`, found end of expression at position 0"),
+		];
+
+		for (source, message) in cases {
+			let error = Expression::parse(source).expect_err(source);
+			assert_eq!(error.to_string(), format!("{message} of `{source}`"));
+		}
+	}
+
+	#[test]
+	fn test_templates() {
+		let attributes = attributes();
+		let lookup = |name: &str| attributes.get(name).map(|value| (*value).to_owned());
+
+		let template = Template::parse("{department}-{title}").expect("failed to parse template");
+		assert_eq!(template.render(lookup), "IT-Senior Nurse");
+
+		let template = Template::parse("{{literal}} {missing}!").expect("failed to parse template");
+		assert_eq!(template.render(lookup), "{literal} !");
+
+		assert_eq!(
+			Template::parse("{department").expect_err("parsed invalid template").to_string(),
+			"Unterminated placeholder at position 0 of `{department`"
+		);
+		assert_eq!(
+			Template::parse("a}").expect_err("parsed invalid template").to_string(),
+			"Unmatched `}` at position 1 of `a}`"
+		);
+		assert_eq!(
+			Template::parse("{}").expect_err("parsed invalid template").to_string(),
+			"Empty placeholder at position 0 of `{}`"
+		);
+	}
+}
@@ -0,0 +1,588 @@
+//! SCIM 2.0 server, allowing identity providers to push users
+//!
+//! Users pushed by SCIM clients take the same path to Zitadel as
+//! users read from sources: rules are applied to them, users outside
+//! of the user scope are invisible, disabled and excluded users are
+//! deleted, and all writes are recorded in the audit log.
+use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+
+use anyhow::{bail, Context, Result};
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::{
+	body::{Bytes, Incoming},
+	header::{HeaderValue, AUTHORIZATION, CONTENT_TYPE},
+	server::conn::http1,
+	service::service_fn,
+	Method, Request, Response, StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+use tokio::{net::TcpListener, sync::Mutex};
+use tracing::Instrument;
+
+use crate::{
+	complete_zitadel_user,
+	config::Config,
+	get_next_zitadel_user, hooks,
+	report::{DeletionReason, Operation, Reporter},
+	rules, spans,
+	user::User,
+	user_cache,
+	zitadel::{UniqueAttributeTaken, Zitadel},
+	FeatureFlag,
+};
+
+mod resource;
+
+use resource::{
+	parse_eq_filter, ListResponse, PatchRequest, ScimUser, ERROR_SCHEMA, LIST_RESPONSE_SCHEMA,
+};
+
+/// The maximum size of request bodies, in bytes
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+/// The number of users returned per page by default
+const DEFAULT_PAGE_SIZE: usize = 100;
+
+/// Configuration of the SCIM server
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct ScimConfig {
+	/// The address to listen on, e.g. `0.0.0.0:8080`
+	pub listen_address: SocketAddr,
+	/// The bearer token SCIM clients authenticate with
+	pub bearer_token: String,
+}
+
+/// The result of a SCIM request: a status and an optional body
+type ScimResult = Result<(StatusCode, Option<Value>), ScimError>;
+
+/// An error returned to SCIM clients
+#[derive(Debug)]
+struct ScimError {
+	/// The HTTP status
+	status: StatusCode,
+	/// The SCIM error type, if any
+	scim_type: Option<&'static str>,
+	/// A human-readable description of the error
+	detail: String,
+}
+
+impl ScimError {
+	/// Create a new error
+	fn new(status: StatusCode, detail: impl ToString) -> Self {
+		Self { status, scim_type: None, detail: detail.to_string() }
+	}
+
+	/// Create an error for invalid requests
+	fn bad_request(error: anyhow::Error) -> Self {
+		Self {
+			scim_type: Some("invalidValue"),
+			..Self::new(StatusCode::BAD_REQUEST, format!("{error:#}"))
+		}
+	}
+
+	/// Create an error for unknown users
+	fn user_not_found(zitadel_id: &str) -> Self {
+		Self::new(StatusCode::NOT_FOUND, format!("User `{zitadel_id}` not found"))
+	}
+
+	/// Convert the error into a SCIM error response
+	fn into_response(self) -> (StatusCode, Option<Value>) {
+		let mut body = json!({
+			"schemas": [ERROR_SCHEMA],
+			"status": self.status.as_str(),
+			"detail": self.detail,
+		});
+		if let Some(scim_type) = self.scim_type {
+			body["scimType"] = json!(scim_type);
+		}
+
+		(self.status, Some(body))
+	}
+}
+
+impl From<anyhow::Error> for ScimError {
+	fn from(error: anyhow::Error) -> Self {
+		if error.is::<UniqueAttributeTaken>() {
+			return Self {
+				scim_type: Some("uniqueness"),
+				..Self::new(StatusCode::CONFLICT, format!("{error:#}"))
+			};
+		}
+
+		tracing::error!("Failed to handle SCIM request: {:?}", error);
+		Self::new(StatusCode::INTERNAL_SERVER_ERROR, format!("{error:#}"))
+	}
+}
+
+/// The state shared by all SCIM requests
+struct ScimServer {
+	/// The sync configuration
+	config: Config,
+	/// The bearer token SCIM clients authenticate with
+	bearer_token: String,
+	/// The Zitadel client, which also serializes all writes
+	zitadel: Mutex<Zitadel>,
+	/// The reporter recording all writes
+	reporter: Mutex<Reporter>,
+}
+
+/// Serve the SCIM API until the process is stopped
+pub async fn serve_scim(config: &Config) -> Result<()> {
+	serve(config).instrument(spans::run_span("scim")).await
+}
+
+/// Serve the SCIM API until the process is stopped, logging within the
+/// current span
+async fn serve(config: &Config) -> Result<()> {
+	let scim_config = config.scim.clone().context("The SCIM server is not configured")?;
+	if scim_config.bearer_token.is_empty() {
+		bail!("The SCIM bearer token must not be empty");
+	}
+
+	let mut zitadel = Zitadel::new(config).await?;
+	zitadel.preflight().await?;
+	// Users are written for as long as the server runs, so the cache
+	// can only be refreshed once it expires
+	user_cache::invalidate(config)?;
+
+	let server = Arc::new(ScimServer {
+		config: config.clone(),
+		bearer_token: scim_config.bearer_token,
+		zitadel: Mutex::new(zitadel),
+		reporter: Mutex::new(
+			Reporter::new(&config.reporting, config.feature_flags.is_enabled(FeatureFlag::DryRun))
+				.with_deletions_dry_run(
+					config.feature_flags.is_enabled(FeatureFlag::DryRunDeletions),
+				)
+				.with_language(config.language)
+				.with_config_fingerprint(&config.fingerprint),
+		),
+	});
+
+	let listener = TcpListener::bind(scim_config.listen_address)
+		.await
+		.context(format!("Failed to listen on {}", scim_config.listen_address))?;
+	tracing::info!("SCIM server listening on {}", scim_config.listen_address);
+
+	loop {
+		let (stream, peer) = match listener.accept().await {
+			Ok(connection) => connection,
+			Err(error) => {
+				tracing::warn!("Failed to accept SCIM connection: {}", error);
+				continue;
+			}
+		};
+
+		let server = server.clone();
+		tokio::spawn(
+			async move {
+				let service = service_fn(move |request| {
+					let server = server.clone();
+					async move { Ok::<_, Infallible>(server.handle(request).await) }
+				});
+
+				if let Err(error) =
+					http1::Builder::new().serve_connection(TokioIo::new(stream), service).await
+				{
+					tracing::debug!("Failed to serve SCIM connection from {}: {}", peer, error);
+				}
+			}
+			.in_current_span(),
+		);
+	}
+}
+
+impl ScimServer {
+	/// Handle a SCIM request
+	async fn handle(&self, request: Request<Incoming>) -> Response<Full<Bytes>> {
+		let method = request.method().clone();
+		let path = request.uri().path().to_owned();
+
+		let (status, body) = self.route(request).await.unwrap_or_else(ScimError::into_response);
+		tracing::info!("SCIM {} {}: {}", method, path, status);
+
+		let mut response = Response::new(Full::new(
+			body.map(|body| Bytes::from(body.to_string())).unwrap_or_default(),
+		));
+		*response.status_mut() = status;
+		response
+			.headers_mut()
+			.insert(CONTENT_TYPE, HeaderValue::from_static("application/scim+json"));
+		response
+	}
+
+	/// Authorize a request and dispatch it to its handler
+	async fn route(&self, request: Request<Incoming>) -> ScimResult {
+		self.authorize(&request)?;
+
+		let method = request.method().clone();
+		let path = request.uri().path().trim_end_matches('/').to_owned();
+		let query = request.uri().query().unwrap_or_default().to_owned();
+		let body = Limited::new(request.into_body(), MAX_BODY_SIZE)
+			.collect()
+			.await
+			.map_err(|error| ScimError::new(StatusCode::PAYLOAD_TOO_LARGE, error))?
+			.to_bytes();
+
+		let resource_path = path.strip_prefix("/scim/v2").unwrap_or_default();
+		let zitadel_id = resource_path.strip_prefix("/Users/");
+
+		match (method, resource_path, zitadel_id) {
+			(Method::GET, "/ServiceProviderConfig", _) => {
+				Ok((StatusCode::OK, Some(service_provider_config())))
+			}
+			(Method::GET, "/Users", _) => self.list_users(&query).await,
+			(Method::POST, "/Users", _) => self.create_user(parse_body(&body)?).await,
+			(Method::GET, _, Some(zitadel_id)) => self.get_user(zitadel_id).await,
+			(Method::PUT, _, Some(zitadel_id)) => {
+				self.replace_user(zitadel_id, parse_body(&body)?).await
+			}
+			(Method::PATCH, _, Some(zitadel_id)) => {
+				self.patch_user(zitadel_id, parse_body(&body)?).await
+			}
+			(Method::DELETE, _, Some(zitadel_id)) => self.delete_user(zitadel_id).await,
+			_ => Err(ScimError::new(StatusCode::NOT_FOUND, format!("No resource at `{path}`"))),
+		}
+	}
+
+	/// Check the bearer token of a request
+	fn authorize(&self, request: &Request<Incoming>) -> Result<(), ScimError> {
+		let token = request
+			.headers()
+			.get(AUTHORIZATION)
+			.and_then(|value| value.to_str().ok())
+			.and_then(|value| value.strip_prefix("Bearer "));
+
+		if token
+			.is_some_and(|token| constant_time_eq(token.as_bytes(), self.bearer_token.as_bytes()))
+		{
+			Ok(())
+		} else {
+			Err(ScimError::new(StatusCode::UNAUTHORIZED, "Invalid bearer token"))
+		}
+	}
+
+	/// List users, optionally filtered by `userName`
+	async fn list_users(&self, query: &str) -> ScimResult {
+		let mut filter = None;
+		let mut start_index = 1;
+		let mut count = DEFAULT_PAGE_SIZE;
+		for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+			match key.as_ref() {
+				"filter" => filter = Some(value.into_owned()),
+				"startIndex" => start_index = value.parse().unwrap_or(1).max(1),
+				"count" => count = value.parse().unwrap_or(DEFAULT_PAGE_SIZE),
+				_ => {}
+			}
+		}
+
+		let mut guard = self.zitadel.lock().await;
+		let zitadel = &mut *guard;
+		let mut users = Vec::new();
+
+		if let Some(filter) = filter {
+			let Some((_, user_name)) = parse_eq_filter(&filter)
+				.filter(|(attribute, _)| attribute.eq_ignore_ascii_case("userName"))
+			else {
+				return Err(ScimError {
+					scim_type: Some("invalidFilter"),
+					..ScimError::new(
+						StatusCode::BAD_REQUEST,
+						"Only `userName eq` filters are supported",
+					)
+				});
+			};
+
+			let mut stream = zitadel.get_users_by_email(vec![user_name])?;
+			while let Some((user, zitadel_id)) = get_next_zitadel_user(&mut stream, zitadel).await?
+			{
+				users.push(ScimUser::from_user(&user, &zitadel_id));
+			}
+		} else {
+			let mut stream = zitadel.list_users()?;
+			while let Some((user, zitadel_id)) = get_next_zitadel_user(&mut stream, zitadel).await?
+			{
+				users.push(ScimUser::from_user(&user, &zitadel_id));
+			}
+		}
+
+		let total_results = users.len();
+		let resources: Vec<_> = users.into_iter().skip(start_index - 1).take(count).collect();
+		let response = ListResponse {
+			schemas: vec![LIST_RESPONSE_SCHEMA.to_owned()],
+			total_results,
+			start_index,
+			items_per_page: resources.len(),
+			resources,
+		};
+
+		Ok((StatusCode::OK, Some(serde_json::to_value(response).map_err(anyhow::Error::from)?)))
+	}
+
+	/// Get a single user
+	async fn get_user(&self, zitadel_id: &str) -> ScimResult {
+		let mut zitadel = self.zitadel.lock().await;
+		let user = find_user(&mut zitadel, zitadel_id).await?;
+
+		user_response(StatusCode::OK, &user, zitadel_id)
+	}
+
+	/// Create a user
+	async fn create_user(&self, scim_user: ScimUser) -> ScimResult {
+		let mut user = scim_user.to_user(None).map_err(ScimError::bad_request)?;
+		if !user.enabled {
+			return Err(ScimError::new(
+				StatusCode::BAD_REQUEST,
+				"Inactive users are not provisioned",
+			));
+		}
+		self.check_writable()?;
+
+		hooks::run_user_hooks(&mut user)?;
+		user.fill_missing_names(self.config.name_fallback.as_ref());
+		check_readable(&user)?;
+		let trace = rules::apply_rules(&self.config.rules, &mut user);
+		if trace.excluded {
+			return Err(ScimError::new(
+				StatusCode::FORBIDDEN,
+				format!(
+					"User is excluded by rule `{}`",
+					trace.fired.last().map_or("", String::as_str)
+				),
+			));
+		}
+
+		let mut guard = self.zitadel.lock().await;
+		let zitadel = &mut *guard;
+
+		let mut stream = zitadel.get_users_by_email(vec![user.email.clone()])?;
+		if get_next_zitadel_user(&mut stream, zitadel).await?.is_some() {
+			return Err(ScimError {
+				scim_type: Some("uniqueness"),
+				..ScimError::new(
+					StatusCode::CONFLICT,
+					format!("A user with the email address `{}` already exists", user.email),
+				)
+			});
+		}
+
+		let res = zitadel
+			.import_user(&user)
+			.instrument(spans::user_span(Operation::Create, Some(&user.external_user_id), None))
+			.await;
+		self.record(|reporter| {
+			reporter.record(Operation::Create, Some(&user.external_user_id), None, &res);
+		})
+		.await;
+		res?;
+
+		// Dry runs don't create a Zitadel user, so fall back to a
+		// stable ID
+		let mut zitadel_id = user.get_famedly_uuid()?;
+		let mut stream = zitadel.get_users_by_email(vec![user.email.clone()])?;
+		while let Some((created_user, id)) = get_next_zitadel_user(&mut stream, zitadel).await? {
+			if created_user.external_user_id == user.external_user_id {
+				zitadel_id = id;
+			}
+		}
+
+		user_response(StatusCode::CREATED, &user, &zitadel_id)
+	}
+
+	/// Replace a user
+	async fn replace_user(&self, zitadel_id: &str, scim_user: ScimUser) -> ScimResult {
+		let mut guard = self.zitadel.lock().await;
+		let zitadel = &mut *guard;
+		let existing_user = find_user(zitadel, zitadel_id).await?;
+
+		self.update_user(zitadel, zitadel_id, existing_user, &scim_user).await
+	}
+
+	/// Modify a user
+	async fn patch_user(&self, zitadel_id: &str, request: PatchRequest) -> ScimResult {
+		let mut guard = self.zitadel.lock().await;
+		let zitadel = &mut *guard;
+		let existing_user = find_user(zitadel, zitadel_id).await?;
+
+		let scim_user = ScimUser::from_user(&existing_user, zitadel_id)
+			.patch(&request)
+			.map_err(ScimError::bad_request)?;
+
+		self.update_user(zitadel, zitadel_id, existing_user, &scim_user).await
+	}
+
+	/// Delete a user
+	async fn delete_user(&self, zitadel_id: &str) -> ScimResult {
+		let mut guard = self.zitadel.lock().await;
+		let zitadel = &mut *guard;
+		let user = find_user(zitadel, zitadel_id).await?;
+
+		self.remove_user(zitadel, zitadel_id, &user, DeletionReason::DeletedViaScim).await?;
+
+		Ok((StatusCode::NO_CONTENT, None))
+	}
+
+	/// Update an existing user to match a SCIM user
+	async fn update_user(
+		&self,
+		zitadel: &mut Zitadel,
+		zitadel_id: &str,
+		existing_user: User,
+		scim_user: &ScimUser,
+	) -> ScimResult {
+		let mut user = scim_user.to_user(Some(&existing_user)).map_err(ScimError::bad_request)?;
+		hooks::run_user_hooks(&mut user)?;
+		user.fill_missing_names(self.config.name_fallback.as_ref());
+		check_readable(&user)?;
+		let trace = rules::apply_rules(&self.config.rules, &mut user);
+
+		// Disabling a user is the only change allowed when only
+		// deactivating users is enabled
+		if user.enabled || trace.excluded {
+			self.check_writable()?;
+		}
+
+		// As in syncs, disabled and excluded users are deleted
+		if !user.enabled || trace.excluded {
+			let reason = if trace.excluded {
+				DeletionReason::ExcludedByRule {
+					rule: trace.fired.last().cloned().unwrap_or_default(),
+				}
+			} else {
+				DeletionReason::DisabledInSource
+			};
+			self.remove_user(zitadel, zitadel_id, &user, reason).await?;
+
+			user.enabled = false;
+			return user_response(StatusCode::OK, &user, zitadel_id);
+		}
+
+		if user != existing_user {
+			let res = zitadel
+				.update_user(zitadel_id, &existing_user, &user)
+				.instrument(spans::user_span(
+					Operation::Update,
+					Some(&user.external_user_id),
+					Some(zitadel_id),
+				))
+				.await;
+			self.record(|reporter| {
+				reporter.record_update(
+					&user.external_user_id,
+					zitadel_id,
+					existing_user.diff(&user).into_iter().map(|(field, _, _)| field).collect(),
+					&res,
+				);
+			})
+			.await;
+			res?;
+		}
+
+		user_response(StatusCode::OK, &user, zitadel_id)
+	}
+
+	/// Delete a user, or only deactivate it if only deactivating users
+	/// is enabled
+	async fn remove_user(
+		&self,
+		zitadel: &mut Zitadel,
+		zitadel_id: &str,
+		user: &User,
+		reason: DeletionReason,
+	) -> Result<(), ScimError> {
+		let span =
+			spans::user_span(Operation::Delete, Some(&user.external_user_id), Some(zitadel_id));
+		let res = if self.config.feature_flags.is_enabled(FeatureFlag::DeactivateOnly) {
+			zitadel.deactivate_user(zitadel_id).instrument(span).await
+		} else {
+			zitadel.delete_user(zitadel_id).instrument(span).await
+		};
+		self.record(|reporter| {
+			reporter.record_deletion(Some(&user.external_user_id), zitadel_id, reason, &res);
+		})
+		.await;
+		res?;
+
+		Ok(())
+	}
+
+	/// Fail if only deactivations are enabled
+	fn check_writable(&self) -> Result<(), ScimError> {
+		if self.config.feature_flags.is_enabled(FeatureFlag::DeactivateOnly) {
+			return Err(ScimError::new(
+				StatusCode::FORBIDDEN,
+				"Only deactivating users is enabled",
+			));
+		}
+
+		Ok(())
+	}
+
+	/// Record writes, and flush them to the audit log right away
+	async fn record(&self, record: impl FnOnce(&mut Reporter)) {
+		let mut reporter = self.reporter.lock().await;
+		record(&mut reporter);
+		if let Err(error) = reporter.flush() {
+			tracing::error!("Failed to flush sync report: {:?}", error);
+		}
+	}
+}
+
+/// Get a Zitadel user managed by the sync
+async fn find_user(zitadel: &mut Zitadel, zitadel_id: &str) -> Result<User, ScimError> {
+	let user =
+		zitadel.get_user(zitadel_id).await?.ok_or_else(|| ScimError::user_not_found(zitadel_id))?;
+
+	complete_zitadel_user(zitadel, user, zitadel_id)
+		.await?
+		.ok_or_else(|| ScimError::user_not_found(zitadel_id))
+}
+
+/// Refuse users which can't be written to Zitadel, e.g. for lacking a
+/// name no `name_fallback` filled in
+fn check_readable(user: &User) -> Result<(), ScimError> {
+	match &user.unreadable {
+		Some(unreadable) => Err(ScimError::bad_request(anyhow::anyhow!("The user {unreadable}"))),
+		None => Ok(()),
+	}
+}
+
+/// Respond with a user
+fn user_response(status: StatusCode, user: &User, zitadel_id: &str) -> ScimResult {
+	let body =
+		serde_json::to_value(ScimUser::from_user(user, zitadel_id)).map_err(anyhow::Error::from)?;
+
+	Ok((status, Some(body)))
+}
+
+/// Parse a JSON request body
+fn parse_body<T: DeserializeOwned>(body: &[u8]) -> Result<T, ScimError> {
+	serde_json::from_slice(body).map_err(|error| {
+		ScimError::bad_request(anyhow::Error::from(error).context("Invalid request body"))
+	})
+}
+
+/// Compare two byte strings in constant time, so that the bearer token
+/// can't be guessed from response times
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+	a.len() == b.len() && a.iter().zip(b).fold(0, |difference, (a, b)| difference | (a ^ b)) == 0
+}
+
+/// The capabilities of the SCIM server
+fn service_provider_config() -> Value {
+	json!({
+		"schemas": ["urn:ietf:params:scim:schemas:core:2.0:ServiceProviderConfig"],
+		"patch": { "supported": true },
+		"bulk": { "supported": false, "maxOperations": 0, "maxPayloadSize": 0 },
+		"filter": { "supported": true, "maxResults": DEFAULT_PAGE_SIZE },
+		"changePassword": { "supported": false },
+		"sort": { "supported": false },
+		"etag": { "supported": false },
+		"authenticationSchemes": [{
+			"type": "oauthbearertoken",
+			"name": "OAuth Bearer Token",
+			"description": "Authentication with the configured bearer token",
+		}],
+	})
+}
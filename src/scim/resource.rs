@@ -0,0 +1,462 @@
+//! SCIM 2.0 resources and their mapping to users
+//!
+//! Only the attributes of the core user schema which correspond to
+//! user fields are supported. `userName` is the email address of the
+//! user, and `externalId` its external ID, defaulting to the
+//! `userName`.
+use std::collections::BTreeSet;
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::user::{non_empty, User};
+
+/// The schema of SCIM users
+pub const USER_SCHEMA: &str = "urn:ietf:params:scim:schemas:core:2.0:User";
+/// The schema of SCIM list responses
+pub const LIST_RESPONSE_SCHEMA: &str = "urn:ietf:params:scim:api:messages:2.0:ListResponse";
+/// The schema of SCIM errors
+pub const ERROR_SCHEMA: &str = "urn:ietf:params:scim:api:messages:2.0:Error";
+
+/// A SCIM user resource
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ScimUser {
+	/// The schemas of the resource
+	#[serde(default)]
+	pub schemas: Vec<String>,
+	/// The Zitadel ID of the user
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub id: Option<String>,
+	/// The external ID of the user
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub external_id: Option<String>,
+	/// The email address of the user
+	pub user_name: String,
+	/// The name of the user
+	#[serde(default)]
+	pub name: ScimName,
+	/// The preferred username of the user
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub display_name: Option<String>,
+	/// The email addresses of the user
+	#[serde(default)]
+	pub emails: Vec<ScimMultiValue>,
+	/// The phone numbers of the user
+	#[serde(default)]
+	pub phone_numbers: Vec<ScimMultiValue>,
+	/// Whether the user is enabled
+	#[serde(default = "default_active", deserialize_with = "deserialize_bool")]
+	pub active: bool,
+	/// Resource metadata
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub meta: Option<ScimMeta>,
+}
+
+/// Default for [`ScimUser::active`]
+fn default_active() -> bool {
+	true
+}
+
+/// The name of a SCIM user
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ScimName {
+	/// The first name
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub given_name: Option<String>,
+	/// The last name
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub family_name: Option<String>,
+}
+
+/// A value of a multi-valued SCIM attribute, e.g. an email address
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+pub struct ScimMultiValue {
+	/// The value
+	pub value: String,
+	/// The type of the value, e.g. `work`
+	#[serde(default, rename = "type", skip_serializing_if = "Option::is_none")]
+	pub kind: Option<String>,
+	/// Whether this is the primary value
+	#[serde(default, deserialize_with = "deserialize_bool")]
+	pub primary: bool,
+}
+
+/// SCIM resource metadata
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ScimMeta {
+	/// The type of the resource
+	pub resource_type: String,
+	/// The URL of the resource
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub location: Option<String>,
+}
+
+/// A SCIM list response
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ListResponse {
+	/// The schemas of the response
+	pub schemas: Vec<String>,
+	/// The total number of results
+	pub total_results: usize,
+	/// The 1-based index of the first returned result
+	pub start_index: usize,
+	/// The number of returned results
+	pub items_per_page: usize,
+	/// The returned results
+	#[serde(rename = "Resources")]
+	pub resources: Vec<ScimUser>,
+}
+
+/// A SCIM PATCH request
+#[derive(Debug, Clone, Deserialize)]
+pub struct PatchRequest {
+	/// The operations to apply in order
+	#[serde(rename = "Operations")]
+	pub operations: Vec<PatchOperation>,
+}
+
+/// A single SCIM PATCH operation
+#[derive(Debug, Clone, Deserialize)]
+pub struct PatchOperation {
+	/// The operation, `add`, `replace` or `remove`
+	pub op: String,
+	/// The attribute to modify; the value holds the attributes to
+	/// modify if unset
+	pub path: Option<String>,
+	/// The new value
+	pub value: Option<Value>,
+}
+
+/// Deserialize a boolean which some clients send as a string, e.g.
+/// `"False"`
+fn deserialize_bool<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+	match Value::deserialize(deserializer)? {
+		Value::Bool(value) => Ok(value),
+		Value::String(value) if value.eq_ignore_ascii_case("true") => Ok(true),
+		Value::String(value) if value.eq_ignore_ascii_case("false") => Ok(false),
+		value => Err(serde::de::Error::custom(format!("invalid boolean `{value}`"))),
+	}
+}
+
+impl ScimUser {
+	/// Represent a Zitadel user as a SCIM user
+	#[must_use]
+	pub fn from_user(user: &User, zitadel_id: &str) -> Self {
+		let external_id = user
+			.get_external_id_bytes()
+			.ok()
+			.and_then(|bytes| String::from_utf8(bytes).ok())
+			.unwrap_or_else(|| user.external_user_id.clone());
+
+		Self {
+			schemas: vec![USER_SCHEMA.to_owned()],
+			id: Some(zitadel_id.to_owned()),
+			external_id: Some(external_id),
+			user_name: user.email.clone(),
+			name: ScimName {
+				given_name: Some(user.first_name.clone()),
+				family_name: Some(user.last_name.clone()),
+			},
+			display_name: user.preferred_username.clone(),
+			emails: vec![ScimMultiValue {
+				value: user.email.clone(),
+				kind: Some("work".to_owned()),
+				primary: true,
+			}],
+			phone_numbers: user
+				.phone
+				.iter()
+				.map(|phone| ScimMultiValue {
+					value: phone.clone(),
+					kind: Some("work".to_owned()),
+					primary: true,
+				})
+				.collect(),
+			active: user.enabled,
+			meta: Some(ScimMeta {
+				resource_type: "User".to_owned(),
+				location: Some(format!("/scim/v2/Users/{zitadel_id}")),
+			}),
+		}
+	}
+
+	/// Convert the SCIM user to a user to sync. The identity, localpart
+	/// and metadata of an existing user are kept.
+	pub fn to_user(&self, existing: Option<&User>) -> Result<User> {
+		let email = primary_value(&self.emails).unwrap_or(&self.user_name).trim().to_owned();
+		if email.is_empty() {
+			bail!("A `userName` or email address is required");
+		}
+
+		let external_user_id = match existing {
+			Some(existing) => existing.external_user_id.clone(),
+			None => hex::encode(self.external_id.as_deref().unwrap_or(&self.user_name)),
+		};
+
+		Ok(User {
+			first_name: self.name.given_name.clone().unwrap_or_default(),
+			last_name: self.name.family_name.clone().unwrap_or_default(),
+			email,
+			phone: non_empty(primary_value(&self.phone_numbers).map(ToOwned::to_owned)),
+			enabled: self.active,
+			preferred_username: non_empty(self.display_name.clone()),
+			external_user_id,
+			localpart: existing.and_then(|existing| existing.localpart.clone()),
+			metadata: existing.map(|existing| existing.metadata.clone()).unwrap_or_default(),
+			roles: BTreeSet::new(),
+			groups: BTreeSet::new(),
+			common_name: None,
+			source_version: None,
+			unreadable: None,
+			preferred_username_unknown: false,
+		})
+	}
+
+	/// Apply the operations of a PATCH request
+	pub fn patch(&self, request: &PatchRequest) -> Result<Self> {
+		let mut resource = serde_json::to_value(self)?;
+		let Value::Object(attributes) = &mut resource else {
+			bail!("Invalid user resource");
+		};
+
+		for operation in &request.operations {
+			let op = operation.op.to_ascii_lowercase();
+			let value = operation.value.clone().unwrap_or(Value::Null);
+
+			match (op.as_str(), operation.path.as_deref()) {
+				("add" | "replace", None) => {
+					let Value::Object(values) = value else {
+						bail!("A PATCH operation without path requires an object value");
+					};
+					for (path, value) in values {
+						apply_operation(attributes, &op, &path, value)?;
+					}
+				}
+				("add" | "replace" | "remove", Some(path)) => {
+					apply_operation(attributes, &op, path, value)?;
+				}
+				("remove", None) => bail!("A remove operation requires a path"),
+				_ => bail!("Unsupported PATCH operation `{}`", operation.op),
+			}
+		}
+
+		serde_json::from_value(resource).context("Invalid user after PATCH")
+	}
+}
+
+/// Get the primary or first value of a multi-valued attribute
+fn primary_value(values: &[ScimMultiValue]) -> Option<&str> {
+	values
+		.iter()
+		.find(|value| value.primary)
+		.or_else(|| values.first())
+		.map(|value| value.value.as_str())
+}
+
+/// Apply a single PATCH operation to the attributes of a resource
+fn apply_operation(
+	attributes: &mut Map<String, Value>,
+	op: &str,
+	path: &str,
+	value: Value,
+) -> Result<()> {
+	let path = path.strip_prefix(USER_SCHEMA).map_or(path, |path| path.trim_start_matches(':'));
+	if path.starts_with("urn:") {
+		tracing::warn!("Ignoring PATCH of unsupported attribute `{}`", path);
+		return Ok(());
+	}
+
+	// Paths into multi-valued attributes, e.g. `emails[type eq
+	// "work"].value`
+	if let Some((attribute, rest)) = path.split_once('[') {
+		let (filter, sub_attribute) =
+			rest.split_once(']').context(format!("Invalid PATCH path `{path}`"))?;
+		let sub_attribute = sub_attribute.trim_start_matches('.');
+		let (filter_attribute, filter_value) = parse_eq_filter(filter)
+			.context(format!("Unsupported filter in PATCH path `{path}`"))?;
+
+		let key = find_key(attributes, attribute);
+		let values = attributes.entry(key).or_insert_with(|| Value::Array(Vec::new()));
+		let Value::Array(values) = values else {
+			bail!("`{attribute}` is not multi-valued");
+		};
+
+		let matches = |element: &Value| {
+			element.get(&filter_attribute).is_some_and(|actual| match actual {
+				Value::String(actual) => actual.eq_ignore_ascii_case(&filter_value),
+				actual => actual.to_string() == filter_value,
+			})
+		};
+
+		if op == "remove" {
+			values.retain(|element| !matches(element));
+			return Ok(());
+		}
+
+		if !values.iter().any(&matches) {
+			values.push(Value::Object(Map::from_iter([(
+				filter_attribute.clone(),
+				Value::String(filter_value.clone()),
+			)])));
+		}
+		for element in values.iter_mut().filter(|element| matches(&**element)) {
+			match (element, sub_attribute) {
+				(Value::Object(element), "") => {
+					if let Value::Object(value) = &value {
+						element.extend(value.clone());
+					}
+				}
+				(Value::Object(element), sub_attribute) => {
+					element.insert(sub_attribute.to_owned(), value.clone());
+				}
+				_ => bail!("Invalid value of `{attribute}`"),
+			}
+		}
+
+		return Ok(());
+	}
+
+	let (attribute, sub_attribute) = match path.split_once('.') {
+		Some((attribute, sub_attribute)) => (attribute, Some(sub_attribute)),
+		None => (path, None),
+	};
+	let key = find_key(attributes, attribute);
+
+	match (op, sub_attribute) {
+		("remove", None) => {
+			attributes.remove(&key);
+		}
+		("remove", Some(sub_attribute)) => {
+			if let Some(Value::Object(object)) = attributes.get_mut(&key) {
+				let sub_key = find_key(object, sub_attribute);
+				object.remove(&sub_key);
+			}
+		}
+		// Adding to multi-valued attributes appends the values
+		("add", None) if value.is_array() && attributes.get(&key).is_some_and(Value::is_array) => {
+			if let (Some(Value::Array(values)), Value::Array(added)) =
+				(attributes.get_mut(&key), value)
+			{
+				values.extend(added);
+			}
+		}
+		(_, None) => {
+			attributes.insert(key, value);
+		}
+		(_, Some(sub_attribute)) => {
+			let object = attributes.entry(key).or_insert_with(|| Value::Object(Map::new()));
+			let Value::Object(object) = object else {
+				bail!("`{attribute}` has no sub-attributes");
+			};
+			let sub_key = find_key(object, sub_attribute);
+			object.insert(sub_key, value);
+		}
+	}
+
+	Ok(())
+}
+
+/// Find the key of an attribute, whose names are case-insensitive in
+/// SCIM
+fn find_key(attributes: &Map<String, Value>, name: &str) -> String {
+	attributes
+		.keys()
+		.find(|key| key.eq_ignore_ascii_case(name))
+		.cloned()
+		.unwrap_or_else(|| name.to_owned())
+}
+
+/// Parse a filter of the form `attribute eq "value"` into the
+/// attribute and the value
+#[must_use]
+pub fn parse_eq_filter(filter: &str) -> Option<(String, String)> {
+	let mut parts = filter.trim().splitn(3, char::is_whitespace);
+	let attribute = parts.next()?;
+	if !parts.next()?.eq_ignore_ascii_case("eq") {
+		return None;
+	}
+	let value = parts.next()?.trim();
+	let value = value.strip_prefix('"').and_then(|value| value.strip_suffix('"')).unwrap_or(value);
+
+	Some((attribute.to_owned(), value.to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+	use serde_json::json;
+
+	use super::*;
+
+	fn scim_user() -> ScimUser {
+		serde_json::from_value(json!({
+			"schemas": [USER_SCHEMA],
+			"externalId": "alice",
+			"userName": "alice@example.com",
+			"name": { "givenName": "Alice", "familyName": "Smith" },
+			"emails": [{ "value": "alice@example.com", "type": "work", "primary": true }],
+			"active": "True"
+		}))
+		.expect("invalid SCIM user")
+	}
+
+	#[test]
+	fn test_to_user() {
+		let user = scim_user().to_user(None).expect("invalid user");
+
+		assert_eq!(user.email, "alice@example.com");
+		assert_eq!(user.first_name, "Alice");
+		assert_eq!(user.last_name, "Smith");
+		assert_eq!(user.external_user_id, hex::encode("alice"));
+		assert!(user.enabled);
+		assert_eq!(user.phone, None);
+
+		let scim_user = ScimUser::from_user(&user, "123");
+		assert_eq!(scim_user.id.as_deref(), Some("123"));
+		assert_eq!(scim_user.external_id.as_deref(), Some("alice"));
+		assert_eq!(scim_user.to_user(Some(&user)).expect("invalid user"), user);
+	}
+
+	#[test]
+	fn test_patch() {
+		let request: PatchRequest = serde_json::from_value(json!({
+			"schemas": ["urn:ietf:params:scim:api:messages:2.0:PatchOp"],
+			"Operations": [
+				{ "op": "Replace", "path": "active", "value": "False" },
+				{ "op": "replace", "path": "name.givenName", "value": "Alicia" },
+				{ "op": "add", "path": "phoneNumbers[type eq \"work\"].value", "value": "+49123" },
+				{ "op": "replace", "value": { "displayName": "alicia", "emails[type eq \"work\"].value": "alicia@example.com" } },
+				{ "op": "add", "path": "urn:ietf:params:scim:schemas:extension:enterprise:2.0:User:department", "value": "IT" }
+			]
+		}))
+		.expect("invalid PATCH request");
+
+		let patched = scim_user().patch(&request).expect("failed to apply PATCH");
+
+		assert!(!patched.active);
+		assert_eq!(patched.name.given_name.as_deref(), Some("Alicia"));
+		assert_eq!(patched.display_name.as_deref(), Some("alicia"));
+		assert_eq!(patched.emails.len(), 1);
+		assert_eq!(patched.emails[0].value, "alicia@example.com");
+		assert_eq!(patched.phone_numbers[0].value, "+49123");
+
+		let remove: PatchRequest = serde_json::from_value(json!({
+			"Operations": [{ "op": "remove", "path": "phoneNumbers[type eq \"work\"]" }]
+		}))
+		.expect("invalid PATCH request");
+		assert!(patched.patch(&remove).expect("failed to apply PATCH").phone_numbers.is_empty());
+	}
+
+	#[test]
+	fn test_parse_eq_filter() {
+		assert_eq!(
+			parse_eq_filter("userName eq \"alice@example.com\""),
+			Some(("userName".to_owned(), "alice@example.com".to_owned()))
+		);
+		assert_eq!(parse_eq_filter("userName sw \"alice\""), None);
+	}
+}
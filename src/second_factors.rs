@@ -0,0 +1,164 @@
+//! Protection of users with second factors against deletion
+//!
+//! Deleting a user destroys its registered second factors, such as
+//! TOTP apps and passkeys, which the user can't simply restore if the
+//! account is re-created, e.g. after a mishandled rename or a broken
+//! migration of the external IDs. Users slated for deletion are
+//! therefore checked for second factors first. Every such user is
+//! listed in the sync report, and if a sync would delete more of them
+//! than configured, their deletion is held back until it is confirmed
+//! with `--allow-second-factor-deletions`.
+use serde::Deserialize;
+
+use crate::{
+	report::{Operation, Reporter},
+	spans,
+	user::User,
+	zitadel::Zitadel,
+};
+
+/// The default number of users with second factors a sync may delete
+const DEFAULT_MAX_DELETIONS: usize = 5;
+
+/// The second factors assumed for users which couldn't be checked
+const UNKNOWN_FACTORS: &str = "unknown";
+
+/// The prefix of Zitadel's names of authentication methods
+const AUTHENTICATION_METHOD_PREFIX: &str = "AUTHENTICATION_METHOD_TYPE_";
+
+/// Authentication methods which aren't second factors
+const FIRST_FACTORS: &[&str] = &["unspecified", "password", "idp"];
+
+/// Configuration of the protection of users with second factors
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct SecondFactorProtectionConfig {
+	/// Whether to check users slated for deletion for second factors
+	#[serde(default = "default_check")]
+	pub check: bool,
+	/// The number of users with second factors a single sync may
+	/// delete. Beyond that, none of them are deleted unless confirmed.
+	#[serde(default = "default_max_deletions")]
+	pub max_deletions: usize,
+}
+
+impl Default for SecondFactorProtectionConfig {
+	fn default() -> Self {
+		Self { check: default_check(), max_deletions: DEFAULT_MAX_DELETIONS }
+	}
+}
+
+/// Default for [`SecondFactorProtectionConfig::check`]
+fn default_check() -> bool {
+	true
+}
+
+/// Default for [`SecondFactorProtectionConfig::max_deletions`]
+fn default_max_deletions() -> usize {
+	DEFAULT_MAX_DELETIONS
+}
+
+/// The name of a second factor, given Zitadel's name of an
+/// authentication method, e.g. `totp` for
+/// `AUTHENTICATION_METHOD_TYPE_TOTP`, or `None` if the method isn't a
+/// second factor
+pub(crate) fn second_factor_name(authentication_method: &str) -> Option<String> {
+	let name = authentication_method
+		.strip_prefix(AUTHENTICATION_METHOD_PREFIX)
+		.unwrap_or(authentication_method)
+		.to_lowercase();
+
+	(!FIRST_FACTORS.contains(&name.as_str())).then_some(name)
+}
+
+/// Check the users slated for deletion for second factors, up to the
+/// given number at once, and return the deletions which may proceed
+///
+/// Users which can't be checked are assumed to have second factors.
+pub(crate) async fn protect_deletions(
+	config: &SecondFactorProtectionConfig,
+	zitadel: &Zitadel,
+	reporter: &mut Reporter,
+	concurrency: usize,
+	deletions: Vec<(User, String)>,
+) -> Vec<(User, String)> {
+	if !config.check {
+		return deletions;
+	}
+
+	let mut factors = Vec::with_capacity(deletions.len());
+	for chunk in deletions.chunks(concurrency.max(1)) {
+		factors.extend(
+			futures::future::join_all(chunk.iter().map(|(existing_user, zitadel_id)| {
+				let mut zitadel = zitadel.clone();
+				let span = spans::user_span(
+					Operation::Delete,
+					Some(&existing_user.external_user_id),
+					Some(zitadel_id),
+				);
+				async move {
+					zitadel.list_second_factors(zitadel_id).await.unwrap_or_else(|error| {
+						span.in_scope(|| {
+							tracing::warn!(
+								"Failed to check user `{}` for second factors, assuming it has \
+								 some: {:?}",
+								zitadel_id,
+								error
+							);
+						});
+						vec![UNKNOWN_FACTORS.to_owned()]
+					})
+				}
+			}))
+			.await,
+		);
+	}
+
+	let protected_count = factors.iter().filter(|factors| !factors.is_empty()).count();
+	let held_back = protected_count > config.max_deletions;
+	if held_back {
+		tracing::error!(
+			"Not deleting {} users with second factors, more than the {} allowed; confirm their \
+			 deletion with `--allow-second-factor-deletions`",
+			protected_count,
+			config.max_deletions
+		);
+	} else if protected_count > 0 {
+		tracing::warn!("Deleting {} users with second factors", protected_count);
+	}
+
+	deletions
+		.into_iter()
+		.zip(factors)
+		.filter_map(|((existing_user, zitadel_id), factors)| {
+			if factors.is_empty() {
+				return Some((existing_user, zitadel_id));
+			}
+
+			reporter.record_second_factor_user(
+				&existing_user.external_user_id,
+				&zitadel_id,
+				factors,
+				held_back,
+			);
+			(!held_back).then_some((existing_user, zitadel_id))
+		})
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_second_factor_name() {
+		assert_eq!(second_factor_name("AUTHENTICATION_METHOD_TYPE_TOTP"), Some("totp".to_owned()));
+		assert_eq!(
+			second_factor_name("AUTHENTICATION_METHOD_TYPE_PASSKEY"),
+			Some("passkey".to_owned())
+		);
+		assert_eq!(second_factor_name("AUTHENTICATION_METHOD_TYPE_U2F"), Some("u2f".to_owned()));
+		assert_eq!(second_factor_name("AUTHENTICATION_METHOD_TYPE_PASSWORD"), None);
+		assert_eq!(second_factor_name("AUTHENTICATION_METHOD_TYPE_IDP"), None);
+		assert_eq!(second_factor_name("AUTHENTICATION_METHOD_TYPE_UNSPECIFIED"), None);
+	}
+}
@@ -0,0 +1,455 @@
+//! Changes users make to their own Zitadel accounts
+//!
+//! Users may change their email address or phone number in Zitadel,
+//! which the next sync would blindly overwrite with the source data.
+//! Zitadel reports these changes to the event endpoint served here,
+//! through an action target subscribed to the `user.human.email.changed`,
+//! `user.human.phone.changed` and `user.human.phone.removed` events.
+//! Changes users made themselves are recorded in the state file. While
+//! a recorded change is still in place, the sync keeps it if the field
+//! is approved for self-service, and otherwise overwrites it as before,
+//! but reports it as drift.
+use std::{collections::BTreeMap, convert::Infallible, net::SocketAddr, path::PathBuf, sync::Arc};
+
+use anyhow::{bail, Context, Result};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::{
+	body::{Bytes, Incoming},
+	server::conn::http1,
+	service::service_fn,
+	Method, Request, Response, StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use serde::Deserialize;
+use sha2::Sha256;
+use tokio::{net::TcpListener, sync::Mutex};
+use tracing::Instrument;
+
+use crate::{
+	config::Config,
+	report::Reporter,
+	spans,
+	state::{SelfServiceChange, SyncState},
+	user::User,
+};
+
+/// The maximum size of request bodies, in bytes
+const MAX_BODY_SIZE: usize = 64 * 1024;
+
+/// The header carrying the signature of Zitadel's requests
+const SIGNATURE_HEADER: &str = "ZITADEL-Signature";
+
+/// How old a signed request may be, in seconds, before it is rejected
+/// as a possible replay
+const SIGNATURE_TOLERANCE_SECONDS: i64 = 300;
+
+/// Configuration of the endpoint receiving self-service changes
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct SelfServiceConfig {
+	/// The address to listen on, e.g. `0.0.0.0:8081`
+	pub listen_address: SocketAddr,
+	/// The signing key of the Zitadel action target
+	pub signing_key: String,
+	/// The fields users may change themselves. Changes to these
+	/// fields are kept by the sync, changes to other fields are
+	/// overwritten and reported as drift.
+	#[serde(default)]
+	pub approved_fields: Vec<SelfServiceField>,
+}
+
+/// A field users can change in Zitadel
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum SelfServiceField {
+	/// The email address
+	Email,
+	/// The phone number
+	Phone,
+}
+
+impl SelfServiceField {
+	/// All fields users can change
+	const ALL: [Self; 2] = [Self::Email, Self::Phone];
+
+	/// The name of the field, as used in the state file and reports
+	fn name(self) -> &'static str {
+		match self {
+			Self::Email => "email",
+			Self::Phone => "phone",
+		}
+	}
+
+	/// The value of the field of the given user
+	fn get(self, user: &User) -> Option<&str> {
+		match self {
+			Self::Email => Some(&user.email),
+			Self::Phone => user.phone.as_deref(),
+		}
+	}
+
+	/// Set the field of the given user
+	fn set(self, user: &mut User, value: Option<String>) {
+		match self {
+			Self::Email => user.email = value.unwrap_or_default(),
+			Self::Phone => user.phone = value,
+		}
+	}
+
+	/// The field changed by the given Zitadel event type, along with
+	/// the new value
+	fn from_event(event_type: &str, payload: &EventPayload) -> Option<(Self, Option<String>)> {
+		match event_type {
+			"user.human.email.changed" => Some((Self::Email, payload.email.clone())),
+			"user.human.phone.changed" => Some((Self::Phone, payload.phone.clone())),
+			"user.human.phone.removed" => Some((Self::Phone, None)),
+			_ => None,
+		}
+	}
+}
+
+/// An event sent by a Zitadel action target
+#[derive(Debug, Deserialize)]
+struct Event {
+	/// The ID of the changed user
+	#[serde(rename = "aggregateID")]
+	aggregate_id: String,
+	/// The organization of the changed user
+	#[serde(rename = "resourceOwner")]
+	resource_owner: String,
+	/// The type of the event, e.g. `user.human.email.changed`
+	event_type: String,
+	/// When the event happened
+	created_at: String,
+	/// The ID of the user making the change
+	#[serde(rename = "userID")]
+	user_id: String,
+	/// The event data
+	#[serde(default)]
+	event_payload: EventPayload,
+}
+
+/// The data of an event changing a user's contact details
+#[derive(Debug, Default, Deserialize)]
+struct EventPayload {
+	/// The new email address
+	email: Option<String>,
+	/// The new phone number
+	phone: Option<String>,
+}
+
+/// The state shared by all event requests
+struct EventServer {
+	/// The organization whose users are synced
+	organization_id: String,
+	/// The state file changes are recorded in
+	state_path: PathBuf,
+	/// The signing key of the Zitadel action target
+	signing_key: String,
+	/// Serializes writes to the state file
+	state_lock: Mutex<()>,
+}
+
+/// Receive self-service changes from Zitadel until the process is
+/// stopped
+pub async fn serve_self_service_events(config: &Config) -> Result<()> {
+	serve(config).instrument(spans::run_span("self_service")).await
+}
+
+/// Receive self-service changes, logging within the current span
+async fn serve(config: &Config) -> Result<()> {
+	let self_service =
+		config.self_service.clone().context("Self-service changes are not configured")?;
+	if self_service.signing_key.is_empty() {
+		bail!("The self-service signing key must not be empty");
+	}
+	let state_path =
+		config.state_path.clone().context("Self-service changes require `state_path` to be set")?;
+
+	let server = Arc::new(EventServer {
+		organization_id: config.zitadel.organization_id.clone(),
+		state_path,
+		signing_key: self_service.signing_key,
+		state_lock: Mutex::new(()),
+	});
+
+	let listener = TcpListener::bind(self_service.listen_address)
+		.await
+		.context(format!("Failed to listen on {}", self_service.listen_address))?;
+	tracing::info!("Receiving self-service changes on {}", self_service.listen_address);
+
+	loop {
+		let (stream, peer) = match listener.accept().await {
+			Ok(connection) => connection,
+			Err(error) => {
+				tracing::warn!("Failed to accept event connection: {}", error);
+				continue;
+			}
+		};
+
+		let server = server.clone();
+		tokio::spawn(
+			async move {
+				let service = service_fn(move |request| {
+					let server = server.clone();
+					async move { Ok::<_, Infallible>(server.handle(request).await) }
+				});
+
+				if let Err(error) =
+					http1::Builder::new().serve_connection(TokioIo::new(stream), service).await
+				{
+					tracing::debug!("Failed to serve event connection from {}: {}", peer, error);
+				}
+			}
+			.in_current_span(),
+		);
+	}
+}
+
+impl EventServer {
+	/// Handle an event request
+	async fn handle(&self, request: Request<Incoming>) -> Response<Full<Bytes>> {
+		let status = match self.receive(request).await {
+			Ok(()) => StatusCode::OK,
+			Err(status) => status,
+		};
+
+		let mut response = Response::new(Full::default());
+		*response.status_mut() = status;
+		response
+	}
+
+	/// Verify an event request and record the change it reports
+	async fn receive(&self, request: Request<Incoming>) -> Result<(), StatusCode> {
+		if request.method() != Method::POST {
+			return Err(StatusCode::METHOD_NOT_ALLOWED);
+		}
+
+		let signature = request
+			.headers()
+			.get(SIGNATURE_HEADER)
+			.and_then(|value| value.to_str().ok())
+			.map(ToOwned::to_owned);
+		let body = Limited::new(request.into_body(), MAX_BODY_SIZE)
+			.collect()
+			.await
+			.map_err(|_| StatusCode::PAYLOAD_TOO_LARGE)?
+			.to_bytes();
+
+		if let Err(error) =
+			verify_signature(&self.signing_key, signature.as_deref(), &body, Utc::now().timestamp())
+		{
+			tracing::warn!("Rejecting event: {:#}", error);
+			return Err(StatusCode::UNAUTHORIZED);
+		}
+
+		let event: Event = serde_json::from_slice(&body).map_err(|error| {
+			tracing::warn!("Rejecting invalid event: {}", error);
+			StatusCode::BAD_REQUEST
+		})?;
+
+		self.record(&event).await.map_err(|error| {
+			tracing::error!("Failed to record self-service change: {:?}", error);
+			StatusCode::INTERNAL_SERVER_ERROR
+		})
+	}
+
+	/// Record the change of an event, if the user made it themselves
+	async fn record(&self, event: &Event) -> Result<()> {
+		let Some((field, value)) =
+			SelfServiceField::from_event(&event.event_type, &event.event_payload)
+		else {
+			tracing::debug!("Ignoring event of type `{}`", event.event_type);
+			return Ok(());
+		};
+
+		if event.resource_owner != self.organization_id {
+			tracing::debug!("Ignoring event of organization `{}`", event.resource_owner);
+			return Ok(());
+		}
+
+		// Changes by administrators or the sync itself are not
+		// self-service
+		if event.user_id != event.aggregate_id {
+			tracing::debug!(
+				"Ignoring change of the {} of `{}` by `{}`",
+				field.name(),
+				event.aggregate_id,
+				event.user_id
+			);
+			return Ok(());
+		}
+
+		let _guard = self.state_lock.lock().await;
+		let recorded = SyncState::record_self_service_change(
+			&self.state_path,
+			&self.organization_id,
+			&event.aggregate_id,
+			field.name(),
+			SelfServiceChange { value, changed_at: event.created_at.clone() },
+		)?;
+
+		if recorded {
+			tracing::info!(
+				"Recorded self-service change of the {} of `{}`",
+				field.name(),
+				event.aggregate_id
+			);
+		} else {
+			tracing::info!(
+				"Ignoring self-service change of `{}` before the first sync",
+				event.aggregate_id
+			);
+		}
+
+		Ok(())
+	}
+}
+
+/// Verify the signature of an event request, which has the form
+/// `t=<timestamp>,v1=<hex-encoded HMAC-SHA256 of "<timestamp>.<body>">`
+fn verify_signature(
+	signing_key: &str,
+	signature: Option<&str>,
+	body: &[u8],
+	now: i64,
+) -> Result<()> {
+	let signature = signature.context(format!("Missing {SIGNATURE_HEADER} header"))?;
+
+	let mut timestamp = None;
+	let mut signatures = Vec::new();
+	for part in signature.split(',') {
+		match part.trim().split_once('=') {
+			Some(("t", value)) => timestamp = Some(value),
+			Some(("v1", value)) => signatures.push(value),
+			_ => {}
+		}
+	}
+
+	let timestamp = timestamp.context("Missing signature timestamp")?;
+	let signed_at: i64 = timestamp.parse().context("Invalid signature timestamp")?;
+	if (now - signed_at).abs() > SIGNATURE_TOLERANCE_SECONDS {
+		bail!("Signature timestamp {} is too far from the current time", signed_at);
+	}
+
+	for signature in signatures {
+		let Ok(signature) = hex::decode(signature) else {
+			continue;
+		};
+
+		let mut mac = Hmac::<Sha256>::new_from_slice(signing_key.as_bytes())
+			.context("Invalid signing key")?;
+		mac.update(timestamp.as_bytes());
+		mac.update(b".");
+		mac.update(body);
+		if mac.verify_slice(&signature).is_ok() {
+			return Ok(());
+		}
+	}
+
+	bail!("Invalid signature")
+}
+
+/// Apply the recorded self-service changes of a Zitadel user to the
+/// source user it is about to be updated with
+///
+/// Approved changes are kept by taking them over into the source user,
+/// other changes are reported as drift and overwritten.
+pub(crate) fn reconcile(
+	config: &Config,
+	changes: &BTreeMap<String, BTreeMap<String, SelfServiceChange>>,
+	source_user: &mut User,
+	zitadel_user: &User,
+	zitadel_id: &str,
+	reporter: &mut Reporter,
+) {
+	let Some(changes) = changes.get(zitadel_id) else {
+		return;
+	};
+	let approved_fields =
+		config.self_service.as_ref().map(|self_service| self_service.approved_fields.as_slice());
+
+	for field in SelfServiceField::ALL {
+		let Some(change) = changes.get(field.name()) else {
+			continue;
+		};
+
+		// Changes the user made since, or which the sync already
+		// overwrote, don't matter anymore
+		let current = field.get(zitadel_user);
+		if current != change.value.as_deref() || field.get(source_user) == current {
+			continue;
+		}
+
+		if approved_fields.is_some_and(|fields| fields.contains(&field)) {
+			tracing::debug!(
+				"Keeping self-service change of the {} of `{}`",
+				field.name(),
+				zitadel_id
+			);
+			field.set(source_user, change.value.clone());
+		} else {
+			reporter.record_self_service_drift(
+				&source_user.external_user_id,
+				zitadel_id,
+				field.name(),
+				&change.changed_at,
+			);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use indoc::indoc;
+
+	use super::*;
+
+	fn sign(signing_key: &str, timestamp: i64, body: &[u8]) -> String {
+		let mut mac =
+			Hmac::<Sha256>::new_from_slice(signing_key.as_bytes()).expect("invalid signing key");
+		mac.update(format!("{timestamp}.").as_bytes());
+		mac.update(body);
+		format!("t={},v1={}", timestamp, hex::encode(mac.finalize().into_bytes()))
+	}
+
+	#[test]
+	fn test_verify_signature() {
+		let body = br#"{"event_type":"user.human.email.changed"}"#;
+		let now = 1_700_000_000;
+		let signature = sign("secret", now, body);
+
+		assert!(verify_signature("secret", Some(&signature), body, now).is_ok());
+		assert!(verify_signature("secret", Some(&signature), body, now + 60).is_ok());
+		assert!(verify_signature("other", Some(&signature), body, now).is_err());
+		assert!(verify_signature("secret", Some(&signature), b"{}", now).is_err());
+		assert!(verify_signature("secret", Some(&signature), body, now + 3600).is_err());
+		assert!(verify_signature("secret", None, body, now).is_err());
+	}
+
+	#[test]
+	fn test_parse_event() {
+		let event: Event = serde_json::from_str(indoc! {r#"
+			{
+				"aggregateID": "123",
+				"aggregateType": "user",
+				"resourceOwner": "1",
+				"instanceID": "42",
+				"version": "v2",
+				"sequence": 7,
+				"event_type": "user.human.phone.changed",
+				"created_at": "2024-01-01T12:00:00Z",
+				"userID": "123",
+				"event_payload": { "phone": "+49 123 456" }
+			}
+		"#})
+		.expect("invalid event");
+
+		assert_eq!(
+			SelfServiceField::from_event(&event.event_type, &event.event_payload),
+			Some((SelfServiceField::Phone, Some("+49 123 456".to_owned())))
+		);
+		assert_eq!(SelfServiceField::from_event("user.human.added", &event.event_payload), None);
+	}
+}
@@ -0,0 +1,368 @@
+//! Timeouts and circuit breakers for reads of the sources
+//!
+//! A source which hangs or fails on every sync would otherwise block
+//! the whole scheduled slot of each sync, until the next one starts
+//! and runs into the same problem. Reads of each source are aborted
+//! after a timeout, and after the configured number of failed reads in
+//! a row, the circuit of the source opens: syncs abort right away with
+//! a distinct exit code until the cooldown is over, after which one
+//! sync tries the source again.
+use std::{collections::BTreeMap, fmt, future::Future, path::PathBuf, time::Duration};
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+
+use crate::{
+	state::{SourceFailures, SyncState},
+	Config, FeatureFlag,
+};
+
+/// The exit code used when a sync is aborted because a source is
+/// unavailable
+pub const SOURCE_UNAVAILABLE_EXIT_CODE: u8 = 4;
+
+/// The default timeout of reads of a source, in seconds
+const DEFAULT_FETCH_TIMEOUT_SECONDS: u64 = 1800;
+/// The default time the circuit of a source stays open, in minutes
+const DEFAULT_COOLDOWN_MINUTES: u64 = 60;
+/// The names of the sources timeouts can be configured for
+const SOURCE_NAMES: [&str; 6] = ["ldap", "csv", "fhir", "entra", "keycloak", "ukt"];
+
+/// Configuration of the timeouts and circuit breakers of the sources
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct SourceGuardConfig {
+	/// The number of seconds after which a read of a source is aborted
+	#[serde(default = "default_fetch_timeout_seconds")]
+	pub fetch_timeout_seconds: u64,
+	/// Timeouts overriding `fetch_timeout_seconds`, in seconds, by
+	/// source, i.e. `ldap`, `csv`, `fhir`, `entra`, `keycloak` or `ukt`
+	#[serde(default)]
+	pub timeouts: BTreeMap<String, u64>,
+	/// The number of failed reads of a source in a row after which its
+	/// circuit opens. Without it, the circuit never opens.
+	pub failure_threshold: Option<u32>,
+	/// The number of minutes the circuit of a source stays open
+	#[serde(default = "default_cooldown_minutes")]
+	pub cooldown_minutes: u64,
+}
+
+/// The default timeout of reads of a source
+fn default_fetch_timeout_seconds() -> u64 {
+	DEFAULT_FETCH_TIMEOUT_SECONDS
+}
+
+/// The default time the circuit of a source stays open
+fn default_cooldown_minutes() -> u64 {
+	DEFAULT_COOLDOWN_MINUTES
+}
+
+impl SourceGuardConfig {
+	/// Check that the timeouts name known sources, and that the
+	/// failures can be counted across syncs
+	pub(crate) fn validate(&self, has_state_path: bool) -> Result<()> {
+		if let Some(source) =
+			self.timeouts.keys().find(|source| !SOURCE_NAMES.contains(&source.as_str()))
+		{
+			bail!(
+				"`source_guard.timeouts` lists the unknown source `{}`, expected one of {:?}",
+				source,
+				SOURCE_NAMES
+			);
+		}
+		if self.failure_threshold == Some(0) {
+			bail!("`failure_threshold` must be at least 1");
+		}
+		if self.failure_threshold.is_some() && !has_state_path {
+			bail!(
+				"`failure_threshold` requires `state_path` to be set, to count the failed reads \
+				 across syncs"
+			);
+		}
+
+		Ok(())
+	}
+
+	/// The timeout of reads of the given source
+	fn timeout(&self, source: &str) -> Duration {
+		Duration::from_secs(
+			self.timeouts.get(source).copied().unwrap_or(self.fetch_timeout_seconds),
+		)
+	}
+
+	/// When the circuit of a source with the given failures closes
+	/// again, if it is open
+	fn open_until(&self, failures: &SourceFailures) -> Result<Option<DateTime<Utc>>> {
+		let Some(threshold) = self.failure_threshold else {
+			return Ok(None);
+		};
+		if failures.consecutive < threshold {
+			return Ok(None);
+		}
+
+		let last_failure_at = DateTime::parse_from_rfc3339(&failures.last_failure_at)
+			.context("Invalid source failure timestamp")?
+			.with_timezone(&Utc);
+		let minutes = i64::try_from(self.cooldown_minutes).unwrap_or(i64::MAX);
+		let closes_at = last_failure_at + chrono::Duration::minutes(minutes);
+
+		Ok((closes_at > Utc::now()).then_some(closes_at))
+	}
+}
+
+/// Why a source is unavailable
+#[derive(Debug)]
+pub enum Unavailability {
+	/// The read took longer than the timeout
+	TimedOut(Duration),
+	/// The circuit of the source is open after too many failed reads
+	CircuitOpen {
+		/// The number of failed reads in a row
+		failures: u32,
+		/// When the source is tried again
+		retry_after: DateTime<Utc>,
+	},
+}
+
+/// The error returned if a sync is aborted because a source is
+/// unavailable
+#[derive(Debug)]
+pub struct SourceUnavailable {
+	/// The name of the source
+	source: String,
+	/// Why the source is unavailable
+	reason: Unavailability,
+}
+
+impl fmt::Display for SourceUnavailable {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match &self.reason {
+			Unavailability::TimedOut(timeout) => {
+				write!(f, "Reading from {} timed out after {:?}", self.source, timeout)
+			}
+			Unavailability::CircuitOpen { failures, retry_after } => write!(
+				f,
+				"Not reading from {} after {} failed reads in a row until {}",
+				self.source,
+				failures,
+				retry_after.to_rfc3339()
+			),
+		}
+	}
+}
+
+impl std::error::Error for SourceUnavailable {}
+
+/// Guard of the reads of the sources of a sync, which doesn't guard
+/// them by default
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SourceGuard {
+	/// The configuration of the guard, if any
+	config: Option<SourceGuardConfig>,
+	/// The path of the state counting the failed reads
+	state_path: Option<PathBuf>,
+	/// The organization the failed reads are counted for
+	organization_id: String,
+	/// Whether failed reads are left uncounted, as in dry runs
+	dry_run: bool,
+}
+
+impl SourceGuard {
+	/// Create the guard of the sources of a configuration
+	pub(crate) fn new(config: &Config) -> Self {
+		Self {
+			config: config.source_guard.clone(),
+			state_path: config.state_path.clone(),
+			organization_id: config.zitadel.organization_id.clone(),
+			dry_run: config.feature_flags.is_enabled(FeatureFlag::DryRun),
+		}
+	}
+
+	/// Read from the named source, aborting the read after its timeout
+	/// and without starting it while the circuit of the source is open
+	pub(crate) async fn read<T>(
+		&self,
+		source: &str,
+		read: impl Future<Output = Result<T>>,
+	) -> Result<T> {
+		let Some(config) = &self.config else {
+			return read.await;
+		};
+		let key = source.to_lowercase();
+
+		let failures = self.load_failures(&key)?;
+		if let Some(retry_after) =
+			failures.as_ref().map(|failures| config.open_until(failures)).transpose()?.flatten()
+		{
+			return Err(SourceUnavailable {
+				source: source.to_owned(),
+				reason: Unavailability::CircuitOpen {
+					failures: failures.map_or(0, |failures| failures.consecutive),
+					retry_after,
+				},
+			}
+			.into());
+		}
+
+		let timeout = config.timeout(&key);
+		let result = match tokio::time::timeout(timeout, read).await {
+			Ok(result) => result,
+			Err(_) => Err(SourceUnavailable {
+				source: source.to_owned(),
+				reason: Unavailability::TimedOut(timeout),
+			}
+			.into()),
+		};
+
+		match &result {
+			Ok(_) if failures.is_some() => self.record_failures(&key, None)?,
+			Ok(_) => {}
+			Err(_) => {
+				let failures = SourceFailures {
+					consecutive: failures.map_or(0, |failures| failures.consecutive) + 1,
+					last_failure_at: Utc::now().to_rfc3339(),
+				};
+				if config
+					.failure_threshold
+					.is_some_and(|threshold| failures.consecutive >= threshold)
+				{
+					tracing::warn!(
+						"Reading from {} failed {} times in a row, not trying again for {} minutes",
+						source,
+						failures.consecutive,
+						config.cooldown_minutes
+					);
+				}
+				self.record_failures(&key, Some(failures))?;
+			}
+		}
+
+		result
+	}
+
+	/// Load the failed reads in a row of a source
+	fn load_failures(&self, key: &str) -> Result<Option<SourceFailures>> {
+		match &self.state_path {
+			Some(path) => SyncState::load_source_failures(path, &self.organization_id, key),
+			None => Ok(None),
+		}
+	}
+
+	/// Store the failed reads in a row of a source
+	fn record_failures(&self, key: &str, failures: Option<SourceFailures>) -> Result<()> {
+		match &self.state_path {
+			Some(_) if self.dry_run => Ok(()),
+			Some(path) => {
+				SyncState::record_source_failures(path, &self.organization_id, key, failures)
+			}
+			None => Ok(()),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use anyhow::anyhow;
+	use tempfile::TempDir;
+
+	use super::*;
+
+	/// A guard with the given circuit breaker, storing its state in the
+	/// given directory
+	fn guard(dir: &TempDir, failure_threshold: Option<u32>) -> SourceGuard {
+		SourceGuard {
+			config: Some(SourceGuardConfig {
+				fetch_timeout_seconds: 60,
+				timeouts: BTreeMap::from([("ldap".to_owned(), 0)]),
+				failure_threshold,
+				cooldown_minutes: 60,
+			}),
+			state_path: Some(dir.path().join("state.json")),
+			organization_id: "1".to_owned(),
+			dry_run: false,
+		}
+	}
+
+	#[tokio::test]
+	async fn test_source_timeout() {
+		let dir = TempDir::new().expect("failed to create tempdir");
+		let guard = guard(&dir, None);
+
+		let error = guard
+			.read("LDAP", async {
+				tokio::time::sleep(Duration::from_secs(60)).await;
+				Ok(())
+			})
+			.await
+			.expect_err("slow read was not aborted");
+		assert!(error.is::<SourceUnavailable>());
+
+		let users = guard.read("CSV", async { Ok(vec!["jdoe"]) }).await.expect("read failed");
+		assert_eq!(users, vec!["jdoe"]);
+	}
+
+	#[tokio::test]
+	async fn test_circuit_breaker() {
+		let dir = TempDir::new().expect("failed to create tempdir");
+		let guard = guard(&dir, Some(2));
+		let failing = || async { Err::<(), _>(anyhow!("Connection refused")) };
+
+		let error = guard.read("CSV", failing()).await.expect_err("failing read succeeded");
+		assert!(!error.is::<SourceUnavailable>());
+
+		// A success resets the count
+		guard.read("CSV", async { Ok(()) }).await.expect("read failed");
+		guard.read("CSV", failing()).await.expect_err("failing read succeeded");
+		guard.read("CSV", failing()).await.expect_err("failing read succeeded");
+
+		// The circuit is open, so even a read which would succeed fails
+		let error = guard
+			.read("CSV", async { Ok(()) })
+			.await
+			.expect_err("read succeeded despite the open circuit");
+		assert!(error.is::<SourceUnavailable>());
+
+		// Other sources have circuits of their own
+		guard.read("FHIR", async { Ok(()) }).await.expect("read failed");
+
+		// After the cooldown, the source is tried again
+		let failures = SourceFailures {
+			consecutive: 2,
+			last_failure_at: (Utc::now() - chrono::Duration::minutes(61)).to_rfc3339(),
+		};
+		SyncState::record_source_failures(
+			&dir.path().join("state.json"),
+			"1",
+			"csv",
+			Some(failures),
+		)
+		.expect("failed to record failures");
+		guard.read("CSV", async { Ok(()) }).await.expect("read failed after the cooldown");
+		assert!(SyncState::load_source_failures(&dir.path().join("state.json"), "1", "csv")
+			.expect("failed to load failures")
+			.is_none());
+	}
+
+	#[tokio::test]
+	async fn test_dry_run() {
+		let dir = TempDir::new().expect("failed to create tempdir");
+		let guard = SourceGuard { dry_run: true, ..guard(&dir, Some(1)) };
+
+		guard
+			.read("CSV", async { Err::<(), _>(anyhow!("Connection refused")) })
+			.await
+			.expect_err("failing read succeeded");
+		assert!(!dir.path().join("state.json").exists());
+	}
+
+	#[test]
+	fn test_validate() {
+		let dir = TempDir::new().expect("failed to create tempdir");
+		let mut config = guard(&dir, Some(3)).config.expect("guard must be configured");
+		assert!(config.validate(true).is_ok());
+		assert!(config.validate(false).is_err());
+
+		config.timeouts.insert("LDAP".to_owned(), 600);
+		assert!(config.validate(true).is_err());
+	}
+}
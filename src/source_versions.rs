@@ -0,0 +1,222 @@
+//! Skipping of users unchanged in the source since the last sync
+//!
+//! With the `last_modified` attribute of the LDAP source and a state
+//! file configured, the modification timestamps of the users are stored
+//! once a sync applied all changes. Later syncs, full syncs included,
+//! take users whose timestamp didn't advance as synced, and skip reading
+//! them from Zitadel. Changes made to such users directly in Zitadel are
+//! only corrected once they change in the source, or the options
+//! shaping the synced users change.
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+use anyhow::Result;
+use sha2::{Digest, Sha256};
+
+use crate::{
+	state::{SourceVersionMarks, SyncState},
+	user::User,
+	Config, FeatureFlag,
+};
+
+/// The source versions of the users of a sync
+pub(crate) struct SourceVersions {
+	/// The fingerprint of the configuration the versions apply to
+	config_fingerprint: String,
+	/// The versions to store once the sync applied all changes, by
+	/// external user ID
+	versions: BTreeMap<String, String>,
+	/// The external IDs of the users whose version didn't change since
+	/// the last sync
+	unchanged: BTreeSet<String>,
+}
+
+impl SourceVersions {
+	/// Compare the versions of the given source users with those of the
+	/// last sync, or return `None` if unchanged users can't be skipped
+	///
+	/// Users are only skipped when `skip_unchanged` is set, all of them
+	/// come from the LDAP source, and all changes of a sync are applied
+	/// to those compared.
+	pub(crate) fn load(
+		config: &Config,
+		users: &VecDeque<User>,
+		skip_unchanged: bool,
+	) -> Result<Option<Self>> {
+		let (Some(state_path), Some(ldap)) = (&config.state_path, &config.sources.ldap) else {
+			return Ok(None);
+		};
+		if !skip_unchanged
+			|| ldap.attributes.last_modified.is_none()
+			|| config.source_merge.is_some()
+			|| config.pilot.is_some()
+			|| config.feature_flags.is_enabled(FeatureFlag::DeactivateOnly)
+		{
+			return Ok(None);
+		}
+
+		let config_fingerprint = fingerprint(config);
+		let last_sync =
+			SyncState::load_source_versions(state_path, &config.zitadel.organization_id)?
+				.filter(|marks| marks.config_fingerprint == config_fingerprint)
+				.map(|marks| marks.versions)
+				.unwrap_or_default();
+
+		let mut versions = BTreeMap::new();
+		let mut unchanged = BTreeSet::new();
+		for user in users.iter().filter(|user| user.enabled) {
+			let last_version = last_sync.get(&user.external_user_id);
+			// Users read from Zitadel by incremental syncs lack a
+			// version, and keep that of the last sync
+			let Some(version) = user.source_version.as_ref().or(last_version) else {
+				continue;
+			};
+			if user.source_version.is_some() && last_version == Some(version) {
+				unchanged.insert(user.external_user_id.clone());
+			}
+			versions.insert(user.external_user_id.clone(), version.clone());
+		}
+
+		tracing::info!("Skipping {} users unchanged since the last sync", unchanged.len());
+		Ok(Some(Self { config_fingerprint, versions, unchanged }))
+	}
+
+	/// Whether the user with the given external ID didn't change since
+	/// the last sync
+	pub(crate) fn is_unchanged(&self, external_user_id: &str) -> bool {
+		self.unchanged.contains(external_user_id)
+	}
+
+	/// Store the versions in the state file
+	pub(crate) fn save(self, config: &Config) -> Result<()> {
+		let Some(state_path) = &config.state_path else {
+			return Ok(());
+		};
+
+		SyncState::record_source_versions(
+			state_path,
+			&config.zitadel.organization_id,
+			SourceVersionMarks {
+				config_fingerprint: self.config_fingerprint,
+				versions: self.versions,
+			},
+		)
+	}
+}
+
+/// The fingerprint of the options of a configuration which shape the
+/// users written to Zitadel
+///
+/// Options only concerning a run, e.g. the paths of its report, are
+/// left out, so that they don't make every user count as changed.
+fn fingerprint(config: &Config) -> String {
+	let options = format!(
+		"{:?}",
+		(
+			&config.zitadel,
+			&config.sources,
+			&config.feature_flags,
+			&config.rules,
+			&config.comparison,
+			&config.name_fallback,
+			&config.drift,
+		)
+	);
+	hex::encode(Sha256::digest(options))
+}
+
+#[cfg(test)]
+mod tests {
+	use indoc::indoc;
+	use tempfile::TempDir;
+
+	use super::*;
+
+	const EXAMPLE_CONFIG: &str = indoc! {r#"
+        zitadel:
+          url: http://localhost:8080
+          key_file: tests/environment/zitadel/service-user.json
+          organization_id: 1
+          project_id: 1
+          idp_id: 1
+
+        sources:
+          ldap:
+            url: ldap://localhost:1389
+            base_dn: ou=testorg,dc=example,dc=org
+            bind_dn: cn=admin,dc=example,dc=org
+            bind_password: adminpassword
+            user_filter: "(objectClass=shadowAccount)"
+            timeout: 5
+            check_for_deleted_entries: true
+            use_attribute_filter: true
+            attributes:
+              first_name: "cn"
+              last_name: "sn"
+              preferred_username: "displayName"
+              email: "mail"
+              user_id: "uid"
+              status: "shadowFlag"
+              disable_bitmasks: [0x2, 0x10]
+              phone: "telephoneNumber"
+              last_modified: "modifyTimestamp"
+    "#};
+
+	fn user(external_user_id: &str, source_version: Option<&str>) -> User {
+		let mut user = User::new(
+			"John".to_owned(),
+			"Doe".to_owned(),
+			format!("{external_user_id}@example.com"),
+			None,
+			true,
+			None,
+			external_user_id.to_owned(),
+			None,
+		);
+		user.source_version = source_version.map(ToOwned::to_owned);
+		user
+	}
+
+	#[test]
+	fn test_unchanged_users() {
+		let dir = TempDir::new().expect("failed to create tempdir");
+		let mut config: Config = serde_yaml::from_str(EXAMPLE_CONFIG).expect("invalid config");
+		config.state_path = Some(dir.path().join("state.json"));
+
+		let users = VecDeque::from([user("aaron", Some("1")), user("jdoe", Some("1"))]);
+		let versions = SourceVersions::load(&config, &users, true)
+			.expect("failed to load versions")
+			.expect("versions are not enabled");
+		assert!(!versions.is_unchanged("aaron"));
+		versions.save(&config).expect("failed to save versions");
+
+		let users = VecDeque::from([
+			user("aaron", Some("1")),
+			user("jdoe", Some("2")),
+			user("mmuster", None),
+		]);
+		let versions = SourceVersions::load(&config, &users, true)
+			.expect("failed to load versions")
+			.expect("versions are not enabled");
+		assert!(versions.is_unchanged("aaron"));
+		assert!(!versions.is_unchanged("jdoe"));
+		assert!(!versions.is_unchanged("mmuster"));
+
+		// Versions stored with another configuration don't apply
+		config.sources.ldap.as_mut().expect("no LDAP source configured").user_filter =
+			"(objectClass=person)".to_owned();
+		let versions = SourceVersions::load(&config, &users, true)
+			.expect("failed to load versions")
+			.expect("versions are not enabled");
+		assert!(!versions.is_unchanged("aaron"));
+
+		// Unchanged users aren't skipped when all users are compared
+		assert!(SourceVersions::load(&config, &users, false)
+			.expect("failed to load versions")
+			.is_none());
+
+		config.state_path = None;
+		assert!(SourceVersions::load(&config, &users, true)
+			.expect("failed to load versions")
+			.is_none());
+	}
+}
@@ -1,11 +1,19 @@
 //! Sources of data we want to sync from.
 
+use std::collections::BTreeMap;
+
 use anyhow::Result;
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 
 pub mod csv;
+pub mod entra;
+pub mod fhir;
+pub mod keycloak;
 pub mod ldap;
+pub mod merged;
 pub mod ukt;
+pub mod verification;
 
 use crate::user::User;
 
@@ -28,4 +36,18 @@ pub trait Source {
 	// gains this feature, we should probably switch to a stream here,
 	// though (and update existing sources to return sorted streams).
 	async fn get_sorted_users(&self) -> Result<Vec<User>>;
+
+	/// Get the attributes of the user with the given external user ID
+	/// as they are stored in the source, for debugging
+	async fn get_raw_attributes(
+		&self,
+		external_user_id: &str,
+	) -> Result<Option<BTreeMap<String, Vec<String>>>>;
+
+	/// When the data of the source was last changed, if the source
+	/// knows it, e.g. by the modification time of a file. Otherwise,
+	/// the time of the query is taken as the time of the snapshot.
+	async fn snapshot_time(&self) -> Result<Option<DateTime<Utc>>> {
+		Ok(None)
+	}
 }
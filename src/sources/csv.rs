@@ -1,15 +1,25 @@
 //! CSV source for syncing with Famedly's Zitadel.
 
-use std::{fs, path::PathBuf};
+use std::{
+	collections::{BTreeMap, BTreeSet},
+	fs,
+	path::PathBuf,
+};
 
 use anyhow::{Context, Result};
 use async_trait::async_trait;
-use csv::Reader;
+use chrono::{DateTime, Utc};
+use csv::{Reader, ReaderBuilder};
 use serde::Deserialize;
 
-use super::Source;
+use super::{verification::PayloadVerification, Source};
 use crate::user::User;
 
+mod download;
+
+pub use download::CsvDownloadConfig;
+use download::CsvLocation;
+
 /// CSV Source
 pub struct CsvSource {
 	/// CSV Source configuration
@@ -23,10 +33,55 @@ impl Source for CsvSource {
 	}
 
 	async fn get_sorted_users(&self) -> Result<Vec<User>> {
-		let mut new_users = self.read_csv()?;
+		let mut new_users = self.read_csv().await?;
 		new_users.sort_by(|a, b| a.external_user_id.cmp(&b.external_user_id));
 		return Ok(new_users);
 	}
+
+	async fn get_raw_attributes(
+		&self,
+		external_user_id: &str,
+	) -> Result<Option<BTreeMap<String, Vec<String>>>> {
+		let payload = self.read_payload().await?;
+		let mut reader = Reader::from_reader(payload.as_slice());
+		let headers = reader.headers()?.clone();
+
+		for record in reader.records() {
+			let record = record?;
+			let attributes: BTreeMap<_, _> = headers
+				.iter()
+				.zip(record.iter())
+				.map(|(header, value)| (header.to_owned(), vec![value.to_owned()]))
+				.collect();
+
+			let email = attributes.get("email").and_then(|values| values.first());
+			if email.is_some_and(|email| hex::encode(email) == external_user_id) {
+				return Ok(Some(attributes));
+			}
+		}
+
+		Ok(None)
+	}
+
+	async fn snapshot_time(&self) -> Result<Option<DateTime<Utc>>> {
+		let download = self.csv_config.download.as_ref();
+		match self.csv_config.location()? {
+			CsvLocation::File(file_path) => {
+				let modified = fs::metadata(&file_path)
+					.and_then(|metadata| metadata.modified())
+					.context(format!(
+						"Failed to read the modification time of {}",
+						file_path.to_string_lossy()
+					))?;
+				Ok(Some(modified.into()))
+			}
+			CsvLocation::Https(url) => download::https_modified(&url, download).await,
+			CsvLocation::Sftp(url) => {
+				let download = download.context("`download` is required for SFTP")?;
+				download::sftp_modified(&url, download).await
+			}
+		}
+	}
 }
 
 impl CsvSource {
@@ -35,26 +90,132 @@ impl CsvSource {
 		Self { csv_config }
 	}
 
-	/// Get list of users from CSV file
-	fn read_csv(&self) -> Result<Vec<User>> {
+	/// Read the CSV file, downloading it if it is given as a URL, and
+	/// verify it if configured
+	async fn read_payload(&self) -> Result<Vec<u8>> {
 		let file_path = &self.csv_config.file_path;
-		let file = fs::File::open(&self.csv_config.file_path)
-			.context(format!("Failed to open CSV file {}", file_path.to_string_lossy()))?;
-		let mut reader = Reader::from_reader(file);
-		Ok(reader
-			.deserialize()
-			.map(|r| r.inspect_err(|x| tracing::error!("Failed to deserialize: {x}")))
-			.filter_map(Result::ok)
-			.map(CsvData::to_user)
-			.collect())
+		let download = self.csv_config.download.as_ref();
+		let verification = self.csv_config.verification.as_ref();
+
+		let payload = match self.csv_config.location()? {
+			CsvLocation::File(_) => fs::read(file_path)
+				.context(format!("Failed to open CSV file {}", file_path.to_string_lossy()))?,
+			CsvLocation::Https(url) => {
+				let (payload, headers) = download::download_https(&url, download).await?;
+				// Signatures sent along with the file are checked here,
+				// detached ones below
+				if let Some(verification @ PayloadVerification::HmacHeader { .. }) = verification {
+					verification.verify_response(&headers, &payload)?;
+					return Ok(payload);
+				}
+				payload
+			}
+			CsvLocation::Sftp(url) => {
+				let download = download.context("`download` is required for SFTP")?;
+				download::download_sftp(&url, download).await?
+			}
+		};
+
+		if let Some(verification) = verification {
+			verification.verify_file(file_path, &payload)?;
+		}
+
+		Ok(payload)
+	}
+
+	/// Get list of users from CSV file
+	///
+	/// Rows which fail to parse, but whose email address is known, are
+	/// returned as unparsable users, so that their Zitadel counterparts
+	/// are left untouched. Any other row which fails to parse aborts,
+	/// since the user it belongs to can't be told.
+	async fn read_csv(&self) -> Result<Vec<User>> {
+		let payload = self.read_payload().await?;
+		let mut reader = ReaderBuilder::new().flexible(true).from_reader(payload.as_slice());
+		let headers = reader.headers()?.clone();
+		let email_column = headers
+			.iter()
+			.position(|header| header == "email")
+			.context("The CSV file has no `email` column")?;
+
+		reader
+			.records()
+			.enumerate()
+			.map(|(index, record)| {
+				let record =
+					record.context(format!("Failed to read row {} of the CSV file", index + 1))?;
+				match record.deserialize::<CsvData>(Some(&headers)) {
+					Ok(csv_data) => {
+						Ok(CsvData::to_user(csv_data, self.csv_config.preferred_username))
+					}
+					Err(error) => {
+						let email = record
+							.get(email_column)
+							.filter(|email| !email.is_empty())
+							.context(format!(
+								"Failed to parse row {} of the CSV file, which has no email \
+								 address: {}",
+								index + 1,
+								error
+							))?;
+						tracing::error!("Failed to parse the CSV row of `{}`: {}", email, error);
+						Ok(User::unparsable(hex::encode(email), error.to_string()))
+					}
+				}
+			})
+			.collect()
 	}
 }
 
 /// Configuration to get a list of users from a CSV file
 #[derive(Debug, Clone, Deserialize, PartialEq)]
 pub struct CsvSourceConfig {
-	/// The path to the CSV file
+	/// The path to the CSV file, or an `https://` or `sftp://` URL to
+	/// download it from
 	pub file_path: PathBuf,
+	/// The column to sync as the user's preferred username
+	#[serde(default)]
+	pub preferred_username: CsvPreferredUsername,
+	/// How to verify the CSV file before reading users from it
+	pub verification: Option<PayloadVerification>,
+	/// How to download the CSV file, if `file_path` is a URL
+	pub download: Option<CsvDownloadConfig>,
+}
+
+impl CsvSourceConfig {
+	/// Check that the CSV file can be read and verified as configured
+	pub(crate) fn validate(&self) -> Result<()> {
+		let location = self.location()?;
+		CsvDownloadConfig::validate(self.download.as_ref(), &location)?;
+
+		match (&self.verification, location) {
+			(
+				Some(verification @ PayloadVerification::HmacHeader { .. }),
+				CsvLocation::Https(_),
+			) => verification.validate_for_http(),
+			(Some(verification), _) => verification.validate_for_files(),
+			(None, _) => Ok(()),
+		}
+	}
+
+	/// Where to read the CSV file from
+	fn location(&self) -> Result<CsvLocation> {
+		CsvLocation::parse(&self.file_path)
+	}
+}
+
+/// The column of the CSV file to sync as the preferred username
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CsvPreferredUsername {
+	/// The email address
+	#[default]
+	Email,
+	/// The localpart; users without one get no preferred username
+	Localpart,
+	/// Don't sync the preferred username, leaving existing values in
+	/// Zitadel as they are
+	Disabled,
 }
 
 /// CSV data structure
@@ -75,16 +236,30 @@ struct CsvData {
 
 impl CsvData {
 	/// Convert CsvData to User data
-	fn to_user(csv_data: CsvData) -> User {
+	fn to_user(csv_data: CsvData, preferred_username: CsvPreferredUsername) -> User {
+		let localpart = (!csv_data.localpart.is_empty()).then_some(csv_data.localpart);
+		let preferred_username = match preferred_username {
+			CsvPreferredUsername::Email => Some(csv_data.email.clone()),
+			CsvPreferredUsername::Localpart => localpart.clone(),
+			CsvPreferredUsername::Disabled => None,
+		};
+
 		User {
 			email: csv_data.email.clone(),
 			first_name: csv_data.first_name,
 			last_name: csv_data.last_name,
 			phone: if csv_data.phone.is_empty() { None } else { Some(csv_data.phone) },
-			preferred_username: Some(csv_data.email.clone()),
+			preferred_username,
 			external_user_id: hex::encode(csv_data.email),
 			enabled: true,
-			localpart: (!csv_data.localpart.is_empty()).then_some(csv_data.localpart),
+			localpart,
+			metadata: BTreeMap::new(),
+			roles: BTreeSet::new(),
+			groups: BTreeSet::new(),
+			common_name: None,
+			source_version: None,
+			unreadable: None,
+			preferred_username_unknown: false,
 		}
 	}
 }
@@ -139,8 +314,8 @@ mod tests {
 		serde_yaml::from_str(EXAMPLE_CONFIG).expect("invalid config")
 	}
 
-	#[test]
-	fn test_get_users() {
+	#[tokio::test]
+	async fn test_get_users() {
 		let mut config = load_config();
 		let csv_content = indoc! {r#"
           email,first_name,last_name,phone,localpart
@@ -154,7 +329,7 @@ mod tests {
 		let csv_config = config.sources.csv.expect("CsvSource configuration is missing");
 		let csv = CsvSource::new(csv_config);
 
-		let result = csv.read_csv();
+		let result = csv.read_csv().await;
 		assert!(result.is_ok(), "Failed to get users: {:?}", result);
 
 		let users = result.expect("Failed to get users");
@@ -208,8 +383,50 @@ mod tests {
 		assert_eq!(users[3].phone, Some("+4444444444".to_owned()), "Unexpected phone at index 3");
 	}
 
-	#[test]
-	fn test_get_users_empty_file() {
+	#[tokio::test]
+	async fn test_snapshot_time() {
+		let mut config = load_config();
+		let _file = test_helpers::temp_csv_file(&mut config, "email,first_name,last_name\n");
+
+		let csv_config = config.sources.csv.expect("CsvSource configuration is missing");
+		let csv = CsvSource::new(csv_config);
+
+		let snapshot_at = csv
+			.snapshot_time()
+			.await
+			.expect("failed to read snapshot time")
+			.expect("missing snapshot time");
+		assert!(Utc::now() - snapshot_at < chrono::Duration::minutes(1));
+	}
+
+	#[tokio::test]
+	async fn test_get_users_preferred_username() {
+		let mut config = load_config();
+		let csv_content = indoc! {r#"
+          email,first_name,last_name,phone,localpart
+          john.doe@example.com,John,Doe,+1111111111,john.doe
+          jane.smith@example.com,Jane,Smith,+2222222222,
+        "#};
+		let _file = test_helpers::temp_csv_file(&mut config, csv_content);
+		let mut csv_config = config.sources.csv.expect("CsvSource configuration is missing");
+
+		let users =
+			CsvSource::new(csv_config.clone()).read_csv().await.expect("Failed to get users");
+		assert_eq!(users[0].preferred_username, Some("john.doe@example.com".to_owned()));
+
+		csv_config.preferred_username = CsvPreferredUsername::Localpart;
+		let users =
+			CsvSource::new(csv_config.clone()).read_csv().await.expect("Failed to get users");
+		assert_eq!(users[0].preferred_username, Some("john.doe".to_owned()));
+		assert_eq!(users[1].preferred_username, None);
+
+		csv_config.preferred_username = CsvPreferredUsername::Disabled;
+		let users = CsvSource::new(csv_config).read_csv().await.expect("Failed to get users");
+		assert!(users.iter().all(|user| user.preferred_username.is_none()));
+	}
+
+	#[tokio::test]
+	async fn test_get_users_empty_file() {
 		let mut config = load_config();
 		let csv_content = indoc! {r#"
           email,first_name,last_name,phone,localpart
@@ -219,15 +436,15 @@ mod tests {
 		let csv_config = config.sources.csv.expect("CsvSource configuration is missing");
 		let csv = CsvSource::new(csv_config);
 
-		let result = csv.read_csv();
+		let result = csv.read_csv().await;
 		assert!(result.is_ok(), "Failed to get users: {:?}", result);
 
 		let users = result.expect("Failed to get users");
 		assert_eq!(users.len(), 0, "Expected empty user list");
 	}
 
-	#[test]
-	fn test_get_users_invalid_file() {
+	#[tokio::test]
+	async fn test_get_users_invalid_file() {
 		let mut config = load_config();
 		if let Some(csv) = config.sources.csv.as_mut() {
 			csv.file_path = PathBuf::from("invalid_path.csv");
@@ -236,7 +453,7 @@ mod tests {
 		let csv_config = config.sources.csv.expect("CsvSource configuration is missing");
 		let csv = CsvSource::new(csv_config);
 
-		let result = csv.read_csv();
+		let result = csv.read_csv().await;
 		let error = result.expect_err("Expected error for invalid CSV data");
 		assert!(
 			error.to_string().contains("Failed to open CSV file"),
@@ -245,8 +462,8 @@ mod tests {
 		);
 	}
 
-	#[test]
-	fn test_get_users_invalid_headers() {
+	#[tokio::test]
+	async fn test_get_users_invalid_headers() {
 		let mut config = load_config();
 		let csv_content = indoc! {r#"
           first_name
@@ -257,13 +474,18 @@ mod tests {
 		let csv_config = config.sources.csv.expect("CsvSource configuration is missing");
 		let csv = CsvSource::new(csv_config);
 
-		let result = csv.read_csv();
-		let users = result.expect("Failed to get users");
-		assert_eq!(users.len(), 0, "Unexpected number of users");
+		// Without an email column, no user can be identified, and
+		// returning none would delete all of them
+		let error = csv.read_csv().await.expect_err("Expected error for missing email column");
+		assert!(
+			error.to_string().contains("no `email` column"),
+			"Unexpected error message: {:?}",
+			error
+		);
 	}
 
-	#[test]
-	fn test_get_users_invalid_content() {
+	#[tokio::test]
+	async fn test_get_users_invalid_content() {
 		let mut config = load_config();
 		let csv_content = indoc! {r#"
           email,first_name,last_name,phone,localpart
@@ -275,27 +497,52 @@ mod tests {
 		let csv_config = config.sources.csv.expect("CsvSource configuration is missing");
 		let csv = CsvSource::new(csv_config);
 
-		let result = csv.read_csv();
+		let result = csv.read_csv().await;
 		assert!(result.is_ok(), "Failed to get users: {:?}", result);
 
 		let users = result.expect("Failed to get users");
-		assert_eq!(users.len(), 1, "Unexpected number of users");
-		assert_eq!(users[0].email, "jane.smith@example.com", "Unexpected email at index 0");
-		assert_eq!(users[0].last_name, "Smith", "Unexpected last name at index 0");
+		assert_eq!(users.len(), 2, "Unexpected number of users");
+		// The invalid row is kept, so that its user isn't deleted
 		assert_eq!(
 			users[0].external_user_id,
-			hex::encode("jane.smith@example.com".as_bytes()),
+			hex::encode("john.doe@example.com".as_bytes()),
 			"Unexpected external_user_id at index 0"
 		);
+		assert!(users[0].is_unparsable(), "Expected unparsable user at index 0");
+		assert_eq!(users[1].email, "jane.smith@example.com", "Unexpected email at index 1");
+		assert_eq!(users[1].last_name, "Smith", "Unexpected last name at index 1");
 		assert_eq!(
-			users[0].localpart,
+			users[1].external_user_id,
+			hex::encode("jane.smith@example.com".as_bytes()),
+			"Unexpected external_user_id at index 1"
+		);
+		assert_eq!(
+			users[1].localpart,
 			Some("jane.smith".to_owned()),
-			"Unexpected localpart at index 0"
+			"Unexpected localpart at index 1"
 		);
+		assert!(users[1].unreadable.is_none(), "Unexpected unparsable user at index 1");
+	}
+
+	#[tokio::test]
+	async fn test_get_users_invalid_content_without_email() {
+		let mut config = load_config();
+		let csv_content = indoc! {r#"
+          email,first_name,last_name,phone,localpart
+          ,John
+          jane.smith@example.com,Jane,Smith,+2222222222,jane.smith
+        "#};
+		let _file = test_helpers::temp_csv_file(&mut config, csv_content);
+
+		let csv_config = config.sources.csv.expect("CsvSource configuration is missing");
+		let csv = CsvSource::new(csv_config);
+
+		let error = csv.read_csv().await.expect_err("Expected error for unidentifiable row");
+		assert!(error.to_string().contains("row 1"), "Unexpected error message: {:?}", error);
 	}
 
-	#[test]
-	fn test_backward_compatibility() {
+	#[tokio::test]
+	async fn test_backward_compatibility() {
 		// Test that old CSV format without localpart column still works
 		let mut config = load_config();
 		let csv_content = indoc! {r#"
@@ -308,7 +555,7 @@ mod tests {
 		let csv_config = config.sources.csv.expect("CsvSource configuration is missing");
 		let csv = CsvSource::new(csv_config);
 
-		let result = csv.read_csv();
+		let result = csv.read_csv().await;
 		assert!(result.is_ok(), "Failed to get users: {:?}", result);
 
 		let users = result.expect("Failed to get users");
@@ -319,4 +566,32 @@ mod tests {
 			"Expected all users to have None localpart"
 		);
 	}
+
+	#[tokio::test]
+	async fn test_get_raw_attributes() {
+		let mut config = load_config();
+		let csv_content = indoc! {r#"
+          email,first_name,last_name,phone
+          john.doe@example.com,John,Doe,+1111111111
+          jane.smith@example.com,Jane,Smith,
+        "#};
+		let _file = test_helpers::temp_csv_file(&mut config, csv_content);
+
+		let csv_config = config.sources.csv.expect("CsvSource configuration is missing");
+		let csv = CsvSource::new(csv_config);
+
+		let attributes = csv
+			.get_raw_attributes(&hex::encode("jane.smith@example.com"))
+			.await
+			.expect("Failed to get raw attributes")
+			.expect("User not found");
+		assert_eq!(attributes.get("first_name"), Some(&vec!["Jane".to_owned()]));
+		assert_eq!(attributes.get("phone"), Some(&vec![String::new()]));
+
+		let attributes = csv
+			.get_raw_attributes(&hex::encode("missing@example.com"))
+			.await
+			.expect("Failed to get raw attributes");
+		assert!(attributes.is_none());
+	}
 }
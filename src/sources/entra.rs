@@ -0,0 +1,785 @@
+//! Microsoft Entra ID source for syncing with Famedly's Zitadel.
+//!
+//! Reads the users of an Entra ID (formerly Azure AD) tenant through
+//! the Microsoft Graph API, so that cloud-only tenants can be synced
+//! without an on-premises LDAP connector. The sync authenticates as an
+//! app registration through the client credentials grant, which
+//! requires the `User.Read.All` application permission.
+//!
+//! With delta queries enabled, only the users changed since the last
+//! sync are read, starting at the delta link Graph returned at the end
+//! of the previous sync.
+
+use std::{
+	collections::{BTreeMap, BTreeSet},
+	time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use reqwest::{Client, StatusCode};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use url::Url;
+
+use super::Source;
+use crate::{
+	user::{non_empty, User},
+	SourceChanges,
+};
+
+/// The Microsoft Graph API of the global cloud
+const DEFAULT_GRAPH_URL: &str = "https://graph.microsoft.com/v1.0";
+
+/// The Microsoft identity platform of the global cloud
+const DEFAULT_LOGIN_URL: &str = "https://login.microsoftonline.com";
+
+/// How long before its expiry an access token is renewed
+const TOKEN_RENEWAL_MARGIN: Duration = Duration::from_secs(30);
+
+/// The property holding whether the account is enabled
+const ACCOUNT_ENABLED: &str = "accountEnabled";
+
+/// The property marking users removed since the last delta query
+const REMOVED: &str = "@removed";
+
+/// Entra ID Source
+pub struct EntraSource {
+	/// Entra ID Source configuration
+	entra_config: EntraSourceConfig,
+	/// The Microsoft Graph API
+	graph_url: Url,
+	/// The Microsoft identity platform
+	login_url: Url,
+	/// Reqwest client
+	client: Client,
+}
+
+#[async_trait]
+impl Source for EntraSource {
+	fn get_name(&self) -> &'static str {
+		"Entra"
+	}
+
+	async fn get_sorted_users(&self) -> Result<Vec<User>> {
+		let (graph_users, _) = self.fetch_pages(self.list_url()?, &mut None).await?;
+		self.to_sorted_users(&graph_users)
+	}
+
+	async fn get_raw_attributes(
+		&self,
+		external_user_id: &str,
+	) -> Result<Option<BTreeMap<String, Vec<String>>>> {
+		let (graph_users, _) = self.fetch_pages(self.list_url()?, &mut None).await?;
+
+		Ok(graph_users
+			.iter()
+			.find(|graph_user| {
+				graph_user.external_user_id(&self.entra_config).ok().as_deref()
+					== Some(external_user_id)
+			})
+			.map(GraphUser::raw_attributes))
+	}
+}
+
+impl EntraSource {
+	/// Create a new Entra ID source
+	pub fn new(entra_config: EntraSourceConfig) -> Result<Self> {
+		let client = Client::builder()
+			.timeout(Duration::from_secs(entra_config.timeout))
+			.build()
+			.context("Failed to build the Microsoft Graph client")?;
+		let graph_url = match &entra_config.graph_url {
+			Some(graph_url) => graph_url.clone(),
+			None => Url::parse(DEFAULT_GRAPH_URL)?,
+		};
+		let login_url = match &entra_config.login_url {
+			Some(login_url) => login_url.clone(),
+			None => Url::parse(DEFAULT_LOGIN_URL)?,
+		};
+
+		Ok(Self { entra_config, graph_url, login_url, client })
+	}
+
+	/// Get all users along with the delta link to read the changes since
+	/// from, by starting a new round of delta queries
+	pub(crate) async fn get_users_with_delta_link(&self) -> Result<(Vec<User>, String)> {
+		let url = self.users_url(&["delta"])?;
+		let (graph_users, delta_link) = self.fetch_pages(url, &mut None).await?;
+		let delta_link = delta_link.context("Microsoft Graph returned no delta link")?;
+
+		let graph_users: Vec<_> =
+			graph_users.into_iter().filter(|graph_user| !graph_user.is_removed()).collect();
+		Ok((self.to_sorted_users(&graph_users)?, delta_link.into()))
+	}
+
+	/// Get the users changed and removed since the given delta link was
+	/// returned, along with the delta link to read the next changes from
+	///
+	/// Delta queries only return the properties which changed, so the
+	/// changed users are read again in full.
+	pub(crate) async fn get_delta_changes(
+		&self,
+		delta_link: &str,
+	) -> Result<(SourceChanges, String)> {
+		let url = Url::parse(delta_link).context("Invalid Microsoft Graph delta link")?;
+		let mut token = None;
+		let (graph_users, delta_link) = self.fetch_pages(url, &mut token).await?;
+		let delta_link = delta_link.context("Microsoft Graph returned no delta link")?;
+
+		// Users may be listed more than once, the last entry being the
+		// current one
+		let mut removed_by_id = BTreeMap::new();
+		for graph_user in &graph_users {
+			removed_by_id.insert(graph_user.id()?.to_owned(), graph_user.is_removed());
+		}
+
+		let mut changes = SourceChanges::default();
+		for (id, removed) in removed_by_id {
+			let graph_user = if removed { None } else { self.fetch_user(&id, &mut token).await? };
+			match graph_user {
+				Some(graph_user) => {
+					changes.changed.push(graph_user.to_user_or_unparsable(&self.entra_config)?);
+				}
+				None => changes.removed.push(hex::encode(&id)),
+			}
+		}
+
+		tracing::info!(
+			"Read {} changed and {} removed users from Entra ID",
+			changes.changed.len(),
+			changes.removed.len()
+		);
+
+		Ok((changes, delta_link.into()))
+	}
+
+	/// The URL of the given path below the users collection, selecting
+	/// the mapped properties
+	fn users_url(&self, segments: &[&str]) -> Result<Url> {
+		let mut url = self.graph_url.clone();
+		url.path_segments_mut()
+			.map_err(|()| anyhow::anyhow!("Invalid Microsoft Graph URL"))?
+			.pop_if_empty()
+			.push("users")
+			.extend(segments);
+		url.query_pairs_mut().append_pair("$select", &self.entra_config.attributes.select());
+
+		Ok(url)
+	}
+
+	/// The URL listing the users to sync
+	fn list_url(&self) -> Result<Url> {
+		let mut url = self.users_url(&[])?;
+		url.query_pairs_mut().append_pair("$top", &self.entra_config.page_size.to_string());
+		if let Some(filter) = &self.entra_config.filter {
+			url.query_pairs_mut().append_pair("$filter", filter);
+		}
+
+		Ok(url)
+	}
+
+	/// Fetch a single user, if it still exists
+	async fn fetch_user(
+		&self,
+		id: &str,
+		token: &mut Option<AccessToken>,
+	) -> Result<Option<GraphUser>> {
+		let url = self.users_url(&[id])?;
+		let access_token = self.access_token(token).await?;
+		let response = self.client.get(url).bearer_auth(access_token).send().await?;
+
+		if response.status() == StatusCode::NOT_FOUND {
+			return Ok(None);
+		}
+		response.error_for_status_ref().context("Microsoft Graph received non-OK status code")?;
+
+		response.json().await.context("Failed to deserialize Microsoft Graph user").map(Some)
+	}
+
+	/// Fetch all users, following the pages of the collection, along
+	/// with the delta link of the last page, if any
+	async fn fetch_pages(
+		&self,
+		url: Url,
+		token: &mut Option<AccessToken>,
+	) -> Result<(Vec<GraphUser>, Option<Url>)> {
+		let mut graph_users = Vec::new();
+		let mut next_page = Some(url);
+		let mut delta_link = None;
+
+		while let Some(page_url) = next_page.take() {
+			// Don't hand the access token to other servers
+			if page_url.origin() != self.graph_url.origin() {
+				anyhow::bail!("Microsoft Graph linked to a page on another server: {}", page_url);
+			}
+
+			let access_token = self.access_token(token).await?;
+			let response = self
+				.client
+				.get(page_url)
+				.bearer_auth(access_token)
+				.header("Prefer", format!("odata.maxpagesize={}", self.entra_config.page_size))
+				.send()
+				.await?;
+			response
+				.error_for_status_ref()
+				.context("Microsoft Graph received non-OK status code")?;
+			let page: GraphPage =
+				response.json().await.context("Failed to deserialize Microsoft Graph page")?;
+
+			graph_users.extend(page.value);
+			next_page = page.next_link;
+			delta_link = page.delta_link;
+		}
+
+		tracing::info!("Fetched {} users from Entra ID", graph_users.len());
+
+		Ok((graph_users, delta_link))
+	}
+
+	/// Convert users read from Graph, sorted by external user ID
+	fn to_sorted_users(&self, graph_users: &[GraphUser]) -> Result<Vec<User>> {
+		let mut users: Vec<User> = graph_users
+			.iter()
+			.map(|graph_user| graph_user.to_user_or_unparsable(&self.entra_config))
+			.collect::<Result<_>>()?;
+
+		users.sort_by(|a, b| a.external_user_id.cmp(&b.external_user_id));
+		Ok(users)
+	}
+
+	/// Get an access token, renewing the given one if it is about to
+	/// expire
+	async fn access_token(&self, token: &mut Option<AccessToken>) -> Result<String> {
+		if let Some(current) = token
+			.as_ref()
+			.filter(|current| current.expires_at > Instant::now() + TOKEN_RENEWAL_MARGIN)
+		{
+			return Ok(current.access_token.clone());
+		}
+
+		let renewed = self.get_access_token().await?;
+		let access_token = renewed.access_token.clone();
+		*token = Some(renewed);
+		Ok(access_token)
+	}
+
+	/// Get an access token through the client credentials grant
+	async fn get_access_token(&self) -> Result<AccessToken> {
+		let config = &self.entra_config;
+		let mut token_url = self.login_url.clone();
+		token_url
+			.path_segments_mut()
+			.map_err(|()| anyhow::anyhow!("Invalid Microsoft login URL"))?
+			.pop_if_empty()
+			.extend([config.tenant_id.as_str(), "oauth2", "v2.0", "token"]);
+		// Application permissions are requested through the default
+		// scope of the Graph API
+		let scope = format!("{}/.default", self.graph_url.origin().ascii_serialization());
+		let params = [
+			("grant_type", "client_credentials"),
+			("client_id", &config.client_id),
+			("client_secret", &config.client_secret),
+			("scope", &scope),
+		];
+
+		let requested_at = Instant::now();
+		let response = self.client.post(token_url).form(&params).send().await?;
+
+		response.error_for_status_ref().context("Entra ID oAuth2 received non-OK status code")?;
+
+		let response: TokenResponse =
+			response.json().await.context("Failed to deserialize oAuth2 token response")?;
+
+		Ok(AccessToken {
+			access_token: response.access_token,
+			expires_at: requested_at + Duration::from_secs(response.expires_in),
+		})
+	}
+}
+
+/// Configuration to get a list of users from a Microsoft Entra ID
+/// tenant through the Microsoft Graph API
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct EntraSourceConfig {
+	/// The ID of the tenant
+	pub tenant_id: String,
+	/// The application (client) ID of the app registration
+	pub client_id: String,
+	/// A client secret of the app registration
+	pub client_secret: String,
+	/// The Microsoft Graph API, which differs in national clouds.
+	/// Defaults to `https://graph.microsoft.com/v1.0`.
+	pub graph_url: Option<Url>,
+	/// The Microsoft identity platform issuing access tokens, which
+	/// differs in national clouds. Defaults to
+	/// `https://login.microsoftonline.com`.
+	pub login_url: Option<Url>,
+	/// The properties of Graph users to sync
+	#[serde(default)]
+	pub attributes: EntraAttributes,
+	/// An OData filter restricting the users to sync, e.g.
+	/// `userType eq 'Member'`. Don't filter out disabled users, since
+	/// they wouldn't be deleted from Zitadel.
+	pub filter: Option<String>,
+	/// Read only the changes since the last sync, using delta queries
+	#[serde(default)]
+	pub delta: bool,
+	/// The number of users to request per page, at most 999
+	#[serde(default = "default_page_size")]
+	pub page_size: u32,
+	/// Timeout for Microsoft Graph requests in seconds
+	#[serde(default = "default_timeout")]
+	pub timeout: u64,
+}
+
+/// The properties of Graph users mapped to the fields of users
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct EntraAttributes {
+	/// The property used as the external user ID. Delta queries only
+	/// identify removed users by their `id`, so they require it.
+	#[serde(default = "default_external_user_id")]
+	pub external_user_id: String,
+	/// The property of the first name
+	#[serde(default = "default_first_name")]
+	pub first_name: String,
+	/// The property of the last name
+	#[serde(default = "default_last_name")]
+	pub last_name: String,
+	/// The property of the email address. Cloud-only users without a
+	/// mailbox lack `mail`, in which case `userPrincipalName` may be
+	/// used.
+	#[serde(default = "default_email")]
+	pub email: String,
+	/// The property of the phone number. Of properties listing several
+	/// numbers, such as `businessPhones`, the first is used.
+	#[serde(default = "default_phone")]
+	pub phone: Option<String>,
+	/// The property of the preferred username. If unset, the preferred
+	/// username isn't synced, and existing values in Zitadel are left
+	/// as they are.
+	pub preferred_username: Option<String>,
+}
+
+impl Default for EntraAttributes {
+	fn default() -> Self {
+		Self {
+			external_user_id: default_external_user_id(),
+			first_name: default_first_name(),
+			last_name: default_last_name(),
+			email: default_email(),
+			phone: default_phone(),
+			preferred_username: None,
+		}
+	}
+}
+
+impl EntraAttributes {
+	/// The `$select` parameter of the mapped properties
+	fn select(&self) -> String {
+		let properties: BTreeSet<&str> = [
+			Some("id"),
+			Some(ACCOUNT_ENABLED),
+			Some(self.external_user_id.as_str()),
+			Some(self.first_name.as_str()),
+			Some(self.last_name.as_str()),
+			Some(self.email.as_str()),
+			self.phone.as_deref(),
+			self.preferred_username.as_deref(),
+		]
+		.into_iter()
+		.flatten()
+		.collect();
+
+		properties.into_iter().collect::<Vec<_>>().join(",")
+	}
+}
+
+/// Default for [`EntraSourceConfig::page_size`]
+fn default_page_size() -> u32 {
+	999
+}
+
+/// Default for [`EntraSourceConfig::timeout`]
+fn default_timeout() -> u64 {
+	30
+}
+
+/// Default for [`EntraAttributes::external_user_id`]
+fn default_external_user_id() -> String {
+	"id".to_owned()
+}
+
+/// Default for [`EntraAttributes::first_name`]
+fn default_first_name() -> String {
+	"givenName".to_owned()
+}
+
+/// Default for [`EntraAttributes::last_name`]
+fn default_last_name() -> String {
+	"surname".to_owned()
+}
+
+/// Default for [`EntraAttributes::email`]
+fn default_email() -> String {
+	"mail".to_owned()
+}
+
+/// Default for [`EntraAttributes::phone`]
+fn default_phone() -> Option<String> {
+	Some("mobilePhone".to_owned())
+}
+
+/// OAuth2 token response
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+	/// Access token
+	access_token: String,
+	/// Lifetime of the access token in seconds
+	expires_in: u64,
+}
+
+/// An access token along with its expiry
+#[derive(Debug)]
+struct AccessToken {
+	/// Access token
+	access_token: String,
+	/// When the access token expires
+	expires_at: Instant,
+}
+
+/// A page of a Graph collection
+#[derive(Debug, Deserialize)]
+struct GraphPage {
+	/// The users of this page
+	#[serde(default)]
+	value: Vec<GraphUser>,
+	/// The URL of the next page, if any
+	#[serde(rename = "@odata.nextLink")]
+	next_link: Option<Url>,
+	/// The URL to read the next changes from, on the last page of
+	/// delta queries
+	#[serde(rename = "@odata.deltaLink")]
+	delta_link: Option<Url>,
+}
+
+/// A Graph user, with the selected properties
+#[derive(Debug, Deserialize)]
+#[serde(transparent)]
+struct GraphUser(Map<String, Value>);
+
+impl GraphUser {
+	/// The object ID of the user
+	fn id(&self) -> Result<&str> {
+		self.0.get("id").and_then(Value::as_str).context("Graph user lacks an ID")
+	}
+
+	/// Whether the user was removed since the last delta query, e.g.
+	/// deleted or moved out of scope
+	fn is_removed(&self) -> bool {
+		self.0.contains_key(REMOVED)
+	}
+
+	/// The value of the given property, the first one of lists
+	fn property(&self, name: &str) -> Option<String> {
+		let value = match self.0.get(name)? {
+			Value::Array(values) => values.first()?,
+			value => value,
+		};
+		let value = match value {
+			Value::String(value) => value.clone(),
+			Value::Bool(value) => value.to_string(),
+			Value::Number(value) => value.to_string(),
+			_ => return None,
+		};
+
+		non_empty(Some(value))
+	}
+
+	/// The external user ID, from the configured property
+	fn external_user_id(&self, config: &EntraSourceConfig) -> Result<String> {
+		let property = &config.attributes.external_user_id;
+		let id = self.property(property).context(format!("Missing property `{property}`"))?;
+
+		Ok(hex::encode(id))
+	}
+
+	/// Convert the Graph user to a user
+	fn to_user(&self, config: &EntraSourceConfig) -> Result<User> {
+		let attributes = &config.attributes;
+
+		Ok(User {
+			first_name: self.property(&attributes.first_name).unwrap_or_default(),
+			last_name: self.property(&attributes.last_name).unwrap_or_default(),
+			email: self
+				.property(&attributes.email)
+				.context(format!("Missing property `{}`", attributes.email))?,
+			phone: attributes.phone.as_deref().and_then(|phone| self.property(phone)),
+			preferred_username: attributes
+				.preferred_username
+				.as_deref()
+				.and_then(|preferred_username| self.property(preferred_username)),
+			external_user_id: self.external_user_id(config)?,
+			enabled: self.0.get(ACCOUNT_ENABLED).and_then(Value::as_bool).unwrap_or(true),
+			localpart: None,
+			metadata: BTreeMap::new(),
+			roles: BTreeSet::new(),
+			groups: BTreeSet::new(),
+			common_name: None,
+			source_version: None,
+			unreadable: None,
+			preferred_username_unknown: false,
+		})
+	}
+
+	/// Convert the Graph user to a user, or to an unparsable user if it
+	/// can be identified, so that its Zitadel counterpart is left
+	/// untouched. Users which can't be identified abort, since the user
+	/// they belong to can't be told.
+	fn to_user_or_unparsable(&self, config: &EntraSourceConfig) -> Result<User> {
+		let error = match self.to_user(config) {
+			Ok(user) => return Ok(user),
+			Err(error) => error,
+		};
+		let id = self.id().unwrap_or_default();
+		let external_user_id = self
+			.external_user_id(config)
+			.context(format!("Failed to identify Graph user `{id}`: {error:?}"))?;
+
+		tracing::error!("Failed to parse Graph user `{}`: {:?}", id, error);
+		Ok(User::unparsable(external_user_id, format!("{error:#}")))
+	}
+
+	/// The user's properties, for debugging
+	fn raw_attributes(&self) -> BTreeMap<String, Vec<String>> {
+		/// The value as a string, without the quotes of JSON strings
+		fn to_string(value: &Value) -> String {
+			value.as_str().map_or_else(|| value.to_string(), str::to_owned)
+		}
+
+		self.0
+			.iter()
+			.map(|(name, value)| {
+				let values = match value {
+					Value::Array(values) => values.iter().map(to_string).collect(),
+					Value::Null => Vec::new(),
+					value => vec![to_string(value)],
+				};
+				(name.clone(), values)
+			})
+			.collect()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use indoc::indoc;
+	use wiremock::{
+		matchers::{body_string_contains, header, method, path, query_param},
+		Mock, MockServer, ResponseTemplate,
+	};
+
+	use super::*;
+
+	fn graph_user(id: &str, email: &str, enabled: bool) -> Value {
+		serde_json::json!({
+			"id": id,
+			"accountEnabled": enabled,
+			"givenName": "Erika",
+			"surname": "Mustermann",
+			"mail": email,
+			"mobilePhone": null,
+			"businessPhones": ["+49 123 456", "+49 123 789"],
+		})
+	}
+
+	fn load_config(base_url: &str) -> EntraSourceConfig {
+		serde_yaml::from_str(&format!(
+			indoc! {r#"
+				tenant_id: contoso
+				client_id: famedly-sync
+				client_secret: mock_client_secret
+				graph_url: {0}/v1.0
+				login_url: {0}
+				attributes:
+				  phone: businessPhones
+				page_size: 1
+			"#},
+			base_url
+		))
+		.expect("invalid config")
+	}
+
+	async fn mock_token(mock_server: &MockServer) {
+		Mock::given(method("POST"))
+			.and(path("/contoso/oauth2/v2.0/token"))
+			.and(body_string_contains("grant_type=client_credentials"))
+			.and(body_string_contains("client_secret=mock_client_secret"))
+			.and(body_string_contains("%2F.default"))
+			.respond_with(ResponseTemplate::new(StatusCode::OK).set_body_json(serde_json::json!({
+				"access_token": "mock_access_token",
+				"token_type": "Bearer",
+				"expires_in": 3599,
+			})))
+			.mount(mock_server)
+			.await;
+	}
+
+	#[test]
+	fn test_graph_user_to_user() {
+		let config = load_config("https://graph.example.org");
+		let graph_user: GraphUser =
+			serde_json::from_value(graph_user("1", "erika@example.org", false))
+				.expect("invalid Graph user");
+
+		let user = graph_user.to_user(&config).expect("failed to convert Graph user");
+		assert_eq!(user.first_name, "Erika");
+		assert_eq!(user.last_name, "Mustermann");
+		assert_eq!(user.email, "erika@example.org");
+		assert_eq!(user.phone, Some("+49 123 456".to_owned()));
+		assert_eq!(user.external_user_id, hex::encode("1"));
+		assert_eq!(user.preferred_username, None);
+		assert!(!user.enabled);
+
+		// Without an email address, the user is still identified, so
+		// that its Zitadel counterpart isn't deleted
+		let graph_user: GraphUser =
+			serde_json::from_value(serde_json::json!({ "id": "2", "mail": null }))
+				.expect("invalid Graph user");
+		assert!(graph_user.to_user(&config).is_err());
+		let user = graph_user.to_user_or_unparsable(&config).expect("failed to identify user");
+		assert_eq!(user.external_user_id, hex::encode("2"));
+		assert!(user.is_unparsable());
+
+		assert_eq!(
+			config.attributes.select(),
+			"accountEnabled,businessPhones,givenName,id,mail,surname"
+		);
+	}
+
+	#[tokio::test]
+	async fn test_get_sorted_users() {
+		let mock_server = MockServer::start().await;
+		let config = load_config(&mock_server.uri());
+		let next_page = format!("{}/v1.0/users?$skiptoken=abc", mock_server.uri());
+		mock_token(&mock_server).await;
+
+		Mock::given(method("GET"))
+			.and(path("/v1.0/users"))
+			.and(query_param("$top", "1"))
+			.and(header("Authorization", "Bearer mock_access_token"))
+			.respond_with(ResponseTemplate::new(StatusCode::OK).set_body_json(serde_json::json!({
+				"@odata.nextLink": next_page,
+				"value": [graph_user("b", "erika@example.org", true)],
+			})))
+			.mount(&mock_server)
+			.await;
+		Mock::given(method("GET"))
+			.and(path("/v1.0/users"))
+			.and(query_param("$skiptoken", "abc"))
+			.respond_with(ResponseTemplate::new(StatusCode::OK).set_body_json(serde_json::json!({
+				"value": [graph_user("a", "max@example.org", true)],
+			})))
+			.mount(&mock_server)
+			.await;
+
+		let entra = EntraSource::new(config).expect("failed to create Entra ID source");
+		let users = entra.get_sorted_users().await.expect("failed to get users");
+
+		let emails: Vec<_> = users.iter().map(|user| user.email.as_str()).collect();
+		assert_eq!(emails, vec!["max@example.org", "erika@example.org"]);
+
+		let attributes = entra
+			.get_raw_attributes(&hex::encode("a"))
+			.await
+			.expect("failed to get attributes")
+			.expect("user not found");
+		assert_eq!(attributes.get("mail"), Some(&vec!["max@example.org".to_owned()]));
+		assert_eq!(attributes.get("businessPhones").map(Vec::len), Some(2));
+	}
+
+	#[tokio::test]
+	async fn test_delta_queries() {
+		let mock_server = MockServer::start().await;
+		let config = load_config(&mock_server.uri());
+		let delta_link = format!("{}/v1.0/users/delta?$deltatoken=1", mock_server.uri());
+		let next_delta_link = format!("{}/v1.0/users/delta?$deltatoken=2", mock_server.uri());
+		mock_token(&mock_server).await;
+
+		Mock::given(method("GET"))
+			.and(path("/v1.0/users/delta"))
+			.and(query_param("$select", config.attributes.select()))
+			.respond_with(ResponseTemplate::new(StatusCode::OK).set_body_json(serde_json::json!({
+				"@odata.deltaLink": delta_link,
+				"value": [
+					graph_user("a", "erika@example.org", true),
+					graph_user("b", "max@example.org", true),
+				],
+			})))
+			.mount(&mock_server)
+			.await;
+		Mock::given(method("GET"))
+			.and(path("/v1.0/users/delta"))
+			.and(query_param("$deltatoken", "1"))
+			.respond_with(ResponseTemplate::new(StatusCode::OK).set_body_json(serde_json::json!({
+				"@odata.deltaLink": next_delta_link,
+				"value": [
+					{ "id": "a", "surname": "Musterfrau" },
+					{ "id": "b", "@removed": { "reason": "changed" } },
+					{ "id": "c", "givenName": "Gone" },
+				],
+			})))
+			.mount(&mock_server)
+			.await;
+		Mock::given(method("GET"))
+			.and(path("/v1.0/users/a"))
+			.respond_with(ResponseTemplate::new(StatusCode::OK).set_body_json(serde_json::json!({
+				"id": "a",
+				"accountEnabled": true,
+				"givenName": "Erika",
+				"surname": "Musterfrau",
+				"mail": "erika@example.org",
+			})))
+			.mount(&mock_server)
+			.await;
+		Mock::given(method("GET"))
+			.and(path("/v1.0/users/c"))
+			.respond_with(ResponseTemplate::new(StatusCode::NOT_FOUND))
+			.mount(&mock_server)
+			.await;
+
+		let entra = EntraSource::new(config).expect("failed to create Entra ID source");
+		let (users, link) =
+			entra.get_users_with_delta_link().await.expect("failed to start delta queries");
+		assert_eq!(users.len(), 2);
+		assert_eq!(link, delta_link);
+
+		let (changes, link) = entra.get_delta_changes(&link).await.expect("failed to get changes");
+		assert_eq!(link, next_delta_link);
+		let last_names: Vec<_> =
+			changes.changed.iter().map(|user| user.last_name.as_str()).collect();
+		assert_eq!(last_names, vec!["Musterfrau"]);
+		assert_eq!(changes.removed, vec![hex::encode("b"), hex::encode("c")]);
+	}
+
+	#[tokio::test]
+	async fn test_foreign_next_page() {
+		let mock_server = MockServer::start().await;
+		let config = load_config(&mock_server.uri());
+		mock_token(&mock_server).await;
+
+		Mock::given(method("GET"))
+			.and(path("/v1.0/users"))
+			.respond_with(ResponseTemplate::new(StatusCode::OK).set_body_json(serde_json::json!({
+				"@odata.nextLink": "https://attacker.example.org/v1.0/users?$skiptoken=abc",
+				"value": [],
+			})))
+			.mount(&mock_server)
+			.await;
+
+		let entra = EntraSource::new(config).expect("failed to create Entra ID source");
+		let error = entra.get_sorted_users().await.expect_err("followed a foreign page");
+		assert!(error.to_string().contains("another server"));
+	}
+}
@@ -0,0 +1,530 @@
+//! Keycloak source for syncing with Famedly's Zitadel.
+//!
+//! Reads the users of a Keycloak realm through the Keycloak Admin REST
+//! API. The sync authenticates as a confidential client with a service
+//! account through the client credentials grant; the service account
+//! needs the `view-users` role of the `realm-management` client.
+
+use std::{
+	collections::{BTreeMap, BTreeSet},
+	time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use url::Url;
+
+use super::Source;
+use crate::user::{non_empty, User};
+
+/// How long before its expiry an access token is renewed
+const TOKEN_RENEWAL_MARGIN: Duration = Duration::from_secs(30);
+
+/// The property holding whether the account is enabled
+const ENABLED: &str = "enabled";
+
+/// The property holding the custom attributes of a user
+const ATTRIBUTES: &str = "attributes";
+
+/// Keycloak Source
+pub struct KeycloakSource {
+	/// Keycloak Source configuration
+	keycloak_config: KeycloakSourceConfig,
+	/// Reqwest client
+	client: Client,
+}
+
+#[async_trait]
+impl Source for KeycloakSource {
+	fn get_name(&self) -> &'static str {
+		"Keycloak"
+	}
+
+	async fn get_sorted_users(&self) -> Result<Vec<User>> {
+		let mut users: Vec<User> = self
+			.fetch_users()
+			.await?
+			.iter()
+			.map(|keycloak_user| keycloak_user.to_user_or_unparsable(&self.keycloak_config))
+			.collect::<Result<_>>()?;
+
+		users.sort_by(|a, b| a.external_user_id.cmp(&b.external_user_id));
+		Ok(users)
+	}
+
+	async fn get_raw_attributes(
+		&self,
+		external_user_id: &str,
+	) -> Result<Option<BTreeMap<String, Vec<String>>>> {
+		Ok(self
+			.fetch_users()
+			.await?
+			.iter()
+			.find(|keycloak_user| {
+				keycloak_user.external_user_id(&self.keycloak_config).ok().as_deref()
+					== Some(external_user_id)
+			})
+			.map(KeycloakUser::raw_attributes))
+	}
+}
+
+impl KeycloakSource {
+	/// Create a new Keycloak source
+	pub fn new(keycloak_config: KeycloakSourceConfig) -> Result<Self> {
+		let client = Client::builder()
+			.timeout(Duration::from_secs(keycloak_config.timeout))
+			.build()
+			.context("Failed to build the Keycloak client")?;
+
+		Ok(Self { keycloak_config, client })
+	}
+
+	/// Fetch all users of the realm, page by page
+	async fn fetch_users(&self) -> Result<Vec<KeycloakUser>> {
+		let users_url = self.url(&["admin", "realms", &self.keycloak_config.realm, "users"])?;
+		let page_size = self.keycloak_config.page_size;
+
+		let mut token = None;
+		let mut keycloak_users = Vec::new();
+		loop {
+			let mut page_url = users_url.clone();
+			page_url
+				.query_pairs_mut()
+				.append_pair("first", &keycloak_users.len().to_string())
+				.append_pair("max", &page_size.to_string())
+				.append_pair("briefRepresentation", "false");
+
+			let response = self
+				.client
+				.get(page_url)
+				.bearer_auth(self.access_token(&mut token).await?)
+				.send()
+				.await?;
+			response.error_for_status_ref().context("Keycloak received non-OK status code")?;
+			let page: Vec<KeycloakUser> =
+				response.json().await.context("Failed to deserialize Keycloak users")?;
+
+			let last_page = page.len() < usize::try_from(page_size)?;
+			keycloak_users.extend(page);
+			if last_page {
+				break;
+			}
+		}
+
+		tracing::info!("Fetched {} users from Keycloak", keycloak_users.len());
+
+		Ok(keycloak_users)
+	}
+
+	/// The URL of the given path below the Keycloak base URL
+	fn url(&self, segments: &[&str]) -> Result<Url> {
+		let mut url = self.keycloak_config.base_url.clone();
+		url.path_segments_mut()
+			.map_err(|()| anyhow::anyhow!("Invalid Keycloak base URL"))?
+			.pop_if_empty()
+			.extend(segments);
+
+		Ok(url)
+	}
+
+	/// Get an access token, renewing the given one if it is about to
+	/// expire
+	async fn access_token(&self, token: &mut Option<AccessToken>) -> Result<String> {
+		if let Some(current) = token
+			.as_ref()
+			.filter(|current| current.expires_at > Instant::now() + TOKEN_RENEWAL_MARGIN)
+		{
+			return Ok(current.access_token.clone());
+		}
+
+		let renewed = self.get_access_token().await?;
+		let access_token = renewed.access_token.clone();
+		*token = Some(renewed);
+		Ok(access_token)
+	}
+
+	/// Get an access token through the client credentials grant
+	async fn get_access_token(&self) -> Result<AccessToken> {
+		let config = &self.keycloak_config;
+		let auth_realm = config.auth_realm.as_deref().unwrap_or(&config.realm);
+		let token_url = self.url(&["realms", auth_realm, "protocol", "openid-connect", "token"])?;
+		let params = [
+			("grant_type", "client_credentials"),
+			("client_id", &config.client_id),
+			("client_secret", &config.client_secret),
+		];
+
+		let requested_at = Instant::now();
+		let response = self.client.post(token_url).form(&params).send().await?;
+
+		response.error_for_status_ref().context("Keycloak oAuth2 received non-OK status code")?;
+
+		let response: TokenResponse =
+			response.json().await.context("Failed to deserialize oAuth2 token response")?;
+
+		Ok(AccessToken {
+			access_token: response.access_token,
+			expires_at: requested_at + Duration::from_secs(response.expires_in),
+		})
+	}
+}
+
+/// Configuration to get a list of users from a Keycloak realm
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct KeycloakSourceConfig {
+	/// The base URL of the Keycloak server, e.g.
+	/// `https://keycloak.example.org`, including the `/auth` path of
+	/// older versions
+	pub base_url: Url,
+	/// The realm whose users to sync
+	pub realm: String,
+	/// The realm of the client, if it differs from `realm`, e.g.
+	/// `master`
+	pub auth_realm: Option<String>,
+	/// The ID of the confidential client with a service account
+	pub client_id: String,
+	/// The secret of the client
+	pub client_secret: String,
+	/// The properties or custom attributes of Keycloak users to sync
+	#[serde(default)]
+	pub attributes: KeycloakAttributes,
+	/// The number of users to request per page
+	#[serde(default = "default_page_size")]
+	pub page_size: u32,
+	/// Timeout for Keycloak requests in seconds
+	#[serde(default = "default_timeout")]
+	pub timeout: u64,
+}
+
+/// The properties or custom attributes of Keycloak users mapped to the
+/// fields of users. Names which aren't properties of the user
+/// representation, such as `firstName`, refer to custom attributes.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct KeycloakAttributes {
+	/// The attribute used as the external user ID. Users federated from
+	/// LDAP carry the ID of their LDAP entry in `LDAP_ID`.
+	#[serde(default = "default_external_user_id")]
+	pub external_user_id: String,
+	/// The attribute of the first name
+	#[serde(default = "default_first_name")]
+	pub first_name: String,
+	/// The attribute of the last name
+	#[serde(default = "default_last_name")]
+	pub last_name: String,
+	/// The attribute of the email address
+	#[serde(default = "default_email")]
+	pub email: String,
+	/// The attribute of the phone number, if any
+	pub phone: Option<String>,
+	/// The attribute of the preferred username, e.g. `username`. If
+	/// unset, the preferred username isn't synced, and existing values
+	/// in Zitadel are left as they are.
+	pub preferred_username: Option<String>,
+}
+
+impl Default for KeycloakAttributes {
+	fn default() -> Self {
+		Self {
+			external_user_id: default_external_user_id(),
+			first_name: default_first_name(),
+			last_name: default_last_name(),
+			email: default_email(),
+			phone: None,
+			preferred_username: None,
+		}
+	}
+}
+
+/// Default for [`KeycloakSourceConfig::page_size`]
+fn default_page_size() -> u32 {
+	100
+}
+
+/// Default for [`KeycloakSourceConfig::timeout`]
+fn default_timeout() -> u64 {
+	30
+}
+
+/// Default for [`KeycloakAttributes::external_user_id`]
+fn default_external_user_id() -> String {
+	"id".to_owned()
+}
+
+/// Default for [`KeycloakAttributes::first_name`]
+fn default_first_name() -> String {
+	"firstName".to_owned()
+}
+
+/// Default for [`KeycloakAttributes::last_name`]
+fn default_last_name() -> String {
+	"lastName".to_owned()
+}
+
+/// Default for [`KeycloakAttributes::email`]
+fn default_email() -> String {
+	"email".to_owned()
+}
+
+/// OAuth2 token response
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+	/// Access token
+	access_token: String,
+	/// Lifetime of the access token in seconds
+	expires_in: u64,
+}
+
+/// An access token along with its expiry
+#[derive(Debug)]
+struct AccessToken {
+	/// Access token
+	access_token: String,
+	/// When the access token expires
+	expires_at: Instant,
+}
+
+/// A Keycloak user representation
+#[derive(Debug, Deserialize)]
+#[serde(transparent)]
+struct KeycloakUser(Map<String, Value>);
+
+impl KeycloakUser {
+	/// The value of the given property, or else of the custom attribute
+	/// of that name, the first one of lists
+	fn attribute(&self, name: &str) -> Option<String> {
+		let value = match self.0.get(name) {
+			Some(value) => value,
+			None => self.0.get(ATTRIBUTES)?.get(name)?,
+		};
+		let value = match value {
+			Value::Array(values) => values.first()?,
+			value => value,
+		};
+		let value = match value {
+			Value::String(value) => value.clone(),
+			Value::Bool(value) => value.to_string(),
+			Value::Number(value) => value.to_string(),
+			_ => return None,
+		};
+
+		non_empty(Some(value))
+	}
+
+	/// The external user ID, from the configured attribute
+	fn external_user_id(&self, config: &KeycloakSourceConfig) -> Result<String> {
+		let attribute = &config.attributes.external_user_id;
+		let id = self.attribute(attribute).context(format!("Missing attribute `{attribute}`"))?;
+
+		Ok(hex::encode(id))
+	}
+
+	/// Convert the Keycloak user to a user
+	fn to_user(&self, config: &KeycloakSourceConfig) -> Result<User> {
+		let attributes = &config.attributes;
+
+		Ok(User {
+			first_name: self.attribute(&attributes.first_name).unwrap_or_default(),
+			last_name: self.attribute(&attributes.last_name).unwrap_or_default(),
+			email: self
+				.attribute(&attributes.email)
+				.context(format!("Missing attribute `{}`", attributes.email))?,
+			phone: attributes.phone.as_deref().and_then(|phone| self.attribute(phone)),
+			preferred_username: attributes
+				.preferred_username
+				.as_deref()
+				.and_then(|preferred_username| self.attribute(preferred_username)),
+			external_user_id: self.external_user_id(config)?,
+			enabled: self.0.get(ENABLED).and_then(Value::as_bool).unwrap_or(true),
+			localpart: None,
+			metadata: BTreeMap::new(),
+			roles: BTreeSet::new(),
+			groups: BTreeSet::new(),
+			common_name: None,
+			source_version: None,
+			unreadable: None,
+			preferred_username_unknown: false,
+		})
+	}
+
+	/// Convert the Keycloak user to a user, or to an unparsable user if
+	/// it can be identified, so that its Zitadel counterpart is left
+	/// untouched. Users which can't be identified abort, since the user
+	/// they belong to can't be told.
+	fn to_user_or_unparsable(&self, config: &KeycloakSourceConfig) -> Result<User> {
+		let error = match self.to_user(config) {
+			Ok(user) => return Ok(user),
+			Err(error) => error,
+		};
+		let id = self.attribute("id").unwrap_or_default();
+		let external_user_id = self
+			.external_user_id(config)
+			.context(format!("Failed to identify Keycloak user `{id}`: {error:?}"))?;
+
+		tracing::error!("Failed to parse Keycloak user `{}`: {:?}", id, error);
+		Ok(User::unparsable(external_user_id, format!("{error:#}")))
+	}
+
+	/// The user's properties and custom attributes, for debugging
+	fn raw_attributes(&self) -> BTreeMap<String, Vec<String>> {
+		/// The values of a property, without the quotes of JSON strings
+		fn to_strings(value: &Value) -> Vec<String> {
+			match value {
+				Value::Array(values) => values.iter().flat_map(to_strings).collect(),
+				Value::String(value) => vec![value.clone()],
+				Value::Null => Vec::new(),
+				value => vec![value.to_string()],
+			}
+		}
+
+		let mut raw_attributes = BTreeMap::new();
+		for (name, value) in &self.0 {
+			match value {
+				Value::Object(attributes) if name == ATTRIBUTES => {
+					for (name, value) in attributes {
+						raw_attributes.insert(format!("{ATTRIBUTES}.{name}"), to_strings(value));
+					}
+				}
+				Value::Object(_) => {}
+				value => {
+					raw_attributes.insert(name.clone(), to_strings(value));
+				}
+			}
+		}
+
+		raw_attributes
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use indoc::indoc;
+	use reqwest::StatusCode;
+	use wiremock::{
+		matchers::{body_string_contains, header, method, path, query_param},
+		Mock, MockServer, ResponseTemplate,
+	};
+
+	use super::*;
+
+	fn keycloak_user(id: &str, email: &str, enabled: bool) -> Value {
+		serde_json::json!({
+			"id": id,
+			"username": format!("user-{id}"),
+			"enabled": enabled,
+			"firstName": "Erika",
+			"lastName": "Mustermann",
+			"email": email,
+			"emailVerified": true,
+			"attributes": {
+				"employeeNumber": [format!("E{id}")],
+				"phoneNumber": ["+49 123 456", "+49 123 789"],
+			},
+		})
+	}
+
+	fn load_config(base_url: &str) -> KeycloakSourceConfig {
+		serde_yaml::from_str(&format!(
+			indoc! {r#"
+				base_url: {}
+				realm: hospital
+				auth_realm: master
+				client_id: famedly-sync
+				client_secret: mock_client_secret
+				attributes:
+				  external_user_id: employeeNumber
+				  phone: phoneNumber
+				  preferred_username: username
+				page_size: 1
+			"#},
+			base_url
+		))
+		.expect("invalid config")
+	}
+
+	#[test]
+	fn test_keycloak_user_to_user() {
+		let config = load_config("https://keycloak.example.org");
+		let keycloak_user: KeycloakUser =
+			serde_json::from_value(keycloak_user("1", "erika@example.org", false))
+				.expect("invalid Keycloak user");
+
+		let user = keycloak_user.to_user(&config).expect("failed to convert Keycloak user");
+		assert_eq!(user.first_name, "Erika");
+		assert_eq!(user.last_name, "Mustermann");
+		assert_eq!(user.email, "erika@example.org");
+		assert_eq!(user.phone, Some("+49 123 456".to_owned()));
+		assert_eq!(user.preferred_username, Some("user-1".to_owned()));
+		assert_eq!(user.external_user_id, hex::encode("E1"));
+		assert!(!user.enabled);
+
+		// Without an email address, the user is still identified, so
+		// that its Zitadel counterpart isn't deleted
+		let keycloak_user: KeycloakUser = serde_json::from_value(serde_json::json!({
+			"id": "2",
+			"attributes": { "employeeNumber": ["E2"] },
+		}))
+		.expect("invalid Keycloak user");
+		assert!(keycloak_user.to_user(&config).is_err());
+		let user = keycloak_user.to_user_or_unparsable(&config).expect("failed to identify user");
+		assert_eq!(user.external_user_id, hex::encode("E2"));
+		assert!(user.is_unparsable());
+
+		let keycloak_user: KeycloakUser =
+			serde_json::from_value(serde_json::json!({ "id": "3", "email": "max@example.org" }))
+				.expect("invalid Keycloak user");
+		assert!(keycloak_user.to_user_or_unparsable(&config).is_err());
+	}
+
+	#[tokio::test]
+	async fn test_get_sorted_users() {
+		let mock_server = MockServer::start().await;
+		let config = load_config(&mock_server.uri());
+
+		Mock::given(method("POST"))
+			.and(path("/realms/master/protocol/openid-connect/token"))
+			.and(body_string_contains("grant_type=client_credentials"))
+			.and(body_string_contains("client_id=famedly-sync"))
+			.and(body_string_contains("client_secret=mock_client_secret"))
+			.respond_with(ResponseTemplate::new(StatusCode::OK).set_body_json(serde_json::json!({
+				"access_token": "mock_access_token",
+				"token_type": "Bearer",
+				"expires_in": 300,
+			})))
+			// Once for each listing, not for each page
+			.expect(2)
+			.mount(&mock_server)
+			.await;
+		for (first, users) in [
+			("0", vec![keycloak_user("2", "max@example.org", true)]),
+			("1", vec![keycloak_user("1", "erika@example.org", true)]),
+			("2", Vec::new()),
+		] {
+			Mock::given(method("GET"))
+				.and(path("/admin/realms/hospital/users"))
+				.and(query_param("first", first))
+				.and(query_param("max", "1"))
+				.and(query_param("briefRepresentation", "false"))
+				.and(header("Authorization", "Bearer mock_access_token"))
+				.respond_with(ResponseTemplate::new(StatusCode::OK).set_body_json(users))
+				.mount(&mock_server)
+				.await;
+		}
+
+		let keycloak = KeycloakSource::new(config).expect("failed to create Keycloak source");
+		let users = keycloak.get_sorted_users().await.expect("failed to get users");
+
+		let emails: Vec<_> = users.iter().map(|user| user.email.as_str()).collect();
+		assert_eq!(emails, vec!["erika@example.org", "max@example.org"]);
+
+		let attributes = keycloak
+			.get_raw_attributes(&hex::encode("E2"))
+			.await
+			.expect("failed to get attributes")
+			.expect("user not found");
+		assert_eq!(attributes.get("username"), Some(&vec!["user-2".to_owned()]));
+		assert_eq!(attributes.get("attributes.phoneNumber").map(Vec::len), Some(2));
+	}
+}
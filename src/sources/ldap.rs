@@ -1,9 +1,14 @@
 //! LDAP source for syncing with Famedly's Zitadel.
 
-use std::{fmt::Display, path::PathBuf};
+use std::{
+	collections::{BTreeMap, BTreeSet},
+	fmt::Display,
+	path::PathBuf,
+};
 
 use anyhow::{anyhow, bail, Context, Result};
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 use ldap_poller::{
 	config::TLSConfig, ldap::EntryStatus, ldap3::SearchEntry, AttributeConfig, CacheMethod,
 	ConnectionConfig, Ldap, SearchEntryExt, Searches,
@@ -14,7 +19,24 @@ use tokio_stream::{wrappers::ReceiverStream, StreamExt};
 use url::Url;
 
 use super::Source;
-use crate::user::User;
+use crate::user::{non_empty, Unreadable, User};
+
+mod active_directory;
+mod dirsync;
+mod freeipa;
+mod incremental;
+mod ucs;
+mod watch;
+
+pub use active_directory::{ActiveDirectoryAttributes, ActiveDirectorySourceConfig};
+pub use dirsync::{DirSyncChanges, DirSyncConfig};
+pub use freeipa::{FreeIpaAttributes, FreeIpaSourceConfig};
+pub use incremental::IncrementalSyncConfig;
+pub use ucs::{UcsAttributes, UcsSourceConfig};
+pub use watch::{WatchConfig, WatchEvent};
+
+/// The number of seconds per day, the unit of expiry dates
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
 
 /// LDAP sync source
 pub struct LdapSource {
@@ -29,21 +51,51 @@ impl Source for LdapSource {
 	}
 
 	async fn get_sorted_users(&self) -> Result<Vec<User>> {
+		self.read_sorted_users(None).await
+	}
+
+	async fn get_raw_attributes(
+		&self,
+		external_user_id: &str,
+	) -> Result<Option<BTreeMap<String, Vec<String>>>> {
 		let (mut ldap_client, ldap_receiver) = Ldap::new(self.ldap_config.clone().into(), None);
 
 		let sync_handle: tokio::task::JoinHandle<Result<_>> = tokio::spawn(async move {
-			ldap_client.sync_once(None).await.context("failed to sync/fetch data from LDAP")?;
-			tracing::info!("Finished syncing LDAP data");
+			ldap_client
+				.sync_once(None)
+				.await
+				.context("failed to sync/fetch data from LDAP")
+				.map_err(explain_search_limit)?;
 			Ok(())
 		});
 
-		let mut added = self.get_user_changes(ldap_receiver).await?;
+		let entries: Vec<_> = ReceiverStream::new(ldap_receiver)
+			.filter_map(|entry_status| match entry_status {
+				EntryStatus::New(entry) => Some(entry),
+				_ => None,
+			})
+			.collect()
+			.await;
 		sync_handle.await??;
 
-		// TODO: Find out if we can use the AD extension for receiving sorted data
-		added.sort_by(|a, b| a.external_user_id.cmp(&b.external_user_id));
+		for entry in entries {
+			if self.parse_user_id(&entry).ok().as_deref() != Some(external_user_id) {
+				continue;
+			}
 
-		Ok(added)
+			let mut attributes: BTreeMap<_, _> = entry.attrs.into_iter().collect();
+			attributes.extend(entry.bin_attrs.into_iter().map(|(name, values)| {
+				(
+					name,
+					values.into_iter().map(|value| format!("0x{}", hex::encode(value))).collect(),
+				)
+			}));
+			attributes.insert("dn".to_owned(), vec![entry.dn]);
+
+			return Ok(Some(attributes));
+		}
+
+		Ok(None)
 	}
 }
 
@@ -53,6 +105,42 @@ impl LdapSource {
 		Self { ldap_config }
 	}
 
+	/// Get the users modified since the given time, according to the
+	/// `last_modified` attribute, sorted by external user ID
+	pub async fn get_users_modified_since(&self, since: DateTime<Utc>) -> Result<Vec<User>> {
+		if self.ldap_config.attributes.last_modified.is_none() {
+			bail!("Reading modified users requires `attributes.last_modified` to be set");
+		}
+
+		let users = self.read_sorted_users(Some(since)).await?;
+		tracing::info!("Read {} users modified since {}", users.len(), since.to_rfc3339());
+		Ok(users)
+	}
+
+	/// Read the users from LDAP, only those modified since the given
+	/// time if any, sorted by external user ID
+	async fn read_sorted_users(&self, since: Option<DateTime<Utc>>) -> Result<Vec<User>> {
+		let (mut ldap_client, ldap_receiver) = Ldap::new(self.ldap_config.clone().into(), None);
+
+		let sync_handle: tokio::task::JoinHandle<Result<_>> = tokio::spawn(async move {
+			ldap_client
+				.sync_once(since)
+				.await
+				.context("failed to sync/fetch data from LDAP")
+				.map_err(explain_search_limit)?;
+			tracing::info!("Finished syncing LDAP data");
+			Ok(())
+		});
+
+		let mut added = self.get_user_changes(ldap_receiver).await?;
+		sync_handle.await??;
+
+		// TODO: Find out if we can use the AD extension for receiving sorted data
+		added.sort_by(|a, b| a.external_user_id.cmp(&b.external_user_id));
+
+		Ok(added)
+	}
+
 	/// Get user changes from an ldap receiver
 	pub async fn get_user_changes(
 		&self,
@@ -70,69 +158,219 @@ impl LdapSource {
 			.await
 	}
 
-	/// Construct a user from an LDAP SearchEntry
-	pub(crate) fn parse_user(&self, entry: SearchEntry) -> Result<User> {
+	/// Read the external user ID of an LDAP SearchEntry
+	fn parse_user_id(&self, entry: &SearchEntry) -> Result<String> {
+		Ok(match read_search_entry(entry, &self.ldap_config.attributes.user_id)? {
+			// Use hex encoding instead of base64 for consistent alphabetical order
+			StringOrBytes::Bytes(byte_id) => hex::encode(byte_id),
+			StringOrBytes::String(string_id) => hex::encode(string_id.as_bytes()),
+		})
+	}
+
+	/// Whether the user of an LDAP SearchEntry is enabled, according to
+	/// the configured status attribute
+	fn parse_status(&self, entry: &SearchEntry) -> Result<bool> {
 		let disable_bitmask = {
 			use std::ops::BitOr;
 			self.ldap_config.attributes.disable_bitmasks.iter().fold(0, i32::bitor)
 		};
-
-		let status = read_search_entry(&entry, &self.ldap_config.attributes.status)?;
-		let enabled = if disable_bitmask != 0 {
-			disable_bitmask
-				& match status {
-					StringOrBytes::String(status) => {
-						status.parse::<i32>().context("failed to parse status attribute")?
-					}
-					StringOrBytes::Bytes(status) => {
-						i32::from_be_bytes(status.try_into().map_err(|err: Vec<u8>| {
-							let err_string = String::from_utf8_lossy(&err).to_string();
-							anyhow!(err_string).context("failed to convert to i32 flag")
-						})?)
-					}
-				} == 0
-		} else if let StringOrBytes::String(status) = status {
-			match &status[..] {
-				"TRUE" => true,
-				"FALSE" => false,
-				_ => bail!("Cannot parse status without disable_bitmasks: {:?}", status),
+		let status = read_search_entry(entry, &self.ldap_config.attributes.status);
+
+		Ok(match self.ldap_config.attributes.status_format {
+			// Accounts are only locked if the attribute is set
+			StatusFormat::Lock => match status {
+				Ok(StringOrBytes::String(status)) => !status.eq_ignore_ascii_case("TRUE"),
+				Ok(StringOrBytes::Bytes(_)) => bail!("Binary status with the `lock` status format"),
+				Err(_) => true,
+			},
+			// Accounts only expire if the attribute is set
+			StatusFormat::Expiry => match status {
+				Ok(StringOrBytes::String(status)) => {
+					let expiry_day =
+						status.parse::<i64>().context("failed to parse expiry status attribute")?;
+					expiry_day > Utc::now().timestamp().div_euclid(SECONDS_PER_DAY)
+				}
+				Ok(StringOrBytes::Bytes(_)) => {
+					bail!("Binary status with the `expiry` status format")
+				}
+				Err(_) => true,
+			},
+			StatusFormat::Flags if disable_bitmask != 0 => {
+				disable_bitmask
+					& match status? {
+						StringOrBytes::String(status) => {
+							status.parse::<i32>().context("failed to parse status attribute")?
+						}
+						StringOrBytes::Bytes(status) => {
+							i32::from_be_bytes(status.try_into().map_err(|err: Vec<u8>| {
+								let err_string = String::from_utf8_lossy(&err).to_string();
+								anyhow!(err_string).context("failed to convert to i32 flag")
+							})?)
+						}
+					} == 0
 			}
-		} else {
-			bail!("Binary status without disable_bitmasks");
-		};
+			StatusFormat::Flags => match status? {
+				StringOrBytes::String(status) => match &status[..] {
+					"TRUE" => true,
+					"FALSE" => false,
+					_ => bail!("Cannot parse status without disable_bitmasks: {:?}", status),
+				},
+				StringOrBytes::Bytes(_) => bail!("Binary status without disable_bitmasks"),
+			},
+		})
+	}
 
-		let ldap_user_id = match read_search_entry(&entry, &self.ldap_config.attributes.user_id)? {
-			// Use hex encoding instead of base64 for consistent alphabetical order
-			StringOrBytes::Bytes(byte_id) => hex::encode(byte_id),
-			StringOrBytes::String(string_id) => hex::encode(string_id.as_bytes()),
+	/// Construct a user from an LDAP SearchEntry
+	pub(crate) fn parse_user(&self, entry: SearchEntry) -> Result<User> {
+		let enabled = self.parse_status(&entry)?;
+
+		let ldap_user_id = self.parse_user_id(&entry)?;
+
+		// Names may be missing, e.g. for single-name users, and are then
+		// filled in from the configured fallbacks, such as the common
+		// name
+		let first_name = non_empty(
+			read_string_entry(&entry, &self.ldap_config.attributes.first_name, &ldap_user_id).ok(),
+		)
+		.unwrap_or_default();
+		let last_name = non_empty(
+			read_string_entry(&entry, &self.ldap_config.attributes.last_name, &ldap_user_id).ok(),
+		)
+		.unwrap_or_default();
+		let common_name = self.ldap_config.attributes.common_name.as_ref().and_then(|attribute| {
+			non_empty(read_string_entry(&entry, attribute, &ldap_user_id).ok())
+		});
+		let missing_attributes = &self.ldap_config.missing_attributes;
+		let mut missing_attribute = None;
+		let preferred_username = self
+			.ldap_config
+			.attributes
+			.preferred_username
+			.as_ref()
+			.map(|attribute| {
+				read_required_entry(
+					&entry,
+					attribute,
+					&ldap_user_id,
+					"preferred_username",
+					&missing_attributes.preferred_username,
+					&mut missing_attribute,
+				)
+			})
+			.transpose()?;
+		// The primary address among the proxy addresses takes
+		// precedence, since `mail` isn't set for all mailboxes
+		let primary_address =
+			self.ldap_config.attributes.proxy_addresses.as_ref().and_then(|proxy_addresses| {
+				entry
+					.attrs
+					.get(&proxy_addresses.clone().get_name())
+					.and_then(|values| primary_smtp_address(values))
+			});
+		let email = match primary_address {
+			Some(email) => email,
+			None => read_required_entry(
+				&entry,
+				&self.ldap_config.attributes.email,
+				&ldap_user_id,
+				"email",
+				&missing_attributes.email,
+				&mut missing_attribute,
+			)?,
 		};
-
-		let first_name =
-			read_string_entry(&entry, &self.ldap_config.attributes.first_name, &ldap_user_id)?;
-		let last_name =
-			read_string_entry(&entry, &self.ldap_config.attributes.last_name, &ldap_user_id)?;
-		let preferred_username = read_string_entry(
-			&entry,
-			&self.ldap_config.attributes.preferred_username,
-			&ldap_user_id,
-		)?;
-		let email = read_string_entry(&entry, &self.ldap_config.attributes.email, &ldap_user_id)?;
-		let phone =
-			read_string_entry(&entry, &self.ldap_config.attributes.phone, &ldap_user_id).ok();
+		let phone = non_empty(
+			read_string_entry(&entry, &self.ldap_config.attributes.phone, &ldap_user_id).ok(),
+		);
+		let metadata = self
+			.ldap_config
+			.attributes
+			.metadata
+			.iter()
+			.filter_map(|(key, attribute)| {
+				non_empty(read_string_entry(&entry, attribute, &ldap_user_id).ok())
+					.map(|value| (key.clone(), value))
+			})
+			.collect();
+		let groups = self
+			.ldap_config
+			.attributes
+			.groups
+			.as_ref()
+			.and_then(|groups| entry.attrs.get(&groups.clone().get_name()))
+			.map(|groups| groups.iter().cloned().collect())
+			.unwrap_or_default();
+		// The modification timestamp lets syncs skip users unchanged
+		// since the last sync
+		let source_version =
+			self.ldap_config.attributes.last_modified.as_ref().and_then(|attribute| {
+				non_empty(read_string_entry(&entry, attribute, &ldap_user_id).ok())
+			});
 
 		Ok(User {
 			first_name,
 			last_name,
-			preferred_username: Some(preferred_username),
+			preferred_username,
 			email,
 			external_user_id: ldap_user_id,
 			phone,
 			enabled,
 			localpart: None,
+			metadata,
+			roles: BTreeSet::new(),
+			groups,
+			common_name,
+			source_version,
+			unreadable: missing_attribute.map(Unreadable::MissingAttribute),
+			preferred_username_unknown: false,
 		})
 	}
 }
 
+/// The result code of a search the server ended because it took too
+/// long
+const TIME_LIMIT_EXCEEDED: u32 = 3;
+
+/// The result code of a search the server ended because it matched
+/// more entries than the client may read
+const SIZE_LIMIT_EXCEEDED: u32 = 4;
+
+/// The result code of a search the server ended because it exceeded
+/// a server-wide limit, e.g. `MaxPageSize` in Active Directory
+const ADMIN_LIMIT_EXCEEDED: u32 = 11;
+
+/// Explain errors of searches the server ended early due to a size or
+/// time limit. The entries read up to that point are only part of the
+/// users, so the sync must not continue with them, since it would
+/// delete the missing users.
+fn explain_search_limit(error: anyhow::Error) -> anyhow::Error {
+	let result_code =
+		error.chain().find_map(|cause| match cause.downcast_ref::<ldap3::LdapError>() {
+			Some(ldap3::LdapError::LdapResult { result }) => Some(result.rc),
+			_ => None,
+		});
+
+	match result_code {
+		Some(SIZE_LIMIT_EXCEEDED | ADMIN_LIMIT_EXCEEDED) => error.context(
+			"The LDAP server returned only part of the users due to a size limit, aborting \
+			 before any users are changed. Enable paging by setting `page_size` below the \
+			 server's limit, or raise the size limit for the bind user",
+		),
+		Some(TIME_LIMIT_EXCEEDED) => error.context(
+			"The LDAP server stopped searching users due to a time limit, aborting before any \
+			 users are changed. Raise the time limit for the bind user, or narrow the base DN \
+			 or user filter",
+		),
+		_ => error,
+	}
+}
+
+/// Find the primary SMTP address among AD `proxyAddresses` values,
+/// which is the one with the upper-case `SMTP:` prefix, while
+/// secondary addresses use `smtp:`
+fn primary_smtp_address(proxy_addresses: &[String]) -> Option<String> {
+	proxy_addresses.iter().find_map(|address| address.strip_prefix("SMTP:")).map(ToOwned::to_owned)
+}
+
 /// Read an an attribute, but assert that it is a string
 fn read_string_entry(
 	entry: &SearchEntry,
@@ -149,6 +387,35 @@ fn read_string_entry(
 	}
 }
 
+/// Read a required attribute, applying the policy for the named field
+/// if the user lacks it. Users skipped by the policy are marked with
+/// the missing field.
+fn read_required_entry(
+	entry: &SearchEntry,
+	attribute: &AttributeMapping,
+	id: &str,
+	field: &str,
+	policy: &MissingAttributePolicy,
+	missing_attribute: &mut Option<String>,
+) -> Result<String> {
+	if read_search_entry(entry, attribute).is_ok() {
+		return read_string_entry(entry, attribute, id);
+	}
+
+	match policy {
+		MissingAttributePolicy::Abort => read_string_entry(entry, attribute, id).context(format!(
+			"User `{id}` lacks the required attribute `{attribute}`; set \
+			 `missing_attributes.{field}` to skip such users or to substitute a default"
+		)),
+		MissingAttributePolicy::Skip => {
+			tracing::warn!("Skipping user `{}`, which lacks the attribute `{}`", id, attribute);
+			missing_attribute.get_or_insert_with(|| field.to_owned());
+			Ok(String::new())
+		}
+		MissingAttributePolicy::Default(value) => Ok(value.clone()),
+	}
+}
+
 /// Read an attribute from the entry
 fn read_search_entry(entry: &SearchEntry, attribute: &AttributeMapping) -> Result<StringOrBytes> {
 	match attribute {
@@ -197,6 +464,48 @@ pub struct LdapSourceConfig {
 	pub use_attribute_filter: bool,
 	/// TLS-related configuration
 	pub tls: Option<LdapTlsConfig>,
+	/// Read only the changes since the last sync from Active
+	/// Directory, using the DirSync control
+	pub dirsync: Option<DirSyncConfig>,
+	/// Read only the users modified since the last sync, according to
+	/// the `last_modified` attribute
+	pub incremental: Option<IncrementalSyncConfig>,
+	/// Watch LDAP for changes with `--watch`, using the Content
+	/// Synchronization Operation
+	pub watch: Option<WatchConfig>,
+	/// The number of entries to request per page, using the paged
+	/// results control. Servers limiting the size of search results,
+	/// such as Active Directory, require paging.
+	pub page_size: Option<i32>,
+	/// What to do with users lacking a required attribute
+	#[serde(default)]
+	pub missing_attributes: MissingAttributePolicies,
+}
+
+/// What to do with users lacking a required attribute, by attribute
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+pub struct MissingAttributePolicies {
+	/// The policy for users without an email address
+	#[serde(default)]
+	pub email: MissingAttributePolicy,
+	/// The policy for users without a preferred username, if
+	/// `attributes.preferred_username` is set
+	#[serde(default)]
+	pub preferred_username: MissingAttributePolicy,
+}
+
+/// What to do with a user lacking a required attribute
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MissingAttributePolicy {
+	/// Abort the sync
+	#[default]
+	Abort,
+	/// Skip the user and report it. Its Zitadel counterpart is
+	/// neither updated nor deleted.
+	Skip,
+	/// Use the given value instead
+	Default(String),
 }
 
 impl From<LdapSourceConfig> for ldap_poller::Config {
@@ -230,21 +539,14 @@ impl From<LdapSourceConfig> for ldap_poller::Config {
 			searches: Searches {
 				user_base: cfg.base_dn,
 				user_filter: cfg.user_filter,
-				page_size: None,
+				page_size: cfg.page_size,
 			},
 			attributes: AttributeConfig {
-				pid: attributes.user_id.get_name(),
-				updated: attributes.last_modified.map(AttributeMapping::get_name),
+				pid: attributes.user_id.clone().get_name(),
+				updated: attributes.last_modified.clone().map(AttributeMapping::get_name),
 				additional: vec![],
 				filter_attributes: cfg.use_attribute_filter,
-				attrs_to_track: vec![
-					attributes.status.get_name(),
-					attributes.first_name.get_name(),
-					attributes.last_name.get_name(),
-					attributes.preferred_username.get_name(),
-					attributes.email.get_name(),
-					attributes.phone.get_name(),
-				],
+				attrs_to_track: tracked_attributes(&attributes),
 			},
 			cache_method: CacheMethod::Disabled,
 			check_for_deleted_entries: cfg.check_for_deleted_entries,
@@ -252,6 +554,26 @@ impl From<LdapSourceConfig> for ldap_poller::Config {
 	}
 }
 
+/// The names of the attributes to track for changes, besides the
+/// user ID
+fn tracked_attributes(attributes: &LdapAttributesMapping) -> Vec<String> {
+	[
+		&attributes.status,
+		&attributes.first_name,
+		&attributes.last_name,
+		&attributes.email,
+		&attributes.phone,
+	]
+	.into_iter()
+	.chain(&attributes.preferred_username)
+	.chain(&attributes.proxy_addresses)
+	.chain(&attributes.common_name)
+	.chain(&attributes.groups)
+	.chain(attributes.metadata.values())
+	.map(|attribute| attribute.clone().get_name())
+	.collect()
+}
+
 /// A mapping from the mostly free-form LDAP attributes to attribute
 /// names as used by famedly
 #[derive(Debug, Clone, Deserialize, PartialEq)]
@@ -260,8 +582,10 @@ pub struct LdapAttributesMapping {
 	pub first_name: AttributeMapping,
 	/// Attribute for the user's last name
 	pub last_name: AttributeMapping,
-	/// Attribute for the user's preferred username
-	pub preferred_username: AttributeMapping,
+	/// Attribute for the user's preferred username. If unset, the
+	/// preferred username isn't synced, and existing values in Zitadel
+	/// are left as they are.
+	pub preferred_username: Option<AttributeMapping>,
 	/// Attribute for the user's email address
 	pub email: AttributeMapping,
 	/// Attribute for the user's phone number
@@ -271,12 +595,51 @@ pub struct LdapAttributesMapping {
 	/// This attribute shows the account status (It expects an i32 like
 	/// userAccountControl in AD)
 	pub status: AttributeMapping,
+	/// How the status attribute is interpreted
+	#[serde(default)]
+	pub status_format: StatusFormat,
 	/// Marks an account as disabled (for example userAccountControl: bit flag
 	/// ACCOUNTDISABLE would be 2)
 	#[serde(default)]
 	pub disable_bitmasks: Vec<i32>,
 	/// Last modified
 	pub last_modified: Option<AttributeMapping>,
+	/// Additional attributes to sync as Zitadel metadata, keyed by
+	/// the metadata key, e.g. `department: "departmentNumber"`.
+	/// Users lacking such an attribute simply don't get the metadata
+	/// entry.
+	#[serde(default)]
+	pub metadata: BTreeMap<String, AttributeMapping>,
+	/// Attribute listing the user's email addresses in the format of
+	/// AD's `proxyAddresses`. If set, the primary SMTP address is used
+	/// as the user's email address, falling back to the email
+	/// attribute for users without one.
+	pub proxy_addresses: Option<AttributeMapping>,
+	/// Attribute for the user's full name, e.g. `cn`, from which the
+	/// `common_name` name fallback derives a missing first or last
+	/// name
+	pub common_name: Option<AttributeMapping>,
+	/// Attribute listing the DNs of the user's groups, e.g.
+	/// `memberOf`, which rules can grant project roles by
+	pub groups: Option<AttributeMapping>,
+}
+
+/// How the status attribute of a user is interpreted
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StatusFormat {
+	/// Bit flags, of which `disable_bitmasks` mark disabled accounts,
+	/// or without `disable_bitmasks`, `TRUE` for enabled accounts
+	#[default]
+	Flags,
+	/// `TRUE` for locked accounts, e.g. FreeIPA's `nsAccountLock`.
+	/// Accounts without the attribute are enabled.
+	Lock,
+	/// The day the account expires, in days since 1970, e.g.
+	/// `shadowExpire`. Accounts whose day has come are disabled, and
+	/// accounts without the attribute are enabled, e.g. in UCS, which
+	/// deactivates accounts by expiring them on day 1.
+	Expiry,
 }
 
 /// How an attribute should be defined in config - it can either be a
@@ -363,7 +726,13 @@ mod tests {
 	use ldap_poller::ldap::EntryStatus;
 	use tokio::sync::mpsc;
 
-	use crate::{sources::ldap::LdapSource, Config};
+	use crate::{
+		sources::ldap::{
+			explain_search_limit, tracked_attributes, AttributeMapping, FreeIpaSourceConfig,
+			LdapSource, UcsSourceConfig, SIZE_LIMIT_EXCEEDED, TIME_LIMIT_EXCEEDED,
+		},
+		Config,
+	};
 
 	const EXAMPLE_CONFIG: &str = indoc! {r#"
         zitadel:
@@ -547,6 +916,328 @@ mod tests {
 		assert!(user.enabled);
 	}
 
+	#[tokio::test]
+	async fn test_parse_user_without_preferred_username() {
+		let mut config = load_config();
+		let ldap_config = config.sources.ldap.as_mut().unwrap();
+		ldap_config.attributes.preferred_username = None;
+		let ldap_source = LdapSource { ldap_config: ldap_config.clone() };
+
+		let mut attrs = new_user();
+		attrs.remove("displayName");
+		let entry = SearchEntry {
+			dn: "uid=testuser,ou=testorg,dc=example,dc=org".to_owned(),
+			attrs,
+			bin_attrs: HashMap::new(),
+		};
+
+		let user = ldap_source.parse_user(entry).expect("failed to parse user");
+		assert_eq!(user.preferred_username, None);
+		assert!(!tracked_attributes(&ldap_source.ldap_config.attributes)
+			.contains(&"displayName".to_owned()));
+	}
+
+	#[tokio::test]
+	async fn test_parse_user_freeipa() {
+		let config: FreeIpaSourceConfig = serde_yaml::from_str(indoc! {r#"
+			url: ldaps://ipa.example.invalid
+			base_dn: cn=users,cn=accounts,dc=example,dc=org
+			bind_dn: uid=famedly-sync,cn=sysaccounts,cn=etc,dc=example,dc=org
+			bind_password: secret
+		"#})
+		.expect("invalid config");
+		let ldap_source = LdapSource { ldap_config: config.into() };
+
+		let entry = |lock: Option<&str>| {
+			let mut attrs = HashMap::from([
+				("uid".to_owned(), vec!["jdoe".to_owned()]),
+				("givenName".to_owned(), vec!["John".to_owned()]),
+				("sn".to_owned(), vec!["Doe".to_owned()]),
+				("cn".to_owned(), vec!["John Doe".to_owned()]),
+				("mail".to_owned(), vec!["jdoe@example.org".to_owned()]),
+				("ipaUniqueID".to_owned(), vec!["0b3e7c2a-7c2e-11ee-9e5c-525400a1b2c3".to_owned()]),
+				(
+					"objectClass".to_owned(),
+					vec![
+						"inetOrgPerson".to_owned(),
+						"posixAccount".to_owned(),
+						"ipaobject".to_owned(),
+					],
+				),
+			]);
+			if let Some(lock) = lock {
+				attrs.insert("nsAccountLock".to_owned(), vec![lock.to_owned()]);
+			}
+			SearchEntry {
+				dn: "uid=jdoe,cn=users,cn=accounts,dc=example,dc=org".to_owned(),
+				attrs,
+				bin_attrs: HashMap::new(),
+			}
+		};
+
+		let user = ldap_source.parse_user(entry(None)).expect("failed to parse user");
+		assert!(user.enabled);
+		assert_eq!(user.first_name, "John");
+		assert_eq!(user.last_name, "Doe");
+		assert_eq!(user.email, "jdoe@example.org");
+		assert_eq!(user.preferred_username, Some("jdoe".to_owned()));
+		assert_eq!(user.phone, None);
+		assert_eq!(
+			user.external_user_id,
+			hex::encode("0b3e7c2a-7c2e-11ee-9e5c-525400a1b2c3".as_bytes())
+		);
+
+		let user = ldap_source.parse_user(entry(Some("FALSE"))).expect("failed to parse user");
+		assert!(user.enabled);
+
+		let user = ldap_source.parse_user(entry(Some("TRUE"))).expect("failed to parse user");
+		assert!(!user.enabled);
+	}
+
+	#[tokio::test]
+	async fn test_parse_user_ucs() {
+		let config: UcsSourceConfig = serde_yaml::from_str(indoc! {r#"
+			url: ldaps://ucs.example.invalid:7636
+			base_dn: cn=users,dc=example,dc=org
+			bind_dn: uid=famedly-sync,cn=users,dc=example,dc=org
+			bind_password: secret
+			attributes:
+			  extended_attributes:
+			    ward: "univentionFreeAttribute1"
+		"#})
+		.expect("invalid config");
+		let ldap_source = LdapSource { ldap_config: config.into() };
+
+		let entry = |shadow_expire: Option<String>| {
+			let mut attrs = HashMap::from([
+				("uid".to_owned(), vec!["jdoe".to_owned()]),
+				("givenName".to_owned(), vec!["John".to_owned()]),
+				("sn".to_owned(), vec!["Doe".to_owned()]),
+				("cn".to_owned(), vec!["John Doe".to_owned()]),
+				("mailPrimaryAddress".to_owned(), vec!["jdoe@example.org".to_owned()]),
+				("entryUUID".to_owned(), vec!["5c0e2b1a-3f7d-103e-8a4b-b5d2c1e0f9a8".to_owned()]),
+				("univentionFreeAttribute1".to_owned(), vec!["Radiology".to_owned()]),
+			]);
+			if let Some(shadow_expire) = shadow_expire {
+				attrs.insert("shadowExpire".to_owned(), vec![shadow_expire]);
+			}
+			SearchEntry {
+				dn: "uid=jdoe,cn=users,dc=example,dc=org".to_owned(),
+				attrs,
+				bin_attrs: HashMap::new(),
+			}
+		};
+
+		let user = ldap_source.parse_user(entry(None)).expect("failed to parse user");
+		assert!(user.enabled);
+		assert_eq!(user.email, "jdoe@example.org");
+		assert_eq!(user.preferred_username, Some("jdoe".to_owned()));
+		assert_eq!(user.metadata.get("ward"), Some(&"Radiology".to_owned()));
+		assert_eq!(
+			user.external_user_id,
+			hex::encode("5c0e2b1a-3f7d-103e-8a4b-b5d2c1e0f9a8".as_bytes())
+		);
+
+		// Deactivated accounts expire on the first day of 1970
+		let user =
+			ldap_source.parse_user(entry(Some("1".to_owned()))).expect("failed to parse user");
+		assert!(!user.enabled);
+
+		let today = chrono::Utc::now().timestamp().div_euclid(super::SECONDS_PER_DAY);
+		let user =
+			ldap_source.parse_user(entry(Some(today.to_string()))).expect("failed to parse user");
+		assert!(!user.enabled);
+
+		let user = ldap_source
+			.parse_user(entry(Some((today + 30).to_string())))
+			.expect("failed to parse user");
+		assert!(user.enabled);
+	}
+
+	#[tokio::test]
+	async fn test_parse_user_common_name() {
+		let mut config = load_config();
+		config.sources.ldap.as_mut().unwrap().attributes.common_name =
+			Some(AttributeMapping::NoBinaryOption("displayName".to_owned()));
+		let ldap_source = LdapSource { ldap_config: config.sources.ldap.unwrap() };
+
+		let entry = |display_name: &str| {
+			let mut user = new_user();
+			user.remove("cn");
+			user.insert("displayName".to_owned(), vec![display_name.to_owned()]);
+			SearchEntry {
+				dn: "uid=testuser,ou=testorg,dc=example,dc=org".to_owned(),
+				attrs: user,
+				bin_attrs: HashMap::new(),
+			}
+		};
+
+		// The common name is only read, and split by the `common_name`
+		// name fallback
+		let user = ldap_source.parse_user(entry("Maria da Silva")).expect("failed to parse user");
+		assert_eq!(user.first_name, "");
+		assert_eq!(user.last_name, "User");
+		assert_eq!(user.common_name.as_deref(), Some("Maria da Silva"));
+
+		let user = ldap_source.parse_user(entry("")).expect("failed to parse user");
+		assert_eq!(user.common_name, None);
+	}
+
+	#[tokio::test]
+	async fn test_parse_user_metadata() {
+		let mut config = load_config();
+		config.sources.ldap.as_mut().unwrap().attributes.metadata =
+			serde_yaml::from_str("{ title: title, department: departmentNumber }")
+				.expect("invalid config fragment");
+		let ldap_source = LdapSource { ldap_config: config.sources.ldap.unwrap() };
+
+		let entry = SearchEntry {
+			dn: "uid=testuser,ou=testorg,dc=example,dc=org".to_owned(),
+			attrs: {
+				let mut user = new_user();
+				user.insert("departmentNumber".to_owned(), vec!["IT".to_owned()]);
+				user
+			},
+			bin_attrs: HashMap::new(),
+		};
+
+		let user = ldap_source.parse_user(entry).expect("failed to parse user");
+		assert_eq!(user.metadata.get("department"), Some(&"IT".to_owned()));
+		assert_eq!(user.metadata.get("title"), None);
+	}
+
+	#[tokio::test]
+	async fn test_parse_user_groups() {
+		let mut config = load_config();
+		let ldap_source = LdapSource { ldap_config: config.sources.ldap.clone().unwrap() };
+		let mut entry = SearchEntry {
+			dn: "uid=testuser,ou=testorg,dc=example,dc=org".to_owned(),
+			attrs: new_user(),
+			bin_attrs: HashMap::new(),
+		};
+		entry.attrs.insert(
+			"memberOf".to_owned(),
+			vec![
+				"cn=admins,ou=groups,dc=example,dc=org".to_owned(),
+				"cn=staff,ou=groups,dc=example,dc=org".to_owned(),
+			],
+		);
+
+		// Groups are only read if configured
+		let user = ldap_source.parse_user(entry.clone()).expect("failed to parse user");
+		assert!(user.groups.is_empty());
+
+		config.sources.ldap.as_mut().unwrap().attributes.groups =
+			Some(AttributeMapping::NoBinaryOption("memberOf".to_owned()));
+		let ldap_source = LdapSource { ldap_config: config.sources.ldap.unwrap() };
+		let user = ldap_source.parse_user(entry).expect("failed to parse user");
+		assert_eq!(
+			user.groups,
+			BTreeSet::from([
+				"cn=admins,ou=groups,dc=example,dc=org".to_owned(),
+				"cn=staff,ou=groups,dc=example,dc=org".to_owned(),
+			])
+		);
+	}
+
+	#[tokio::test]
+	async fn test_parse_user_missing_attributes() {
+		let mut config = load_config();
+		let mut entry = SearchEntry {
+			dn: "uid=testuser,ou=testorg,dc=example,dc=org".to_owned(),
+			attrs: new_user(),
+			bin_attrs: HashMap::new(),
+		};
+		entry.attrs.remove("mail");
+
+		let ldap_source = LdapSource { ldap_config: config.sources.ldap.clone().unwrap() };
+		let error = ldap_source.parse_user(entry.clone()).expect_err("parsed user without email");
+		assert!(error.to_string().contains("missing_attributes.email"));
+
+		let ldap_config = config.sources.ldap.as_mut().unwrap();
+		ldap_config.missing_attributes.email = MissingAttributePolicy::Skip;
+		let ldap_source = LdapSource { ldap_config: ldap_config.clone() };
+		let user = ldap_source.parse_user(entry.clone()).expect("failed to parse user");
+		assert_eq!(user.unreadable, Some(Unreadable::MissingAttribute("email".to_owned())));
+
+		ldap_config.missing_attributes.email =
+			MissingAttributePolicy::Default("noreply@example.com".to_owned());
+		let ldap_source = LdapSource { ldap_config: ldap_config.clone() };
+		let user = ldap_source.parse_user(entry).expect("failed to parse user");
+		assert_eq!(user.email, "noreply@example.com");
+		assert!(user.unreadable.is_none());
+	}
+
+	#[tokio::test]
+	async fn test_parse_user_proxy_addresses() {
+		let mut config = load_config();
+		config.sources.ldap.as_mut().unwrap().attributes.proxy_addresses =
+			Some(AttributeMapping::NoBinaryOption("proxyAddresses".to_owned()));
+		let ldap_source = LdapSource { ldap_config: config.sources.ldap.unwrap() };
+
+		let entry = SearchEntry {
+			dn: "uid=testuser,ou=testorg,dc=example,dc=org".to_owned(),
+			attrs: {
+				let mut user = new_user();
+				user.remove("mail");
+				user.insert(
+					"proxyAddresses".to_owned(),
+					vec![
+						"smtp:alias@example.com".to_owned(),
+						"SMTP:primary@example.com".to_owned(),
+						"X500:/o=ExchangeLabs".to_owned(),
+					],
+				);
+				user
+			},
+			bin_attrs: HashMap::new(),
+		};
+
+		let user = ldap_source.parse_user(entry.clone()).expect("failed to parse user");
+		assert_eq!(user.email, "primary@example.com");
+
+		// The primary address takes precedence over `mail`
+		let mut with_mail = entry.clone();
+		with_mail.attrs.insert("mail".to_owned(), vec!["mail@example.com".to_owned()]);
+		let user = ldap_source.parse_user(with_mail).expect("failed to parse user");
+		assert_eq!(user.email, "primary@example.com");
+
+		// Without a primary address, `mail` is used
+		let mut without_primary = entry;
+		without_primary.attrs.insert(
+			"proxyAddresses".to_owned(),
+			vec!["smtp:alias@example.com".to_owned(), "X500:/o=ExchangeLabs".to_owned()],
+		);
+		without_primary.attrs.insert("mail".to_owned(), vec!["mail@example.com".to_owned()]);
+		let user = ldap_source.parse_user(without_primary).expect("failed to parse user");
+		assert_eq!(user.email, "mail@example.com");
+	}
+
+	#[test]
+	fn test_explain_search_limit() {
+		let limit_error = |rc| {
+			anyhow::Error::new(ldap3::LdapError::LdapResult {
+				result: ldap3::LdapResult {
+					rc,
+					matched: String::new(),
+					text: String::new(),
+					refs: Vec::new(),
+					ctrls: Vec::new(),
+				},
+			})
+			.context("failed to sync/fetch data from LDAP")
+		};
+
+		let error = explain_search_limit(limit_error(SIZE_LIMIT_EXCEEDED));
+		assert!(error.to_string().contains("`page_size`"));
+
+		let error = explain_search_limit(limit_error(TIME_LIMIT_EXCEEDED));
+		assert!(error.to_string().contains("time limit"));
+
+		let error = explain_search_limit(limit_error(32));
+		assert_eq!(error.to_string(), "failed to sync/fetch data from LDAP");
+	}
+
 	#[tokio::test]
 	async fn test_text_enabled() {
 		let mut config = load_config();
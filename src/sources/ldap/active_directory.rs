@@ -0,0 +1,211 @@
+//! Active Directory presets for the LDAP source
+//!
+//! Active Directory uses the same schema everywhere, so the attribute
+//! mapping and connection settings of the LDAP source can be derived
+//! from a handful of settings. Any attribute can still be overridden.
+//! Referrals returned by AD, e.g. for other domains of the forest, are
+//! never followed.
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+use url::Url;
+
+use super::{
+	AttributeMapping, DirSyncConfig, LdapAttributesMapping, LdapSourceConfig, LdapTlsConfig,
+	MissingAttributePolicies, StatusFormat,
+};
+
+/// The default filter selecting user accounts, excluding computer
+/// accounts, which are users in AD as well
+const DEFAULT_USER_FILTER: &str = "(&(objectCategory=person)(objectClass=user))";
+
+/// The default number of entries per page. AD returns at most 1000
+/// entries per search unless the results are paged.
+const DEFAULT_PAGE_SIZE: i32 = 500;
+
+/// The default timeout for LDAP operations in seconds
+const DEFAULT_TIMEOUT: u64 = 30;
+
+/// The `ACCOUNTDISABLE` flag of `userAccountControl`
+const ACCOUNTDISABLE: i32 = 0x2;
+
+/// Active Directory configuration, a specialization of the LDAP
+/// source with presets for AD
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct ActiveDirectorySourceConfig {
+	/// The URL of the domain controller
+	pub url: Url,
+	/// The base DN for searching users
+	pub base_dn: String,
+	/// The DN to bind for authentication
+	pub bind_dn: String,
+	/// The password for the bind DN
+	pub bind_password: String,
+	/// Filter to apply when searching for users, all user accounts by
+	/// default. Don't filter out disabled accounts, since they
+	/// wouldn't be deleted from Zitadel.
+	pub user_filter: Option<String>,
+	/// Timeout for LDAP operations in seconds
+	#[serde(default = "default_timeout")]
+	pub timeout: u64,
+	/// The number of entries to request per page
+	#[serde(default = "default_page_size")]
+	pub page_size: i32,
+	/// Overrides of the preset attribute mapping
+	#[serde(default)]
+	pub attributes: ActiveDirectoryAttributes,
+	/// Whether to sync the preferred username from
+	/// `attributes.preferred_username`. If disabled, existing values in
+	/// Zitadel are left as they are.
+	#[serde(default = "default_sync_preferred_username")]
+	pub sync_preferred_username: bool,
+	/// TLS-related configuration
+	pub tls: Option<LdapTlsConfig>,
+	/// What to do with users lacking a required attribute
+	#[serde(default)]
+	pub missing_attributes: MissingAttributePolicies,
+	/// Read only the changes since the last sync, using the DirSync
+	/// control
+	pub dirsync: Option<DirSyncConfig>,
+}
+
+/// Overrides of the attributes preset for Active Directory
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+pub struct ActiveDirectoryAttributes {
+	/// Attribute for the user's first name, `givenName` by default
+	pub first_name: Option<AttributeMapping>,
+	/// Attribute for the user's last name, `sn` by default
+	pub last_name: Option<AttributeMapping>,
+	/// Attribute for the user's preferred username, `sAMAccountName`
+	/// by default
+	pub preferred_username: Option<AttributeMapping>,
+	/// Attribute for the user's email address if the proxy addresses
+	/// contain no primary SMTP address, `mail` by default
+	pub email: Option<AttributeMapping>,
+	/// Attribute listing the user's proxy addresses, whose primary
+	/// SMTP address is used as the user's email address,
+	/// `proxyAddresses` by default
+	pub proxy_addresses: Option<AttributeMapping>,
+	/// Attribute for the user's phone number, `telephoneNumber` by
+	/// default
+	pub phone: Option<AttributeMapping>,
+	/// Attribute for the user's full name, from which the
+	/// `common_name` name fallback derives a missing first or last
+	/// name, `cn` by default
+	pub common_name: Option<AttributeMapping>,
+	/// Attribute for the user's unique ID, the binary `objectGUID` by
+	/// default
+	pub user_id: Option<AttributeMapping>,
+	/// Additional attributes to sync as Zitadel metadata, keyed by
+	/// the metadata key
+	#[serde(default)]
+	pub metadata: BTreeMap<String, AttributeMapping>,
+	/// Attribute listing the DNs of the user's groups, e.g.
+	/// `memberOf`, which rules can grant project roles by. Groups
+	/// aren't read by default.
+	pub groups: Option<AttributeMapping>,
+}
+
+/// Default for [`ActiveDirectorySourceConfig::timeout`]
+fn default_timeout() -> u64 {
+	DEFAULT_TIMEOUT
+}
+
+/// Default for [`ActiveDirectorySourceConfig::page_size`]
+fn default_page_size() -> i32 {
+	DEFAULT_PAGE_SIZE
+}
+
+/// Default for [`ActiveDirectorySourceConfig::sync_preferred_username`]
+fn default_sync_preferred_username() -> bool {
+	true
+}
+
+/// An attribute which isn't binary
+fn text(name: &str) -> AttributeMapping {
+	AttributeMapping::NoBinaryOption(name.to_owned())
+}
+
+impl From<ActiveDirectorySourceConfig> for LdapSourceConfig {
+	fn from(cfg: ActiveDirectorySourceConfig) -> LdapSourceConfig {
+		let attributes = cfg.attributes;
+
+		LdapSourceConfig {
+			url: cfg.url,
+			base_dn: cfg.base_dn,
+			bind_dn: cfg.bind_dn,
+			bind_password: cfg.bind_password,
+			user_filter: cfg.user_filter.unwrap_or_else(|| DEFAULT_USER_FILTER.to_owned()),
+			timeout: cfg.timeout,
+			attributes: LdapAttributesMapping {
+				first_name: attributes.first_name.unwrap_or_else(|| text("givenName")),
+				last_name: attributes.last_name.unwrap_or_else(|| text("sn")),
+				preferred_username: cfg.sync_preferred_username.then(|| {
+					attributes.preferred_username.unwrap_or_else(|| text("sAMAccountName"))
+				}),
+				email: attributes.email.unwrap_or_else(|| text("mail")),
+				phone: attributes.phone.unwrap_or_else(|| text("telephoneNumber")),
+				user_id: attributes.user_id.unwrap_or_else(|| AttributeMapping::OptionalBinary {
+					name: "objectGUID".to_owned(),
+					is_binary: true,
+				}),
+				status: text("userAccountControl"),
+				status_format: StatusFormat::Flags,
+				disable_bitmasks: vec![ACCOUNTDISABLE],
+				last_modified: None,
+				metadata: attributes.metadata,
+				proxy_addresses: Some(
+					attributes.proxy_addresses.unwrap_or_else(|| text("proxyAddresses")),
+				),
+				common_name: Some(attributes.common_name.unwrap_or_else(|| text("cn"))),
+				groups: attributes.groups,
+			},
+			check_for_deleted_entries: true,
+			// Without a filter, AD sends every attribute of the user,
+			// including large ones such as certificates and photos
+			use_attribute_filter: true,
+			tls: cfg.tls,
+			dirsync: cfg.dirsync,
+			incremental: None,
+			watch: None,
+			page_size: Some(cfg.page_size),
+			missing_attributes: cfg.missing_attributes,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use indoc::indoc;
+
+	use super::*;
+
+	#[test]
+	fn test_presets() {
+		let config: ActiveDirectorySourceConfig = serde_yaml::from_str(indoc! {r#"
+			url: ldaps://dc.example.invalid
+			base_dn: ou=staff,dc=example,dc=org
+			bind_dn: cn=sync,dc=example,dc=org
+			bind_password: secret
+			attributes:
+			  preferred_username: "userPrincipalName"
+			  metadata:
+			    department: "department"
+		"#})
+		.expect("invalid config");
+
+		let ldap: LdapSourceConfig = config.into();
+
+		assert_eq!(ldap.user_filter, DEFAULT_USER_FILTER);
+		assert_eq!(ldap.page_size, Some(DEFAULT_PAGE_SIZE));
+		assert_eq!(
+			ldap.attributes.user_id,
+			AttributeMapping::OptionalBinary { name: "objectGUID".to_owned(), is_binary: true }
+		);
+		assert_eq!(ldap.attributes.status, text("userAccountControl"));
+		assert_eq!(ldap.attributes.disable_bitmasks, vec![ACCOUNTDISABLE]);
+		assert_eq!(ldap.attributes.email, text("mail"));
+		assert_eq!(ldap.attributes.preferred_username, Some(text("userPrincipalName")));
+		assert_eq!(ldap.attributes.metadata.get("department"), Some(&text("department")));
+	}
+}
@@ -0,0 +1,448 @@
+//! Incremental sync from Active Directory using the DirSync control
+//!
+//! DirSync returns the objects changed since the state described by
+//! an opaque cookie, along with a cookie for the new state. Since it
+//! only returns the changed attributes, changed users are re-read in
+//! full, and deleted users are identified by reading the retained
+//! attributes of their tombstones.
+use std::{mem::size_of, time::Duration};
+
+use anyhow::{bail, Context, Result};
+use ldap3::{controls::RawControl, Ldap, LdapConnAsync, LdapConnSettings, Scope, SearchEntry};
+use serde::Deserialize;
+
+use super::{
+	explain_search_limit, tracked_attributes, LdapSource, LdapSourceConfig, LdapTlsConfig,
+};
+use crate::user::User;
+
+/// OID of the DirSync control
+const DIRSYNC_OID: &str = "1.2.840.113556.1.4.841";
+/// OID of the control including deleted objects in search results
+const SHOW_DELETED_OID: &str = "1.2.840.113556.1.4.417";
+/// The maximum size of a single DirSync response, in bytes
+const DIRSYNC_MAX_BYTES: i64 = 1_048_576;
+
+/// Configuration of incremental syncs using the DirSync control
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct DirSyncConfig {
+	/// The root of the naming context containing the base DN, which
+	/// DirSync requires as search base. Derived from the `DC`
+	/// components of the base DN if unset.
+	pub naming_context: Option<String>,
+	/// The filter for objects whose changes to read. This can't be the
+	/// user filter, since deleted objects lose most of their
+	/// attributes.
+	#[serde(default = "default_filter")]
+	pub filter: String,
+}
+
+/// Default for [`DirSyncConfig::filter`]
+fn default_filter() -> String {
+	"(objectClass=user)".to_owned()
+}
+
+/// Users changed since an earlier sync
+#[derive(Debug, Default)]
+pub struct DirSyncChanges {
+	/// Users which were created or changed
+	pub changed: Vec<User>,
+	/// External IDs of users which were deleted or moved out of the
+	/// base DN
+	pub removed: Vec<String>,
+}
+
+impl LdapSource {
+	/// Get the users changed since the state described by the given
+	/// cookie, along with the cookie for the current state
+	pub async fn get_dirsync_changes(&self, cookie: Vec<u8>) -> Result<(DirSyncChanges, Vec<u8>)> {
+		let mut ldap = connect(&self.ldap_config).await?;
+		let (entries, cookie) = self.dirsync(&mut ldap, cookie).await?;
+		let mut changes = DirSyncChanges::default();
+
+		for entry in entries {
+			let Some(guid) = read_object_guid(&entry) else {
+				tracing::warn!("Ignoring DirSync entry `{}` without object GUID", entry.dn);
+				continue;
+			};
+			let guid_dn = format!("<GUID={guid}>");
+
+			if entry.attrs.get("isDeleted").and_then(|values| values.first()).map(String::as_str)
+				== Some("TRUE")
+			{
+				match self.read_user_id(&mut ldap, &guid_dn, true).await? {
+					Some(user_id) => changes.removed.push(user_id),
+					None => tracing::warn!(
+						"Cannot identify deleted user `{}`, the user ID attribute is not retained",
+						entry.dn
+					),
+				}
+				continue;
+			}
+
+			match self.read_user(&mut ldap, &guid_dn).await? {
+				Some(user_entry) if is_in_base_dn(&user_entry.dn, &self.ldap_config.base_dn) => {
+					tracing::debug!("Changed entry: {:?}", user_entry);
+					changes.changed.push(self.parse_user(user_entry)?);
+				}
+				// The object no longer matches the user filter or was
+				// moved out of the base DN, so it may have been a user
+				// before
+				_ => {
+					if let Some(user_id) = self.read_user_id(&mut ldap, &guid_dn, false).await? {
+						changes.removed.push(user_id);
+					}
+				}
+			}
+		}
+
+		ldap.unbind().await.context("Failed to unbind from LDAP")?;
+
+		tracing::info!(
+			"Read {} changed and {} removed users from DirSync",
+			changes.changed.len(),
+			changes.removed.len()
+		);
+
+		Ok((changes, cookie))
+	}
+
+	/// Get a cookie for the current state, without reading any
+	/// changes
+	pub async fn get_dirsync_cookie(&self) -> Result<Vec<u8>> {
+		let mut ldap = connect(&self.ldap_config).await?;
+		let (_, cookie) = self.dirsync(&mut ldap, Vec::new()).await?;
+		ldap.unbind().await.context("Failed to unbind from LDAP")?;
+
+		Ok(cookie)
+	}
+
+	/// Read all pages of DirSync results, starting at the given cookie
+	async fn dirsync(
+		&self,
+		ldap: &mut Ldap,
+		mut cookie: Vec<u8>,
+	) -> Result<(Vec<SearchEntry>, Vec<u8>)> {
+		let dirsync_config =
+			self.ldap_config.dirsync.as_ref().context("DirSync is not configured")?;
+		let naming_context = dirsync_config
+			.naming_context
+			.clone()
+			.unwrap_or_else(|| naming_context(&self.ldap_config.base_dn));
+		let attributes: Vec<String> = self
+			.user_attributes()
+			.into_iter()
+			.chain(["objectGUID".to_owned(), "isDeleted".to_owned()])
+			.collect();
+
+		let mut entries = Vec::new();
+		loop {
+			let (results, result) = ldap
+				.with_controls(RawControl {
+					ctype: DIRSYNC_OID.to_owned(),
+					crit: true,
+					val: Some(encode_dirsync_control(0, DIRSYNC_MAX_BYTES, &cookie)),
+				})
+				.search(&naming_context, Scope::Subtree, &dirsync_config.filter, attributes.clone())
+				.await
+				.context("Failed to read changes from LDAP")?
+				.success()
+				.context("Failed to read changes from LDAP")
+				.map_err(explain_search_limit)?;
+			entries.extend(results.into_iter().map(SearchEntry::construct));
+
+			let control = result
+				.ctrls
+				.iter()
+				.find(|control| control.1.ctype == DIRSYNC_OID)
+				.context("LDAP server didn't return a DirSync cookie")?;
+			let (more_results, new_cookie) =
+				decode_dirsync_control(control.1.val.as_deref().unwrap_or_default())?;
+			cookie = new_cookie;
+
+			if !more_results {
+				break;
+			}
+		}
+
+		Ok((entries, cookie))
+	}
+
+	/// Read the user with the given DN, if it matches the user filter
+	async fn read_user(&self, ldap: &mut Ldap, dn: &str) -> Result<Option<SearchEntry>> {
+		let attributes = if self.ldap_config.use_attribute_filter {
+			self.user_attributes()
+		} else {
+			vec!["*".to_owned()]
+		};
+
+		let (mut results, _) = ldap
+			.search(dn, Scope::Base, &self.ldap_config.user_filter, attributes)
+			.await
+			.context(format!("Failed to read LDAP entry `{dn}`"))?
+			.success()
+			.context(format!("Failed to read LDAP entry `{dn}`"))?;
+
+		Ok(results.pop().map(SearchEntry::construct))
+	}
+
+	/// The attributes read for each user
+	pub(super) fn user_attributes(&self) -> Vec<String> {
+		std::iter::once(self.ldap_config.attributes.user_id.clone().get_name())
+			.chain(tracked_attributes(&self.ldap_config.attributes))
+			.collect()
+	}
+
+	/// Read the external user ID of the object with the given DN, if
+	/// it has one
+	async fn read_user_id(
+		&self,
+		ldap: &mut Ldap,
+		dn: &str,
+		deleted: bool,
+	) -> Result<Option<String>> {
+		let controls = if deleted {
+			vec![RawControl { ctype: SHOW_DELETED_OID.to_owned(), crit: true, val: None }]
+		} else {
+			Vec::new()
+		};
+		let user_id_attribute = self.ldap_config.attributes.user_id.clone().get_name();
+
+		let (mut results, _) = ldap
+			.with_controls(controls)
+			.search(dn, Scope::Base, "(objectClass=*)", vec![user_id_attribute])
+			.await
+			.context(format!("Failed to read LDAP entry `{dn}`"))?
+			.success()
+			.context(format!("Failed to read LDAP entry `{dn}`"))?;
+
+		Ok(results.pop().and_then(|entry| self.parse_user_id(&SearchEntry::construct(entry)).ok()))
+	}
+}
+
+/// Connect and bind to the LDAP server
+pub(super) async fn connect(config: &LdapSourceConfig) -> Result<Ldap> {
+	let mut settings =
+		LdapConnSettings::new().set_conn_timeout(Duration::from_secs(config.timeout));
+	if let Some(tls) = &config.tls {
+		settings = settings
+			.set_starttls(tls.danger_use_start_tls)
+			.set_no_tls_verify(tls.danger_disable_tls_verify)
+			.set_connector(tls_connector(tls)?);
+	}
+
+	let (connection, mut ldap) = LdapConnAsync::from_url_with_settings(settings, &config.url)
+		.await
+		.context("Failed to connect to LDAP")?;
+	ldap3::drive!(connection);
+
+	ldap.with_timeout(Duration::from_secs(config.timeout))
+		.simple_bind(&config.bind_dn, &config.bind_password)
+		.await
+		.context("Failed to bind to LDAP")?
+		.success()
+		.context("Failed to bind to LDAP")?;
+
+	Ok(ldap)
+}
+
+/// Build a TLS connector from the LDAP TLS configuration
+fn tls_connector(tls: &LdapTlsConfig) -> Result<native_tls::TlsConnector> {
+	let mut builder = native_tls::TlsConnector::builder();
+
+	if let Some(path) = &tls.server_certificate {
+		let certificate = std::fs::read(path)
+			.context(format!("Failed to read server certificate {}", path.display()))?;
+		builder.add_root_certificate(native_tls::Certificate::from_pem(&certificate)?);
+	}
+
+	if let (Some(key_path), Some(certificate_path)) = (&tls.client_key, &tls.client_certificate) {
+		let key = std::fs::read(key_path)
+			.context(format!("Failed to read client key {}", key_path.display()))?;
+		let certificate = std::fs::read(certificate_path)
+			.context(format!("Failed to read client certificate {}", certificate_path.display()))?;
+		builder.identity(native_tls::Identity::from_pkcs8(&certificate, &key)?);
+	}
+
+	builder.danger_accept_invalid_certs(tls.danger_disable_tls_verify);
+
+	Ok(builder.build()?)
+}
+
+/// Read the object GUID of an entry as hex, as used in `<GUID=...>`
+/// DNs
+fn read_object_guid(entry: &SearchEntry) -> Option<String> {
+	entry
+		.bin_attrs
+		.get("objectGUID")
+		.and_then(|values| values.first().cloned())
+		// GUIDs which happen to be valid UTF-8 are returned as strings
+		.or_else(|| {
+			entry
+				.attrs
+				.get("objectGUID")
+				.and_then(|values| values.first())
+				.map(|value| value.as_bytes().to_vec())
+		})
+		.map(hex::encode)
+}
+
+/// Derive the root of the naming context from the `DC` components of
+/// a DN
+fn naming_context(dn: &str) -> String {
+	dn.split(',')
+		.map(str::trim)
+		.filter(|component| component.to_ascii_lowercase().starts_with("dc="))
+		.collect::<Vec<_>>()
+		.join(",")
+}
+
+/// Whether a DN is within the base DN
+fn is_in_base_dn(dn: &str, base_dn: &str) -> bool {
+	/// Normalize a DN for comparison
+	fn normalize(dn: &str) -> String {
+		dn.split(',')
+			.map(|component| component.trim().to_ascii_lowercase())
+			.collect::<Vec<_>>()
+			.join(",")
+	}
+
+	let dn = normalize(dn);
+	let base_dn = normalize(base_dn);
+	dn == base_dn || dn.ends_with(&format!(",{base_dn}"))
+}
+
+/// BER-encode the value of a DirSync control
+///
+/// Requests and responses share the structure `SEQUENCE { flags or
+/// moreResults INTEGER, maxBytes INTEGER, cookie OCTET STRING }`.
+fn encode_dirsync_control(flags: i64, max_bytes: i64, cookie: &[u8]) -> Vec<u8> {
+	let mut contents = encode_integer(flags);
+	contents.extend(encode_integer(max_bytes));
+	contents.extend(encode_tlv(0x04, cookie));
+	encode_tlv(0x30, &contents)
+}
+
+/// Decode the value of a DirSync response control into whether more
+/// results are available and the cookie
+fn decode_dirsync_control(value: &[u8]) -> Result<(bool, Vec<u8>)> {
+	let (tag, contents, _) = read_tlv(value)?;
+	if tag != 0x30 {
+		bail!("Invalid DirSync control: expected a sequence, found tag {tag:#x}");
+	}
+
+	let (tag, more_results, rest) = read_tlv(contents)?;
+	// Some servers encode the flag as a boolean
+	if tag != 0x02 && tag != 0x01 {
+		bail!("Invalid DirSync control: expected an integer, found tag {tag:#x}");
+	}
+	let (_, _, rest) = read_tlv(rest)?;
+	let (tag, cookie, _) = read_tlv(rest)?;
+	if tag != 0x04 {
+		bail!("Invalid DirSync control: expected an octet string, found tag {tag:#x}");
+	}
+
+	Ok((more_results.iter().any(|byte| *byte != 0), cookie.to_vec()))
+}
+
+/// BER-encode an integer in its minimal two's complement form
+fn encode_integer(value: i64) -> Vec<u8> {
+	let bytes = value.to_be_bytes();
+	let mut start = 0;
+	while start < bytes.len() - 1 {
+		let redundant = (bytes[start] == 0x00 && bytes[start + 1] & 0x80 == 0)
+			|| (bytes[start] == 0xff && bytes[start + 1] & 0x80 != 0);
+		if !redundant {
+			break;
+		}
+		start += 1;
+	}
+
+	encode_tlv(0x02, &bytes[start..])
+}
+
+/// BER-encode a value with the given tag
+pub(super) fn encode_tlv(tag: u8, contents: &[u8]) -> Vec<u8> {
+	let mut encoded = vec![tag];
+
+	if contents.len() < 0x80 {
+		encoded.push(contents.len() as u8);
+	} else {
+		let length = contents.len().to_be_bytes();
+		let skip = length.iter().take_while(|byte| **byte == 0).count();
+		encoded.push(0x80 | (length.len() - skip) as u8);
+		encoded.extend(&length[skip..]);
+	}
+
+	encoded.extend(contents);
+	encoded
+}
+
+/// Read a BER value, returning its tag, its contents and the
+/// remaining input
+pub(super) fn read_tlv(input: &[u8]) -> Result<(u8, &[u8], &[u8])> {
+	let [tag, length, rest @ ..] = input else {
+		bail!("Truncated BER value");
+	};
+
+	let (length, rest) = if length & 0x80 == 0 {
+		(usize::from(*length), rest)
+	} else {
+		let length_bytes = usize::from(length & 0x7f);
+		if length_bytes > size_of::<usize>() || rest.len() < length_bytes {
+			bail!("Invalid BER length");
+		}
+		let (length, rest) = rest.split_at(length_bytes);
+		(length.iter().fold(0, |length, byte| (length << 8) | usize::from(*byte)), rest)
+	};
+
+	if rest.len() < length {
+		bail!("Truncated BER value");
+	}
+	let (contents, rest) = rest.split_at(length);
+
+	Ok((*tag, contents, rest))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_dirsync_control() {
+		let encoded = encode_dirsync_control(0, DIRSYNC_MAX_BYTES, &[]);
+		assert_eq!(
+			encoded,
+			vec![0x30, 0x0a, 0x02, 0x01, 0x00, 0x02, 0x03, 0x10, 0x00, 0x00, 0x04, 0x00]
+		);
+		assert_eq!(decode_dirsync_control(&encoded).expect("invalid control"), (false, vec![]));
+
+		let cookie: Vec<u8> = (0..=255).collect();
+		let encoded = encode_dirsync_control(1, 0, &cookie);
+		assert_eq!(decode_dirsync_control(&encoded).expect("invalid control"), (true, cookie));
+
+		assert!(decode_dirsync_control(&encoded[..encoded.len() - 1]).is_err());
+	}
+
+	#[test]
+	fn test_encode_integer() {
+		assert_eq!(encode_integer(0), vec![0x02, 0x01, 0x00]);
+		assert_eq!(encode_integer(128), vec![0x02, 0x02, 0x00, 0x80]);
+		assert_eq!(encode_integer(-1), vec![0x02, 0x01, 0xff]);
+		assert_eq!(encode_integer(-129), vec![0x02, 0x02, 0xff, 0x7f]);
+	}
+
+	#[test]
+	fn test_naming_context() {
+		assert_eq!(naming_context("OU=Staff, DC=example,DC=org"), "DC=example,DC=org");
+		assert!(is_in_base_dn(
+			"CN=Alice,OU=Staff, DC=example,DC=org",
+			"ou=staff,dc=example,dc=org"
+		));
+		assert!(!is_in_base_dn("CN=Bob,OU=Guests,DC=example,DC=org", "ou=staff,dc=example,dc=org"));
+		assert!(!is_in_base_dn(
+			"CN=Bob,OU=NotStaff,DC=example,DC=org",
+			"ou=staff,dc=example,dc=org"
+		));
+	}
+}
@@ -0,0 +1,195 @@
+//! FreeIPA presets for the LDAP source
+//!
+//! FreeIPA uses the same schema everywhere, so the attribute mapping
+//! and connection settings of the LDAP source can be derived from a
+//! handful of settings. Users are identified by their `ipaUniqueID`,
+//! which, unlike the `uid`, never changes, and disabled if their
+//! `nsAccountLock` is set. Any attribute can still be overridden.
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+use url::Url;
+
+use super::{
+	AttributeMapping, LdapAttributesMapping, LdapSourceConfig, LdapTlsConfig,
+	MissingAttributePolicies, StatusFormat, WatchConfig,
+};
+
+/// The default filter selecting user accounts. Staged and preserved
+/// users live outside of the active users container.
+const DEFAULT_USER_FILTER: &str = "(objectClass=inetOrgPerson)";
+
+/// The default number of entries per page, below the default size
+/// limit of FreeIPA's directory server
+const DEFAULT_PAGE_SIZE: i32 = 500;
+
+/// The default timeout for LDAP operations in seconds
+const DEFAULT_TIMEOUT: u64 = 30;
+
+/// FreeIPA configuration, a specialization of the LDAP source with
+/// presets for FreeIPA
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct FreeIpaSourceConfig {
+	/// The URL of the FreeIPA server
+	pub url: Url,
+	/// The base DN for searching users, usually the active users
+	/// container `cn=users,cn=accounts,<domain DN>`
+	pub base_dn: String,
+	/// The DN to bind for authentication, usually a system account
+	/// in `cn=sysaccounts,cn=etc,<domain DN>`
+	pub bind_dn: String,
+	/// The password for the bind DN
+	pub bind_password: String,
+	/// Filter to apply when searching for users, all user accounts by
+	/// default. Don't filter out locked accounts, since they wouldn't
+	/// be deleted from Zitadel.
+	pub user_filter: Option<String>,
+	/// Timeout for LDAP operations in seconds
+	#[serde(default = "default_timeout")]
+	pub timeout: u64,
+	/// The number of entries to request per page
+	#[serde(default = "default_page_size")]
+	pub page_size: i32,
+	/// Overrides of the preset attribute mapping
+	#[serde(default)]
+	pub attributes: FreeIpaAttributes,
+	/// Whether to sync the preferred username from
+	/// `attributes.preferred_username`. If disabled, existing values in
+	/// Zitadel are left as they are.
+	#[serde(default = "default_sync_preferred_username")]
+	pub sync_preferred_username: bool,
+	/// TLS-related configuration
+	pub tls: Option<LdapTlsConfig>,
+	/// What to do with users lacking a required attribute
+	#[serde(default)]
+	pub missing_attributes: MissingAttributePolicies,
+	/// Watch the directory for changes with `--watch`
+	pub watch: Option<WatchConfig>,
+}
+
+/// Overrides of the attributes preset for FreeIPA
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+pub struct FreeIpaAttributes {
+	/// Attribute for the user's first name, `givenName` by default
+	pub first_name: Option<AttributeMapping>,
+	/// Attribute for the user's last name, `sn` by default
+	pub last_name: Option<AttributeMapping>,
+	/// Attribute for the user's preferred username, `uid` by default
+	pub preferred_username: Option<AttributeMapping>,
+	/// Attribute for the user's email address, `mail` by default
+	pub email: Option<AttributeMapping>,
+	/// Attribute for the user's phone number, `telephoneNumber` by
+	/// default
+	pub phone: Option<AttributeMapping>,
+	/// Attribute for the user's full name, from which the
+	/// `common_name` name fallback derives a missing first or last
+	/// name, `cn` by default
+	pub common_name: Option<AttributeMapping>,
+	/// Attribute for the user's unique ID, `ipaUniqueID` by default
+	pub user_id: Option<AttributeMapping>,
+	/// Additional attributes to sync as Zitadel metadata, keyed by
+	/// the metadata key
+	#[serde(default)]
+	pub metadata: BTreeMap<String, AttributeMapping>,
+	/// Attribute listing the DNs of the user's groups, e.g.
+	/// `memberOf`, which rules can grant project roles by. Groups
+	/// aren't read by default.
+	pub groups: Option<AttributeMapping>,
+}
+
+/// Default for [`FreeIpaSourceConfig::timeout`]
+fn default_timeout() -> u64 {
+	DEFAULT_TIMEOUT
+}
+
+/// Default for [`FreeIpaSourceConfig::page_size`]
+fn default_page_size() -> i32 {
+	DEFAULT_PAGE_SIZE
+}
+
+/// Default for [`FreeIpaSourceConfig::sync_preferred_username`]
+fn default_sync_preferred_username() -> bool {
+	true
+}
+
+/// An attribute which isn't binary
+fn text(name: &str) -> AttributeMapping {
+	AttributeMapping::NoBinaryOption(name.to_owned())
+}
+
+impl From<FreeIpaSourceConfig> for LdapSourceConfig {
+	fn from(cfg: FreeIpaSourceConfig) -> LdapSourceConfig {
+		let attributes = cfg.attributes;
+
+		LdapSourceConfig {
+			url: cfg.url,
+			base_dn: cfg.base_dn,
+			bind_dn: cfg.bind_dn,
+			bind_password: cfg.bind_password,
+			user_filter: cfg.user_filter.unwrap_or_else(|| DEFAULT_USER_FILTER.to_owned()),
+			timeout: cfg.timeout,
+			attributes: LdapAttributesMapping {
+				first_name: attributes.first_name.unwrap_or_else(|| text("givenName")),
+				last_name: attributes.last_name.unwrap_or_else(|| text("sn")),
+				preferred_username: cfg
+					.sync_preferred_username
+					.then(|| attributes.preferred_username.unwrap_or_else(|| text("uid"))),
+				email: attributes.email.unwrap_or_else(|| text("mail")),
+				phone: attributes.phone.unwrap_or_else(|| text("telephoneNumber")),
+				user_id: attributes.user_id.unwrap_or_else(|| text("ipaUniqueID")),
+				status: text("nsAccountLock"),
+				status_format: StatusFormat::Lock,
+				disable_bitmasks: Vec::new(),
+				last_modified: None,
+				metadata: attributes.metadata,
+				proxy_addresses: None,
+				common_name: Some(attributes.common_name.unwrap_or_else(|| text("cn"))),
+				groups: attributes.groups,
+			},
+			check_for_deleted_entries: true,
+			// FreeIPA users carry many operational attributes, e.g.
+			// Kerberos keys, which aren't needed
+			use_attribute_filter: true,
+			tls: cfg.tls,
+			dirsync: None,
+			incremental: None,
+			watch: cfg.watch,
+			page_size: Some(cfg.page_size),
+			missing_attributes: cfg.missing_attributes,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use indoc::indoc;
+
+	use super::*;
+
+	#[test]
+	fn test_presets() {
+		let config: FreeIpaSourceConfig = serde_yaml::from_str(indoc! {r#"
+			url: ldaps://ipa.example.invalid
+			base_dn: cn=users,cn=accounts,dc=example,dc=org
+			bind_dn: uid=famedly-sync,cn=sysaccounts,cn=etc,dc=example,dc=org
+			bind_password: secret
+			attributes:
+			  phone: "mobile"
+			  metadata:
+			    department: "departmentNumber"
+		"#})
+		.expect("invalid config");
+
+		let ldap: LdapSourceConfig = config.into();
+
+		assert_eq!(ldap.user_filter, DEFAULT_USER_FILTER);
+		assert_eq!(ldap.page_size, Some(DEFAULT_PAGE_SIZE));
+		assert_eq!(ldap.attributes.user_id, text("ipaUniqueID"));
+		assert_eq!(ldap.attributes.status, text("nsAccountLock"));
+		assert_eq!(ldap.attributes.status_format, StatusFormat::Lock);
+		assert!(ldap.attributes.disable_bitmasks.is_empty());
+		assert_eq!(ldap.attributes.preferred_username, Some(text("uid")));
+		assert_eq!(ldap.attributes.phone, text("mobile"));
+		assert_eq!(ldap.attributes.metadata.get("department"), Some(&text("departmentNumber")));
+	}
+}
@@ -0,0 +1,87 @@
+//! Incremental sync from LDAP using modification timestamps
+//!
+//! Only the users whose `last_modified` attribute, e.g.
+//! `modifyTimestamp`, changed since the last sync are read from LDAP.
+//! Deleted entries leave no timestamp behind, so a full sync is run
+//! periodically to remove them from Zitadel.
+use chrono::{DateTime, Duration, Utc};
+use serde::Deserialize;
+
+/// The default number of hours between full syncs
+const DEFAULT_FULL_SYNC_INTERVAL_HOURS: u32 = 24;
+
+/// The default number of seconds changes are read from before the last
+/// sync
+const DEFAULT_OVERLAP_SECONDS: u32 = 300;
+
+/// Configuration of incremental syncs using modification timestamps
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct IncrementalSyncConfig {
+	/// The number of hours after which a full sync is run again, which
+	/// also removes users deleted from LDAP
+	#[serde(default = "default_full_sync_interval_hours")]
+	pub full_sync_interval_hours: u32,
+	/// The number of seconds changes are read from before the last
+	/// sync started, allowing for clock skew between the sync and the
+	/// LDAP server
+	#[serde(default = "default_overlap_seconds")]
+	pub overlap_seconds: u32,
+}
+
+impl Default for IncrementalSyncConfig {
+	fn default() -> Self {
+		Self {
+			full_sync_interval_hours: DEFAULT_FULL_SYNC_INTERVAL_HOURS,
+			overlap_seconds: DEFAULT_OVERLAP_SECONDS,
+		}
+	}
+}
+
+/// Default for [`IncrementalSyncConfig::full_sync_interval_hours`]
+fn default_full_sync_interval_hours() -> u32 {
+	DEFAULT_FULL_SYNC_INTERVAL_HOURS
+}
+
+/// Default for [`IncrementalSyncConfig::overlap_seconds`]
+fn default_overlap_seconds() -> u32 {
+	DEFAULT_OVERLAP_SECONDS
+}
+
+impl IncrementalSyncConfig {
+	/// The time to read changes from, given when the last sync started
+	/// and when the last full sync started, or `None` if a full sync is
+	/// due
+	#[must_use]
+	pub fn modified_since(
+		&self,
+		last_sync_at: DateTime<Utc>,
+		last_full_sync_at: DateTime<Utc>,
+		now: DateTime<Utc>,
+	) -> Option<DateTime<Utc>> {
+		if now - last_full_sync_at >= Duration::hours(i64::from(self.full_sync_interval_hours)) {
+			return None;
+		}
+
+		Some(last_sync_at - Duration::seconds(i64::from(self.overlap_seconds)))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_modified_since() {
+		let config = IncrementalSyncConfig::default();
+		let now = Utc::now();
+
+		assert_eq!(
+			config.modified_since(now - Duration::hours(1), now - Duration::hours(2), now),
+			Some(now - Duration::hours(1) - Duration::minutes(5))
+		);
+		assert_eq!(
+			config.modified_since(now - Duration::hours(1), now - Duration::hours(24), now),
+			None
+		);
+	}
+}
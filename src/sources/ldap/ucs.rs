@@ -0,0 +1,216 @@
+//! Univention Corporate Server presets for the LDAP source
+//!
+//! UCS stores its users in OpenLDAP with a fixed schema, so the
+//! attribute mapping of the LDAP source can be derived from a handful
+//! of settings. Users are identified by their `entryUUID`, and disabled
+//! or expired accounts are recognized by their `shadowExpire`, which
+//! UCS sets to a past day when deactivating an account. Extended
+//! attributes, which UCS stores in the LDAP attributes configured in
+//! their `univentionUDMPropertyLdapMapping`, are synced as metadata.
+//! Any attribute can still be overridden.
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+use url::Url;
+
+use super::{
+	AttributeMapping, LdapAttributesMapping, LdapSourceConfig, LdapTlsConfig,
+	MissingAttributePolicies, StatusFormat, WatchConfig,
+};
+
+/// The default filter selecting user accounts, excluding the root
+/// account and machine accounts
+const DEFAULT_USER_FILTER: &str = "(&(objectClass=univentionPerson)(!(uidNumber=0))(!(uid=*$)))";
+
+/// The default number of entries per page, below the default size
+/// limit of UCS' OpenLDAP
+const DEFAULT_PAGE_SIZE: i32 = 500;
+
+/// The default timeout for LDAP operations in seconds
+const DEFAULT_TIMEOUT: u64 = 30;
+
+/// UCS configuration, a specialization of the LDAP source with presets
+/// for Univention Corporate Server
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct UcsSourceConfig {
+	/// The URL of the UCS primary directory node or a replica
+	pub url: Url,
+	/// The base DN for searching users, usually `cn=users,<LDAP base>`
+	pub base_dn: String,
+	/// The DN to bind for authentication, e.g. a simple authentication
+	/// account in `cn=users,<LDAP base>`
+	pub bind_dn: String,
+	/// The password for the bind DN
+	pub bind_password: String,
+	/// Filter to apply when searching for users, all user accounts by
+	/// default. Don't filter out deactivated accounts, since they
+	/// wouldn't be deleted from Zitadel.
+	pub user_filter: Option<String>,
+	/// Timeout for LDAP operations in seconds
+	#[serde(default = "default_timeout")]
+	pub timeout: u64,
+	/// The number of entries to request per page
+	#[serde(default = "default_page_size")]
+	pub page_size: i32,
+	/// Overrides of the preset attribute mapping
+	#[serde(default)]
+	pub attributes: UcsAttributes,
+	/// Whether to sync the preferred username from
+	/// `attributes.preferred_username`. If disabled, existing values in
+	/// Zitadel are left as they are.
+	#[serde(default = "default_sync_preferred_username")]
+	pub sync_preferred_username: bool,
+	/// TLS-related configuration
+	pub tls: Option<LdapTlsConfig>,
+	/// What to do with users lacking a required attribute
+	#[serde(default)]
+	pub missing_attributes: MissingAttributePolicies,
+	/// Watch the directory for changes with `--watch`
+	pub watch: Option<WatchConfig>,
+}
+
+/// Overrides of the attributes preset for UCS
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+pub struct UcsAttributes {
+	/// Attribute for the user's first name, `givenName` by default
+	pub first_name: Option<AttributeMapping>,
+	/// Attribute for the user's last name, `sn` by default
+	pub last_name: Option<AttributeMapping>,
+	/// Attribute for the user's preferred username, `uid` by default
+	pub preferred_username: Option<AttributeMapping>,
+	/// Attribute for the user's email address, the primary address of
+	/// the UCS mail stack `mailPrimaryAddress` by default. Set it to
+	/// `mail` if users' addresses are maintained as external email
+	/// addresses instead.
+	pub email: Option<AttributeMapping>,
+	/// Attribute for the user's phone number, `telephoneNumber` by
+	/// default
+	pub phone: Option<AttributeMapping>,
+	/// Attribute for the user's full name, from which the
+	/// `common_name` name fallback derives a missing first or last
+	/// name, `cn` by default
+	pub common_name: Option<AttributeMapping>,
+	/// Attribute for the user's unique ID, `entryUUID` by default
+	pub user_id: Option<AttributeMapping>,
+	/// UCS extended attributes to sync as Zitadel metadata, keyed by
+	/// the metadata key, with the LDAP attribute the extended
+	/// attribute is mapped to, e.g. `ward: "univentionFreeAttribute1"`
+	#[serde(default)]
+	pub extended_attributes: BTreeMap<String, AttributeMapping>,
+	/// Additional attributes to sync as Zitadel metadata, keyed by
+	/// the metadata key. These take precedence over extended
+	/// attributes with the same key.
+	#[serde(default)]
+	pub metadata: BTreeMap<String, AttributeMapping>,
+	/// Attribute listing the DNs of the user's groups, e.g.
+	/// `memberOf`, which rules can grant project roles by. Groups
+	/// aren't read by default.
+	pub groups: Option<AttributeMapping>,
+}
+
+/// Default for [`UcsSourceConfig::timeout`]
+fn default_timeout() -> u64 {
+	DEFAULT_TIMEOUT
+}
+
+/// Default for [`UcsSourceConfig::page_size`]
+fn default_page_size() -> i32 {
+	DEFAULT_PAGE_SIZE
+}
+
+/// Default for [`UcsSourceConfig::sync_preferred_username`]
+fn default_sync_preferred_username() -> bool {
+	true
+}
+
+/// An attribute which isn't binary
+fn text(name: &str) -> AttributeMapping {
+	AttributeMapping::NoBinaryOption(name.to_owned())
+}
+
+impl From<UcsSourceConfig> for LdapSourceConfig {
+	fn from(cfg: UcsSourceConfig) -> LdapSourceConfig {
+		let attributes = cfg.attributes;
+		let mut metadata = attributes.extended_attributes;
+		metadata.extend(attributes.metadata);
+
+		LdapSourceConfig {
+			url: cfg.url,
+			base_dn: cfg.base_dn,
+			bind_dn: cfg.bind_dn,
+			bind_password: cfg.bind_password,
+			user_filter: cfg.user_filter.unwrap_or_else(|| DEFAULT_USER_FILTER.to_owned()),
+			timeout: cfg.timeout,
+			attributes: LdapAttributesMapping {
+				first_name: attributes.first_name.unwrap_or_else(|| text("givenName")),
+				last_name: attributes.last_name.unwrap_or_else(|| text("sn")),
+				preferred_username: cfg
+					.sync_preferred_username
+					.then(|| attributes.preferred_username.unwrap_or_else(|| text("uid"))),
+				email: attributes.email.unwrap_or_else(|| text("mailPrimaryAddress")),
+				phone: attributes.phone.unwrap_or_else(|| text("telephoneNumber")),
+				user_id: attributes.user_id.unwrap_or_else(|| text("entryUUID")),
+				// UCS sets `shadowExpire` to 1 when deactivating an
+				// account, and to the expiry day of accounts with an
+				// expiry date
+				status: text("shadowExpire"),
+				status_format: StatusFormat::Expiry,
+				disable_bitmasks: Vec::new(),
+				last_modified: None,
+				metadata,
+				proxy_addresses: None,
+				common_name: Some(attributes.common_name.unwrap_or_else(|| text("cn"))),
+				groups: attributes.groups,
+			},
+			check_for_deleted_entries: true,
+			// UCS users carry many attributes of other services, e.g.
+			// Samba and Kerberos, which aren't needed
+			use_attribute_filter: true,
+			tls: cfg.tls,
+			dirsync: None,
+			incremental: None,
+			watch: cfg.watch,
+			page_size: Some(cfg.page_size),
+			missing_attributes: cfg.missing_attributes,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use indoc::indoc;
+
+	use super::*;
+
+	#[test]
+	fn test_presets() {
+		let config: UcsSourceConfig = serde_yaml::from_str(indoc! {r#"
+			url: ldaps://ucs.example.invalid:7636
+			base_dn: cn=users,dc=example,dc=org
+			bind_dn: uid=famedly-sync,cn=users,dc=example,dc=org
+			bind_password: secret
+			sync_preferred_username: false
+			attributes:
+			  email: "mail"
+			  extended_attributes:
+			    ward: "univentionFreeAttribute1"
+			    department: "univentionFreeAttribute2"
+			  metadata:
+			    department: "departmentNumber"
+		"#})
+		.expect("invalid config");
+
+		let ldap: LdapSourceConfig = config.into();
+
+		assert_eq!(ldap.user_filter, DEFAULT_USER_FILTER);
+		assert_eq!(ldap.page_size, Some(DEFAULT_PAGE_SIZE));
+		assert_eq!(ldap.attributes.user_id, text("entryUUID"));
+		assert_eq!(ldap.attributes.status, text("shadowExpire"));
+		assert_eq!(ldap.attributes.status_format, StatusFormat::Expiry);
+		assert!(ldap.attributes.disable_bitmasks.is_empty());
+		assert_eq!(ldap.attributes.email, text("mail"));
+		assert_eq!(ldap.attributes.preferred_username, None);
+		assert_eq!(ldap.attributes.metadata.get("ward"), Some(&text("univentionFreeAttribute1")));
+		assert_eq!(ldap.attributes.metadata.get("department"), Some(&text("departmentNumber")));
+	}
+}
@@ -0,0 +1,249 @@
+//! Watching LDAP for changes using the Content Synchronization
+//! Operation (RFC 4533, syncrepl)
+//!
+//! A search in `refreshAndPersist` mode first returns all users, and
+//! then stays open, returning each user as it is added, changed or
+//! deleted. Deleted entries are only identified by their entry UUID,
+//! so the external IDs of all users returned by the search are kept by
+//! UUID.
+use std::{collections::HashMap, time::Duration};
+
+use anyhow::{bail, Context, Result};
+use ldap3::{controls::RawControl, Scope, SearchEntry};
+use serde::Deserialize;
+use tokio::sync::mpsc::UnboundedSender;
+
+use super::{
+	dirsync::{connect, encode_tlv, read_tlv},
+	LdapSource,
+};
+use crate::user::User;
+
+/// OID of the Sync Request control
+const SYNC_REQUEST_OID: &str = "1.3.6.1.4.1.4203.1.9.1.1";
+/// OID of the Sync State control
+const SYNC_STATE_OID: &str = "1.3.6.1.4.1.4203.1.9.1.2";
+/// The `refreshAndPersist` mode of the Sync Request control
+const REFRESH_AND_PERSIST: u8 = 3;
+
+/// The default number of seconds to wait for further changes before
+/// syncing
+const DEFAULT_DEBOUNCE_SECONDS: u64 = 5;
+/// The default number of seconds to wait before reconnecting
+const DEFAULT_RECONNECT_DELAY_SECONDS: u64 = 30;
+
+/// Configuration of watching LDAP for changes, with `--watch`
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct WatchConfig {
+	/// The number of seconds to wait for further changes after a
+	/// change, so that changes made together are synced together
+	#[serde(default = "default_debounce_seconds")]
+	pub debounce_seconds: u64,
+	/// The number of seconds to wait before reconnecting after the
+	/// connection to LDAP was lost
+	#[serde(default = "default_reconnect_delay_seconds")]
+	pub reconnect_delay_seconds: u64,
+}
+
+/// Default for [`WatchConfig::debounce_seconds`]
+fn default_debounce_seconds() -> u64 {
+	DEFAULT_DEBOUNCE_SECONDS
+}
+
+/// Default for [`WatchConfig::reconnect_delay_seconds`]
+fn default_reconnect_delay_seconds() -> u64 {
+	DEFAULT_RECONNECT_DELAY_SECONDS
+}
+
+/// An event observed while watching LDAP
+#[derive(Debug)]
+pub enum WatchEvent {
+	/// The watch (re)connected. Changes made while it was disconnected
+	/// are only picked up by a full sync.
+	Connected,
+	/// The user was added or changed
+	Changed(User),
+	/// The user with the given external ID was deleted, or no longer
+	/// matches the user filter
+	Removed(String),
+}
+
+/// The state of an entry, according to the Sync State control
+#[derive(Debug, PartialEq)]
+enum EntryState {
+	/// The entry is unchanged since the state of a cookie
+	Present,
+	/// The entry was added
+	Add,
+	/// The entry was changed
+	Modify,
+	/// The entry was deleted
+	Delete,
+}
+
+impl LdapSource {
+	/// Watch LDAP for changes, sending them to the given channel until
+	/// it is closed. Lost connections are re-established after the
+	/// configured delay.
+	pub async fn watch(&self, events: UnboundedSender<WatchEvent>) -> Result<()> {
+		let watch_config = self.ldap_config.watch.as_ref().context("Watching is not configured")?;
+		let mut user_ids = HashMap::new();
+
+		loop {
+			match self.watch_once(&events, &mut user_ids).await {
+				Ok(()) => tracing::warn!("The LDAP server ended the watch"),
+				Err(error) => tracing::warn!("Watching LDAP failed: {:?}", error),
+			}
+			if events.is_closed() {
+				return Ok(());
+			}
+
+			tracing::info!(
+				"Reconnecting to LDAP in {} seconds",
+				watch_config.reconnect_delay_seconds
+			);
+			tokio::time::sleep(Duration::from_secs(watch_config.reconnect_delay_seconds)).await;
+		}
+	}
+
+	/// Run a single `refreshAndPersist` search, until the connection
+	/// is lost
+	async fn watch_once(
+		&self,
+		events: &UnboundedSender<WatchEvent>,
+		user_ids: &mut HashMap<Vec<u8>, String>,
+	) -> Result<()> {
+		let mut ldap = connect(&self.ldap_config).await?;
+		let attributes = if self.ldap_config.use_attribute_filter {
+			self.user_attributes()
+		} else {
+			vec!["*".to_owned()]
+		};
+
+		let mut search = ldap
+			.with_controls(RawControl {
+				ctype: SYNC_REQUEST_OID.to_owned(),
+				crit: true,
+				val: Some(encode_tlv(0x30, &encode_tlv(0x0a, &[REFRESH_AND_PERSIST]))),
+			})
+			.streaming_search(
+				&self.ldap_config.base_dn,
+				Scope::Subtree,
+				&self.ldap_config.user_filter,
+				attributes,
+			)
+			.await
+			.context("Failed to start watching LDAP")?;
+		tracing::info!("Watching LDAP for changes");
+		if events.send(WatchEvent::Connected).is_err() {
+			return Ok(());
+		}
+
+		while let Some(entry) = search.next().await.context("Failed to read changes from LDAP")? {
+			// Intermediate messages only carry cookies, which aren't
+			// stored
+			if entry.is_intermediate() {
+				continue;
+			}
+
+			let Some(control) = entry.1.iter().find(|control| control.1.ctype == SYNC_STATE_OID)
+			else {
+				continue;
+			};
+			let (state, entry_uuid) =
+				decode_sync_state_control(control.1.val.as_deref().unwrap_or_default())?;
+			let entry = SearchEntry::construct(entry);
+
+			let event = match state {
+				EntryState::Present => continue,
+				EntryState::Delete => match user_ids.remove(&entry_uuid) {
+					Some(user_id) => WatchEvent::Removed(user_id),
+					None => {
+						tracing::warn!("Cannot identify deleted LDAP entry `{}`", entry.dn);
+						continue;
+					}
+				},
+				EntryState::Add | EntryState::Modify => {
+					let dn = entry.dn.clone();
+					let user = match self.parse_user(entry) {
+						Ok(user) => user,
+						Err(error) => {
+							tracing::error!("Ignoring change of LDAP entry `{}`: {:?}", dn, error);
+							continue;
+						}
+					};
+					// The user ID attribute changed, so the user with the
+					// old ID is gone
+					if let Some(old_user_id) = user_ids
+						.insert(entry_uuid, user.external_user_id.clone())
+						.filter(|user_id| *user_id != user.external_user_id)
+					{
+						if events.send(WatchEvent::Removed(old_user_id)).is_err() {
+							return Ok(());
+						}
+					}
+					WatchEvent::Changed(user)
+				}
+			};
+
+			if events.send(event).is_err() {
+				return Ok(());
+			}
+		}
+
+		search.finish().await.success().context("Failed to watch LDAP").map(|_| ())
+	}
+}
+
+/// Decode the value of a Sync State control into the state and UUID
+/// of the entry
+///
+/// The control has the structure `SEQUENCE { state ENUMERATED,
+/// entryUUID OCTET STRING, cookie OCTET STRING OPTIONAL }`.
+fn decode_sync_state_control(value: &[u8]) -> Result<(EntryState, Vec<u8>)> {
+	let (tag, contents, _) = read_tlv(value)?;
+	if tag != 0x30 {
+		bail!("Invalid Sync State control: expected a sequence, found tag {tag:#x}");
+	}
+
+	let (tag, state, rest) = read_tlv(contents)?;
+	if tag != 0x0a {
+		bail!("Invalid Sync State control: expected an enumeration, found tag {tag:#x}");
+	}
+	let state = match state {
+		[0] => EntryState::Present,
+		[1] => EntryState::Add,
+		[2] => EntryState::Modify,
+		[3] => EntryState::Delete,
+		_ => bail!("Invalid Sync State control: unknown state {state:?}"),
+	};
+
+	let (tag, entry_uuid, _) = read_tlv(rest)?;
+	if tag != 0x04 {
+		bail!("Invalid Sync State control: expected an octet string, found tag {tag:#x}");
+	}
+
+	Ok((state, entry_uuid.to_vec()))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_sync_state_control() {
+		let entry_uuid: Vec<u8> = (0..16).collect();
+		let mut contents = encode_tlv(0x0a, &[2]);
+		contents.extend(encode_tlv(0x04, &entry_uuid));
+		contents.extend(encode_tlv(0x04, b"rid=001,csn=20240301"));
+
+		assert_eq!(
+			decode_sync_state_control(&encode_tlv(0x30, &contents)).expect("invalid control"),
+			(EntryState::Modify, entry_uuid)
+		);
+
+		let invalid_state = encode_tlv(0x30, &encode_tlv(0x0a, &[7]));
+		assert!(decode_sync_state_control(&invalid_state).is_err());
+		assert!(decode_sync_state_control(&contents).is_err());
+	}
+}
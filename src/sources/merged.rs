@@ -0,0 +1,357 @@
+//! Merging of several sources into one
+//!
+//! A directory can be supplemented with users listed elsewhere, e.g.
+//! contractors kept in a CSV file next to the staff in LDAP. The users
+//! of all configured sources are read in the configured order of
+//! priority and merged by external user ID.
+
+use std::collections::{btree_map::Entry, BTreeMap};
+
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+
+use super::{
+	csv::CsvSource, entra::EntraSource, fhir::FhirSource, keycloak::KeycloakSource,
+	ldap::LdapSource, Source,
+};
+use crate::{
+	source_guard::SourceGuard,
+	user::{Unreadable, User},
+	Config,
+};
+
+/// Configuration of how the users of several sources are merged
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct SourceMergeConfig {
+	/// The configured sources, in order of priority
+	pub priority: Vec<SourceKind>,
+	/// How users found in several sources are merged
+	#[serde(default)]
+	pub strategy: MergeStrategy,
+}
+
+/// A kind of source which can be merged with others
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SourceKind {
+	/// The LDAP source, including Active Directory, FreeIPA and UCS
+	Ldap,
+	/// The CSV source
+	Csv,
+	/// The FHIR source
+	Fhir,
+	/// The Microsoft Entra ID source
+	Entra,
+	/// The Keycloak source
+	Keycloak,
+}
+
+/// How users found in several sources are merged
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MergeStrategy {
+	/// Take the user from the source with the highest priority, and
+	/// ignore it in the others
+	#[default]
+	FirstMatch,
+	/// Take the user from the source with the highest priority, and
+	/// fill in the fields it lacks from the others
+	FieldMerge,
+}
+
+impl SourceMergeConfig {
+	/// Check that the priorities list each of the configured sources
+	/// exactly once
+	pub(crate) fn validate(&self, configured: &[SourceKind]) -> Result<()> {
+		for (index, kind) in self.priority.iter().enumerate() {
+			if !configured.contains(kind) {
+				bail!("`source_merge` lists the {:?} source, which isn't configured", kind);
+			}
+			if self.priority[..index].contains(kind) {
+				bail!("`source_merge` lists the {:?} source more than once", kind);
+			}
+		}
+		if let Some(kind) = configured.iter().find(|kind| !self.priority.contains(kind)) {
+			bail!("`source_merge` doesn't list the configured {:?} source", kind);
+		}
+
+		Ok(())
+	}
+}
+
+/// Several sources, merged into one
+pub struct MergedSource {
+	/// The sources, in order of priority
+	sources: Vec<Box<dyn Source + Send + Sync>>,
+	/// How users found in several sources are merged
+	strategy: MergeStrategy,
+	/// The timeouts and circuit breakers of the sources
+	guard: SourceGuard,
+}
+
+impl MergedSource {
+	/// Create the merged source of a configuration
+	pub fn new(config: &Config) -> Result<Self> {
+		let merge = config.source_merge.as_ref().context("`source_merge` is not configured")?;
+
+		let sources = merge
+			.priority
+			.iter()
+			.map(|kind| {
+				let source: Box<dyn Source + Send + Sync> = match kind {
+					SourceKind::Ldap => Box::new(LdapSource::new(
+						config.sources.ldap.clone().context("No LDAP source configured")?,
+					)),
+					SourceKind::Csv => Box::new(CsvSource::new(
+						config.sources.csv.clone().context("No CSV source configured")?,
+					)),
+					SourceKind::Fhir => Box::new(FhirSource::new(
+						config.sources.fhir.clone().context("No FHIR source configured")?,
+					)?),
+					SourceKind::Entra => Box::new(EntraSource::new(
+						config.sources.entra.clone().context("No Entra ID source configured")?,
+					)?),
+					SourceKind::Keycloak => Box::new(KeycloakSource::new(
+						config.sources.keycloak.clone().context("No Keycloak source configured")?,
+					)?),
+				};
+				anyhow::Ok(source)
+			})
+			.collect::<Result<_>>()?;
+
+		Ok(Self { sources, strategy: merge.strategy, guard: SourceGuard::new(config) })
+	}
+}
+
+#[async_trait]
+impl Source for MergedSource {
+	fn get_name(&self) -> &'static str {
+		"merged sources"
+	}
+
+	async fn get_sorted_users(&self) -> Result<Vec<User>> {
+		let mut merged: BTreeMap<String, User> = BTreeMap::new();
+
+		for source in &self.sources {
+			let users = self
+				.guard
+				.read(source.get_name(), source.get_sorted_users())
+				.await
+				.context(format!("Failed to query users from {}", source.get_name()))?;
+			tracing::info!("Read {} users from {}", users.len(), source.get_name());
+
+			for user in users {
+				match merged.entry(user.external_user_id.clone()) {
+					Entry::Vacant(entry) => {
+						entry.insert(user);
+					}
+					Entry::Occupied(mut entry) => match self.strategy {
+						MergeStrategy::FirstMatch => {
+							tracing::debug!(
+								"Ignoring user `{}` from {}, found in a source of higher \
+								 priority",
+								user.external_user_id,
+								source.get_name()
+							);
+						}
+						MergeStrategy::FieldMerge => merge_fields(entry.get_mut(), user),
+					},
+				}
+			}
+		}
+
+		Ok(merged.into_values().collect())
+	}
+
+	async fn get_raw_attributes(
+		&self,
+		external_user_id: &str,
+	) -> Result<Option<BTreeMap<String, Vec<String>>>> {
+		for source in &self.sources {
+			if let Some(attributes) = source.get_raw_attributes(external_user_id).await? {
+				return Ok(Some(attributes));
+			}
+		}
+
+		Ok(None)
+	}
+
+	async fn snapshot_time(&self) -> Result<Option<DateTime<Utc>>> {
+		// The merged snapshot is as old as the oldest of its sources
+		let mut oldest = None;
+		for source in &self.sources {
+			if let Some(time) = source.snapshot_time().await? {
+				oldest = Some(oldest.map_or(time, |oldest: DateTime<Utc>| oldest.min(time)));
+			}
+		}
+
+		Ok(oldest)
+	}
+}
+
+/// Fill in the fields a user lacks from the same user in a source of
+/// lower priority
+///
+/// Whether the user is enabled is always taken from the source of
+/// higher priority, while roles are granted if any source grants them,
+/// and group memberships are combined likewise.
+pub(crate) fn merge_fields(user: &mut User, lower: User) {
+	if user.first_name.is_empty() {
+		user.first_name = lower.first_name;
+	}
+	if user.last_name.is_empty() {
+		user.last_name = lower.last_name;
+	}
+	if user.email.is_empty() {
+		user.email = lower.email;
+	}
+	user.phone = user.phone.take().or(lower.phone);
+	user.preferred_username = user.preferred_username.take().or(lower.preferred_username);
+	user.localpart = user.localpart.take().or(lower.localpart);
+
+	for (key, value) in lower.metadata {
+		user.metadata.entry(key).or_insert(value);
+	}
+	user.roles.extend(lower.roles);
+	user.groups.extend(lower.groups);
+
+	// A user skipped for lacking an attribute is complete once another
+	// source provides it, while an entry which failed to parse in any
+	// source leaves the merged user incomplete
+	user.unreadable = match user.unreadable.take() {
+		Some(Unreadable::MissingAttribute(attribute)) => {
+			let missing = match attribute.as_str() {
+				"email" => user.email.is_empty(),
+				"preferred_username" => user.preferred_username.is_none(),
+				_ => true,
+			};
+			missing.then_some(Unreadable::MissingAttribute(attribute))
+		}
+		unreadable => unreadable,
+	};
+	if let Some(Unreadable::ParseFailure(error)) = lower.unreadable {
+		user.unreadable = Some(Unreadable::ParseFailure(error));
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// A source returning fixed users
+	struct StaticSource(Vec<User>);
+
+	#[async_trait]
+	impl Source for StaticSource {
+		fn get_name(&self) -> &'static str {
+			"static"
+		}
+
+		async fn get_sorted_users(&self) -> Result<Vec<User>> {
+			Ok(self.0.clone())
+		}
+
+		async fn get_raw_attributes(
+			&self,
+			_external_user_id: &str,
+		) -> Result<Option<BTreeMap<String, Vec<String>>>> {
+			Ok(None)
+		}
+	}
+
+	fn user(external_user_id: &str, first_name: &str, phone: Option<&str>) -> User {
+		User::new(
+			first_name.to_owned(),
+			"Doe".to_owned(),
+			format!("{external_user_id}@example.com"),
+			phone.map(ToOwned::to_owned),
+			true,
+			None,
+			external_user_id.to_owned(),
+			None,
+		)
+	}
+
+	fn merged_source(strategy: MergeStrategy) -> MergedSource {
+		let mut directory_user = user("jdoe", "John", None);
+		directory_user.metadata.insert("department".to_owned(), "Radiology".to_owned());
+		let mut listed_user = user("jdoe", "Johnny", Some("+12015551111"));
+		listed_user.metadata.insert("department".to_owned(), "Contractors".to_owned());
+		listed_user.metadata.insert("company".to_owned(), "ACME".to_owned());
+
+		MergedSource {
+			sources: vec![
+				Box::new(StaticSource(vec![directory_user, user("mmuster", "Max", None)])),
+				Box::new(StaticSource(vec![user("aaron", "Aaron", None), listed_user])),
+			],
+			strategy,
+			guard: SourceGuard::default(),
+		}
+	}
+
+	#[tokio::test]
+	async fn test_first_match() {
+		let users = merged_source(MergeStrategy::FirstMatch)
+			.get_sorted_users()
+			.await
+			.expect("failed to merge users");
+
+		let ids: Vec<&str> = users.iter().map(User::get_external_id).collect();
+		assert_eq!(ids, vec!["aaron", "jdoe", "mmuster"]);
+
+		let jdoe = &users[1];
+		assert_eq!(jdoe.first_name, "John");
+		assert_eq!(jdoe.phone, None);
+		assert_eq!(jdoe.get_attribute("company"), None);
+	}
+
+	#[tokio::test]
+	async fn test_field_merge() {
+		let users = merged_source(MergeStrategy::FieldMerge)
+			.get_sorted_users()
+			.await
+			.expect("failed to merge users");
+
+		let jdoe = &users[1];
+		assert_eq!(jdoe.first_name, "John");
+		assert_eq!(jdoe.phone.as_deref(), Some("+12015551111"));
+		assert_eq!(jdoe.get_attribute("department"), Some("Radiology".to_owned()));
+		assert_eq!(jdoe.get_attribute("company"), Some("ACME".to_owned()));
+	}
+
+	#[test]
+	fn test_merge_unreadable_fields() {
+		let mut skipped = user("jdoe", "John", None);
+		skipped.email = String::new();
+		skipped.unreadable = Some(Unreadable::MissingAttribute("email".to_owned()));
+		merge_fields(&mut skipped, user("jdoe", "Johnny", None));
+		assert_eq!(skipped.email, "jdoe@example.com");
+		assert!(skipped.unreadable.is_none());
+
+		// An entry failing to parse in a lower source may have provided
+		// any of the fields
+		let mut merged = user("jdoe", "John", None);
+		merge_fields(&mut merged, User::unparsable("jdoe".to_owned(), "invalid row".to_owned()));
+		assert_eq!(merged.unreadable, Some(Unreadable::ParseFailure("invalid row".to_owned())));
+	}
+
+	#[test]
+	fn test_validate() {
+		let merge = SourceMergeConfig {
+			priority: vec![SourceKind::Ldap, SourceKind::Csv],
+			strategy: MergeStrategy::default(),
+		};
+
+		assert!(merge.validate(&[SourceKind::Ldap, SourceKind::Csv]).is_ok());
+		assert!(merge.validate(&[SourceKind::Ldap, SourceKind::Csv, SourceKind::Fhir]).is_err());
+		assert!(merge.validate(&[SourceKind::Ldap]).is_err());
+
+		let duplicated = SourceMergeConfig {
+			priority: vec![SourceKind::Ldap, SourceKind::Ldap],
+			strategy: MergeStrategy::default(),
+		};
+		assert!(duplicated.validate(&[SourceKind::Ldap]).is_err());
+	}
+}
@@ -2,12 +2,30 @@
 
 use std::collections::HashMap;
 
-use anyhow::{Context, Result};
-use chrono::Utc;
+use anyhow::{bail, Context, Result};
+use chrono::{NaiveDate, Utc};
 use reqwest::Client;
 use serde::Deserialize;
+use serde_json::Value;
 use url::Url;
 
+use super::verification::PayloadVerification;
+
+/// The schema versions of the endpoint's responses which are understood
+///
+/// Version 1 is a JSON list of the email addresses of removed users.
+const SCHEMA_VERSIONS: &[u32] = &[1];
+
+/// The schema version assumed unless configured
+const DEFAULT_SCHEMA_VERSION: u32 = 1;
+
+/// The number of schema violations listed in errors
+const MAX_LISTED_VIOLATIONS: usize = 10;
+
+/// Date formats which entries of the list are checked against, to
+/// point out dates where email addresses are expected
+const DATE_FORMATS: &[&str] = &["%Y%m%d", "%Y-%m-%d", "%d.%m.%Y"];
+
 /// UKT Source
 pub struct UktSource {
 	/// UKT Source configuration
@@ -72,16 +90,116 @@ impl UktSource {
 
 		response.error_for_status_ref().context("UKT endpoint received non-OK status code")?;
 
-		let response: serde_json::Value = response.json().await?;
+		let headers = response.headers().clone();
+		let payload = response.bytes().await?;
+		if let Some(verification) = &self.ukt_config.verification {
+			verification
+				.verify_response(&headers, &payload)
+				.context("Failed to verify UKT endpoint response")?;
+		}
+
+		let response: serde_json::Value = serde_json::from_slice(&payload)
+			.context("Failed to parse UKT endpoint response as JSON")?;
 
 		if let Some(error) = response.get("error") {
 			anyhow::bail!("Error in UKT endpoint response body: {}", error)
 		}
 
-		let email_list: EmailList = serde_json::from_value(response)
-			.context("Failed to deserialize email list response")?;
+		validate_email_list(self.ukt_config.schema_version, response)
+	}
+}
 
-		Ok(email_list)
+/// Validate a response of the endpoint against the given schema version
+/// and return the email addresses it lists
+///
+/// The response is rejected as a whole if any entry doesn't match, so
+/// that an unannounced change of the export format fails the sync
+/// instead of deleting the wrong users.
+fn validate_email_list(schema_version: u32, response: Value) -> Result<EmailList> {
+	let Value::Array(entries) = response else {
+		bail!(
+			"UKT endpoint response doesn't match schema version {}: expected a list of email \
+			 addresses, got {}",
+			schema_version,
+			describe_value(&response)
+		);
+	};
+
+	let violations: Vec<String> = entries
+		.iter()
+		.enumerate()
+		.filter_map(|(index, entry)| {
+			let problem = match entry {
+				Value::String(value) if is_email_address(value) => return None,
+				Value::String(value) if is_date(value) => {
+					format!("`{value}` is a date instead of an email address")
+				}
+				Value::String(value) => format!("`{value}` is not an email address"),
+				other => format!("{} instead of an email address", describe_value(other)),
+			};
+			Some(format!("entry {index}: {problem}"))
+		})
+		.collect();
+
+	if !violations.is_empty() {
+		let listed: String = violations
+			.iter()
+			.take(MAX_LISTED_VIOLATIONS)
+			.map(|violation| format!("\n  - {violation}"))
+			.collect();
+		let unlisted = match violations.len().saturating_sub(MAX_LISTED_VIOLATIONS) {
+			0 => String::new(),
+			count => format!("\n  and {count} more"),
+		};
+		bail!(
+			"UKT endpoint response doesn't match schema version {}, its format may have \
+			 changed:{}{}",
+			schema_version,
+			listed,
+			unlisted
+		);
+	}
+
+	Ok(entries
+		.into_iter()
+		.filter_map(|entry| match entry {
+			Value::String(email) => Some(email),
+			_ => None,
+		})
+		.collect())
+}
+
+/// Whether a value looks like an email address
+fn is_email_address(value: &str) -> bool {
+	match value.split_once('@') {
+		Some((local, domain)) => {
+			!local.is_empty()
+				&& domain.contains('.')
+				&& !domain.contains('@')
+				&& !value.contains(char::is_whitespace)
+		}
+		None => false,
+	}
+}
+
+/// Whether a value is a date in one of the common formats
+fn is_date(value: &str) -> bool {
+	DATE_FORMATS.iter().any(|format| NaiveDate::parse_from_str(value, format).is_ok())
+}
+
+/// Describe a JSON value which isn't of the expected kind
+fn describe_value(value: &Value) -> String {
+	match value {
+		Value::Null => "null".to_owned(),
+		Value::Bool(_) => "a boolean".to_owned(),
+		Value::Number(number) => format!("the number {number}"),
+		Value::String(_) => "a string".to_owned(),
+		Value::Array(_) => "a list".to_owned(),
+		Value::Object(object) => {
+			let mut columns: Vec<String> = object.keys().map(|key| format!("`{key}`")).collect();
+			columns.sort();
+			format!("an object with the columns {}", columns.join(", "))
+		}
 	}
 }
 
@@ -112,6 +230,35 @@ pub struct UktSourceConfig {
 	pub scope: String,
 	/// The grant type
 	pub grant_type: String,
+	/// The version of the schema of the endpoint's responses
+	#[serde(default = "default_schema_version")]
+	pub schema_version: u32,
+	/// How to verify the endpoint's responses before reading them
+	pub verification: Option<PayloadVerification>,
+}
+
+impl UktSourceConfig {
+	/// Check that the configured schema version is understood and the
+	/// verification applies to the endpoint
+	pub(crate) fn validate(&self) -> Result<()> {
+		if !SCHEMA_VERSIONS.contains(&self.schema_version) {
+			bail!(
+				"Unsupported UKT schema version {}, supported versions are {:?}",
+				self.schema_version,
+				SCHEMA_VERSIONS
+			);
+		}
+		if let Some(verification) = &self.verification {
+			verification.validate_for_http()?;
+		}
+
+		Ok(())
+	}
+}
+
+/// Default for [`UktSourceConfig::schema_version`]
+fn default_schema_version() -> u32 {
+	DEFAULT_SCHEMA_VERSION
 }
 
 /// Helper module for unit and e2e tests
@@ -185,6 +332,7 @@ pub mod test_helpers {
 #[cfg(test)]
 mod tests {
 	use indoc::indoc;
+	use serde_json::json;
 	use wiremock::MockServer;
 
 	use super::*;
@@ -305,6 +453,50 @@ mod tests {
 		assert!(result.is_err(), "Didn't expect to fetch email list: {:?}", result);
 	}
 
+	#[test]
+	fn test_validate_email_list() {
+		let emails = validate_email_list(1, json!(["delete@famedly.de", "also.delete@famedly.de"]))
+			.expect("valid response rejected");
+		assert_eq!(emails, vec!["delete@famedly.de", "also.delete@famedly.de"]);
+
+		let emails = validate_email_list(1, json!([])).expect("empty response rejected");
+		assert!(emails.is_empty());
+	}
+
+	#[test]
+	fn test_validate_email_list_changed_format() {
+		let error = validate_email_list(1, json!({ "emails": ["delete@famedly.de"] }))
+			.expect_err("object response accepted");
+		assert!(error.to_string().contains("an object with the columns `emails`"));
+
+		let error = validate_email_list(
+			1,
+			json!([
+				"delete@famedly.de",
+				"20240131",
+				{ "mail": "delete@famedly.de", "date": "2024-01-31" },
+				"delete famedly.de",
+			]),
+		)
+		.expect_err("invalid entries accepted");
+		let error = error.to_string();
+		assert!(error.contains("entry 1: `20240131` is a date instead of an email address"));
+		assert!(error.contains("entry 2: an object with the columns `date`, `mail`"));
+		assert!(error.contains("entry 3: `delete famedly.de` is not an email address"));
+		assert!(!error.contains("entry 0"));
+	}
+
+	#[test]
+	fn test_unsupported_schema_version() {
+		let mut config = load_config();
+		let ukt = config.sources.ukt.as_mut().expect("UktSource configuration is missing");
+		assert_eq!(ukt.schema_version, 1);
+		assert!(ukt.validate().is_ok());
+
+		ukt.schema_version = 2;
+		assert!(ukt.validate().is_err());
+	}
+
 	#[tokio::test]
 	#[ignore]
 	/// Connects to the real URL in config to get the OAuth2 token
@@ -0,0 +1,276 @@
+//! Verification of the payloads of file- and HTTP-based sources
+//!
+//! The sync deletes the users missing from a source, so a tampered or
+//! truncated feed could drive destructive changes. With verification
+//! configured, a payload is checked against a checksum or signature
+//! before any user is read from it, and a payload failing the check
+//! fails the sync.
+use std::{
+	fs,
+	path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use hmac::{Hmac, Mac};
+use reqwest::header::HeaderMap;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+/// The prefix some signers put before hex-encoded signatures
+const SIGNATURE_PREFIX: &str = "sha256=";
+
+/// How to verify the payload of a source
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PayloadVerification {
+	/// Compare the SHA-256 checksum of a file with a manifest in the
+	/// format of `sha256sum`
+	Sha256Manifest {
+		/// The path to the manifest
+		path: PathBuf,
+	},
+	/// Check the hex-encoded HMAC-SHA256 signature of a file, stored in
+	/// a detached file
+	HmacSignatureFile {
+		/// The path to the signature file
+		path: PathBuf,
+		/// The key shared with the signer
+		signing_key: String,
+	},
+	/// Check the hex-encoded HMAC-SHA256 signature of an HTTP response,
+	/// sent in a header
+	HmacHeader {
+		/// The name of the header
+		header: String,
+		/// The key shared with the signer
+		signing_key: String,
+	},
+}
+
+impl PayloadVerification {
+	/// Check that the verification applies to file-based sources
+	pub(crate) fn validate_for_files(&self) -> Result<()> {
+		match self {
+			Self::Sha256Manifest { .. } => Ok(()),
+			Self::HmacSignatureFile { signing_key, .. } => validate_signing_key(signing_key),
+			Self::HmacHeader { .. } => {
+				bail!("`hmac_header` verification only applies to HTTP-based sources")
+			}
+		}
+	}
+
+	/// Check that the verification applies to HTTP-based sources
+	pub(crate) fn validate_for_http(&self) -> Result<()> {
+		match self {
+			Self::HmacHeader { signing_key, .. } => validate_signing_key(signing_key),
+			Self::Sha256Manifest { .. } | Self::HmacSignatureFile { .. } => {
+				bail!("Only `hmac_header` verification applies to HTTP-based sources")
+			}
+		}
+	}
+
+	/// Verify the payload read from the file at the given path
+	pub(crate) fn verify_file(&self, file_path: &Path, payload: &[u8]) -> Result<()> {
+		match self {
+			Self::Sha256Manifest { path } => verify_manifest(path, file_path, payload),
+			Self::HmacSignatureFile { path, signing_key } => {
+				let signature = fs::read_to_string(path)
+					.context(format!("Failed to read signature file {}", path.display()))?;
+				verify_hmac(signing_key, &signature, payload)
+					.context(format!("Failed to verify {}", file_path.display()))
+			}
+			Self::HmacHeader { .. } => {
+				bail!("`hmac_header` verification only applies to HTTP-based sources")
+			}
+		}
+	}
+
+	/// Verify the payload of an HTTP response with the given headers
+	pub(crate) fn verify_response(&self, headers: &HeaderMap, payload: &[u8]) -> Result<()> {
+		match self {
+			Self::HmacHeader { header, signing_key } => {
+				let signature = headers
+					.get(header)
+					.context(format!("Response lacks the signature header `{header}`"))?
+					.to_str()
+					.context(format!("Invalid signature header `{header}`"))?;
+				verify_hmac(signing_key, signature, payload)
+			}
+			Self::Sha256Manifest { .. } | Self::HmacSignatureFile { .. } => {
+				bail!("Only `hmac_header` verification applies to HTTP-based sources")
+			}
+		}
+	}
+}
+
+/// Check that a signing key is set
+fn validate_signing_key(signing_key: &str) -> Result<()> {
+	if signing_key.is_empty() {
+		bail!("The `signing_key` of payload verification must not be empty");
+	}
+
+	Ok(())
+}
+
+/// Compare the SHA-256 checksum of a file with its entry in a manifest
+///
+/// Manifests list one `<checksum>  <file name>` per line; a manifest
+/// with a single entry may omit the file name.
+fn verify_manifest(manifest_path: &Path, file_path: &Path, payload: &[u8]) -> Result<()> {
+	let manifest = fs::read_to_string(manifest_path)
+		.context(format!("Failed to read checksum manifest {}", manifest_path.display()))?;
+	let file_name = file_path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+
+	let entries: Vec<(&str, Option<&str>)> = manifest
+		.lines()
+		.map(str::trim)
+		.filter(|line| !line.is_empty())
+		.map(|line| match line.split_once(char::is_whitespace) {
+			Some((checksum, name)) => (checksum, Some(name.trim().trim_start_matches('*'))),
+			None => (line, None),
+		})
+		.collect();
+
+	let expected = match entries.as_slice() {
+		[(checksum, None)] => *checksum,
+		_ => entries
+			.iter()
+			.find(|(_, name)| {
+				name.is_some_and(|name| {
+					Path::new(name).file_name().and_then(|name| name.to_str()) == Some(file_name)
+				})
+			})
+			.map(|(checksum, _)| *checksum)
+			.context(format!(
+				"Checksum manifest {} has no entry for {}",
+				manifest_path.display(),
+				file_name
+			))?,
+	};
+
+	let actual = hex::encode(Sha256::digest(payload));
+	if !actual.eq_ignore_ascii_case(expected) {
+		bail!(
+			"Checksum of {} doesn't match the manifest, the file may have been tampered with or \
+			 truncated: expected {}, got {}",
+			file_path.display(),
+			expected,
+			actual
+		);
+	}
+
+	Ok(())
+}
+
+/// Check a hex-encoded HMAC-SHA256 signature of a payload
+fn verify_hmac(signing_key: &str, signature: &str, payload: &[u8]) -> Result<()> {
+	let signature = signature.trim();
+	let signature = signature.strip_prefix(SIGNATURE_PREFIX).unwrap_or(signature);
+	let signature = hex::decode(signature).context("Signature is not hex-encoded")?;
+
+	let mut mac =
+		Hmac::<Sha256>::new_from_slice(signing_key.as_bytes()).context("Invalid signing key")?;
+	mac.update(payload);
+	mac.verify_slice(&signature).map_err(|_| {
+		anyhow::anyhow!(
+			"Signature doesn't match the payload, it may have been tampered with or truncated"
+		)
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use reqwest::header::HeaderValue;
+	use tempfile::TempDir;
+
+	use super::*;
+
+	const PAYLOAD: &[u8] = b"email,first_name,last_name,phone\njohn.doe@example.com,John,Doe,\n";
+
+	const SIGNING_KEY: &str = "secret";
+
+	fn sign(payload: &[u8]) -> String {
+		let mut mac = Hmac::<Sha256>::new_from_slice(SIGNING_KEY.as_bytes()).expect("invalid key");
+		mac.update(payload);
+		hex::encode(mac.finalize().into_bytes())
+	}
+
+	#[test]
+	fn test_verify_manifest() {
+		let directory = TempDir::new().expect("failed to create directory");
+		let file_path = directory.path().join("users.csv");
+		let manifest_path = directory.path().join("SHA256SUMS");
+		fs::write(
+			&manifest_path,
+			format!(
+				"{}  other.csv\n{} *users.csv\n",
+				hex::encode(Sha256::digest(b"other")),
+				hex::encode(Sha256::digest(PAYLOAD))
+			),
+		)
+		.expect("failed to write manifest");
+		let verification = PayloadVerification::Sha256Manifest { path: manifest_path.clone() };
+
+		assert!(verification.verify_file(&file_path, PAYLOAD).is_ok());
+		assert!(verification.verify_file(&file_path, &PAYLOAD[..PAYLOAD.len() - 10]).is_err());
+		assert!(verification.verify_file(&directory.path().join("missing.csv"), PAYLOAD).is_err());
+
+		fs::write(&manifest_path, hex::encode(Sha256::digest(PAYLOAD)))
+			.expect("failed to write manifest");
+		assert!(verification.verify_file(&file_path, PAYLOAD).is_ok());
+	}
+
+	#[test]
+	fn test_verify_signature_file() {
+		let directory = TempDir::new().expect("failed to create directory");
+		let signature_path = directory.path().join("users.csv.sig");
+		fs::write(&signature_path, format!("{}\n", sign(PAYLOAD)))
+			.expect("failed to write signature");
+		let verification = PayloadVerification::HmacSignatureFile {
+			path: signature_path,
+			signing_key: SIGNING_KEY.to_owned(),
+		};
+
+		let file_path = directory.path().join("users.csv");
+		assert!(verification.verify_file(&file_path, PAYLOAD).is_ok());
+		assert!(verification.verify_file(&file_path, b"email\n").is_err());
+	}
+
+	#[test]
+	fn test_verify_response() {
+		let verification = PayloadVerification::HmacHeader {
+			header: "x-signature".to_owned(),
+			signing_key: SIGNING_KEY.to_owned(),
+		};
+
+		let mut headers = HeaderMap::new();
+		assert!(verification.verify_response(&headers, PAYLOAD).is_err());
+
+		headers.insert(
+			"x-signature",
+			HeaderValue::from_str(&format!("sha256={}", sign(PAYLOAD))).expect("invalid header"),
+		);
+		assert!(verification.verify_response(&headers, PAYLOAD).is_ok());
+		assert!(verification.verify_response(&headers, b"[]").is_err());
+	}
+
+	#[test]
+	fn test_validate() {
+		let header = PayloadVerification::HmacHeader {
+			header: "x-signature".to_owned(),
+			signing_key: SIGNING_KEY.to_owned(),
+		};
+		assert!(header.validate_for_http().is_ok());
+		assert!(header.validate_for_files().is_err());
+
+		let manifest = PayloadVerification::Sha256Manifest { path: PathBuf::from("SHA256SUMS") };
+		assert!(manifest.validate_for_files().is_ok());
+		assert!(manifest.validate_for_http().is_err());
+
+		let unkeyed = PayloadVerification::HmacSignatureFile {
+			path: PathBuf::from("users.csv.sig"),
+			signing_key: String::new(),
+		};
+		assert!(unkeyed.validate_for_files().is_err());
+	}
+}
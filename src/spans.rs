@@ -0,0 +1,52 @@
+//! Tracing spans carrying the context of log events
+//!
+//! Events are logged within these spans, so that every event carries
+//! the same fields regardless of its message: `run_id` and `source` for
+//! the run, and `operation`, `external_user_id` and `zitadel_id` for
+//! the user being written. Logs can then be queried by these fields
+//! uniformly.
+use tracing::Span;
+use uuid::Uuid;
+
+use crate::{report::Operation, Config};
+
+/// A span covering a run, e.g. a sync, a garbage collection or the
+/// SCIM server, identified by a random run ID
+pub(crate) fn run_span(source: &str) -> Span {
+	tracing::info_span!("run", run_id = %Uuid::new_v4(), source)
+}
+
+/// A span covering an operation on a user. IDs which aren't known,
+/// e.g. the Zitadel ID of a user yet to be imported, are left out.
+pub(crate) fn user_span(
+	operation: Operation,
+	external_user_id: Option<&str>,
+	zitadel_id: Option<&str>,
+) -> Span {
+	tracing::info_span!("user", operation = operation.name(), external_user_id, zitadel_id)
+}
+
+/// The name of the configured source
+pub(crate) fn source_name(config: &Config) -> &'static str {
+	if config.source_merge.is_some() && config.sources.ukt.is_none() {
+		return "merged";
+	}
+
+	let sources = &config.sources;
+	match (
+		&sources.csv,
+		&sources.ldap,
+		&sources.fhir,
+		&sources.entra,
+		&sources.keycloak,
+		&sources.ukt,
+	) {
+		(Some(_), _, _, _, _, _) => "csv",
+		(_, Some(_), _, _, _, _) => "ldap",
+		(_, _, Some(_), _, _, _) => "fhir",
+		(_, _, _, Some(_), _, _) => "entra",
+		(_, _, _, _, Some(_), _) => "keycloak",
+		(_, _, _, _, _, Some(_)) => "ukt",
+		_ => "none",
+	}
+}
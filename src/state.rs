@@ -0,0 +1,606 @@
+//! State persisted between syncs
+use std::{collections::BTreeMap, path::Path};
+
+use anyhow::{Context, Result};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+/// State persisted between syncs
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+pub struct SyncState {
+	/// The Zitadel organization the state belongs to
+	pub organization_id: String,
+	/// When the first sync against the organization finished
+	pub first_sync_at: String,
+	/// When the last sync against the organization finished
+	pub last_sync_at: String,
+	/// The base64-encoded DirSync cookie describing the state of the
+	/// LDAP source at the last sync
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub dirsync_cookie: Option<String>,
+	/// The delta link describing the state of the Entra ID source at
+	/// the last sync
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub entra_delta_link: Option<String>,
+	/// When the last incremental and full syncs from LDAP which applied
+	/// all changes started
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub incremental_sync: Option<IncrementalSyncMarks>,
+	/// The number of enabled source users at the last sync
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub source_user_count: Option<usize>,
+	/// Changes users made to their own Zitadel accounts, by Zitadel ID
+	/// and field
+	#[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+	pub self_service_changes: BTreeMap<String, BTreeMap<String, SelfServiceChange>>,
+	/// The hashed source values of the users at the last sync, by
+	/// external user ID and field, for drift classification
+	#[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+	pub synced_values: BTreeMap<String, BTreeMap<String, String>>,
+	/// The source versions of the users at the last sync which applied
+	/// all changes
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub source_versions: Option<SourceVersionMarks>,
+	/// The failed reads of each source in a row, by source name
+	#[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+	pub source_failures: BTreeMap<String, SourceFailures>,
+}
+
+/// When the last incremental syncs from LDAP started reading the
+/// source, as RFC 3339 timestamps
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct IncrementalSyncMarks {
+	/// When the last sync, incremental or full, started
+	pub synced_at: String,
+	/// When the last full sync started
+	pub full_synced_at: String,
+}
+
+/// Failed reads of a source in a row, for its circuit breaker
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct SourceFailures {
+	/// The number of reads in a row which failed
+	pub consecutive: u32,
+	/// When the last read failed, as an RFC 3339 timestamp
+	pub last_failure_at: String,
+}
+
+/// The versions of the source users at the last sync, e.g. their LDAP
+/// modification timestamps
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct SourceVersionMarks {
+	/// The fingerprint of the configuration of the sync, since the
+	/// versions only apply to the same configuration
+	pub config_fingerprint: String,
+	/// The versions, by external user ID
+	pub versions: BTreeMap<String, String>,
+}
+
+/// A change a user made to their own Zitadel account
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct SelfServiceChange {
+	/// The value set by the user, unset if it was removed
+	pub value: Option<String>,
+	/// When the user made the change
+	pub changed_at: String,
+}
+
+impl SyncState {
+	/// Load the state from a file, if it exists
+	pub fn load(path: &Path) -> Result<Option<Self>> {
+		if !path.exists() {
+			return Ok(None);
+		}
+
+		let state = std::fs::read(path)
+			.context(format!("Failed to read sync state from {}", path.display()))?;
+		serde_json::from_slice(&state)
+			.context(format!("Invalid sync state in {}", path.display()))
+			.map(Some)
+	}
+
+	/// Load the state of the given organization from a file, if it
+	/// exists. State of other organizations is ignored.
+	pub fn load_for_organization(path: &Path, organization_id: &str) -> Result<Option<Self>> {
+		Ok(Self::load(path)?.filter(|state| state.organization_id == organization_id))
+	}
+
+	/// Write the state to a file
+	pub fn save(&self, path: &Path) -> Result<()> {
+		// Write to a temporary file first, so that a crash doesn't
+		// leave a corrupted state behind
+		let temporary_path = path.with_extension("tmp");
+		std::fs::write(&temporary_path, serde_json::to_vec_pretty(self)?)
+			.context(format!("Failed to write sync state to {}", temporary_path.display()))?;
+		std::fs::rename(&temporary_path, path)
+			.context(format!("Failed to write sync state to {}", path.display()))
+	}
+
+	/// Whether a sync against the given organization finished, according
+	/// to the state at the given path. State written by syncs which
+	/// didn't finish, e.g. failed source reads, doesn't count.
+	pub fn has_synced(path: &Path, organization_id: &str) -> Result<bool> {
+		Ok(Self::load_for_organization(path, organization_id)?
+			.is_some_and(|state| !state.last_sync_at.is_empty()))
+	}
+
+	/// Record a finished sync against the given organization in the
+	/// state at the given path
+	pub fn record_sync(path: &Path, organization_id: &str) -> Result<()> {
+		let now = Utc::now().to_rfc3339();
+		let mut state =
+			Self::load_for_organization(path, organization_id)?.unwrap_or_else(|| Self {
+				organization_id: organization_id.to_owned(),
+				first_sync_at: now.clone(),
+				last_sync_at: String::new(),
+				..Default::default()
+			});
+		if state.first_sync_at.is_empty() {
+			state.first_sync_at.clone_from(&now);
+		}
+		state.last_sync_at = now;
+		state.save(path)
+	}
+
+	/// Get the DirSync cookie stored for the given organization in the
+	/// state at the given path
+	pub fn load_dirsync_cookie(path: &Path, organization_id: &str) -> Result<Option<Vec<u8>>> {
+		Self::load_for_organization(path, organization_id)?
+			.and_then(|state| state.dirsync_cookie)
+			.map(|cookie| BASE64_STANDARD.decode(cookie).context("Invalid DirSync cookie"))
+			.transpose()
+	}
+
+	/// Store the DirSync cookie for the given organization in the
+	/// state at the given path
+	pub fn record_dirsync_cookie(path: &Path, organization_id: &str, cookie: &[u8]) -> Result<()> {
+		let mut state =
+			Self::load_for_organization(path, organization_id)?.unwrap_or_else(|| Self {
+				organization_id: organization_id.to_owned(),
+				first_sync_at: Utc::now().to_rfc3339(),
+				..Default::default()
+			});
+		state.dirsync_cookie = Some(BASE64_STANDARD.encode(cookie));
+		state.save(path)
+	}
+
+	/// Get the Entra ID delta link stored for the given organization in
+	/// the state at the given path
+	pub fn load_entra_delta_link(path: &Path, organization_id: &str) -> Result<Option<String>> {
+		Ok(Self::load_for_organization(path, organization_id)?
+			.and_then(|state| state.entra_delta_link))
+	}
+
+	/// Store the Entra ID delta link for the given organization in the
+	/// state at the given path
+	pub fn record_entra_delta_link(
+		path: &Path,
+		organization_id: &str,
+		delta_link: &str,
+	) -> Result<()> {
+		let mut state =
+			Self::load_for_organization(path, organization_id)?.unwrap_or_else(|| Self {
+				organization_id: organization_id.to_owned(),
+				first_sync_at: Utc::now().to_rfc3339(),
+				..Default::default()
+			});
+		state.entra_delta_link = Some(delta_link.to_owned());
+		state.save(path)
+	}
+
+	/// Get the incremental sync marks stored for the given organization
+	/// in the state at the given path
+	pub fn load_incremental_sync(
+		path: &Path,
+		organization_id: &str,
+	) -> Result<Option<IncrementalSyncMarks>> {
+		Ok(Self::load_for_organization(path, organization_id)?
+			.and_then(|state| state.incremental_sync))
+	}
+
+	/// Store the incremental sync marks for the given organization in
+	/// the state at the given path
+	pub fn record_incremental_sync(
+		path: &Path,
+		organization_id: &str,
+		marks: IncrementalSyncMarks,
+	) -> Result<()> {
+		let mut state =
+			Self::load_for_organization(path, organization_id)?.unwrap_or_else(|| Self {
+				organization_id: organization_id.to_owned(),
+				first_sync_at: Utc::now().to_rfc3339(),
+				..Default::default()
+			});
+		state.incremental_sync = Some(marks);
+		state.save(path)
+	}
+
+	/// Get the number of enabled source users at the last sync against
+	/// the given organization from the state at the given path
+	pub fn load_source_user_count(path: &Path, organization_id: &str) -> Result<Option<usize>> {
+		Ok(Self::load_for_organization(path, organization_id)?
+			.and_then(|state| state.source_user_count))
+	}
+
+	/// Store the number of enabled source users for the given
+	/// organization in the state at the given path
+	pub fn record_source_user_count(
+		path: &Path,
+		organization_id: &str,
+		count: usize,
+	) -> Result<()> {
+		let mut state =
+			Self::load_for_organization(path, organization_id)?.unwrap_or_else(|| Self {
+				organization_id: organization_id.to_owned(),
+				first_sync_at: Utc::now().to_rfc3339(),
+				..Default::default()
+			});
+		state.source_user_count = Some(count);
+		state.save(path)
+	}
+
+	/// Get the self-service changes recorded for the given
+	/// organization in the state at the given path
+	pub fn load_self_service_changes(
+		path: &Path,
+		organization_id: &str,
+	) -> Result<BTreeMap<String, BTreeMap<String, SelfServiceChange>>> {
+		Ok(Self::load_for_organization(path, organization_id)?
+			.map(|state| state.self_service_changes)
+			.unwrap_or_default())
+	}
+
+	/// Store a self-service change of a user of the given organization
+	/// in the state at the given path, replacing earlier changes of the
+	/// same field. Changes before the first sync are not stored, since
+	/// the state marks the first sync as done, and `false` is returned.
+	pub fn record_self_service_change(
+		path: &Path,
+		organization_id: &str,
+		zitadel_id: &str,
+		field: &str,
+		change: SelfServiceChange,
+	) -> Result<bool> {
+		let Some(mut state) = Self::load_for_organization(path, organization_id)? else {
+			return Ok(false);
+		};
+		state
+			.self_service_changes
+			.entry(zitadel_id.to_owned())
+			.or_default()
+			.insert(field.to_owned(), change);
+		state.save(path)?;
+		Ok(true)
+	}
+
+	/// Get the hashed source values of the users at the last sync
+	/// against the given organization from the state at the given path
+	pub fn load_synced_values(
+		path: &Path,
+		organization_id: &str,
+	) -> Result<BTreeMap<String, BTreeMap<String, String>>> {
+		Ok(Self::load_for_organization(path, organization_id)?
+			.map(|state| state.synced_values)
+			.unwrap_or_default())
+	}
+
+	/// Store the hashed source values of the users for the given
+	/// organization in the state at the given path
+	pub fn record_synced_values(
+		path: &Path,
+		organization_id: &str,
+		values: BTreeMap<String, BTreeMap<String, String>>,
+	) -> Result<()> {
+		let mut state =
+			Self::load_for_organization(path, organization_id)?.unwrap_or_else(|| Self {
+				organization_id: organization_id.to_owned(),
+				first_sync_at: Utc::now().to_rfc3339(),
+				..Default::default()
+			});
+		state.synced_values = values;
+		state.save(path)
+	}
+
+	/// Get the source versions of the users at the last sync against the
+	/// given organization from the state at the given path
+	pub fn load_source_versions(
+		path: &Path,
+		organization_id: &str,
+	) -> Result<Option<SourceVersionMarks>> {
+		Ok(Self::load_for_organization(path, organization_id)?
+			.and_then(|state| state.source_versions))
+	}
+
+	/// Store the source versions of the users for the given organization
+	/// in the state at the given path
+	pub fn record_source_versions(
+		path: &Path,
+		organization_id: &str,
+		marks: SourceVersionMarks,
+	) -> Result<()> {
+		let mut state =
+			Self::load_for_organization(path, organization_id)?.unwrap_or_else(|| Self {
+				organization_id: organization_id.to_owned(),
+				first_sync_at: Utc::now().to_rfc3339(),
+				..Default::default()
+			});
+		state.source_versions = Some(marks);
+		state.save(path)
+	}
+
+	/// Get the failed reads in a row of the given source at the syncs
+	/// against the given organization from the state at the given path
+	pub fn load_source_failures(
+		path: &Path,
+		organization_id: &str,
+		source: &str,
+	) -> Result<Option<SourceFailures>> {
+		Ok(Self::load_for_organization(path, organization_id)?
+			.and_then(|mut state| state.source_failures.remove(source)))
+	}
+
+	/// Store the failed reads in a row of the given source for the given
+	/// organization in the state at the given path, removing them after
+	/// a successful read
+	pub fn record_source_failures(
+		path: &Path,
+		organization_id: &str,
+		source: &str,
+		failures: Option<SourceFailures>,
+	) -> Result<()> {
+		// Failed reads don't make a sync, so the time of the first sync
+		// is left for the first finished one
+		let mut state = match Self::load_for_organization(path, organization_id)? {
+			Some(state) => state,
+			None if failures.is_none() => return Ok(()),
+			None => Self { organization_id: organization_id.to_owned(), ..Default::default() },
+		};
+		match failures {
+			Some(failures) => state.source_failures.insert(source.to_owned(), failures),
+			None => state.source_failures.remove(source),
+		};
+		state.save(path)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use tempfile::TempDir;
+
+	use super::*;
+
+	#[test]
+	fn test_record_sync() {
+		let dir = TempDir::new().expect("failed to create tempdir");
+		let path = dir.path().join("state.json");
+
+		assert!(SyncState::load(&path).expect("failed to load state").is_none());
+
+		SyncState::record_sync(&path, "1").expect("failed to record sync");
+		let state = SyncState::load_for_organization(&path, "1")
+			.expect("failed to load state")
+			.expect("state was not saved");
+		assert_eq!(state.first_sync_at, state.last_sync_at);
+
+		SyncState::record_sync(&path, "1").expect("failed to record sync");
+		let updated_state = SyncState::load_for_organization(&path, "1")
+			.expect("failed to load state")
+			.expect("state was not saved");
+		assert_eq!(updated_state.first_sync_at, state.first_sync_at);
+
+		assert!(SyncState::load_for_organization(&path, "2")
+			.expect("failed to load state")
+			.is_none());
+	}
+
+	#[test]
+	fn test_dirsync_cookie() {
+		let dir = TempDir::new().expect("failed to create tempdir");
+		let path = dir.path().join("state.json");
+
+		SyncState::record_dirsync_cookie(&path, "1", &[0, 1, 255])
+			.expect("failed to record cookie");
+		SyncState::record_sync(&path, "1").expect("failed to record sync");
+
+		assert_eq!(
+			SyncState::load_dirsync_cookie(&path, "1").expect("failed to load cookie"),
+			Some(vec![0, 1, 255])
+		);
+		assert_eq!(
+			SyncState::load_dirsync_cookie(&path, "2").expect("failed to load cookie"),
+			None
+		);
+	}
+
+	#[test]
+	fn test_entra_delta_link() {
+		let dir = TempDir::new().expect("failed to create tempdir");
+		let path = dir.path().join("state.json");
+		let delta_link = "https://graph.microsoft.com/v1.0/users/delta?$deltatoken=abc";
+
+		SyncState::record_entra_delta_link(&path, "1", delta_link)
+			.expect("failed to record delta link");
+		SyncState::record_sync(&path, "1").expect("failed to record sync");
+
+		assert_eq!(
+			SyncState::load_entra_delta_link(&path, "1").expect("failed to load delta link"),
+			Some(delta_link.to_owned())
+		);
+		assert_eq!(
+			SyncState::load_entra_delta_link(&path, "2").expect("failed to load delta link"),
+			None
+		);
+	}
+
+	#[test]
+	fn test_incremental_sync_marks() {
+		let dir = TempDir::new().expect("failed to create tempdir");
+		let path = dir.path().join("state.json");
+		let marks = IncrementalSyncMarks {
+			synced_at: "2024-06-02T00:00:00+00:00".to_owned(),
+			full_synced_at: "2024-06-01T00:00:00+00:00".to_owned(),
+		};
+
+		SyncState::record_incremental_sync(&path, "1", marks.clone())
+			.expect("failed to record marks");
+		SyncState::record_sync(&path, "1").expect("failed to record sync");
+
+		assert_eq!(
+			SyncState::load_incremental_sync(&path, "1").expect("failed to load marks"),
+			Some(marks)
+		);
+		assert_eq!(
+			SyncState::load_incremental_sync(&path, "2").expect("failed to load marks"),
+			None
+		);
+	}
+
+	#[test]
+	fn test_source_user_count() {
+		let dir = TempDir::new().expect("failed to create tempdir");
+		let path = dir.path().join("state.json");
+
+		assert_eq!(
+			SyncState::load_source_user_count(&path, "1").expect("failed to load count"),
+			None
+		);
+
+		SyncState::record_source_user_count(&path, "1", 42).expect("failed to record count");
+		SyncState::record_sync(&path, "1").expect("failed to record sync");
+
+		assert_eq!(
+			SyncState::load_source_user_count(&path, "1").expect("failed to load count"),
+			Some(42)
+		);
+		assert_eq!(
+			SyncState::load_source_user_count(&path, "2").expect("failed to load count"),
+			None
+		);
+	}
+
+	#[test]
+	fn test_self_service_changes() {
+		let dir = TempDir::new().expect("failed to create tempdir");
+		let path = dir.path().join("state.json");
+		let change = |value: &str| SelfServiceChange {
+			value: Some(value.to_owned()),
+			changed_at: "2024-01-01T12:00:00Z".to_owned(),
+		};
+
+		assert!(!SyncState::record_self_service_change(&path, "1", "123", "email", change("a"))
+			.expect("failed to record change"));
+		assert!(SyncState::load(&path).expect("failed to load state").is_none());
+
+		SyncState::record_sync(&path, "1").expect("failed to record sync");
+		assert!(SyncState::record_self_service_change(&path, "1", "123", "email", change("a"))
+			.expect("failed to record change"));
+		assert!(SyncState::record_self_service_change(&path, "1", "123", "email", change("b"))
+			.expect("failed to record change"));
+
+		let changes =
+			SyncState::load_self_service_changes(&path, "1").expect("failed to load changes");
+		assert_eq!(changes.get("123").and_then(|changes| changes.get("email")), Some(&change("b")));
+		assert!(SyncState::load_self_service_changes(&path, "2")
+			.expect("failed to load changes")
+			.is_empty());
+	}
+
+	#[test]
+	fn test_synced_values() {
+		let dir = TempDir::new().expect("failed to create tempdir");
+		let path = dir.path().join("state.json");
+		let values = BTreeMap::from([(
+			"john.doe".to_owned(),
+			BTreeMap::from([("phone".to_owned(), "0123456789abcdef".to_owned())]),
+		)]);
+
+		SyncState::record_synced_values(&path, "1", values.clone())
+			.expect("failed to record values");
+		SyncState::record_sync(&path, "1").expect("failed to record sync");
+
+		assert_eq!(
+			SyncState::load_synced_values(&path, "1").expect("failed to load values"),
+			values
+		);
+		assert!(SyncState::load_synced_values(&path, "2")
+			.expect("failed to load values")
+			.is_empty());
+	}
+
+	#[test]
+	fn test_source_versions() {
+		let dir = TempDir::new().expect("failed to create tempdir");
+		let path = dir.path().join("state.json");
+		let marks = SourceVersionMarks {
+			config_fingerprint: "abc".to_owned(),
+			versions: BTreeMap::from([("jdoe".to_owned(), "20240601000000Z".to_owned())]),
+		};
+
+		assert_eq!(
+			SyncState::load_source_versions(&path, "1").expect("failed to load versions"),
+			None
+		);
+
+		SyncState::record_source_versions(&path, "1", marks.clone())
+			.expect("failed to record versions");
+		SyncState::record_sync(&path, "1").expect("failed to record sync");
+
+		assert_eq!(
+			SyncState::load_source_versions(&path, "1").expect("failed to load versions"),
+			Some(marks)
+		);
+		assert_eq!(
+			SyncState::load_source_versions(&path, "2").expect("failed to load versions"),
+			None
+		);
+	}
+
+	#[test]
+	fn test_invalid_state() {
+		let dir = TempDir::new().expect("failed to create tempdir");
+		let path = dir.path().join("state.json");
+		std::fs::write(&path, "not json").expect("failed to write state");
+
+		assert!(SyncState::load(&path).is_err());
+	}
+
+	#[test]
+	fn test_source_failures() {
+		let dir = TempDir::new().expect("failed to create tempdir");
+		let path = dir.path().join("state.json");
+		let failures =
+			SourceFailures { consecutive: 2, last_failure_at: "2026-10-15T02:00:00Z".to_owned() };
+
+		SyncState::record_source_failures(&path, "1", "ldap", Some(failures.clone()))
+			.expect("failed to record failures");
+		assert!(!SyncState::has_synced(&path, "1").expect("failed to load state"));
+		assert_eq!(
+			SyncState::load_source_failures(&path, "1", "ldap").expect("failed to load failures"),
+			Some(failures)
+		);
+		assert!(SyncState::load_source_failures(&path, "1", "csv")
+			.expect("failed to load failures")
+			.is_none());
+
+		SyncState::record_source_failures(&path, "1", "ldap", None)
+			.expect("failed to record failures");
+		assert!(SyncState::load_source_failures(&path, "1", "ldap")
+			.expect("failed to load failures")
+			.is_none());
+
+		SyncState::record_sync(&path, "1").expect("failed to record sync");
+		assert!(SyncState::has_synced(&path, "1").expect("failed to load state"));
+		let state = SyncState::load_for_organization(&path, "1")
+			.expect("failed to load state")
+			.expect("state was not saved");
+		assert_eq!(state.first_sync_at, state.last_sync_at);
+
+		// A successful read without earlier failures doesn't create
+		// the state
+		let other_path = dir.path().join("other.json");
+		SyncState::record_source_failures(&other_path, "1", "ldap", None)
+			.expect("failed to record failures");
+		assert!(!other_path.exists());
+	}
+}
@@ -1,12 +1,57 @@
 //! User data helpers
-use anyhow::{anyhow, Context, Result};
+use std::collections::{BTreeMap, BTreeSet};
+
+use anyhow::{Context, Result};
 use base64::{engine::general_purpose, Engine as _};
+use serde::{Deserialize, Serialize};
 use uuid::{uuid, Uuid};
 use zitadel_rust_client::v2::users::HumanUser;
 
 /// The Famedly UUID namespace to use to generate v5 UUIDs.
 const FAMEDLY_NAMESPACE: Uuid = uuid!("d9979cff-abee-4666-bc88-1ec45a843fb8");
 
+/// The user fields accessible as attributes, e.g. in rules. Any
+/// other attribute refers to the user's metadata.
+pub const USER_FIELDS: &[&str] = &[
+	"first_name",
+	"last_name",
+	"email",
+	"phone",
+	"enabled",
+	"preferred_username",
+	"external_user_id",
+	"localpart",
+];
+
+/// Where to take a missing first or last name from
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum NameFallback {
+	/// The user's preferred username
+	PreferredUsername,
+	/// The configured placeholder
+	Placeholder,
+	/// The user's common name, e.g. the LDAP `cn`, split into a first
+	/// and last name at the last space
+	CommonName,
+}
+
+/// Fallbacks for users lacking a first or last name, both of which
+/// Zitadel requires
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct NameFallbackConfig {
+	/// The fallbacks to try, in order
+	pub fallbacks: Vec<NameFallback>,
+	/// The name used by the `placeholder` fallback
+	#[serde(default = "default_name_placeholder")]
+	pub placeholder: String,
+}
+
+/// Default for [`NameFallbackConfig::placeholder`]
+fn default_name_placeholder() -> String {
+	"-".to_owned()
+}
+
 /// The encoding of the external ID in the database
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum ExternalIdEncoding {
@@ -21,7 +66,7 @@ pub enum ExternalIdEncoding {
 }
 
 /// Source-agnostic representation of a user
-#[derive(Clone)]
+#[derive(Clone, Deserialize, Serialize)]
 pub struct User {
 	/// The user's first name
 	pub(crate) first_name: String,
@@ -39,6 +84,57 @@ pub struct User {
 	pub(crate) external_user_id: String,
 	/// The user's localpart (used as Zitadel userId)
 	pub(crate) localpart: Option<String>,
+	/// Additional attributes, synced as Zitadel metadata
+	#[serde(default)]
+	pub(crate) metadata: BTreeMap<String, String>,
+	/// Project roles granted in addition to the default role
+	#[serde(default)]
+	pub(crate) roles: BTreeSet<String>,
+	/// The DNs of the user's groups in the source, which rules grant
+	/// roles by, and which aren't synced
+	#[serde(skip)]
+	pub(crate) groups: BTreeSet<String>,
+	/// The user's common name in the source, which a missing first or
+	/// last name can be derived from, and which isn't synced
+	#[serde(skip)]
+	pub(crate) common_name: Option<String>,
+	/// The version of the user in the source, e.g. its LDAP
+	/// modification timestamp, which isn't synced
+	#[serde(skip)]
+	pub(crate) source_version: Option<String>,
+	/// Why the user couldn't be read in full from the source, in which
+	/// case its Zitadel counterpart is left untouched, which isn't
+	/// synced
+	#[serde(skip)]
+	pub(crate) unreadable: Option<Unreadable>,
+	/// Whether the preferred username of the Zitadel user failed to be
+	/// read, in which case it is left as it is, which isn't synced
+	#[serde(skip)]
+	pub(crate) preferred_username_unknown: bool,
+}
+
+/// Why a source user couldn't be read in full
+///
+/// The sync must neither update nor delete the Zitadel counterpart of
+/// such a user, since it can't tell what the user should look like.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Unreadable {
+	/// The user lacks the given required attribute, and is skipped
+	/// for lacking it
+	MissingAttribute(String),
+	/// The entry of the user failed to parse with the given error
+	ParseFailure(String),
+}
+
+impl std::fmt::Display for Unreadable {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::MissingAttribute(attribute) => {
+				write!(f, "lacks the required attribute `{attribute}`")
+			}
+			Self::ParseFailure(error) => write!(f, "failed to parse: {error}"),
+		}
+	}
 }
 
 impl User {
@@ -58,48 +154,231 @@ impl User {
 			first_name,
 			last_name,
 			email,
-			phone,
+			phone: non_empty(phone),
 			enabled,
-			preferred_username,
+			preferred_username: non_empty(preferred_username),
+			external_user_id,
+			localpart: non_empty(localpart),
+			metadata: BTreeMap::new(),
+			roles: BTreeSet::new(),
+			groups: BTreeSet::new(),
+			common_name: None,
+			source_version: None,
+			unreadable: None,
+			preferred_username_unknown: false,
+		}
+	}
+
+	/// A placeholder for the source user with the given external ID,
+	/// whose entry failed to parse, so that its Zitadel counterpart is
+	/// left untouched rather than deleted as missing from the source
+	pub(crate) fn unparsable(external_user_id: String, error: String) -> Self {
+		Self {
+			first_name: String::new(),
+			last_name: String::new(),
+			email: String::new(),
+			phone: None,
+			// Whether the user is enabled is unknown, and disabled users
+			// would be deleted
+			enabled: true,
+			preferred_username: None,
 			external_user_id,
-			localpart,
+			localpart: None,
+			metadata: BTreeMap::new(),
+			roles: BTreeSet::new(),
+			groups: BTreeSet::new(),
+			common_name: None,
+			source_version: None,
+			unreadable: Some(Unreadable::ParseFailure(error)),
+			preferred_username_unknown: false,
 		}
 	}
 
+	/// Whether the source entry of the user failed to parse
+	pub(crate) fn is_unparsable(&self) -> bool {
+		matches!(self.unreadable, Some(Unreadable::ParseFailure(_)))
+	}
+
 	/// Convert a Zitadel user to our internal representation
 	pub fn try_from_zitadel_user(user: HumanUser, external_id: String) -> Result<Self> {
-		let first_name = user
-			.profile()
-			.and_then(|profile| profile.given_name())
-			.ok_or(anyhow!("Missing first name for {}", external_id))?
-			.clone();
-
-		let last_name = user
-			.profile()
-			.and_then(|profile| profile.family_name())
-			.ok_or(anyhow!("Missing last name for {}", external_id))?
-			.clone();
-
-		let email = user
-			.email()
-			.and_then(|human_email| human_email.email())
-			.ok_or(anyhow!("Missing email address for {}", external_id))?
-			.clone();
-
-		let phone = user.phone().and_then(|human_phone| human_phone.phone());
+		// Users synced with a single name may lack the other one, which
+		// is then compared as empty
+		let first_name =
+			user.profile().and_then(|profile| profile.given_name()).cloned().unwrap_or_default();
+		let last_name =
+			user.profile().and_then(|profile| profile.family_name()).cloned().unwrap_or_default();
+
+		// Users without an email address are handled according to the
+		// configured `MissingEmailPolicy` by the caller
+		let email =
+			user.email().and_then(|human_email| human_email.email()).cloned().unwrap_or_default();
+
+		// Zitadel returns an empty phone number for users without one
+		let phone = non_empty(user.phone().and_then(|human_phone| human_phone.phone()).cloned());
 
 		Ok(Self {
 			first_name,
 			last_name,
 			email,
-			phone: phone.cloned(),
+			phone,
 			preferred_username: None,
 			external_user_id: external_id,
 			enabled: true,
 			localpart: None,
+			metadata: BTreeMap::new(),
+			roles: BTreeSet::new(),
+			groups: BTreeSet::new(),
+			common_name: None,
+			source_version: None,
+			unreadable: None,
+			preferred_username_unknown: false,
 		})
 	}
 
+	/// Fill in a missing first or last name from the configured
+	/// fallbacks
+	///
+	/// Enabled users still lacking either name are marked as unreadable
+	/// for lacking it, so that they are skipped and reported rather
+	/// than written to Zitadel without a name.
+	pub(crate) fn fill_missing_names(&mut self, config: Option<&NameFallbackConfig>) {
+		for fallback in config.map(|config| config.fallbacks.as_slice()).unwrap_or_default() {
+			if !self.first_name.is_empty() && !self.last_name.is_empty() {
+				break;
+			}
+
+			let Some((first_name, last_name)) = self.fallback_names(*fallback, config) else {
+				continue;
+			};
+
+			tracing::debug!("Filling in missing name of user `{}`", self.external_user_id);
+			if self.first_name.is_empty() {
+				self.first_name = first_name;
+			}
+			if self.last_name.is_empty() {
+				self.last_name = last_name;
+			}
+		}
+
+		let missing_name = if self.first_name.is_empty() {
+			"first_name"
+		} else if self.last_name.is_empty() {
+			"last_name"
+		} else {
+			return;
+		};
+		if self.enabled && self.unreadable.is_none() {
+			tracing::warn!(
+				"Skipping user `{}`, which lacks the `{}` and no `name_fallback` filled it in",
+				self.external_user_id,
+				missing_name
+			);
+			self.unreadable = Some(Unreadable::MissingAttribute(missing_name.to_owned()));
+		}
+	}
+
+	/// The first and last name the given fallback fills in, if any
+	fn fallback_names(
+		&self,
+		fallback: NameFallback,
+		config: Option<&NameFallbackConfig>,
+	) -> Option<(String, String)> {
+		let name = match fallback {
+			NameFallback::PreferredUsername => non_empty(self.preferred_username.clone()),
+			NameFallback::Placeholder => non_empty(config.map(|config| config.placeholder.clone())),
+			NameFallback::CommonName => {
+				return self.common_name.as_deref().and_then(split_common_name);
+			}
+		};
+		name.map(|name| (name.clone(), name))
+	}
+
+	/// Get the value of a user field or metadata attribute
+	#[must_use]
+	pub fn get_attribute(&self, name: &str) -> Option<String> {
+		match name {
+			"first_name" => Some(self.first_name.clone()),
+			"last_name" => Some(self.last_name.clone()),
+			"email" => Some(self.email.clone()),
+			"phone" => self.phone.clone(),
+			"enabled" => Some(self.enabled.to_string()),
+			"preferred_username" => self.preferred_username.clone(),
+			"external_user_id" => Some(self.external_user_id.clone()),
+			"localpart" => self.localpart.clone(),
+			_ => self.metadata.get(name).cloned(),
+		}
+	}
+
+	/// Set a user field or metadata attribute. Empty values unset
+	/// optional fields and metadata.
+	///
+	/// The fields identifying the user (`email`, `external_user_id`
+	/// and `enabled`) are not settable and ignored.
+	pub fn set_attribute(&mut self, name: &str, value: String) {
+		let optional_value = non_empty(Some(value.clone()));
+
+		match name {
+			"first_name" => self.first_name = value,
+			"last_name" => self.last_name = value,
+			"phone" => self.phone = optional_value,
+			"preferred_username" => self.preferred_username = optional_value,
+			"localpart" => self.localpart = optional_value,
+			"email" | "external_user_id" | "enabled" => {
+				tracing::warn!("Ignoring attempt to set `{}` of user", name);
+			}
+			_ => match optional_value {
+				Some(value) => {
+					self.metadata.insert(name.to_owned(), value);
+				}
+				None => {
+					self.metadata.remove(name);
+				}
+			},
+		}
+	}
+
+	/// Set the user's email address
+	pub fn set_email(&mut self, email: String) {
+		self.email = email;
+	}
+
+	/// Set the user's external ID
+	pub fn set_external_user_id(&mut self, external_user_id: String) {
+		self.external_user_id = external_user_id;
+	}
+
+	/// List the attributes which differ from those of an updated
+	/// version of this user, as `(attribute, old value, new value)`
+	#[must_use]
+	pub fn diff(&self, updated: &User) -> Vec<(String, String, String)> {
+		let metadata_keys: BTreeSet<_> =
+			self.metadata.keys().chain(updated.metadata.keys()).collect();
+
+		let mut changes: Vec<_> = USER_FIELDS
+			.iter()
+			.map(|field| (*field).to_owned())
+			.chain(metadata_keys.into_iter().cloned())
+			.filter_map(|attribute| {
+				let old_value = self.get_attribute(&attribute).unwrap_or_default();
+				let new_value = updated.get_attribute(&attribute).unwrap_or_default();
+				(old_value != new_value).then_some((attribute, old_value, new_value))
+			})
+			.collect();
+
+		if self.roles != updated.roles {
+			let format_roles = |roles: &BTreeSet<String>| {
+				roles.iter().map(String::as_str).collect::<Vec<_>>().join(", ")
+			};
+			changes.push((
+				"roles".to_owned(),
+				format_roles(&self.roles),
+				format_roles(&updated.roles),
+			));
+		}
+
+		changes
+	}
+
 	/// Get a display name for this user
 	#[must_use]
 	pub fn get_display_name(&self) -> String {
@@ -220,16 +499,30 @@ impl User {
 	}
 }
 
+/// Treat an empty value as missing. Zitadel returns empty strings for
+/// unset fields such as the phone number, and so may sources.
+pub(crate) fn non_empty(value: Option<String>) -> Option<String> {
+	value.filter(|value| !value.is_empty())
+}
+
+/// Whether two optional values are the same, treating empty values as
+/// missing
+pub(crate) fn same_value(a: Option<&str>, b: Option<&str>) -> bool {
+	a.filter(|a| !a.is_empty()) == b.filter(|b| !b.is_empty())
+}
+
 impl PartialEq for User {
 	fn eq(&self, other: &Self) -> bool {
 		self.first_name == other.first_name
 			&& self.last_name == other.last_name
 			&& self.email == other.email
-			&& self.phone == other.phone
+			&& same_value(self.phone.as_deref(), other.phone.as_deref())
 			&& self.enabled == other.enabled
-			&& self.preferred_username == other.preferred_username
+			&& same_value(self.preferred_username.as_deref(), other.preferred_username.as_deref())
 			&& self.external_user_id == other.external_user_id
-			&& self.localpart == other.localpart
+			&& same_value(self.localpart.as_deref(), other.localpart.as_deref())
+			&& self.metadata == other.metadata
+			&& self.roles == other.roles
 	}
 }
 
@@ -243,11 +536,27 @@ impl std::fmt::Debug for User {
 			.field("preferred_username", &"***")
 			.field("external_user_id", &self.external_user_id)
 			.field("localpart", &self.localpart)
+			.field("metadata", &self.metadata.keys().collect::<Vec<_>>())
+			.field("roles", &self.roles)
 			.field("enabled", &self.enabled)
 			.finish()
 	}
 }
 
+/// Split a common name into a first and last name at the last
+/// whitespace. Single names are used as both.
+fn split_common_name(common_name: &str) -> Option<(String, String)> {
+	let common_name = common_name.trim();
+	if common_name.is_empty() {
+		return None;
+	}
+
+	match common_name.rsplit_once(char::is_whitespace) {
+		Some((first, last)) => Some((first.trim_end().to_owned(), last.to_owned())),
+		None => Some((common_name.to_owned(), common_name.to_owned())),
+	}
+}
+
 /// Helper function for base64 decoding with fallback
 fn decode_base64_or_fallback(id: &str, warning_message: &str) -> String {
 	match general_purpose::STANDARD.decode(id) {
@@ -258,3 +567,98 @@ fn decode_base64_or_fallback(id: &str, warning_message: &str) -> String {
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn user(phone: Option<&str>) -> User {
+		User::new(
+			"John".to_owned(),
+			"Doe".to_owned(),
+			"john.doe@example.com".to_owned(),
+			phone.map(ToOwned::to_owned),
+			true,
+			None,
+			"john.doe".to_owned(),
+			None,
+		)
+	}
+
+	#[test]
+	fn test_fill_missing_names() {
+		let config = NameFallbackConfig {
+			fallbacks: vec![NameFallback::PreferredUsername, NameFallback::Placeholder],
+			placeholder: "-".to_owned(),
+		};
+
+		let mut single_name = user(None);
+		single_name.last_name = String::new();
+		single_name.preferred_username = Some("jdoe".to_owned());
+		single_name.fill_missing_names(Some(&config));
+		assert_eq!(
+			(single_name.first_name.as_str(), single_name.last_name.as_str()),
+			("John", "jdoe")
+		);
+
+		let mut shared = user(None);
+		shared.first_name = String::new();
+		shared.last_name = String::new();
+		shared.fill_missing_names(Some(&config));
+		assert_eq!((shared.first_name.as_str(), shared.last_name.as_str()), ("-", "-"));
+
+		let config = NameFallbackConfig {
+			fallbacks: vec![NameFallback::CommonName, NameFallback::Placeholder],
+			placeholder: "-".to_owned(),
+		};
+		let mut common_name = user(None);
+		common_name.first_name = String::new();
+		common_name.common_name = Some("Maria da Silva".to_owned());
+		common_name.fill_missing_names(Some(&config));
+		assert_eq!(
+			(common_name.first_name.as_str(), common_name.last_name.as_str()),
+			("Maria da", "Doe")
+		);
+
+		let mut single_common_name = user(None);
+		single_common_name.first_name = String::new();
+		single_common_name.last_name = String::new();
+		single_common_name.common_name = Some("Reception".to_owned());
+		single_common_name.fill_missing_names(Some(&config));
+		assert_eq!(
+			(single_common_name.first_name.as_str(), single_common_name.last_name.as_str()),
+			("Reception", "Reception")
+		);
+		assert_eq!(split_common_name("  "), None);
+
+		// Users still lacking a name are skipped rather than imported
+		// without it
+		let mut unconfigured = user(None);
+		unconfigured.first_name = String::new();
+		unconfigured.fill_missing_names(None);
+		assert!(unconfigured.first_name.is_empty());
+		assert_eq!(
+			unconfigured.unreadable,
+			Some(Unreadable::MissingAttribute("first_name".to_owned()))
+		);
+
+		let mut disabled = user(None);
+		disabled.last_name = String::new();
+		disabled.enabled = false;
+		disabled.fill_missing_names(None);
+		assert!(disabled.unreadable.is_none());
+	}
+
+	#[test]
+	fn test_empty_values_are_missing() {
+		assert_eq!(user(Some("")).phone, None);
+		assert!(same_value(Some(""), None));
+		assert!(!same_value(Some("+1111111111"), None));
+
+		let mut with_empty_phone = user(None);
+		with_empty_phone.phone = Some(String::new());
+		assert_eq!(with_empty_phone, user(None));
+		assert_ne!(user(Some("+1111111111")), user(None));
+		assert!(with_empty_phone.diff(&user(None)).is_empty());
+	}
+}
@@ -0,0 +1,221 @@
+//! Short-lived local cache of the Zitadel user listing
+//!
+//! Read-only commands such as `--explain-user` list all Zitadel users
+//! along with their metadata and grants, which takes many requests
+//! for large organizations. When run repeatedly, e.g. while debugging
+//! a configuration, the listing is read from a local cache instead.
+//! The cache is discarded once it expires, when the Zitadel
+//! configuration changes, and before syncs write to Zitadel; writes
+//! made by others are only picked up once it expires.
+use std::{
+	path::{Path, PathBuf},
+	time::Duration,
+};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+	get_next_zitadel_user,
+	user::User,
+	zitadel::{Zitadel, ZitadelConfig},
+	Config,
+};
+
+/// Configuration of the Zitadel user cache
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct UserCacheConfig {
+	/// Path to the cache file, which contains personal data
+	pub path: PathBuf,
+	/// How long the cache is used, in seconds
+	#[serde(default = "default_ttl_seconds")]
+	pub ttl_seconds: u64,
+}
+
+/// Default for [`UserCacheConfig::ttl_seconds`]
+fn default_ttl_seconds() -> u64 {
+	300
+}
+
+/// A cached listing of the Zitadel users
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct CachedUsers {
+	/// When the users were listed, in RFC 3339 format
+	cached_at: String,
+	/// The Zitadel configuration the users were listed with
+	zitadel: ZitadelConfig,
+	/// The metadata keys managed by the sync when the users were
+	/// listed
+	metadata_keys: Vec<String>,
+	/// The users within the user scope, along with their Zitadel IDs
+	users: Vec<(User, String)>,
+}
+
+impl CachedUsers {
+	/// Whether the cache can be used with the given configuration
+	fn is_valid(&self, config: &Config, ttl: Duration) -> bool {
+		let fresh = DateTime::parse_from_rfc3339(&self.cached_at)
+			.ok()
+			.and_then(|cached_at| Utc::now().signed_duration_since(cached_at).to_std().ok())
+			.is_some_and(|age| age < ttl);
+
+		fresh
+			&& self.zitadel == config.zitadel
+			&& self.metadata_keys == config.additional_metadata_keys()
+	}
+}
+
+/// List the Zitadel users within the user scope, along with their
+/// metadata and roles, using the cache if it is valid
+pub(crate) async fn list_users(
+	config: &Config,
+	cache_config: &UserCacheConfig,
+) -> Result<Vec<(User, String)>> {
+	let ttl = Duration::from_secs(cache_config.ttl_seconds);
+	match load(&cache_config.path) {
+		Ok(Some(cached)) if cached.is_valid(config, ttl) => {
+			tracing::info!(
+				"Using Zitadel users cached at {} from {}",
+				cached.cached_at,
+				cache_config.path.display()
+			);
+			return Ok(cached.users);
+		}
+		Ok(_) => {}
+		Err(error) => tracing::warn!("Ignoring invalid Zitadel user cache: {:?}", error),
+	}
+
+	let mut zitadel = Zitadel::new(config).await?;
+	let mut stream = zitadel.list_users()?;
+	let mut users = Vec::new();
+	while let Some(user) = get_next_zitadel_user(&mut stream, &mut zitadel).await? {
+		users.push(user);
+	}
+
+	let cached = CachedUsers {
+		cached_at: Utc::now().to_rfc3339(),
+		zitadel: config.zitadel.clone(),
+		metadata_keys: config.additional_metadata_keys(),
+		users,
+	};
+	if let Err(error) = save(&cache_config.path, &cached) {
+		tracing::warn!("Failed to write Zitadel user cache: {:?}", error);
+	}
+
+	Ok(cached.users)
+}
+
+/// Discard the cache, since Zitadel is about to be written to
+pub(crate) fn invalidate(config: &Config) -> Result<()> {
+	let Some(cache_config) = &config.zitadel_cache else {
+		return Ok(());
+	};
+
+	match std::fs::remove_file(&cache_config.path) {
+		Err(error) if error.kind() != std::io::ErrorKind::NotFound => Err(error).context(format!(
+			"Failed to remove Zitadel user cache {}",
+			cache_config.path.display()
+		)),
+		_ => Ok(()),
+	}
+}
+
+/// Load the cache, if it exists
+fn load(path: &Path) -> Result<Option<CachedUsers>> {
+	if !path.exists() {
+		return Ok(None);
+	}
+
+	let cached = std::fs::read(path)
+		.context(format!("Failed to read Zitadel user cache from {}", path.display()))?;
+	serde_json::from_slice(&cached)
+		.context(format!("Invalid Zitadel user cache in {}", path.display()))
+		.map(Some)
+}
+
+/// Write the cache
+fn save(path: &Path, cached: &CachedUsers) -> Result<()> {
+	// Write to a temporary file first, so that concurrent commands
+	// never read a partially written cache
+	let temporary_path = path.with_extension("tmp");
+	std::fs::write(&temporary_path, serde_json::to_vec(cached)?)
+		.context(format!("Failed to write Zitadel user cache to {}", temporary_path.display()))?;
+	std::fs::rename(&temporary_path, path)
+		.context(format!("Failed to write Zitadel user cache to {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+	use indoc::indoc;
+
+	use super::*;
+
+	const EXAMPLE_CONFIG: &str = indoc! {r#"
+        zitadel:
+          url: http://localhost:8080
+          key_file: tests/environment/zitadel/service-user.json
+          organization_id: 1
+          project_id: 1
+          idp_id: 1
+
+        sources:
+          csv:
+            file_path: ./test_users.csv
+    "#};
+
+	#[test]
+	fn test_cache_validity() {
+		let config: Config = serde_yaml::from_str(EXAMPLE_CONFIG).expect("invalid config");
+		let ttl = Duration::from_secs(300);
+
+		let mut cached = CachedUsers {
+			cached_at: Utc::now().to_rfc3339(),
+			zitadel: config.zitadel.clone(),
+			metadata_keys: config.additional_metadata_keys(),
+			users: Vec::new(),
+		};
+		assert!(cached.is_valid(&config, ttl));
+
+		let mut other_config = config.clone();
+		other_config.zitadel.organization_id = "2".to_owned();
+		assert!(!cached.is_valid(&other_config, ttl));
+
+		cached.cached_at = (Utc::now() - chrono::Duration::seconds(301)).to_rfc3339();
+		assert!(!cached.is_valid(&config, ttl));
+	}
+
+	#[test]
+	fn test_save_and_invalidate() {
+		let dir = tempfile::TempDir::new().expect("failed to create tempdir");
+		let path = dir.path().join("users.json");
+		let mut config: Config = serde_yaml::from_str(EXAMPLE_CONFIG).expect("invalid config");
+		config.zitadel_cache = Some(UserCacheConfig { path: path.clone(), ttl_seconds: 300 });
+
+		let user = User::new(
+			"John".to_owned(),
+			"Doe".to_owned(),
+			"john.doe@example.com".to_owned(),
+			None,
+			true,
+			None,
+			"john.doe".to_owned(),
+			None,
+		);
+		let cached = CachedUsers {
+			cached_at: Utc::now().to_rfc3339(),
+			zitadel: config.zitadel.clone(),
+			metadata_keys: Vec::new(),
+			users: vec![(user, "123".to_owned())],
+		};
+		save(&path, &cached).expect("failed to save cache");
+
+		let loaded = load(&path).expect("failed to load cache").expect("cache was not saved");
+		assert_eq!(loaded.users.len(), 1);
+		assert_eq!(loaded.users[0].1, "123");
+
+		invalidate(&config).expect("failed to invalidate cache");
+		assert!(load(&path).expect("failed to load cache").is_none());
+		invalidate(&config).expect("failed to invalidate missing cache");
+	}
+}
@@ -0,0 +1,246 @@
+//! Checking whether the installed sync is outdated
+//!
+//! Deployments tend to run the same version for months, and run into
+//! bugs fixed long ago. With `version_check` configured, every sync
+//! asks the configured version endpoint for the latest release and for
+//! releases with critical fixes, and warns in the log and the sync
+//! report if the installed version is older. The request advertises the
+//! installed version in its user agent. The check never fails a sync.
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::messages::{Language, Message};
+
+/// The installed version of the sync
+const INSTALLED_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Configuration of the check for newer versions of the sync
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct VersionCheckConfig {
+	/// The URL of the version endpoint
+	pub url: Url,
+	/// Timeout for the request in seconds
+	#[serde(default = "default_timeout")]
+	pub timeout: u64,
+}
+
+/// Default for [`VersionCheckConfig::timeout`]
+fn default_timeout() -> u64 {
+	10
+}
+
+/// The releases published by the version endpoint
+#[derive(Debug, Clone, Deserialize)]
+struct VersionManifest {
+	/// The latest version
+	latest: String,
+	/// Releases fixing critical bugs
+	#[serde(default)]
+	critical_fixes: Vec<CriticalFix>,
+}
+
+/// A release fixing a critical bug
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct CriticalFix {
+	/// The first version containing the fix
+	pub fixed_in: String,
+	/// A description of the bug
+	pub description: String,
+}
+
+/// A notice that the installed sync is outdated
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct VersionNotice {
+	/// The installed version
+	pub installed: String,
+	/// The latest version
+	pub latest: String,
+	/// The critical fixes the installed version lacks
+	pub missing_critical_fixes: Vec<CriticalFix>,
+}
+
+/// Check whether the installed sync is outdated, logging a warning if
+/// it is. Failures of the check are logged and otherwise ignored.
+pub(crate) async fn check_version(
+	config: &VersionCheckConfig,
+	language: Language,
+) -> Option<VersionNotice> {
+	let manifest = match fetch_manifest(config).await {
+		Ok(manifest) => manifest,
+		Err(error) => {
+			tracing::warn!("Failed to check for newer versions of famedly-sync: {:?}", error);
+			return None;
+		}
+	};
+
+	let notice = compare_versions(INSTALLED_VERSION, &manifest)?;
+	for message in notice.messages() {
+		tracing::warn!("{}", message.render(language));
+	}
+
+	Some(notice)
+}
+
+/// Fetch the releases from the version endpoint
+async fn fetch_manifest(config: &VersionCheckConfig) -> Result<VersionManifest> {
+	let client = Client::builder()
+		.timeout(Duration::from_secs(config.timeout))
+		.user_agent(format!("famedly-sync/{INSTALLED_VERSION} ({})", env!("VERGEN_GIT_SHA")))
+		.build()
+		.context("Failed to build the version check client")?;
+
+	let response = client.get(config.url.clone()).send().await?;
+	response.error_for_status_ref().context("Version endpoint received non-OK status code")?;
+
+	response.json().await.context("Failed to deserialize the version manifest")
+}
+
+/// Compare the installed version with the published releases,
+/// returning a notice if it is outdated
+fn compare_versions(installed: &str, manifest: &VersionManifest) -> Option<VersionNotice> {
+	let Some(installed_version) = parse_version(installed) else {
+		tracing::warn!("Can't compare the installed version `{}`", installed);
+		return None;
+	};
+	let is_older = |version: &str| match parse_version(version) {
+		Some(version) => installed_version < version,
+		None => {
+			tracing::warn!("Ignoring invalid version `{}` of the version endpoint", version);
+			false
+		}
+	};
+
+	if !is_older(&manifest.latest) {
+		return None;
+	}
+
+	Some(VersionNotice {
+		installed: installed.to_owned(),
+		latest: manifest.latest.clone(),
+		missing_critical_fixes: manifest
+			.critical_fixes
+			.iter()
+			.filter(|fix| is_older(&fix.fixed_in))
+			.cloned()
+			.collect(),
+	})
+}
+
+/// Parse a version of the form `major.minor.patch`, ignoring any
+/// pre-release or build suffix
+fn parse_version(version: &str) -> Option<[u64; 3]> {
+	let version = version.trim().trim_start_matches('v');
+	let core = version.split(['-', '+']).next()?;
+
+	let mut parts = core.split('.').map(str::parse::<u64>);
+	let version = [parts.next()?.ok()?, parts.next()?.ok()?, parts.next()?.ok()?];
+	parts.next().is_none().then_some(version)
+}
+
+impl VersionNotice {
+	/// The messages to show the operator
+	pub(crate) fn messages(&self) -> Vec<Message<'_>> {
+		std::iter::once(Message::OutdatedVersion {
+			installed: &self.installed,
+			latest: &self.latest,
+		})
+		.chain(self.missing_critical_fixes.iter().map(|fix| Message::MissingCriticalFix {
+			fixed_in: &fix.fixed_in,
+			description: &fix.description,
+		}))
+		.collect()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use reqwest::StatusCode;
+	use wiremock::{
+		matchers::{header_regex, method, path},
+		Mock, MockServer, ResponseTemplate,
+	};
+
+	use super::*;
+
+	fn manifest(latest: &str, critical_fixes: &[(&str, &str)]) -> VersionManifest {
+		VersionManifest {
+			latest: latest.to_owned(),
+			critical_fixes: critical_fixes
+				.iter()
+				.map(|(fixed_in, description)| CriticalFix {
+					fixed_in: (*fixed_in).to_owned(),
+					description: (*description).to_owned(),
+				})
+				.collect(),
+		}
+	}
+
+	#[test]
+	fn test_parse_version() {
+		assert_eq!(parse_version("0.9.0"), Some([0, 9, 0]));
+		assert_eq!(parse_version("v1.10.2"), Some([1, 10, 2]));
+		assert_eq!(parse_version("1.0.0-rc.1"), Some([1, 0, 0]));
+		assert_eq!(parse_version("1.0"), None);
+		assert_eq!(parse_version("1.0.0.0"), None);
+		assert_eq!(parse_version("latest"), None);
+	}
+
+	#[test]
+	fn test_compare_versions() {
+		let releases = manifest(
+			"0.12.1",
+			&[("0.9.2", "Deletes users on LDAP timeouts"), ("0.11.0", "Drops phone numbers")],
+		);
+
+		let notice = compare_versions("0.10.0", &releases).expect("0.10.0 is outdated");
+		assert_eq!(notice.latest, "0.12.1");
+		let fixed_in: Vec<_> =
+			notice.missing_critical_fixes.iter().map(|fix| fix.fixed_in.as_str()).collect();
+		assert_eq!(fixed_in, vec!["0.11.0"]);
+		assert_eq!(notice.messages().len(), 2);
+
+		// Versions are compared numerically, not as strings
+		let notice = compare_versions("0.9.10", &releases).expect("0.9.10 is outdated");
+		assert_eq!(notice.missing_critical_fixes.len(), 1);
+
+		assert_eq!(compare_versions("0.12.1", &releases), None);
+		assert_eq!(compare_versions("0.13.0", &releases), None);
+		assert_eq!(compare_versions("0.10.0", &manifest("unknown", &[])), None);
+	}
+
+	#[tokio::test]
+	async fn test_check_version() {
+		let mock_server = MockServer::start().await;
+		let config = VersionCheckConfig {
+			url: Url::parse(&format!("{}/famedly-sync.json", mock_server.uri()))
+				.expect("invalid URL"),
+			timeout: 5,
+		};
+
+		Mock::given(method("GET"))
+			.and(path("/famedly-sync.json"))
+			.and(header_regex("User-Agent", &format!("^famedly-sync/{INSTALLED_VERSION} ")))
+			.respond_with(ResponseTemplate::new(StatusCode::OK).set_body_json(serde_json::json!({
+				"latest": "999.0.0",
+				"critical_fixes": [
+					{ "fixed_in": "999.0.0", "description": "Deletes users on LDAP timeouts" },
+					{ "fixed_in": "0.0.1", "description": "Fixed long ago" },
+				],
+			})))
+			.up_to_n_times(1)
+			.mount(&mock_server)
+			.await;
+
+		let notice = check_version(&config, Language::English).await.expect("not outdated");
+		assert_eq!(notice.installed, INSTALLED_VERSION);
+		assert_eq!(notice.latest, "999.0.0");
+		assert_eq!(notice.missing_critical_fixes.len(), 1);
+
+		// Failures of the check are ignored
+		assert_eq!(check_version(&config, Language::English).await, None);
+	}
+}
@@ -0,0 +1,179 @@
+//! Syncing changes as they are made in LDAP
+//!
+//! With `--watch`, the LDAP source is watched for changes, which are
+//! synced within seconds instead of by the next scheduled sync.
+//! Changes arriving within `debounce_seconds` of each other are synced
+//! together. Changes made while the watch was disconnected aren't
+//! observed, so a full sync runs whenever it (re)connects, and after a
+//! sync of changes failed.
+use std::{collections::BTreeMap, pin::pin, time::Duration};
+
+use anyhow::{bail, Context, Result};
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+
+use crate::{
+	daemon::{run_until_shutdown, shutdown_signal},
+	perform_sync, perform_sync_of_changes,
+	sources::ldap::{LdapSource, WatchConfig, WatchEvent},
+	user::User,
+	Config, SourceChanges,
+};
+
+/// Watch the LDAP source for changes and sync them, until the process
+/// receives SIGTERM or SIGINT
+pub async fn watch_ldap(config: &Config) -> Result<()> {
+	if config.source_merge.is_some() {
+		bail!("Watching LDAP can't be used with `source_merge`");
+	}
+	let ldap_config = config.sources.ldap.clone().context("Watching requires an LDAP source")?;
+	let watch_config =
+		ldap_config.watch.clone().context("Watching requires `watch` to be set for LDAP")?;
+
+	let ldap = LdapSource::new(ldap_config);
+	let (sender, events) = mpsc::unbounded_channel();
+
+	// The watch only stops once the events are no longer received
+	tokio::select! {
+		result = ldap.watch(sender) => result,
+		result = sync_changes(config, &watch_config, events) => result,
+	}
+}
+
+/// The changes observed since the last sync
+#[derive(Debug, Default)]
+struct ObservedChanges {
+	/// Whether a full sync is due
+	full_sync: bool,
+	/// The changed users by external ID, `None` for removed users
+	users: BTreeMap<String, Option<User>>,
+}
+
+impl ObservedChanges {
+	/// Add an event to the changes
+	fn add(&mut self, event: WatchEvent) {
+		match event {
+			// The full sync reads all changes anyway
+			WatchEvent::Connected => {
+				self.full_sync = true;
+				self.users.clear();
+			}
+			WatchEvent::Changed(user) => {
+				self.users.insert(user.external_user_id.clone(), Some(user));
+			}
+			WatchEvent::Removed(external_user_id) => {
+				self.users.insert(external_user_id, None);
+			}
+		}
+	}
+
+	/// The changes to sync, unless a full sync is due
+	fn into_source_changes(self) -> Option<SourceChanges> {
+		if self.full_sync {
+			return None;
+		}
+
+		let mut changes = SourceChanges::default();
+		for (external_user_id, user) in self.users {
+			match user {
+				Some(user) => changes.changed.push(user),
+				None => changes.removed.push(external_user_id),
+			}
+		}
+		Some(changes)
+	}
+}
+
+/// Sync the observed changes in batches, until the process receives
+/// SIGTERM or SIGINT
+async fn sync_changes(
+	config: &Config,
+	watch_config: &WatchConfig,
+	mut events: UnboundedReceiver<WatchEvent>,
+) -> Result<()> {
+	let mut shutdown = pin!(shutdown_signal()?);
+	let debounce = Duration::from_secs(watch_config.debounce_seconds);
+	let mut full_sync_due = false;
+
+	loop {
+		let event = tokio::select! {
+			event = events.recv() => event.context("Stopped watching LDAP")?,
+			() = &mut shutdown => {
+				tracing::info!("Shutting down");
+				return Ok(());
+			}
+		};
+
+		let mut changes = ObservedChanges { full_sync: full_sync_due, ..Default::default() };
+		changes.add(event);
+		while let Ok(Some(event)) = tokio::time::timeout(debounce, events.recv()).await {
+			changes.add(event);
+		}
+
+		let result = match changes.into_source_changes() {
+			Some(changes) => {
+				tracing::info!(
+					"Syncing {} changed and {} removed users",
+					changes.changed.len(),
+					changes.removed.len()
+				);
+				run_until_shutdown(perform_sync_of_changes(config, changes), &mut shutdown).await
+			}
+			None => {
+				tracing::info!("Running a full sync");
+				run_until_shutdown(perform_sync(config), &mut shutdown).await
+			}
+		};
+
+		// Failed changes are only retried by a full sync
+		full_sync_due = match result {
+			Some(Ok(report)) => !report.failures.is_empty(),
+			Some(Err(_)) => true,
+			None => return Ok(()),
+		};
+		if full_sync_due {
+			tracing::warn!("Some changes failed to sync, running a full sync with the next change");
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// A user with the given external ID
+	fn user(external_user_id: &str) -> User {
+		User::new(
+			"John".to_owned(),
+			"Doe".to_owned(),
+			format!("{external_user_id}@example.com"),
+			None,
+			true,
+			None,
+			external_user_id.to_owned(),
+			None,
+		)
+	}
+
+	#[test]
+	fn test_observed_changes() {
+		let mut changes = ObservedChanges::default();
+		changes.add(WatchEvent::Changed(user("aa")));
+		changes.add(WatchEvent::Removed("bb".to_owned()));
+		changes.add(WatchEvent::Changed(user("bb")));
+		changes.add(WatchEvent::Removed("cc".to_owned()));
+
+		let source_changes = changes.into_source_changes().expect("no full sync is due");
+		let changed: Vec<_> =
+			source_changes.changed.iter().map(|user| user.external_user_id.as_str()).collect();
+		assert_eq!(changed, ["aa", "bb"]);
+		assert_eq!(source_changes.removed, ["cc"]);
+
+		// Reconnecting discards the changes, since a full sync reads
+		// them anyway
+		let mut changes = ObservedChanges::default();
+		changes.add(WatchEvent::Removed("aa".to_owned()));
+		changes.add(WatchEvent::Connected);
+		changes.add(WatchEvent::Changed(user("bb")));
+		assert!(changes.into_source_changes().is_none());
+	}
+}
@@ -0,0 +1,205 @@
+//! Watchdog aborting syncs which stop making progress
+//!
+//! The sync reports its progress to a global state, which the
+//! watchdog checks periodically. If no progress is made for the
+//! configured time, the state is dumped to the log and the sync is
+//! aborted, so that hung runs can be diagnosed.
+use std::{
+	fmt,
+	future::Future,
+	sync::{Mutex, MutexGuard, PoisonError},
+	time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use serde::Deserialize;
+
+/// The exit code used when the watchdog aborts a sync
+pub const WATCHDOG_EXIT_CODE: u8 = 3;
+
+/// The progress of the running sync
+static STATE: Mutex<ProgressState> = Mutex::new(ProgressState {
+	phase: "starting",
+	last_user: None,
+	progress_count: 0,
+	last_progress: None,
+	in_flight: Vec::new(),
+	next_operation_id: 0,
+});
+
+/// Configuration for the watchdog
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct WatchdogConfig {
+	/// The number of minutes without progress after which the sync
+	/// is aborted
+	pub stall_timeout_minutes: u64,
+}
+
+/// The error returned if the watchdog aborted a sync
+#[derive(Debug)]
+pub struct WatchdogTimeout {
+	/// The time without progress
+	stalled_for: Duration,
+}
+
+impl fmt::Display for WatchdogTimeout {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "Sync aborted by watchdog after {:?} without progress", self.stalled_for)
+	}
+}
+
+impl std::error::Error for WatchdogTimeout {}
+
+/// The progress of the running sync
+#[derive(Debug)]
+struct ProgressState {
+	/// The current phase of the sync
+	phase: &'static str,
+	/// The external ID of the last processed user
+	last_user: Option<String>,
+	/// The number of progress events so far
+	progress_count: u64,
+	/// When progress was last made
+	last_progress: Option<Instant>,
+	/// Operations which have started but not yet finished
+	in_flight: Vec<(u64, String)>,
+	/// The ID of the next tracked operation
+	next_operation_id: u64,
+}
+
+/// Lock the progress state, ignoring poisoning, since the state is
+/// only used for diagnostics
+fn state() -> MutexGuard<'static, ProgressState> {
+	STATE.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Record that the sync entered a new phase
+pub(crate) fn set_phase(phase: &'static str) {
+	let mut state = state();
+	state.phase = phase;
+	state.last_progress = Some(Instant::now());
+}
+
+/// Record that the sync processed a user
+pub(crate) fn record_progress(external_user_id: &str) {
+	let mut state = state();
+	state.last_user = Some(external_user_id.to_owned());
+	state.progress_count += 1;
+	state.last_progress = Some(Instant::now());
+}
+
+/// The current phase of the sync, the number of progress events so
+/// far and the number of operations in flight
+pub(crate) fn progress() -> (&'static str, u64, usize) {
+	let state = state();
+	(state.phase, state.progress_count, state.in_flight.len())
+}
+
+/// An operation tracked as in flight until dropped
+#[derive(Debug)]
+#[must_use]
+pub(crate) struct InFlight(u64);
+
+impl Drop for InFlight {
+	fn drop(&mut self) {
+		state().in_flight.retain(|(id, _)| *id != self.0);
+	}
+}
+
+/// Track an operation as in flight until the returned guard is
+/// dropped
+pub(crate) fn track(operation: String) -> InFlight {
+	let mut state = state();
+	let id = state.next_operation_id;
+	state.next_operation_id += 1;
+	state.in_flight.push((id, operation));
+	InFlight(id)
+}
+
+/// Log the progress state
+fn dump_state(stalled_for: Duration) {
+	let state = state();
+	tracing::error!(
+		"No progress for {:?}; phase: {}, last processed user: {:?}, progress events: {}, in-flight operations: {:?}",
+		stalled_for,
+		state.phase,
+		state.last_user,
+		state.progress_count,
+		state.in_flight.iter().map(|(_, operation)| operation).collect::<Vec<_>>()
+	);
+}
+
+/// Run a future, aborting it if no progress is made for the
+/// configured time
+pub async fn run_with_watchdog<T>(
+	config: Option<&WatchdogConfig>,
+	future: impl Future<Output = Result<T>> + Send,
+) -> Result<T> {
+	match config {
+		Some(config) => {
+			let timeout = Duration::from_secs(config.stall_timeout_minutes.saturating_mul(60));
+			watch(future, timeout, Duration::from_secs(10).min(timeout)).await
+		}
+		None => future.await,
+	}
+}
+
+/// Run a future, checking for progress in the given interval
+async fn watch<T>(
+	future: impl Future<Output = Result<T>> + Send,
+	timeout: Duration,
+	check_interval: Duration,
+) -> Result<T> {
+	set_phase("starting");
+
+	let watchdog = async {
+		loop {
+			tokio::time::sleep(check_interval).await;
+
+			let stalled_for = state().last_progress.map_or(Duration::ZERO, |last| last.elapsed());
+			if stalled_for >= timeout {
+				dump_state(stalled_for);
+				return WatchdogTimeout { stalled_for };
+			}
+		}
+	};
+
+	tokio::select! {
+		result = future => result,
+		stalled = watchdog => Err(stalled.into()),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// Both cases share the global progress state, so they can't run
+	// in parallel
+	#[tokio::test]
+	async fn test_watchdog() {
+		let timeout = Duration::from_millis(200);
+		let check_interval = Duration::from_millis(10);
+
+		let progressing = async {
+			for i in 0..10 {
+				record_progress(&i.to_string());
+				tokio::time::sleep(Duration::from_millis(50)).await;
+			}
+			Ok(())
+		};
+		watch(progressing, timeout, check_interval).await.expect("progressing sync was aborted");
+
+		let stalled = async {
+			set_phase("stalling");
+			let _in_flight = track("sleeping".to_owned());
+			tokio::time::sleep(Duration::from_secs(60)).await;
+			Ok(())
+		};
+		let error = watch(stalled, timeout, check_interval)
+			.await
+			.expect_err("stalled sync was not aborted");
+		assert!(error.is::<WatchdogTimeout>());
+		assert!(state().in_flight.is_empty(), "In-flight operation was not dropped");
+	}
+}
@@ -0,0 +1,149 @@
+//! Batching of concurrent Zitadel writes by user
+//!
+//! Writes are sent to Zitadel in batches of up to the configured
+//! `write_concurrency`, each batch finishing before the next one
+//! starts. Two writes to the same user must never run at once, e.g. an
+//! update racing a deletion, and must be applied in the order they were
+//! made. Batches are therefore partitioned by user: no batch holds two
+//! writes to the same user, and the writes to a user are spread over
+//! successive batches in their original order.
+use std::collections::BTreeMap;
+
+/// Split writes into batches of up to the given number of writes to
+/// distinct users, keeping the order of the writes to each user
+pub(crate) fn partition<T, K: Ord>(
+	writes: impl IntoIterator<Item = T>,
+	size: usize,
+	key: impl Fn(&T) -> K,
+) -> Vec<Vec<T>> {
+	let size = size.max(1);
+	let mut batches: Vec<Vec<T>> = Vec::new();
+	// The last batch holding a write to each user
+	let mut last_batches: BTreeMap<K, usize> = BTreeMap::new();
+	// All batches before this one are full
+	let mut first_open = 0;
+
+	for write in writes {
+		let key = key(&write);
+		let mut index = last_batches.get(&key).map_or(first_open, |last| first_open.max(last + 1));
+		while batches.get(index).is_some_and(|batch| batch.len() >= size) {
+			index += 1;
+		}
+
+		if index == batches.len() {
+			batches.push(Vec::new());
+		}
+		batches[index].push(write);
+		last_batches.insert(key, index);
+
+		while batches.get(first_open).is_some_and(|batch| batch.len() >= size) {
+			first_open += 1;
+		}
+	}
+
+	batches
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		collections::{BTreeSet, HashMap},
+		sync::Mutex,
+	};
+
+	use super::*;
+
+	/// Check that no batch holds two writes to the same user, and that
+	/// the writes to each user keep their order
+	fn assert_partitioned(writes: &[(u32, usize)], batches: &[Vec<(u32, usize)>], size: usize) {
+		let mut seen: HashMap<u32, usize> = HashMap::new();
+		for batch in batches {
+			assert!(!batch.is_empty() && batch.len() <= size);
+			let users: BTreeSet<_> = batch.iter().map(|(user, _)| user).collect();
+			assert_eq!(users.len(), batch.len(), "batch with repeated user: {batch:?}");
+
+			for (user, sequence) in batch {
+				if let Some(previous) = seen.insert(*user, *sequence) {
+					assert!(previous < *sequence, "writes to user {user} reordered");
+				}
+			}
+		}
+		assert_eq!(batches.iter().map(Vec::len).sum::<usize>(), writes.len());
+	}
+
+	/// Writes to a few users, numbered in the order they were made
+	fn writes(count: usize, users: u32) -> Vec<(u32, usize)> {
+		// A linear congruential generator, so that runs are reproducible
+		let mut state: u32 = 12345;
+		(0..count)
+			.map(|sequence| {
+				state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+				((state >> 16) % users, sequence)
+			})
+			.collect()
+	}
+
+	#[test]
+	fn test_partition() {
+		let writes = vec![(1, 0), (2, 1), (1, 2), (3, 3), (1, 4), (2, 5)];
+		let batches = partition(writes.clone(), 2, |(user, _)| *user);
+		assert_eq!(batches, vec![vec![(1, 0), (2, 1)], vec![(1, 2), (3, 3)], vec![(1, 4), (2, 5)]]);
+
+		// Writes to distinct users are batched as they come
+		let batches = partition(0..5, 2, |user| *user);
+		assert_eq!(batches, vec![vec![0, 1], vec![2, 3], vec![4]]);
+
+		// A size of 0 is treated as 1
+		assert_eq!(partition([7, 7], 0, |user| *user), vec![vec![7], vec![7]]);
+
+		for (size, users) in [(1, 5), (4, 3), (10, 7), (50, 200)] {
+			let writes = writes(1000, users);
+			let batches = partition(writes.clone(), size, |(user, _)| *user);
+			assert_partitioned(&writes, &batches, size);
+		}
+	}
+
+	#[tokio::test]
+	async fn test_partition_under_load() {
+		let writes = writes(2000, 13);
+		// The users with a write in flight, and the writes applied to
+		// each user
+		let in_flight = Mutex::new(BTreeSet::new());
+		let applied: Mutex<HashMap<u32, Vec<usize>>> = Mutex::new(HashMap::new());
+
+		for batch in partition(writes.clone(), 8, |(user, _)| *user) {
+			futures::future::join_all(batch.iter().map(|(user, sequence)| {
+				let in_flight = &in_flight;
+				let applied = &applied;
+				async move {
+					assert!(
+						in_flight.lock().expect("poisoned lock").insert(*user),
+						"concurrent writes to user {user}"
+					);
+					// Writes take varying time, so that they interleave
+					for _ in 0..(sequence % 5) {
+						tokio::task::yield_now().await;
+					}
+					applied
+						.lock()
+						.expect("poisoned lock")
+						.entry(*user)
+						.or_default()
+						.push(*sequence);
+					in_flight.lock().expect("poisoned lock").remove(user);
+				}
+			}))
+			.await;
+		}
+
+		let applied = applied.into_inner().expect("poisoned lock");
+		for (user, sequences) in applied {
+			let expected: Vec<_> = writes
+				.iter()
+				.filter(|(write_user, _)| *write_user == user)
+				.map(|(_, sequence)| *sequence)
+				.collect();
+			assert_eq!(sequences, expected, "writes to user {user} reordered");
+		}
+	}
+}
@@ -1,33 +1,84 @@
 //! Helper functions for submitting data to Zitadel
-use std::path::PathBuf;
+use std::{
+	collections::{BTreeMap, BTreeSet, HashSet},
+	path::PathBuf,
+	pin::Pin,
+	sync::{Arc, Mutex, MutexGuard, PoisonError},
+	time::Duration,
+};
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use base64::prelude::{Engine, BASE64_STANDARD};
+use chrono::{DateTime, Utc};
 use futures::{Stream, StreamExt};
 use serde::{Deserialize, Serialize};
 use url::Url;
 use zitadel_rust_client::{
-	v1::Zitadel as ZitadelClientV1,
+	v1::{
+		error::{Error as ZitadelErrorV1, TonicErrorCode},
+		Zitadel as ZitadelClientV1,
+	},
 	v2::{
 		users::{
-			AddHumanUserRequest, IdpLink, InUserEmailsQuery, ListUsersRequest, Organization,
-			SearchQuery, SetHumanEmail, SetHumanPhone, SetHumanProfile, SetMetadataEntry,
-			TypeQuery, UpdateHumanUserRequest, User as ZitadelUser, UserFieldName, Userv2Type,
+			AddHumanUserRequest, IdpLink, InUserEmailsQuery, InUserIdQuery, ListUsersRequest,
+			Organization, SearchQuery, SetHumanEmail, SetHumanPhone, SetHumanProfile,
+			SetMetadataEntry, TypeQuery, UpdateHumanUserRequest, User as ZitadelUser,
+			UserFieldName, UserNameQuery, Userv2Type,
 		},
 		Zitadel as ZitadelClient,
 	},
 };
 
+mod permissions;
+
+pub use permissions::{Capability, PermissionProbe};
+
 use crate::{
-	config::{Config, FeatureFlags},
+	config::{Config, FeatureFlags, GcConfig},
 	get_next_zitadel_user,
-	user::User,
-	FeatureFlag,
+	guests::GuestConfig,
+	hold::is_hold_active,
+	id_mapping::IdMappingStore,
+	intent_log::{Intent, IntentLog},
+	latency,
+	messages::{ConfiguredObject, Language, Message},
+	page_prefetch::{prefetch_pages, time_waits},
+	rate_limit::{throttle_pages, RateLimiter},
+	remap_roles::remap_role_keys,
+	report::{append_json_lines, CollisionKey, Operation},
+	rules,
+	second_factors::second_factor_name,
+	user::{non_empty, same_value, User},
+	watchdog, FeatureFlag,
 };
 
-/// The Zitadel project role to assign to users.
+/// The default Zitadel project role to assign to users
 const FAMEDLY_USER_ROLE: &str = "User";
 
+/// The metadata key marking users whose messenger account is pending
+/// deprovisioning
+pub const PENDING_DEPROVISIONING_KEY: &str = "pending_deprovisioning";
+
+/// The metadata key recording when a user was deactivated instead of
+/// deleted
+pub const DEACTIVATED_AT_KEY: &str = "deactivated_at";
+
+/// The metadata key recording until when the names of a user are kept
+/// as they are in Zitadel, set with `--hold-user`
+pub const SYNC_HOLD_UNTIL_KEY: &str = "sync_hold_until";
+
+/// The default number of users to request per page when listing
+/// users
+const DEFAULT_PAGE_SIZE: usize = 100;
+
+/// The default delay between checks whether an imported user is
+/// listed, in milliseconds
+const DEFAULT_RETRY_INTERVAL_MS: u64 = 500;
+
+/// The default number of concurrent writes of each kind, sending them
+/// one at a time
+const DEFAULT_WRITE_CONCURRENCY: usize = 1;
+
 /// The number of users to sample for encoding detection
 const USER_SAMPLE_SIZE: usize = 50;
 
@@ -40,9 +91,36 @@ pub struct Zitadel {
 	feature_flags: FeatureFlags,
 	/// The backing Zitadel zitadel_client
 	pub zitadel_client: ZitadelClient,
-	/// The backing Ztiadel client, but for v1 API requests - some are
-	/// still required since the v2 API doesn't cover everything
+	/// The backing Zitadel client, but for v1 API requests. The v2 API
+	/// doesn't cover looking up organizations, projects and IDPs,
+	/// permissions, user grants and listing IDP links yet, so these
+	/// are only accessed through wrappers in this module.
 	zitadel_client_v1: ZitadelClientV1,
+	/// Zitadel IDs of listed users without an email address
+	users_without_email: Vec<String>,
+	/// Zitadel IDs of listed users not managed by the sync, by external
+	/// ID
+	unmanaged_users: BTreeMap<String, String>,
+	/// Metadata keys managed in addition to the built-in ones
+	additional_metadata_keys: Vec<String>,
+	/// The project roles granted by rules, which are the only roles
+	/// beyond the default role the sync manages
+	managed_roles: BTreeSet<String>,
+	/// The handling of guest users, if configured
+	guests: Option<GuestConfig>,
+	/// Whether the preferred username is managed
+	manage_preferred_username: bool,
+	/// Path to archive the data of users to before deleting them
+	deletion_archive_path: Option<PathBuf>,
+	/// Mapping of external user IDs to localparts, if configured
+	id_mapping: Option<Arc<Mutex<IdMappingStore>>>,
+	/// The log of writes, if configured and not a dry run
+	intent_log: Option<Arc<Mutex<IntentLog>>>,
+	/// The language of operator-facing messages
+	language: Language,
+	/// The limit of the rate of requests, if configured, shared by all
+	/// clones
+	rate_limiter: Option<Arc<RateLimiter>>,
 }
 
 impl Zitadel {
@@ -63,14 +141,146 @@ impl Zitadel {
 			feature_flags: config.feature_flags.clone(),
 			zitadel_client,
 			zitadel_client_v1,
+			users_without_email: Vec::new(),
+			unmanaged_users: BTreeMap::new(),
+			additional_metadata_keys: config.additional_metadata_keys(),
+			managed_roles: rules::managed_roles(&config.rules),
+			guests: config.guests.clone(),
+			manage_preferred_username: config.syncs_preferred_username(),
+			deletion_archive_path: config.reporting.deletion_archive_path.clone(),
+			id_mapping: config
+				.id_mapping
+				.as_ref()
+				.map(IdMappingStore::open)
+				.transpose()?
+				.map(|store| Arc::new(Mutex::new(store))),
+			intent_log: config
+				.reporting
+				.intent_log_path
+				.as_deref()
+				.filter(|_| !config.feature_flags.is_enabled(FeatureFlag::DryRun))
+				.map(IntentLog::open)
+				.transpose()?
+				.map(|log| Arc::new(Mutex::new(log))),
+			language: config.language,
+			rate_limiter: config.zitadel.max_requests_per_second.map(|requests_per_second| {
+				Arc::new(RateLimiter::new(
+					requests_per_second,
+					config.zitadel.request_burst.unwrap_or(requests_per_second),
+				))
+			}),
 		})
 	}
 
+	/// Wait until the rate limit, if configured, allows another request
+	async fn throttle(&self) {
+		if let Some(rate_limiter) = &self.rate_limiter {
+			rate_limiter.acquire().await;
+		}
+	}
+
+	/// Check that the configured organization, project and IDP exist
+	/// and are accessible to the service user, so that
+	/// misconfigurations are reported before the sync starts
+	pub async fn preflight(&mut self) -> Result<()> {
+		let organization_id = self.zitadel_config.organization_id.clone();
+		let project_id = self.zitadel_config.project_id.clone();
+		let idp_id = self.zitadel_config.idp_id.clone();
+
+		let language = self.language;
+
+		self.throttle().await;
+		self.zitadel_client_v1.get_organization_by_id(&organization_id).await.map_err(|error| {
+			describe_preflight_error(
+				error,
+				ConfiguredObject::Organization { organization_id: &organization_id },
+				language,
+			)
+		})?;
+
+		self.throttle().await;
+		self.zitadel_client_v1
+			.get_project_by_id(&project_id, Some(organization_id.clone()))
+			.await
+			.map_err(|error| {
+				describe_preflight_error(
+					error,
+					ConfiguredObject::Project {
+						project_id: &project_id,
+						organization_id: &organization_id,
+					},
+					language,
+				)
+			})?;
+
+		if self.feature_flags.is_enabled(FeatureFlag::SsoLogin) {
+			self.throttle().await;
+			self.zitadel_client_v1
+				.get_org_idp_by_id(&idp_id, Some(organization_id.clone()))
+				.await
+				.map_err(|error| {
+					describe_preflight_error(
+						error,
+						ConfiguredObject::Idp {
+							idp_id: &idp_id,
+							organization_id: &organization_id,
+						},
+						language,
+					)
+				})?;
+		}
+
+		let required: &[Capability] = if self.feature_flags.is_enabled(FeatureFlag::DryRun) {
+			&[Capability::ListUsers]
+		} else {
+			&Capability::ALL
+		};
+		let probe = self.probe_permissions().await?;
+		let missing: Vec<_> = probe.missing(required).cloned().collect();
+
+		if !missing.is_empty() {
+			let roles: Vec<&str> = probe.missing_roles(required).into_iter().collect();
+			bail!(Message::MissingPermissions {
+				missing: &missing,
+				roles: &roles,
+				organization_id: &organization_id
+			}
+			.render(language));
+		}
+
+		tracing::debug!("Zitadel preflight checks passed");
+
+		Ok(())
+	}
+
+	/// Determine which operations the service user can perform in the
+	/// configured organization
+	pub async fn probe_permissions(&mut self) -> Result<PermissionProbe> {
+		self.throttle().await;
+		let permissions = self
+			.zitadel_client_v1
+			.list_my_zitadel_permissions(Some(self.zitadel_config.organization_id.clone()))
+			.await
+			.context("Failed to list the permissions of the service user")?;
+		let probe = PermissionProbe::from_permissions(&permissions);
+
+		for capability in &probe.allowed {
+			tracing::debug!("Service user can {capability}");
+		}
+		for (capability, permissions) in &probe.denied {
+			tracing::debug!("Service user cannot {capability}, missing {}", permissions.join(", "));
+		}
+
+		Ok(probe)
+	}
+
 	/// Get a list of users by their email addresses
 	pub fn get_users_by_email(
 		&mut self,
 		emails: Vec<String>,
 	) -> Result<impl Stream<Item = Result<(User, String)>> + Send> {
+		let rate_limiter = self.rate_limiter.clone();
+		let organization_id = self.zitadel_config.organization_id.clone();
 		self.zitadel_client
 			.list_users(
 				ListUsersRequest::new(vec![
@@ -80,10 +290,13 @@ impl Zitadel {
 					),
 				])
 				.with_asc(true)
-				.with_sorting_column(UserFieldName::NickName),
+				.with_sorting_column(UserFieldName::NickName)
+				.with_page_size(self.zitadel_config.page_size),
 			)
 			.map(|stream| {
-				stream.map(|user| {
+				let stream = throttle_pages(rate_limiter, self.zitadel_config.page_size, stream);
+				own_users(organization_id, stream.map(Ok)).map(|user| {
+					let user = user?;
 					let id = user.user_id().ok_or(anyhow!("Missing Zitadel user ID"))?.clone();
 					let user = search_result_to_user(user)?;
 					Ok((user, id))
@@ -91,91 +304,1177 @@ impl Zitadel {
 			})
 	}
 
-	/// Return a stream of Zitadel users
-	pub fn list_users(&mut self) -> Result<impl Stream<Item = Result<(User, String)>> + Send> {
-		self.zitadel_client
-			.list_users(
-				ListUsersRequest::new(vec![
-					SearchQuery::new().with_type_query(TypeQuery::new(Userv2Type::Human))
-				])
-				.with_asc(true)
-				.with_sorting_column(UserFieldName::NickName),
-			)
-			.map(|stream| {
-				stream.map(|user| {
-					let id = user.user_id().ok_or(anyhow!("Missing Zitadel user ID"))?.clone();
-					let user = search_result_to_user(user)?;
-					Ok((user, id))
-				})
-			})
+	/// Look for a user of another organization holding the email
+	/// address of a user about to be imported, returning its Zitadel ID
+	/// and organization. Only the organizations whose users the service
+	/// user may read are searched.
+	pub async fn find_cross_org_email_holder(
+		&mut self,
+		imported_user: &User,
+	) -> Result<Option<(String, String)>> {
+		if imported_user.email.is_empty() {
+			return Ok(None);
+		}
+
+		let stream = self.zitadel_client.list_users(
+			ListUsersRequest::new(vec![
+				SearchQuery::new().with_type_query(TypeQuery::new(Userv2Type::Human)),
+				SearchQuery::new().with_in_user_emails_query(
+					InUserEmailsQuery::new().with_user_emails(vec![imported_user.email.clone()]),
+				),
+			])
+			.with_page_size(self.zitadel_config.page_size),
+		)?;
+		let mut stream =
+			throttle_pages(self.rate_limiter.clone(), self.zitadel_config.page_size, stream);
+
+		while let Some(user) = stream.next().await {
+			match resource_owner(&user) {
+				Some(organization_id) if organization_id != self.zitadel_config.organization_id => {
+					let zitadel_id = user.user_id().context("Missing Zitadel user ID")?;
+					return Ok(Some((zitadel_id.clone(), organization_id.to_owned())));
+				}
+				_ => {}
+			}
+		}
+
+		Ok(None)
+	}
+
+	/// Get the Zitadel user with the given ID, without metadata and
+	/// roles
+	pub async fn get_user(&mut self, zitadel_id: &str) -> Result<Option<User>> {
+		self.throttle().await;
+		let mut stream = self.zitadel_client.list_users(
+			ListUsersRequest::new(vec![
+				SearchQuery::new().with_type_query(TypeQuery::new(Userv2Type::Human)),
+				SearchQuery::new().with_in_user_ids_query(
+					InUserIdQuery::new().with_user_ids(vec![zitadel_id.to_owned()]),
+				),
+			])
+			.with_page_size(1),
+		)?;
+
+		stream.next().await.map(search_result_to_user).transpose()
+	}
+
+	/// Return a stream of Zitadel users
+	pub fn list_users(&mut self) -> Result<impl Stream<Item = Result<(User, String)>> + Send> {
+		let rate_limiter = self.rate_limiter.clone();
+		let organization_id = self.zitadel_config.organization_id.clone();
+		let page_size = self.zitadel_config.page_size;
+		let request = |page_size: usize| {
+			ListUsersRequest::new(vec![
+				SearchQuery::new().with_type_query(TypeQuery::new(Userv2Type::Human))
+			])
+			.with_asc(true)
+			.with_sorting_column(UserFieldName::NickName)
+			.with_page_size(page_size)
+		};
+
+		let users: Pin<Box<dyn Stream<Item = Result<ZitadelUser>> + Send>> =
+			match self.zitadel_config.prefetch_pages {
+				0 => {
+					let stream = self.zitadel_client.list_users(request(page_size))?;
+					Box::pin(throttle_pages(rate_limiter, page_size, stream).map(Ok))
+				}
+				pages_ahead => {
+					let zitadel_client = self.zitadel_client.clone();
+					let pages = prefetch_pages(page_size, pages_ahead, move |page| {
+						let zitadel_client = zitadel_client.clone();
+						let rate_limiter = rate_limiter.clone();
+						async move {
+							if let Some(rate_limiter) = &rate_limiter {
+								rate_limiter.acquire().await;
+							}
+							// The client only requests the next page once
+							// the current one is consumed
+							let offset = page * page_size;
+							let stream = zitadel_client
+								.list_users(request(page_size).with_offset(offset))?;
+							let users: Vec<ZitadelUser> = stream.take(page_size).collect().await;
+
+							// The client ends a listing on a failed request
+							// rather than returning the error, so a short
+							// page is only taken for the end of the listing
+							// if no user follows it
+							if users.len() < page_size {
+								let end = offset + users.len();
+								if let Some(rate_limiter) = &rate_limiter {
+									rate_limiter.acquire().await;
+								}
+								let mut rest =
+									zitadel_client.list_users(request(1).with_offset(end))?;
+								if rest.next().await.is_some() {
+									bail!(
+										"The Zitadel user listing ended early after {} users, \
+										 since a page failed to load",
+										end
+									);
+								}
+							}
+
+							Ok(users)
+						}
+					});
+
+					// Pages requested at the wrong offset would repeat
+					// users, which would otherwise be synced twice
+					let mut listed_ids = HashSet::new();
+					Box::pin(pages.map(move |user| {
+						let user = user?;
+						if let Some(zitadel_id) = user.user_id() {
+							if !listed_ids.insert(zitadel_id.clone()) {
+								bail!(
+									"Zitadel user `{}` was listed twice, the pages of the \
+									 listing overlap",
+									zitadel_id
+								);
+							}
+						}
+						Ok(user)
+					}))
+				}
+			};
+
+		Ok(time_waits(own_users(organization_id, users)).map(|user| {
+			let user = user?;
+			let id = user.user_id().ok_or(anyhow!("Missing Zitadel user ID"))?.clone();
+			let user = search_result_to_user(user)?;
+			Ok((user, id))
+		}))
+	}
+
+	/// Whether a user was already imported with the given Zitadel ID,
+	/// e.g. by a previous sync that was interrupted
+	async fn is_previously_imported(
+		&mut self,
+		imported_user: &User,
+		zitadel_id: &str,
+	) -> Result<bool> {
+		// Look the user up by ID first, since its email address may
+		// have been changed in the meantime
+		let query = SearchQuery::new().with_in_user_ids_query(
+			InUserIdQuery::new().with_user_ids(vec![zitadel_id.to_owned()]),
+		);
+		if let Some((_, external_user_id)) = self.find_user_by(query).await? {
+			return Ok(external_user_id.as_deref() == Some(imported_user.external_user_id.as_str()));
+		}
+
+		let mut stream = self.get_users_by_email(vec![imported_user.email.clone()])?;
+
+		while let Some((user, id)) = stream.next().await.transpose()? {
+			if id == zitadel_id && user.external_user_id == imported_user.external_user_id {
+				return Ok(true);
+			}
+		}
+
+		Ok(false)
+	}
+
+	/// Look for the Zitadel user holding a unique attribute of a user
+	/// whose import failed since it already exists, checking the user
+	/// ID (localpart), the username and the email address in turn.
+	/// Users of all organizations and types are searched, since user
+	/// IDs and usernames may be unique across the instance.
+	async fn find_collision(
+		&mut self,
+		imported_user: &User,
+		localpart: &str,
+	) -> Result<Option<UniqueAttributeTaken>> {
+		let mut queries = vec![(
+			CollisionKey::UserId,
+			SearchQuery::new().with_in_user_ids_query(
+				InUserIdQuery::new().with_user_ids(vec![localpart.to_owned()]),
+			),
+		)];
+		// Zitadel uses the email address as the username of the users
+		// the sync imports
+		if !imported_user.email.is_empty() {
+			queries.push((
+				CollisionKey::Username,
+				SearchQuery::new()
+					.with_user_name_query(UserNameQuery::new(imported_user.email.clone())),
+			));
+			queries.push((
+				CollisionKey::Email,
+				SearchQuery::new().with_in_user_emails_query(
+					InUserEmailsQuery::new().with_user_emails(vec![imported_user.email.clone()]),
+				),
+			));
+		}
+
+		for (key, query) in queries {
+			if let Some((zitadel_id, external_user_id)) = self.find_user_by(query).await? {
+				return Ok(Some(UniqueAttributeTaken { key, zitadel_id, external_user_id }));
+			}
+		}
+
+		Ok(None)
+	}
+
+	/// Find the first Zitadel user of any organization and type
+	/// matching a search query, returning its Zitadel ID and its
+	/// external ID, if it has one
+	async fn find_user_by(
+		&mut self,
+		query: SearchQuery,
+	) -> Result<Option<(String, Option<String>)>> {
+		self.throttle().await;
+		let mut stream =
+			self.zitadel_client.list_users(ListUsersRequest::new(vec![query]).with_page_size(1))?;
+
+		let Some(user) = stream.next().await else {
+			return Ok(None);
+		};
+		let zitadel_id = user.user_id().context("Missing Zitadel user ID")?.clone();
+		let external_user_id = user
+			.human()
+			.and_then(|human_user| human_user.profile())
+			.and_then(|profile| profile.nick_name())
+			.filter(|nick_name| !nick_name.is_empty())
+			.cloned();
+
+		Ok(Some((zitadel_id, external_user_id)))
+	}
+
+	/// Wait until an imported user is included in user listings, up to
+	/// the configured number of retries
+	async fn wait_until_listed(&mut self, imported_user: &User) -> Result<()> {
+		let consistency = self.zitadel_config.consistency.clone();
+
+		for _ in 0..consistency.verify_retries {
+			let mut stream = self.get_users_by_email(vec![imported_user.email.clone()])?;
+			while let Some((user, _)) = stream.next().await.transpose()? {
+				if user.external_user_id == imported_user.external_user_id {
+					return Ok(());
+				}
+			}
+
+			tokio::time::sleep(Duration::from_millis(consistency.retry_interval_ms)).await;
+		}
+
+		tracing::warn!(
+			"Imported user `{}` is not listed after {} checks",
+			imported_user.external_user_id,
+			consistency.verify_retries
+		);
+
+		Ok(())
+	}
+
+	/// Wait for Zitadel's projections to catch up with the writes of
+	/// the sync, if configured
+	pub async fn wait_for_projections(&self) {
+		let settle_delay_ms = self.zitadel_config.consistency.settle_delay_ms;
+
+		if settle_delay_ms > 0 && !self.feature_flags.is_enabled(FeatureFlag::DryRun) {
+			tracing::debug!("Waiting {}ms for Zitadel projections to settle", settle_delay_ms);
+			tokio::time::sleep(Duration::from_millis(settle_delay_ms)).await;
+		}
+	}
+
+	/// Get the value of a metadata entry of a Zitadel user, if it
+	/// exists
+	pub async fn get_metadata_value(&mut self, zitadel_id: &str, key: &str) -> Option<String> {
+		self.try_get_metadata_value(zitadel_id, key).await.ok().flatten()
+	}
+
+	/// Get the value of a metadata entry, if it exists, failing if it
+	/// can't be read, e.g. due to a timeout, rather than treating it
+	/// as missing
+	pub async fn try_get_metadata_value(
+		&mut self,
+		zitadel_id: &str,
+		key: &str,
+	) -> Result<Option<String>> {
+		self.throttle().await;
+		match self.zitadel_client.get_user_metadata(zitadel_id, key).await {
+			Ok(metadata) => Ok(non_empty(metadata.metadata().value())),
+			Err(error) if is_not_found(&error) => Ok(None),
+			Err(error) => Err(error)
+				.context(format!("Failed to read metadata `{key}` of user `{zitadel_id}`")),
+		}
+	}
+
+	/// Get the value of a metadata entry managed by the sync, if it
+	/// exists
+	pub async fn get_managed_metadata_value(
+		&mut self,
+		zitadel_id: &str,
+		key: &str,
+	) -> Option<String> {
+		let key = self.zitadel_config.metadata_key(key);
+		self.get_metadata_value(zitadel_id, &key).await
+	}
+
+	/// Get the value of a metadata entry managed by the sync, if it
+	/// exists, failing if it can't be read
+	pub async fn try_get_managed_metadata_value(
+		&mut self,
+		zitadel_id: &str,
+		key: &str,
+	) -> Result<Option<String>> {
+		let key = self.zitadel_config.metadata_key(key);
+		self.try_get_metadata_value(zitadel_id, &key).await
+	}
+
+	/// Whether the names of a user are held, i.e. kept as they are in
+	/// Zitadel, failing if the hold can't be read
+	async fn is_profile_held(&mut self, zitadel_id: &str) -> Result<bool> {
+		Ok(self
+			.try_get_managed_metadata_value(zitadel_id, SYNC_HOLD_UNTIL_KEY)
+			.await?
+			.is_some_and(|hold_until| is_hold_active(&hold_until, Utc::now())))
+	}
+
+	/// Hold the names of a user until the given time, or release the
+	/// hold
+	pub async fn set_profile_hold(
+		&mut self,
+		zitadel_id: &str,
+		hold_until: Option<DateTime<Utc>>,
+	) -> Result<()> {
+		if self.feature_flags.is_enabled(FeatureFlag::DryRun) {
+			tracing::warn!("Skipping hold due to dry run");
+			return Ok(());
+		}
+
+		let key = self.zitadel_config.metadata_key(SYNC_HOLD_UNTIL_KEY);
+		match hold_until {
+			Some(hold_until) => {
+				self.throttle().await;
+				latency::timed(
+					"set metadata",
+					self.zitadel_client.set_user_metadata(
+						zitadel_id,
+						&key,
+						&hold_until.to_rfc3339(),
+					),
+				)
+				.await?;
+			}
+			None if self.get_metadata_value(zitadel_id, &key).await.is_some() => {
+				self.throttle().await;
+				latency::timed(
+					"delete metadata",
+					self.zitadel_client.delete_user_metadata(zitadel_id, &key),
+				)
+				.await?;
+			}
+			None => tracing::info!("User `{}` isn't held", zitadel_id),
+		}
+
+		Ok(())
+	}
+
+	/// Whether a Zitadel user is part of the configured user scope
+	///
+	/// Users whose scope metadata can't be read are taken to be out of
+	/// scope, so that excluded users are never touched due to a failed
+	/// read.
+	pub async fn is_user_in_scope(&mut self, zitadel_id: &str) -> bool {
+		match self.try_is_user_in_scope(zitadel_id).await {
+			Ok(in_scope) => in_scope,
+			Err(error) => {
+				tracing::warn!(
+					"Leaving Zitadel user `{}` untouched, since its user scope is unknown: {:?}",
+					zitadel_id,
+					error
+				);
+				false
+			}
+		}
+	}
+
+	/// Whether a Zitadel user is part of the configured user scope,
+	/// failing if its scope metadata can't be read
+	async fn try_is_user_in_scope(&mut self, zitadel_id: &str) -> Result<bool> {
+		let Some(user_scope) = self.zitadel_config.user_scope.clone() else {
+			return Ok(true);
+		};
+
+		if let Some(include_metadata) = user_scope.include_metadata {
+			let value = self.try_get_metadata_value(zitadel_id, &include_metadata.key).await?;
+			if value.as_ref() != Some(&include_metadata.value) {
+				return Ok(false);
+			}
+		}
+
+		if let Some(exclude_metadata_key) = user_scope.exclude_metadata_key {
+			if self.try_get_metadata_value(zitadel_id, &exclude_metadata_key).await?.is_some() {
+				return Ok(false);
+			}
+		}
+
+		Ok(true)
+	}
+
+	/// Apply the configured [`MissingEmailPolicy`] to a listed user
+	/// without an email address, returning whether the user should
+	/// still be synced
+	pub fn keep_user_without_email(&mut self, zitadel_id: &str) -> bool {
+		self.users_without_email.push(zitadel_id.to_owned());
+		self.zitadel_config.missing_email.keeps_user(zitadel_id)
+	}
+
+	/// Take the Zitadel IDs of the listed users without an email
+	/// address encountered so far
+	pub fn take_users_without_email(&mut self) -> Vec<String> {
+		std::mem::take(&mut self.users_without_email)
+	}
+
+	/// Remember a listed user which isn't managed by the sync, e.g.
+	/// since it is outside of the user scope
+	pub fn record_unmanaged_user(&mut self, external_user_id: &str, zitadel_id: &str) {
+		self.unmanaged_users.insert(external_user_id.to_owned(), zitadel_id.to_owned());
+	}
+
+	/// Look for an existing Zitadel user with the external ID of a user
+	/// about to be imported, which isn't managed by the sync, returning
+	/// its Zitadel ID
+	///
+	/// Besides the listed users skipped as unmanaged, users with the
+	/// same email address are searched, since the listing may not
+	/// include all users of the organization.
+	pub async fn find_unmanaged_user(&mut self, imported_user: &User) -> Result<Option<String>> {
+		if let Some(zitadel_id) = self.unmanaged_users.get(&imported_user.external_user_id) {
+			return Ok(Some(zitadel_id.clone()));
+		}
+		if imported_user.email.is_empty() {
+			return Ok(None);
+		}
+
+		let mut stream = self.get_users_by_email(vec![imported_user.email.clone()])?;
+		while let Some((user, zitadel_id)) = stream.next().await.transpose()? {
+			if user.external_user_id == imported_user.external_user_id {
+				return Ok(Some(zitadel_id));
+			}
+		}
+
+		Ok(None)
+	}
+
+	/// Look for a Zitadel user with another external ID holding the
+	/// email address of a user about to be imported, returning its
+	/// Zitadel and external ID
+	pub async fn find_email_holder(
+		&mut self,
+		imported_user: &User,
+	) -> Result<Option<(String, String)>> {
+		if imported_user.email.is_empty() {
+			return Ok(None);
+		}
+
+		let mut stream = self.get_users_by_email(vec![imported_user.email.clone()])?;
+		while let Some((user, zitadel_id)) = stream.next().await.transpose()? {
+			if user.external_user_id != imported_user.external_user_id {
+				return Ok(Some((zitadel_id, user.external_user_id)));
+			}
+		}
+
+		Ok(None)
+	}
+
+	/// Get the additionally managed metadata of a Zitadel user, failing
+	/// if any entry can't be read
+	pub async fn get_additional_metadata(
+		&mut self,
+		zitadel_id: &str,
+	) -> Result<BTreeMap<String, String>> {
+		let mut metadata = BTreeMap::new();
+
+		for key in self.additional_metadata_keys.clone() {
+			if let Some(value) = self.try_get_managed_metadata_value(zitadel_id, &key).await? {
+				metadata.insert(key, value);
+			}
+		}
+
+		Ok(metadata)
+	}
+
+	/// Get the project roles of a Zitadel user managed by the sync
+	/// beyond the default role, i.e. those granted by rules
+	pub async fn get_additional_roles(&mut self, zitadel_id: &str) -> Result<BTreeSet<String>> {
+		if self.managed_roles.is_empty() {
+			return Ok(BTreeSet::new());
+		}
+
+		let mut roles = self.list_additional_roles(zitadel_id).await?;
+		roles.retain(|role| self.managed_roles.contains(role));
+		Ok(roles)
+	}
+
+	/// List the project roles of a Zitadel user beyond the default
+	/// role, regardless of whether roles are managed
+	async fn list_additional_roles(&mut self, zitadel_id: &str) -> Result<BTreeSet<String>> {
+		Ok(self
+			.get_project_roles(zitadel_id)
+			.await?
+			.into_iter()
+			.filter(|role| {
+				*role != self.zitadel_config.user_role && Some(role.as_str()) != self.guest_role()
+			})
+			.collect())
+	}
+
+	/// The project role granted to guests instead of the default role,
+	/// if configured
+	fn guest_role(&self) -> Option<&str> {
+		self.guests.as_ref().and_then(|guests| guests.role.as_deref())
+	}
+
+	/// The project role granted to the given user in addition to its
+	/// roles, i.e. the guest role for guests and the default role
+	/// otherwise
+	fn user_role(&self, user: &User) -> &str {
+		self.guests
+			.as_ref()
+			.and_then(|guests| guests.role_of(user))
+			.unwrap_or(&self.zitadel_config.user_role)
+	}
+
+	/// Get the roles of the configured project granted to a user,
+	/// including the role granted to all users
+	pub async fn get_project_roles(&mut self, zitadel_id: &str) -> Result<Vec<String>> {
+		Ok(self
+			.get_project_grant(zitadel_id)
+			.await?
+			.map(|grant| grant.role_keys)
+			.unwrap_or_default())
+	}
+
+	/// Grant a Zitadel user the role granted to the user and its
+	/// additional roles, removing the managed roles it no longer has
+	/// and keeping all other roles
+	async fn set_additional_roles(&mut self, zitadel_id: &str, user: &User) -> Result<()> {
+		let Some(grant) = self.get_project_grant(zitadel_id).await? else {
+			let role_keys = get_role_keys(self.user_role(user), &user.roles);
+			return self.add_project_grant(zitadel_id, role_keys).await;
+		};
+
+		let role_keys = rules::merge_role_keys(
+			&grant.role_keys,
+			self.user_role(user),
+			&user.roles,
+			&self.managed_roles,
+		);
+		if role_keys == grant.role_keys {
+			return Ok(());
+		}
+		self.update_grant(zitadel_id, grant.id, role_keys).await
+	}
+
+	/// Replace the role granted to a user in addition to its roles,
+	/// keeping its other roles
+	async fn replace_user_role(&mut self, zitadel_id: &str, from: &str, to: &str) -> Result<()> {
+		let Some(grant) = self.get_project_grant(zitadel_id).await? else {
+			return self.add_project_grant(zitadel_id, vec![to.to_owned()]).await;
+		};
+		if grant.role_keys.iter().any(|role| role == to) {
+			return Ok(());
+		}
+
+		let role_keys = remap_role_keys(&grant.role_keys, from, to).unwrap_or_else(|| {
+			grant.role_keys.iter().cloned().chain(std::iter::once(to.to_owned())).collect()
+		});
+		self.update_grant(zitadel_id, grant.id, role_keys).await
+	}
+
+	// The v2 API doesn't cover user grants, so these wrappers are the
+	// only place grants are accessed through the v1 API
+
+	/// List the grants of a user across all projects
+	async fn list_grants(&mut self, zitadel_id: &str) -> Result<Vec<UserGrant>> {
+		self.throttle().await;
+		let grants = self
+			.zitadel_client_v1
+			.list_user_grants(&self.zitadel_config.organization_id, zitadel_id)
+			.await?;
+
+		Ok(grants
+			.result
+			.into_iter()
+			.map(|grant| UserGrant {
+				id: grant.id,
+				project_id: grant.project_id,
+				role_keys: grant.role_keys,
+			})
+			.collect())
+	}
+
+	/// Get the grant of the configured project to a user, if any
+	async fn get_project_grant(&mut self, zitadel_id: &str) -> Result<Option<UserGrant>> {
+		Ok(self
+			.list_grants(zitadel_id)
+			.await?
+			.into_iter()
+			.find(|grant| grant.project_id == self.zitadel_config.project_id))
+	}
+
+	/// Grant roles of the configured project to a user who has no
+	/// grant of the project yet
+	async fn add_project_grant(&mut self, zitadel_id: &str, role_keys: Vec<String>) -> Result<()> {
+		self.throttle().await;
+		self.zitadel_client_v1
+			.add_user_grant(
+				Some(self.zitadel_config.organization_id.clone()),
+				zitadel_id.to_owned(),
+				self.zitadel_config.project_id.clone(),
+				None,
+				role_keys,
+			)
+			.await?;
+
+		Ok(())
+	}
+
+	/// Replace the roles of an existing grant
+	async fn update_grant(
+		&mut self,
+		zitadel_id: &str,
+		grant_id: String,
+		role_keys: Vec<String>,
+	) -> Result<()> {
+		self.throttle().await;
+		self.zitadel_client_v1
+			.update_user_grant(
+				Some(self.zitadel_config.organization_id.clone()),
+				zitadel_id.to_owned(),
+				grant_id,
+				role_keys,
+			)
+			.await?;
+
+		Ok(())
+	}
+
+	/// Count the Zitadel users managed by the sync, i.e. those the sync
+	/// compares with the source users
+	///
+	/// Users outside of the user scope, and users without an email
+	/// address unless they are matched, are left out, without fetching
+	/// any metadata besides that of the user scope.
+	pub async fn count_users(&mut self) -> Result<usize> {
+		let mut stream = self.list_users()?;
+		let mut count = 0;
+
+		while let Some((user, zitadel_id)) = stream.next().await.transpose()? {
+			if user.email.is_empty()
+				&& self.zitadel_config.missing_email != MissingEmailPolicy::Match
+			{
+				continue;
+			}
+			if self.is_user_in_scope(&zitadel_id).await {
+				count += 1;
+			}
+		}
+
+		Ok(count)
+	}
+
+	/// Return a vector of a random sample of Zitadel users
+	/// We use this to determine the encoding of the external IDs
+	pub async fn get_users_sample(&mut self) -> Result<Vec<User>> {
+		let rate_limiter = self.rate_limiter.clone();
+		let mut stream = self
+			.zitadel_client
+			.list_users(
+				ListUsersRequest::new(vec![
+					SearchQuery::new().with_type_query(TypeQuery::new(Userv2Type::Human))
+				])
+				.with_asc(true)
+				.with_sorting_column(UserFieldName::NickName)
+				.with_page_size(USER_SAMPLE_SIZE),
+			)
+			.map(|stream| {
+				throttle_pages(rate_limiter, USER_SAMPLE_SIZE, stream).map(|user| {
+					let id = user.user_id().ok_or(anyhow!("Missing Zitadel user ID"))?.clone();
+					let user = search_result_to_user(user)?;
+					Ok((user, id))
+				})
+			})?;
+
+		let mut users = Vec::new();
+
+		while let Some(user) = get_next_zitadel_user(&mut stream, self).await? {
+			users.push(user.0);
+		}
+
+		Ok(users)
+	}
+
+	/// Delete a Zitadel user
+	pub async fn delete_user(&mut self, zitadel_id: &str) -> Result<()> {
+		tracing::info!("Deleting user with Zitadel ID: {}", zitadel_id);
+		let _in_flight = watchdog::track(format!("deletion of user `{zitadel_id}`"));
+
+		if self.feature_flags.is_enabled(FeatureFlag::DryRun) {
+			tracing::warn!("Skipping deletion due to dry run");
+			return Ok(());
+		}
+
+		if self.feature_flags.is_enabled(FeatureFlag::DryRunDeletions) {
+			tracing::warn!("Skipping deletion due to deletion dry run");
+			return Ok(());
+		}
+
+		let intent = self.begin_intent(Operation::Delete, zitadel_id, &zitadel_id)?;
+		let result = self.remove_user(zitadel_id).await;
+		self.finish_intent(intent, &result);
+		result
+	}
+
+	/// Deactivate a Zitadel user without ever deleting it
+	pub async fn deactivate_user(&mut self, zitadel_id: &str) -> Result<()> {
+		tracing::info!("Deactivating user with Zitadel ID: {}", zitadel_id);
+		let _in_flight = watchdog::track(format!("deactivation of user `{zitadel_id}`"));
+
+		if self.feature_flags.is_enabled(FeatureFlag::DryRun) {
+			tracing::warn!("Skipping deactivation due to dry run");
+			return Ok(());
+		}
+
+		if self.feature_flags.is_enabled(FeatureFlag::DryRunDeletions) {
+			tracing::warn!("Skipping deactivation due to deletion dry run");
+			return Ok(());
+		}
+
+		let intent = self.begin_intent(Operation::Delete, zitadel_id, &zitadel_id)?;
+		let result = self.mark_deactivated(zitadel_id).await;
+		self.finish_intent(intent, &result);
+		result
+	}
+
+	/// Deactivate a Zitadel user, marking it with the
+	/// [`DEACTIVATED_AT_KEY`] metadata entry
+	async fn mark_deactivated(&mut self, zitadel_id: &str) -> Result<()> {
+		// The time is recorded first, so that a sync failing in between
+		// doesn't leave a deactivated user it doesn't know about
+		self.throttle().await;
+		latency::timed(
+			"set metadata",
+			self.zitadel_client.set_user_metadata(
+				zitadel_id,
+				&self.zitadel_config.metadata_key(DEACTIVATED_AT_KEY),
+				&Utc::now().to_rfc3339(),
+			),
+		)
+		.await?;
+		self.throttle().await;
+		latency::timed("deactivate user", self.zitadel_client.deactivate_user(zitadel_id)).await?;
+		Ok(())
+	}
+
+	/// Delete a Zitadel user, or deactivate it or mark it as pending
+	/// deprovisioning, archiving it first if configured
+	async fn remove_user(&mut self, zitadel_id: &str) -> Result<()> {
+		// Users deactivated before are deleted once their grace period
+		// is over
+		if self.zitadel_config.deprovisioning == DeprovisioningPolicy::Deactivate
+			&& self.try_get_managed_metadata_value(zitadel_id, DEACTIVATED_AT_KEY).await?.is_none()
+		{
+			tracing::info!("Deactivating user `{}` instead", zitadel_id);
+			return self.mark_deactivated(zitadel_id).await;
+		}
+
+		if self.zitadel_config.deprovisioning == DeprovisioningPolicy::MarkPending {
+			tracing::info!("Marking user `{}` as pending deprovisioning instead", zitadel_id);
+			self.throttle().await;
+			latency::timed(
+				"set metadata",
+				self.zitadel_client.set_user_metadata(
+					zitadel_id,
+					&self.zitadel_config.metadata_key(PENDING_DEPROVISIONING_KEY),
+					&Utc::now().to_rfc3339(),
+				),
+			)
+			.await?;
+			return Ok(());
+		}
+
+		if let Some(deletion_archive_path) = self.deletion_archive_path.clone() {
+			let archived_user =
+				latency::timed("archive user", self.get_archived_user(zitadel_id)).await?;
+			append_json_lines(&deletion_archive_path, &[archived_user])
+				.context("Failed to archive user before deletion")?;
+		}
+
+		self.throttle().await;
+		latency::timed("delete user", self.zitadel_client.delete_user(zitadel_id)).await?;
+
+		if let Some(id_mapping) = &self.id_mapping {
+			lock_id_mapping(id_mapping).retire(zitadel_id)?;
+		}
+
+		Ok(())
+	}
+
+	/// Change the external ID of a user in place, preserving its
+	/// Zitadel ID, localpart, grants and metadata
+	pub async fn rename_user(
+		&mut self,
+		zitadel_id: &str,
+		old_user: &User,
+		new_user: &User,
+	) -> Result<()> {
+		tracing::info!(
+			"Renaming user `{}` from `{}` to `{}`",
+			zitadel_id,
+			old_user.external_user_id,
+			new_user.external_user_id
+		);
+
+		let intent = self.begin_intent(Operation::Rename, zitadel_id, new_user)?;
+		let result = self.change_external_id(zitadel_id, old_user, new_user).await;
+		self.finish_intent(intent, &result);
+		result
+	}
+
+	/// Change the external ID of a user, along with its IDP link and
+	/// ID mapping
+	async fn change_external_id(
+		&mut self,
+		zitadel_id: &str,
+		old_user: &User,
+		new_user: &User,
+	) -> Result<()> {
+		// The localpart is the Zitadel ID, so it can't change
+		let renamed_user = User { localpart: old_user.localpart.clone(), ..new_user.clone() };
+		self.update_user(zitadel_id, old_user, &renamed_user).await?;
+
+		if self.feature_flags.is_enabled(FeatureFlag::DryRun) {
+			return Ok(());
+		}
+
+		// Replace the link to the IDP user with the old external ID
+		if self.feature_flags.is_enabled(FeatureFlag::SsoLogin) {
+			let provided_user_id = get_zitadel_encoded_id(new_user.get_external_id_bytes()?);
+			self.throttle().await;
+			self.zitadel_client
+				.add_idp_link(
+					zitadel_id,
+					IdpLink::new()
+						.with_user_id(provided_user_id.clone())
+						.with_idp_id(self.zitadel_config.idp_id.clone())
+						.with_user_name(new_user.email.clone()),
+				)
+				.await?;
+			self.collect_stale_idp_links(zitadel_id, &HashSet::from([provided_user_id]), true)
+				.await?;
+		}
+
+		if let Some(id_mapping) = &self.id_mapping {
+			lock_id_mapping(id_mapping).rename(zitadel_id, &new_user.external_user_id)?;
+		}
+
+		Ok(())
+	}
+
+	/// Record the localpart of a user listed in Zitadel in the ID
+	/// mapping, so that users imported before the mapping was
+	/// configured keep their localparts
+	pub fn record_id_mapping(&self, user: &User, zitadel_id: &str) -> Result<()> {
+		let Some(id_mapping) = &self.id_mapping else {
+			return Ok(());
+		};
+
+		if self.feature_flags.is_enabled(FeatureFlag::DryRun) {
+			return Ok(());
+		}
+
+		let localpart = user.localpart.as_deref().unwrap_or(zitadel_id);
+		lock_id_mapping(id_mapping).observe(&user.external_user_id, localpart, zitadel_id)
+	}
+
+	/// The metadata keys managed by the sync, without namespace
+	fn own_metadata_keys(&self) -> Vec<String> {
+		let mut keys = vec!["localpart".to_owned(), "preferred_username".to_owned()];
+		keys.extend(self.additional_metadata_keys.iter().cloned());
+		keys
+	}
+
+	/// The Zitadel metadata keys managed by the sync
+	fn managed_metadata_keys(&self) -> Vec<String> {
+		let mut keys: Vec<String> = self
+			.own_metadata_keys()
+			.iter()
+			.map(|key| self.zitadel_config.metadata_key(key))
+			.collect();
+
+		if let Some(user_scope) = &self.zitadel_config.user_scope {
+			keys.extend(user_scope.include_metadata.as_ref().map(|entry| entry.key.clone()));
+			keys.extend(user_scope.exclude_metadata_key.clone());
+		}
+
+		keys
+	}
+
+	/// Collect the metadata and grants of a user for archival
+	async fn get_archived_user(&mut self, zitadel_id: &str) -> Result<ArchivedUser> {
+		let mut metadata = BTreeMap::new();
+		for key in self.managed_metadata_keys() {
+			if let Some(value) = self.get_metadata_value(zitadel_id, &key).await {
+				metadata.insert(key, value);
+			}
+		}
+
+		let grants = self
+			.list_grants(zitadel_id)
+			.await?
+			.into_iter()
+			.map(|grant| ArchivedGrant { project_id: grant.project_id, role_keys: grant.role_keys })
+			.collect();
+
+		Ok(ArchivedUser {
+			timestamp: Utc::now().to_rfc3339(),
+			zitadel_id: zitadel_id.to_owned(),
+			metadata,
+			grants,
+		})
+	}
+
+	/// List the second factors a user registered, e.g. `totp` or
+	/// `passkey`
+	pub async fn list_second_factors(&mut self, zitadel_id: &str) -> Result<Vec<String>> {
+		self.throttle().await;
+		let response = latency::timed(
+			"list authentication methods",
+			self.zitadel_client.list_authentication_method_types(zitadel_id),
+		)
+		.await?;
+
+		// The methods are only matched by name, so that methods added
+		// by newer Zitadel versions count as second factors
+		Ok(response
+			.auth_method_types()
+			.into_iter()
+			.flatten()
+			.filter_map(|method| serde_json::to_value(method).ok())
+			.filter_map(|method| method.as_str().and_then(second_factor_name))
+			.collect())
+	}
+
+	/// Find the links of a user to the configured IDP whose provided
+	/// user ID isn't among the given ones, removing them if requested.
+	/// Returns the provided user IDs of the stale links.
+	pub async fn collect_stale_idp_links(
+		&mut self,
+		zitadel_id: &str,
+		valid_provided_user_ids: &HashSet<String>,
+		remove: bool,
+	) -> Result<Vec<String>> {
+		self.throttle().await;
+		let stale_links: Vec<String> = self
+			.zitadel_client_v1
+			.list_user_idps(zitadel_id.to_owned())
+			.await?
+			.into_iter()
+			.filter(|link| link.idp_id == self.zitadel_config.idp_id)
+			.map(|link| link.provided_user_id)
+			.filter(|provided_user_id| !valid_provided_user_ids.contains(provided_user_id))
+			.collect();
+
+		for provided_user_id in &stale_links {
+			tracing::warn!(
+				"User `{}` has a stale link to IDP user `{}`",
+				zitadel_id,
+				provided_user_id
+			);
+
+			if !remove {
+				continue;
+			}
+
+			if self.feature_flags.is_enabled(FeatureFlag::DryRun) {
+				tracing::warn!("Skipping IDP link removal due to dry run");
+				continue;
+			}
+
+			self.throttle().await;
+			self.zitadel_client_v1
+				.remove_user_idp(
+					zitadel_id.to_owned(),
+					self.zitadel_config.idp_id.clone(),
+					provided_user_id.clone(),
+				)
+				.await?;
+		}
+
+		Ok(stale_links)
 	}
 
-	/// Return a vector of a random sample of Zitadel users
-	/// We use this to determine the encoding of the external IDs
-	pub async fn get_users_sample(&mut self) -> Result<Vec<User>> {
-		let mut stream = self
-			.zitadel_client
-			.list_users(
-				ListUsersRequest::new(vec![
-					SearchQuery::new().with_type_query(TypeQuery::new(Userv2Type::Human))
-				])
-				.with_asc(true)
-				.with_sorting_column(UserFieldName::NickName)
-				.with_page_size(USER_SAMPLE_SIZE),
-			)
-			.map(|stream| {
-				stream.map(|user| {
-					let id = user.user_id().ok_or(anyhow!("Missing Zitadel user ID"))?.clone();
-					let user = search_result_to_user(user)?;
-					Ok((user, id))
-				})
-			})?;
+	/// Remove metadata and project roles left behind by earlier syncs
+	/// from a user, returning whether anything was removed
+	pub async fn collect_garbage(&mut self, zitadel_id: &str, gc: &GcConfig) -> Result<bool> {
+		let mut managed_metadata_keys = self.managed_metadata_keys();
+		// Preferred usernames left behind once they are no longer
+		// synced may be removed as well
+		if !self.manage_preferred_username {
+			let preferred_username_key = self.zitadel_config.metadata_key("preferred_username");
+			managed_metadata_keys.retain(|key| *key != preferred_username_key);
+		}
+		let mut stale_metadata_keys = Vec::new();
+		for key in &gc.metadata_keys {
+			if !managed_metadata_keys.contains(key)
+				&& self.get_metadata_value(zitadel_id, key).await.is_some()
+			{
+				stale_metadata_keys.push(key.clone());
+			}
+		}
 
-		let mut users = Vec::new();
+		let stale_roles = if gc.roles && self.managed_roles.is_empty() {
+			self.list_additional_roles(zitadel_id).await?
+		} else {
+			BTreeSet::new()
+		};
+		let remove_roles = !stale_roles.is_empty();
 
-		while let Some(user) = get_next_zitadel_user(&mut stream, self).await? {
-			users.push(user.0);
+		if stale_metadata_keys.is_empty() && !remove_roles {
+			return Ok(false);
 		}
 
-		Ok(users)
+		tracing::info!(
+			"Removing stale metadata {:?}{} from user `{}`",
+			stale_metadata_keys,
+			if remove_roles { " and additional project roles" } else { "" },
+			zitadel_id
+		);
+
+		if self.feature_flags.is_enabled(FeatureFlag::DryRun) {
+			tracing::warn!("Skipping garbage collection due to dry run");
+			return Ok(true);
+		}
+
+		for key in stale_metadata_keys {
+			self.throttle().await;
+			self.zitadel_client.delete_user_metadata(zitadel_id, &key).await?;
+		}
+
+		if let Some(grant) = self.get_project_grant(zitadel_id).await?.filter(|_| remove_roles) {
+			let role_keys = grant
+				.role_keys
+				.iter()
+				.filter(|role| !stale_roles.contains(*role))
+				.cloned()
+				.collect();
+			self.update_grant(zitadel_id, grant.id, role_keys).await?;
+		}
+
+		Ok(true)
 	}
 
-	/// Delete a Zitadel user
-	pub async fn delete_user(&mut self, zitadel_id: &str) -> Result<()> {
-		tracing::info!("Deleting user with Zitadel ID: {}", zitadel_id);
+	/// Move the metadata managed by the sync from unprefixed keys to
+	/// the keys of the configured namespace, returning whether
+	/// anything was moved
+	///
+	/// Values already stored under the namespaced key take precedence
+	/// over the unprefixed ones, which are removed either way.
+	pub async fn migrate_metadata_namespace(&mut self, zitadel_id: &str) -> Result<bool> {
+		let mut moved_entries = Vec::new();
+		for key in self.own_metadata_keys() {
+			let namespaced_key = self.zitadel_config.metadata_key(&key);
+			if namespaced_key == key {
+				continue;
+			}
+
+			if let Some(value) = self.get_metadata_value(zitadel_id, &key).await {
+				let existing = self.get_metadata_value(zitadel_id, &namespaced_key).await;
+				moved_entries.push((key, namespaced_key, existing.is_none().then_some(value)));
+			}
+		}
+
+		if moved_entries.is_empty() {
+			return Ok(false);
+		}
+
+		tracing::info!(
+			"Moving metadata {:?} of user `{}` to the namespace",
+			moved_entries.iter().map(|(key, _, _)| key).collect::<Vec<_>>(),
+			zitadel_id
+		);
 
 		if self.feature_flags.is_enabled(FeatureFlag::DryRun) {
-			tracing::warn!("Skipping deletion due to dry run");
-			return Ok(());
+			tracing::warn!("Skipping metadata migration due to dry run");
+			return Ok(true);
+		}
+
+		for (key, namespaced_key, value) in moved_entries {
+			if let Some(value) = value {
+				self.throttle().await;
+				self.zitadel_client.set_user_metadata(zitadel_id, &namespaced_key, &value).await?;
+			}
+			self.throttle().await;
+			self.zitadel_client.delete_user_metadata(zitadel_id, &key).await?;
 		}
 
-		self.zitadel_client.delete_user(zitadel_id).await.map(|_o| ())
+		Ok(true)
 	}
 
 	/// Import a user into Zitadel
 	pub async fn import_user(&mut self, imported_user: &User) -> Result<()> {
 		tracing::info!("Importing user with external ID: {}", imported_user.external_user_id);
+		let _in_flight =
+			watchdog::track(format!("import of user `{}`", imported_user.external_user_id));
 
 		if self.feature_flags.is_enabled(FeatureFlag::DryRun) {
 			tracing::warn!("Skipping import due to dry run");
 			return Ok(());
 		}
 
-		// Use the localpart from the user if available, otherwise generate one
+		let intent =
+			self.begin_intent(Operation::Create, &imported_user.external_user_id, imported_user)?;
+		let result = self.create_user(imported_user).await;
+		self.finish_intent(intent, &result);
+		result
+	}
+
+	/// Create a user in Zitadel, along with its metadata and grants
+	async fn create_user(&mut self, imported_user: &User) -> Result<()> {
+		// Use the localpart from the user if available, otherwise generate one.
+		// Since the localpart is deterministic, it also serves as an
+		// idempotency key for the import.
 		let localpart = if let Some(localpart) = &imported_user.localpart {
 			localpart.clone()
-		} else if self.feature_flags.contains(&FeatureFlag::PlainLocalpart) {
-			String::from_utf8(imported_user.get_external_id_bytes()?)
-				.context(format!("Unsupported binary external ID for user: {:?}", imported_user))?
 		} else {
-			imported_user.get_famedly_uuid()?
+			let derived = if self.feature_flags.contains(&FeatureFlag::PlainLocalpart) {
+				String::from_utf8(imported_user.get_external_id_bytes()?).context(format!(
+					"Unsupported binary external ID for user: {:?}",
+					imported_user
+				))?
+			} else {
+				imported_user.get_famedly_uuid()?
+			};
+
+			// External IDs may be recycled, in which case the mapping
+			// allocates a fresh localpart
+			match &self.id_mapping {
+				Some(id_mapping) => lock_id_mapping(id_mapping)
+					.allocate_localpart(&imported_user.external_user_id, &derived)?,
+				None => derived,
+			}
 		};
 
-		let mut metadata = vec![SetMetadataEntry::new("localpart".to_owned(), localpart.clone())];
+		let metadata_key = |key: &str| self.zitadel_config.metadata_key(key);
+		let mut metadata =
+			vec![SetMetadataEntry::new(metadata_key("localpart"), localpart.clone())];
 
 		if let Some(preferred_username) = imported_user.preferred_username.clone() {
-			metadata
-				.push(SetMetadataEntry::new("preferred_username".to_owned(), preferred_username));
+			metadata.push(SetMetadataEntry::new(
+				metadata_key("preferred_username"),
+				preferred_username,
+			));
+		}
+
+		for (key, value) in &imported_user.metadata {
+			metadata.push(SetMetadataEntry::new(metadata_key(key), value.clone()));
+		}
+
+		// Make sure the user is part of the user scope in future syncs
+		if let Some(include_metadata) =
+			self.zitadel_config.user_scope.as_ref().and_then(|scope| scope.include_metadata.clone())
+		{
+			metadata.push(SetMetadataEntry::new(include_metadata.key, include_metadata.value));
 		}
 
 		let mut user = AddHumanUserRequest::new(
@@ -189,7 +1488,7 @@ impl Zitadel {
 			Organization::new().with_org_id(self.zitadel_config.organization_id.clone()),
 		)
 		.with_metadata(metadata)
-		.with_user_id(localpart); // Set the Zitadel userId to the localpart
+		.with_user_id(localpart.clone()); // Set the Zitadel userId to the localpart
 
 		if let Some(phone) = imported_user.phone.clone() {
 			user.set_phone(
@@ -206,7 +1505,10 @@ impl Zitadel {
 				.with_user_name(imported_user.email.clone())]);
 		}
 
-		match self.zitadel_client.create_human_user(user.clone()).await {
+		self.throttle().await;
+		match latency::timed("create user", self.zitadel_client.create_human_user(user.clone()))
+			.await
+		{
 			Ok(res) => {
 				let id = res
 					.user_id()
@@ -216,28 +1518,40 @@ impl Zitadel {
 					))?
 					.clone();
 
-				self.zitadel_client_v1
-					.add_user_grant(
-						Some(self.zitadel_config.organization_id.clone()),
-						id,
-						self.zitadel_config.project_id.clone(),
-						None,
-						vec![FAMEDLY_USER_ROLE.to_owned()],
-					)
-					.await?;
+				let role_keys = get_role_keys(self.user_role(imported_user), &imported_user.roles);
+				latency::timed("add grant", self.add_project_grant(&id, role_keys)).await?;
 			}
 
 			Err(error) => {
 				// If the phone number is invalid
 				if error.to_string().contains("PHONE-so0wa") {
 					user.reset_phone();
+					self.throttle().await;
 					self.zitadel_client.create_human_user(user).await?;
+				} else if self.is_previously_imported(imported_user, &localpart).await? {
+					// A previous sync was interrupted after creating the
+					// user, so only the remaining steps are missing
+					tracing::warn!(
+						"User `{}` was already imported as `{}`, completing the import",
+						imported_user.external_user_id,
+						localpart
+					);
+					self.set_additional_roles(&localpart, imported_user).await?;
+				} else if is_already_exists(&error) {
+					match self.find_collision(imported_user, &localpart).await? {
+						Some(collision) => return Err(collision.into()),
+						None => anyhow::bail!(error),
+					}
 				} else {
 					anyhow::bail!(error)
 				}
 			}
 		}
 
+		if self.zitadel_config.consistency.verify_retries > 0 {
+			self.wait_until_listed(imported_user).await?;
+		}
+
 		Ok(())
 	}
 
@@ -253,6 +1567,7 @@ impl Zitadel {
 			old_user.external_user_id,
 			updated_user.external_user_id
 		);
+		let _in_flight = watchdog::track(format!("update of user `{zitadel_id}`"));
 
 		// Check if localpart has changed and emit warning if it has
 		if old_user.localpart != updated_user.localpart {
@@ -269,7 +1584,38 @@ impl Zitadel {
 			return Ok(());
 		}
 
+		let intent = self.begin_intent(Operation::Update, zitadel_id, updated_user)?;
+		let result = self.apply_user_changes(zitadel_id, old_user, updated_user).await;
+		self.finish_intent(intent, &result);
+		result
+	}
+
+	/// Apply the changes between two versions of a user to Zitadel
+	async fn apply_user_changes(
+		&mut self,
+		zitadel_id: &str,
+		old_user: &User,
+		updated_user: &User,
+	) -> Result<()> {
+		// Users deactivated instead of deleted are reactivated when they
+		// reappear in the source, before their deactivation time is
+		// removed along with the other metadata
+		if old_user.metadata.contains_key(DEACTIVATED_AT_KEY)
+			&& !updated_user.metadata.contains_key(DEACTIVATED_AT_KEY)
+		{
+			tracing::info!("Reactivating user `{}`", zitadel_id);
+			self.throttle().await;
+			latency::timed("reactivate user", self.zitadel_client.reactivate_user(zitadel_id))
+				.await?;
+		}
+
+		// Changes are applied in order of their importance for matching
+		// the user, so that a sync failing midway leaves a user which
+		// is still matched by external ID, and whose remaining changes
+		// are picked up by the next sync: the human user first, then
+		// the phone removal, metadata and roles.
 		let mut request = UpdateHumanUserRequest::new();
+		let mut update_human_user = false;
 
 		if old_user.email != updated_user.email {
 			request.set_username(updated_user.email.clone());
@@ -277,68 +1623,314 @@ impl Zitadel {
 				SetHumanEmail::new(updated_user.email.clone())
 					.with_is_verified(!self.feature_flags.is_enabled(FeatureFlag::VerifyEmail)),
 			);
+			update_human_user = true;
 		}
 
-		if old_user.first_name != updated_user.first_name
-			|| old_user.last_name != updated_user.last_name
+		// Names fixed manually in Zitadel are kept while the user is
+		// held, but the nick name is still updated, since users are
+		// matched by it
+		let names_changed = old_user.first_name != updated_user.first_name
+			|| old_user.last_name != updated_user.last_name;
+		let names = if names_changed && self.is_profile_held(zitadel_id).await? {
+			tracing::info!("Keeping the names of held user `{}`", zitadel_id);
+			old_user
+		} else {
+			updated_user
+		};
+		if old_user.first_name != names.first_name
+			|| old_user.last_name != names.last_name
 			|| old_user.external_user_id != updated_user.external_user_id
 		{
 			request.set_profile(
-				SetHumanProfile::new(
-					updated_user.first_name.clone(),
-					updated_user.last_name.clone(),
-				)
-				.with_display_name(updated_user.get_display_name())
-				.with_nick_name(updated_user.external_user_id.clone()),
+				SetHumanProfile::new(names.first_name.clone(), names.last_name.clone())
+					.with_display_name(names.get_display_name())
+					.with_nick_name(updated_user.external_user_id.clone()),
 			);
+			update_human_user = true;
 		}
 
-		if old_user.phone != updated_user.phone {
-			if let Some(phone) = updated_user.phone.clone() {
+		let mut remove_phone = false;
+		if !same_value(old_user.phone.as_deref(), updated_user.phone.as_deref()) {
+			if let Some(phone) = non_empty(updated_user.phone.clone()) {
 				request.set_phone(
 					SetHumanPhone::new()
 						.with_phone(phone.clone())
 						.with_is_verified(!self.feature_flags.is_enabled(FeatureFlag::VerifyPhone)),
 				);
+				update_human_user = true;
 			} else {
-				self.zitadel_client.remove_phone(zitadel_id).await?;
+				remove_phone = true;
 			}
 		}
 
-		if let Err(error) = self.zitadel_client.update_human_user(zitadel_id, request.clone()).await
-		{
-			// If the new phone number is invalid
-			if error.to_string().contains("PHONE-so0wa") {
-				request.reset_phone();
-				self.zitadel_client.update_human_user(zitadel_id, request).await?;
-
-				if let Err(error) = self.zitadel_client.remove_phone(zitadel_id).await {
-					// If the user didn't start out with a phone
-					if !error.to_string().contains("COMMAND-ieJ2e") {
-						anyhow::bail!(error);
-					}
-				};
-			} else {
-				anyhow::bail!(error);
+		// All attributes of the human user are changed in a single
+		// request, which is skipped if only metadata or roles changed
+		if update_human_user {
+			self.throttle().await;
+			if let Err(error) = latency::timed(
+				"update user",
+				self.zitadel_client.update_human_user(zitadel_id, request.clone()),
+			)
+			.await
+			{
+				// If the new phone number is invalid
+				if error.to_string().contains("PHONE-so0wa") {
+					request.reset_phone();
+					self.throttle().await;
+					latency::timed(
+						"update user",
+						self.zitadel_client.update_human_user(zitadel_id, request),
+					)
+					.await?;
+					remove_phone = true;
+				} else {
+					anyhow::bail!(error);
+				}
+			};
+		}
+
+		if remove_phone {
+			self.throttle().await;
+			if let Err(error) =
+				latency::timed("remove phone", self.zitadel_client.remove_phone(zitadel_id)).await
+			{
+				// If the user didn't start out with a phone
+				if !error.to_string().contains("COMMAND-ieJ2e") {
+					anyhow::bail!(error);
+				}
 			}
-		};
+		}
 
-		if old_user.preferred_username != updated_user.preferred_username {
-			if let Some(preferred_username) = updated_user.preferred_username.clone() {
-				self.zitadel_client
-					.set_user_metadata(
-						zitadel_id,
-						"preferred_username",
-						&preferred_username.clone(),
+		for (key, value) in metadata_changes(old_user, updated_user) {
+			let key = self.zitadel_config.metadata_key(&key);
+			match value {
+				Some(value) => {
+					self.throttle().await;
+					latency::timed(
+						"set metadata",
+						self.zitadel_client.set_user_metadata(zitadel_id, &key, &value),
 					)
 					.await?;
-			} else {
-				self.zitadel_client.delete_user_metadata(zitadel_id, "preferred_username").await?;
+				}
+				None => {
+					self.throttle().await;
+					latency::timed(
+						"delete metadata",
+						self.zitadel_client.delete_user_metadata(zitadel_id, &key),
+					)
+					.await?;
+				}
 			}
 		}
 
+		if self.user_role(old_user) != self.user_role(updated_user) {
+			let (from, to) =
+				(self.user_role(old_user).to_owned(), self.user_role(updated_user).to_owned());
+			latency::timed("set roles", self.replace_user_role(zitadel_id, &from, &to)).await?;
+		}
+		if old_user.roles != updated_user.roles {
+			latency::timed("set roles", self.set_additional_roles(zitadel_id, updated_user))
+				.await?;
+		}
+
+		Ok(())
+	}
+
+	/// Log the intent to write to a user, before writing
+	fn begin_intent(
+		&self,
+		operation: Operation,
+		target: &str,
+		payload: &impl Serialize,
+	) -> Result<Option<Intent>> {
+		self.intent_log
+			.as_ref()
+			.map(|intent_log| {
+				lock_intent_log(intent_log)
+					.begin(operation, target, payload)
+					.context("Failed to log the intent to write")
+			})
+			.transpose()
+	}
+
+	/// Log the outcome of a write. The write already happened, so
+	/// failing to log it only warrants a warning.
+	fn finish_intent(&self, intent: Option<Intent>, result: &Result<()>) {
+		let (Some(intent_log), Some(intent)) = (&self.intent_log, intent) else {
+			return;
+		};
+		if let Err(error) = lock_intent_log(intent_log).finish(&intent, result) {
+			tracing::warn!("Failed to log the outcome of a write: {:?}", error);
+		}
+	}
+
+	/// Mark the email address of a user as unverified, which makes
+	/// Zitadel send a new verification email to it
+	pub async fn reverify_email(&mut self, zitadel_id: &str, email: &str) -> Result<()> {
+		tracing::info!("Requesting email verification for user `{}`", zitadel_id);
+		let _in_flight = watchdog::track(format!("email verification of user `{zitadel_id}`"));
+
+		if self.feature_flags.is_enabled(FeatureFlag::DryRun) {
+			tracing::warn!("Skipping email verification due to dry run");
+			return Ok(());
+		}
+
+		let mut request = UpdateHumanUserRequest::new();
+		request.set_email(SetHumanEmail::new(email.to_owned()).with_is_verified(false));
+		self.throttle().await;
+		latency::timed("update user", self.zitadel_client.update_human_user(zitadel_id, request))
+			.await?;
+
 		Ok(())
 	}
+
+	/// Replace a project role of a user with another one, returning
+	/// whether the user had the role
+	pub async fn remap_role(&mut self, zitadel_id: &str, from: &str, to: &str) -> Result<bool> {
+		let Some((grant_id, role_keys)) =
+			self.get_project_grant(zitadel_id).await?.and_then(|grant| {
+				remap_role_keys(&grant.role_keys, from, to).map(|role_keys| (grant.id, role_keys))
+			})
+		else {
+			return Ok(false);
+		};
+
+		tracing::info!("Replacing role `{}` with `{}` for user `{}`", from, to, zitadel_id);
+		let _in_flight = watchdog::track(format!("role remapping of user `{zitadel_id}`"));
+
+		if self.feature_flags.is_enabled(FeatureFlag::DryRun) {
+			tracing::warn!("Skipping role remapping due to dry run");
+			return Ok(true);
+		}
+
+		latency::timed("set roles", self.update_grant(zitadel_id, grant_id, role_keys)).await?;
+
+		Ok(true)
+	}
+}
+
+/// Get the project role keys to grant, given the role granted to all
+/// users and the additional roles of a user
+pub(crate) fn get_role_keys(user_role: &str, additional_roles: &BTreeSet<String>) -> Vec<String> {
+	std::iter::once(user_role.to_owned()).chain(additional_roles.iter().cloned()).collect()
+}
+
+/// Turn an error looking up a configured Zitadel object into a
+/// targeted error message
+fn describe_preflight_error(
+	error: ZitadelErrorV1,
+	object: ConfiguredObject<'_>,
+	language: Language,
+) -> anyhow::Error {
+	let message = match &error {
+		ZitadelErrorV1::TonicResponseError(status) if status.code() == TonicErrorCode::NotFound => {
+			Message::ObjectNotFound(object)
+		}
+		ZitadelErrorV1::TonicResponseError(status)
+			if status.code() == TonicErrorCode::PermissionDenied =>
+		{
+			Message::ObjectAccessDenied(object)
+		}
+		ZitadelErrorV1::TonicResponseError(status)
+			if status.code() == TonicErrorCode::Unauthenticated =>
+		{
+			Message::ObjectUnauthenticated(object)
+		}
+		_ => return anyhow!(error).context(Message::ObjectLookupFailed(object).render(language)),
+	};
+
+	anyhow!(message.render(language))
+}
+
+/// The metadata entries to change to turn the metadata of the old
+/// user into that of the updated one, including the preferred
+/// username, along with their new value, or `None` if they are to be
+/// deleted. Changed entries come before deleted ones.
+fn metadata_changes(old_user: &User, updated_user: &User) -> Vec<(String, Option<String>)> {
+	let mut old_metadata = old_user.metadata.clone();
+	old_metadata.extend(
+		non_empty(old_user.preferred_username.clone())
+			.map(|value| ("preferred_username".to_owned(), value)),
+	);
+	let mut new_metadata = updated_user.metadata.clone();
+	new_metadata.extend(
+		non_empty(updated_user.preferred_username.clone())
+			.map(|value| ("preferred_username".to_owned(), value)),
+	);
+
+	let sets = new_metadata
+		.iter()
+		.filter(|(key, value)| old_metadata.get(*key) != Some(*value))
+		.map(|(key, value)| (key.clone(), Some(value.clone())));
+	let deletions = old_metadata
+		.keys()
+		.filter(|key| !new_metadata.contains_key(*key))
+		.map(|key| (key.clone(), None));
+
+	sets.chain(deletions).collect()
+}
+
+/// Whether a Zitadel error says that the requested object doesn't
+/// exist, as opposed to failing to look it up
+fn is_not_found(error: &impl std::fmt::Display) -> bool {
+	let message = format!("{error:#}").to_lowercase();
+	message.contains("not found") || message.contains("notfound")
+}
+
+/// Whether a Zitadel error says that the object to create already
+/// exists
+fn is_already_exists(error: &impl std::fmt::Display) -> bool {
+	let message = format!("{error:#}").to_lowercase();
+	message.contains("already exist") || message.contains("alreadyexist")
+}
+
+/// A user couldn't be imported, since one of its unique attributes is
+/// already taken by another Zitadel user
+#[derive(Debug, Clone)]
+pub struct UniqueAttributeTaken {
+	/// The attribute which collided
+	pub key: CollisionKey,
+	/// The Zitadel ID of the user holding the attribute
+	pub zitadel_id: String,
+	/// The external ID of the user holding the attribute, unless it
+	/// has none
+	pub external_user_id: Option<String>,
+}
+
+impl std::fmt::Display for UniqueAttributeTaken {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		let key = match self.key {
+			CollisionKey::UserId => "user ID",
+			CollisionKey::Username => "username",
+			CollisionKey::Email => "email address",
+		};
+		write!(f, "The {key} of the user is already taken by Zitadel user `{}`", self.zitadel_id)
+	}
+}
+
+impl std::error::Error for UniqueAttributeTaken {}
+
+/// The organization owning a Zitadel user, if listed
+fn resource_owner(user: &ZitadelUser) -> Option<&str> {
+	user.details().and_then(|details| details.resource_owner()).map(String::as_str)
+}
+
+/// Drop the users of other organizations from a listing, which it
+/// includes if the service user may read users across organizations
+fn own_users<S>(
+	organization_id: String,
+	users: S,
+) -> impl Stream<Item = Result<ZitadelUser>> + Unpin
+where
+	S: Stream<Item = Result<ZitadelUser>> + Unpin,
+{
+	users.filter(move |user| {
+		let foreign = matches!(
+			user.as_ref().ok().and_then(resource_owner),
+			Some(owner) if owner != organization_id
+		);
+		futures::future::ready(!foreign)
+	})
 }
 
 /// Convert a Zitadel search result to a user
@@ -347,6 +1939,7 @@ pub fn search_result_to_user(user: ZitadelUser) -> Result<User> {
 	let nick_name = human_user
 		.profile()
 		.and_then(|p| p.nick_name())
+		.filter(|nick_name| !nick_name.is_empty())
 		.ok_or(anyhow!("Missing external ID found for user"))?;
 
 	// TODO: If async closures become a reality, we
@@ -385,4 +1978,300 @@ pub struct ZitadelConfig {
 	pub project_id: String,
 	/// IDP ID provided by Famedly Zitadel
 	pub idp_id: String,
+	/// Optional restriction of the Zitadel users managed by the sync
+	pub user_scope: Option<UserScopeConfig>,
+	/// How to handle Zitadel users without an email address
+	#[serde(default)]
+	pub missing_email: MissingEmailPolicy,
+	/// How to handle source users which already exist in Zitadel, but
+	/// aren't managed by the sync
+	#[serde(default)]
+	pub unmanaged_users: UnmanagedUserPolicy,
+	/// The number of users to request per page when listing users.
+	/// Zitadel's user listing doesn't support field masks, so larger
+	/// pages are the only way to reduce its cost. The per-user reads of
+	/// metadata and grants, one request per managed metadata key, are
+	/// unaffected.
+	#[serde(default = "default_page_size")]
+	pub page_size: usize,
+	/// The number of pages of the user listing requested ahead of the
+	/// one being processed, which shortens listings over high-latency
+	/// links. Pages are requested one at a time if 0.
+	#[serde(default)]
+	pub prefetch_pages: usize,
+	/// The project role granted to all synced users
+	#[serde(default = "default_user_role")]
+	pub user_role: String,
+	/// Handling of Zitadel's eventual consistency after writes
+	#[serde(default)]
+	pub consistency: ConsistencyConfig,
+	/// What happens to users removed from the source
+	#[serde(default)]
+	pub deprovisioning: DeprovisioningPolicy,
+	/// The number of days users deactivated due to `deprovisioning:
+	/// deactivate` are kept before they are deleted. Without it, they
+	/// are kept until deleted by other means.
+	pub deactivation_grace_days: Option<u32>,
+	/// Prefix of the metadata keys managed by the sync, e.g.
+	/// `famedly_sync:`, so that they don't collide with metadata of
+	/// other tools
+	pub metadata_namespace: Option<String>,
+	/// How many writes of each kind the sync sends to Zitadel at once
+	#[serde(default)]
+	pub write_concurrency: WriteConcurrencyConfig,
+	/// The maximum number of requests per second sent to Zitadel, so
+	/// that large syncs don't exceed its rate limits. Requests aren't
+	/// limited without it.
+	pub max_requests_per_second: Option<u32>,
+	/// The number of requests which may be sent at once after an idle
+	/// period, before `max_requests_per_second` applies. Defaults to
+	/// `max_requests_per_second`.
+	pub request_burst: Option<u32>,
+	/// Whether to look for the email addresses of new users in other
+	/// organizations before importing them, and report the users found
+	/// there as conflicts instead. Only the organizations whose users
+	/// the service user may read are searched.
+	#[serde(default)]
+	pub cross_org_email_check: bool,
+}
+
+impl ZitadelConfig {
+	/// The Zitadel metadata key of a metadata entry managed by the
+	/// sync
+	#[must_use]
+	pub fn metadata_key(&self, key: &str) -> String {
+		format!("{}{key}", self.metadata_namespace.as_deref().unwrap_or_default())
+	}
+
+	/// Whether the grace period of a user deactivated at the given
+	/// time is over, so that the user is to be deleted
+	#[must_use]
+	pub fn deactivation_expired(&self, deactivated_at: &str, now: DateTime<Utc>) -> bool {
+		self.deactivation_grace_days
+			.is_some_and(|grace_days| grace_period_over(deactivated_at, grace_days, now))
+	}
+}
+
+/// Whether the given number of days passed since a user was
+/// deactivated at the given time
+pub(crate) fn grace_period_over(deactivated_at: &str, grace_days: u32, now: DateTime<Utc>) -> bool {
+	let Ok(deactivated_at) = DateTime::parse_from_rfc3339(deactivated_at) else {
+		tracing::warn!("Ignoring invalid deactivation time `{}`", deactivated_at);
+		return false;
+	};
+
+	now - deactivated_at.with_timezone(&Utc) >= chrono::Duration::days(i64::from(grace_days))
+}
+
+/// Handling of Zitadel's eventual consistency after writes
+///
+/// Zitadel's listings are based on projections which may lag behind
+/// writes, so users may not be listed immediately after their import.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct ConsistencyConfig {
+	/// How often to check whether an imported user is listed before
+	/// moving on. Imported users aren't verified if this is 0.
+	#[serde(default)]
+	pub verify_retries: usize,
+	/// The delay between checks whether an imported user is listed,
+	/// in milliseconds
+	#[serde(default = "default_retry_interval_ms")]
+	pub retry_interval_ms: u64,
+	/// How long to wait after the sync's writes for projections to
+	/// settle, in milliseconds
+	#[serde(default)]
+	pub settle_delay_ms: u64,
+}
+
+impl Default for ConsistencyConfig {
+	fn default() -> Self {
+		Self { verify_retries: 0, retry_interval_ms: DEFAULT_RETRY_INTERVAL_MS, settle_delay_ms: 0 }
+	}
+}
+
+/// How many writes of each kind the sync sends to Zitadel at once
+///
+/// Deletions and imports are only executed once all users were
+/// compared, so they can be sent concurrently. Updates are collected
+/// while the users are compared, and sent in batches of concurrent
+/// writes.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct WriteConcurrencyConfig {
+	/// The number of concurrent deletions
+	#[serde(default = "default_write_concurrency")]
+	pub deletions: usize,
+	/// The number of concurrent imports
+	#[serde(default = "default_write_concurrency")]
+	pub imports: usize,
+	/// The number of concurrent updates
+	#[serde(default = "default_write_concurrency")]
+	pub updates: usize,
+}
+
+impl Default for WriteConcurrencyConfig {
+	fn default() -> Self {
+		Self {
+			deletions: DEFAULT_WRITE_CONCURRENCY,
+			imports: DEFAULT_WRITE_CONCURRENCY,
+			updates: DEFAULT_WRITE_CONCURRENCY,
+		}
+	}
+}
+
+/// Default for [`WriteConcurrencyConfig::deletions`],
+/// [`WriteConcurrencyConfig::imports`] and
+/// [`WriteConcurrencyConfig::updates`]
+fn default_write_concurrency() -> usize {
+	DEFAULT_WRITE_CONCURRENCY
+}
+
+/// Default for [`ConsistencyConfig::retry_interval_ms`]
+fn default_retry_interval_ms() -> u64 {
+	DEFAULT_RETRY_INTERVAL_MS
+}
+
+/// Default for [`ZitadelConfig::page_size`]
+fn default_page_size() -> usize {
+	DEFAULT_PAGE_SIZE
+}
+
+/// Default for [`ZitadelConfig::user_role`]
+fn default_user_role() -> String {
+	FAMEDLY_USER_ROLE.to_owned()
+}
+
+/// How to handle Zitadel users without an email address
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MissingEmailPolicy {
+	/// Leave the user untouched, only counting it in the report
+	Skip,
+	/// Leave the user untouched, logging a warning
+	#[default]
+	Report,
+	/// Match the user by external ID as usual, which sets the email
+	/// address from the source
+	Match,
+}
+
+impl MissingEmailPolicy {
+	/// Whether a listed user without an email address is still synced
+	pub(crate) fn keeps_user(self, zitadel_id: &str) -> bool {
+		match self {
+			Self::Skip => false,
+			Self::Report => {
+				tracing::warn!("Skipping Zitadel user `{}` without email address", zitadel_id);
+				false
+			}
+			Self::Match => {
+				tracing::info!(
+					"Matching Zitadel user `{}` without email address by external ID",
+					zitadel_id
+				);
+				true
+			}
+		}
+	}
+}
+
+/// How to handle source users which already exist in Zitadel, but
+/// aren't managed by the sync, e.g. since they are outside of the user
+/// scope
+///
+/// Such users look like new users to the sync, but importing them
+/// fails, since their Zitadel ID is taken.
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum UnmanagedUserPolicy {
+	/// Skip the import, listing the user as unmanaged in the report
+	#[default]
+	Report,
+	/// Skip the import and count it as failed
+	Fail,
+	/// Import the user without looking for it first, as in earlier
+	/// versions
+	Import,
+}
+
+/// What happens to Zitadel users removed from the source
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeprovisioningPolicy {
+	/// Delete the user
+	#[default]
+	Delete,
+	/// Keep the user, marking it with the
+	/// [`PENDING_DEPROVISIONING_KEY`] metadata entry, so that
+	/// downstream tooling can run the messenger's retention workflows
+	/// before deleting it
+	MarkPending,
+	/// Deactivate the user, marking it with the [`DEACTIVATED_AT_KEY`]
+	/// metadata entry, and delete it once
+	/// [`ZitadelConfig::deactivation_grace_days`] have passed
+	Deactivate,
+}
+
+/// Restriction of the Zitadel users managed by the sync, based on
+/// user metadata
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct UserScopeConfig {
+	/// Only manage users carrying this metadata entry. Newly imported
+	/// users are given this entry.
+	pub include_metadata: Option<MetadataEntryConfig>,
+	/// Never manage users carrying metadata with this key
+	pub exclude_metadata_key: Option<String>,
+}
+
+/// A Zitadel metadata key/value pair
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct MetadataEntryConfig {
+	/// The metadata key
+	pub key: String,
+	/// The metadata value
+	pub value: String,
+}
+
+/// A grant of project roles to a user
+#[derive(Debug, Clone)]
+struct UserGrant {
+	/// The ID of the grant
+	id: String,
+	/// The ID of the granted project
+	project_id: String,
+	/// The granted roles
+	role_keys: Vec<String>,
+}
+
+/// The data of a user archived before its deletion
+#[derive(Debug, Clone, Serialize)]
+struct ArchivedUser {
+	/// When the user was archived, in RFC 3339 format
+	timestamp: String,
+	/// The Zitadel ID of the user
+	zitadel_id: String,
+	/// The metadata managed by the sync
+	metadata: BTreeMap<String, String>,
+	/// The project grants of the user
+	grants: Vec<ArchivedGrant>,
+}
+
+/// A project grant of an archived user
+#[derive(Debug, Clone, Serialize)]
+struct ArchivedGrant {
+	/// The ID of the granted project
+	project_id: String,
+	/// The granted roles
+	role_keys: Vec<String>,
+}
+
+/// Lock the ID mapping, which is only poisoned if a panic occurred
+/// while it was being written
+fn lock_id_mapping(id_mapping: &Mutex<IdMappingStore>) -> MutexGuard<'_, IdMappingStore> {
+	id_mapping.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Lock the intent log, which is only poisoned if a panic occurred
+/// while it was being written
+fn lock_intent_log(intent_log: &Mutex<IntentLog>) -> MutexGuard<'_, IntentLog> {
+	intent_log.lock().unwrap_or_else(PoisonError::into_inner)
 }
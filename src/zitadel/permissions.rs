@@ -0,0 +1,166 @@
+//! Checks of the Zitadel permissions granted to the service user
+//!
+//! Zitadel reports the permissions of the authenticated user as
+//! strings such as `user.write`. The operations the sync performs are
+//! mapped to these permissions, so that missing rights can be reported
+//! before the sync starts, instead of failing halfway through.
+use std::{collections::BTreeSet, fmt};
+
+/// An operation the sync performs in Zitadel
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Capability {
+	/// Listing users and reading their metadata
+	ListUsers,
+	/// Creating users
+	CreateUsers,
+	/// Updating, deactivating and reactivating users
+	UpdateUsers,
+	/// Deleting users
+	DeleteUsers,
+	/// Setting and removing user metadata
+	Metadata,
+	/// Reading and changing project grants
+	Grants,
+}
+
+impl Capability {
+	/// All operations the sync may perform
+	pub const ALL: [Capability; 6] = [
+		Capability::ListUsers,
+		Capability::CreateUsers,
+		Capability::UpdateUsers,
+		Capability::DeleteUsers,
+		Capability::Metadata,
+		Capability::Grants,
+	];
+
+	/// The Zitadel permissions required for the operation
+	#[must_use]
+	pub fn required_permissions(self) -> &'static [&'static str] {
+		match self {
+			Capability::ListUsers => &["user.read"],
+			Capability::CreateUsers | Capability::UpdateUsers | Capability::Metadata => {
+				&["user.write"]
+			}
+			Capability::DeleteUsers => &["user.delete"],
+			Capability::Grants => &["user.grant.read", "user.grant.write"],
+		}
+	}
+
+	/// The Zitadel organization role granting the permissions for the
+	/// operation
+	#[must_use]
+	pub fn granting_role(self) -> &'static str {
+		match self {
+			Capability::Grants => "ORG_USER_PERMISSION_EDITOR",
+			_ => "ORG_USER_MANAGER",
+		}
+	}
+}
+
+impl fmt::Display for Capability {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let name = match self {
+			Capability::ListUsers => "list users",
+			Capability::CreateUsers => "create users",
+			Capability::UpdateUsers => "update users",
+			Capability::DeleteUsers => "delete users",
+			Capability::Metadata => "manage user metadata",
+			Capability::Grants => "manage user grants",
+		};
+
+		write!(f, "{name}")
+	}
+}
+
+/// The operations the service user can and cannot perform, according
+/// to its permissions
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PermissionProbe {
+	/// The operations the service user can perform
+	pub allowed: Vec<Capability>,
+	/// The operations the service user cannot perform, along with the
+	/// permissions it lacks for them
+	pub denied: Vec<(Capability, Vec<&'static str>)>,
+}
+
+impl PermissionProbe {
+	/// Map the permissions granted to the service user to the
+	/// operations it can perform
+	#[must_use]
+	pub fn from_permissions(granted: &[String]) -> Self {
+		let granted: BTreeSet<&str> = granted.iter().map(String::as_str).collect();
+		let mut allowed = Vec::new();
+		let mut denied = Vec::new();
+
+		for capability in Capability::ALL {
+			let missing: Vec<&'static str> = capability
+				.required_permissions()
+				.iter()
+				.copied()
+				.filter(|permission| !granted.contains(permission))
+				.collect();
+
+			if missing.is_empty() {
+				allowed.push(capability);
+			} else {
+				denied.push((capability, missing));
+			}
+		}
+
+		Self { allowed, denied }
+	}
+
+	/// The required operations the service user cannot perform
+	pub fn missing<'a>(
+		&'a self,
+		required: &'a [Capability],
+	) -> impl Iterator<Item = &'a (Capability, Vec<&'static str>)> + 'a {
+		self.denied.iter().filter(|(capability, _)| required.contains(capability))
+	}
+
+	/// The roles to grant the service user so that it can perform the
+	/// required operations
+	#[must_use]
+	pub fn missing_roles(&self, required: &[Capability]) -> BTreeSet<&'static str> {
+		self.missing(required).map(|(capability, _)| capability.granting_role()).collect()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_permission_probe() {
+		let probe = PermissionProbe::from_permissions(&[
+			"org.read".to_owned(),
+			"user.read".to_owned(),
+			"user.write".to_owned(),
+			"user.grant.read".to_owned(),
+		]);
+
+		assert_eq!(
+			probe.allowed,
+			vec![
+				Capability::ListUsers,
+				Capability::CreateUsers,
+				Capability::UpdateUsers,
+				Capability::Metadata
+			]
+		);
+		assert_eq!(
+			probe.denied,
+			vec![
+				(Capability::DeleteUsers, vec!["user.delete"]),
+				(Capability::Grants, vec!["user.grant.write"])
+			]
+		);
+
+		assert_eq!(probe.missing(&[Capability::ListUsers]).count(), 0);
+		assert_eq!(
+			probe.missing_roles(&Capability::ALL),
+			BTreeSet::from(["ORG_USER_MANAGER", "ORG_USER_PERMISSION_EDITOR"])
+		);
+	}
+}
@@ -7,11 +7,13 @@ use std::{collections::HashSet, path::Path, time::Duration};
 use base64::{engine::general_purpose, Engine as _};
 use famedly_sync::{
 	csv_test_helpers::temp_csv_file,
-	get_next_zitadel_user, perform_sync,
+	get_next_zitadel_user, hold_user, migrate_metadata_namespace, perform_sync,
 	ukt_test_helpers::{
 		get_mock_server_url, prepare_endpoint_mock, prepare_oauth2_mock, ENDPOINT_PATH, OAUTH2_PATH,
 	},
-	zitadel::Zitadel as SyncZitadel,
+	user::User,
+	verify_idempotent,
+	zitadel::{MissingEmailPolicy, Zitadel as SyncZitadel},
 	AttributeMapping, Config, FeatureFlag,
 };
 use ldap3::{Ldap as LdapClient, LdapConnAsync, LdapConnSettings, Mod};
@@ -20,9 +22,12 @@ use tokio::sync::OnceCell;
 use url::Url;
 use uuid::{uuid, Uuid};
 use wiremock::MockServer;
-use zitadel_rust_client::v1::{
-	error::{Error as ZitadelError, TonicErrorCode},
-	Email, Gender, ImportHumanUserRequest, Phone, Profile, UserType, Zitadel,
+use zitadel_rust_client::{
+	v1::{
+		error::{Error as ZitadelError, TonicErrorCode},
+		UserType, Zitadel,
+	},
+	v2::users::{AddHumanUserRequest, Organization, SetHumanEmail, SetHumanPhone, SetHumanProfile},
 };
 
 static CONFIG_WITH_LDAP: OnceCell<Config> = OnceCell::const_new();
@@ -133,7 +138,11 @@ async fn test_e2e_user_id_sync_ordering() {
 	}
 
 	// Initial sync
-	perform_sync(config).await.expect("Initial sync failed");
+	let report = perform_sync(config).await.expect("Initial sync failed");
+	assert!(report.failures.is_empty());
+	for user in TEST_USERS {
+		assert!(report.created.contains(&hex::encode(user.uid.as_bytes())));
+	}
 
 	// Verify all users exist with correct data
 	for user in TEST_USERS {
@@ -179,7 +188,15 @@ async fn test_e2e_user_id_sync_ordering() {
 	}
 
 	// Sync again
-	perform_sync(config).await.expect("Update sync failed");
+	let report = perform_sync(config).await.expect("Update sync failed");
+	for user in TEST_USERS {
+		let external_user_id = hex::encode(user.uid.as_bytes());
+		assert!(report.updated.contains(&external_user_id));
+		assert_eq!(
+			report.changed_fields.get(&external_user_id),
+			Some(&vec!["last_name".to_owned()])
+		);
+	}
 
 	// Verify updates were applied in correct order
 	for user in TEST_USERS {
@@ -504,6 +521,52 @@ async fn test_e2e_sync_deletion() {
 	assert!(user.is_err_and(|error| matches!(error, ZitadelError::TonicResponseError(status) if status.code() == TonicErrorCode::NotFound)));
 }
 
+#[test(tokio::test)]
+#[test_log(default_log_filter = "debug")]
+async fn test_e2e_email_reuse() {
+	let mut config = ldap_config().await.clone();
+	config.identity = serde_yaml::from_str("external_id_only").expect("invalid identity policy");
+	config.zitadel.deprovisioning =
+		serde_yaml::from_str("mark_pending").expect("invalid deprovisioning policy");
+
+	let mut ldap = Ldap::new().await;
+	ldap.create_user(
+		"Departed",
+		"Staff",
+		"Departed",
+		"reused@famedly.de",
+		None,
+		"reused_departed",
+		false,
+	)
+	.await;
+	perform_sync(&config).await.expect("syncing failed");
+
+	let zitadel = open_zitadel_connection().await;
+	let departed = zitadel
+		.get_user_by_login_name("reused@famedly.de")
+		.await
+		.expect("could not query Zitadel users")
+		.expect("could not find user");
+
+	// The departed user is kept pending deprovisioning, while a new
+	// hire is given the same email address
+	ldap.delete_user("reused_departed").await;
+	ldap.create_user("New", "Hire", "New", "reused@famedly.de", None, "reused_hire", false).await;
+	let report = perform_sync(&config).await.expect("syncing failed");
+
+	assert!(report.email_conflicts.iter().any(|conflict| conflict.zitadel_id == departed.id));
+
+	let user = zitadel
+		.get_user_by_login_name("reused@famedly.de")
+		.await
+		.expect("could not query Zitadel users")
+		.expect("could not find user");
+	assert_eq!(user.id, departed.id, "the new hire took over the departed user's account");
+
+	ldap.delete_user("reused_hire").await;
+}
+
 #[test(tokio::test)]
 #[test_log(default_log_filter = "debug")]
 async fn test_e2e_ldaps() {
@@ -809,10 +872,10 @@ async fn test_e2e_binary_preferred_username() {
 		.as_mut()
 		.expect("ldap must be configured for this test")
 		.attributes
-		.preferred_username = AttributeMapping::OptionalBinary {
+		.preferred_username = Some(AttributeMapping::OptionalBinary {
 		name: "userSMIMECertificate".to_owned(),
 		is_binary: true,
-	};
+	});
 
 	let mut ldap = Ldap::new().await;
 	ldap.create_user(
@@ -1087,31 +1150,9 @@ async fn test_e2e_ukt_sync() {
 		})
 		.expect("UKT configuration is missing");
 
-	let user = ImportHumanUserRequest {
-		user_name: "delete_me@famedly.de".to_owned(),
-		profile: Some(Profile {
-			first_name: "First".to_owned(),
-			last_name: "Last".to_owned(),
-			display_name: "First Last".to_owned(),
-			gender: Gender::Unspecified.into(),
-			nick_name: "nickname".to_owned(),
-			preferred_language: String::default(),
-		}),
-		email: Some(Email { email: "delete_me@famedly.de".to_owned(), is_email_verified: true }),
-		phone: Some(Phone { phone: "+12015551111".to_owned(), is_phone_verified: true }),
-		password: String::default(),
-		hashed_password: None,
-		password_change_required: false,
-		request_passwordless_registration: false,
-		otp_code: String::default(),
-		idps: vec![],
-	};
+	create_zitadel_user(&config, "delete_me@famedly.de", "delete_me@famedly.de", "nickname").await;
 
 	let zitadel = open_zitadel_connection().await;
-	zitadel
-		.create_human_user(&config.zitadel.organization_id, user)
-		.await
-		.expect("failed to create user");
 
 	let user = zitadel
 		.get_user_by_login_name("delete_me@famedly.de")
@@ -1241,6 +1282,198 @@ async fn test_e2e_csv_sync() {
 	assert_eq!(localpart, Some(user.id), "Localpart metadata should match userId");
 }
 
+#[test(tokio::test)]
+#[test_log(default_log_filter = "debug")]
+async fn test_e2e_metadata_namespace_migration() {
+	let mut config = csv_config().await.clone();
+	let csv_content = indoc::indoc! {r#"
+    email,first_name,last_name,phone,localpart
+    namespace@example.com,Name,Space,,namespace
+  "#};
+	let _file = temp_csv_file(&mut config, csv_content);
+
+	perform_sync(&config).await.expect("syncing failed");
+
+	config.zitadel.metadata_namespace = Some("famedly_sync:".to_owned());
+	migrate_metadata_namespace(&config).await.expect("migrating metadata failed");
+
+	let zitadel = open_zitadel_connection().await;
+	let organization_id = Some(config.zitadel.organization_id.clone());
+	let localpart = zitadel
+		.get_user_metadata(organization_id.clone(), "namespace", "famedly_sync:localpart")
+		.await
+		.expect("could not get user metadata");
+	assert_eq!(localpart, Some("namespace".to_owned()));
+	let localpart = zitadel
+		.get_user_metadata(organization_id, "namespace", "localpart")
+		.await
+		.expect("could not get user metadata");
+	assert_eq!(localpart, None);
+
+	// The migrated user compares equal to its source user
+	verify_idempotent(&config).await.expect("sync with namespace isn't idempotent");
+}
+
+#[test(tokio::test)]
+#[test_log(default_log_filter = "debug")]
+async fn test_e2e_concurrent_writes() {
+	let mut config = csv_config().await.clone();
+	config.zitadel.write_concurrency.deletions = 2;
+	config.zitadel.write_concurrency.imports = 2;
+	config.zitadel.write_concurrency.updates = 2;
+	let csv_content = indoc::indoc! {r#"
+    email,first_name,last_name,phone,localpart
+    concurrent_kept@example.com,Kept,User,,concurrent_kept
+    concurrent_updated_1@example.com,Updated,One,,concurrent_updated_1
+    concurrent_updated_2@example.com,Updated,Two,,concurrent_updated_2
+    concurrent_updated_3@example.com,Updated,Three,,concurrent_updated_3
+    concurrent_deleted_1@example.com,Deleted,One,,concurrent_deleted_1
+    concurrent_deleted_2@example.com,Deleted,Two,,concurrent_deleted_2
+    concurrent_deleted_3@example.com,Deleted,Three,,concurrent_deleted_3
+  "#};
+	let _file = temp_csv_file(&mut config, csv_content);
+
+	perform_sync(&config).await.expect("syncing failed");
+
+	let csv_content = indoc::indoc! {r#"
+    email,first_name,last_name,phone,localpart
+    concurrent_kept@example.com,Kept,User,,concurrent_kept
+    concurrent_updated_1@example.com,Changed,One,,concurrent_updated_1
+    concurrent_updated_2@example.com,Changed,Two,,concurrent_updated_2
+    concurrent_updated_3@example.com,Changed,Three,,concurrent_updated_3
+    concurrent_imported_1@example.com,Imported,One,,concurrent_imported_1
+    concurrent_imported_2@example.com,Imported,Two,,concurrent_imported_2
+    concurrent_imported_3@example.com,Imported,Three,,concurrent_imported_3
+  "#};
+	let _file = temp_csv_file(&mut config, csv_content);
+
+	perform_sync(&config).await.expect("syncing failed");
+
+	let zitadel = open_zitadel_connection().await;
+	for email in [
+		"concurrent_deleted_1@example.com",
+		"concurrent_deleted_2@example.com",
+		"concurrent_deleted_3@example.com",
+	] {
+		let user = zitadel.get_user_by_login_name(email).await;
+		assert!(user.is_err_and(|error| matches!(error, ZitadelError::TonicResponseError(status) if status.code() == TonicErrorCode::NotFound)));
+	}
+	for email in [
+		"concurrent_kept@example.com",
+		"concurrent_imported_1@example.com",
+		"concurrent_imported_2@example.com",
+		"concurrent_imported_3@example.com",
+	] {
+		let user = zitadel.get_user_by_login_name(email).await.expect("failed to find user");
+		assert!(user.is_some());
+	}
+	for email in [
+		"concurrent_updated_1@example.com",
+		"concurrent_updated_2@example.com",
+		"concurrent_updated_3@example.com",
+	] {
+		let user = zitadel.get_user_by_login_name(email).await.expect("failed to find user");
+		match user.expect("could not find user").r#type {
+			Some(UserType::Human(user)) => {
+				assert_eq!(user.profile.expect("user lacks a profile").first_name, "Changed");
+			}
+			_ => panic!("user lacks details"),
+		}
+	}
+}
+
+#[test(tokio::test)]
+#[test_log(default_log_filter = "debug")]
+async fn test_e2e_hold_user() {
+	let mut config = csv_config().await.clone();
+	let csv_content = indoc::indoc! {r#"
+    email,first_name,last_name,phone,localpart
+    held@example.com,Held,User,+1111111111,held
+  "#};
+	let _file = temp_csv_file(&mut config, csv_content);
+
+	perform_sync(&config).await.expect("syncing failed");
+	hold_user(&config, "held@example.com", 7).await.expect("failed to hold user");
+
+	// The names are kept while the user is held, other changes are
+	// still synced
+	let csv_content = indoc::indoc! {r#"
+    email,first_name,last_name,phone,localpart
+    held@example.com,HELD,USER,+2222222222,held
+  "#};
+	let _file = temp_csv_file(&mut config, csv_content);
+
+	perform_sync(&config).await.expect("syncing failed");
+
+	let zitadel = open_zitadel_connection().await;
+	let user =
+		zitadel.get_user_by_login_name("held@example.com").await.expect("failed to find user");
+	match user.expect("could not find user").r#type {
+		Some(UserType::Human(user)) => {
+			assert_eq!(user.profile.expect("user lacks a profile").first_name, "Held");
+			assert_eq!(user.phone.expect("user lacks a phone number").phone, "+2222222222");
+		}
+		_ => panic!("user lacks details"),
+	}
+
+	// Once released, the names are synced again
+	hold_user(&config, "held@example.com", 0).await.expect("failed to release user");
+	perform_sync(&config).await.expect("syncing failed");
+
+	let user =
+		zitadel.get_user_by_login_name("held@example.com").await.expect("failed to find user");
+	match user.expect("could not find user").r#type {
+		Some(UserType::Human(user)) => {
+			assert_eq!(user.profile.expect("user lacks a profile").first_name, "HELD");
+		}
+		_ => panic!("user lacks details"),
+	}
+}
+
+#[test(tokio::test)]
+#[test_log(default_log_filter = "debug")]
+async fn test_e2e_csv_unparsable_row() {
+	let mut config = csv_config().await.clone();
+	let csv_content = indoc::indoc! {r#"
+    email,first_name,last_name,phone,localpart
+    unparsable@example.com,Un,Parsable,+1111111111,unparsable
+    parsable@example.com,Par,Sable,,parsable
+  "#};
+	let _file = temp_csv_file(&mut config, csv_content);
+
+	perform_sync(&config).await.expect("syncing failed");
+
+	// The row of the user lacks columns, which must neither update nor
+	// delete the user
+	let csv_content = indoc::indoc! {r#"
+    email,first_name,last_name,phone,localpart
+    unparsable@example.com,Changed
+    parsable@example.com,Par,Sable,,parsable
+  "#};
+	let _file = temp_csv_file(&mut config, csv_content);
+
+	let report = perform_sync(&config).await.expect("syncing failed");
+	assert_eq!(report.unparsable_users.len(), 1);
+
+	let zitadel = open_zitadel_connection().await;
+	let user = zitadel
+		.get_user_by_login_name("unparsable@example.com")
+		.await
+		.expect("could not query Zitadel users")
+		.expect("user with unparsable row was deleted");
+
+	if let Some(UserType::Human(user)) = user.r#type {
+		let profile = user.profile.expect("user lacks a profile");
+		let phone = user.phone.expect("user lacks a phone number");
+
+		assert_eq!(profile.first_name, "Un");
+		assert_eq!(profile.last_name, "Parsable");
+		assert_eq!(phone.phone, "+1111111111");
+	} else {
+		panic!("user lacks details");
+	}
+}
+
 #[test(tokio::test)]
 #[test_log(default_log_filter = "debug")]
 async fn test_e2e_ldap_with_ukt_sync() {
@@ -1418,6 +1651,185 @@ async fn test_e2e_sso_linking() {
 	);
 }
 
+#[test(tokio::test)]
+#[test_log(default_log_filter = "debug")]
+async fn test_e2e_verify_idempotent() {
+	let mut ldap = Ldap::new().await;
+	ldap.create_user(
+		"Idempotent",
+		"User",
+		"Idempotent User",
+		"idempotent@famedly.de",
+		Some("+12015550150"),
+		"idempotent",
+		false,
+	)
+	.await;
+
+	verify_idempotent(ldap_config().await).await.expect("sync is not idempotent");
+}
+
+#[test(tokio::test)]
+#[test_log(default_log_filter = "debug")]
+async fn test_e2e_verify_idempotent_unchanged_source_user() {
+	let state_dir = tempfile::tempdir().expect("failed to create tempdir");
+	let mut config = ldap_config().await.clone();
+	config.state_path = Some(state_dir.path().join("state.json"));
+	config
+		.sources
+		.ldap
+		.as_mut()
+		.expect("ldap must be configured for this test")
+		.attributes
+		.last_modified = Some(AttributeMapping::NoBinaryOption("modifyTimestamp".to_owned()));
+
+	let mut ldap = Ldap::new().await;
+	ldap.create_user(
+		"Unchanged",
+		"User",
+		"Unchanged User",
+		"idempotent_unchanged@famedly.de",
+		None,
+		"idempotent_unchanged",
+		false,
+	)
+	.await;
+
+	// Store the source version of the user
+	perform_sync(&config).await.expect("syncing failed");
+
+	// Change the user directly in Zitadel, leaving it unchanged in LDAP
+	let mut sync_zitadel =
+		SyncZitadel::new(&config).await.expect("failed to set up Zitadel client");
+	let mut stream = sync_zitadel.list_users().expect("failed to list users");
+	let external_user_id = hex::encode("idempotent_unchanged");
+	let mut synced_user = None;
+	while let Some((user, zitadel_id)) =
+		get_next_zitadel_user(&mut stream, &mut sync_zitadel).await.expect("failed to list users")
+	{
+		if user.get_external_id() == external_user_id {
+			synced_user = Some((user, zitadel_id));
+		}
+	}
+	let (user, zitadel_id) = synced_user.expect("user wasn't synced");
+	let changed_user = User::new(
+		"Changed".to_owned(),
+		"User".to_owned(),
+		"idempotent_unchanged@famedly.de".to_owned(),
+		None,
+		true,
+		None,
+		external_user_id,
+		None,
+	);
+	sync_zitadel
+		.update_user(&zitadel_id, &user, &changed_user)
+		.await
+		.expect("failed to change user in Zitadel");
+
+	// The first pass skips the user as unchanged in LDAP, while the
+	// second one compares it and finds the change
+	let error = verify_idempotent(&config).await.expect_err("change in Zitadel wasn't reported");
+	assert!(error.to_string().contains("Sync is not idempotent"), "unexpected error: {error:?}");
+}
+
+#[test(tokio::test)]
+#[test_log(default_log_filter = "debug")]
+async fn test_e2e_repeated_import() {
+	let config = ldap_config().await;
+	let test_email = "repeated_import@famedly.de";
+
+	let user = User::new(
+		"Repeated".to_owned(),
+		"Import".to_owned(),
+		test_email.to_owned(),
+		None,
+		true,
+		None,
+		hex::encode("repeated_import"),
+		None,
+	);
+
+	let mut sync_zitadel = SyncZitadel::new(config).await.expect("failed to set up Zitadel client");
+	sync_zitadel.import_user(&user).await.expect("initial import failed");
+
+	// Simulate a previous sync which crashed after the import, but
+	// before it was recorded
+	sync_zitadel.import_user(&user).await.expect("repeated import failed");
+
+	let zitadel = open_zitadel_connection().await;
+	let zitadel_user = zitadel
+		.get_user_by_login_name(test_email)
+		.await
+		.expect("could not query Zitadel users")
+		.expect("could not find user");
+
+	let grants = zitadel
+		.list_user_grants(&config.zitadel.organization_id, &zitadel_user.id)
+		.await
+		.expect("failed to get user grants");
+	let grant = grants.result.first().expect("no user grants found");
+	assert!(grant.role_keys.clone().into_iter().any(|key| key == FAMEDLY_USER_ROLE));
+}
+
+#[test(tokio::test)]
+#[test_log(default_log_filter = "debug")]
+async fn test_e2e_user_without_email() {
+	let config = ldap_config().await;
+	let external_user_id = hex::encode("no_email");
+	let zitadel_id =
+		create_zitadel_user(config, "no_email", "no_email@famedly.de", &external_user_id).await;
+
+	// Zitadel requires an email address for new users, so the listing
+	// of a user without one is simulated for an existing user
+	let listed_user = User::new(
+		"Changed".to_owned(),
+		"Name".to_owned(),
+		String::new(),
+		None,
+		true,
+		None,
+		external_user_id.clone(),
+		None,
+	);
+
+	// By default, the user is never compared with the source users, so
+	// that it is neither updated nor deleted
+	let mut sync_zitadel = SyncZitadel::new(config).await.expect("failed to set up Zitadel client");
+	let mut stream = futures::stream::iter(vec![Ok((listed_user.clone(), zitadel_id.clone()))]);
+	let compared = get_next_zitadel_user(&mut stream, &mut sync_zitadel)
+		.await
+		.expect("failed to get next user");
+	assert!(compared.is_none());
+	assert_eq!(sync_zitadel.take_users_without_email(), vec![zitadel_id.clone()]);
+
+	let zitadel = open_zitadel_connection().await;
+	let user = zitadel
+		.get_user_by_login_name("no_email")
+		.await
+		.expect("could not query Zitadel users")
+		.expect("user without email was deleted");
+	let Some(UserType::Human(human)) = user.r#type else {
+		panic!("user lacks details");
+	};
+	let profile = human.profile.expect("user lacks profile");
+	assert_eq!(profile.first_name, "Test");
+	assert_eq!(profile.last_name, "User");
+
+	// With `missing_email: match`, the user is compared as usual
+	let mut match_config = config.clone();
+	match_config.zitadel.missing_email = MissingEmailPolicy::Match;
+	let mut sync_zitadel =
+		SyncZitadel::new(&match_config).await.expect("failed to set up Zitadel client");
+	let mut stream = futures::stream::iter(vec![Ok((listed_user, zitadel_id.clone()))]);
+	let compared = get_next_zitadel_user(&mut stream, &mut sync_zitadel)
+		.await
+		.expect("failed to get next user");
+	assert_eq!(compared.map(|(_, id)| id), Some(zitadel_id.clone()));
+
+	zitadel.remove_user(zitadel_id).await.expect("failed to delete user");
+}
+
 #[test(tokio::test)]
 #[test_log(default_log_filter = "debug")]
 async fn test_e2e_migrate_base64_id() {
@@ -1509,34 +1921,13 @@ async fn test_e2e_migrate_ambiguous_id_as_base64() {
 	// logic should heuristically find out, that the DB has external IDs encoded
 	// with base64 and thus treat the ambiguous ID as base64 even though it can be
 	// both base64 and hex
-	let base_64_user = ImportHumanUserRequest {
-		user_name: "another_test".to_owned(),
-		profile: Some(Profile {
-			first_name: "Test".to_owned(),
-			last_name: "User".to_owned(),
-			display_name: "User, Test".to_owned(),
-			gender: Gender::Unspecified.into(),
-			nick_name: "Z9FmZQ==".to_owned(), // base64 encoded
-			preferred_language: String::default(),
-		}),
-		email: Some(Email {
-			email: "another_test@example.com".to_owned(),
-			is_email_verified: true,
-		}),
-		phone: Some(Phone { phone: "+12345678901".to_owned(), is_phone_verified: true }),
-		password: String::default(),
-		hashed_password: None,
-		password_change_required: false,
-		request_passwordless_registration: false,
-		otp_code: String::default(),
-		idps: vec![],
-	};
-
-	let zitadel = open_zitadel_connection().await;
-	let temp_user = zitadel
-		.create_human_user(&config.zitadel.organization_id, base_64_user)
-		.await
-		.expect("Failed to create user");
+	let temp_user = create_zitadel_user(
+		config,
+		"another_test",
+		"another_test@example.com",
+		"Z9FmZQ==", // base64 encoded
+	)
+	.await;
 
 	let user_name = "ambiguous_user_two";
 
@@ -1549,6 +1940,7 @@ async fn test_e2e_migrate_ambiguous_id_as_base64() {
 
 	run_migration_test(config, email, user_name, ambiguous_id, expected_id).await;
 
+	let zitadel = open_zitadel_connection().await;
 	zitadel.remove_user(temp_user).await.expect("Failed to delete user");
 }
 
@@ -1759,6 +2151,40 @@ async fn open_zitadel_connection() -> Zitadel {
 		.expect("failed to set up Zitadel client")
 }
 
+/// Create a user directly in Zitadel, bypassing the sync, returning
+/// its Zitadel ID
+async fn create_zitadel_user(
+	config: &Config,
+	user_name: &str,
+	email: &str,
+	nick_name: &str,
+) -> String {
+	let mut zitadel = SyncZitadel::new(config).await.expect("failed to set up Zitadel client");
+
+	// Only the request setters the sync itself uses for imports and
+	// updates, so that the fixtures rely on the pinned client's API
+	let mut user = AddHumanUserRequest::new(
+		SetHumanProfile::new("Test".to_owned(), "User".to_owned())
+			.with_nick_name(nick_name.to_owned())
+			.with_display_name("User, Test".to_owned()),
+		SetHumanEmail::new(email.to_owned()).with_is_verified(true),
+	)
+	.with_organization(Organization::new().with_org_id(config.zitadel.organization_id.clone()));
+	user.set_username(user_name.to_owned());
+	user.set_phone(
+		SetHumanPhone::new().with_phone("+12345678901".to_owned()).with_is_verified(true),
+	);
+
+	zitadel
+		.zitadel_client
+		.create_human_user(user)
+		.await
+		.expect("failed to create user")
+		.user_id()
+		.expect("created user lacks an ID")
+		.clone()
+}
+
 /// Helper function to create a user, run migration, and verify the encoding.
 async fn run_migration_test(
 	config: &Config,
@@ -1771,30 +2197,7 @@ async fn run_migration_test(
 	let zitadel = open_zitadel_connection().await;
 
 	// Create user in Zitadel
-	let user = ImportHumanUserRequest {
-		user_name: user_name.to_owned(),
-		profile: Some(Profile {
-			first_name: "Test".to_owned(),
-			last_name: "User".to_owned(),
-			display_name: "User, Test".to_owned(),
-			gender: Gender::Unspecified.into(),
-			nick_name: initial_nick_name.clone(),
-			preferred_language: String::default(),
-		}),
-		email: Some(Email { email: email.to_owned(), is_email_verified: true }),
-		phone: Some(Phone { phone: "+12345678901".to_owned(), is_phone_verified: true }),
-		password: String::default(),
-		hashed_password: None,
-		password_change_required: false,
-		request_passwordless_registration: false,
-		otp_code: String::default(),
-		idps: vec![],
-	};
-
-	zitadel
-		.create_human_user(&config.zitadel.organization_id, user)
-		.await
-		.expect("Failed to create user");
+	create_zitadel_user(config, user_name, email, &initial_nick_name).await;
 
 	// Run migration
 	run_migration_binary(config.feature_flags.contains(&FeatureFlag::DryRun));